@@ -0,0 +1,37 @@
+//! Measures how fast the 6502 core runs, without the rest of the console
+//!
+//! This runs the first 8000 instructions of nestest's automated mode on the
+//! flat-RAM test harness, so that the PPU (which runs three times as often as
+//! the CPU) doesn't drown out changes to instruction dispatch.
+//!
+//! Run with `cargo bench --bench cpu`.
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use defenestrate_core::devices::cpu::{TestHarnessMotherboard, WithCpu};
+
+const NESTEST_ROM_PATH: &str = "./tests/data/nestest.nes";
+
+/// How many instructions each iteration runs
+const INSTRUCTIONS: u64 = 8000;
+
+fn nestest(c: &mut Criterion) {
+    let rom = std::fs::read(NESTEST_ROM_PATH).expect("Could not read NESTEST rom");
+    // nestest is a single 16k bank after the iNES header, mirrored at $C000
+    let prg = &rom[16..16 + 0x4000];
+    let mut group = c.benchmark_group("cpu");
+    group.throughput(Throughput::Elements(INSTRUCTIONS));
+    group.bench_function("nestest", |b| {
+        b.iter(|| {
+            let mut mb = TestHarnessMotherboard::new();
+            mb.load(0x8000, prg);
+            mb.load(0xC000, prg);
+            mb.cpu_mut().force_pc(0xC000);
+            mb.run(INSTRUCTIONS as usize);
+            mb
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, nestest);
+criterion_main!(benches);
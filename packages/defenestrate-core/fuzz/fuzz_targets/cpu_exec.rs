@@ -0,0 +1,20 @@
+//! Runs arbitrary bytes as 6502 code, on a motherboard that's nothing but RAM
+//!
+//! The input is loaded from $0000 up, with the rest of memory zeroed, and the
+//! CPU starts from whatever the reset vector ends up as. Anything the CPU
+//! can be fed should run without panicking, including on overflow.
+
+#![no_main]
+
+use defenestrate_core::devices::cpu::{reset, TestHarnessMotherboard};
+use libfuzzer_sys::fuzz_target;
+
+/// How many instructions to run for each input
+const INSTRUCTIONS: usize = 1000;
+
+fuzz_target!(|data: &[u8]| {
+    let mut mb = TestHarnessMotherboard::new();
+    mb.load(0x0000, &data[..data.len().min(0x10000)]);
+    reset(&mut mb);
+    mb.run(INSTRUCTIONS);
+});
@@ -0,0 +1,21 @@
+//! Loads arbitrary bytes as an iNES ROM, and runs whatever loads
+//!
+//! Nothing a ROM contains should be able to crash the emulator, whether
+//! that's a bad header or a program that pokes at every register.
+
+#![no_main]
+
+use defenestrate_core::devices::nes::Nes;
+use libfuzzer_sys::fuzz_target;
+
+/// How many frames to run each ROM that loads for
+const FRAMES: usize = 2;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = Nes::rom_info(data);
+    if let Ok(mut nes) = Nes::new_from_buf(data) {
+        for _ in 0..FRAMES {
+            nes.tick_frame();
+        }
+    }
+});
@@ -0,0 +1,67 @@
+/*
+ * C API for the deFeNEStrate emulation core
+ *
+ * Build the core with the `capi` feature to get a library exporting these.
+ * The documentation for each function is on its definition, in
+ * src/bindings/capi.rs; this header only declares them.
+ *
+ * A console is an opaque `Nes` pointer from `defenestrate_create`, which must
+ * be handed back to `defenestrate_destroy`. A panic in the core is reported
+ * as DEFENESTRATE_PANICKED (or as NULL or 0 by functions that don't return a
+ * status), after which the console should be destroyed.
+ */
+
+#ifndef DEFENESTRATE_H
+#define DEFENESTRATE_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+typedef struct Nes Nes;
+
+typedef enum DefenestrateStatus {
+    DEFENESTRATE_OK = 0,
+    DEFENESTRATE_NULL_POINTER = 1,
+    DEFENESTRATE_BUFFER_TOO_SMALL = 2,
+    DEFENESTRATE_INVALID_HEADER = 3,
+    DEFENESTRATE_TRUNCATED_ROM = 4,
+    DEFENESTRATE_UNSUPPORTED_MAPPER = 5,
+    DEFENESTRATE_UNSUPPORTED_CONSOLE = 6,
+    DEFENESTRATE_OTHER = 7,
+    DEFENESTRATE_PANICKED = 8,
+} DefenestrateStatus;
+
+/* Controller buttons, for defenestrate_set_input */
+#define DEFENESTRATE_BUTTON_A 0x01
+#define DEFENESTRATE_BUTTON_B 0x02
+#define DEFENESTRATE_BUTTON_SELECT 0x04
+#define DEFENESTRATE_BUTTON_START 0x08
+#define DEFENESTRATE_BUTTON_UP 0x10
+#define DEFENESTRATE_BUTTON_DOWN 0x20
+#define DEFENESTRATE_BUTTON_LEFT 0x40
+#define DEFENESTRATE_BUTTON_RIGHT 0x80
+
+size_t defenestrate_frame_size(void);
+
+Nes *defenestrate_create(void);
+void defenestrate_destroy(Nes *nes);
+
+DefenestrateStatus defenestrate_load_rom(Nes *nes, const uint8_t *rom, size_t len);
+DefenestrateStatus defenestrate_reset(Nes *nes);
+DefenestrateStatus defenestrate_set_input(Nes *nes, uint32_t port, uint8_t buttons);
+DefenestrateStatus defenestrate_run_frame(Nes *nes, uint8_t *buf, size_t len);
+uint64_t defenestrate_frame_id(const Nes *nes);
+
+size_t defenestrate_save_data_size(const Nes *nes);
+DefenestrateStatus defenestrate_save_data(const Nes *nes, uint8_t *buf, size_t len);
+DefenestrateStatus defenestrate_load_save_data(Nes *nes, const uint8_t *data, size_t len);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* DEFENESTRATE_H */
@@ -0,0 +1,455 @@
+//! C front-end for the NES emulator
+//!
+//! This mirrors the wasm bindings for native frontends that aren't written in
+//! Rust, like a libretro core, Python bindings, or a C++ GUI. A console is an
+//! opaque `Nes` pointer from `defenestrate_create`, which must be handed back
+//! to `defenestrate_destroy` when the frontend is done with it.
+//!
+//! The declarations for C are in `include/defenestrate.h`, next to this
+//! crate's `Cargo.toml`.
+//!
+//! Functions that can fail return a `DefenestrateStatus`. Pointer arguments
+//! may be null only where a function says so; everything else is checked for
+//! null and reported as `NullPointer`. A panic in the core is caught before it
+//! reaches the caller, and reported as `Panicked` (or as a null pointer or 0
+//! by functions that don't return a status). The console it happened on may
+//! be halfway through a frame, so it should be destroyed rather than run
+//! again.
+//!
+//! Save states are out of scope: the core can't save or restore a whole
+//! console yet, so there's nothing to export. What is covered is the
+//! cartridge's battery-backed memory, which is what a frontend needs to keep
+//! between sessions.
+
+use core::{ptr, slice};
+use std::panic::{self, AssertUnwindSafe};
+
+use crate::devices::nes::{Buttons, Nes, NesBuilder, FRAME_SIZE};
+use crate::error::Error;
+
+/// The result of a call that can fail
+#[repr(C)]
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum DefenestrateStatus {
+    Ok = 0,
+    /// A pointer that can't be null was
+    NullPointer = 1,
+    /// The caller's buffer is too small for what was asked of it
+    BufferTooSmall = 2,
+    /// The ROM isn't an iNES ROM
+    InvalidHeader = 3,
+    /// The ROM is shorter than its header says
+    TruncatedRom = 4,
+    /// The ROM's mapper isn't emulated
+    UnsupportedMapper = 5,
+    /// The ROM is for a Vs. System or another console that isn't emulated
+    UnsupportedConsole = 6,
+    /// Any other error
+    Other = 7,
+    /// The core panicked
+    Panicked = 8,
+}
+
+impl From<Error> for DefenestrateStatus {
+    fn from(err: Error) -> DefenestrateStatus {
+        match err {
+            Error::InvalidHeader => DefenestrateStatus::InvalidHeader,
+            Error::TruncatedRom { .. } => DefenestrateStatus::TruncatedRom,
+            Error::UnsupportedMapper { .. } => DefenestrateStatus::UnsupportedMapper,
+            Error::UnsupportedConsole { .. } => DefenestrateStatus::UnsupportedConsole,
+            _ => DefenestrateStatus::Other,
+        }
+    }
+}
+
+/// Borrow `len` bytes at `ptr`, treating null as empty only when `len` is 0
+///
+/// # Safety
+///
+/// `ptr` must be valid for reads of `len` bytes, unless it's null.
+unsafe fn bytes<'a>(ptr: *const u8, len: usize) -> Option<&'a [u8]> {
+    match (ptr.is_null(), len) {
+        (true, 0) => Some(&[]),
+        (true, _) => None,
+        (false, _) => Some(slice::from_raw_parts(ptr, len)),
+    }
+}
+
+/// Run `f`, or give back `on_panic` if it panics
+///
+/// Unwinding out of an `extern "C"` function aborts the process, so every one
+/// of them goes through this.
+fn catch<T, F: FnOnce() -> T>(on_panic: T, f: F) -> T {
+    panic::catch_unwind(AssertUnwindSafe(f)).unwrap_or(on_panic)
+}
+
+/// The size of a frame from `defenestrate_run_frame`, in bytes
+///
+/// Frames are 256x240 pixels, as 8-bit RGB triples in row-major order.
+#[no_mangle]
+pub extern "C" fn defenestrate_frame_size() -> usize {
+    catch(0, || FRAME_SIZE)
+}
+
+/// Create a new console with nothing plugged in
+///
+/// Load a game with `defenestrate_load_rom`. Until then, the console runs a
+/// blank cartridge and draws black frames.
+#[no_mangle]
+pub extern "C" fn defenestrate_create() -> *mut Nes {
+    catch(ptr::null_mut(), || {
+        Box::into_raw(Box::new(NesBuilder::new().build()))
+    })
+}
+
+/// Free a console from `defenestrate_create`
+///
+/// # Safety
+///
+/// `nes` must be null, or a pointer from `defenestrate_create` that hasn't
+/// been destroyed yet. It can't be used again afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn defenestrate_destroy(nes: *mut Nes) {
+    catch((), || {
+        if !nes.is_null() {
+            drop(Box::from_raw(nes));
+        }
+    })
+}
+
+/// Load an iNES ROM in place of the current cartridge, and power cycle
+///
+/// If the ROM can't be loaded, the current game keeps running untouched.
+///
+/// # Safety
+///
+/// `nes` must be a live pointer from `defenestrate_create`, and `rom` must be
+/// valid for reads of `len` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn defenestrate_load_rom(
+    nes: *mut Nes,
+    rom: *const u8,
+    len: usize,
+) -> DefenestrateStatus {
+    catch(DefenestrateStatus::Panicked, || {
+        let (nes, rom) = match (nes.as_mut(), bytes(rom, len)) {
+            (Some(nes), Some(rom)) => (nes, rom),
+            _ => return DefenestrateStatus::NullPointer,
+        };
+        match nes.load_rom(rom) {
+            Ok(()) => DefenestrateStatus::Ok,
+            Err(err) => err.into(),
+        }
+    })
+}
+
+/// Press the console's reset button
+///
+/// # Safety
+///
+/// `nes` must be a live pointer from `defenestrate_create`.
+#[no_mangle]
+pub unsafe extern "C" fn defenestrate_reset(nes: *mut Nes) -> DefenestrateStatus {
+    catch(DefenestrateStatus::Panicked, || match nes.as_mut() {
+        Some(nes) => {
+            nes.reset();
+            DefenestrateStatus::Ok
+        }
+        None => DefenestrateStatus::NullPointer,
+    })
+}
+
+/// Set the buttons held on the controller in `port` (0 or 1)
+///
+/// `buttons` is a bitmask in the order the controller reports them, from A
+/// in bit 0 to Right in bit 7. Ports other than 0 and 1 are ignored.
+///
+/// # Safety
+///
+/// `nes` must be a live pointer from `defenestrate_create`.
+#[no_mangle]
+pub unsafe extern "C" fn defenestrate_set_input(
+    nes: *mut Nes,
+    port: u32,
+    buttons: u8,
+) -> DefenestrateStatus {
+    catch(DefenestrateStatus::Panicked, || {
+        let nes = match nes.as_mut() {
+            Some(nes) => nes,
+            None => return DefenestrateStatus::NullPointer,
+        };
+        if port < 2 {
+            nes.set_buttons(port as usize, Buttons::from_bits_truncate(buttons));
+        }
+        DefenestrateStatus::Ok
+    })
+}
+
+/// Run the console to the end of the next frame, and copy it into `buf`
+///
+/// `buf` must hold at least `defenestrate_frame_size()` bytes. If it's too
+/// small, nothing runs.
+///
+/// # Safety
+///
+/// `nes` must be a live pointer from `defenestrate_create`, and `buf` must be
+/// valid for writes of `len` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn defenestrate_run_frame(
+    nes: *mut Nes,
+    buf: *mut u8,
+    len: usize,
+) -> DefenestrateStatus {
+    catch(DefenestrateStatus::Panicked, || {
+        let nes = match nes.as_mut() {
+            Some(nes) if !buf.is_null() => nes,
+            _ => return DefenestrateStatus::NullPointer,
+        };
+        if len < FRAME_SIZE {
+            return DefenestrateStatus::BufferTooSmall;
+        }
+        let frame = nes.tick_frame();
+        slice::from_raw_parts_mut(buf, FRAME_SIZE).copy_from_slice(frame);
+        DefenestrateStatus::Ok
+    })
+}
+
+/// The id of the most recent frame, see `Nes::frame_id`
+///
+/// # Safety
+///
+/// `nes` must be a live pointer from `defenestrate_create`.
+#[no_mangle]
+pub unsafe extern "C" fn defenestrate_frame_id(nes: *const Nes) -> u64 {
+    catch(0, || nes.as_ref().map_or(0, Nes::frame_id))
+}
+
+/// The size of the cartridge's battery-backed memory, or 0 if it has none
+///
+/// # Safety
+///
+/// `nes` must be a live pointer from `defenestrate_create`.
+#[no_mangle]
+pub unsafe extern "C" fn defenestrate_save_data_size(nes: *const Nes) -> usize {
+    catch(0, || {
+        nes.as_ref()
+            .and_then(Nes::save_data)
+            .map_or(0, |data| data.len())
+    })
+}
+
+/// Copy the cartridge's battery-backed memory into `buf`
+///
+/// `buf` must hold at least `defenestrate_save_data_size()` bytes. For a
+/// cartridge with nothing to save, this copies nothing, and `buf` may be
+/// null.
+///
+/// # Safety
+///
+/// `nes` must be a live pointer from `defenestrate_create`, and `buf` must be
+/// valid for writes of `len` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn defenestrate_save_data(
+    nes: *const Nes,
+    buf: *mut u8,
+    len: usize,
+) -> DefenestrateStatus {
+    catch(DefenestrateStatus::Panicked, || {
+        let nes = match nes.as_ref() {
+            Some(nes) => nes,
+            None => return DefenestrateStatus::NullPointer,
+        };
+        let data = nes.save_data().unwrap_or(&[]);
+        if data.is_empty() {
+            return DefenestrateStatus::Ok;
+        }
+        if buf.is_null() {
+            return DefenestrateStatus::NullPointer;
+        }
+        if len < data.len() {
+            return DefenestrateStatus::BufferTooSmall;
+        }
+        slice::from_raw_parts_mut(buf, data.len()).copy_from_slice(data);
+        DefenestrateStatus::Ok
+    })
+}
+
+/// Restore the cartridge's battery-backed memory from an earlier
+/// `defenestrate_save_data`
+///
+/// Data of the wrong size for the cartridge is ignored.
+///
+/// # Safety
+///
+/// `nes` must be a live pointer from `defenestrate_create`, and `data` must
+/// be valid for reads of `len` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn defenestrate_load_save_data(
+    nes: *mut Nes,
+    data: *const u8,
+    len: usize,
+) -> DefenestrateStatus {
+    catch(DefenestrateStatus::Panicked, || {
+        match (nes.as_mut(), bytes(data, len)) {
+            (Some(nes), Some(data)) => {
+                nes.load_save_data(data);
+                DefenestrateStatus::Ok
+            }
+            _ => DefenestrateStatus::NullPointer,
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::ptr;
+
+    /// A 16k NROM game that loops forever at $8000
+    fn nrom() -> Vec<u8> {
+        let mut rom = vec![0u8; 16 + 0x4000 + 0x2000];
+        rom[0..4].copy_from_slice(b"NES\x1A");
+        rom[4] = 1;
+        rom[5] = 1;
+        // JMP $8000
+        rom[16..19].copy_from_slice(&[0x4C, 0x00, 0x80]);
+        // the reset vector, at $FFFC mirrored down to $BFFC
+        rom[16 + 0x3FFC..16 + 0x3FFE].copy_from_slice(&[0x00, 0x80]);
+        rom
+    }
+
+    #[test]
+    fn runs_frames_into_the_callers_buffer() {
+        let rom = nrom();
+        let mut expected = Nes::new_from_buf(&rom).expect("Could not load test ROM");
+        let expected = expected.tick_frame().to_vec();
+        unsafe {
+            let nes = defenestrate_create();
+            assert_eq!(
+                defenestrate_load_rom(nes, rom.as_ptr(), rom.len()),
+                DefenestrateStatus::Ok
+            );
+            assert_eq!(
+                defenestrate_set_input(nes, 0, Buttons::START.bits()),
+                DefenestrateStatus::Ok
+            );
+            assert_eq!((*nes).buttons(0), Buttons::START);
+            let mut frame = vec![0xFFu8; defenestrate_frame_size()];
+            let id = defenestrate_frame_id(nes);
+            assert_eq!(
+                defenestrate_run_frame(nes, frame.as_mut_ptr(), frame.len()),
+                DefenestrateStatus::Ok
+            );
+            assert_eq!(defenestrate_frame_id(nes), id + 1);
+            assert!(frame == expected, "Frames differ from the core's");
+            defenestrate_destroy(nes);
+        }
+    }
+
+    #[test]
+    fn reports_errors_as_statuses() {
+        unsafe {
+            let nes = defenestrate_create();
+            let garbage = [0u8; 32];
+            assert_eq!(
+                defenestrate_load_rom(nes, garbage.as_ptr(), garbage.len()),
+                DefenestrateStatus::InvalidHeader
+            );
+            let rom = nrom();
+            assert_eq!(
+                defenestrate_load_rom(nes, rom.as_ptr(), 100),
+                DefenestrateStatus::TruncatedRom
+            );
+            assert_eq!(
+                defenestrate_load_rom(nes, ptr::null(), rom.len()),
+                DefenestrateStatus::NullPointer
+            );
+            assert_eq!(
+                defenestrate_reset(ptr::null_mut()),
+                DefenestrateStatus::NullPointer
+            );
+
+            let mut small = [0u8; 16];
+            let id = defenestrate_frame_id(nes);
+            assert_eq!(
+                defenestrate_run_frame(nes, small.as_mut_ptr(), small.len()),
+                DefenestrateStatus::BufferTooSmall
+            );
+            assert_eq!(defenestrate_frame_id(nes), id, "A frame ran anyway");
+
+            // the blank cartridge has nothing to save
+            assert_eq!(defenestrate_save_data_size(nes), 0);
+            assert_eq!(
+                defenestrate_save_data(nes, ptr::null_mut(), 0),
+                DefenestrateStatus::Ok
+            );
+            assert_eq!(
+                defenestrate_load_save_data(nes, ptr::null(), 0),
+                DefenestrateStatus::Ok
+            );
+            defenestrate_destroy(nes);
+            defenestrate_destroy(ptr::null_mut());
+        }
+    }
+
+    #[test]
+    fn catches_panics() {
+        assert_eq!(
+            catch(DefenestrateStatus::Panicked, || -> DefenestrateStatus {
+                panic!("Cursed ROM")
+            }),
+            DefenestrateStatus::Panicked
+        );
+        assert_eq!(catch(0u64, || 42), 42);
+    }
+
+    #[test]
+    fn header_declares_every_function() {
+        let header = include_str!("../../include/defenestrate.h");
+        for decl in &[
+            "size_t defenestrate_frame_size(void);",
+            "Nes *defenestrate_create(void);",
+            "void defenestrate_destroy(Nes *nes);",
+            "DefenestrateStatus defenestrate_load_rom(Nes *nes, const uint8_t *rom, size_t len);",
+            "DefenestrateStatus defenestrate_reset(Nes *nes);",
+            "DefenestrateStatus defenestrate_set_input(Nes *nes, uint32_t port, uint8_t buttons);",
+            "DefenestrateStatus defenestrate_run_frame(Nes *nes, uint8_t *buf, size_t len);",
+            "uint64_t defenestrate_frame_id(const Nes *nes);",
+            "size_t defenestrate_save_data_size(const Nes *nes);",
+            "DefenestrateStatus defenestrate_save_data(const Nes *nes, uint8_t *buf, size_t len);",
+            "DefenestrateStatus defenestrate_load_save_data(Nes *nes, const uint8_t *data, size_t len);",
+        ] {
+            assert!(header.contains(decl), "Header is missing {}", decl);
+        }
+        // the header has to agree with the Rust enum on every value
+        for &(name, status) in &[
+            ("DEFENESTRATE_OK", DefenestrateStatus::Ok),
+            ("DEFENESTRATE_NULL_POINTER", DefenestrateStatus::NullPointer),
+            (
+                "DEFENESTRATE_BUFFER_TOO_SMALL",
+                DefenestrateStatus::BufferTooSmall,
+            ),
+            (
+                "DEFENESTRATE_INVALID_HEADER",
+                DefenestrateStatus::InvalidHeader,
+            ),
+            (
+                "DEFENESTRATE_TRUNCATED_ROM",
+                DefenestrateStatus::TruncatedRom,
+            ),
+            (
+                "DEFENESTRATE_UNSUPPORTED_MAPPER",
+                DefenestrateStatus::UnsupportedMapper,
+            ),
+            (
+                "DEFENESTRATE_UNSUPPORTED_CONSOLE",
+                DefenestrateStatus::UnsupportedConsole,
+            ),
+            ("DEFENESTRATE_OTHER", DefenestrateStatus::Other),
+            ("DEFENESTRATE_PANICKED", DefenestrateStatus::Panicked),
+        ] {
+            let decl = format!("{} = {},", name, status as u32);
+            assert!(header.contains(&decl), "Header is missing {}", decl);
+        }
+    }
+}
@@ -1,2 +1,4 @@
-#[cfg(target_family = "wasm")]
+#[cfg(feature = "capi")]
+pub mod capi;
+#[cfg(all(target_family = "wasm", feature = "std", feature = "console"))]
 pub mod wasm;
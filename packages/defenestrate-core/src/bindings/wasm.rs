@@ -1,9 +1,13 @@
 /// WASM front-end for the NES emulator
 use crate::devices::cpu::WithCpu;
-use crate::devices::nes::Nes;
+use crate::devices::nes::{
+    AccuracyMode, Breakpoint, Buttons, Channel, LayerMask, Nes, Palette, Symbols, Watch,
+};
+use crate::error::Error;
 use console_error_panic_hook;
-use js_sys::Uint8Array;
+use js_sys::{Float32Array, Reflect, Uint16Array, Uint8Array};
 use std::panic;
+use std::time::Duration;
 use wasm_bindgen::prelude::*;
 
 #[wasm_bindgen]
@@ -11,6 +15,120 @@ extern "C" {
     fn alert(s: &str);
 }
 
+#[cfg(feature = "console-log")]
+#[wasm_bindgen]
+extern "C" {
+    #[wasm_bindgen(js_namespace = console, js_name = error)]
+    fn console_error(s: &str);
+    #[wasm_bindgen(js_namespace = console, js_name = warn)]
+    fn console_warn(s: &str);
+    #[wasm_bindgen(js_namespace = console, js_name = info)]
+    fn console_info(s: &str);
+    #[wasm_bindgen(js_namespace = console, js_name = debug)]
+    fn console_debug(s: &str);
+}
+
+/// A `log` backend that writes to the browser console
+#[cfg(feature = "console-log")]
+struct ConsoleLogger;
+
+#[cfg(feature = "console-log")]
+impl log::Log for ConsoleLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &log::Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let message = format!("[{}] {}", record.target(), record.args());
+        match record.level() {
+            log::Level::Error => console_error(&message),
+            log::Level::Warn => console_warn(&message),
+            log::Level::Info => console_info(&message),
+            log::Level::Debug | log::Level::Trace => console_debug(&message),
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+#[cfg(feature = "console-log")]
+static CONSOLE_LOGGER: ConsoleLogger = ConsoleLogger;
+
+/// Turn an `Error` into a JS `Error`, with the details as extra properties
+///
+/// Every error gets a `kind` property naming the variant. Unsupported mappers
+/// also get `mapper`, `board` (or `undefined`), `prgSize`, and `chrSize`, with
+/// the sizes in bytes, unsupported consoles get `console`, invalid watch
+/// expressions get the `offset` they went wrong at, invalid symbol files get
+/// the `line`, and images of the wrong size get their `width` and `height`.
+fn to_js_error(err: Error) -> JsValue {
+    let js_err = js_sys::Error::new(&err.to_string());
+    let set = |key: &str, value: JsValue| {
+        // this only fails if the target isn't an object, which it always is
+        let _ = Reflect::set(&js_err, &JsValue::from_str(key), &value);
+    };
+    let kind = match err {
+        Error::InvalidHeader => "InvalidHeader",
+        Error::TruncatedRom { .. } => "TruncatedRom",
+        Error::UnsupportedMapper {
+            mapper,
+            board,
+            prg_size,
+            chr_size,
+        } => {
+            set("mapper", mapper.into());
+            set("board", board.map_or(JsValue::UNDEFINED, JsValue::from_str));
+            set("prgSize", ((prg_size * 0x4000) as u32).into());
+            set("chrSize", ((chr_size * 0x2000) as u32).into());
+            "UnsupportedMapper"
+        }
+        Error::InvalidRomSize {
+            mapper,
+            prg_size,
+            chr_size,
+        } => {
+            set("mapper", mapper.into());
+            set("prgSize", ((prg_size * 0x4000) as u32).into());
+            set("chrSize", ((chr_size * 0x2000) as u32).into());
+            "InvalidRomSize"
+        }
+        Error::UnsupportedConsole { console } => {
+            set("console", JsValue::from_str(console));
+            "UnsupportedConsole"
+        }
+        Error::InvalidPatch => "InvalidPatch",
+        Error::PatchSourceMismatch { .. } => "PatchSourceMismatch",
+        Error::InvalidDiskImage => "InvalidDiskImage",
+        Error::NoSuchDiskSide { side, side_count } => {
+            set("side", (side as u32).into());
+            set("sideCount", (side_count as u32).into());
+            "NoSuchDiskSide"
+        }
+        Error::InvalidBios { .. } => "InvalidBios",
+        Error::InvalidPalette { .. } => "InvalidPalette",
+        Error::InvalidWatch { offset } => {
+            set("offset", (offset as u32).into());
+            "InvalidWatch"
+        }
+        Error::InvalidSymbolFile { line } => {
+            set("line", (line as u32).into());
+            "InvalidSymbolFile"
+        }
+        Error::InvalidPng => "InvalidPng",
+        Error::ImageSizeMismatch { width, height } => {
+            set("width", (width as u32).into());
+            set("height", (height as u32).into());
+            "ImageSizeMismatch"
+        }
+        Error::Io(_) => "Io",
+    };
+    set("kind", JsValue::from_str(kind));
+    js_err.into()
+}
+
 #[wasm_bindgen]
 pub struct NesEmulator {
     nes: Nes,
@@ -23,12 +141,94 @@ pub struct EmulatorDebugState {
     pub chr: Uint8Array,
 }
 
+/// A nametable from `Nes::decode_nametable`, split into one array per field
+/// of `TileEntry` so each is 960 entries long, in row-major order
+#[wasm_bindgen(getter_with_clone)]
+pub struct DecodedNametable {
+    pub tiles: Uint8Array,
+    pub pattern_addrs: Uint16Array,
+    pub palettes: Uint8Array,
+}
+
+/// The frame size and pixel shape from `Nes::frame_info`, for scaling the
+/// screen to the right aspect ratio
+#[wasm_bindgen]
+pub struct FrameInfo {
+    pub width: u32,
+    pub height: u32,
+    pub pixel_aspect_ratio: f32,
+}
+
+/// Frame pacing statistics from `Nes::telemetry`, for a performance HUD
+///
+/// The rates and frame times are `undefined` until enough frames have been
+/// recorded with `record_host_frame`.
+#[wasm_bindgen]
+pub struct TelemetryInfo {
+    pub emulated_fps: Option<f64>,
+    pub host_fps: Option<f64>,
+    pub audio_buffer_fill: Option<f32>,
+    /// The median emulation time per host frame, in milliseconds
+    pub frame_time_p50: Option<f64>,
+    pub frame_time_p95: Option<f64>,
+    pub frame_time_p99: Option<f64>,
+    pub dropped_frames: u32,
+}
+
+/// The PPU registers from `Nes::ppu_debug_state`, for a PPU viewer panel
+#[wasm_bindgen(getter_with_clone)]
+pub struct PpuDebugInfo {
+    pub v: u16,
+    pub t: u16,
+    pub fine_x: u8,
+    pub write_latch: bool,
+    pub scanline: i16,
+    pub dot: u16,
+    pub control: u8,
+    pub mask: u8,
+    pub status: u8,
+    pub sprite_0_hit: bool,
+    pub sprite_overflow: bool,
+    pub nmi_pending: bool,
+    pub bg_tile_hi_shift_reg: u16,
+    pub bg_tile_lo_shift_reg: u16,
+    pub bg_attr_hi_shift_reg: u16,
+    pub bg_attr_lo_shift_reg: u16,
+    pub sprite_tile_hi_shift_regs: Uint8Array,
+    pub sprite_tile_lo_shift_regs: Uint8Array,
+}
+
 #[wasm_bindgen]
 impl NesEmulator {
     #[wasm_bindgen(constructor)]
-    pub fn new(buf: &[u8]) -> NesEmulator {
-        let mut nes = Nes::new_from_buf(buf);
-        return NesEmulator { nes };
+    pub fn new(buf: &[u8]) -> Result<NesEmulator, JsValue> {
+        let nes = Nes::new_from_buf(buf).map_err(to_js_error)?;
+        return Ok(NesEmulator { nes });
+    }
+
+    #[wasm_bindgen]
+    pub fn new_with_patch(buf: &[u8], patch: &[u8]) -> Result<NesEmulator, JsValue> {
+        let nes = Nes::new_from_buf_with_patch(buf, patch).map_err(to_js_error)?;
+        return Ok(NesEmulator { nes });
+    }
+
+    /// Boot the Famicom Disk System, with an .fds disk image in the drive
+    #[wasm_bindgen]
+    pub fn new_fds(bios: &[u8], disk: &[u8]) -> Result<NesEmulator, JsValue> {
+        let nes = Nes::new_from_fds(bios, disk).map_err(to_js_error)?;
+        return Ok(NesEmulator { nes });
+    }
+
+    /// Put a side of the disk in the FDS drive, or eject it with `undefined`
+    ///
+    /// Returns false if there's no FDS, or the disk doesn't have that side.
+    #[wasm_bindgen]
+    pub fn set_disk_side(&mut self, side: Option<u32>) -> bool {
+        let fds = match self.nes.fds_mut() {
+            Some(fds) => fds,
+            None => return false,
+        };
+        fds.insert_side(side.map(|side| side as usize)).is_ok()
     }
 
     #[wasm_bindgen]
@@ -36,30 +236,549 @@ impl NesEmulator {
         return format!("{}", &self.nes.dbg_step_cpu());
     }
 
+    /// Step the CPU like `dbg_step_cpu`, noting the subroutine depth and
+    /// where branches and jumps went
+    #[wasm_bindgen]
+    pub fn dbg_step_cpu_annotated(&mut self) -> String {
+        self.nes.dbg_step_cpu_annotated()
+    }
+
+    /// Set the buttons held on a controller, as a bitmask in `Buttons` order
+    #[wasm_bindgen]
+    pub fn set_buttons(&mut self, port: usize, buttons: u8) {
+        self.nes
+            .set_buttons(port, Buttons::from_bits_truncate(buttons));
+    }
+
+    /// The buttons the game last latched on a controller, after turbo, as a
+    /// bitmask in `Buttons` order
+    #[wasm_bindgen]
+    pub fn last_latched_input(&self, port: usize) -> u8 {
+        self.nes.last_latched_input(port).bits()
+    }
+
+    /// Record a presented frame, with `performance.now()` and how long the
+    /// frame took to emulate, in milliseconds
+    #[wasm_bindgen]
+    pub fn record_host_frame(&mut self, timestamp_ms: f64, frame_time_ms: f64) {
+        self.nes
+            .telemetry_mut()
+            .record_host_frame(timestamp_ms, frame_time_ms);
+    }
+
+    /// Record how full the audio buffer is, from 0 to 1
+    #[wasm_bindgen]
+    pub fn set_audio_buffer_fill(&mut self, fill: f32) {
+        self.nes.telemetry_mut().set_audio_buffer_fill(fill);
+    }
+
+    #[wasm_bindgen]
+    pub fn telemetry(&self) -> TelemetryInfo {
+        let telemetry = self.nes.telemetry();
+        TelemetryInfo {
+            emulated_fps: telemetry.emulated_fps(),
+            host_fps: telemetry.host_fps(),
+            audio_buffer_fill: telemetry.audio_buffer_fill(),
+            frame_time_p50: telemetry.frame_time_percentile(50),
+            frame_time_p95: telemetry.frame_time_percentile(95),
+            frame_time_p99: telemetry.frame_time_percentile(99),
+            dropped_frames: telemetry.dropped_frames() as u32,
+        }
+    }
+
+    /// Put buttons on turbo, as a bitmask in `Buttons` order like
+    /// `set_buttons`
+    #[wasm_bindgen]
+    pub fn set_turbo(&mut self, port: usize, buttons: u8, frames_per_toggle: u8) {
+        self.nes.set_turbo(
+            port,
+            Buttons::from_bits_truncate(buttons),
+            frames_per_toggle,
+        );
+    }
+
     #[wasm_bindgen]
     pub fn reset(&mut self) {
         self.nes.reset();
     }
 
+    /// The cartridge's battery-backed memory, to keep between sessions
+    ///
+    /// This is `undefined` if the cartridge has nothing to save.
+    #[wasm_bindgen]
+    pub fn save_data(&self) -> Option<Uint8Array> {
+        self.nes.save_data().map(Uint8Array::from)
+    }
+
+    /// Restore the cartridge's battery-backed memory from `save_data`
+    #[wasm_bindgen]
+    pub fn load_save_data(&mut self, data: &[u8]) {
+        self.nes.load_save_data(data);
+    }
+
+    /// Switch to another iNES ROM, keeping the settings
+    ///
+    /// If the ROM can't be loaded, this throws and the current game keeps
+    /// running.
+    #[wasm_bindgen]
+    pub fn load_rom(&mut self, buf: &[u8]) -> Result<(), JsValue> {
+        self.nes.load_rom(buf).map_err(to_js_error)
+    }
+
+    /// Use the colors from a .pal file (64 or 512 RGB triplets)
+    ///
+    /// If the file is the wrong length, this goes back to the built-in palette
+    /// and throws an `InvalidPalette` error.
+    #[wasm_bindgen]
+    pub fn load_palette(&mut self, buf: &[u8]) -> Result<(), JsValue> {
+        match Palette::from_pal(buf) {
+            Ok(palette) => {
+                self.nes.set_palette(palette);
+                Ok(())
+            }
+            Err(err) => {
+                self.nes.reset_palette();
+                Err(to_js_error(err))
+            }
+        }
+    }
+
+    /// Go back to the built-in palette
+    #[wasm_bindgen]
+    pub fn reset_palette(&mut self) {
+        self.nes.reset_palette();
+    }
+
+    /// Choose which layers to draw, as a bitmask in `LayerMask` order
+    #[wasm_bindgen]
+    pub fn set_layer_mask(&mut self, layers: u8) {
+        self.nes
+            .set_layer_mask(LayerMask::from_bits_truncate(layers));
+    }
+
+    /// The layers being drawn, as a bitmask in `LayerMask` order
+    #[wasm_bindgen]
+    pub fn layer_mask(&self) -> u8 {
+        self.nes.layer_mask().bits()
+    }
+
+    /// Choose how much of the hardware's edge-case behavior to emulate
+    ///
+    /// `mode` is one of "strict", "balanced", or "fast", and this returns
+    /// false if it isn't.
+    #[wasm_bindgen]
+    pub fn set_accuracy(&mut self, mode: &str) -> bool {
+        let accuracy = match mode {
+            "strict" => AccuracyMode::Strict,
+            "balanced" => AccuracyMode::Balanced,
+            "fast" => AccuracyMode::Fast,
+            _ => return false,
+        };
+        self.nes.set_accuracy(accuracy);
+        true
+    }
+
+    /// Set the sample rate for `take_audio_samples`, usually the
+    /// `AudioContext`'s, or 0 to turn audio off
+    #[wasm_bindgen]
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.nes.set_sample_rate(sample_rate);
+    }
+
+    /// Take the mono audio samples made since the last call
+    #[wasm_bindgen]
+    pub fn take_audio_samples(&mut self) -> Float32Array {
+        Float32Array::from(&self.nes.take_audio_samples()[..])
+    }
+
+    /// Mute or unmute a sound channel
+    ///
+    /// `channel` is one of "pulse1", "pulse2", "triangle", "noise", "dmc", or
+    /// "expansion", and this returns false if it isn't.
+    #[wasm_bindgen]
+    pub fn set_channel_enabled(&mut self, channel: &str, enabled: bool) -> bool {
+        let channel = match channel {
+            "pulse1" => Channel::Pulse1,
+            "pulse2" => Channel::Pulse2,
+            "triangle" => Channel::Triangle,
+            "noise" => Channel::Noise,
+            "dmc" => Channel::Dmc,
+            "expansion" => Channel::Expansion,
+            _ => return false,
+        };
+        self.nes.set_channel_enabled(channel, enabled);
+        true
+    }
+
+    /// Record every CPU bus access until the end of the current frame
+    #[wasm_bindgen]
+    pub fn trace_next_frame(&mut self) {
+        self.nes.trace_next_frame();
+    }
+
+    /// Take the finished bus trace, packed as described in `BusTrace::to_bytes`
+    ///
+    /// This is `undefined` until the traced frame is over.
+    #[wasm_bindgen]
+    pub fn take_bus_trace(&mut self) -> Option<Uint8Array> {
+        let trace = self.nes.take_bus_trace()?;
+        Some(Uint8Array::from(&trace.to_bytes()[..]))
+    }
+
     #[wasm_bindgen]
     pub fn dump_debug_data(&self) -> EmulatorDebugState {
-        let (nametable, palette, chr) = self.nes.dump_debug_data();
+        let data = self.nes.dump_debug_data();
         return EmulatorDebugState {
-            nametable: Uint8Array::from(nametable),
-            palette: Uint8Array::from(palette),
-            chr: Uint8Array::from(chr),
+            nametable: Uint8Array::from(&data.nametables[..]),
+            palette: Uint8Array::from(&data.palette[..]),
+            chr: Uint8Array::from(&data.chr[..]),
+        };
+    }
+
+    /// Decode one of the four logical nametables
+    ///
+    /// Only the low 2 bits of `idx` are used, like PPUCTRL's nametable select.
+    #[wasm_bindgen]
+    pub fn decode_nametable(&self, idx: u8) -> DecodedNametable {
+        let entries = self.nes.decode_nametable((idx & 0x03) as usize);
+        let tiles: Vec<u8> = entries.iter().map(|entry| entry.tile).collect();
+        let pattern_addrs: Vec<u16> = entries.iter().map(|entry| entry.pattern_addr).collect();
+        let palettes: Vec<u8> = entries.iter().map(|entry| entry.palette).collect();
+        return DecodedNametable {
+            tiles: Uint8Array::from(&tiles[..]),
+            pattern_addrs: Uint16Array::from(&pattern_addrs[..]),
+            palettes: Uint8Array::from(&palettes[..]),
+        };
+    }
+
+    /// Decode the attribute table of one of the four logical nametables, as 16
+    /// by 15 palette indices in row-major order
+    #[wasm_bindgen]
+    pub fn decode_attributes(&self, idx: u8) -> Uint8Array {
+        Uint8Array::from(&self.nes.decode_attributes((idx & 0x03) as usize)[..])
+    }
+
+    #[wasm_bindgen]
+    pub fn ppu_debug_state(&self) -> PpuDebugInfo {
+        let view = self.nes.ppu_debug_state();
+        return PpuDebugInfo {
+            v: view.v,
+            t: view.t,
+            fine_x: view.fine_x,
+            write_latch: view.write_latch,
+            scanline: view.scanline,
+            dot: view.dot,
+            control: view.control,
+            mask: view.mask,
+            status: view.status,
+            sprite_0_hit: view.sprite_0_hit,
+            sprite_overflow: view.sprite_overflow,
+            nmi_pending: view.nmi_pending,
+            bg_tile_hi_shift_reg: view.bg_tile_hi_shift_reg,
+            bg_tile_lo_shift_reg: view.bg_tile_lo_shift_reg,
+            bg_attr_hi_shift_reg: view.bg_attr_hi_shift_reg,
+            bg_attr_lo_shift_reg: view.bg_attr_lo_shift_reg,
+            sprite_tile_hi_shift_regs: Uint8Array::from(&view.sprite_tile_hi_shift_regs[..]),
+            sprite_tile_lo_shift_regs: Uint8Array::from(&view.sprite_tile_lo_shift_regs[..]),
+        };
+    }
+
+    /// The scanline the PPU is on, from 0 to 261
+    #[wasm_bindgen]
+    pub fn current_scanline(&self) -> u16 {
+        self.nes.current_scanline()
+    }
+
+    /// The dot the PPU is on in the current scanline, from 0 to 340
+    #[wasm_bindgen]
+    pub fn current_dot(&self) -> u16 {
+        self.nes.current_dot()
+    }
+
+    /// The number of frames finished since power-on
+    ///
+    /// This is a plain JS number rather than a BigInt, which stays exact for
+    /// far longer than anyone will run the emulator.
+    #[wasm_bindgen]
+    pub fn frame_count(&self) -> f64 {
+        self.nes.frame_count() as f64
+    }
+
+    /// The id of the most recent frame, for spotting repeated or dropped
+    /// frames, as a plain JS number
+    #[wasm_bindgen]
+    pub fn frame_id(&self) -> f64 {
+        self.nes.frame_id() as f64
+    }
+
+    /// The number of CPU cycles since power-on, as a plain JS number
+    #[wasm_bindgen]
+    pub fn cpu_cycles(&self) -> f64 {
+        self.nes.cpu_cycles() as f64
+    }
+
+    /// Checksum the console's state, to check that two copies running in
+    /// lockstep haven't desynced
+    #[wasm_bindgen]
+    pub fn state_checksum(&self) -> u32 {
+        self.nes.state_checksum()
+    }
+
+    /// Encode the most recent frame as a PNG, e.g. for downloading
+    #[cfg(feature = "png")]
+    #[wasm_bindgen]
+    pub fn screenshot_png(&self) -> Uint8Array {
+        return Uint8Array::from(&self.nes.screenshot_png()[..]);
+    }
+
+    /// Run until the start of the next vblank
+    #[wasm_bindgen]
+    pub fn run_to_vblank(&mut self) {
+        self.nes.run_until(Breakpoint::NextVblankStart);
+    }
+
+    /// Run until the start of the next time the PPU reaches `scanline`
+    #[wasm_bindgen]
+    pub fn run_to_scanline(&mut self, scanline: i16) {
+        self.nes.run_until(Breakpoint::NextScanline(scanline));
+    }
+
+    /// Run until the next time the PPU reaches `dot` on `scanline`
+    #[wasm_bindgen]
+    pub fn run_to_dot(&mut self, scanline: i16, dot: u16) {
+        self.nes.run_until(Breakpoint::PpuDot { scanline, dot });
+    }
+
+    /// Run until `count` more CPU instructions have finished
+    #[wasm_bindgen]
+    pub fn step_instructions(&mut self, count: u32) {
+        self.nes
+            .run_until(Breakpoint::CpuInstructionCount(u64::from(count)));
+    }
+
+    #[wasm_bindgen]
+    pub fn frame_info(&self) -> FrameInfo {
+        let info = self.nes.frame_info();
+        return FrameInfo {
+            width: info.width as u32,
+            height: info.height as u32,
+            pixel_aspect_ratio: info.pixel_aspect_ratio,
         };
     }
 
+    /// Upscale the most recent frame by a whole number, with nearest-neighbor
+    /// scaling, for canvases that would blur it
+    #[wasm_bindgen]
+    pub fn scale_frame(&self, scale: u32) -> Uint8Array {
+        return Uint8Array::from(&self.nes.scale_frame(scale.max(1) as usize)[..]);
+    }
+
+    /// Start or stop tracking which parts of each frame change
+    #[wasm_bindgen]
+    pub fn set_dirty_tracking(&mut self, enabled: bool) {
+        self.nes.set_dirty_tracking(enabled);
+    }
+
+    /// The parts of the last frame that changed from the one before, packed
+    /// as (x, y, width, height) in pixels
+    ///
+    /// Without dirty tracking, this is always the whole frame.
+    #[wasm_bindgen]
+    pub fn dirty_rects(&self) -> Uint16Array {
+        let mut packed: Vec<u16> = Vec::new();
+        for rect in self.nes.dirty_rects() {
+            packed.extend_from_slice(&[rect.x, rect.y, rect.width, rect.height]);
+        }
+        return Uint16Array::from(&packed[..]);
+    }
+
+    /// Start or stop drawing frames as NES color numbers, for
+    /// `step_frame_indexed`
+    #[wasm_bindgen]
+    pub fn set_indexed_output(&mut self, enabled: bool) {
+        self.nes.set_indexed_output(enabled);
+    }
+
+    /// Run the next frame, and return it as NES color numbers, followed by
+    /// each line's emphasis bits and then palette RAM
+    ///
+    /// Look the colors up in `output_palette` to get RGB. This returns
+    /// nothing (and doesn't run the frame) unless indexed output is on.
+    #[wasm_bindgen]
+    pub fn step_frame_indexed(&mut self) -> Option<Uint8Array> {
+        let frame = self.nes.tick_frame_indexed()?;
+        Some(Uint8Array::from(frame.as_bytes()))
+    }
+
+    /// The RGB for every color and emphasis, as 512 triplets indexed by
+    /// `(emphasis << 6) | color`
+    #[wasm_bindgen]
+    pub fn output_palette(&self) -> Uint8Array {
+        Uint8Array::from(self.nes.output_palette().as_bytes())
+    }
+
     #[wasm_bindgen]
     pub fn step_frame(&mut self) -> Uint8Array {
         let buf = self.nes.tick_frame();
         return Uint8Array::from(buf);
     }
+
+    /// Hold the buttons on both controllers for the next frame, then run it
+    ///
+    /// The bitmasks are in `Buttons` order, like `set_buttons`.
+    #[wasm_bindgen]
+    pub fn step_frame_with_input(&mut self, p1: u8, p2: u8) -> Uint8Array {
+        let buf = self.nes.tick_frame_with_input(
+            Buttons::from_bits_truncate(p1),
+            Buttons::from_bits_truncate(p2),
+        );
+        return Uint8Array::from(buf);
+    }
+
+    /// Run however many frames are due after `elapsed_ms` milliseconds, and
+    /// return the most recent frame
+    ///
+    /// Call this from `requestAnimationFrame` with the time since the last
+    /// call, and frames come at the console's rate whatever the display's.
+    #[wasm_bindgen]
+    pub fn run_for(&mut self, elapsed_ms: f64) -> Uint8Array {
+        // more than a second is well past what `run_for` catches up anyway,
+        // and keeps Infinity from panicking here
+        let elapsed = Duration::from_secs_f64(elapsed_ms.max(0.0).min(1000.0) / 1000.0);
+        return Uint8Array::from(self.nes.run_for(elapsed));
+    }
+
+    /// How fast `run_for` runs, as a multiple of real time
+    #[wasm_bindgen]
+    pub fn speed(&self) -> f32 {
+        self.nes.speed()
+    }
+
+    /// Make `run_for` run faster or slower, from 0.25x to 4x
+    #[wasm_bindgen]
+    pub fn set_speed(&mut self, speed: f32) {
+        self.nes.set_speed(speed);
+    }
+
+    #[wasm_bindgen]
+    pub fn pause(&mut self) {
+        self.nes.pause();
+    }
+
+    #[wasm_bindgen]
+    pub fn resume(&mut self) {
+        self.nes.resume();
+    }
+
+    #[wasm_bindgen]
+    pub fn is_paused(&self) -> bool {
+        self.nes.is_paused()
+    }
+
+    /// Pause, then run exactly one frame and return it
+    #[wasm_bindgen]
+    pub fn frame_advance(&mut self) -> Uint8Array {
+        return Uint8Array::from(self.nes.frame_advance());
+    }
+
+    /// How much time has gone by on the console since power-on, in
+    /// milliseconds
+    #[wasm_bindgen]
+    pub fn emulated_time(&self) -> f64 {
+        self.nes.emulated_time().as_secs_f64() * 1000.0
+    }
+
+    /// Pause `run_for` once an expression like `a == 0xFF && pc in
+    /// 0x8000..0x9000` holds after an instruction
+    ///
+    /// Labels from the symbols can stand in for numbers. If the expression
+    /// doesn't parse, this throws an `InvalidWatch` error.
+    #[wasm_bindgen]
+    pub fn add_watch(&mut self, expr: &str) -> Result<(), JsValue> {
+        let watch = Watch::parse_with_symbols(expr, self.nes.symbols()).map_err(to_js_error)?;
+        self.nes.add_watch(watch);
+        Ok(())
+    }
+
+    #[wasm_bindgen]
+    pub fn clear_watches(&mut self) {
+        self.nes.clear_watches();
+    }
+
+    /// Whether a watch paused `run_for` since the last call
+    #[wasm_bindgen]
+    pub fn take_watch_hit(&mut self) -> bool {
+        self.nes.take_watch_hit().is_some()
+    }
+
+    /// Pause `run_for` when the CPU is about to run the code at `label`,
+    /// returning whether the label is in the symbols
+    #[wasm_bindgen]
+    pub fn break_at(&mut self, label: &str) -> bool {
+        self.nes.break_at(label).is_some()
+    }
+
+    /// Add the labels from an FCEUX .nl file to the symbols
+    ///
+    /// If the file can't be read, this throws an `InvalidSymbolFile` error
+    /// and the symbols are left alone.
+    #[wasm_bindgen]
+    pub fn load_nl_symbols(&mut self, text: &str) -> Result<(), JsValue> {
+        let mut symbols = self.nes.symbols().clone();
+        symbols.extend(Symbols::from_nl(text).map_err(to_js_error)?);
+        self.nes.set_symbols(symbols);
+        Ok(())
+    }
+
+    /// Add the labels from a Mesen .mlb file to the symbols, for a ROM with
+    /// `prg_size` bytes of PRG ROM
+    ///
+    /// If the file can't be read, this throws an `InvalidSymbolFile` error
+    /// and the symbols are left alone.
+    #[wasm_bindgen]
+    pub fn load_mlb_symbols(&mut self, text: &str, prg_size: u32) -> Result<(), JsValue> {
+        let mut symbols = self.nes.symbols().clone();
+        symbols.extend(Symbols::from_mlb(text, prg_size as usize).map_err(to_js_error)?);
+        self.nes.set_symbols(symbols);
+        Ok(())
+    }
+
+    #[wasm_bindgen]
+    pub fn clear_symbols(&mut self) {
+        self.nes.set_symbols(Symbols::new());
+    }
+
+    /// The label for `addr`, or `undefined`
+    #[wasm_bindgen]
+    pub fn symbol_label(&self, addr: u16) -> Option<String> {
+        self.nes.symbols().label(addr).map(String::from)
+    }
 }
 
 /// Installs a global panic handler to make debugging easier
+///
+/// With the `console-log` feature, this also sends warnings and errors from the
+/// core to the console. See `set_log_level` to see more.
 #[wasm_bindgen]
 pub fn init_debug_hooks() {
     panic::set_hook(Box::new(console_error_panic_hook::hook));
+    #[cfg(feature = "console-log")]
+    let _ = log::set_logger(&CONSOLE_LOGGER).map(|()| log::set_max_level(log::LevelFilter::Warn));
+}
+
+/// Set how much of the core's logging goes to the console
+///
+/// `level` is one of "off", "error", "warn", "info", "debug", or "trace", and
+/// this returns false if it isn't. Trace logging happens several times a
+/// frame, so expect it to slow emulation down. Without the `console-log`
+/// feature there's nowhere for logs to go, so this does nothing.
+#[wasm_bindgen]
+pub fn set_log_level(level: &str) -> bool {
+    match level.parse::<log::LevelFilter>() {
+        Ok(filter) => {
+            log::set_max_level(filter);
+            true
+        }
+        Err(_) => false,
+    }
 }
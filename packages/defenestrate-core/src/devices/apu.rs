@@ -0,0 +1,1280 @@
+//! The APU: five sound channels, the frame counter, and APUSTATUS
+//!
+//! Besides the channels' output, this keeps track of everything a game can
+//! see through $4015: each channel's length counter, the DMC's remaining
+//! sample bytes, and the frame and DMC IRQ flags. Music engines poll those
+//! (and some sync to the frame IRQ), so they count down at the right rate
+//! whether anyone is listening or not.
+//!
+//! `Apu::output` mixes the channels the way the console's resistor network
+//! does, once per CPU cycle. Turning that into samples at a rate a sound card
+//! can play is up to `AudioOutput`.
+//!
+//! The DMC's sample fetches go through the CPU bus, which the APU can't see,
+//! so the console services them after each cycle with `take_dmc_fetch` and
+//! `fill_dmc_buffer`. The CPU isn't stalled for them.
+//!
+//! cf. https://wiki.nesdev.com/w/index.php/APU
+
+/// The lengths a length counter can be loaded with, indexed by the top 5 bits
+/// of the channel's last register
+///
+/// cf. https://wiki.nesdev.com/w/index.php/APU_Length_Counter
+const LENGTH_TABLE: [u8; 32] = [
+    10, 254, 20, 2, 40, 4, 80, 6, 160, 8, 60, 10, 14, 12, 26, 14, 12, 16, 24, 18, 48, 20, 96, 22,
+    192, 24, 72, 26, 16, 28, 32, 30,
+];
+
+/// The CPU cycles between each bit the DMC plays, on NTSC consoles
+///
+/// cf. https://wiki.nesdev.com/w/index.php/APU_DMC
+const DMC_RATES: [u16; 16] = [
+    428, 380, 340, 320, 286, 254, 226, 214, 190, 160, 142, 128, 106, 84, 72, 54,
+];
+
+/// The pulse channels' waveforms, by the duty cycle in the top bits of
+/// $4000/$4004, in the order the sequencer plays them
+///
+/// cf. https://wiki.nesdev.com/w/index.php/APU_Pulse
+const DUTY_TABLE: [[u8; 8]; 4] = [
+    [0, 1, 0, 0, 0, 0, 0, 0],
+    [0, 1, 1, 0, 0, 0, 0, 0],
+    [0, 1, 1, 1, 1, 0, 0, 0],
+    [1, 0, 0, 1, 1, 1, 1, 1],
+];
+
+/// The triangle channel's 32-step waveform
+const TRIANGLE_SEQUENCE: [u8; 32] = [
+    15, 14, 13, 12, 11, 10, 9, 8, 7, 6, 5, 4, 3, 2, 1, 0, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12,
+    13, 14, 15,
+];
+
+/// The CPU cycles between each step of the noise channel's shift register, on
+/// NTSC consoles, indexed by the low 4 bits of $400E
+///
+/// cf. https://wiki.nesdev.com/w/index.php/APU_Noise
+const NOISE_PERIODS: [u16; 16] = [
+    4, 8, 16, 32, 64, 96, 128, 160, 202, 254, 380, 508, 762, 1016, 2034, 4068,
+];
+
+/// How loud a cartridge's expansion audio is, at full scale, next to the
+/// APU's own channels
+///
+/// This is about as loud as one pulse channel at full volume. The real levels
+/// vary from board to board, and from one console to the next.
+const EXPANSION_LEVEL: f32 = 0.15;
+
+/// The CPU cycles (after the frame counter is reset) that it clocks the
+/// envelopes and the triangle's linear counter on, in both modes
+const QUARTER_FRAME_STEPS: [u16; 3] = [7457, 14913, 22371];
+/// The CPU cycles the 4-step sequence ends on
+///
+/// The frame IRQ flag is set on all three of these, and the last is where the
+/// sequence starts over.
+const FOUR_STEP_END: [u16; 3] = [29828, 29829, 29830];
+/// The CPU cycle the 5-step sequence's last step is on, and the one after it
+/// where the sequence starts over
+const FIVE_STEP_END: [u16; 2] = [37281, 37282];
+
+bitflags! {
+    /// The bits of APUSTATUS
+    ///
+    /// Writes enable and disable the channels with the low 5 bits, and reads
+    /// get all of these back (with bit 5 left open).
+    ///
+    /// cf. https://wiki.nesdev.com/w/index.php/APU#Status_.28.244015.29
+    #[derive(Default)]
+    pub struct ApuStatus: u8 {
+        const PULSE_1 = 0x01;
+        const PULSE_2 = 0x02;
+        const TRIANGLE = 0x04;
+        const NOISE = 0x08;
+        /// Whether the DMC has sample bytes left to play
+        const DMC = 0x10;
+        const FRAME_IRQ = 0x40;
+        const DMC_IRQ = 0x80;
+    }
+}
+
+/// The sound channels, for muting with `Apu::set_channel_enabled`
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Channel {
+    Pulse1,
+    Pulse2,
+    Triangle,
+    Noise,
+    Dmc,
+    /// Whatever expansion audio the cartridge has
+    Expansion,
+}
+
+/// How long a channel keeps playing, in half frames
+#[derive(Debug, Default, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+struct LengthCounter {
+    enabled: bool,
+    /// Whether the count is paused, for notes that play until they're stopped
+    halted: bool,
+    count: u8,
+}
+
+impl LengthCounter {
+    /// Enable or disable the channel from $4015, which zeroes the count
+    fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        if !enabled {
+            self.count = 0;
+        }
+    }
+
+    /// Load the count from the top 5 bits of `value`, if the channel is on
+    fn load(&mut self, value: u8) {
+        if self.enabled {
+            self.count = LENGTH_TABLE[(value >> 3) as usize];
+        }
+    }
+
+    /// Clock the counter on a half frame
+    fn clock(&mut self) {
+        if !self.halted && self.count > 0 {
+            self.count -= 1;
+        }
+    }
+
+    fn is_active(&self) -> bool {
+        self.count > 0
+    }
+}
+
+/// The volume envelope on the pulse and noise channels
+///
+/// cf. https://wiki.nesdev.com/w/index.php/APU_Envelope
+#[derive(Debug, Default, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+struct Envelope {
+    /// Whether the envelope restarts on the next quarter frame
+    start: bool,
+    /// Whether the envelope starts over at 15 instead of stopping at 0
+    looping: bool,
+    /// Whether the volume is fixed at `period` instead of decaying
+    constant: bool,
+    /// The divider's period, which is also the constant volume
+    period: u8,
+    divider: u8,
+    decay: u8,
+}
+
+impl Envelope {
+    /// Handle a write to the channel's first register, which the envelope
+    /// shares with the length counter's halt flag
+    fn write(&mut self, value: u8) {
+        self.looping = value & 0x20 != 0;
+        self.constant = value & 0x10 != 0;
+        self.period = value & 0x0F;
+    }
+
+    /// Clock the envelope on a quarter frame
+    fn clock(&mut self) {
+        if self.start {
+            self.start = false;
+            self.decay = 15;
+            self.divider = self.period;
+        } else if self.divider == 0 {
+            self.divider = self.period;
+            if self.decay > 0 {
+                self.decay -= 1;
+            } else if self.looping {
+                self.decay = 15;
+            }
+        } else {
+            self.divider -= 1;
+        }
+    }
+
+    fn volume(&self) -> u8 {
+        if self.constant {
+            self.period
+        } else {
+            self.decay
+        }
+    }
+}
+
+/// The pitch sweep on the pulse channels
+///
+/// cf. https://wiki.nesdev.com/w/index.php/APU_Sweep
+#[derive(Debug, Default, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+struct Sweep {
+    enabled: bool,
+    period: u8,
+    negate: bool,
+    shift: u8,
+    /// Whether the divider restarts on the next half frame
+    reload: bool,
+    divider: u8,
+    /// Whether negating subtracts an extra 1, which pulse 1 does since it
+    /// negates with the ones' complement
+    ones_complement: bool,
+}
+
+impl Sweep {
+    fn write(&mut self, value: u8) {
+        self.enabled = value & 0x80 != 0;
+        self.period = (value >> 4) & 0x07;
+        self.negate = value & 0x08 != 0;
+        self.shift = value & 0x07;
+        self.reload = true;
+    }
+
+    /// The period the sweep is heading for from `period`
+    fn target(&self, period: u16) -> u16 {
+        let change = period >> self.shift;
+        if !self.negate {
+            period + change
+        } else if self.ones_complement {
+            period.saturating_sub(change + 1)
+        } else {
+            period.saturating_sub(change)
+        }
+    }
+
+    /// Whether the channel is silenced, which the sweep does for periods out
+    /// of range whether it's enabled or not
+    fn mutes(&self, period: u16) -> bool {
+        period < 8 || self.target(period) > 0x7FF
+    }
+
+    /// Clock the sweep on a half frame, updating the channel's `period`
+    fn clock(&mut self, period: &mut u16) {
+        if self.divider == 0 && self.enabled && self.shift > 0 && !self.mutes(*period) {
+            *period = self.target(*period);
+        }
+        if self.divider == 0 || self.reload {
+            self.divider = self.period;
+            self.reload = false;
+        } else {
+            self.divider -= 1;
+        }
+    }
+}
+
+/// One of the two square wave channels, at $4000-$4003 and $4004-$4007
+#[derive(Debug, Default, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+struct Pulse {
+    length: LengthCounter,
+    envelope: Envelope,
+    sweep: Sweep,
+    /// The 11-bit timer period, which sets the pitch
+    period: u16,
+    /// Which row of `DUTY_TABLE` to play
+    duty: u8,
+    /// The step of the waveform being played
+    sequence: u8,
+    /// APU cycles left until the next step
+    timer: u16,
+}
+
+impl Pulse {
+    fn new(ones_complement: bool) -> Pulse {
+        Pulse {
+            sweep: Sweep {
+                ones_complement,
+                ..Sweep::default()
+            },
+            ..Pulse::default()
+        }
+    }
+
+    fn write(&mut self, reg: u16, value: u8) {
+        match reg {
+            0 => {
+                self.duty = value >> 6;
+                self.length.halted = value & 0x20 != 0;
+                self.envelope.write(value);
+            }
+            1 => self.sweep.write(value),
+            2 => self.period = (self.period & 0x700) | value as u16,
+            _ => {
+                self.period = (self.period & 0xFF) | ((value as u16 & 0x07) << 8);
+                self.length.load(value);
+                self.envelope.start = true;
+                self.sequence = 0;
+            }
+        }
+    }
+
+    /// Clock the timer once per APU cycle, stepping through the waveform
+    fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.period;
+            self.sequence = (self.sequence + 1) % 8;
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    /// The channel's output, from 0 to 15
+    fn output(&self) -> u8 {
+        if DUTY_TABLE[self.duty as usize][self.sequence as usize] == 0 {
+            0
+        } else {
+            self.volume()
+        }
+    }
+
+    fn volume(&self) -> u8 {
+        if !self.length.is_active() || self.sweep.mutes(self.period) {
+            0
+        } else {
+            self.envelope.volume()
+        }
+    }
+}
+
+/// The triangle channel, at $4008-$400B
+#[derive(Debug, Default, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+struct Triangle {
+    length: LengthCounter,
+    /// A second, finer length counter, clocked every quarter frame
+    linear_counter: u8,
+    linear_reload_value: u8,
+    linear_reload: bool,
+    /// The 11-bit timer period, which sets the pitch
+    period: u16,
+    /// The step of `TRIANGLE_SEQUENCE` being played
+    sequence: u8,
+    /// CPU cycles left until the next step
+    timer: u16,
+}
+
+impl Triangle {
+    fn write(&mut self, reg: u16, value: u8) {
+        match reg {
+            0 => {
+                // this bit both halts the length counter and keeps the linear
+                // counter reloading
+                self.length.halted = value & 0x80 != 0;
+                self.linear_reload_value = value & 0x7F;
+            }
+            2 => self.period = (self.period & 0x700) | value as u16,
+            3 => {
+                self.period = (self.period & 0xFF) | ((value as u16 & 0x07) << 8);
+                self.length.load(value);
+                self.linear_reload = true;
+            }
+            _ => {}
+        }
+    }
+
+    /// Clock the timer once per CPU cycle, stepping through the waveform
+    /// while both counters are running
+    ///
+    /// A stopped triangle holds whatever step it was on, rather than dropping
+    /// to 0, so it doesn't pop.
+    fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.period;
+            if self.linear_counter > 0 && self.length.is_active() {
+                self.sequence = (self.sequence + 1) % 32;
+            }
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    /// The channel's output, from 0 to 15
+    fn output(&self) -> u8 {
+        TRIANGLE_SEQUENCE[self.sequence as usize]
+    }
+
+    /// Clock the linear counter on a quarter frame
+    ///
+    /// cf. https://wiki.nesdev.com/w/index.php/APU_Triangle
+    fn clock_linear_counter(&mut self) {
+        if self.linear_reload {
+            self.linear_counter = self.linear_reload_value;
+        } else if self.linear_counter > 0 {
+            self.linear_counter -= 1;
+        }
+        if !self.length.halted {
+            self.linear_reload = false;
+        }
+    }
+}
+
+/// The noise channel, at $400C-$400F
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+struct Noise {
+    length: LengthCounter,
+    envelope: Envelope,
+    /// Whether the shift register feeds back from bit 6 instead of bit 1,
+    /// which makes a short, more tonal loop
+    short_mode: bool,
+    /// CPU cycles between each step of the shift register
+    period: u16,
+    timer: u16,
+    /// The 15-bit linear feedback shift register the noise comes from
+    shift: u16,
+}
+
+impl Noise {
+    fn new() -> Noise {
+        Noise {
+            length: LengthCounter::default(),
+            envelope: Envelope::default(),
+            short_mode: false,
+            period: NOISE_PERIODS[0],
+            timer: 0,
+            shift: 1,
+        }
+    }
+
+    fn write(&mut self, reg: u16, value: u8) {
+        match reg {
+            0 => {
+                self.length.halted = value & 0x20 != 0;
+                self.envelope.write(value);
+            }
+            2 => {
+                self.short_mode = value & 0x80 != 0;
+                self.period = NOISE_PERIODS[(value & 0x0F) as usize];
+            }
+            3 => {
+                self.length.load(value);
+                self.envelope.start = true;
+            }
+            _ => {}
+        }
+    }
+
+    /// Clock the timer once per CPU cycle, shifting the register when it runs
+    /// out
+    fn clock_timer(&mut self) {
+        if self.timer > 0 {
+            self.timer -= 1;
+            return;
+        }
+        self.timer = self.period - 1;
+        let tap = if self.short_mode { 6 } else { 1 };
+        let feedback = (self.shift ^ (self.shift >> tap)) & 1;
+        self.shift = (self.shift >> 1) | (feedback << 14);
+    }
+
+    /// The channel's output, from 0 to 15
+    fn output(&self) -> u8 {
+        if self.shift & 1 == 0 {
+            self.volume()
+        } else {
+            0
+        }
+    }
+
+    fn volume(&self) -> u8 {
+        if self.length.is_active() {
+            self.envelope.volume()
+        } else {
+            0
+        }
+    }
+}
+
+/// The delta modulation channel, at $4010-$4013
+///
+/// This plays 1-bit deltas from sample bytes in PRG space, nudging a 7-bit
+/// output level up or down by 2 for each bit. The buffer counts as full as
+/// soon as a fetch is asked for, so that the bytes left and the IRQ at the end
+/// come at the same time whether or not anything services the fetches.
+///
+/// cf. https://wiki.nesdev.com/w/index.php/APU_DMC
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+struct Dmc {
+    irq_enabled: bool,
+    looping: bool,
+    /// CPU cycles between each bit of output
+    rate: u16,
+    /// The length of the sample, in bytes
+    sample_length: u16,
+    bytes_remaining: u16,
+    /// Whether a sample byte has been fetched and is waiting to play
+    buffer_full: bool,
+    bits_remaining: u8,
+    timer: u16,
+    irq: bool,
+    /// The 7-bit output level, which $4011 sets directly
+    level: u8,
+    /// Where the sample starts, from $4012
+    sample_address: u16,
+    /// Where the next sample byte comes from
+    current_address: u16,
+    /// The byte waiting to play, once it's been fetched
+    sample_buffer: u8,
+    /// The bits of the byte being played, lowest first
+    shift: u8,
+    /// Whether the byte being played never arrived, which holds the level
+    silent: bool,
+    /// The address of a sample byte that needs to be read from the bus
+    pending_fetch: Option<u16>,
+}
+
+impl Dmc {
+    fn new() -> Dmc {
+        Dmc {
+            irq_enabled: false,
+            looping: false,
+            rate: DMC_RATES[0],
+            sample_length: 1,
+            bytes_remaining: 0,
+            buffer_full: false,
+            bits_remaining: 8,
+            timer: DMC_RATES[0],
+            irq: false,
+            level: 0,
+            sample_address: 0xC000,
+            current_address: 0xC000,
+            sample_buffer: 0,
+            shift: 0,
+            silent: true,
+            pending_fetch: None,
+        }
+    }
+
+    fn write(&mut self, reg: u16, value: u8) {
+        match reg {
+            0 => {
+                self.irq_enabled = value & 0x80 != 0;
+                self.looping = value & 0x40 != 0;
+                self.rate = DMC_RATES[(value & 0x0F) as usize];
+                if !self.irq_enabled {
+                    self.irq = false;
+                }
+            }
+            1 => self.level = value & 0x7F,
+            2 => self.sample_address = 0xC000 + (value as u16) * 64,
+            _ => self.sample_length = (value as u16) * 16 + 1,
+        }
+    }
+
+    fn set_enabled(&mut self, enabled: bool) {
+        if !enabled {
+            self.bytes_remaining = 0;
+        } else if self.bytes_remaining == 0 {
+            self.bytes_remaining = self.sample_length;
+            self.current_address = self.sample_address;
+            self.fill_buffer();
+        }
+    }
+
+    /// Fetch the next sample byte, if the buffer has room for one
+    fn fill_buffer(&mut self) {
+        if self.buffer_full || self.bytes_remaining == 0 {
+            return;
+        }
+        self.buffer_full = true;
+        self.pending_fetch = Some(self.current_address);
+        // the address wraps around to $8000, not $0000
+        self.current_address = self.current_address.checked_add(1).unwrap_or(0x8000);
+        self.bytes_remaining -= 1;
+        if self.bytes_remaining == 0 {
+            if self.looping {
+                self.bytes_remaining = self.sample_length;
+                self.current_address = self.sample_address;
+            } else if self.irq_enabled {
+                self.irq = true;
+            }
+        }
+    }
+
+    /// Clock the DMC once per CPU cycle
+    fn clock(&mut self) {
+        self.timer -= 1;
+        if self.timer > 0 {
+            return;
+        }
+        self.timer = self.rate;
+        if !self.silent {
+            if self.shift & 1 != 0 {
+                if self.level <= 125 {
+                    self.level += 2;
+                }
+            } else if self.level >= 2 {
+                self.level -= 2;
+            }
+            self.shift >>= 1;
+        }
+        self.bits_remaining -= 1;
+        if self.bits_remaining == 0 {
+            // start playing the byte in the buffer, and go get the next one
+            self.bits_remaining = 8;
+            self.silent = !self.buffer_full;
+            self.shift = self.sample_buffer;
+            self.buffer_full = false;
+            self.fill_buffer();
+        }
+    }
+}
+
+/// The APU's registers, length counters, and frame counter
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Apu {
+    pulse_1: Pulse,
+    pulse_2: Pulse,
+    triangle: Triangle,
+    noise: Noise,
+    dmc: Dmc,
+    /// CPU cycles since the frame counter's sequence started
+    frame_cycle: u16,
+    /// Whether the frame counter is in 5-step mode
+    five_step: bool,
+    irq_inhibit: bool,
+    frame_irq: bool,
+    /// A write to $4017 that hasn't taken effect yet, and the CPU cycles left
+    /// until it does
+    pending_frame_write: Option<(u8, u8)>,
+    /// Whether this is the second half of an APU cycle
+    odd_cycle: bool,
+    /// Which channels are left out of `output`, indexed by `Channel`
+    ///
+    /// This is a setting for the listener rather than part of the console,
+    /// so it stays the same through power cycles and loading states.
+    muted: [bool; 6],
+}
+
+impl Apu {
+    /// An APU in its power-on state, where the frame counter is running in
+    /// 4-step mode with its IRQ enabled
+    pub fn new() -> Apu {
+        Apu {
+            pulse_1: Pulse::new(true),
+            pulse_2: Pulse::new(false),
+            triangle: Triangle::default(),
+            noise: Noise::new(),
+            dmc: Dmc::new(),
+            frame_cycle: 0,
+            five_step: false,
+            irq_inhibit: false,
+            frame_irq: false,
+            pending_frame_write: None,
+            odd_cycle: false,
+            muted: [false; 6],
+        }
+    }
+
+    /// Go back to the power-on state, keeping the channels that are muted
+    pub(crate) fn power_on(&mut self) {
+        *self = Apu {
+            muted: self.muted,
+            ..Apu::new()
+        };
+    }
+
+    /// Pick up from the state in `apu`, keeping the channels that are muted
+    pub(crate) fn restore(&mut self, apu: Apu) {
+        *self = Apu {
+            muted: self.muted,
+            ..apu
+        };
+    }
+
+    /// Handle the console's reset button, which silences every channel and
+    /// restarts the frame counter in the mode it was in
+    pub fn reset(&mut self) {
+        self.write_status(0x00);
+        let mode = if self.five_step { 0x80 } else { 0x00 };
+        let inhibit = if self.irq_inhibit { 0x40 } else { 0x00 };
+        self.write_frame_counter(mode | inhibit, 0);
+    }
+
+    /// Write to an APU register, given as an offset from $4000
+    pub fn write(&mut self, addr: u16, value: u8) {
+        match addr {
+            0x00..=0x03 => self.pulse_1.write(addr, value),
+            0x04..=0x07 => self.pulse_2.write(addr - 0x04, value),
+            0x08..=0x0B => self.triangle.write(addr - 0x08, value),
+            0x0C..=0x0F => self.noise.write(addr - 0x0C, value),
+            0x10..=0x13 => self.dmc.write(addr - 0x10, value),
+            0x15 => self.write_status(value),
+            0x17 => {
+                // the write lands 3 or 4 CPU cycles later, depending on which
+                // half of an APU cycle it's on
+                let delay = if self.odd_cycle { 4 } else { 3 };
+                self.write_frame_counter(value, delay);
+            }
+            _ => {}
+        }
+    }
+
+    /// Read APUSTATUS, which acknowledges the frame IRQ
+    pub fn read_status(&mut self) -> u8 {
+        let status = self.peek_status();
+        self.frame_irq = false;
+        status.bits()
+    }
+
+    /// What reading APUSTATUS would return, without acknowledging anything
+    pub fn peek_status(&self) -> ApuStatus {
+        let mut status = ApuStatus::empty();
+        status.set(ApuStatus::PULSE_1, self.pulse_1.length.is_active());
+        status.set(ApuStatus::PULSE_2, self.pulse_2.length.is_active());
+        status.set(ApuStatus::TRIANGLE, self.triangle.length.is_active());
+        status.set(ApuStatus::NOISE, self.noise.length.is_active());
+        status.set(ApuStatus::DMC, self.dmc.bytes_remaining > 0);
+        status.set(ApuStatus::FRAME_IRQ, self.frame_irq);
+        status.set(ApuStatus::DMC_IRQ, self.dmc.irq);
+        status
+    }
+
+    /// Each channel's length counter, in pulse 1, pulse 2, triangle, noise
+    /// order
+    pub fn length_counters(&self) -> [u8; 4] {
+        [
+            self.pulse_1.length.count,
+            self.pulse_2.length.count,
+            self.triangle.length.count,
+            self.noise.length.count,
+        ]
+    }
+
+    /// The volume the pulse 1, pulse 2, and noise channels would play at,
+    /// from their envelopes, length counters, and sweeps
+    pub fn volumes(&self) -> [u8; 3] {
+        [
+            self.pulse_1.volume(),
+            self.pulse_2.volume(),
+            self.noise.volume(),
+        ]
+    }
+
+    /// The pulse channels' timer periods, as moved by their sweeps
+    pub fn pulse_periods(&self) -> [u16; 2] {
+        [self.pulse_1.period, self.pulse_2.period]
+    }
+
+    /// The triangle channel's linear counter
+    pub fn linear_counter(&self) -> u8 {
+        self.triangle.linear_counter
+    }
+
+    /// The DMC's output level, from 0 to 127
+    pub fn dmc_level(&self) -> u8 {
+        self.dmc.level
+    }
+
+    /// Whether the frame counter is holding the IRQ line
+    pub fn frame_irq(&self) -> bool {
+        self.frame_irq
+    }
+
+    /// Whether the DMC is holding the IRQ line
+    pub fn dmc_irq(&self) -> bool {
+        self.dmc.irq
+    }
+
+    /// The address of a DMC sample byte to read from the CPU bus, if the DMC
+    /// is waiting on one
+    ///
+    /// Whatever's read there should go to `fill_dmc_buffer`.
+    pub fn take_dmc_fetch(&mut self) -> Option<u16> {
+        self.dmc.pending_fetch.take()
+    }
+
+    /// Hand the DMC the sample byte `take_dmc_fetch` asked for
+    pub fn fill_dmc_buffer(&mut self, value: u8) {
+        self.dmc.sample_buffer = value;
+    }
+
+    /// The level of every channel mixed together, with a cartridge's expansion
+    /// audio (from -1.0 to 1.0) added in
+    ///
+    /// This follows the console's nonlinear mixer, so it runs from 0.0 to
+    /// about 1.0 before the expansion audio. There's a DC offset in it, which
+    /// the console's output filters take out.
+    ///
+    /// cf. https://wiki.nesdev.com/w/index.php/APU_Mixer
+    ///
+    /// Muted channels are mixed in as if they were at 0.
+    pub fn output(&self, expansion: f32) -> f32 {
+        let level = |channel: Channel, output: u8| {
+            if self.muted[channel as usize] {
+                0.0
+            } else {
+                f32::from(output)
+            }
+        };
+        let pulse = level(Channel::Pulse1, self.pulse_1.output())
+            + level(Channel::Pulse2, self.pulse_2.output());
+        let pulse_out = if pulse == 0.0 {
+            0.0
+        } else {
+            95.88 / (8128.0 / pulse + 100.0)
+        };
+        let tnd = level(Channel::Triangle, self.triangle.output()) / 8227.0
+            + level(Channel::Noise, self.noise.output()) / 12241.0
+            + level(Channel::Dmc, self.dmc.level) / 22638.0;
+        let tnd_out = if tnd == 0.0 {
+            0.0
+        } else {
+            159.79 / (1.0 / tnd + 100.0)
+        };
+        let expansion = if self.muted[Channel::Expansion as usize] {
+            0.0
+        } else {
+            expansion
+        };
+        pulse_out + tnd_out + expansion * EXPANSION_LEVEL
+    }
+
+    /// Mute or unmute a channel in `output`
+    ///
+    /// This only changes what's heard. The channel keeps running, and
+    /// APUSTATUS reads the same as ever.
+    pub fn set_channel_enabled(&mut self, channel: Channel, enabled: bool) {
+        self.muted[channel as usize] = !enabled;
+    }
+
+    /// Whether a channel is heard in `output`, see `set_channel_enabled`
+    pub fn is_channel_enabled(&self, channel: Channel) -> bool {
+        !self.muted[channel as usize]
+    }
+
+    /// Clock the APU once per CPU cycle
+    pub fn clock(&mut self) {
+        self.odd_cycle = !self.odd_cycle;
+        if self.odd_cycle {
+            self.pulse_1.clock_timer();
+            self.pulse_2.clock_timer();
+        }
+        self.triangle.clock_timer();
+        self.noise.clock_timer();
+        self.dmc.clock();
+        if let Some((value, delay)) = self.pending_frame_write {
+            if delay <= 1 {
+                self.pending_frame_write = None;
+                self.restart_frame_counter(value);
+                return;
+            }
+            self.pending_frame_write = Some((value, delay - 1));
+        }
+        self.frame_cycle += 1;
+        self.clock_frame_counter();
+    }
+
+    fn write_status(&mut self, value: u8) {
+        let enabled = ApuStatus::from_bits_truncate(value);
+        self.pulse_1
+            .length
+            .set_enabled(enabled.contains(ApuStatus::PULSE_1));
+        self.pulse_2
+            .length
+            .set_enabled(enabled.contains(ApuStatus::PULSE_2));
+        self.triangle
+            .length
+            .set_enabled(enabled.contains(ApuStatus::TRIANGLE));
+        self.noise
+            .length
+            .set_enabled(enabled.contains(ApuStatus::NOISE));
+        self.dmc.set_enabled(enabled.contains(ApuStatus::DMC));
+        self.dmc.irq = false;
+    }
+
+    /// Handle a write to $4017, which takes effect after `delay` CPU cycles
+    ///
+    /// The IRQ inhibit flag takes effect right away.
+    fn write_frame_counter(&mut self, value: u8, delay: u8) {
+        self.irq_inhibit = value & 0x40 != 0;
+        if self.irq_inhibit {
+            self.frame_irq = false;
+        }
+        if delay == 0 {
+            self.restart_frame_counter(value);
+        } else {
+            self.pending_frame_write = Some((value, delay));
+        }
+    }
+
+    /// Start the frame counter's sequence over, in the mode `value` picks
+    ///
+    /// 5-step mode clocks everything right away, rather than waiting for the
+    /// first step.
+    fn restart_frame_counter(&mut self, value: u8) {
+        self.five_step = value & 0x80 != 0;
+        self.frame_cycle = 0;
+        if self.five_step {
+            self.clock_quarter_frame();
+            self.clock_half_frame();
+        }
+    }
+
+    /// Run the step of the frame counter's sequence for this cycle, if there
+    /// is one
+    ///
+    /// cf. https://wiki.nesdev.com/w/index.php/APU_Frame_Counter
+    fn clock_frame_counter(&mut self) {
+        let cycle = self.frame_cycle;
+        if QUARTER_FRAME_STEPS.contains(&cycle) {
+            self.clock_quarter_frame();
+            if cycle == QUARTER_FRAME_STEPS[1] {
+                self.clock_half_frame();
+            }
+        } else if self.five_step {
+            if cycle == FIVE_STEP_END[0] {
+                self.clock_quarter_frame();
+                self.clock_half_frame();
+            } else if cycle == FIVE_STEP_END[1] {
+                self.frame_cycle = 0;
+            }
+        } else if FOUR_STEP_END.contains(&cycle) {
+            if !self.irq_inhibit {
+                self.frame_irq = true;
+            }
+            if cycle == FOUR_STEP_END[1] {
+                self.clock_quarter_frame();
+                self.clock_half_frame();
+            } else if cycle == FOUR_STEP_END[2] {
+                self.frame_cycle = 0;
+            }
+        }
+    }
+
+    /// Clock the envelopes and the triangle's linear counter
+    fn clock_quarter_frame(&mut self) {
+        self.pulse_1.envelope.clock();
+        self.pulse_2.envelope.clock();
+        self.noise.envelope.clock();
+        self.triangle.clock_linear_counter();
+    }
+
+    /// Clock the length counters and sweeps
+    fn clock_half_frame(&mut self) {
+        self.pulse_1.length.clock();
+        self.pulse_2.length.clock();
+        self.triangle.length.clock();
+        self.noise.length.clock();
+        self.pulse_1.sweep.clock(&mut self.pulse_1.period);
+        self.pulse_2.sweep.clock(&mut self.pulse_2.period);
+    }
+}
+
+impl Default for Apu {
+    fn default() -> Apu {
+        Apu::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Clock the APU through `cycles` CPU cycles
+    fn run(apu: &mut Apu, cycles: u32) {
+        for _ in 0..cycles {
+            apu.clock();
+        }
+    }
+
+    #[test]
+    fn loads_length_counters_only_while_enabled() {
+        let mut apu = Apu::new();
+        apu.write(0x03, 0x08);
+        assert_eq!(apu.length_counters(), [0, 0, 0, 0]);
+        apu.write(0x15, 0x0F);
+        apu.write(0x03, 0x08);
+        apu.write(0x07, 0x10);
+        apu.write(0x0B, 0x18);
+        apu.write(0x0F, 0xF8);
+        assert_eq!(apu.length_counters(), [254, 20, 2, 30]);
+        assert_eq!(apu.read_status(), 0x0F);
+        // disabling a channel clears its count
+        apu.write(0x15, 0x0D);
+        assert_eq!(apu.length_counters(), [254, 0, 2, 30]);
+        assert_eq!(apu.read_status(), 0x0D);
+    }
+
+    #[test]
+    fn counts_lengths_down_every_half_frame() {
+        let mut apu = Apu::new();
+        apu.write(0x17, 0x40);
+        apu.write(0x15, 0x03);
+        // 4 half frames
+        apu.write(0x03, 0x28);
+        // halted
+        apu.write(0x04, 0x20);
+        apu.write(0x07, 0x28);
+        run(&mut apu, 3);
+        run(&mut apu, 14913);
+        assert_eq!(apu.length_counters(), [3, 4, 0, 0]);
+        run(&mut apu, 29830 * 2 - 14913);
+        assert_eq!(apu.length_counters(), [0, 4, 0, 0]);
+        assert_eq!(apu.read_status(), 0x02);
+    }
+
+    #[test]
+    fn five_step_mode_clocks_right_away() {
+        let mut apu = Apu::new();
+        apu.write(0x15, 0x01);
+        apu.write(0x03, 0x28);
+        apu.write(0x17, 0x80);
+        run(&mut apu, 3);
+        assert_eq!(apu.length_counters()[0], 3);
+        // there's no half frame at the end of the fourth step in this mode
+        run(&mut apu, 29830);
+        assert_eq!(apu.length_counters()[0], 2);
+        run(&mut apu, 37282 - 29830);
+        assert_eq!(apu.length_counters()[0], 1);
+    }
+
+    #[test]
+    fn raises_the_frame_irq_until_status_is_read() {
+        let mut apu = Apu::new();
+        run(&mut apu, 29827);
+        assert!(!apu.frame_irq());
+        run(&mut apu, 1);
+        assert!(apu.frame_irq());
+        assert_eq!(apu.read_status(), 0x40);
+        assert!(!apu.frame_irq());
+        // the flag is set for 3 cycles in a row, so a read in the middle of
+        // them doesn't clear it for good
+        run(&mut apu, 1);
+        assert!(apu.frame_irq());
+        // inhibiting the IRQ clears the flag
+        apu.write(0x17, 0x40);
+        assert_eq!(apu.read_status(), 0x00);
+        run(&mut apu, 29830 * 2);
+        assert!(!apu.frame_irq());
+        // and 5-step mode never raises it
+        let mut apu = Apu::new();
+        apu.write(0x17, 0x80);
+        run(&mut apu, 37282 * 2);
+        assert!(!apu.frame_irq());
+    }
+
+    #[test]
+    fn decays_envelopes_every_quarter_frame() {
+        let mut apu = Apu::new();
+        apu.write(0x17, 0x40);
+        apu.write(0x15, 0x09);
+        // a divider period of 1, so the volume drops every other quarter frame
+        apu.write(0x00, 0x01);
+        apu.write(0x01, 0x00);
+        apu.write(0x02, 0x00);
+        apu.write(0x03, 0xF9);
+        // a constant volume of 7
+        apu.write(0x0C, 0x17);
+        apu.write(0x0F, 0xF8);
+        run(&mut apu, 3 + 7457);
+        assert_eq!(apu.volumes(), [15, 0, 7]);
+        run(&mut apu, 22371 - 7457);
+        assert_eq!(apu.volumes(), [14, 0, 7]);
+    }
+
+    #[test]
+    fn sweeps_pulse_periods_every_half_frame() {
+        let mut apu = Apu::new();
+        apu.write(0x17, 0x40);
+        // sweep down by period >> 1, every half frame
+        apu.write(0x01, 0x89);
+        apu.write(0x02, 0x00);
+        apu.write(0x03, 0x01);
+        apu.write(0x05, 0x89);
+        apu.write(0x06, 0x00);
+        apu.write(0x07, 0x01);
+        run(&mut apu, 3 + 14913);
+        // pulse 1 subtracts one more than pulse 2
+        assert_eq!(apu.pulse_periods(), [0x100 - 0x81, 0x100 - 0x80]);
+    }
+
+    #[test]
+    fn reloads_the_triangle_linear_counter() {
+        let mut apu = Apu::new();
+        apu.write(0x17, 0x40);
+        apu.write(0x15, 0x04);
+        apu.write(0x08, 0x05);
+        apu.write(0x0B, 0x08);
+        run(&mut apu, 3 + 7457);
+        assert_eq!(apu.linear_counter(), 5);
+        run(&mut apu, 14913 - 7457);
+        assert_eq!(apu.linear_counter(), 4);
+    }
+
+    #[test]
+    fn plays_dmc_samples_at_the_sample_rate() {
+        let mut apu = Apu::new();
+        // the fastest rate, with an IRQ at the end of a 17 byte sample
+        apu.write(0x10, 0x8F);
+        apu.write(0x13, 0x01);
+        apu.write(0x15, 0x10);
+        // one byte goes straight into the buffer
+        assert_eq!(apu.peek_status(), ApuStatus::DMC);
+        // and the rest are fetched as each byte starts playing, give or take
+        // the rest of the bit that was playing at power-on
+        run(&mut apu, 54 * 8 * 15);
+        assert_eq!(apu.peek_status(), ApuStatus::DMC);
+        run(&mut apu, 54 * 8 * 2);
+        assert_eq!(apu.peek_status(), ApuStatus::DMC_IRQ);
+        assert!(apu.dmc_irq());
+        // reading status doesn't acknowledge it, but writing does
+        apu.read_status();
+        assert!(apu.dmc_irq());
+        apu.write(0x15, 0x00);
+        assert!(!apu.dmc_irq());
+    }
+
+    #[test]
+    fn plays_pulse_duty_cycles() {
+        let mut apu = Apu::new();
+        apu.write(0x15, 0x01);
+        // a constant volume of 15, a 12.5% duty cycle, and steps 18 CPU
+        // cycles long
+        apu.write(0x00, 0x3F);
+        apu.write(0x02, 0x08);
+        apu.write(0x03, 0x00);
+        let mut high = 0;
+        for _ in 0..18 * 8 {
+            apu.clock();
+            if apu.pulse_1.output() > 0 {
+                high += 1;
+            }
+        }
+        assert_eq!(high, 18);
+        apu.write(0x00, 0xBF);
+        high = 0;
+        for _ in 0..18 * 8 {
+            apu.clock();
+            if apu.pulse_1.output() > 0 {
+                assert_eq!(apu.pulse_1.output(), 15);
+                high += 1;
+            }
+        }
+        assert_eq!(high, 18 * 4);
+    }
+
+    #[test]
+    fn steps_the_triangle_only_while_both_counters_run() {
+        let mut apu = Apu::new();
+        apu.write(0x17, 0x40);
+        apu.write(0x15, 0x04);
+        apu.write(0x08, 0x7F);
+        apu.write(0x0A, 0x00);
+        apu.write(0x0B, 0x08);
+        // the linear counter isn't loaded until the first quarter frame
+        run(&mut apu, 3 + 7456);
+        assert_eq!(apu.triangle.output(), 15);
+        run(&mut apu, 1 + 16);
+        assert_eq!(apu.triangle.output(), 0);
+        // stopping the channel leaves it on the step it was on
+        apu.write(0x15, 0x00);
+        run(&mut apu, 3);
+        assert_eq!(apu.triangle.output(), 0);
+    }
+
+    #[test]
+    fn loops_the_noise_shift_register() {
+        let mut noise = Noise::new();
+        let steps = |noise: &mut Noise| {
+            let mut steps = 0;
+            loop {
+                noise.timer = 0;
+                noise.clock_timer();
+                steps += 1;
+                if noise.shift == 1 {
+                    return steps;
+                }
+            }
+        };
+        assert_eq!(steps(&mut noise), 32767);
+        noise.write(2, 0x80);
+        assert_eq!(steps(&mut noise), 93);
+    }
+
+    #[test]
+    fn plays_the_dmc_samples_it_fetches() {
+        let mut apu = Apu::new();
+        apu.write(0x10, 0x0F);
+        apu.write(0x11, 0x40);
+        // 65 bytes from $FFC0, which runs off the end of the address space
+        apu.write(0x12, 0xFF);
+        apu.write(0x13, 0x04);
+        apu.write(0x15, 0x10);
+        let mut fetches = Vec::new();
+        loop {
+            if let Some(addr) = apu.take_dmc_fetch() {
+                fetches.push(addr);
+                apu.fill_dmc_buffer(0xFF);
+            }
+            if !apu.peek_status().contains(ApuStatus::DMC) {
+                break;
+            }
+            apu.clock();
+        }
+        assert_eq!(fetches.len(), 65);
+        assert_eq!(fetches[0], 0xFFC0);
+        assert_eq!(fetches[63], 0xFFFF);
+        assert_eq!(fetches[64], 0x8000);
+        // each 1 bit steps the level up by 2, as far as it'll go from $40
+        assert_eq!(apu.dmc.level, 126);
+        // then a byte of 0s, after the last byte of 1s finishes playing
+        apu.write(0x13, 0x00);
+        apu.write(0x15, 0x10);
+        for _ in 0..54 * 8 * 3 {
+            if let Some(addr) = apu.take_dmc_fetch() {
+                assert_eq!(addr, 0xFFC0);
+                apu.fill_dmc_buffer(0x00);
+            }
+            apu.clock();
+        }
+        assert_eq!(apu.dmc.level, 126 - 16);
+    }
+
+    #[test]
+    fn mixes_in_the_dmc_and_expansion_audio() {
+        let mut apu = Apu::new();
+        // the triangle sits on the first step of its waveform, at 15
+        let idle = apu.output(0.0);
+        assert!(idle > 0.0);
+        apu.write(0x11, 0x7F);
+        let dmc = apu.output(0.0);
+        assert!(dmc > idle);
+        assert_eq!(apu.output(1.0), dmc + EXPANSION_LEVEL);
+    }
+
+    #[test]
+    fn muting_a_channel_only_changes_the_mix() {
+        let mut apu = Apu::new();
+        apu.write(0x15, 0x01);
+        apu.write(0x00, 0xBF);
+        apu.write(0x03, 0x00);
+        apu.write(0x11, 0x7F);
+        let mixed = apu.output(1.0);
+        apu.set_channel_enabled(Channel::Dmc, false);
+        apu.set_channel_enabled(Channel::Expansion, false);
+        assert!(!apu.is_channel_enabled(Channel::Dmc));
+        assert!(apu.output(1.0) < mixed);
+        apu.set_channel_enabled(Channel::Triangle, false);
+        apu.set_channel_enabled(Channel::Pulse1, false);
+        assert_eq!(apu.output(1.0), 0.0);
+        // the channels carry on underneath
+        assert_eq!(apu.peek_status(), ApuStatus::PULSE_1);
+        assert_eq!(apu.dmc_level(), 0x7F);
+        // and stay muted through a power cycle
+        apu.power_on();
+        assert!(!apu.is_channel_enabled(Channel::Pulse1));
+        assert!(apu.is_channel_enabled(Channel::Pulse2));
+        apu.set_channel_enabled(Channel::Pulse1, true);
+        assert!(apu.is_channel_enabled(Channel::Pulse1));
+    }
+
+    #[test]
+    fn reset_silences_every_channel() {
+        let mut apu = Apu::new();
+        apu.write(0x15, 0x1F);
+        apu.write(0x03, 0x08);
+        apu.write(0x0F, 0x08);
+        apu.reset();
+        assert_eq!(apu.peek_status(), ApuStatus::empty());
+    }
+}
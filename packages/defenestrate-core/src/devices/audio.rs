@@ -0,0 +1,148 @@
+//! Turning the APU's output into samples a sound card can play
+//!
+//! The APU's mixer changes level once per CPU cycle, about 1.79 million times
+//! a second. `AudioOutput` averages those levels over each output sample,
+//! which is a crude low-pass filter but keeps the pitch right at any sample
+//! rate, and then takes the DC offset out with a high-pass filter, like the
+//! one on the console's audio output.
+//!
+//! Samples pile up until the frontend takes them with
+//! `Nes::take_audio_samples`, so that it can feed them to the sound card at
+//! its own pace.
+
+use alloc::vec::Vec;
+
+/// The sample rate a `Nes` starts out with
+pub const DEFAULT_SAMPLE_RATE: u32 = 44_100;
+
+/// The CPU's clock rate on NTSC consoles, 236.25/11MHz divided by 12, as a
+/// fraction
+const NTSC_CPU_HZ_NUMERATOR: u32 = 39_375_000;
+const NTSC_CPU_HZ_DENOMINATOR: u32 = 22;
+
+/// How much of the last filtered sample the high-pass filter keeps, which
+/// puts its cutoff around 30Hz at 44.1kHz
+const HIGH_PASS_DECAY: f32 = 0.996;
+
+pub(crate) struct AudioOutput {
+    /// Samples per second, or 0 if audio is turned off
+    sample_rate: u32,
+    /// How far along the next sample is, counting up to
+    /// `NTSC_CPU_HZ_NUMERATOR`
+    phase: u32,
+    /// The levels seen since the last sample, added up
+    sum: f32,
+    count: u32,
+    /// The last sample into the high-pass filter, and the last one out of it
+    last_in: f32,
+    last_out: f32,
+    samples: Vec<f32>,
+}
+
+impl AudioOutput {
+    pub fn new(sample_rate: u32) -> AudioOutput {
+        AudioOutput {
+            sample_rate,
+            phase: 0,
+            sum: 0.0,
+            count: 0,
+            last_in: 0.0,
+            last_out: 0.0,
+            samples: Vec::new(),
+        }
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    /// Switch to a new sample rate, dropping any samples at the old one
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        *self = AudioOutput::new(sample_rate);
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.sample_rate > 0
+    }
+
+    /// Take in the APU's output level for one CPU cycle, returning the
+    /// sample it finished, if it finished one
+    pub fn push(&mut self, level: f32) -> Option<f32> {
+        self.sum += level;
+        self.count += 1;
+        self.phase += self.sample_rate * NTSC_CPU_HZ_DENOMINATOR;
+        if self.phase < NTSC_CPU_HZ_NUMERATOR {
+            return None;
+        }
+        self.phase -= NTSC_CPU_HZ_NUMERATOR;
+        let average = self.sum / self.count as f32;
+        self.sum = 0.0;
+        self.count = 0;
+        let sample = average - self.last_in + HIGH_PASS_DECAY * self.last_out;
+        self.last_in = average;
+        self.last_out = sample;
+        // if nothing is taking samples, keep the newest second or so of them
+        if self.samples.len() >= self.sample_rate as usize {
+            self.samples.drain(..self.samples.len() / 2);
+        }
+        self.samples.push(sample);
+        Some(sample)
+    }
+
+    /// The samples made since the last call
+    pub fn take_samples(&mut self) -> Vec<f32> {
+        core::mem::take(&mut self.samples)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// CPU cycles in one second, rounded down
+    const CPU_CYCLES_PER_SECOND: u32 = NTSC_CPU_HZ_NUMERATOR / NTSC_CPU_HZ_DENOMINATOR;
+
+    #[test]
+    fn makes_samples_at_the_sample_rate() {
+        let mut audio = AudioOutput::new(48_000);
+        for _ in 0..CPU_CYCLES_PER_SECOND / 10 {
+            audio.push(0.0);
+        }
+        // that many cycles is a hair short of a tenth of a second
+        assert_eq!(audio.take_samples().len(), 4799);
+        assert!(audio.take_samples().is_empty());
+    }
+
+    #[test]
+    fn filters_out_a_constant_level() {
+        let mut audio = AudioOutput::new(44_100);
+        for _ in 0..CPU_CYCLES_PER_SECOND / 4 {
+            audio.push(0.5);
+        }
+        let samples = audio.take_samples();
+        assert!(samples[0] > 0.4, "The first sample should jump up");
+        assert!(
+            samples.last().unwrap().abs() < 0.01,
+            "The level should settle at 0"
+        );
+    }
+
+    #[test]
+    fn keeps_only_the_newest_samples() {
+        let mut audio = AudioOutput::new(1000);
+        for _ in 0..CPU_CYCLES_PER_SECOND * 3 {
+            audio.push(0.0);
+        }
+        assert!(audio.take_samples().len() <= 1000);
+    }
+
+    #[test]
+    fn makes_nothing_when_turned_off() {
+        let mut audio = AudioOutput::new(0);
+        assert!(!audio.is_enabled());
+        for _ in 0..CPU_CYCLES_PER_SECOND / 10 {
+            audio.push(0.5);
+        }
+        assert!(audio.take_samples().is_empty());
+    }
+}
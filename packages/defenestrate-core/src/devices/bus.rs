@@ -12,8 +12,87 @@ pub trait Motherboard {
 
     /// Write to the bus with the given data
     fn write(&mut self, addr: u16, data: u8);
+
+    /// Where the PPU is, as a (scanline, dot) pair, for CPU debug logs
+    ///
+    /// Boards without a PPU, like test harnesses, can leave this as `None`.
+    fn ppu_position(&self) -> Option<(u16, u16)> {
+        None
+    }
+
+    /// How closely the devices on this bus should follow the hardware
+    ///
+    /// Boards that don't say get everything, since that's what test harnesses
+    /// want.
+    fn accuracy(&self) -> AccuracyMode {
+        AccuracyMode::Strict
+    }
+}
+
+/// How much of the hardware's more expensive edge-case behavior to emulate
+///
+/// The faster modes skip details to save time on slow hosts, like the wasm
+/// build on a low-end phone. Balanced only drops quirks that test ROMs check
+/// for and well-behaved games don't rely on. Fast goes further, and some games
+/// break in it: MMC1 boards ignore the second of two writes on back-to-back
+/// cycles, and games that reset the mapper with a read-modify-write
+/// instruction count on the CPU writing the unmodified value first. Games
+/// that lean on the sprite overflow bug to time raster effects also break.
+///
+/// | Behavior                                   | Strict | Balanced | Fast |
+/// |--------------------------------------------|--------|----------|------|
+/// | PPU open bus decay                         | yes    | no       | no   |
+/// | Dummy reads and writes by the CPU          | yes    | yes      | no   |
+/// | Per-dot sprite evaluation, with its bug    | yes    | yes      | no   |
+/// | OAMADDR quirks and OAM row corruption      | yes    | no       | no   |
+#[derive(Debug, Default, Eq, PartialEq, Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum AccuracyMode {
+    /// Everything the emulator knows how to do
+    Strict,
+    /// Everything that can change how a game runs
+    #[default]
+    Balanced,
+    /// Only what most games need to run, at the cost of the mapper and
+    /// sprite quirks a few of them depend on
+    Fast,
+}
+
+impl AccuracyMode {
+    /// Whether values left on the PPU's data bus fade to 0 when nothing
+    /// refreshes them
+    pub fn decays_open_bus(self) -> bool {
+        self == AccuracyMode::Strict
+    }
+
+    /// Whether the CPU makes the extra bus accesses the 6502 does, like
+    /// reading the wrong page when indexing across one, or writing the
+    /// unmodified value back in a read-modify-write instruction
+    pub fn makes_dummy_accesses(self) -> bool {
+        self != AccuracyMode::Fast
+    }
+
+    /// Whether the PPU looks for the next scanline's sprites a dot at a time,
+    /// rather than all at once at the end of the line
+    ///
+    /// Doing it all at once doesn't reproduce the sprite overflow bug, so
+    /// the overflow flag is set when there really are more than 8 sprites.
+    pub fn evaluates_sprites_per_dot(self) -> bool {
+        self != AccuracyMode::Fast
+    }
+
+    /// Whether the PPU mishandles OAM the way the hardware does when OAMADDR
+    /// isn't 0 as rendering starts, or when rendering is switched off partway
+    /// through a line
+    ///
+    /// Well-behaved games leave OAMADDR at 0 and only toggle rendering in
+    /// vblank, so this only matters to a few games and test ROMs.
+    pub fn emulates_oam_glitches(self) -> bool {
+        self == AccuracyMode::Strict
+    }
 }
 
+#[cfg(feature = "console")]
 #[derive(Debug, Eq, PartialEq)]
 pub enum BusPeekResult {
     Unmapped,
@@ -21,6 +100,7 @@ pub enum BusPeekResult {
     Result(u8),
 }
 
+#[cfg(feature = "console")]
 impl BusPeekResult {
     /// Unwrap a BusPeekResult to an u8
     pub fn unwrap(&self, last_bus_value: u8) -> u8 {
@@ -39,6 +119,7 @@ impl BusPeekResult {
     }
 }
 
+#[cfg(feature = "console")]
 /// Trait for an object that may be mounted to and driven by an address bus
 pub trait BusDevice {
     /// Given a local address and the last bus value, return a new bus value
@@ -56,12 +137,14 @@ pub trait BusDevice {
     fn write(&mut self, addr: u16, value: u8);
 }
 
+#[cfg(feature = "console")]
 pub struct Range {
     start: u16,
     end: u16,
     mask: u16,
 }
 
+#[cfg(feature = "console")]
 impl Range {
     pub const fn new(start: u16, end: u16, mask: u16) -> Range {
         Range { start, end, mask }
@@ -85,13 +168,20 @@ impl Range {
     }
 }
 
+#[cfg(feature = "console")]
 pub mod cpu_memory_map {
     use super::Range;
+    use crate::devices::cartridge::PrgRegion;
 
+    #[derive(Debug, Eq, PartialEq, Copy, Clone)]
     pub enum Device {
-        Cartridge,
+        /// The cartridge, already split up the way boards expect
+        Cartridge(PrgRegion),
         RAM,
         PPUControl,
+        /// The APU and I/O registers, apart from the controller ports
+        ApuIo,
+        Controllers,
         Unmapped,
     }
 
@@ -103,26 +193,39 @@ pub mod cpu_memory_map {
 
     pub const PPU_PORTS: Range = Range::new(0x2000, 0x3FFF, 0x0007);
 
-    pub const OAM_DMA: Range = Range::new(0x4014, 0x4014, 0xFFFF);
+    pub const CONTROLLERS: Range = Range::new(0x4016, 0x4017, 0xFFFF);
 
-    pub const CONTROLLER_DMA: Range = Range::new(0x4016, 0x4017, 0xFFFF);
+    /// The APU and I/O registers, along with the CPU's test registers at
+    /// $4018-$401F, which are disabled on retail consoles
+    ///
+    /// cf. https://wiki.nesdev.com/w/index.php/2A03
+    pub const APU_IO: Range = Range::new_unmasked(0x4000, 0x401F);
+
+    /// APUSTATUS, the only APU register that can be read, as a local address
+    pub const APU_STATUS: u16 = 0x0015;
 
     /// Given a test address, return a device and a local address
     ///
     /// If the address is unmapped, the returned address will be a global addr.
     pub fn match_addr(addr: u16) -> (Device, u16) {
-        if let Some(addr) = CARTRIDGE.map(addr) {
-            (Device::Cartridge, addr)
+        if let Some(local_addr) = CARTRIDGE.map(addr) {
+            let region = PrgRegion::from_cpu_addr(addr);
+            (Device::Cartridge(region), local_addr)
         } else if let Some(addr) = RAM.map(addr) {
             (Device::RAM, addr)
         } else if let Some(addr) = PPU_PORTS.map(addr) {
             (Device::PPUControl, addr)
+        } else if let Some(addr) = CONTROLLERS.map(addr) {
+            (Device::Controllers, addr)
+        } else if let Some(addr) = APU_IO.map(addr) {
+            (Device::ApuIo, addr)
         } else {
             (Device::Unmapped, addr)
         }
     }
 }
 
+#[cfg(feature = "console")]
 pub mod ppu_memory_map {
     use super::Range;
 
@@ -145,3 +248,30 @@ pub mod ppu_memory_map {
         }
     }
 }
+
+#[cfg(all(test, feature = "console"))]
+mod tests {
+    use super::cpu_memory_map::{match_addr, Device};
+    use crate::devices::cartridge::PrgRegion::{Expansion, Ram, Rom};
+
+    #[test]
+    fn maps_the_cpu_bus() {
+        assert_eq!(match_addr(0x0801), (Device::RAM, 0x0001));
+        assert_eq!(match_addr(0x3456), (Device::PPUControl, 0x0006));
+        assert_eq!(match_addr(0x4000), (Device::ApuIo, 0x0000));
+        assert_eq!(match_addr(0x4014), (Device::ApuIo, 0x0014));
+        assert_eq!(match_addr(0x4017), (Device::Controllers, 0x0001));
+        assert_eq!(match_addr(0x401F), (Device::ApuIo, 0x001F));
+        assert_eq!(
+            match_addr(0x4020),
+            (Device::Cartridge(Expansion(0)), 0x0000)
+        );
+        assert_eq!(
+            match_addr(0x5FFF),
+            (Device::Cartridge(Expansion(0x1FDF)), 0x1FDF)
+        );
+        assert_eq!(match_addr(0x6000), (Device::Cartridge(Ram(0)), 0x1FE0));
+        assert_eq!(match_addr(0x7FFF), (Device::Cartridge(Ram(0x1FFF)), 0x3FDF));
+        assert_eq!(match_addr(0x8000), (Device::Cartridge(Rom(0)), 0x3FE0));
+    }
+}
@@ -0,0 +1,607 @@
+//! The Bandai FCG boards (mapper 16), with a 24C02 serial EEPROM for saves
+//!
+//! The FCG-1/2 and LZ93D50 mapper chips have the same 16 registers, repeated
+//! every 16 bytes: eight 1k CHR banks, a 16k PRG bank at $8000, the nametable
+//! mirroring, and a CPU cycle IRQ counter. The last 16k of PRG is fixed at
+//! $C000. The FCG-1/2 has its registers at $6000-$7FFF and the LZ93D50 has
+//! them at $8000-$FFFF, and iNES mapper 16 doesn't say which a game is
+//! for, so they're answered in both places.
+//!
+//! The LZ93D50 boards also have an EEPROM in place of battery-backed RAM,
+//! which the game bit-bangs over I2C through register $xD, and reads back
+//! through bit 4 of $6000-$7FFF.
+//!
+//! The Datach and the SRAM board (mapper 153) aren't emulated, and neither is
+//! the 24C01 used by mapper 159.
+//!
+//! cf. https://wiki.nesdev.com/w/index.php/Bandai_FCG_board
+//! cf. https://wiki.nesdev.com/w/index.php/INES_Mapper_016
+
+use alloc::{vec, vec::Vec};
+
+use super::ines::INesHeader;
+use super::utils::{assert_rom_size, CartridgeState, ICartridge, Mirroring, PrgRegion};
+use crate::devices::bus::BusPeekResult;
+
+const PRG_BANK_SIZE: usize = 0x4000;
+const CHR_BANK_SIZE: usize = 0x0400;
+
+/// The size of a 24C02, in bytes
+const EEPROM_SIZE: usize = 256;
+
+/// In register $xD, the clock line to the EEPROM
+const EEPROM_SCL: u8 = 0x20;
+/// In register $xD, the data line to the EEPROM
+const EEPROM_SDA: u8 = 0x40;
+/// In register $xD, whether the mapper lets go of the data line so that the
+/// EEPROM can drive it
+const EEPROM_READ: u8 = 0x80;
+
+/// What the EEPROM is doing on the I2C bus
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+enum EepromMode {
+    /// Waiting for a start condition
+    Idle,
+    /// Receiving the device address and the read/write bit
+    DeviceAddress,
+    /// Receiving the word address to read or write
+    WordAddress,
+    /// Receiving a byte to write
+    Write,
+    /// Sending a byte
+    Read,
+    /// Pulling the data line low to acknowledge a byte, before `next_mode`
+    SendAck,
+    /// Waiting for the CPU to acknowledge a byte it read, to keep reading
+    WaitAck,
+}
+
+/// A 24C02 serial EEPROM, as seen from its two pins
+///
+/// Bits are clocked in on the rising edge of SCL, most significant first.
+/// Pulling SDA low while SCL is high starts a command, and letting it go high
+/// again stops one.
+///
+/// cf. https://wiki.nesdev.com/w/index.php/Bandai_FCG_board#Serial_EEPROM
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+struct Eeprom24C02 {
+    data: Vec<u8>,
+    mode: EepromMode,
+    /// The mode to go to after an acknowledge
+    next_mode: EepromMode,
+    /// The byte being shifted in or out
+    shift: u8,
+    bit_count: u8,
+    /// The address the next read or write goes to
+    addr: u8,
+    scl: bool,
+    sda: bool,
+    /// What the EEPROM is putting on the data line, where high means it's
+    /// letting go of it
+    output: bool,
+}
+
+impl Eeprom24C02 {
+    fn new() -> Eeprom24C02 {
+        Eeprom24C02 {
+            // an erased EEPROM reads back all 1s
+            data: vec![0xFF; EEPROM_SIZE],
+            mode: EepromMode::Idle,
+            next_mode: EepromMode::Idle,
+            shift: 0,
+            bit_count: 0,
+            addr: 0,
+            scl: false,
+            sda: true,
+            output: true,
+        }
+    }
+
+    /// Drive the clock and data lines
+    fn write(&mut self, scl: bool, sda: bool) {
+        if self.scl && scl && self.sda && !sda {
+            // start, or a repeated start for a random read
+            self.mode = EepromMode::DeviceAddress;
+            self.bit_count = 0;
+            self.output = true;
+        } else if self.scl && scl && !self.sda && sda {
+            // stop
+            self.mode = EepromMode::Idle;
+            self.output = true;
+        } else if !self.scl && scl {
+            self.clock_rising(sda);
+        } else if self.scl && !scl {
+            self.clock_falling();
+        }
+        self.scl = scl;
+        self.sda = sda;
+    }
+
+    fn clock_rising(&mut self, sda: bool) {
+        match self.mode {
+            EepromMode::DeviceAddress | EepromMode::WordAddress | EepromMode::Write => {
+                if self.bit_count < 8 {
+                    self.shift = (self.shift << 1) | sda as u8;
+                    self.bit_count += 1;
+                }
+            }
+            EepromMode::Read => {
+                if self.bit_count < 8 {
+                    self.output = self.shift & (0x80 >> self.bit_count) != 0;
+                    self.bit_count += 1;
+                }
+            }
+            EepromMode::SendAck => self.output = false,
+            EepromMode::WaitAck => {
+                // a high data line is a not-acknowledge, which ends the read
+                self.next_mode = if sda {
+                    EepromMode::Idle
+                } else {
+                    self.shift = self.data[self.addr as usize];
+                    EepromMode::Read
+                };
+            }
+            EepromMode::Idle => {}
+        }
+    }
+
+    fn clock_falling(&mut self) {
+        match self.mode {
+            EepromMode::DeviceAddress if self.bit_count == 8 => {
+                // the top 4 bits select the EEPROM, and the address pins are
+                // all tied low on these boards
+                if self.shift & 0xFE != 0xA0 {
+                    self.mode = EepromMode::Idle;
+                    return;
+                }
+                self.next_mode = if self.shift & 0x01 != 0 {
+                    self.shift = self.data[self.addr as usize];
+                    EepromMode::Read
+                } else {
+                    EepromMode::WordAddress
+                };
+                self.mode = EepromMode::SendAck;
+            }
+            EepromMode::WordAddress if self.bit_count == 8 => {
+                self.addr = self.shift;
+                self.next_mode = EepromMode::Write;
+                self.mode = EepromMode::SendAck;
+            }
+            EepromMode::Write if self.bit_count == 8 => {
+                self.data[self.addr as usize] = self.shift;
+                // writes wrap around within an 8 byte page
+                self.addr = (self.addr & 0xF8) | (self.addr.wrapping_add(1) & 0x07);
+                self.next_mode = EepromMode::Write;
+                self.mode = EepromMode::SendAck;
+            }
+            EepromMode::Read if self.bit_count == 8 => {
+                self.addr = self.addr.wrapping_add(1);
+                self.output = true;
+                self.mode = EepromMode::WaitAck;
+            }
+            EepromMode::SendAck | EepromMode::WaitAck => {
+                self.mode = self.next_mode;
+                self.bit_count = 0;
+                self.output = true;
+            }
+            _ => {}
+        }
+    }
+
+    /// Reset the bus state, leaving the contents alone
+    fn power_cycle(&mut self) {
+        let data = core::mem::take(&mut self.data);
+        *self = Eeprom24C02 {
+            data,
+            ..Eeprom24C02::new()
+        };
+    }
+}
+
+#[derive(Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct BandaiFCGCartridge {
+    chr: Vec<u8>,
+    prg: Vec<u8>,
+    nametable: Vec<u8>,
+    chr_banks: [u8; 8],
+    /// The bank at $8000
+    prg_bank: u8,
+    mirroring: Mirroring,
+    irq_enabled: bool,
+    irq_counter: u16,
+    /// The value loaded into the counter when IRQs are enabled
+    irq_latch: u16,
+    irq_pending: bool,
+    /// The last write to the EEPROM control register
+    eeprom_control: u8,
+    eeprom: Eeprom24C02,
+}
+
+impl BandaiFCGCartridge {
+    pub fn new(header: INesHeader, buf: &[u8]) -> BandaiFCGCartridge {
+        let INesHeader {
+            prg_size, chr_size, ..
+        } = header;
+        let prg_end = 16 + 0x4000 * prg_size;
+        let chr_end = prg_end + 0x2000 * chr_size;
+        BandaiFCGCartridge::from_parts(buf[16..prg_end].to_vec(), buf[prg_end..chr_end].to_vec())
+    }
+
+    /// Build a cartridge straight from its ROMs, without an iNES header
+    ///
+    /// Panics if either ROM isn't a whole number of banks.
+    pub fn from_parts(prg: Vec<u8>, chr: Vec<u8>) -> BandaiFCGCartridge {
+        assert_rom_size("PRG ROM", &prg, PRG_BANK_SIZE);
+        assert_rom_size("CHR ROM", &chr, CHR_BANK_SIZE);
+        BandaiFCGCartridge {
+            chr,
+            prg,
+            nametable: vec![0u8; 0x800],
+            chr_banks: [0u8; 8],
+            prg_bank: 0,
+            mirroring: Mirroring::Vertical,
+            irq_enabled: false,
+            irq_counter: 0,
+            irq_latch: 0,
+            irq_pending: false,
+            eeprom_control: 0,
+            eeprom: Eeprom24C02::new(),
+        }
+    }
+
+    /// Handle a write to one of the mapper registers
+    fn write_register(&mut self, reg: u16, value: u8) {
+        match reg & 0x0F {
+            reg @ 0x0..=0x7 => self.chr_banks[reg as usize] = value,
+            0x8 => self.prg_bank = value & 0x0F,
+            0x9 => {
+                self.mirroring = match value & 0x03 {
+                    0 => Mirroring::Vertical,
+                    1 => Mirroring::Horizontal,
+                    2 => Mirroring::SingleScreenLow,
+                    _ => Mirroring::SingleScreenHigh,
+                }
+            }
+            0xA => {
+                self.irq_enabled = value & 0x01 != 0;
+                // the LZ93D50 reloads the counter here, which the FCG-1/2
+                // games don't notice since they write the counter directly
+                self.irq_counter = self.irq_latch;
+                self.irq_pending = false;
+            }
+            // the FCG-1/2 writes the counter and the LZ93D50 writes the
+            // latch, so write both
+            0xB => {
+                self.irq_latch = (self.irq_latch & 0xFF00) | value as u16;
+                self.irq_counter = self.irq_latch;
+            }
+            0xC => {
+                self.irq_latch = (self.irq_latch & 0x00FF) | ((value as u16) << 8);
+                self.irq_counter = self.irq_latch;
+            }
+            0xD => {
+                self.eeprom_control = value;
+                let sda = value & (EEPROM_SDA | EEPROM_READ) != 0;
+                self.eeprom.write(value & EEPROM_SCL != 0, sda);
+            }
+            _ => {} // $xE and $xF are for the Datach's barcode reader
+        }
+    }
+
+    /// The data line as the CPU sees it
+    fn eeprom_sda(&self) -> bool {
+        // both sides can only pull the line low
+        let mapper_sda = self.eeprom_control & (EEPROM_SDA | EEPROM_READ) != 0;
+        mapper_sda && self.eeprom.output
+    }
+}
+
+impl ICartridge for BandaiFCGCartridge {
+    fn read_chr(&mut self, addr: u16, last_bus_value: u8) -> u8 {
+        self.peek_chr(addr).unwrap(last_bus_value)
+    }
+
+    fn peek_chr(&self, addr: u16) -> BusPeekResult {
+        if addr < 0x2000 {
+            let n_banks = self.chr.len() / CHR_BANK_SIZE;
+            let bank = self.chr_banks[(addr >> 10) as usize] as usize % n_banks;
+            return BusPeekResult::Result(self.chr[bank * CHR_BANK_SIZE + (addr & 0x3FF) as usize]);
+        }
+        BusPeekResult::Result(self.nametable[self.mirroring.nametable_addr(addr)])
+    }
+
+    fn write_chr(&mut self, addr: u16, value: u8) {
+        if addr < 0x2000 {
+            return; // no-op: this is a ROM
+        }
+        let nt_addr = self.mirroring.nametable_addr(addr);
+        self.nametable[nt_addr] = value;
+    }
+
+    fn read_prg(&mut self, region: PrgRegion, last_bus_value: u8) -> u8 {
+        if let PrgRegion::Ram(_) = region {
+            // only bit 4 is driven, by the EEPROM's data line
+            let sda = if self.eeprom_sda() { 0x10 } else { 0x00 };
+            return (last_bus_value & !0x10) | sda;
+        }
+        self.peek_prg(region).unwrap(last_bus_value)
+    }
+
+    fn peek_prg(&self, region: PrgRegion) -> BusPeekResult {
+        match region {
+            // reads here depend on open bus, which only `read_prg` knows
+            PrgRegion::Ram(_) => BusPeekResult::MutableRead,
+            // $8000-$BFFF
+            PrgRegion::Rom(addr @ 0x0000..=0x3FFF) => {
+                let n_banks = self.prg.len() / PRG_BANK_SIZE;
+                let bank = self.prg_bank as usize % n_banks;
+                BusPeekResult::Result(self.prg[bank * PRG_BANK_SIZE + addr as usize])
+            }
+            PrgRegion::Rom(addr) => {
+                let last_bank = self.prg.len() - PRG_BANK_SIZE;
+                BusPeekResult::Result(self.prg[last_bank + (addr & 0x3FFF) as usize])
+            }
+            PrgRegion::Expansion(_) => BusPeekResult::Unmapped,
+        }
+    }
+
+    fn write_prg(&mut self, region: PrgRegion, value: u8) {
+        match region {
+            PrgRegion::Ram(addr) | PrgRegion::Rom(addr) => self.write_register(addr, value),
+            PrgRegion::Expansion(_) => {}
+        }
+    }
+
+    fn dump_chr(&self) -> &[u8] {
+        &self.chr
+    }
+
+    fn dump_nametables(&self) -> &[u8] {
+        &self.nametable
+    }
+
+    fn debug_state(&self) -> CartridgeState {
+        CartridgeState::BandaiFCG(self.clone())
+    }
+
+    fn clock_cpu(&mut self) {
+        if !self.irq_enabled {
+            return;
+        }
+        // the IRQ fires as the counter wraps from 0, not as it reaches 0
+        if self.irq_counter == 0 {
+            self.irq_pending = true;
+        }
+        self.irq_counter = self.irq_counter.wrapping_sub(1);
+    }
+
+    fn irq_pending(&self) -> bool {
+        self.irq_pending
+    }
+
+    fn power_cycle(&mut self) {
+        self.nametable.fill(0);
+        self.chr_banks = [0u8; 8];
+        self.prg_bank = 0;
+        self.mirroring = Mirroring::Vertical;
+        self.irq_enabled = false;
+        self.irq_counter = 0;
+        self.irq_latch = 0;
+        self.irq_pending = false;
+        self.eeprom_control = 0;
+        self.eeprom.power_cycle();
+    }
+
+    fn save_data(&self) -> Option<&[u8]> {
+        Some(&self.eeprom.data)
+    }
+
+    fn load_save_data(&mut self, data: &[u8]) {
+        if data.len() == EEPROM_SIZE {
+            self.eeprom.data.copy_from_slice(data);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Build a 128k PRG, 32k CHR ROM where every byte of a bank is its number
+    fn make_cart() -> BandaiFCGCartridge {
+        BandaiFCGCartridge::from_parts(
+            (0..0x20000).map(|i| (i / PRG_BANK_SIZE) as u8).collect(),
+            (0..0x8000).map(|i| (i / CHR_BANK_SIZE) as u8).collect(),
+        )
+    }
+
+    fn peek_prg(cart: &BandaiFCGCartridge, addr: u16) -> BusPeekResult {
+        cart.peek_prg(PrgRegion::from_cpu_addr(addr))
+    }
+
+    /// Drive the EEPROM lines through register $800D
+    fn set_lines(cart: &mut BandaiFCGCartridge, scl: bool, sda: bool) {
+        let mut value = 0;
+        if scl {
+            value |= EEPROM_SCL;
+        }
+        if sda {
+            value |= EEPROM_SDA;
+        }
+        cart.write_prg(PrgRegion::from_cpu_addr(0x800D), value);
+    }
+
+    fn start(cart: &mut BandaiFCGCartridge) {
+        set_lines(cart, false, true);
+        set_lines(cart, true, true);
+        set_lines(cart, true, false);
+        set_lines(cart, false, false);
+    }
+
+    fn stop(cart: &mut BandaiFCGCartridge) {
+        set_lines(cart, false, false);
+        set_lines(cart, true, false);
+        set_lines(cart, true, true);
+    }
+
+    /// Clock one bit out to the EEPROM
+    fn send_bit(cart: &mut BandaiFCGCartridge, bit: bool) {
+        set_lines(cart, false, bit);
+        set_lines(cart, true, bit);
+        set_lines(cart, false, bit);
+    }
+
+    /// Clock one bit in from the EEPROM, with the mapper letting go of SDA
+    fn receive_bit(cart: &mut BandaiFCGCartridge) -> bool {
+        cart.write_prg(PrgRegion::from_cpu_addr(0x800D), EEPROM_READ);
+        cart.write_prg(PrgRegion::from_cpu_addr(0x800D), EEPROM_READ | EEPROM_SCL);
+        let bit = cart.read_prg(PrgRegion::from_cpu_addr(0x6000), 0x00) & 0x10 != 0;
+        cart.write_prg(PrgRegion::from_cpu_addr(0x800D), EEPROM_READ);
+        bit
+    }
+
+    /// Send a byte and return whether the EEPROM acknowledged it
+    fn send_byte(cart: &mut BandaiFCGCartridge, byte: u8) -> bool {
+        for i in 0..8 {
+            send_bit(cart, byte & (0x80 >> i) != 0);
+        }
+        !receive_bit(cart)
+    }
+
+    /// Receive a byte, and acknowledge it if there's more to read
+    fn receive_byte(cart: &mut BandaiFCGCartridge, ack: bool) -> u8 {
+        let byte = (0..8).fold(0, |byte, _| (byte << 1) | receive_bit(cart) as u8);
+        send_bit(cart, !ack);
+        byte
+    }
+
+    #[test]
+    fn switches_prg_banks() {
+        let mut cart = make_cart();
+        cart.write_prg(PrgRegion::from_cpu_addr(0x8008), 3);
+        assert_eq!(peek_prg(&cart, 0x8000), BusPeekResult::Result(3));
+        assert_eq!(peek_prg(&cart, 0xBFFF), BusPeekResult::Result(3));
+        // the FCG-1/2 registers at $6000 do the same thing
+        cart.write_prg(PrgRegion::from_cpu_addr(0x7FF8), 5);
+        assert_eq!(peek_prg(&cart, 0x8000), BusPeekResult::Result(5));
+        // and the registers repeat every 16 bytes
+        cart.write_prg(PrgRegion::from_cpu_addr(0xC018), 6);
+        assert_eq!(peek_prg(&cart, 0x8000), BusPeekResult::Result(6));
+        // the last bank is fixed
+        assert_eq!(peek_prg(&cart, 0xC000), BusPeekResult::Result(7));
+        assert_eq!(peek_prg(&cart, 0xFFFF), BusPeekResult::Result(7));
+    }
+
+    #[test]
+    fn switches_1k_chr_banks() {
+        let mut cart = make_cart();
+        for i in 0..8 {
+            cart.write_prg(PrgRegion::from_cpu_addr(0x8000 + i), 30 - i as u8);
+        }
+        for i in 0..8u16 {
+            assert_eq!(
+                cart.peek_chr(i * 0x400 + 0x3FF),
+                BusPeekResult::Result(30 - i as u8)
+            );
+        }
+    }
+
+    #[test]
+    fn selects_mirroring() {
+        let mut cart = make_cart();
+        cart.write_chr(0x2000, 1);
+        cart.write_chr(0x2400, 2);
+        assert_eq!(cart.peek_chr(0x2800), BusPeekResult::Result(1));
+        cart.write_prg(PrgRegion::from_cpu_addr(0x8009), 1);
+        assert_eq!(cart.peek_chr(0x2400), BusPeekResult::Result(1));
+        assert_eq!(cart.peek_chr(0x2800), BusPeekResult::Result(2));
+        cart.write_prg(PrgRegion::from_cpu_addr(0x8009), 3);
+        assert_eq!(cart.peek_chr(0x2000), BusPeekResult::Result(2));
+    }
+
+    #[test]
+    fn raises_an_irq_when_the_counter_wraps() {
+        let mut cart = make_cart();
+        cart.write_prg(PrgRegion::from_cpu_addr(0x800B), 0x03);
+        cart.write_prg(PrgRegion::from_cpu_addr(0x800C), 0x00);
+        for _ in 0..3 {
+            cart.clock_cpu();
+        }
+        assert_eq!(cart.irq_counter, 3, "Counted while disabled");
+        cart.write_prg(PrgRegion::from_cpu_addr(0x800A), 0x01);
+        for _ in 0..3 {
+            cart.clock_cpu();
+        }
+        assert!(!cart.irq_pending(), "IRQ fired as the counter reached 0");
+        cart.clock_cpu();
+        assert!(cart.irq_pending());
+        assert_eq!(cart.irq_counter, 0xFFFF);
+        // enabling again acknowledges, and reloads from the latch
+        cart.write_prg(PrgRegion::from_cpu_addr(0x800A), 0x01);
+        assert!(!cart.irq_pending());
+        assert_eq!(cart.irq_counter, 3);
+        cart.write_prg(PrgRegion::from_cpu_addr(0x800A), 0x00);
+        cart.clock_cpu();
+        assert_eq!(cart.irq_counter, 3, "Counter wasn't stopped");
+    }
+
+    #[test]
+    fn writes_and_reads_back_the_eeprom() {
+        let mut cart = make_cart();
+        start(&mut cart);
+        assert!(send_byte(&mut cart, 0xA0), "Device address wasn't acked");
+        assert!(send_byte(&mut cart, 0x12), "Word address wasn't acked");
+        for &byte in &[0xDE, 0xAD, 0xBE] {
+            assert!(send_byte(&mut cart, byte), "Data wasn't acked");
+        }
+        stop(&mut cart);
+        assert_eq!(&cart.save_data().unwrap()[0x12..0x15], &[0xDE, 0xAD, 0xBE]);
+
+        // a random read: set the address with a write, then read from it
+        start(&mut cart);
+        send_byte(&mut cart, 0xA0);
+        send_byte(&mut cart, 0x13);
+        start(&mut cart);
+        assert!(send_byte(&mut cart, 0xA1));
+        assert_eq!(receive_byte(&mut cart, true), 0xAD);
+        assert_eq!(receive_byte(&mut cart, false), 0xBE);
+        stop(&mut cart);
+
+        // nothing answers at other device addresses
+        start(&mut cart);
+        assert!(!send_byte(&mut cart, 0xB0));
+        stop(&mut cart);
+    }
+
+    #[test]
+    fn eeprom_writes_wrap_within_a_page() {
+        let mut cart = make_cart();
+        start(&mut cart);
+        send_byte(&mut cart, 0xA0);
+        send_byte(&mut cart, 0x0E);
+        for byte in 1..=3 {
+            send_byte(&mut cart, byte);
+        }
+        stop(&mut cart);
+        let data = cart.save_data().unwrap();
+        assert_eq!(&data[0x0E..0x10], &[1, 2]);
+        assert_eq!(data[0x08], 3);
+        assert_eq!(data[0x10], 0xFF);
+    }
+
+    #[test]
+    fn eeprom_survives_power_cycles() {
+        let mut cart = make_cart();
+        let mut save = vec![0u8; EEPROM_SIZE];
+        save[0x40] = 0x5A;
+        cart.load_save_data(&save);
+        cart.power_cycle();
+        assert_eq!(cart.save_data().unwrap()[0x40], 0x5A);
+        // saves of the wrong size are ignored
+        cart.load_save_data(&[0u8; 8192]);
+        assert_eq!(cart.save_data().unwrap()[0x40], 0x5A);
+    }
+}
@@ -0,0 +1,411 @@
+//! The Sunsoft FME-7 (mapper 69), and the Sunsoft 5B variant with audio
+//!
+//! The FME-7 has a command register at $8000-$9FFF, which picks what a write
+//! to the parameter register at $A000-$BFFF changes: one of eight 1k CHR
+//! banks, one of four 8k PRG banks (the first of which, at $6000, can be RAM),
+//! the nametable mirroring, or the IRQ counter. $E000-$FFFF is always the last
+//! 8k of PRG.
+//!
+//! Unlike the MMC3, whose IRQ counter watches the PPU address bus, the FME-7
+//! counts down once per CPU cycle, and raises an IRQ when it wraps from $0000
+//! to $FFFF.
+//!
+//! The 5B is the same mapper with an audio chip at $C000-$FFFF, which is only
+//! stubbed out here.
+//!
+//! cf. https://wiki.nesdev.com/w/index.php/Sunsoft_FME-7
+//! cf. https://wiki.nesdev.com/w/index.php/Sunsoft_5B_audio
+
+use alloc::{vec, vec::Vec};
+
+use super::ines::INesHeader;
+use super::utils::{assert_rom_size, CartridgeState, ICartridge, Mirroring, PrgRegion};
+use crate::devices::bus::BusPeekResult;
+
+const PRG_BANK_SIZE: usize = 0x2000;
+const CHR_BANK_SIZE: usize = 0x0400;
+const PRG_RAM_SIZE: usize = 0x2000;
+
+/// In the $6000 bank register, whether the bank is RAM instead of ROM
+const PRG_RAM_SELECT: u8 = 0x40;
+/// In the $6000 bank register, whether RAM is enabled
+const PRG_RAM_ENABLE: u8 = 0x80;
+
+/// The Sunsoft 5B's audio chip, a YM2149F with some pins left off
+///
+/// For now this only keeps track of its registers. The tone, noise, and
+/// envelope generators aren't emulated, so `sample` is always silent.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Sunsoft5B {
+    /// The register that writes to $E000 go to
+    selected: u8,
+    /// The tone periods, noise period, mixer, volumes, and envelope settings
+    registers: [u8; 16],
+}
+
+impl Sunsoft5B {
+    fn new() -> Sunsoft5B {
+        Sunsoft5B {
+            selected: 0,
+            registers: [0u8; 16],
+        }
+    }
+
+    /// Handle a write to $C000, selecting a register
+    fn select(&mut self, value: u8) {
+        // the top 4 bits have to be 0, otherwise the chip ignores the write
+        if value & 0xF0 == 0 {
+            self.selected = value;
+        }
+    }
+
+    /// Handle a write to $E000, to the selected register
+    fn write(&mut self, value: u8) {
+        self.registers[self.selected as usize] = value;
+    }
+
+    pub fn registers(&self) -> &[u8; 16] {
+        &self.registers
+    }
+
+    /// The current output level of the chip
+    pub fn sample(&self) -> f32 {
+        0.0
+    }
+}
+
+#[derive(Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct FME7Cartridge {
+    chr: Vec<u8>,
+    prg: Vec<u8>,
+    prg_ram: Vec<u8>,
+    nametable: Vec<u8>,
+    /// The register that writes to $A000 go to
+    command: u8,
+    chr_banks: [u8; 8],
+    /// The bank at $6000, along with the RAM select and enable bits
+    prg_bank_6000: u8,
+    /// The banks at $8000, $A000, and $C000
+    prg_banks: [u8; 3],
+    mirroring: Mirroring,
+    irq_enabled: bool,
+    irq_counter_enabled: bool,
+    irq_counter: u16,
+    irq_pending: bool,
+    audio: Sunsoft5B,
+}
+
+impl FME7Cartridge {
+    pub fn new(header: INesHeader, buf: &[u8]) -> FME7Cartridge {
+        let INesHeader {
+            prg_size, chr_size, ..
+        } = header;
+        let prg_end = 16 + 0x4000 * prg_size;
+        let chr_end = prg_end + 0x2000 * chr_size;
+        FME7Cartridge::from_parts(buf[16..prg_end].to_vec(), buf[prg_end..chr_end].to_vec())
+    }
+
+    /// Build a cartridge straight from its ROMs, without an iNES header
+    ///
+    /// Panics if either ROM isn't a whole number of banks.
+    pub fn from_parts(prg: Vec<u8>, chr: Vec<u8>) -> FME7Cartridge {
+        assert_rom_size("PRG ROM", &prg, PRG_BANK_SIZE);
+        assert_rom_size("CHR ROM", &chr, CHR_BANK_SIZE);
+        FME7Cartridge {
+            chr,
+            prg,
+            prg_ram: vec![0u8; PRG_RAM_SIZE],
+            nametable: vec![0u8; 0x800],
+            command: 0,
+            chr_banks: [0u8; 8],
+            prg_bank_6000: 0,
+            prg_banks: [0u8; 3],
+            mirroring: Mirroring::Vertical,
+            irq_enabled: false,
+            irq_counter_enabled: false,
+            irq_counter: 0,
+            irq_pending: false,
+            audio: Sunsoft5B::new(),
+        }
+    }
+
+    /// The 5B audio chip on this board
+    pub fn audio(&self) -> &Sunsoft5B {
+        &self.audio
+    }
+
+    /// Map an 8k PRG bank and an offset into it to an offset into PRG ROM
+    fn prg_addr(&self, bank: u8, offset: u16) -> usize {
+        let n_banks = self.prg.len() / PRG_BANK_SIZE;
+        (bank as usize % n_banks) * PRG_BANK_SIZE + offset as usize
+    }
+
+    /// Handle a write to the parameter register
+    fn write_parameter(&mut self, value: u8) {
+        match self.command {
+            0x0..=0x7 => self.chr_banks[self.command as usize] = value,
+            0x8 => self.prg_bank_6000 = value,
+            0x9..=0xB => self.prg_banks[(self.command - 0x9) as usize] = value & 0x3F,
+            0xC => {
+                self.mirroring = match value & 0x03 {
+                    0 => Mirroring::Vertical,
+                    1 => Mirroring::Horizontal,
+                    2 => Mirroring::SingleScreenLow,
+                    _ => Mirroring::SingleScreenHigh,
+                }
+            }
+            0xD => {
+                self.irq_enabled = value & 0x01 != 0;
+                self.irq_counter_enabled = value & 0x80 != 0;
+                // any write here acknowledges the IRQ
+                self.irq_pending = false;
+            }
+            0xE => self.irq_counter = (self.irq_counter & 0xFF00) | value as u16,
+            _ => self.irq_counter = (self.irq_counter & 0x00FF) | ((value as u16) << 8),
+        }
+    }
+}
+
+impl ICartridge for FME7Cartridge {
+    fn read_chr(&mut self, addr: u16, last_bus_value: u8) -> u8 {
+        self.peek_chr(addr).unwrap(last_bus_value)
+    }
+
+    fn peek_chr(&self, addr: u16) -> BusPeekResult {
+        if addr < 0x2000 {
+            let n_banks = self.chr.len() / CHR_BANK_SIZE;
+            let bank = self.chr_banks[(addr >> 10) as usize] as usize % n_banks;
+            return BusPeekResult::Result(self.chr[bank * CHR_BANK_SIZE + (addr & 0x3FF) as usize]);
+        }
+        BusPeekResult::Result(self.nametable[self.mirroring.nametable_addr(addr)])
+    }
+
+    fn write_chr(&mut self, addr: u16, value: u8) {
+        if addr < 0x2000 {
+            return; // no-op: this is a ROM
+        }
+        let nt_addr = self.mirroring.nametable_addr(addr);
+        self.nametable[nt_addr] = value;
+    }
+
+    fn read_prg(&mut self, region: PrgRegion, last_bus_value: u8) -> u8 {
+        self.peek_prg(region).unwrap(last_bus_value)
+    }
+
+    fn peek_prg(&self, region: PrgRegion) -> BusPeekResult {
+        match region {
+            PrgRegion::Ram(addr) if self.prg_bank_6000 & PRG_RAM_SELECT != 0 => {
+                if self.prg_bank_6000 & PRG_RAM_ENABLE != 0 {
+                    BusPeekResult::Result(self.prg_ram[addr as usize])
+                } else {
+                    BusPeekResult::Unmapped
+                }
+            }
+            PrgRegion::Ram(addr) => {
+                let offset = self.prg_addr(self.prg_bank_6000 & 0x3F, addr);
+                BusPeekResult::Result(self.prg[offset])
+            }
+            // $8000-$DFFF
+            PrgRegion::Rom(addr @ 0x0000..=0x5FFF) => {
+                let bank = self.prg_banks[(addr >> 13) as usize];
+                BusPeekResult::Result(self.prg[self.prg_addr(bank, addr & 0x1FFF)])
+            }
+            PrgRegion::Rom(addr) => {
+                let last_bank = self.prg.len() - PRG_BANK_SIZE;
+                BusPeekResult::Result(self.prg[last_bank + (addr & 0x1FFF) as usize])
+            }
+            PrgRegion::Expansion(_) => BusPeekResult::Unmapped,
+        }
+    }
+
+    fn write_prg(&mut self, region: PrgRegion, value: u8) {
+        match region {
+            PrgRegion::Ram(addr) => {
+                let ram_enabled = PRG_RAM_SELECT | PRG_RAM_ENABLE;
+                if self.prg_bank_6000 & ram_enabled == ram_enabled {
+                    self.prg_ram[addr as usize] = value;
+                }
+            }
+            // one register every 8k, starting from $8000
+            PrgRegion::Rom(addr) => match addr >> 13 {
+                0 => self.command = value & 0x0F,
+                1 => self.write_parameter(value),
+                2 => self.audio.select(value),
+                _ => self.audio.write(value),
+            },
+            PrgRegion::Expansion(_) => {}
+        }
+    }
+
+    fn dump_chr(&self) -> &[u8] {
+        &self.chr
+    }
+
+    fn dump_nametables(&self) -> &[u8] {
+        &self.nametable
+    }
+
+    fn debug_state(&self) -> CartridgeState {
+        CartridgeState::FME7(self.clone())
+    }
+
+    fn clock_cpu(&mut self) {
+        if !self.irq_counter_enabled {
+            return;
+        }
+        self.irq_counter = self.irq_counter.wrapping_sub(1);
+        if self.irq_counter == 0xFFFF && self.irq_enabled {
+            self.irq_pending = true;
+        }
+    }
+
+    fn irq_pending(&self) -> bool {
+        self.irq_pending
+    }
+
+    fn audio_sample(&self) -> f32 {
+        self.audio.sample()
+    }
+
+    fn power_cycle(&mut self) {
+        // PRG RAM is left alone, since it may be battery-backed
+        self.nametable.fill(0);
+        self.command = 0;
+        self.chr_banks = [0u8; 8];
+        self.prg_bank_6000 = 0;
+        self.prg_banks = [0u8; 3];
+        self.mirroring = Mirroring::Vertical;
+        self.irq_enabled = false;
+        self.irq_counter_enabled = false;
+        self.irq_counter = 0;
+        self.irq_pending = false;
+        self.audio = Sunsoft5B::new();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Build a 128k PRG, 64k CHR ROM where every byte of a bank is its number
+    fn make_cart() -> FME7Cartridge {
+        FME7Cartridge::from_parts(
+            (0..0x20000).map(|i| (i / PRG_BANK_SIZE) as u8).collect(),
+            (0..0x10000).map(|i| (i / CHR_BANK_SIZE) as u8).collect(),
+        )
+    }
+
+    fn write_register(cart: &mut FME7Cartridge, command: u8, value: u8) {
+        cart.write_prg(PrgRegion::from_cpu_addr(0x8000), command);
+        cart.write_prg(PrgRegion::from_cpu_addr(0xA000), value);
+    }
+
+    fn peek_prg(cart: &FME7Cartridge, addr: u16) -> BusPeekResult {
+        cart.peek_prg(PrgRegion::from_cpu_addr(addr))
+    }
+
+    #[test]
+    fn switches_prg_banks() {
+        let mut cart = make_cart();
+        write_register(&mut cart, 0x9, 3);
+        write_register(&mut cart, 0xA, 7);
+        write_register(&mut cart, 0xB, 0x45);
+        assert_eq!(peek_prg(&cart, 0x8000), BusPeekResult::Result(3));
+        assert_eq!(peek_prg(&cart, 0xBFFF), BusPeekResult::Result(7));
+        // bank numbers past the end of PRG wrap around
+        assert_eq!(peek_prg(&cart, 0xC000), BusPeekResult::Result(5));
+        // the last bank is fixed
+        assert_eq!(peek_prg(&cart, 0xE000), BusPeekResult::Result(15));
+        assert_eq!(peek_prg(&cart, 0xFFFF), BusPeekResult::Result(15));
+    }
+
+    #[test]
+    fn maps_rom_or_ram_at_6000() {
+        let mut cart = make_cart();
+        write_register(&mut cart, 0x8, 2);
+        assert_eq!(peek_prg(&cart, 0x6000), BusPeekResult::Result(2));
+        // RAM, but disabled
+        write_register(&mut cart, 0x8, PRG_RAM_SELECT);
+        cart.write_prg(PrgRegion::from_cpu_addr(0x6123), 0xAB);
+        assert_eq!(peek_prg(&cart, 0x6123), BusPeekResult::Unmapped);
+        write_register(&mut cart, 0x8, PRG_RAM_SELECT | PRG_RAM_ENABLE);
+        assert_eq!(peek_prg(&cart, 0x6123), BusPeekResult::Result(0x00));
+        cart.write_prg(PrgRegion::from_cpu_addr(0x6123), 0xAB);
+        assert_eq!(peek_prg(&cart, 0x6123), BusPeekResult::Result(0xAB));
+    }
+
+    #[test]
+    fn switches_1k_chr_banks() {
+        let mut cart = make_cart();
+        for i in 0..8 {
+            write_register(&mut cart, i, 60 - i);
+        }
+        for i in 0..8u16 {
+            assert_eq!(
+                cart.peek_chr(i * 0x400 + 0x3FF),
+                BusPeekResult::Result(60 - i as u8)
+            );
+        }
+    }
+
+    #[test]
+    fn selects_mirroring() {
+        let mut cart = make_cart();
+        cart.write_chr(0x2000, 1);
+        cart.write_chr(0x2400, 2);
+        // vertical
+        assert_eq!(cart.peek_chr(0x2800), BusPeekResult::Result(1));
+        write_register(&mut cart, 0xC, 1);
+        assert_eq!(cart.peek_chr(0x2400), BusPeekResult::Result(1));
+        assert_eq!(cart.peek_chr(0x2800), BusPeekResult::Result(2));
+        write_register(&mut cart, 0xC, 2);
+        assert_eq!(cart.peek_chr(0x2C00), BusPeekResult::Result(1));
+        write_register(&mut cart, 0xC, 3);
+        assert_eq!(cart.peek_chr(0x2000), BusPeekResult::Result(2));
+    }
+
+    #[test]
+    fn raises_an_irq_when_the_counter_wraps() {
+        let mut cart = make_cart();
+        write_register(&mut cart, 0xE, 0x02);
+        write_register(&mut cart, 0xF, 0x00);
+        // count, but don't raise IRQs
+        write_register(&mut cart, 0xD, 0x80);
+        for _ in 0..3 {
+            cart.clock_cpu();
+        }
+        assert_eq!(cart.irq_counter, 0xFFFF);
+        assert!(!cart.irq_pending());
+        write_register(&mut cart, 0xE, 0x02);
+        write_register(&mut cart, 0xF, 0x00);
+        write_register(&mut cart, 0xD, 0x81);
+        cart.clock_cpu();
+        cart.clock_cpu();
+        assert!(!cart.irq_pending());
+        cart.clock_cpu();
+        assert!(cart.irq_pending());
+        // the counter keeps going, and the IRQ stays up until acknowledged
+        cart.clock_cpu();
+        assert_eq!(cart.irq_counter, 0xFFFE);
+        assert!(cart.irq_pending());
+        write_register(&mut cart, 0xD, 0x00);
+        assert!(!cart.irq_pending());
+        cart.clock_cpu();
+        assert_eq!(cart.irq_counter, 0xFFFE, "Counter wasn't stopped");
+    }
+
+    #[test]
+    fn writes_5b_audio_registers() {
+        let mut cart = make_cart();
+        cart.write_prg(PrgRegion::from_cpu_addr(0xC000), 0x07);
+        cart.write_prg(PrgRegion::from_cpu_addr(0xE000), 0x38);
+        // an invalid register select is ignored
+        cart.write_prg(PrgRegion::from_cpu_addr(0xC000), 0x18);
+        cart.write_prg(PrgRegion::from_cpu_addr(0xE000), 0x3F);
+        assert_eq!(cart.audio().registers()[0x07], 0x3F);
+        assert_eq!(cart.audio().registers()[0x08], 0x00);
+        assert_eq!(cart.audio().sample(), 0.0);
+    }
+}
@@ -2,6 +2,7 @@
 
 /// Interface for an iNES header
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct INesHeader {
     /// The size of the PRG chunk, in 16k chunks. Will not be 0.
     pub prg_size: usize,
@@ -18,20 +19,101 @@ pub struct INesHeader {
     pub flags_9: u8,
     /// NTSC/PAL (again?!?), PRG-RAM (again!?!), also rarely used
     pub flags_10: u8,
+    /// Whether bytes 7-15 were garbage, and were read as zeroes instead
+    pub repaired: bool,
 }
 
-/** Given the first 16 bytes, parse out an iNES header */
+/// The signature some old ROM tools left in bytes 7-15 of the header
+const DISKDUDE_SIGNATURE: &[u8; 9] = b"DiskDude!";
+
+/// Whether bytes 7-15 of a header are garbage rather than flags
+///
+/// Old ROM tools wrote their names over the unused end of the header, which
+/// also lands on the upper nibble of the mapper number. Besides the
+/// well-known "DiskDude!", anything in bytes 12-15 of an iNES 1.0 header
+/// means the same thing, since those are always zero there.
+///
+/// cf. https://wiki.nesdev.com/w/index.php/INES#Flags_7
+fn has_garbage_flags(bytes: &[u8]) -> bool {
+    if bytes[7..16] == DISKDUDE_SIGNATURE[..] {
+        return true;
+    }
+    let is_ines_2_0 = bytes[7] & INesFlags7::IS_INES_2_0.bits() == 0x08;
+    !is_ines_2_0 && bytes[12..16].iter().any(|&byte| byte != 0)
+}
+
+/** Given the first 16 bytes, parse out an iNES header
+ *
+ * Headers with garbage in bytes 7-15 (see `has_garbage_flags`) are read as if
+ * those bytes were zero, and marked as `repaired`.
+ */
 pub fn parse_ines_header(bytes: &[u8]) -> INesHeader {
     // the first 4 bytes of the header are the null-terminated string "NES"
     // the last 5 bytes are unused in iNES 1.0
+    let repaired = has_garbage_flags(bytes);
+    let flags = |index: usize| if repaired { 0 } else { bytes[index] };
     INesHeader {
         prg_size: if bytes[4] == 0 { 1 } else { bytes[4] as usize },
         chr_size: if bytes[5] == 0 { 1 } else { bytes[5] as usize },
         flags_6: INesFlags6::from_bits_truncate(bytes[6]),
-        flags_7: INesFlags7::from_bits_truncate(bytes[7]),
-        flags_8: bytes[8],
-        flags_9: bytes[9],
-        flags_10: bytes[10],
+        flags_7: INesFlags7::from_bits_truncate(flags(7)),
+        flags_8: flags(8),
+        flags_9: flags(9),
+        flags_10: flags(10),
+        repaired,
+    }
+}
+
+impl INesHeader {
+    /// The mapper number, from the nibbles in flags 6 and 7
+    pub fn mapper(&self) -> u8 {
+        let lower_mapper_nibble = (self.flags_6 & INesFlags6::LOWER_MAPPER_NIBBLE).bits();
+        let upper_mapper_nibble = (self.flags_7 & INesFlags7::UPPER_MAPPER_NIBBLE).bits();
+        (lower_mapper_nibble >> 4) | upper_mapper_nibble
+    }
+
+    /// Which console the ROM was made for
+    pub fn console_type(&self) -> ConsoleType {
+        let vs = self.flags_7.contains(INesFlags7::VS_UNISYSTEM_ROM);
+        let playchoice = self.flags_7.contains(INesFlags7::PLAYCHOICE_10);
+        match (vs, playchoice) {
+            (false, false) => ConsoleType::Nes,
+            (true, false) => ConsoleType::VsSystem,
+            (false, true) => ConsoleType::PlayChoice10,
+            // NES 2.0 uses both bits for the extended console types, which
+            // are all clones and oddities
+            (true, true) => ConsoleType::Extended,
+        }
+    }
+}
+
+/// The hardware a ROM was made for, from flags 7
+///
+/// cf. https://wiki.nesdev.com/w/index.php/INES#Flags_7
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ConsoleType {
+    /// A regular NES or Famicom
+    Nes,
+    /// The Vs. System arcade board, with its own PPUs, coin slots, and DIP
+    /// switches
+    VsSystem,
+    /// The PlayChoice-10 arcade board, which runs NES games as-is and adds
+    /// 8k of hint screens after CHR ROM
+    PlayChoice10,
+    /// One of the NES 2.0 extended console types
+    Extended,
+}
+
+impl ConsoleType {
+    /// A name for the console, for error messages
+    pub fn name(self) -> &'static str {
+        match self {
+            ConsoleType::Nes => "NES",
+            ConsoleType::VsSystem => "Vs. System",
+            ConsoleType::PlayChoice10 => "PlayChoice-10",
+            ConsoleType::Extended => "extended console",
+        }
     }
 }
 
@@ -79,6 +161,11 @@ bitflags! {
     }
 }
 
+#[cfg(feature = "serde")]
+crate::serde_utils::serde_bitflags!(INesFlags6, u8);
+#[cfg(feature = "serde")]
+crate::serde_utils::serde_bitflags!(INesFlags7, u8);
+
 // todo: implement other flags as needed
 
 #[cfg(test)]
@@ -100,4 +187,66 @@ mod tests {
         assert_eq!(header.flags_9, 5, "Flags9 mismatch");
         assert_eq!(header.flags_10, 6, "Flags10 mismatch");
     }
+
+    #[test]
+    fn reads_the_mapper_from_both_nibbles() {
+        let mut bytes = [0u8; 16];
+        // the low bits of flags 6 are mirroring, battery, trainer, and four
+        // screen, and none of them are part of the mapper
+        bytes[6] = 0x5F;
+        bytes[7] = 0x40;
+        assert_eq!(parse_ines_header(&bytes).mapper(), 0x45);
+    }
+
+    #[test]
+    fn parses_console_types() {
+        let mut bytes = [0u8; 16];
+        let expected = [
+            (0x00, ConsoleType::Nes),
+            (0x01, ConsoleType::VsSystem),
+            (0x02, ConsoleType::PlayChoice10),
+            (0x0B, ConsoleType::Extended),
+        ];
+        for &(flags_7, console) in &expected {
+            bytes[7] = flags_7 | 0x40;
+            let header = parse_ines_header(&bytes);
+            assert_eq!(header.console_type(), console);
+            assert_eq!(header.mapper(), 0x40, "Console bits leaked into the mapper");
+        }
+    }
+
+    #[test]
+    fn repairs_diskdude_headers() {
+        let mut bytes = [0u8; 16];
+        bytes[0..4].copy_from_slice(b"NES\x1A");
+        bytes[6] = 0x21;
+        bytes[7..16].copy_from_slice(DISKDUDE_SIGNATURE);
+        let header = parse_ines_header(&bytes);
+        assert!(header.repaired);
+        // 'D' would otherwise make this mapper 0x42
+        assert_eq!(header.mapper(), 0x02);
+        assert_eq!(header.flags_7.bits(), 0);
+        assert_eq!(header.flags_8, 0);
+        assert!(header.flags_6.contains(INesFlags6::MIRRORING));
+    }
+
+    #[test]
+    fn repairs_other_garbage_in_ines_1_0_headers() {
+        let mut bytes = [0u8; 16];
+        bytes[7] = 0x10;
+        bytes[12..16].copy_from_slice(b"Ni03");
+        let header = parse_ines_header(&bytes);
+        assert!(header.repaired);
+        assert_eq!(header.mapper(), 0x00);
+
+        // NES 2.0 headers use those bytes
+        bytes[7] = 0x18;
+        let header = parse_ines_header(&bytes);
+        assert!(!header.repaired);
+        assert_eq!(header.mapper(), 0x10);
+
+        bytes[12..16].fill(0);
+        bytes[7] = 0x10;
+        assert!(!parse_ines_header(&bytes).repaired);
+    }
 }
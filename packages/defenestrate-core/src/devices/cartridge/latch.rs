@@ -0,0 +1,261 @@
+//! Discrete-logic boards that bank PRG and CHR with a single latch
+//!
+//! These boards have one register, which latches any write to $8000-$FFFF and
+//! selects a 32k PRG bank and an 8k CHR bank from it. There's nothing to
+//! arbitrate the data bus, so the ROM drives it at the same time as the CPU,
+//! and the value that gets latched is the AND of the two (a "bus conflict").
+//! Games avoid surprises by writing to a byte of ROM that holds the same value.
+//!
+//! Mirroring is hardwired, like on NROM.
+//!
+//! cf. https://wiki.nesdev.com/w/index.php/Color_Dreams
+//! cf. https://wiki.nesdev.com/w/index.php/GxROM
+
+use alloc::{vec, vec::Vec};
+
+use super::ines::INesHeader;
+use super::utils::{
+    assert_rom_size, hardwired_nametable_addr, CartridgeState, ICartridge, NametableArrangement,
+    PrgRegion,
+};
+use crate::devices::bus::BusPeekResult;
+
+const PRG_BANK_SIZE: usize = 0x8000;
+const CHR_BANK_SIZE: usize = 0x2000;
+
+/// Which board a `LatchCartridge` is, which decides how the latch is wired
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum LatchBoard {
+    /// Color Dreams (mapper 11): PRG in bits 0-1, CHR in bits 4-7
+    ColorDreams,
+    /// GxROM (mapper 66): PRG in bits 4-5, CHR in bits 0-1
+    GxROM,
+}
+
+impl LatchBoard {
+    /// Split a latched value into PRG and CHR bank numbers
+    fn banks(self, value: u8) -> (u8, u8) {
+        match self {
+            LatchBoard::ColorDreams => (value & 0x03, value >> 4),
+            LatchBoard::GxROM => ((value >> 4) & 0x03, value & 0x03),
+        }
+    }
+}
+
+#[derive(Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct LatchCartridge {
+    board: LatchBoard,
+    chr: Vec<u8>,
+    prg: Vec<u8>,
+    nametable: Vec<u8>,
+    arrangement: NametableArrangement,
+    prg_bank: u8,
+    chr_bank: u8,
+}
+
+impl LatchCartridge {
+    pub fn new(board: LatchBoard, header: INesHeader, buf: &[u8]) -> LatchCartridge {
+        let INesHeader {
+            prg_size,
+            chr_size,
+            flags_6,
+            ..
+        } = header;
+        let prg_end = 16 + 0x4000 * prg_size;
+        let chr_end = prg_end + 0x2000 * chr_size;
+        LatchCartridge::from_parts(
+            board,
+            buf[16..prg_end].to_vec(),
+            buf[prg_end..chr_end].to_vec(),
+            NametableArrangement::from_flags(flags_6),
+        )
+    }
+
+    /// Build a cartridge straight from its ROMs, without an iNES header
+    ///
+    /// Panics if `prg` isn't a whole number of 16k chunks, or `chr` isn't a
+    /// whole number of 8k banks.
+    pub fn from_parts(
+        board: LatchBoard,
+        prg: Vec<u8>,
+        chr: Vec<u8>,
+        arrangement: NametableArrangement,
+    ) -> LatchCartridge {
+        // 16k ROMs are mirrored, like on NROM
+        assert_rom_size("PRG ROM", &prg, 0x4000);
+        assert_rom_size("CHR ROM", &chr, CHR_BANK_SIZE);
+        LatchCartridge {
+            board,
+            chr,
+            prg,
+            nametable: vec![0u8; arrangement.vram_size()],
+            arrangement,
+            prg_bank: 0,
+            chr_bank: 0,
+        }
+    }
+
+    pub fn board(&self) -> LatchBoard {
+        self.board
+    }
+}
+
+impl ICartridge for LatchCartridge {
+    fn read_chr(&mut self, addr: u16, last_bus_value: u8) -> u8 {
+        self.peek_chr(addr).unwrap(last_bus_value)
+    }
+
+    fn peek_chr(&self, addr: u16) -> BusPeekResult {
+        if addr < 0x2000 {
+            let n_banks = self.chr.len() / CHR_BANK_SIZE;
+            let bank = self.chr_bank as usize % n_banks;
+            return BusPeekResult::Result(self.chr[bank * CHR_BANK_SIZE + addr as usize]);
+        }
+        let nt_addr = hardwired_nametable_addr(addr, self.arrangement);
+        BusPeekResult::Result(self.nametable[nt_addr])
+    }
+
+    fn write_chr(&mut self, addr: u16, value: u8) {
+        if addr < 0x2000 {
+            return; // no-op: this is a ROM
+        }
+        let nt_addr = hardwired_nametable_addr(addr, self.arrangement);
+        self.nametable[nt_addr] = value;
+    }
+
+    fn read_prg(&mut self, region: PrgRegion, last_bus_value: u8) -> u8 {
+        self.peek_prg(region).unwrap(last_bus_value)
+    }
+
+    fn peek_prg(&self, region: PrgRegion) -> BusPeekResult {
+        let addr = match region {
+            PrgRegion::Rom(addr) => addr,
+            _ => return BusPeekResult::Unmapped,
+        };
+        let n_banks = self.prg.len() / PRG_BANK_SIZE;
+        // 16k ROMs are mirrored, like on NROM
+        let offset = addr as usize % self.prg.len().min(PRG_BANK_SIZE);
+        let bank = self.prg_bank as usize % n_banks.max(1);
+        BusPeekResult::Result(self.prg[bank * PRG_BANK_SIZE + offset])
+    }
+
+    fn write_prg(&mut self, region: PrgRegion, value: u8) {
+        let rom_value = match self.peek_prg(region) {
+            BusPeekResult::Result(rom_value) => rom_value,
+            _ => return,
+        };
+        let (prg_bank, chr_bank) = self.board.banks(value & rom_value);
+        self.prg_bank = prg_bank;
+        self.chr_bank = chr_bank;
+    }
+
+    fn dump_chr(&self) -> &[u8] {
+        &self.chr
+    }
+
+    fn dump_nametables(&self) -> &[u8] {
+        &self.nametable
+    }
+
+    fn debug_state(&self) -> CartridgeState {
+        CartridgeState::Latch(self.clone())
+    }
+
+    fn power_cycle(&mut self) {
+        self.nametable.fill(0);
+        self.prg_bank = 0;
+        self.chr_bank = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Build a cart with `prg_size` 16k chunks of PRG, and `chr_banks` 8k
+    /// banks of CHR
+    ///
+    /// Each PRG bank starts with its bank number, and is $FF after that. Every
+    /// byte of a CHR bank is its bank number.
+    fn make_cart(board: LatchBoard, prg_size: usize, chr_banks: usize) -> LatchCartridge {
+        let prg = (0..prg_size * 0x4000)
+            .map(|i| {
+                if i % PRG_BANK_SIZE == 0 {
+                    (i / PRG_BANK_SIZE) as u8
+                } else {
+                    0xFF
+                }
+            })
+            .collect();
+        let chr = (0..chr_banks * CHR_BANK_SIZE)
+            .map(|i| (i / CHR_BANK_SIZE) as u8)
+            .collect();
+        LatchCartridge::from_parts(board, prg, chr, NametableArrangement::Horizontal)
+    }
+
+    /// The bank number at the start of the current PRG bank
+    fn prg_bank(cart: &LatchCartridge) -> BusPeekResult {
+        cart.peek_prg(PrgRegion::from_cpu_addr(0x8000))
+    }
+
+    /// Write `value` to a byte of ROM that holds $FF, to avoid a bus conflict
+    fn latch(cart: &mut LatchCartridge, value: u8) {
+        cart.write_prg(PrgRegion::from_cpu_addr(0xFFF0), value);
+    }
+
+    #[test]
+    fn color_dreams_switches_banks() {
+        let mut cart = make_cart(LatchBoard::ColorDreams, 8, 16);
+        latch(&mut cart, 0xA2);
+        assert_eq!(prg_bank(&cart), BusPeekResult::Result(2));
+        assert_eq!(cart.peek_chr(0x1FFF), BusPeekResult::Result(0x0A));
+        latch(&mut cart, 0xF3);
+        assert_eq!(prg_bank(&cart), BusPeekResult::Result(3));
+        assert_eq!(cart.peek_chr(0x0000), BusPeekResult::Result(0x0F));
+    }
+
+    #[test]
+    fn gxrom_switches_banks() {
+        let mut cart = make_cart(LatchBoard::GxROM, 8, 4);
+        latch(&mut cart, 0x21);
+        assert_eq!(prg_bank(&cart), BusPeekResult::Result(2));
+        assert_eq!(cart.peek_chr(0x1000), BusPeekResult::Result(0x01));
+        // the unused bits are ignored
+        latch(&mut cart, 0xCE);
+        assert_eq!(prg_bank(&cart), BusPeekResult::Result(0));
+        assert_eq!(cart.peek_chr(0x1000), BusPeekResult::Result(0x02));
+    }
+
+    #[test]
+    fn wraps_banks_past_the_end_of_rom() {
+        let mut cart = make_cart(LatchBoard::ColorDreams, 4, 4);
+        latch(&mut cart, 0x73);
+        assert_eq!(prg_bank(&cart), BusPeekResult::Result(1));
+        assert_eq!(cart.peek_chr(0x0000), BusPeekResult::Result(0x03));
+    }
+
+    #[test]
+    fn has_bus_conflicts() {
+        let mut cart = make_cart(LatchBoard::GxROM, 8, 4);
+        latch(&mut cart, 0x11);
+        // $8000 holds a 1 in bank 1, so only bit 0 makes it through
+        cart.write_prg(PrgRegion::from_cpu_addr(0x8000), 0x33);
+        assert_eq!(prg_bank(&cart), BusPeekResult::Result(0));
+        assert_eq!(cart.peek_chr(0x0000), BusPeekResult::Result(0x01));
+    }
+
+    #[test]
+    fn mirrors_16k_prg() {
+        let cart = make_cart(LatchBoard::GxROM, 1, 1);
+        assert_eq!(
+            cart.peek_prg(PrgRegion::from_cpu_addr(0x8000)),
+            cart.peek_prg(PrgRegion::from_cpu_addr(0xC000))
+        );
+        assert_eq!(
+            cart.peek_prg(PrgRegion::from_cpu_addr(0xBFFF)),
+            cart.peek_prg(PrgRegion::from_cpu_addr(0xFFFF))
+        );
+    }
+}
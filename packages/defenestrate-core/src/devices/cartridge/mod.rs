@@ -1,18 +1,403 @@
+use alloc::boxed::Box;
+
+use crate::error::{Error, Result};
+
+mod bandai;
+mod fme7;
 mod ines;
+mod latch;
+mod namco163;
 mod nrom;
 mod utils;
 
-pub use utils::{ICartridge, WithCartridge};
+pub use bandai::BandaiFCGCartridge;
+pub use fme7::{FME7Cartridge, Sunsoft5B};
+pub use ines::ConsoleType;
+pub use latch::{LatchBoard, LatchCartridge};
+pub use namco163::{Namco163Audio, Namco163Cartridge};
+pub use nrom::NROMCartridge;
+pub(crate) use utils::hardwired_nametable_addr;
+pub use utils::{CartridgeState, ICartridge, NametableArrangement, PrgRegion};
+
+/// The iNES magic number, "NES" followed by an MS-DOS EOF
+const INES_MAGIC: [u8; 4] = [0x4E, 0x45, 0x53, 0x1A];
+
+/// The board most commonly behind a mapper number, for error messages
+///
+/// Only the mappers that cover most of the licensed library (and the ones we
+/// support) are listed here. cf. https://wiki.nesdev.com/w/index.php/Mapper
+fn board_name(mapper: u8) -> Option<&'static str> {
+    let name = match mapper {
+        0 => "NROM",
+        1 => "SxROM/MMC1",
+        2 => "UxROM",
+        3 => "CNROM",
+        4 => "TxROM/MMC3",
+        5 => "ExROM/MMC5",
+        7 => "AxROM",
+        9 => "PxROM/MMC2",
+        10 => "FxROM/MMC4",
+        11 => "Color Dreams",
+        16 => "Bandai FCG",
+        19 => "Namco 163",
+        21 | 23 | 25 => "VRC2/VRC4",
+        22 => "VRC2",
+        24 | 26 => "VRC6",
+        34 => "BNROM/NINA-001",
+        66 => "GxROM",
+        69 => "Sunsoft FME-7",
+        71 => "Camerica/Codemasters",
+        85 => "VRC7",
+        206 => "DxROM/Namco 118",
+        _ => return None,
+    };
+    Some(name)
+}
+
+/// What an iNES header says about a ROM, without loading it
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct RomInfo {
+    /// The iNES mapper number
+    pub mapper: u8,
+    /// The board most commonly behind the mapper number, if it's a well-known
+    /// one
+    pub board: Option<&'static str>,
+    /// The size of PRG ROM, in 16k chunks
+    pub prg_size: usize,
+    /// The size of CHR ROM, in 8k chunks
+    pub chr_size: usize,
+    /// Whether the cartridge keeps its RAM with a battery
+    pub has_battery: bool,
+    /// The hardware the ROM was made for
+    pub console_type: ConsoleType,
+    /// Whether the header had garbage where its flags go, like the
+    /// "DiskDude!" some old ROM tools left behind
+    ///
+    /// Those bytes are read as zeroes, so the mapper number comes from the
+    /// lower nibble alone. A wrong mapper on a repaired ROM probably means the
+    /// header needs fixing by hand.
+    pub header_repaired: bool,
+}
+
+/// Read the header of an iNES ROM, checking that the rest of the ROM is there
+pub fn rom_info(buf: &[u8]) -> Result<RomInfo> {
+    if buf.len() < 16 {
+        return Err(Error::TruncatedRom {
+            expected: 16,
+            actual: buf.len(),
+        });
+    }
+    if buf[0..4] != INES_MAGIC {
+        return Err(Error::InvalidHeader);
+    }
+    let header = ines::parse_ines_header(buf);
+    let expected = 16 + 0x4000 * header.prg_size + 0x2000 * header.chr_size;
+    if buf.len() < expected {
+        return Err(Error::TruncatedRom {
+            expected,
+            actual: buf.len(),
+        });
+    }
+    Ok(RomInfo {
+        mapper: header.mapper(),
+        board: board_name(header.mapper()),
+        prg_size: header.prg_size,
+        chr_size: header.chr_size,
+        has_battery: header
+            .flags_6
+            .contains(ines::INesFlags6::HAS_PERSISTENT_MEMORY),
+        console_type: header.console_type(),
+        header_repaired: header.repaired,
+    })
+}
+
+/// Check that the ROM's board can hold as much PRG and CHR ROM as its header
+/// says, since the boards' `from_parts` constructors panic on sizes they can't
+/// handle
+fn check_rom_size(info: &RomInfo) -> Result<()> {
+    let fits = match info.mapper {
+        // NROM doesn't switch banks, so it only has room for 32k and 8k
+        0 => info.prg_size <= 2 && info.chr_size == 1,
+        // the other boards take any whole number of banks, which is all a
+        // header can describe
+        _ => true,
+    };
+    if fits {
+        Ok(())
+    } else {
+        Err(Error::InvalidRomSize {
+            mapper: info.mapper,
+            prg_size: info.prg_size,
+            chr_size: info.chr_size,
+        })
+    }
+}
 
 /// Given a buffer to an iNES ROM, return an ICartridge representing that ROM
-pub fn from_rom(buf: &[u8]) -> impl utils::ICartridge {
+pub fn from_rom(buf: &[u8]) -> Result<Box<dyn ICartridge>> {
+    let info = rom_info(buf)?;
+    if info.header_repaired {
+        log::warn!("Ignoring garbage in bytes 7-15 of the iNES header");
+    }
+    match info.console_type {
+        ConsoleType::Nes => {}
+        ConsoleType::PlayChoice10 => {
+            // the games themselves are unmodified, so they run fine without
+            // the hint screens
+            log::info!("Running a PlayChoice-10 ROM as a regular NES game");
+        }
+        console => {
+            return Err(Error::UnsupportedConsole {
+                console: console.name(),
+            })
+        }
+    }
+    check_rom_size(&info)?;
     let header = ines::parse_ines_header(&buf);
-    let lower_mapper_nibble: u8 = (header.flags_6 & ines::INesFlags6::LOWER_MAPPER_NIBBLE).bits();
-    let upper_mapper_nibble: u8 = (header.flags_7 & ines::INesFlags7::UPPER_MAPPER_NIBBLE).bits();
-    let mapper = lower_mapper_nibble | (upper_mapper_nibble >> 4);
+    match info.mapper {
+        0 => Ok(Box::new(nrom::NROMCartridge::new(header, &buf))),
+        11 => Ok(Box::new(latch::LatchCartridge::new(
+            latch::LatchBoard::ColorDreams,
+            header,
+            buf,
+        ))),
+        16 => Ok(Box::new(bandai::BandaiFCGCartridge::new(header, buf))),
+        19 => Ok(Box::new(namco163::Namco163Cartridge::new(header, buf))),
+        66 => Ok(Box::new(latch::LatchCartridge::new(
+            latch::LatchBoard::GxROM,
+            header,
+            buf,
+        ))),
+        69 => Ok(Box::new(fme7::FME7Cartridge::new(header, buf))),
+        _ => Err(Error::UnsupportedMapper {
+            mapper: info.mapper,
+            board: info.board,
+            prg_size: info.prg_size,
+            chr_size: info.chr_size,
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(prg_size: u8, mapper: u8) -> Vec<u8> {
+        let mut rom = vec![0u8; 16];
+        rom[0..4].copy_from_slice(&INES_MAGIC);
+        rom[4] = prg_size;
+        rom[5] = 1;
+        rom[6] = mapper << 4;
+        rom
+    }
+
+    #[test]
+    fn rejects_bad_magic() {
+        let mut rom = header(1, 0);
+        rom[0] = 0;
+        rom.resize(16 + 0x4000 + 0x2000, 0);
+        assert!(matches!(from_rom(&rom), Err(Error::InvalidHeader)));
+    }
+
+    #[test]
+    fn rejects_truncated_roms() {
+        let mut rom = header(2, 0);
+        rom.resize(16 + 0x4000 + 0x2000, 0);
+        match from_rom(&rom) {
+            Err(Error::TruncatedRom { expected, actual }) => {
+                assert_eq!(expected, 16 + 0x8000 + 0x2000);
+                assert_eq!(actual, rom.len());
+            }
+            _ => panic!("Expected a truncated ROM error"),
+        }
+        assert!(matches!(
+            from_rom(&rom[..8]),
+            Err(Error::TruncatedRom { .. })
+        ));
+    }
+
+    #[test]
+    fn rejects_roms_too_big_for_their_board() {
+        // 48k of PRG on NROM
+        let mut rom = header(3, 0);
+        rom.resize(16 + 0xC000 + 0x2000, 0);
+        assert!(matches!(
+            from_rom(&rom),
+            Err(Error::InvalidRomSize {
+                mapper: 0,
+                prg_size: 3,
+                chr_size: 1
+            })
+        ));
+        // 16k of CHR
+        let mut rom = header(1, 0);
+        rom[5] = 2;
+        rom.resize(16 + 0x4000 + 0x4000, 0);
+        assert!(matches!(
+            from_rom(&rom),
+            Err(Error::InvalidRomSize { chr_size: 2, .. })
+        ));
+    }
+
+    #[test]
+    fn loads_latch_mappers() {
+        for &(mapper, board) in &[(11, LatchBoard::ColorDreams), (66, LatchBoard::GxROM)] {
+            let mut rom = header(2, mapper);
+            rom[7] = mapper & 0xF0;
+            rom.resize(16 + 0x8000 + 0x2000, 0);
+            match from_rom(&rom).map(|cart| cart.debug_state()) {
+                Ok(CartridgeState::Latch(cart)) => assert_eq!(cart.board(), board),
+                _ => panic!("Mapper {} didn't load as a latch board", mapper),
+            }
+        }
+    }
+
+    #[test]
+    fn loads_namco_163_with_its_battery() {
+        let mut rom = header(2, 19);
+        rom[6] |= 0x02;
+        rom[7] = 0x10;
+        rom.resize(16 + 0x8000 + 0x2000, 0);
+        let cart = from_rom(&rom).unwrap();
+        assert!(matches!(cart.debug_state(), CartridgeState::Namco163(_)));
+        assert_eq!(cart.save_data().map(<[u8]>::len), Some(0x2000));
+    }
+
+    #[test]
+    fn arranges_hardwired_nametables() {
+        use crate::devices::bus::BusPeekResult;
+        // horizontal mirroring, vertical mirroring, and four-screen
+        let expected: [(u8, [u8; 4]); 3] = [
+            (0x00, [2, 2, 4, 4]),
+            (0x01, [3, 4, 3, 4]),
+            (0x08, [1, 2, 3, 4]),
+        ];
+        for &(flags, tables) in &expected {
+            let mut rom = header(1, 0);
+            rom[6] |= flags;
+            rom.resize(16 + 0x4000 + 0x2000, 0);
+            let mut cart = from_rom(&rom).unwrap();
+            for table in 0..4u16 {
+                cart.write_chr(0x2000 + table * 0x400, table as u8 + 1);
+            }
+            for table in 0..4u16 {
+                assert_eq!(
+                    cart.peek_chr(0x2000 + table * 0x400),
+                    BusPeekResult::Result(tables[table as usize]),
+                    "Nametable {} mismatch with flags {:02X}",
+                    table,
+                    flags
+                );
+            }
+            // $3000-$3EFF mirrors $2000-$2EFF either way
+            assert_eq!(cart.peek_chr(0x3800), BusPeekResult::Result(tables[2]));
+        }
+    }
+
+    #[test]
+    fn rejects_unsupported_mappers() {
+        let mut rom = header(1, 4);
+        rom.resize(16 + 0x4000 + 0x2000, 0);
+        let err = match from_rom(&rom) {
+            Err(err) => err,
+            Ok(_) => panic!("Expected an unsupported mapper error"),
+        };
+        assert!(matches!(
+            err,
+            Error::UnsupportedMapper {
+                mapper: 4,
+                board: Some("TxROM/MMC3"),
+                prg_size: 1,
+                chr_size: 1,
+            }
+        ));
+        assert_eq!(
+            err.to_string(),
+            "mapper 4 (TxROM/MMC3) is not supported (16k PRG ROM, 8k CHR ROM)"
+        );
+    }
+
+    #[test]
+    fn reads_rom_info() {
+        let mut rom = header(2, 0x5);
+        rom[6] |= 0x02;
+        rom[7] = 0x40;
+        rom.resize(16 + 0x8000 + 0x2000, 0);
+        assert_eq!(
+            rom_info(&rom).unwrap(),
+            RomInfo {
+                mapper: 0x45,
+                board: Some("Sunsoft FME-7"),
+                prg_size: 2,
+                chr_size: 1,
+                has_battery: true,
+                console_type: ConsoleType::Nes,
+                header_repaired: false,
+            }
+        );
+    }
+
+    #[test]
+    fn reports_repaired_headers() {
+        let mut rom = header(1, 0);
+        rom[7..16].copy_from_slice(b"DiskDude!");
+        rom.resize(16 + 0x4000 + 0x2000, 0);
+        let info = rom_info(&rom).unwrap();
+        assert!(info.header_repaired);
+        assert_eq!(info.mapper, 0);
+        assert_eq!(info.board, Some("NROM"));
+        assert!(matches!(
+            from_rom(&rom).map(|cart| cart.debug_state()),
+            Ok(CartridgeState::NROM(_))
+        ));
+    }
+
+    #[test]
+    fn rejects_vs_system_roms() {
+        let mut rom = header(1, 0);
+        rom[7] = 0x01;
+        rom.resize(16 + 0x4000 + 0x2000, 0);
+        assert_eq!(rom_info(&rom).unwrap().console_type, ConsoleType::VsSystem);
+        let err = from_rom(&rom)
+            .err()
+            .expect("Expected an unsupported console error");
+        assert!(matches!(
+            err,
+            Error::UnsupportedConsole {
+                console: "Vs. System"
+            }
+        ));
+        assert_eq!(err.to_string(), "Vs. System ROMs are not supported");
+    }
+
+    #[test]
+    fn runs_playchoice_roms_as_nes_games() {
+        let mut rom = header(1, 0);
+        rom[7] = 0x02;
+        // the hint screens come after CHR ROM
+        rom.resize(16 + 0x4000 + 0x2000 + 0x2000, 0);
+        assert_eq!(
+            rom_info(&rom).unwrap().console_type,
+            ConsoleType::PlayChoice10
+        );
+        assert!(matches!(
+            from_rom(&rom).map(|cart| cart.debug_state()),
+            Ok(CartridgeState::NROM(_))
+        ));
+    }
 
-    match mapper {
-        0 => nrom::NROMCartridge::new(header, &buf),
-        _ => unimplemented!("Mapper {} not implemented", mapper),
+    #[test]
+    fn names_unknown_mappers_by_number() {
+        let mut rom = header(2, 0xE);
+        rom[7] = 0xF0;
+        rom.resize(16 + 0x8000 + 0x2000, 0);
+        let err = from_rom(&rom)
+            .err()
+            .expect("Expected an unsupported mapper error");
+        assert_eq!(
+            err.to_string(),
+            "mapper 254 is not supported (32k PRG ROM, 8k CHR ROM)"
+        );
     }
 }
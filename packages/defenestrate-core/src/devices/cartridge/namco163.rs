@@ -0,0 +1,666 @@
+//! The Namco 163 (mapper 19), and its wavetable expansion audio
+//!
+//! The 163 has twelve 1k CHR bank registers. Eight cover the pattern tables
+//! at $0000-$1FFF, as usual, and the other four cover the nametables. Bank
+//! numbers $E0-$FF pick one of the console's two 1k nametables (CIRAM)
+//! instead of CHR ROM, so games can lay out the nametables any which way, or
+//! draw them straight from CHR ROM. The pattern table registers can point at
+//! CIRAM too, unless that's turned off in $E800.
+//!
+//! PRG is split into four 8k banks, the last fixed to the end of PRG ROM, with
+//! 8k of (usually battery-backed) RAM at $6000. There's also a 15-bit IRQ
+//! counter, which counts up once per CPU cycle and raises an IRQ at $7FFF.
+//!
+//! cf. https://wiki.nesdev.com/w/index.php/Namco_163
+//! cf. https://wiki.nesdev.com/w/index.php/Namco_163_audio
+
+use alloc::{vec, vec::Vec};
+
+use super::ines::{INesFlags6, INesHeader};
+use super::utils::{assert_rom_size, CartridgeState, ICartridge, PrgRegion};
+use crate::devices::bus::BusPeekResult;
+
+const PRG_BANK_SIZE: usize = 0x2000;
+const CHR_BANK_SIZE: usize = 0x0400;
+const PRG_RAM_SIZE: usize = 0x2000;
+
+/// Bank numbers at or above this select CIRAM instead of CHR ROM
+const CIRAM_BANK_START: u8 = 0xE0;
+
+/// In $E000, whether the audio is turned off
+const SOUND_DISABLE: u8 = 0x40;
+/// In $E800, whether the registers for $0000-$0FFF always select CHR ROM
+const LOW_CIRAM_DISABLE: u8 = 0x40;
+/// In $E800, whether the registers for $1000-$1FFF always select CHR ROM
+const HIGH_CIRAM_DISABLE: u8 = 0x80;
+
+/// In $F800, the value the top 4 bits must have for PRG RAM to be writable
+const PRG_RAM_WRITE_ENABLE: u8 = 0x40;
+
+/// In the sound address port, whether the address goes up after each access
+const SOUND_AUTO_INCREMENT: u8 = 0x80;
+
+/// How many CPU cycles it takes to update one channel
+const CYCLES_PER_CHANNEL: u8 = 15;
+/// Where the first channel's registers are in sound RAM
+///
+/// The channels are numbered up from here, but the last channel is the one
+/// that's always enabled.
+const CHANNEL_REGS_START: usize = 0x40;
+/// The largest magnitude a channel can output, `(0 - 8) * 15`
+const MAX_OUTPUT: f32 = 120.0;
+
+/// What a 1k slice of the PPU address space is mapped to
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+enum ChrPage {
+    /// An offset into CHR ROM
+    Rom(usize),
+    /// An offset into the console's nametables
+    Ciram(usize),
+}
+
+/// The 163's audio: up to eight wavetable channels, sharing 128 bytes of RAM
+///
+/// Each channel has its registers in the top of sound RAM, and plays 4-bit
+/// samples out of whatever part of the same RAM its wave address points to.
+/// The chip only updates one channel every 15 CPU cycles, so turning more
+/// channels on makes each one update less often (and, on real hardware, whine
+/// at the rate it switches between them).
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Namco163Audio {
+    /// Wave samples, two to a byte, with the channel registers at $40-$7F
+    #[cfg_attr(feature = "serde", serde(with = "crate::serde_utils::byte_array"))]
+    ram: [u8; 128],
+    /// The sound address port, with the auto-increment bit
+    address: u8,
+    /// CPU cycles until the next channel update
+    timer: u8,
+    /// The channel being updated next
+    channel: u8,
+    /// The last output of each channel, from -120 to 105
+    outputs: [i16; 8],
+}
+
+impl Namco163Audio {
+    fn new() -> Namco163Audio {
+        Namco163Audio {
+            ram: [0u8; 128],
+            address: 0,
+            timer: CYCLES_PER_CHANNEL,
+            channel: 7,
+            outputs: [0i16; 8],
+        }
+    }
+
+    /// The sound RAM, channel registers and all
+    pub fn ram(&self) -> &[u8; 128] {
+        &self.ram
+    }
+
+    /// How many channels are enabled, counting down from channel 7
+    pub fn active_channels(&self) -> u8 {
+        ((self.ram[0x7F] >> 4) & 0x07) + 1
+    }
+
+    /// Handle a write to $F800, setting the sound RAM address
+    fn set_address(&mut self, value: u8) {
+        self.address = value;
+    }
+
+    /// Move the sound RAM address along, if auto-increment is on
+    fn step_address(&mut self) {
+        if self.address & SOUND_AUTO_INCREMENT != 0 {
+            self.address = SOUND_AUTO_INCREMENT | (self.address.wrapping_add(1) & 0x7F);
+        }
+    }
+
+    fn peek_data(&self) -> u8 {
+        self.ram[(self.address & 0x7F) as usize]
+    }
+
+    /// Handle a read from $4800
+    fn read_data(&mut self) -> u8 {
+        let value = self.peek_data();
+        self.step_address();
+        value
+    }
+
+    /// Handle a write to $4800
+    fn write_data(&mut self, value: u8) {
+        self.ram[(self.address & 0x7F) as usize] = value;
+        self.step_address();
+    }
+
+    /// Read the 4-bit sample at a wave address, counted in samples
+    fn wave_sample(&self, addr: u8) -> u8 {
+        let byte = self.ram[(addr >> 1) as usize];
+        if addr & 0x01 == 0 {
+            byte & 0x0F
+        } else {
+            byte >> 4
+        }
+    }
+
+    /// Step one channel along its wave, and work out its new output
+    ///
+    /// A channel's 8 bytes of registers hold an 18-bit frequency, a 24-bit
+    /// phase, the wave's length and where it starts, and a 4-bit volume.
+    fn update_channel(&mut self, channel: u8) {
+        let regs = CHANNEL_REGS_START + 8 * channel as usize;
+        let freq = self.ram[regs] as u32
+            | (self.ram[regs + 2] as u32) << 8
+            | (self.ram[regs + 4] as u32 & 0x03) << 16;
+        let phase = self.ram[regs + 1] as u32
+            | (self.ram[regs + 3] as u32) << 8
+            | (self.ram[regs + 5] as u32) << 16;
+        let length = 256 - (self.ram[regs + 4] & 0xFC) as u32;
+        let phase = (phase + freq) % (length << 16);
+        self.ram[regs + 1] = phase as u8;
+        self.ram[regs + 3] = (phase >> 8) as u8;
+        self.ram[regs + 5] = (phase >> 16) as u8;
+        let offset = self.ram[regs + 6];
+        let sample = self.wave_sample(((phase >> 16) as u8).wrapping_add(offset));
+        let volume = (self.ram[regs + 7] & 0x0F) as i16;
+        self.outputs[channel as usize] = (sample as i16 - 8) * volume;
+    }
+
+    /// Clock the audio once per CPU cycle
+    fn clock(&mut self) {
+        self.timer -= 1;
+        if self.timer > 0 {
+            return;
+        }
+        self.timer = CYCLES_PER_CHANNEL;
+        self.update_channel(self.channel);
+        self.channel = if self.channel <= 8 - self.active_channels() {
+            7
+        } else {
+            self.channel - 1
+        };
+    }
+
+    /// The average output of the enabled channels, from -1.0 to 1.0
+    ///
+    /// The chip really plays one channel at a time, switching every 15
+    /// cycles, which averages out to this once filtered.
+    pub fn sample(&self) -> f32 {
+        let active = self.active_channels();
+        let total: i16 = self.outputs[(8 - active as usize)..].iter().sum();
+        total as f32 / (active as f32 * MAX_OUTPUT)
+    }
+}
+
+#[derive(Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Namco163Cartridge {
+    chr: Vec<u8>,
+    prg: Vec<u8>,
+    prg_ram: Vec<u8>,
+    nametable: Vec<u8>,
+    has_battery: bool,
+    /// The banks at $0000-$1FFF, then the ones at $2000-$2FFF
+    chr_banks: [u8; 12],
+    /// The banks at $8000, $A000, and $C000, along with the control bits
+    /// that share their registers
+    prg_banks: [u8; 3],
+    /// The last write to $F800, which protects PRG RAM
+    write_protect: u8,
+    irq_enabled: bool,
+    irq_counter: u16,
+    irq_pending: bool,
+    audio: Namco163Audio,
+}
+
+impl Namco163Cartridge {
+    pub fn new(header: INesHeader, buf: &[u8]) -> Namco163Cartridge {
+        let INesHeader {
+            prg_size,
+            chr_size,
+            flags_6,
+            ..
+        } = header;
+        let prg_end = 16 + 0x4000 * prg_size;
+        let chr_end = prg_end + 0x2000 * chr_size;
+        let mut cart = Namco163Cartridge::from_parts(
+            buf[16..prg_end].to_vec(),
+            buf[prg_end..chr_end].to_vec(),
+        );
+        cart.has_battery = flags_6.contains(INesFlags6::HAS_PERSISTENT_MEMORY);
+        cart
+    }
+
+    /// Build a cartridge straight from its ROMs, without an iNES header
+    ///
+    /// Panics if either ROM isn't a whole number of banks.
+    pub fn from_parts(prg: Vec<u8>, chr: Vec<u8>) -> Namco163Cartridge {
+        assert_rom_size("PRG ROM", &prg, PRG_BANK_SIZE);
+        assert_rom_size("CHR ROM", &chr, CHR_BANK_SIZE);
+        Namco163Cartridge {
+            chr,
+            prg,
+            prg_ram: vec![0u8; PRG_RAM_SIZE],
+            nametable: vec![0u8; 0x800],
+            has_battery: false,
+            chr_banks: [0u8; 12],
+            prg_banks: [0u8; 3],
+            write_protect: 0,
+            irq_enabled: false,
+            irq_counter: 0,
+            irq_pending: false,
+            audio: Namco163Audio::new(),
+        }
+    }
+
+    /// The wavetable audio on this board
+    pub fn audio(&self) -> &Namco163Audio {
+        &self.audio
+    }
+
+    fn sound_enabled(&self) -> bool {
+        self.prg_banks[0] & SOUND_DISABLE == 0
+    }
+
+    /// Work out what a PPU address in $0000-$3EFF is mapped to
+    fn chr_page(&self, addr: u16) -> ChrPage {
+        // $3000-$3EFF mirrors $2000-$2EFF
+        let slot = match addr >> 10 {
+            slot @ 0x0..=0xB => slot,
+            slot => slot - 4,
+        } as usize;
+        let bank = self.chr_banks[slot];
+        let ciram_allowed = match slot {
+            0..=3 => self.prg_banks[1] & LOW_CIRAM_DISABLE == 0,
+            4..=7 => self.prg_banks[1] & HIGH_CIRAM_DISABLE == 0,
+            _ => true,
+        };
+        if bank >= CIRAM_BANK_START && ciram_allowed {
+            ChrPage::Ciram(((bank as usize & 0x01) << 10) | (addr & 0x3FF) as usize)
+        } else {
+            let n_banks = self.chr.len() / CHR_BANK_SIZE;
+            ChrPage::Rom((bank as usize % n_banks) * CHR_BANK_SIZE + (addr & 0x3FF) as usize)
+        }
+    }
+
+    /// Map an 8k PRG bank and an offset into it to an offset into PRG ROM
+    fn prg_addr(&self, bank: u8, offset: u16) -> usize {
+        let n_banks = self.prg.len() / PRG_BANK_SIZE;
+        ((bank & 0x3F) as usize % n_banks) * PRG_BANK_SIZE + offset as usize
+    }
+
+    /// Whether a write to PRG RAM at this offset would go through
+    ///
+    /// $F800 has to be $4x, and each of its low bits protects one 2k quarter.
+    fn prg_ram_writable(&self, addr: u16) -> bool {
+        self.write_protect & 0xF0 == PRG_RAM_WRITE_ENABLE
+            && self.write_protect & (1 << (addr >> 11)) == 0
+    }
+}
+
+impl ICartridge for Namco163Cartridge {
+    fn read_chr(&mut self, addr: u16, last_bus_value: u8) -> u8 {
+        self.peek_chr(addr).unwrap(last_bus_value)
+    }
+
+    fn peek_chr(&self, addr: u16) -> BusPeekResult {
+        match self.chr_page(addr) {
+            ChrPage::Rom(offset) => BusPeekResult::Result(self.chr[offset]),
+            ChrPage::Ciram(offset) => BusPeekResult::Result(self.nametable[offset]),
+        }
+    }
+
+    fn write_chr(&mut self, addr: u16, value: u8) {
+        if let ChrPage::Ciram(offset) = self.chr_page(addr) {
+            self.nametable[offset] = value;
+        }
+    }
+
+    fn read_prg(&mut self, region: PrgRegion, last_bus_value: u8) -> u8 {
+        match region {
+            PrgRegion::Expansion(_) if region.cpu_addr() & 0xF800 == 0x4800 => {
+                self.audio.read_data()
+            }
+            _ => self.peek_prg(region).unwrap(last_bus_value),
+        }
+    }
+
+    fn peek_prg(&self, region: PrgRegion) -> BusPeekResult {
+        match region {
+            PrgRegion::Expansion(_) => match region.cpu_addr() & 0xF800 {
+                0x4800 => BusPeekResult::Result(self.audio.peek_data()),
+                0x5000 => BusPeekResult::Result(self.irq_counter as u8),
+                0x5800 => BusPeekResult::Result(
+                    (self.irq_counter >> 8) as u8 | if self.irq_enabled { 0x80 } else { 0 },
+                ),
+                _ => BusPeekResult::Unmapped,
+            },
+            PrgRegion::Ram(addr) => BusPeekResult::Result(self.prg_ram[addr as usize]),
+            // $8000-$DFFF
+            PrgRegion::Rom(addr @ 0x0000..=0x5FFF) => {
+                let bank = self.prg_banks[(addr >> 13) as usize];
+                BusPeekResult::Result(self.prg[self.prg_addr(bank, addr & 0x1FFF)])
+            }
+            PrgRegion::Rom(addr) => {
+                let last_bank = self.prg.len() - PRG_BANK_SIZE;
+                BusPeekResult::Result(self.prg[last_bank + (addr & 0x1FFF) as usize])
+            }
+        }
+    }
+
+    fn write_prg(&mut self, region: PrgRegion, value: u8) {
+        match region {
+            PrgRegion::Expansion(_) => match region.cpu_addr() & 0xF800 {
+                0x4800 => self.audio.write_data(value),
+                0x5000 => {
+                    self.irq_counter = (self.irq_counter & 0x7F00) | value as u16;
+                    self.irq_pending = false;
+                }
+                0x5800 => {
+                    self.irq_counter = (self.irq_counter & 0x00FF) | ((value as u16 & 0x7F) << 8);
+                    self.irq_enabled = value & 0x80 != 0;
+                    self.irq_pending = false;
+                }
+                _ => {}
+            },
+            PrgRegion::Ram(addr) => {
+                if self.prg_ram_writable(addr) {
+                    self.prg_ram[addr as usize] = value;
+                }
+            }
+            // one register every 2k, starting from $8000
+            PrgRegion::Rom(addr) => match addr >> 11 {
+                reg @ 0x0..=0xB => self.chr_banks[reg as usize] = value,
+                reg @ 0xC..=0xE => self.prg_banks[(reg - 0xC) as usize] = value,
+                _ => {
+                    self.write_protect = value;
+                    self.audio.set_address(value);
+                }
+            },
+        }
+    }
+
+    fn dump_chr(&self) -> &[u8] {
+        &self.chr
+    }
+
+    fn dump_nametables(&self) -> &[u8] {
+        &self.nametable
+    }
+
+    fn debug_state(&self) -> CartridgeState {
+        CartridgeState::Namco163(self.clone())
+    }
+
+    fn clock_cpu(&mut self) {
+        if self.sound_enabled() {
+            self.audio.clock();
+        }
+        if !self.irq_enabled || self.irq_counter == 0x7FFF {
+            return;
+        }
+        self.irq_counter += 1;
+        if self.irq_counter == 0x7FFF {
+            self.irq_pending = true;
+        }
+    }
+
+    fn irq_pending(&self) -> bool {
+        self.irq_pending
+    }
+
+    fn audio_sample(&self) -> f32 {
+        self.audio.sample()
+    }
+
+    fn power_cycle(&mut self) {
+        // PRG RAM is left alone, since it may be battery-backed
+        self.nametable.fill(0);
+        self.chr_banks = [0u8; 12];
+        self.prg_banks = [0u8; 3];
+        self.write_protect = 0;
+        self.irq_enabled = false;
+        self.irq_counter = 0;
+        self.irq_pending = false;
+        self.audio = Namco163Audio::new();
+    }
+
+    fn save_data(&self) -> Option<&[u8]> {
+        if self.has_battery {
+            Some(&self.prg_ram)
+        } else {
+            None
+        }
+    }
+
+    fn load_save_data(&mut self, data: &[u8]) {
+        if self.has_battery && data.len() == PRG_RAM_SIZE {
+            self.prg_ram.copy_from_slice(data);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Build a 128k PRG, 64k CHR ROM where every byte of a bank is its number
+    fn make_cart() -> Namco163Cartridge {
+        Namco163Cartridge::from_parts(
+            (0..0x20000).map(|i| (i / PRG_BANK_SIZE) as u8).collect(),
+            (0..0x10000).map(|i| (i / CHR_BANK_SIZE) as u8).collect(),
+        )
+    }
+
+    fn write(cart: &mut Namco163Cartridge, addr: u16, value: u8) {
+        cart.write_prg(PrgRegion::from_cpu_addr(addr), value);
+    }
+
+    fn peek_prg(cart: &Namco163Cartridge, addr: u16) -> BusPeekResult {
+        cart.peek_prg(PrgRegion::from_cpu_addr(addr))
+    }
+
+    #[test]
+    fn switches_prg_banks() {
+        let mut cart = make_cart();
+        write(&mut cart, 0xE000, 3);
+        write(&mut cart, 0xE800, 7);
+        // the top bits are control bits, and bank numbers past the end of PRG
+        // wrap around
+        write(&mut cart, 0xF000, 0xC0 | 0x15);
+        assert_eq!(peek_prg(&cart, 0x8000), BusPeekResult::Result(3));
+        assert_eq!(peek_prg(&cart, 0xBFFF), BusPeekResult::Result(7));
+        assert_eq!(peek_prg(&cart, 0xC000), BusPeekResult::Result(5));
+        // the last bank is fixed
+        assert_eq!(peek_prg(&cart, 0xE000), BusPeekResult::Result(15));
+        assert_eq!(peek_prg(&cart, 0xFFFF), BusPeekResult::Result(15));
+    }
+
+    #[test]
+    fn write_protects_prg_ram() {
+        let mut cart = make_cart();
+        write(&mut cart, 0x6000, 0xAB);
+        assert_eq!(peek_prg(&cart, 0x6000), BusPeekResult::Result(0x00));
+        // writable, except for $6800-$6FFF
+        write(&mut cart, 0xF800, PRG_RAM_WRITE_ENABLE | 0x02);
+        write(&mut cart, 0x6000, 0xAB);
+        write(&mut cart, 0x6800, 0xCD);
+        write(&mut cart, 0x7FFF, 0xEF);
+        assert_eq!(peek_prg(&cart, 0x6000), BusPeekResult::Result(0xAB));
+        assert_eq!(peek_prg(&cart, 0x6800), BusPeekResult::Result(0x00));
+        assert_eq!(peek_prg(&cart, 0x7FFF), BusPeekResult::Result(0xEF));
+    }
+
+    #[test]
+    fn switches_1k_chr_banks() {
+        let mut cart = make_cart();
+        for i in 0..8u16 {
+            write(&mut cart, 0x8000 + i * 0x800, 60 - i as u8);
+        }
+        for i in 0..8u16 {
+            assert_eq!(
+                cart.peek_chr(i * 0x400 + 0x3FF),
+                BusPeekResult::Result(60 - i as u8)
+            );
+        }
+    }
+
+    #[test]
+    fn maps_ciram_or_chr_rom_into_the_nametables() {
+        let mut cart = make_cart();
+        // a vertical arrangement from CIRAM, except $2C00 comes from CHR ROM
+        write(&mut cart, 0xC000, 0xE0);
+        write(&mut cart, 0xC800, 0xE1);
+        write(&mut cart, 0xD000, 0xE0);
+        write(&mut cart, 0xD800, 0x2A);
+        cart.write_chr(0x2000, 1);
+        cart.write_chr(0x2400, 2);
+        cart.write_chr(0x2C00, 3);
+        assert_eq!(cart.peek_chr(0x2800), BusPeekResult::Result(1));
+        assert_eq!(cart.peek_chr(0x2400), BusPeekResult::Result(2));
+        assert_eq!(cart.peek_chr(0x2C00), BusPeekResult::Result(0x2A));
+        assert_eq!(cart.peek_chr(0x3C00), BusPeekResult::Result(0x2A));
+        assert_eq!(&cart.dump_nametables()[..2], &[1, 0]);
+        assert_eq!(cart.dump_nametables()[0x400], 2);
+    }
+
+    #[test]
+    fn maps_ciram_into_the_pattern_tables_unless_disabled() {
+        let mut cart = make_cart();
+        write(&mut cart, 0x8000, 0xE1);
+        write(&mut cart, 0xA000, 0xE0);
+        cart.write_chr(0x0005, 0x11);
+        cart.write_chr(0x1005, 0x22);
+        assert_eq!(cart.dump_nametables()[0x405], 0x11);
+        assert_eq!(cart.dump_nametables()[0x005], 0x22);
+        // CHR ROM at $0000-$0FFF, so bank $E1 wraps around to bank $21
+        write(&mut cart, 0xE800, LOW_CIRAM_DISABLE);
+        assert_eq!(cart.peek_chr(0x0005), BusPeekResult::Result(0x21));
+        assert_eq!(cart.peek_chr(0x1005), BusPeekResult::Result(0x22));
+        write(&mut cart, 0xE800, HIGH_CIRAM_DISABLE);
+        assert_eq!(cart.peek_chr(0x0005), BusPeekResult::Result(0x11));
+        assert_eq!(cart.peek_chr(0x1005), BusPeekResult::Result(0x20));
+        // writes to CHR ROM are dropped
+        cart.write_chr(0x1005, 0x33);
+        assert_eq!(cart.peek_chr(0x1005), BusPeekResult::Result(0x20));
+    }
+
+    #[test]
+    fn raises_an_irq_when_the_counter_reaches_7fff() {
+        let mut cart = make_cart();
+        write(&mut cart, 0x5000, 0xFD);
+        write(&mut cart, 0x5800, 0x7F);
+        // disabled, so it doesn't count
+        cart.clock_cpu();
+        assert_eq!(peek_prg(&cart, 0x5000), BusPeekResult::Result(0xFD));
+        write(&mut cart, 0x5800, 0x80 | 0x7F);
+        assert_eq!(peek_prg(&cart, 0x5800), BusPeekResult::Result(0xFF));
+        cart.clock_cpu();
+        assert!(!cart.irq_pending());
+        cart.clock_cpu();
+        assert!(cart.irq_pending());
+        // the counter stops at $7FFF, and the IRQ stays up until acknowledged
+        cart.clock_cpu();
+        assert_eq!(cart.irq_counter, 0x7FFF);
+        assert!(cart.irq_pending());
+        write(&mut cart, 0x5000, 0x00);
+        assert!(!cart.irq_pending());
+    }
+
+    #[test]
+    fn auto_increments_the_sound_address() {
+        let mut cart = make_cart();
+        write(&mut cart, 0xF800, SOUND_AUTO_INCREMENT | 0x7E);
+        write(&mut cart, 0x4800, 0x12);
+        write(&mut cart, 0x4800, 0x34);
+        // wraps around to $00
+        write(&mut cart, 0x4800, 0x56);
+        assert_eq!(&cart.audio().ram()[0x7E..], &[0x12, 0x34]);
+        assert_eq!(cart.audio().ram()[0x00], 0x56);
+        write(&mut cart, 0xF800, 0x7E);
+        assert_eq!(cart.read_prg(PrgRegion::from_cpu_addr(0x4800), 0), 0x12);
+        assert_eq!(cart.read_prg(PrgRegion::from_cpu_addr(0x4800), 0), 0x12);
+        write(&mut cart, 0xF800, SOUND_AUTO_INCREMENT | 0x7E);
+        assert_eq!(cart.read_prg(PrgRegion::from_cpu_addr(0x4800), 0), 0x12);
+        assert_eq!(cart.read_prg(PrgRegion::from_cpu_addr(0x4800), 0), 0x34);
+    }
+
+    /// Set up channel 7 to play a 4-sample wave, one sample per update
+    fn play_square_wave(cart: &mut Namco163Cartridge) {
+        write(cart, 0xF800, SOUND_AUTO_INCREMENT);
+        // samples F, F, 0, 0
+        write(cart, 0x4800, 0xFF);
+        write(cart, 0x4800, 0x00);
+        write(cart, 0xF800, SOUND_AUTO_INCREMENT | 0x78);
+        // frequency $10000, phase 0, length 4, wave at 0, volume 15
+        for &value in &[0x00, 0x00, 0x00, 0x00, 0xFC | 0x01, 0x00, 0x00, 0x0F] {
+            write(cart, 0x4800, value);
+        }
+    }
+
+    #[test]
+    fn plays_the_wavetable() {
+        let mut cart = make_cart();
+        play_square_wave(&mut cart);
+        assert_eq!(cart.audio().sample(), 0.0);
+        let mut samples = Vec::new();
+        for _ in 0..4 {
+            for _ in 0..CYCLES_PER_CHANNEL {
+                cart.clock_cpu();
+            }
+            samples.push(cart.audio().sample());
+        }
+        // the phase is stepped before the sample is read
+        let high = 7.0 * 15.0 / MAX_OUTPUT;
+        assert_eq!(samples, vec![high, -1.0, -1.0, high]);
+        assert_eq!(cart.audio().ram()[0x7D], 0x00, "Phase didn't wrap");
+    }
+
+    #[test]
+    fn shares_updates_between_active_channels() {
+        let mut cart = make_cart();
+        play_square_wave(&mut cart);
+        // two channels on, with channel 6 silent
+        write(&mut cart, 0xF800, 0x7F);
+        write(&mut cart, 0x4800, 0x10 | 0x0F);
+        for _ in 0..CYCLES_PER_CHANNEL {
+            cart.clock_cpu();
+        }
+        let high = 7.0 * 15.0 / MAX_OUTPUT / 2.0;
+        assert_eq!(cart.audio().sample(), high);
+        // channel 6's turn, so channel 7 doesn't move
+        for _ in 0..CYCLES_PER_CHANNEL {
+            cart.clock_cpu();
+        }
+        assert_eq!(cart.audio().sample(), high);
+        for _ in 0..CYCLES_PER_CHANNEL {
+            cart.clock_cpu();
+        }
+        assert_eq!(cart.audio().sample(), -0.5);
+    }
+
+    #[test]
+    fn silences_audio_when_disabled() {
+        let mut cart = make_cart();
+        play_square_wave(&mut cart);
+        write(&mut cart, 0xE000, SOUND_DISABLE);
+        for _ in 0..4 * CYCLES_PER_CHANNEL {
+            cart.clock_cpu();
+        }
+        assert_eq!(cart.audio().sample(), 0.0);
+    }
+
+    #[test]
+    fn only_saves_with_a_battery() {
+        let mut cart = make_cart();
+        assert!(cart.save_data().is_none());
+        cart.has_battery = true;
+        let mut save = vec![0u8; PRG_RAM_SIZE];
+        save[0x40] = 0x5A;
+        cart.load_save_data(&save);
+        assert_eq!(peek_prg(&cart, 0x6040), BusPeekResult::Result(0x5A));
+        assert_eq!(cart.save_data().unwrap()[0x40], 0x5A);
+        // the wrong size is ignored
+        cart.load_save_data(&[0u8; 128]);
+        assert_eq!(cart.save_data().unwrap()[0x40], 0x5A);
+    }
+}
@@ -1,12 +1,21 @@
-use super::ines::{INesFlags6, INesHeader};
-use super::utils::ICartridge;
+use alloc::{vec, vec::Vec};
+
+use super::ines::INesHeader;
+use super::utils::{
+    hardwired_nametable_addr, CartridgeState, ICartridge, NametableArrangement, PrgRegion,
+    DEFAULT_PRG_RAM_SIZE,
+};
 use crate::devices::bus::BusPeekResult;
 
+#[derive(Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct NROMCartridge {
     chr: Vec<u8>,
     prg: Vec<u8>,
+    /// RAM at $6000-$7FFF, like on the Family BASIC cartridge
+    prg_ram: Vec<u8>,
     nametable: Vec<u8>,
-    use_horizontal_mirroring: bool,
+    arrangement: NametableArrangement,
     is_16k: bool,
 }
 
@@ -16,16 +25,34 @@ impl NROMCartridge {
             prg_size, flags_6, ..
         } = header;
         let prg_end = 16 + 0x4000 * prg_size;
-        let mut prg_buffer = vec![0u8; 0x4000 * prg_size];
-        prg_buffer.clone_from_slice(&buf[16..prg_end]);
-        let mut chr_buffer = vec![0u8; 0x2000];
-        chr_buffer.clone_from_slice(&buf[prg_end..(prg_end + 0x2000)]);
+        NROMCartridge::from_parts(
+            buf[16..prg_end].to_vec(),
+            buf[prg_end..(prg_end + 0x2000)].to_vec(),
+            NametableArrangement::from_flags(flags_6),
+        )
+    }
+
+    /// Build a cartridge straight from its ROMs, without an iNES header
+    ///
+    /// Panics if `prg` isn't 16k or 32k, or `chr` isn't 8k.
+    pub fn from_parts(
+        prg: Vec<u8>,
+        chr: Vec<u8>,
+        arrangement: NametableArrangement,
+    ) -> NROMCartridge {
+        assert!(
+            prg.len() == 0x4000 || prg.len() == 0x8000,
+            "NROM PRG ROM must be 16k or 32k, not {} bytes",
+            prg.len()
+        );
+        assert_eq!(chr.len(), 0x2000, "NROM CHR ROM must be 8k");
         NROMCartridge {
-            chr: chr_buffer,
-            prg: prg_buffer,
-            nametable: vec![0u8; 0x800],
-            use_horizontal_mirroring: !flags_6.contains(INesFlags6::MIRRORING),
-            is_16k: prg_size == 1,
+            is_16k: prg.len() == 0x4000,
+            chr,
+            prg,
+            prg_ram: vec![0u8; DEFAULT_PRG_RAM_SIZE],
+            nametable: vec![0u8; arrangement.vram_size()],
+            arrangement,
         }
     }
 }
@@ -39,57 +66,56 @@ impl ICartridge for NROMCartridge {
         if addr < 0x2000 {
             return BusPeekResult::Result(self.chr[addr as usize]);
         }
-        let nt_addr = addr - 0x2000;
-        let nt_addr = if self.use_horizontal_mirroring {
-            // horizontal mirroring is done by wiring address pin 11 to
-            // CIRAM 10, meaning bit 11 is moved to where bit 10 is and
-            // the old bit 10 is dropped into the shadow realm
-            (nt_addr & 0x3FF) | ((0x800 & addr) >> 1)
-        } else {
-            nt_addr & 0x7FF
-        };
-        return BusPeekResult::Result(self.nametable[nt_addr as usize]);
+        let nt_addr = hardwired_nametable_addr(addr, self.arrangement);
+        return BusPeekResult::Result(self.nametable[nt_addr]);
     }
 
     fn write_chr(&mut self, addr: u16, value: u8) {
         if addr < 0x2000 {
             return; // no-op: this is a ROM
         }
-        let nt_addr = addr - 0x2000;
-        let nt_addr = if self.use_horizontal_mirroring {
-            (nt_addr & 0x3FF) | ((0x800 & addr) >> 1)
-        } else {
-            nt_addr & 0x7FF
-        };
-        self.nametable[nt_addr as usize] = value;
-    }
-
-    fn read_prg(&mut self, addr: u16, last_bus_value: u8) -> u8 {
-        self.peek_prg(addr).unwrap(last_bus_value)
-    }
-
-    fn peek_prg(&self, addr: u16) -> crate::devices::bus::BusPeekResult {
-        // 0x3FE0 is 0x8000 - CART_START_ADDR, since NROM starts at $8000
-        BusPeekResult::Result(
-            self.prg[if self.is_16k {
-                (addr - 0x3FE0) & 0x3FFF
-            } else {
-                addr - 0x3FE0
-            } as usize],
-        )
+        let nt_addr = hardwired_nametable_addr(addr, self.arrangement);
+        self.nametable[nt_addr] = value;
     }
 
-    fn write_prg(&mut self, _addr: u16, _value: u8) {
-        return; // no-op: NROM PRG is read-only
+    fn read_prg(&mut self, region: PrgRegion, last_bus_value: u8) -> u8 {
+        self.peek_prg(region).unwrap(last_bus_value)
+    }
+
+    fn peek_prg(&self, region: PrgRegion) -> BusPeekResult {
+        match region {
+            PrgRegion::Expansion(_) => BusPeekResult::Unmapped,
+            PrgRegion::Ram(addr) => BusPeekResult::Result(self.prg_ram[addr as usize]),
+            PrgRegion::Rom(addr) => BusPeekResult::Result(
+                // 16k ROMs are mirrored into both halves
+                self.prg[if self.is_16k { addr & 0x3FFF } else { addr } as usize],
+            ),
+        }
+    }
+
+    fn write_prg(&mut self, region: PrgRegion, value: u8) {
+        // ROM writes are a no-op, since it's a ROM
+        if let PrgRegion::Ram(addr) = region {
+            self.prg_ram[addr as usize] = value;
+        }
     }
 
     fn dump_chr(&self) -> &[u8] {
         return &self.chr;
     }
 
+    fn debug_state(&self) -> CartridgeState {
+        CartridgeState::NROM(self.clone())
+    }
+
     fn dump_nametables(&self) -> &[u8] {
         return &self.nametable;
     }
+
+    fn power_cycle(&mut self) {
+        self.nametable.fill(0);
+        self.prg_ram.fill(0);
+    }
 }
 
 #[cfg(test)]
@@ -101,9 +127,6 @@ mod tests {
     use std::path::Path;
 
     const NESTEST_PATH: &str = "./tests/data/nestest.nes";
-    const NROM_OFFSET: u16 = 0x3FE0;
-    // it's convenient to test in global addresses, but the carts use local addrs
-    const GLOBAL_ADDR_OFFSET: u16 = 0x4020;
 
     fn read_nestest() -> NROMCartridge {
         let path = Path::new(&NESTEST_PATH);
@@ -121,7 +144,7 @@ mod tests {
     #[test]
     fn should_map_prg_reads() {
         let cart = read_nestest();
-        let data = cart.peek_prg(0xC000 - GLOBAL_ADDR_OFFSET).unwrap(0);
+        let data = cart.peek_prg(PrgRegion::from_cpu_addr(0xC000)).unwrap(0);
         // 0x4C is what we expect to be at this location in PRG, and can be
         // verified in xxd
         assert_eq!(data, 0x4C);
@@ -133,12 +156,34 @@ mod tests {
 
         // $3FFF and $7FFF should be mirrors in 16k PRGs like NESTEST
         // In full address space, these addresses map to the reset vector
-        let left = cart.peek_prg(0x3FFF + NROM_OFFSET).unwrap(0);
-        let right = cart.peek_prg(0x7FFF + NROM_OFFSET).unwrap(0);
+        let left = cart.peek_prg(PrgRegion::Rom(0x3FFF)).unwrap(0);
+        let right = cart.peek_prg(PrgRegion::Rom(0x7FFF)).unwrap(0);
         assert_eq!(left, 0xC5, "Initial address doesn't match expected result");
         assert_eq!(left, right, "Mirrors don't align");
     }
 
+    #[test]
+    fn has_prg_ram() {
+        let mut cart = read_nestest();
+        cart.write_prg(PrgRegion::Ram(0x0000), 0x12);
+        cart.write_prg(PrgRegion::Ram(0x1FFF), 0x34);
+        assert_eq!(cart.read_prg(PrgRegion::Ram(0x0000), 0xFF), 0x12);
+        assert_eq!(
+            cart.peek_prg(PrgRegion::Ram(0x1FFF)),
+            BusPeekResult::Result(0x34)
+        );
+        // and nothing is mapped below it
+        assert_eq!(
+            cart.peek_prg(PrgRegion::Expansion(0x1FDF)),
+            BusPeekResult::Unmapped
+        );
+        cart.power_cycle();
+        assert_eq!(
+            cart.peek_prg(PrgRegion::Ram(0x0000)),
+            BusPeekResult::Result(0x00)
+        );
+    }
+
     #[test]
     fn should_read_chr_correctly() {
         let cart = read_nestest();
@@ -147,4 +192,29 @@ mod tests {
         // $0020 should be 0x80, which can be verified by looking in xxd
         assert_eq!(data, 0x80);
     }
+
+    #[test]
+    fn builds_from_parts() {
+        let mut prg = vec![0u8; 0x8000];
+        prg[0x0000] = 0x12;
+        prg[0x7FFF] = 0x34;
+        let mut cart =
+            NROMCartridge::from_parts(prg, vec![0x56; 0x2000], NametableArrangement::Vertical);
+        // 32k ROMs aren't mirrored
+        assert_eq!(
+            cart.peek_prg(PrgRegion::from_cpu_addr(0x8000)).unwrap(0),
+            0x12
+        );
+        assert_eq!(
+            cart.peek_prg(PrgRegion::from_cpu_addr(0xC000)).unwrap(0),
+            0x00
+        );
+        assert_eq!(
+            cart.peek_prg(PrgRegion::from_cpu_addr(0xFFFF)).unwrap(0),
+            0x34
+        );
+        assert_eq!(cart.peek_chr(0x1FFF).unwrap(0), 0x56);
+        cart.write_chr(0x2000, 0x78);
+        assert_eq!(cart.peek_chr(0x2800).unwrap(0), 0x78);
+    }
 }
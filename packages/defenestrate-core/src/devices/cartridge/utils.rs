@@ -1,32 +1,300 @@
+use alloc::boxed::Box;
+
+use super::bandai::BandaiFCGCartridge;
+use super::fme7::FME7Cartridge;
+use super::ines::INesFlags6;
+use super::latch::LatchCartridge;
+use super::namco163::Namco163Cartridge;
+use super::nrom::NROMCartridge;
 use crate::devices::bus::BusPeekResult;
+use crate::devices::fds::FdsAdapter;
+
+/// Where the cartridge's space on the CPU bus starts
+const CART_START_ADDR: u16 = 0x4020;
+const PRG_RAM_START_ADDR: u16 = 0x6000;
+const PRG_ROM_START_ADDR: u16 = 0x8000;
+
+/// How much PRG RAM boards get when nothing says otherwise
+///
+/// iNES 1.0 headers don't have a reliable field for this, so 8k is what most
+/// emulators assume.
+pub(crate) const DEFAULT_PRG_RAM_SIZE: usize = 0x2000;
+
+/// A part of the cartridge's space on the CPU bus, and an offset into it
+///
+/// The console hands everything from $4020 up to the cartridge, but boards
+/// split it up the same few ways, so mappers get the split already done
+/// instead of working out offsets from $4020 themselves.
+///
+/// cf. https://wiki.nesdev.com/w/index.php/CPU_memory_map
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum PrgRegion {
+    /// $4020-$5FFF, which most boards leave unmapped
+    ///
+    /// Boards with more than a bank switch or two put their registers and
+    /// extra RAM here, like MMC5, the Namco 163, and the FDS RAM adapter.
+    Expansion(u16),
+    /// $6000-$7FFF, where boards usually put their PRG RAM
+    Ram(u16),
+    /// $8000-$FFFF, where boards usually put their PRG ROM
+    Rom(u16),
+}
+
+impl PrgRegion {
+    /// Split up an address on the CPU bus
+    ///
+    /// # Panics
+    ///
+    /// This panics if `addr` is below $4020, which isn't the cartridge's.
+    pub fn from_cpu_addr(addr: u16) -> PrgRegion {
+        match addr {
+            PRG_ROM_START_ADDR..=0xFFFF => PrgRegion::Rom(addr - PRG_ROM_START_ADDR),
+            PRG_RAM_START_ADDR..=0x7FFF => PrgRegion::Ram(addr - PRG_RAM_START_ADDR),
+            CART_START_ADDR..=0x5FFF => PrgRegion::Expansion(addr - CART_START_ADDR),
+            _ => panic!("${:04X} isn't in the cartridge's address space", addr),
+        }
+    }
+
+    /// The address on the CPU bus this is
+    pub fn cpu_addr(self) -> u16 {
+        match self {
+            PrgRegion::Expansion(offset) => CART_START_ADDR + offset,
+            PrgRegion::Ram(offset) => PRG_RAM_START_ADDR + offset,
+            PrgRegion::Rom(offset) => PRG_ROM_START_ADDR + offset,
+        }
+    }
+}
 
 /// Trait for a cartridge device
 ///
 /// Cartridges are attached to _both_ the PPU and CPU address busses, and thus
-/// can't really use the IBusDevice interface
-pub trait ICartridge {
+/// can't really use the IBusDevice interface. PRG accesses come split up by
+/// `PrgRegion`, and CHR accesses use PPU addresses.
+///
+/// Cartridges must be `Send`, so that a `Nes` can be moved to another thread.
+/// Mappers that need shared state should use thread-safe types for it.
+///
+/// A cartridge that wraps another, like one handed to `Nes::with_cart`, has to
+/// forward every method here, including the ones with default bodies. A
+/// wrapper that leaves out `clock_cpu` and `irq_pending` quietly stops IRQ
+/// counters like the FME-7's, and one that leaves out `reset`, `save_data` or
+/// `fds_mut` loses resets, battery saves, and disk swaps.
+pub trait ICartridge: Send {
     fn read_chr(&mut self, addr: u16, last_bus_value: u8) -> u8;
 
     fn peek_chr(&self, addr: u16) -> BusPeekResult;
 
     fn write_chr(&mut self, addr: u16, value: u8);
 
-    fn read_prg(&mut self, addr: u16, last_bus_value: u8) -> u8;
+    fn read_prg(&mut self, region: PrgRegion, last_bus_value: u8) -> u8;
 
-    fn peek_prg(&self, addr: u16) -> BusPeekResult;
+    fn peek_prg(&self, region: PrgRegion) -> BusPeekResult;
 
-    fn write_prg(&mut self, addr: u16, value: u8);
+    fn write_prg(&mut self, region: PrgRegion, value: u8);
 
     fn dump_chr(&self) -> &[u8];
 
     fn dump_nametables(&self) -> &[u8];
+
+    /// Copy out the state of this cartridge, for debugging
+    fn debug_state(&self) -> CartridgeState;
+
+    /// Handle the console's reset button
+    ///
+    /// Most boards don't see the reset line at all, so by default this does
+    /// nothing.
+    fn reset(&mut self) {}
+
+    /// Clock the board once per CPU cycle
+    ///
+    /// This is for boards that count CPU cycles, like the FME-7's IRQ counter.
+    /// Everything else can ignore it.
+    fn clock_cpu(&mut self) {}
+
+    /// Whether the board is asserting the CPU's IRQ line
+    fn irq_pending(&self) -> bool {
+        false
+    }
+
+    /// Whether the board counts edges on PPU A12, like the MMC3's scanline
+    /// counter
+    ///
+    /// The PPU's batch renderer fetches a whole line of background tiles on
+    /// dot 1, which would bunch those edges together, so it stays off for
+    /// boards that say yes here.
+    fn watches_ppu_a12(&self) -> bool {
+        false
+    }
+
+    /// The output of the board's expansion audio chip, from -1.0 to 1.0
+    ///
+    /// The console mixes this in with the APU's channels. Boards without a
+    /// sound chip are silent.
+    fn audio_sample(&self) -> f32 {
+        0.0
+    }
+
+    /// Get the Famicom Disk System's RAM adapter, if this is one
+    ///
+    /// This is how the disk in the drive gets changed.
+    fn fds_mut(&mut self) -> Option<&mut FdsAdapter> {
+        None
+    }
+
+    /// Return the board to its power-on state
+    fn power_cycle(&mut self) {
+        self.reset();
+    }
+
+    /// The board's battery-backed RAM or EEPROM, if it has any
+    ///
+    /// This is what a frontend should keep between sessions, and hand back
+    /// with `load_save_data`.
+    fn save_data(&self) -> Option<&[u8]> {
+        None
+    }
+
+    /// Restore the memory from `save_data`
+    ///
+    /// Data that isn't the same size as `save_data` is ignored, as is
+    /// everything on boards with nothing to save.
+    fn load_save_data(&mut self, _data: &[u8]) {}
+}
+
+/// The state of a cartridge, by board type
+#[derive(Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum CartridgeState {
+    NROM(NROMCartridge),
+    FME7(FME7Cartridge),
+    Latch(LatchCartridge),
+    FDS(Box<FdsAdapter>),
+    BandaiFCG(BandaiFCGCartridge),
+    Namco163(Namco163Cartridge),
+}
+
+impl CartridgeState {
+    /// Put the copied cartridge back in a box, to plug into the console
+    pub(crate) fn into_cartridge(self) -> Box<dyn ICartridge> {
+        match self {
+            CartridgeState::NROM(cart) => Box::new(cart),
+            CartridgeState::FME7(cart) => Box::new(cart),
+            CartridgeState::Latch(cart) => Box::new(cart),
+            CartridgeState::FDS(adapter) => adapter,
+            CartridgeState::BandaiFCG(cart) => Box::new(cart),
+            CartridgeState::Namco163(cart) => Box::new(cart),
+        }
+    }
+}
+
+/// The nametable layouts that boards with mapper-controlled mirroring select
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub(crate) enum Mirroring {
+    Vertical,
+    Horizontal,
+    SingleScreenLow,
+    SingleScreenHigh,
+}
+
+impl Mirroring {
+    /// Map a PPU address in $2000-$3EFF to an offset into 2k of nametables
+    pub fn nametable_addr(self, addr: u16) -> usize {
+        let table = (addr >> 10) & 0x03;
+        let bank = match self {
+            Mirroring::Vertical => table & 0x01,
+            Mirroring::Horizontal => table >> 1,
+            Mirroring::SingleScreenLow => 0,
+            Mirroring::SingleScreenHigh => 1,
+        };
+        ((bank << 10) | (addr & 0x3FF)) as usize
+    }
+}
+
+/// How the nametables are laid out on a board with mirroring set by solder
+/// pads
+///
+/// The console only has 2k of VRAM, which is enough for two nametables, so
+/// most boards mirror them one way or the other. A few (like Gauntlet) bring
+/// another 2k of VRAM along and get all four.
+///
+/// cf. https://wiki.nesdev.com/w/index.php/Mirroring#Nametable_Mirroring
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum NametableArrangement {
+    Horizontal,
+    Vertical,
+    FourScreen,
+}
+
+impl NametableArrangement {
+    /// Get the arrangement from the mirroring and four-screen bits of an
+    /// iNES header
+    pub(crate) fn from_flags(flags_6: INesFlags6) -> NametableArrangement {
+        if flags_6.contains(INesFlags6::USE_FOUR_SCREEN_VRAM) {
+            NametableArrangement::FourScreen
+        } else if flags_6.contains(INesFlags6::MIRRORING) {
+            NametableArrangement::Vertical
+        } else {
+            NametableArrangement::Horizontal
+        }
+    }
+
+    /// The amount of VRAM the nametables take up, including any on the cart
+    pub(crate) fn vram_size(self) -> usize {
+        match self {
+            NametableArrangement::FourScreen => 0x1000,
+            _ => 0x800,
+        }
+    }
+}
+
+/// Map a PPU address in $2000-$3EFF to an offset into the nametables, for
+/// boards with mirroring set by solder pads
+pub fn hardwired_nametable_addr(addr: u16, arrangement: NametableArrangement) -> usize {
+    let nt_addr = addr - 0x2000;
+    let nt_addr = match arrangement {
+        // horizontal mirroring is done by wiring address pin 11 to
+        // CIRAM 10, meaning bit 11 is moved to where bit 10 is and
+        // the old bit 10 is dropped into the shadow realm
+        NametableArrangement::Horizontal => (nt_addr & 0x3FF) | ((0x800 & addr) >> 1),
+        NametableArrangement::Vertical => nt_addr & 0x7FF,
+        // the cart's VRAM covers $2800-$2FFF, so nothing is mirrored
+        NametableArrangement::FourScreen => nt_addr & 0xFFF,
+    };
+    nt_addr as usize
+}
+
+/// Check that a ROM handed to a `from_parts` constructor is a whole number of
+/// `bank_size` banks
+///
+/// Panics if it isn't, naming the ROM as `name`.
+pub(crate) fn assert_rom_size(name: &str, rom: &[u8], bank_size: usize) {
+    assert!(
+        !rom.is_empty() && rom.len().checked_rem(bank_size) == Some(0),
+        "{} must be a multiple of {}k, not {} bytes",
+        name,
+        bank_size / 0x400,
+        rom.len()
+    );
 }
 
-/// A trait for devices that own a Cartridge
-pub trait WithCartridge {
-    /// Get a reference to a cartridge
-    fn cart(&self) -> &Box<dyn ICartridge>;
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    /// Get a mutable reference to a cartridge
-    fn cart_mut(&mut self) -> &mut Box<dyn ICartridge>;
+    #[test]
+    fn splits_up_the_cartridge_space() {
+        for (addr, region) in [
+            (0x4020, PrgRegion::Expansion(0x0000)),
+            (0x5FFF, PrgRegion::Expansion(0x1FDF)),
+            (0x6000, PrgRegion::Ram(0x0000)),
+            (0x7FFF, PrgRegion::Ram(0x1FFF)),
+            (0x8000, PrgRegion::Rom(0x0000)),
+            (0xFFFF, PrgRegion::Rom(0x7FFF)),
+        ] {
+            assert_eq!(PrgRegion::from_cpu_addr(addr), region);
+            assert_eq!(region.cpu_addr(), addr);
+        }
+    }
 }
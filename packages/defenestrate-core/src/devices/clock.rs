@@ -0,0 +1,97 @@
+//! The console's master clock, and the clocks divided down from it
+//!
+//! Every chip in the console runs off the same crystal, through dividers. The
+//! `Nes` counts time in PPU cycles, the fastest clock anything here runs at,
+//! and the CPU and APU clocks are views of that count. The count is 64-bit,
+//! so it won't wrap even after centuries of emulated time, which keeps
+//! timestamps from different points in a long session comparable.
+//!
+//! cf. https://wiki.nesdev.com/w/index.php/Cycle_reference_chart
+
+/// PPU cycles per CPU cycle, on NTSC consoles
+pub const CPU_DIVIDER: u64 = 3;
+
+/// PPU cycles per APU cycle
+///
+/// Most of the APU is clocked on every other CPU cycle.
+pub const APU_DIVIDER: u64 = CPU_DIVIDER * 2;
+
+/// A count of PPU cycles since power-on
+#[derive(Debug, Default, Eq, PartialEq, Ord, PartialOrd, Hash, Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct MasterClock {
+    ticks: u64,
+}
+
+impl MasterClock {
+    pub fn new() -> MasterClock {
+        MasterClock::default()
+    }
+
+    /// A time `ticks` PPU cycles after power-on
+    pub fn from_ppu_cycles(ticks: u64) -> MasterClock {
+        MasterClock { ticks }
+    }
+
+    /// Advance the clock by one PPU cycle
+    pub fn tick(&mut self) {
+        self.ticks += 1;
+    }
+
+    /// The number of PPU cycles since power-on
+    pub fn ppu_cycles(self) -> u64 {
+        self.ticks
+    }
+
+    /// The number of whole CPU cycles since power-on
+    pub fn cpu_cycles(self) -> u64 {
+        self.ticks / CPU_DIVIDER
+    }
+
+    /// The number of whole APU cycles since power-on
+    pub fn apu_cycles(self) -> u64 {
+        self.ticks / APU_DIVIDER
+    }
+
+    /// Whether the CPU gets clocked on this PPU cycle
+    pub fn is_cpu_cycle(self) -> bool {
+        self.ticks % CPU_DIVIDER == 0
+    }
+
+    /// The number of PPU cycles from `earlier` to this time
+    pub fn since(self, earlier: MasterClock) -> u64 {
+        self.ticks.saturating_sub(earlier.ticks)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn divides_down_to_the_cpu_and_apu() {
+        let mut clock = MasterClock::new();
+        let mut cpu_ticks = 0;
+        for _ in 0..60 {
+            clock.tick();
+            if clock.is_cpu_cycle() {
+                cpu_ticks += 1;
+            }
+        }
+        assert_eq!(clock.ppu_cycles(), 60);
+        assert_eq!(clock.cpu_cycles(), 20);
+        assert_eq!(cpu_ticks, 20);
+        assert_eq!(clock.apu_cycles(), 10);
+    }
+
+    #[test]
+    fn counts_past_32_bits() {
+        let start = MasterClock::from_ppu_cycles(u32::MAX as u64);
+        let mut clock = start;
+        clock.tick();
+        assert_eq!(clock.ppu_cycles(), 1 << 32);
+        assert!(clock > start);
+        assert_eq!(clock.since(start), 1);
+        assert_eq!(start.since(clock), 0);
+    }
+}
@@ -0,0 +1,442 @@
+//! Module for the controller ports at $4016 and $4017
+//!
+//! Writing bit 0 of $4016 sets the strobe line on both ports. While the strobe
+//! is high, a standard controller continuously reloads its shift register
+//! with the state of its buttons, so every read returns the A button. Once
+//! the strobe goes low the buttons stay latched, and each read of $4016 (for
+//! port 1) or $4017 (for port 2) shifts out the next one, in the order A, B,
+//! Select, Start, Up, Down, Left, Right. After all 8, official controllers
+//! read back as 1.
+//!
+//! Only the low 5 bits of these reads are driven, and a standard controller
+//! only uses bit 0 of them. The top 3 bits are open bus, which for the usual
+//! `LDA $4016` is the high byte of the address, so games see $40 or $41. Some
+//! games (like Paperboy) depend on that.
+//!
+//! On a Famicom, the second controller has no Select or Start buttons, and
+//! has a microphone instead, which reads back in bit 2 of $4016. Both consoles
+//! also have an expansion port, which sees the low 3 bits of every $4016 write
+//! and can drive bits 1-4 of reads from either port.
+//!
+//! Turbo buttons, like the ones on the NES Advantage, are emulated here too.
+//! A held turbo button is reported as pressed and released in turns, a given
+//! number of frames each, so the controllers get clocked once per frame.
+//!
+//! cf. https://wiki.nesdev.com/w/index.php/Standard_controller
+//! cf. https://wiki.nesdev.com/w/index.php/Expansion_port
+
+use alloc::boxed::Box;
+
+use super::bus::{BusDevice, BusPeekResult};
+use super::nes::Console;
+
+bitflags! {
+    /// The buttons on a standard controller
+    ///
+    /// Each button's bit is its position in the order the controller reports
+    /// them.
+    pub struct Buttons: u8 {
+        const A = 0x01;
+        const B = 0x02;
+        const SELECT = 0x04;
+        const START = 0x08;
+        const UP = 0x10;
+        const DOWN = 0x20;
+        const LEFT = 0x40;
+        const RIGHT = 0x80;
+    }
+}
+
+/// The bits of a controller port read that are driven by the port itself
+const DRIVEN_BITS: u8 = 0x1F;
+
+/// The bits of a controller port read that the expansion port can drive
+const EXPANSION_BITS: u8 = 0x1E;
+
+/// The bit of $4016 that the Famicom microphone drives
+const MICROPHONE_BIT: u8 = 0x04;
+
+/// A device plugged into the expansion port, like the Family BASIC keyboard
+pub trait ExpansionDevice: Send {
+    /// Handle a write to $4016, whose low 3 bits are the OUT0-OUT2 lines
+    fn write(&mut self, out: u8);
+
+    /// Read from $4016 (port 0) or $4017 (port 1)
+    ///
+    /// Only bits 1-4 of the result are used.
+    fn read(&mut self, port: usize) -> u8;
+}
+
+/// A standard NES controller
+#[derive(Debug, Copy, Clone)]
+pub struct Controller {
+    /// The buttons currently held down
+    buttons: Buttons,
+    /// The latched buttons, shifted out one per read
+    shift: u8,
+    /// The buttons the shift register was last loaded with
+    latched: Buttons,
+    /// Whether the strobe line is high
+    strobe: bool,
+    /// The buttons that are on turbo
+    turbo: Buttons,
+    /// How many frames turbo buttons stay pressed, and then released, for
+    turbo_rate: u8,
+    /// The number of frames since turbo buttons were last toggled
+    turbo_frames: u8,
+    /// Whether held turbo buttons are currently released
+    turbo_released: bool,
+}
+
+impl Controller {
+    pub fn new() -> Controller {
+        Controller {
+            buttons: Buttons::empty(),
+            // unplugged and fully-shifted controllers both read as all 1s
+            shift: 0xFF,
+            latched: Buttons::empty(),
+            strobe: false,
+            turbo: Buttons::empty(),
+            turbo_rate: 1,
+            turbo_frames: 0,
+            turbo_released: false,
+        }
+    }
+
+    /// Put `turbo` on turbo, toggling every `frames_per_toggle` frames
+    ///
+    /// Pass `Buttons::empty()` to turn turbo off. A rate of 0 is treated as 1.
+    pub fn set_turbo(&mut self, turbo: Buttons, frames_per_toggle: u8) {
+        self.turbo = turbo;
+        self.turbo_rate = frames_per_toggle.max(1);
+    }
+
+    pub fn turbo(&self) -> Buttons {
+        self.turbo
+    }
+
+    /// Advance the turbo buttons by a frame
+    pub fn clock_frame(&mut self) {
+        self.turbo_frames += 1;
+        if self.turbo_frames >= self.turbo_rate {
+            self.turbo_frames = 0;
+            self.turbo_released = !self.turbo_released;
+        }
+    }
+
+    /// The buttons the game sees as pressed, after turbo
+    fn pressed(&self) -> Buttons {
+        if self.turbo_released {
+            self.buttons - self.turbo
+        } else {
+            self.buttons
+        }
+    }
+
+    pub fn set_buttons(&mut self, buttons: Buttons) {
+        self.buttons = buttons;
+    }
+
+    pub fn buttons(&self) -> Buttons {
+        self.buttons
+    }
+
+    pub fn set_strobe(&mut self, strobe: bool) {
+        // the shift register reloads for as long as the strobe is high, so it
+        // ends up with whatever was held when the strobe went low
+        if strobe || self.strobe {
+            self.latched = self.pressed();
+            self.shift = self.latched.bits();
+        }
+        self.strobe = strobe;
+    }
+
+    /// The buttons the game last latched, after turbo
+    ///
+    /// These are the ones it reads back, whatever was held since.
+    pub fn latched(&self) -> Buttons {
+        self.latched
+    }
+
+    /// Read the next button, returning it in bit 0
+    pub fn read(&mut self) -> u8 {
+        if self.strobe {
+            return self.pressed().bits() & 0x01;
+        }
+        let bit = self.shift & 0x01;
+        // the controller's shift register fills with 1s as it empties
+        self.shift = (self.shift >> 1) | 0x80;
+        bit
+    }
+}
+
+/// Both controller ports, mapped at $4016 and $4017, and the expansion port
+pub struct ControllerPorts {
+    pub ports: [Controller; 2],
+    console: Console,
+    /// Whether someone is speaking into the Famicom microphone
+    microphone: bool,
+    pub expansion: Option<Box<dyn ExpansionDevice>>,
+}
+
+impl ControllerPorts {
+    pub fn new(console: Console) -> ControllerPorts {
+        ControllerPorts {
+            ports: [Controller::new(), Controller::new()],
+            console,
+            microphone: false,
+            expansion: None,
+        }
+    }
+
+    /// Clear the latched state of both controllers, keeping the buttons held
+    /// and the turbo settings
+    pub fn power_on(&mut self, console: Console) {
+        self.console = console;
+        for port in self.ports.iter_mut() {
+            *port = Controller {
+                buttons: port.buttons,
+                turbo: port.turbo,
+                turbo_rate: port.turbo_rate,
+                ..Controller::new()
+            };
+        }
+    }
+
+    /// Advance both controllers' turbo buttons by a frame
+    pub fn clock_frame(&mut self) {
+        for port in self.ports.iter_mut() {
+            port.clock_frame();
+        }
+    }
+
+    /// Set the buttons held on a controller, dropping any it doesn't have
+    pub fn set_buttons(&mut self, port: usize, buttons: Buttons) {
+        let buttons = match (self.console, port) {
+            (Console::Famicom, 1) => buttons - (Buttons::SELECT | Buttons::START),
+            _ => buttons,
+        };
+        self.ports[port].set_buttons(buttons);
+    }
+
+    pub fn set_microphone(&mut self, active: bool) {
+        self.microphone = active;
+    }
+}
+
+impl BusDevice for ControllerPorts {
+    fn read(&mut self, addr: u16, last_bus_value: u8) -> u8 {
+        let port = (addr & 0x01) as usize;
+        let mut data = self.ports[port].read();
+        if port == 0 && self.console == Console::Famicom && self.microphone {
+            data |= MICROPHONE_BIT;
+        }
+        if let Some(expansion) = self.expansion.as_mut() {
+            data |= expansion.read(port) & EXPANSION_BITS;
+        }
+        (last_bus_value & !DRIVEN_BITS) | data
+    }
+
+    fn peek(&self, _addr: u16) -> BusPeekResult {
+        // reads shift the controller, and the top bits are open bus anyway
+        BusPeekResult::MutableRead
+    }
+
+    fn write(&mut self, addr: u16, value: u8) {
+        // $4017 writes go to the APU frame counter, not the controllers
+        if addr == 0 {
+            let strobe = value & 0x01 == 0x01;
+            for port in self.ports.iter_mut() {
+                port.set_strobe(strobe);
+            }
+            if let Some(expansion) = self.expansion.as_mut() {
+                expansion.write(value & 0x07);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    fn latch(ports: &mut ControllerPorts) {
+        ports.write(0, 1);
+        ports.write(0, 0);
+    }
+
+    #[test]
+    fn shifts_out_buttons_in_order() {
+        let mut ports = ControllerPorts::new(Console::Nes);
+        ports.ports[0].set_buttons(Buttons::A | Buttons::START | Buttons::RIGHT);
+        latch(&mut ports);
+        let bits: Vec<u8> = (0..8).map(|_| ports.read(0, 0x40) & 0x01).collect();
+        assert_eq!(bits, vec![1, 0, 0, 1, 0, 0, 0, 1]);
+    }
+
+    #[test]
+    fn reads_one_after_eight_buttons() {
+        let mut ports = ControllerPorts::new(Console::Nes);
+        latch(&mut ports);
+        for _ in 0..8 {
+            assert_eq!(ports.read(0, 0x40), 0x40);
+        }
+        for _ in 0..4 {
+            assert_eq!(ports.read(0, 0x40), 0x41);
+        }
+    }
+
+    #[test]
+    fn keeps_open_bus_in_the_top_bits() {
+        let mut ports = ControllerPorts::new(Console::Nes);
+        ports.ports[1].set_buttons(Buttons::A);
+        latch(&mut ports);
+        assert_eq!(ports.read(1, 0x40), 0x41);
+        // the driven bits are never open bus
+        assert_eq!(ports.read(1, 0xFF), 0xE0);
+    }
+
+    #[test]
+    fn reads_a_continuously_while_strobed() {
+        let mut ports = ControllerPorts::new(Console::Nes);
+        ports.ports[0].set_buttons(Buttons::A | Buttons::B);
+        ports.write(0, 1);
+        for _ in 0..10 {
+            assert_eq!(ports.read(0, 0x40), 0x41);
+        }
+        // buttons are reloaded for as long as the strobe is high
+        ports.ports[0].set_buttons(Buttons::B);
+        assert_eq!(ports.read(0, 0x40), 0x40);
+        ports.write(0, 0);
+        assert_eq!(ports.read(0, 0x40), 0x40);
+        assert_eq!(ports.read(0, 0x40), 0x41);
+    }
+
+    #[test]
+    fn ignores_buttons_pressed_after_latching() {
+        let mut ports = ControllerPorts::new(Console::Nes);
+        latch(&mut ports);
+        ports.ports[0].set_buttons(Buttons::A);
+        assert_eq!(ports.read(0, 0x40), 0x40);
+    }
+
+    #[test]
+    fn ignores_writes_to_4017() {
+        let mut ports = ControllerPorts::new(Console::Nes);
+        ports.ports[0].set_buttons(Buttons::B);
+        ports.write(1, 1);
+        // a strobed controller would report A, which isn't pressed
+        assert_eq!(ports.read(0, 0x40), 0x41);
+    }
+
+    #[test]
+    fn second_controller_has_no_select_or_start() {
+        let mut ports = ControllerPorts::new(Console::Famicom);
+        ports.set_buttons(0, Buttons::all());
+        ports.set_buttons(1, Buttons::all());
+        assert_eq!(ports.ports[0].buttons(), Buttons::all());
+        assert_eq!(
+            ports.ports[1].buttons(),
+            Buttons::all() - Buttons::SELECT - Buttons::START
+        );
+    }
+
+    #[test]
+    fn reads_the_microphone_on_4016() {
+        let mut ports = ControllerPorts::new(Console::Famicom);
+        ports.set_microphone(true);
+        ports.write(0, 1);
+        assert_eq!(ports.read(0, 0x40), 0x44);
+        assert_eq!(ports.read(1, 0x40), 0x40);
+        ports.set_microphone(false);
+        assert_eq!(ports.read(0, 0x40), 0x40);
+    }
+
+    #[test]
+    fn nes_has_no_microphone() {
+        let mut ports = ControllerPorts::new(Console::Nes);
+        ports.set_microphone(true);
+        ports.set_buttons(1, Buttons::START);
+        ports.write(0, 1);
+        assert_eq!(ports.read(0, 0x40), 0x40);
+        assert_eq!(ports.ports[1].buttons(), Buttons::START);
+    }
+
+    #[test]
+    fn turbo_buttons_toggle_every_few_frames() {
+        let mut ports = ControllerPorts::new(Console::Nes);
+        ports.set_buttons(0, Buttons::A | Buttons::B);
+        ports.ports[0].set_turbo(Buttons::A, 2);
+        let mut seen = Vec::new();
+        for _ in 0..8 {
+            latch(&mut ports);
+            seen.push(ports.read(0, 0x40) & 0x01);
+            // B isn't on turbo, so it's always pressed
+            assert_eq!(ports.read(0, 0x40), 0x41);
+            ports.clock_frame();
+        }
+        assert_eq!(seen, vec![1, 1, 0, 0, 1, 1, 0, 0]);
+    }
+
+    #[test]
+    fn remembers_the_latched_buttons() {
+        let mut ports = ControllerPorts::new(Console::Nes);
+        assert_eq!(ports.ports[0].latched(), Buttons::empty());
+        ports.set_buttons(0, Buttons::A | Buttons::UP);
+        ports.ports[0].set_turbo(Buttons::A, 1);
+        latch(&mut ports);
+        assert_eq!(ports.ports[0].latched(), Buttons::A | Buttons::UP);
+        // neither pressing buttons nor clocking turbo changes it until the
+        // next latch
+        ports.set_buttons(0, Buttons::DOWN | Buttons::A);
+        ports.clock_frame();
+        assert_eq!(ports.ports[0].latched(), Buttons::A | Buttons::UP);
+        latch(&mut ports);
+        assert_eq!(ports.ports[0].latched(), Buttons::DOWN);
+    }
+
+    #[test]
+    fn turbo_only_affects_held_buttons() {
+        let mut ports = ControllerPorts::new(Console::Nes);
+        ports.ports[0].set_turbo(Buttons::A, 1);
+        for _ in 0..4 {
+            latch(&mut ports);
+            assert_eq!(ports.read(0, 0x40), 0x40);
+            ports.clock_frame();
+        }
+    }
+
+    /// Records writes, and returns the same bits on every read
+    struct TestDevice {
+        writes: Arc<Mutex<Vec<u8>>>,
+    }
+
+    impl ExpansionDevice for TestDevice {
+        fn write(&mut self, out: u8) {
+            self.writes.lock().unwrap().push(out);
+        }
+
+        fn read(&mut self, port: usize) -> u8 {
+            if port == 1 {
+                0xFF
+            } else {
+                0x00
+            }
+        }
+    }
+
+    #[test]
+    fn expansion_devices_drive_bits_1_to_4() {
+        let writes = Arc::new(Mutex::new(Vec::new()));
+        let mut ports = ControllerPorts::new(Console::Famicom);
+        ports.expansion = Some(Box::new(TestDevice {
+            writes: writes.clone(),
+        }));
+        ports.write(0, 0xFD);
+        ports.write(1, 0xFF);
+        assert_eq!(*writes.lock().unwrap(), vec![0x05]);
+        assert_eq!(ports.read(0, 0x40), 0x40);
+        assert_eq!(ports.read(1, 0x40), 0x5E);
+    }
+}
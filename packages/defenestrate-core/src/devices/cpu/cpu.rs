@@ -1,17 +1,28 @@
 //! Emulator for the MOS 6502
 //!
-//! This does not include support for Binary Coded Decimal, which was omitted
-//! on the 2A03 variant used on the NES and Famicom. Support for BCD may be
-//! added later.
+//! Binary Coded Decimal is off by default, since it was omitted on the 2A03
+//! variant used on the NES and Famicom (which ignores the D flag). Other
+//! systems can turn it on with `Cpu6502::enable_bcd`.
+//!
+//! Nothing here is specific to the NES: the CPU runs against anything that
+//! implements `Motherboard` and `WithCpu`. Without the default `console`
+//! feature, this is the only part of the emulator that gets built.
 
-use std::num::Wrapping;
+use alloc::{format, string::String};
+use core::num::Wrapping;
 
 use super::super::bus::Motherboard;
 use super::{
+    opcodes,
     structs::{AddressingMode, CpuState, Instruction, Status, POWERON_CPU_STATE},
-    utils,
+    utils::{self, bytes_to_addr},
+};
+
+/// How many cycles an interrupt or reset takes, which is as long as a BRK
+const INTERRUPT_CYCLES: u32 = match opcodes::OPCODES[0x00] {
+    Some(brk) => brk.cycles as u32,
+    None => panic!("BRK is missing from the opcode table"),
 };
-use crate::{adj_cycles, bus, bytes_to_addr, reg};
 
 macro_rules! op_fn {
     ($mnemonic: ident, $mb: ident, $body: expr) => {
@@ -21,6 +32,29 @@ macro_rules! op_fn {
     };
 }
 
+/// One of the 6502's interrupt vectors
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum Vector {
+    /// The NMI vector, at $FFFA
+    Nmi,
+    /// The reset vector, at $FFFC
+    Reset,
+    /// The IRQ vector, at $FFFE, which BRK also uses
+    Irq,
+}
+
+impl Vector {
+    /// The address the vector is read from
+    pub fn addr(self) -> u16 {
+        match self {
+            Vector::Nmi => 0xFFFA,
+            Vector::Reset => 0xFFFC,
+            Vector::Irq => 0xFFFE,
+        }
+    }
+}
+
+#[derive(Clone)]
 pub struct Cpu6502 {
     pub state: CpuState,
     //region internal state
@@ -41,7 +75,13 @@ pub struct Cpu6502 {
     pub maskable_interrupt: bool,
     /// Whether an 'oops' cycle occurred
     pub oops_cycle: bool,
+    /// The address of the opcode being executed, after any interrupt
+    pub instruction_addr: u16,
     //endregion
+    /// Whether ADC and SBC honor the D flag, which the 2A03 doesn't
+    bcd_enabled: bool,
+    /// Addresses to jump to in place of the NMI, reset, and IRQ vectors
+    vector_overrides: [Option<u16>; 3],
 }
 
 impl Cpu6502 {
@@ -59,8 +99,32 @@ impl Cpu6502 {
             interrupt_pending: false,
             maskable_interrupt: false,
             oops_cycle: false,
+            instruction_addr: 0,
+            bcd_enabled: false,
+            vector_overrides: [None; 3],
         }
     }
+
+    /// Jump to `target` instead of reading `vector` off the bus
+    ///
+    /// This is meant for test harnesses, which might not have anything mapped
+    /// at the top of memory. Passing `None` goes back to reading the vector.
+    pub fn override_vector(&mut self, vector: Vector, target: Option<u16>) {
+        self.vector_overrides[vector as usize] = target;
+    }
+
+    /// Jump to `addr` before the next instruction
+    pub fn force_pc(&mut self, addr: u16) {
+        self.state.pc = addr;
+    }
+
+    /// Enable or disable decimal mode for ADC and SBC
+    ///
+    /// This is off by default, like on the 2A03. The D flag can still be set
+    /// and cleared either way, but it only changes arithmetic if this is on.
+    pub fn enable_bcd(&mut self, enabled: bool) {
+        self.bcd_enabled = enabled;
+    }
 }
 
 /// Trait for a device that owns a CPU, such as the motherboard or a test harness
@@ -81,34 +145,41 @@ pub fn tick<T: WithCpu>(mb: &mut T) -> bool {
 
 pub fn exec<T: WithCpu + Motherboard>(mb: &mut T) {
     run_interrupt(mb);
-    let instruction = fetch_opcode(mb);
-    decode_opcode(mb, instruction);
-    mb.cpu_mut().state.addr = get_addr(mb, reg!(get instruction, mb));
+    prepare_instr(mb);
     exec_instr(mb);
 }
 
 pub fn debug<T: WithCpu + Motherboard>(mb: &mut T) -> String {
-    let old_pc = reg!(get pc, mb);
+    let old_pc = mb.cpu().state.pc;
     run_interrupt(mb);
-    let instruction = fetch_opcode(mb);
-    decode_opcode(mb, instruction);
-    mb.cpu_mut().state.addr = get_addr(mb, reg!(get instruction, mb));
-    let new_pc = reg!(get pc, mb);
-    reg!(set pc, mb, old_pc);
+    prepare_instr(mb);
+    let new_pc = mb.cpu().state.pc;
+    mb.cpu_mut().state.pc = old_pc;
     let debug_str = format!("{}", utils::print_debug(mb));
-    reg!(set pc, mb, new_pc);
+    mb.cpu_mut().state.pc = new_pc;
     exec_instr(mb);
     debug_str
 }
 
 /// Triggers a hardware reset of the CPU
 pub fn reset<T: WithCpu + Motherboard>(mb: &mut T) {
-    let fst = bus!(read mb, 0xFFFC);
-    let snd = bus!(read mb, 0xFFFD);
+    let addr = read_vector(mb, Vector::Reset);
     let cpu = mb.cpu_mut();
-    cpu.state.stack -= 3;
+    cpu.interrupt_pending = false;
+    cpu.state.stack = cpu.state.stack.wrapping_sub(3);
     cpu.state.status |= Status::IRQ_DISABLE;
-    cpu.state.pc = bytes_to_addr!(fst, snd);
+    cpu.state.pc = addr;
+    cpu.cycles += INTERRUPT_CYCLES;
+}
+
+/// Read the address an interrupt vector points to, unless it's overridden
+fn read_vector<T: WithCpu + Motherboard>(mb: &mut T, vector: Vector) -> u16 {
+    if let Some(addr) = mb.cpu().vector_overrides[vector as usize] {
+        return addr;
+    }
+    let fst = mb.read(vector.addr());
+    let snd = mb.read(vector.addr().wrapping_add(1));
+    bytes_to_addr!(fst, snd)
 }
 
 /// Trigger a hard interrupt (NMI)
@@ -124,6 +195,9 @@ pub fn trigger_irq<T: WithCpu>(mb: &mut T) {
     if cpu.state.status.contains(Status::IRQ_DISABLE) {
         return; // interrupt ignored
     }
+    if cpu.interrupt_pending {
+        return; // an NMI takes priority, and another IRQ is redundant
+    }
     cpu.interrupt_pending = true;
     cpu.maskable_interrupt = true;
 }
@@ -140,31 +214,35 @@ fn clear_flag<T: WithCpu>(mb: &mut T, flag: Status) {
 
 /// Advance the program counter, with overflow
 fn adv_pc<T: WithCpu>(mb: &mut T, increment: u16) {
-    reg!(add pc, mb, increment);
+    let state = &mut mb.cpu_mut().state;
+    state.pc = state.pc.wrapping_add(increment);
 }
 
 /// Process any CPU interrupts and return whether one occurred
-fn run_interrupt<T: WithCpu + Motherboard>(mb: &mut T) -> bool {
+///
+/// `exec` does this before fetching an instruction, but it's also exposed so
+/// that the PC can be inspected after an interrupt and before the instruction.
+pub fn run_interrupt<T: WithCpu + Motherboard>(mb: &mut T) -> bool {
     if !mb.cpu().interrupt_pending {
         return false;
     }
     let is_maskable = mb.cpu().maskable_interrupt;
-    eprintln!(
-        " [INFO] CPU Interrupt: {}",
-        if is_maskable { "IRQ" } else { "NMI" }
-    );
+    log::trace!("CPU interrupt: {}", if is_maskable { "IRQ" } else { "NMI" });
     mb.cpu_mut().interrupt_pending = false;
-    let addr_bytes = reg!(get pc, mb).to_le_bytes();
-    push_stack(mb, addr_bytes[1]);
-    push_stack(mb, addr_bytes[0]);
+    push_stack16(mb, mb.cpu().state.pc);
     clear_flag(mb, Status::BREAK);
     set_flag(mb, Status::UNUSED);
-    let status = reg!(get status, mb).bits();
+    let status = mb.cpu().state.status.bits();
     push_stack(mb, status);
-    let addr = if is_maskable { 0xFFFE } else { 0xFFFA };
-    let addr_fst = bus!(read mb, addr);
-    let addr_snd = bus!(read mb, addr.wrapping_add(1));
-    reg!(set pc, mb, bytes_to_addr!(addr_fst, addr_snd));
+    // otherwise, an IRQ that's still asserted would interrupt its own handler
+    set_flag(mb, Status::IRQ_DISABLE);
+    let vector = if is_maskable {
+        Vector::Irq
+    } else {
+        Vector::Nmi
+    };
+    mb.cpu_mut().state.pc = read_vector(mb, vector);
+    mb.cpu_mut().cycles += INTERRUPT_CYCLES;
     true
 }
 /// Read the next instruction word from the address bus
@@ -177,44 +255,53 @@ fn run_interrupt<T: WithCpu + Motherboard>(mb: &mut T) -> bool {
 /// decode, since reads are not side-effect free
 fn fetch_opcode<T: WithCpu + Motherboard>(mb: &mut T) -> u32 {
     let pc = mb.cpu().state.pc;
-    // These will advance the cycle counter. If we need to make corrections
-    // (eg, because an instruction isn't actually 3 bytes long), get_addr will
-    // correct for that
-    let opcode = bus!(read mb, pc);
-    let operand1 = bus!(read mb, pc.wrapping_add(1));
-    let operand2 = bus!(read mb, pc.wrapping_add(2));
+    mb.cpu_mut().instruction_addr = pc;
+    let opcode = mb.read(pc);
+    let operand1 = mb.read(pc.wrapping_add(1));
+    let operand2 = mb.read(pc.wrapping_add(2));
 
     u32::from(opcode) | (u32::from(operand1) << 8) | (u32::from(operand2) << 16)
 }
 
 /// Decodes an instruction and prepares the CPU to execute it
-fn decode_opcode<T: WithCpu>(mb: &mut T, instruction: u32) {
+fn decode_opcode<T: WithCpu>(mb: &mut T, instruction: u32) -> opcodes::Opcode {
     let ops = instruction.to_le_bytes();
 
-    let instr = utils::decode_instruction(ops[0]);
+    let opcode = opcodes::decode_instruction(ops[0]);
     let cpu = mb.cpu_mut();
     cpu.state.instruction = instruction;
-    cpu.state.addr_mode = instr.0;
-    cpu.state.instr = instr.1;
+    cpu.state.addr_mode = opcode.addr_mode;
+    cpu.state.instr = opcode.instr;
+    opcode
+}
+
+/// Fetch and decode the next instruction, work out the address of its
+/// operand, and count the cycles it takes
+///
+/// The cycle count comes from the opcode table, plus the oops cycle for
+/// reads that index across a page. Taken branches add their own extra cycle
+/// as they run.
+fn prepare_instr<T: WithCpu + Motherboard>(mb: &mut T) {
+    let instruction = fetch_opcode(mb);
+    let opcode = decode_opcode(mb, instruction);
+    mb.cpu_mut().state.addr = get_addr(mb, instruction);
+    let cpu = mb.cpu_mut();
+    cpu.cycles += u32::from(opcode.cycles);
+    if cpu.oops_cycle && opcode.has_oops_cycle() {
+        cpu.cycles += 1;
+    }
 }
 
 /// Gets the address of the operand to read from.
 ///
 /// # Notes
 ///
-/// This sets the `cycles` to the average whole number of cycles any
-/// instruction with this addressing mode will have. Other instructions may
-/// need to add or subtract to compensate, refer to the 6502 datasheet for
-/// details:
-///
-/// http://archive.6502.org/datasheets/mos_6501-6505_mpu_preliminary_aug_1975.pdf
-///
 /// A note on the so-called "oops" cycle: The "oops" cycle occurs when an
 /// index instruction crosses a page boundary, as the CPU reads off the high
-/// byte first without checking for a carry-out. Some instructions (like all
-/// the store instructions) have some special-cased behavior that the 6502
-/// datasheet details. These depend on the instruction being executed, but
-/// this function is the best place to
+/// byte first without checking for a carry-out. This only notes whether that
+/// happened, since whether it costs a cycle depends on the instruction, but
+/// it is the best place to make the dummy read of the address before the
+/// carry, since that happens while the address is worked out.
 fn get_addr<T: WithCpu + Motherboard>(mb: &mut T, instruction: u32) -> u16 {
     let ops = instruction.to_le_bytes();
     // Advance the PC at _least_ 1 byte
@@ -228,72 +315,59 @@ fn get_addr<T: WithCpu + Motherboard>(mb: &mut T, instruction: u32) -> u16 {
         }
         AddressingMode::AbsInd => {
             let addr_fst = bytes_to_addr!(ops[1], ops[2]);
+            // the high byte of the pointer never gets the carry, so a pointer
+            // at $xxFF wraps around to the start of its page
             let addr_snd = bytes_to_addr!(ops[1].wrapping_add(1), ops[2]);
             adv_pc(mb, 2);
-            let fst = bus!(read mb, addr_fst);
-            let snd = bus!(read mb, addr_snd);
+            let fst = mb.read(addr_fst);
+            let snd = mb.read(addr_snd);
             bytes_to_addr!(fst, snd)
         }
         AddressingMode::AbsX => {
-            let addr = bytes_to_addr!(ops[1], ops[2]).wrapping_add(u16::from(reg!(get x, mb)));
+            let base = bytes_to_addr!(ops[1], ops[2]);
+            let addr = base.wrapping_add(u16::from(mb.cpu().state.x));
             adv_pc(mb, 2);
-            if (u16::from(reg!(get x, mb)) + u16::from(ops[1])) & 0x0100 == 0x0100 {
-                adj_cycles!(mb, 1); // oops cycle
-                mb.cpu_mut().oops_cycle = true;
-            }
+            mb.cpu_mut().oops_cycle = crosses_page(base, addr);
+            dummy_read(mb, base, addr);
             addr
         }
         AddressingMode::AbsY => {
-            let addr = bytes_to_addr!(ops[1], ops[2]).wrapping_add(u16::from(reg!(get y, mb)));
+            let base = bytes_to_addr!(ops[1], ops[2]);
+            let addr = base.wrapping_add(u16::from(mb.cpu().state.y));
             adv_pc(mb, 2);
-            if (u16::from(reg!(get y, mb)) + u16::from(ops[1])) & 0x0100 == 0x0100 {
-                adj_cycles!(mb, 1); // oops cycle
-                mb.cpu_mut().oops_cycle = true;
-            }
+            mb.cpu_mut().oops_cycle = crosses_page(base, addr);
+            dummy_read(mb, base, addr);
             addr
         }
         AddressingMode::Accum => {
             // TODO: Make addressing Optional?
-            adj_cycles!(mb, -1i32);
             0x0000
         }
         AddressingMode::Imm => {
             adv_pc(mb, 1);
-            adj_cycles!(mb, -1i32);
-            0x0000
-        }
-        AddressingMode::Impl => {
-            adj_cycles!(mb, -1i32);
             0x0000
         }
+        AddressingMode::Impl => 0x0000,
         AddressingMode::IndX => {
-            adj_cycles!(mb, -1i32); // lop off one of the micro-ops
-                                    // I know we immediately re-add it but I want cycle corrections
-                                    // to be purposeful, since we're trying for clock cycle accuracy
-                                    // and the clock adjustments before now have been for instruction
-                                    // length. This instruction is 2 bytes long.
             adv_pc(mb, 1);
-            let val = ops[1].wrapping_add(reg!(get x, mb));
-            let fst = bus!(read mb, u16::from(val));
-            let snd = bus!(read mb, u16::from(val.wrapping_add(1)));
-            adj_cycles!(mb, 1);
+            let val = ops[1].wrapping_add(mb.cpu().state.x);
+            let fst = mb.read(u16::from(val));
+            let snd = mb.read(u16::from(val.wrapping_add(1)));
             bytes_to_addr!(fst, snd)
         }
         AddressingMode::IndY => {
-            adj_cycles!(mb, -1i32);
             adv_pc(mb, 1);
-            let fst = bus!(read mb, u16::from(ops[1]));
-            let snd = bus!(read mb, u16::from(ops[1].wrapping_add(1)));
-            if (u16::from(reg!(get y, mb)) + u16::from(fst)) & 0x0100 == 0x0100 {
-                adj_cycles!(mb, 1); // oops cycle
-                mb.cpu_mut().oops_cycle = true;
-            }
-            bytes_to_addr!(fst, snd).wrapping_add(reg!(get y, mb) as u16)
+            let fst = mb.read(u16::from(ops[1]));
+            let snd = mb.read(u16::from(ops[1].wrapping_add(1)));
+            let base = bytes_to_addr!(fst, snd);
+            let addr = base.wrapping_add(u16::from(mb.cpu().state.y));
+            mb.cpu_mut().oops_cycle = crosses_page(base, addr);
+            dummy_read(mb, base, addr);
+            addr
         }
         AddressingMode::Rel => {
             adv_pc(mb, 1);
-            adj_cycles!(mb, -1i32);
-            let bytes = reg!(get pc, mb).to_le_bytes();
+            let bytes = mb.cpu().state.pc.to_le_bytes();
             // The 'offset' is _signed_, so we need to add it as a signed
             // integer.
             let fst = bytes[0];
@@ -303,48 +377,111 @@ fn get_addr<T: WithCpu + Motherboard>(mb: &mut T, instruction: u32) -> u16 {
         }
         AddressingMode::ZP => {
             adv_pc(mb, 1);
-            adj_cycles!(mb, -1i32);
             bytes_to_addr!(ops[1], 0u8)
         }
         AddressingMode::ZPX => {
             adv_pc(mb, 1);
-            // adj_cycles!(mb, -1i32);
-            bytes_to_addr!(ops[1].wrapping_add(reg!(get x, mb)), 0u8)
+            bytes_to_addr!(ops[1].wrapping_add(mb.cpu().state.x), 0u8)
         }
         AddressingMode::ZPY => {
             adv_pc(mb, 1);
-            adj_cycles!(mb, -1i32);
-            bytes_to_addr!(ops[1].wrapping_add(reg!(get y, mb)), 0u8)
+            bytes_to_addr!(ops[1].wrapping_add(mb.cpu().state.y), 0u8)
         }
     }
 }
 
+/// Whether indexing `base` to get `addr` carried into the high byte
+///
+/// Indexing past $FFFF wraps around to the zero page, which counts.
+fn crosses_page(base: u16, addr: u16) -> bool {
+    base & 0xFF00 != addr & 0xFF00
+}
+
+/// Read the indexed address before the carry into the high byte is fixed up
+///
+/// Reads only do this when the index crosses a page, and then read again from
+/// the right address. Stores and read-modify-writes can't risk acting on the
+/// wrong address, so they always do it. Either way, the cycle is already
+/// counted (as the oops cycle, or in the instruction's cycle count), but
+/// registers with read side effects like $2007 still see it.
+fn dummy_read<T: WithCpu + Motherboard>(mb: &mut T, base: u16, addr: u16) {
+    if !mb.accuracy().makes_dummy_accesses() {
+        return;
+    }
+    let always = matches!(
+        mb.cpu().state.instr,
+        Instruction::STA
+            | Instruction::ASL
+            | Instruction::LSR
+            | Instruction::ROL
+            | Instruction::ROR
+            | Instruction::INC
+            | Instruction::DEC
+    );
+    let partial = (base & 0xFF00) | (addr & 0x00FF);
+    if always || crosses_page(base, addr) {
+        mb.read(partial);
+    }
+}
+
 /// Read the data at the resolved address
 fn read<T: WithCpu + Motherboard>(mb: &mut T) -> u8 {
-    let ops = reg!(get instruction, mb).to_le_bytes();
-    match reg!(get addr_mode, mb) {
+    let ops = mb.cpu().state.instruction.to_le_bytes();
+    match mb.cpu().state.addr_mode {
         AddressingMode::Imm => ops[1],
-        AddressingMode::Accum => reg!(get acc, mb),
-        _ => bus!(read mb, reg!(get addr, mb)),
+        AddressingMode::Accum => mb.cpu().state.acc,
+        _ => mb.read(mb.cpu().state.addr),
     }
 }
 
 /// Write the data to the resolved address
 fn write<T: WithCpu + Motherboard>(mb: &mut T, data: u8) {
-    adj_cycles!(mb, 1);
-    mb.write(reg!(get addr, mb), data);
+    mb.write(mb.cpu().state.addr, data);
+}
+
+/// Write the result of a read-modify-write instruction
+///
+/// While it's modifying the value, the 6502 writes the original back to the
+/// same address, and only then writes the result. Some mappers can see that
+/// extra write (the MMC1 ignores the second of two back-to-back writes, for
+/// one). Each RMW instruction's cycle count already covers it.
+fn write_rmw<T: WithCpu + Motherboard>(mb: &mut T, original: u8, data: u8) {
+    if mb.accuracy().makes_dummy_accesses() {
+        mb.write(mb.cpu().state.addr, original);
+    }
+    write(mb, data);
 }
 
 fn push_stack<T: WithCpu + Motherboard>(mb: &mut T, data: u8) {
-    let addr = bytes_to_addr!(reg!(get stack, mb), 0x01u8);
-    bus!(write mb, addr, data);
-    reg!(sub stack, mb, 1);
+    let addr = bytes_to_addr!(mb.cpu().state.stack, 0x01u8);
+    mb.write(addr, data);
+    let state = &mut mb.cpu_mut().state;
+    state.stack = state.stack.wrapping_sub(1);
 }
 
 fn pop_stack<T: WithCpu + Motherboard>(mb: &mut T) -> u8 {
-    reg!(add stack, mb, 1);
-    let addr = bytes_to_addr!(reg!(get stack, mb), 0x01u8);
-    bus!(read mb, addr)
+    let state = &mut mb.cpu_mut().state;
+    state.stack = state.stack.wrapping_add(1);
+    let addr = bytes_to_addr!(state.stack, 0x01u8);
+    mb.read(addr)
+}
+
+/// Push a 16-bit value onto the stack, high byte first
+///
+/// This leaves the low byte at the lower address, so that it reads back as a
+/// little-endian word. Like every stack access, the pushes wrap around within
+/// page 1.
+fn push_stack16<T: WithCpu + Motherboard>(mb: &mut T, data: u16) {
+    let [lo, hi] = data.to_le_bytes();
+    push_stack(mb, hi);
+    push_stack(mb, lo);
+}
+
+/// Pop a 16-bit value off the stack, low byte first
+fn pop_stack16<T: WithCpu + Motherboard>(mb: &mut T) -> u16 {
+    let lo = pop_stack(mb);
+    let hi = pop_stack(mb);
+    bytes_to_addr!(lo, hi)
 }
 
 fn check_carry<T: WithCpu>(mb: &mut T, val: u16) {
@@ -383,137 +520,194 @@ fn check_negative<T: WithCpu>(mb: &mut T, op: u8) {
     }
 }
 
+/// Run the instruction the opcode table decoded
+///
+/// Opcodes only ever map to instructions through the table, and this only
+/// maps each `Instruction` to the function that runs it, with no catch-all,
+/// so a new instruction can't be added to the table without a handler here.
 fn exec_instr<T: WithCpu + Motherboard>(mb: &mut T) {
-    let handler = match_handler(reg!(get instr, mb));
-    handler(mb);
-}
-
-#[allow(type_alias_bounds)] // leaving this in for self-documenting reasons
-type OpcodeHandler<T: WithCpu + Motherboard> = fn(mb: &mut T);
-
-fn match_handler<T: WithCpu + Motherboard>(mnemonic: Instruction) -> OpcodeHandler<T> {
-    match mnemonic {
-        Instruction::ADC => op_adc,
-        Instruction::AND => op_and,
-        Instruction::ASL => op_asl,
-        Instruction::BIT => op_bit,
-        Instruction::BPL => op_bpl,
-        Instruction::BMI => op_bmi,
-        Instruction::BVC => op_bvc,
-        Instruction::BVS => op_bvs,
-        Instruction::BCC => op_bcc,
-        Instruction::BCS => op_bcs,
-        Instruction::BNE => op_bne,
-        Instruction::BEQ => op_beq,
-        Instruction::BRK => op_brk,
-        Instruction::CMP => op_cmp,
-        Instruction::CPX => op_cpx,
-        Instruction::CPY => op_cpy,
-        Instruction::DEC => op_dec,
-        Instruction::EOR => op_eor,
-        Instruction::CLC => op_clc,
-        Instruction::SEC => op_sec,
-        Instruction::CLI => op_cli,
-        Instruction::SEI => op_sei,
-        Instruction::CLV => op_clv,
-        Instruction::CLD => op_cld,
-        Instruction::SED => op_sed,
-        Instruction::INC => op_inc,
-        Instruction::JMP => op_jmp,
-        Instruction::JSR => op_jsr,
-        Instruction::LDA => op_lda,
-        Instruction::LDX => op_ldx,
-        Instruction::LDY => op_ldy,
-        Instruction::LSR => op_lsr,
-        Instruction::NOP => op_nop,
-        Instruction::ORA => op_ora,
-        Instruction::TAX => op_tax,
-        Instruction::TXA => op_txa,
-        Instruction::DEX => op_dex,
-        Instruction::INX => op_inx,
-        Instruction::TAY => op_tay,
-        Instruction::TYA => op_tya,
-        Instruction::DEY => op_dey,
-        Instruction::INY => op_iny,
-        Instruction::ROL => op_rol,
-        Instruction::ROR => op_ror,
-        Instruction::RTI => op_rti,
-        Instruction::RTS => op_rts,
-        Instruction::SBC => op_sbc,
-        Instruction::STA => op_sta,
-        Instruction::STX => op_stx,
-        Instruction::STY => op_sty,
-        Instruction::TXS => op_txs,
-        Instruction::TSX => op_tsx,
-        Instruction::PHA => op_pha,
-        Instruction::PLA => op_pla,
-        Instruction::PHP => op_php,
-        Instruction::PLP => op_plp,
+    // this calls each handler directly, rather than through a function
+    // pointer, so that the compiler can inline the hot ones
+    match mb.cpu().state.instr {
+        Instruction::ADC => op_adc(mb),
+        Instruction::AND => op_and(mb),
+        Instruction::ASL => op_asl(mb),
+        Instruction::BIT => op_bit(mb),
+        Instruction::BPL => op_bpl(mb),
+        Instruction::BMI => op_bmi(mb),
+        Instruction::BVC => op_bvc(mb),
+        Instruction::BVS => op_bvs(mb),
+        Instruction::BCC => op_bcc(mb),
+        Instruction::BCS => op_bcs(mb),
+        Instruction::BNE => op_bne(mb),
+        Instruction::BEQ => op_beq(mb),
+        Instruction::BRK => op_brk(mb),
+        Instruction::CMP => op_cmp(mb),
+        Instruction::CPX => op_cpx(mb),
+        Instruction::CPY => op_cpy(mb),
+        Instruction::DEC => op_dec(mb),
+        Instruction::EOR => op_eor(mb),
+        Instruction::CLC => op_clc(mb),
+        Instruction::SEC => op_sec(mb),
+        Instruction::CLI => op_cli(mb),
+        Instruction::SEI => op_sei(mb),
+        Instruction::CLV => op_clv(mb),
+        Instruction::CLD => op_cld(mb),
+        Instruction::SED => op_sed(mb),
+        Instruction::INC => op_inc(mb),
+        Instruction::JMP => op_jmp(mb),
+        Instruction::JSR => op_jsr(mb),
+        Instruction::LDA => op_lda(mb),
+        Instruction::LDX => op_ldx(mb),
+        Instruction::LDY => op_ldy(mb),
+        Instruction::LSR => op_lsr(mb),
+        Instruction::NOP => op_nop(mb),
+        Instruction::ORA => op_ora(mb),
+        Instruction::TAX => op_tax(mb),
+        Instruction::TXA => op_txa(mb),
+        Instruction::DEX => op_dex(mb),
+        Instruction::INX => op_inx(mb),
+        Instruction::TAY => op_tay(mb),
+        Instruction::TYA => op_tya(mb),
+        Instruction::DEY => op_dey(mb),
+        Instruction::INY => op_iny(mb),
+        Instruction::ROL => op_rol(mb),
+        Instruction::ROR => op_ror(mb),
+        Instruction::RTI => op_rti(mb),
+        Instruction::RTS => op_rts(mb),
+        Instruction::SBC => op_sbc(mb),
+        Instruction::STA => op_sta(mb),
+        Instruction::STX => op_stx(mb),
+        Instruction::STY => op_sty(mb),
+        Instruction::TXS => op_txs(mb),
+        Instruction::TSX => op_tsx(mb),
+        Instruction::PHA => op_pha(mb),
+        Instruction::PLA => op_pla(mb),
+        Instruction::PHP => op_php(mb),
+        Instruction::PLP => op_plp(mb),
     }
 }
 
 //region Arithmetic ops
 // ADC SBC
 op_fn!(op_adc, mb, {
-    if reg!(get status, mb).contains(Status::DECIMAL) {
-        eprintln!(" [WARN] This emulator doesn't support BCD, but the BCD flag is set");
-    }
     let op = read(mb);
-    let val = Wrapping(u16::from(reg!(get acc, mb)))
+    if is_decimal(mb) {
+        return adc_decimal(mb, op);
+    }
+    let val = Wrapping(u16::from(mb.cpu().state.acc))
         + Wrapping(u16::from(op))
-        + Wrapping(if reg!(get status, mb).contains(Status::CARRY) {
+        + Wrapping(if mb.cpu().state.status.contains(Status::CARRY) {
             1
         } else {
             0
         });
     check_carry(mb, val.0);
-    check_overflow(mb, reg!(get acc, mb), op);
-    reg!(set acc, mb, (0xFF & val.0) as u8);
-    check_zero(mb, reg!(get acc, mb));
-    check_negative(mb, reg!(get acc, mb));
+    check_overflow(mb, mb.cpu().state.acc, op);
+    mb.cpu_mut().state.acc = (0xFF & val.0) as u8;
+    check_zero(mb, mb.cpu().state.acc);
+    check_negative(mb, mb.cpu().state.acc);
 });
 op_fn!(op_sbc, mb, {
-    if reg!(get status, mb).contains(Status::DECIMAL) {
-        eprintln!(" [WARN] This emulator doesn't support BCD, but the BCD flag is set");
-    }
     let op = read(mb);
-    let val = Wrapping(u16::from(reg!(get acc, mb)))
+    let acc = mb.cpu().state.acc;
+    let carry = mb.cpu().state.status.contains(Status::CARRY);
+    let val = Wrapping(u16::from(mb.cpu().state.acc))
         - Wrapping(u16::from(op))
-        - Wrapping(if !reg!(get status, mb).contains(Status::CARRY) {
+        - Wrapping(if !mb.cpu().state.status.contains(Status::CARRY) {
             1
         } else {
             0
         });
     check_carry(mb, !val.0);
-    check_overflow(mb, reg!(get acc, mb), !op);
-    reg!(set acc, mb, (0xFF & val.0) as u8);
-    check_zero(mb, reg!(get acc, mb));
-    check_negative(mb, reg!(get acc, mb));
+    check_overflow(mb, mb.cpu().state.acc, !op);
+    mb.cpu_mut().state.acc = (0xFF & val.0) as u8;
+    check_zero(mb, mb.cpu().state.acc);
+    check_negative(mb, mb.cpu().state.acc);
+    if is_decimal(mb) {
+        // the flags are the same as in binary mode, only the result changes
+        mb.cpu_mut().state.acc = sbc_decimal(acc, op, carry);
+    }
 });
+
+/// Whether ADC and SBC should do decimal arithmetic
+fn is_decimal<T: WithCpu>(mb: &T) -> bool {
+    let cpu = mb.cpu();
+    cpu.bcd_enabled && cpu.state.status.contains(Status::DECIMAL)
+}
+
+/// ADC in decimal mode, the way the NMOS 6502 does it
+///
+/// Z comes from the binary sum, and N and V from the sum before the high digit
+/// is adjusted. Invalid BCD operands give the same garbage as on hardware.
+/// cf. http://www.6502.org/tutorials/decimal_mode.html
+fn adc_decimal<T: WithCpu>(mb: &mut T, op: u8) {
+    let acc = mb.cpu().state.acc;
+    let carry = u16::from(mb.cpu().state.status.contains(Status::CARRY));
+    let binary = u16::from(acc) + u16::from(op) + carry;
+    let mut lo = u16::from(acc & 0x0F) + u16::from(op & 0x0F) + carry;
+    if lo >= 0x0A {
+        lo = ((lo + 0x06) & 0x0F) + 0x10;
+    }
+    let mut sum = u16::from(acc & 0xF0) + u16::from(op & 0xF0) + lo;
+    check_zero(mb, (binary & 0xFF) as u8);
+    check_negative(mb, (sum & 0xFF) as u8);
+    if !(acc ^ op) & (acc ^ (sum & 0xFF) as u8) & 0x80 != 0 {
+        set_flag(mb, Status::OVERFLOW);
+    } else {
+        clear_flag(mb, Status::OVERFLOW);
+    }
+    if sum >= 0xA0 {
+        sum += 0x60;
+    }
+    if sum >= 0x100 {
+        set_flag(mb, Status::CARRY);
+    } else {
+        clear_flag(mb, Status::CARRY);
+    }
+    mb.cpu_mut().state.acc = (sum & 0xFF) as u8;
+}
+
+/// The result of SBC in decimal mode, the way the NMOS 6502 does it
+///
+/// The flags are set from the binary difference, so only the result differs.
+fn sbc_decimal(acc: u8, op: u8, carry: bool) -> u8 {
+    let borrow = i16::from(!carry);
+    let mut lo = i16::from(acc & 0x0F) - i16::from(op & 0x0F) - borrow;
+    if lo < 0 {
+        lo = ((lo - 0x06) & 0x0F) - 0x10;
+    }
+    let mut diff = i16::from(acc & 0xF0) - i16::from(op & 0xF0) + lo;
+    if diff < 0 {
+        diff -= 0x60;
+    }
+    (diff & 0xFF) as u8
+}
 //endregion
 
 //region Bitwise ops
 // AND BIT EOR ORA
 op_fn!(op_and, mb, {
     mb.cpu_mut().state.acc &= read(mb);
-    check_zero(mb, reg!(get acc, mb));
-    check_negative(mb, reg!(get acc, mb));
+    check_zero(mb, mb.cpu().state.acc);
+    check_negative(mb, mb.cpu().state.acc);
 });
 op_fn!(op_bit, mb, {
     let op = read(mb);
-    let res = reg!(get acc, mb) & op;
+    let res = mb.cpu().state.acc & op;
     check_zero(mb, res);
-    reg!(set status, mb, Status::from_bits_truncate((reg!(get status, mb).bits() & 0x3F) | (0xC0 & op)));
+    mb.cpu_mut().state.status =
+        Status::from_bits_truncate((mb.cpu().state.status.bits() & 0x3F) | (0xC0 & op));
 });
 op_fn!(op_eor, mb, {
     mb.cpu_mut().state.acc ^= read(mb);
-    check_zero(mb, reg!(get acc, mb));
-    check_negative(mb, reg!(get acc, mb));
+    check_zero(mb, mb.cpu().state.acc);
+    check_negative(mb, mb.cpu().state.acc);
 });
 op_fn!(op_ora, mb, {
     mb.cpu_mut().state.acc |= read(mb);
-    check_zero(mb, reg!(get acc, mb));
-    check_negative(mb, reg!(get acc, mb));
+    check_zero(mb, mb.cpu().state.acc);
+    check_negative(mb, mb.cpu().state.acc);
 });
 //endregion
 op_fn!(op_asl, mb, {
@@ -523,114 +717,101 @@ op_fn!(op_asl, mb, {
     let res = (0xFF & res) as u8;
     check_zero(mb, res);
     check_negative(mb, res);
-    // Cycle corrections
-    match reg!(get addr_mode, mb) {
-        AddressingMode::ZP => adj_cycles!(mb, 1),
-        AddressingMode::ZPX => adj_cycles!(mb, 1),
-        AddressingMode::Abs => adj_cycles!(mb, 1),
-        AddressingMode::AbsX => adj_cycles!(mb, 2),
-        _ => {}
-    };
-    match reg!(get addr_mode, mb) {
-        AddressingMode::Accum => reg!(set acc, mb, res),
-        _ => write(mb, res),
+    match mb.cpu().state.addr_mode {
+        AddressingMode::Accum => mb.cpu_mut().state.acc = res,
+        _ => write_rmw(mb, op, res),
     }
 });
 
 //region Branch instructions
 // BPL BMI BVC BVS BCC BCS BEQ BNE
+
+/// Jump to a branch's target, which takes an extra cycle
+fn take_branch<T: WithCpu>(mb: &mut T) {
+    let cpu = mb.cpu_mut();
+    cpu.cycles += 1;
+    cpu.state.pc = cpu.state.addr;
+}
 op_fn!(op_bpl, mb, {
-    if reg!(get status, mb).contains(Status::NEGATIVE) {
+    if mb.cpu().state.status.contains(Status::NEGATIVE) {
         return;
     }
-    adj_cycles!(mb, 1);
-    reg!(set pc, mb, reg!(get addr, mb));
+    take_branch(mb);
 });
 op_fn!(op_bmi, mb, {
-    if !reg!(get status, mb).contains(Status::NEGATIVE) {
+    if !mb.cpu().state.status.contains(Status::NEGATIVE) {
         return;
     }
-    adj_cycles!(mb, 1);
-    reg!(set pc, mb, reg!(get addr, mb));
+    take_branch(mb);
 });
 op_fn!(op_bvc, mb, {
-    if reg!(get status, mb).contains(Status::OVERFLOW) {
+    if mb.cpu().state.status.contains(Status::OVERFLOW) {
         return;
     }
-    adj_cycles!(mb, 1);
-    reg!(set pc, mb, reg!(get addr, mb));
+    take_branch(mb);
 });
 op_fn!(op_bvs, mb, {
-    if !reg!(get status, mb).contains(Status::OVERFLOW) {
+    if !mb.cpu().state.status.contains(Status::OVERFLOW) {
         return;
     }
-    adj_cycles!(mb, 1);
-    reg!(set pc, mb, reg!(get addr, mb));
+    take_branch(mb);
 });
 op_fn!(op_bcc, mb, {
-    if reg!(get status, mb).contains(Status::CARRY) {
+    if mb.cpu().state.status.contains(Status::CARRY) {
         return;
     }
-    adj_cycles!(mb, 1);
-    reg!(set pc, mb, reg!(get addr, mb));
+    take_branch(mb);
 });
 op_fn!(op_bcs, mb, {
-    if !reg!(get status, mb).contains(Status::CARRY) {
+    if !mb.cpu().state.status.contains(Status::CARRY) {
         return;
     }
-    adj_cycles!(mb, 1);
-    reg!(set pc, mb, reg!(get addr, mb));
+    take_branch(mb);
 });
 op_fn!(op_beq, mb, {
-    if !reg!(get status, mb).contains(Status::ZERO) {
+    if !mb.cpu().state.status.contains(Status::ZERO) {
         return;
     }
-    adj_cycles!(mb, 1);
-    reg!(set pc, mb, reg!(get addr, mb));
+    take_branch(mb);
 });
 op_fn!(op_bne, mb, {
-    if reg!(get status, mb).contains(Status::ZERO) {
+    if mb.cpu().state.status.contains(Status::ZERO) {
         return;
     }
-    adj_cycles!(mb, 1);
-    reg!(set pc, mb, reg!(get addr, mb));
+    take_branch(mb);
 });
 //endregion
 op_fn!(op_brk, mb, {
-    let addr_bytes = reg!(get pc, mb).to_le_bytes();
-    push_stack(mb, addr_bytes[1]);
-    push_stack(mb, addr_bytes[0]);
+    push_stack16(mb, mb.cpu().state.pc);
     set_flag(mb, Status::BREAK);
     set_flag(mb, Status::UNUSED);
-    let status = reg!(get status, mb).bits();
+    let status = mb.cpu().state.status.bits();
     push_stack(mb, status);
-    let addr_fst = bus!(read mb, 0xFFFE);
-    let addr_snd = bus!(read mb, 0xFFFF);
-    reg!(set pc, mb, bytes_to_addr!(addr_fst, addr_snd));
+    mb.cpu_mut().state.pc = read_vector(mb, Vector::Irq);
 });
 
 //region Compare functions
 // CMP CPX CPY
 op_fn!(op_cmp, mb, {
     let data = read(mb);
-    let res = Wrapping(reg!(get acc, mb)) - Wrapping(data);
-    let acc = reg!(get acc, mb);
+    let res = Wrapping(mb.cpu().state.acc) - Wrapping(data);
+    let acc = mb.cpu().state.acc;
     mb.cpu_mut().state.status.set(Status::CARRY, acc >= data);
     check_zero(mb, res.0);
     check_negative(mb, res.0);
 });
 op_fn!(op_cpx, mb, {
     let data = read(mb);
-    let res = Wrapping(reg!(get x, mb)) - Wrapping(data);
-    let x = reg!(get x, mb);
+    let res = Wrapping(mb.cpu().state.x) - Wrapping(data);
+    let x = mb.cpu().state.x;
     mb.cpu_mut().state.status.set(Status::CARRY, x >= data);
     check_zero(mb, res.0);
     check_negative(mb, res.0);
 });
 op_fn!(op_cpy, mb, {
     let data = read(mb);
-    let res = Wrapping(reg!(get y, mb)) - Wrapping(data);
-    let y = reg!(get y, mb);
+    let res = Wrapping(mb.cpu().state.y) - Wrapping(data);
+    let y = mb.cpu().state.y;
     mb.cpu_mut().state.status.set(Status::CARRY, y >= data);
     check_zero(mb, res.0);
     check_negative(mb, res.0);
@@ -640,24 +821,18 @@ op_fn!(op_cpy, mb, {
 //region Memory functions
 // DEC INC LSR ROL ROR
 op_fn!(op_dec, mb, {
-    let op = (Wrapping(read(mb)) - Wrapping(1)).0;
-    adj_cycles!(mb, 1);
-    write(mb, op);
+    let original = read(mb);
+    let op = (Wrapping(original) - Wrapping(1)).0;
+    write_rmw(mb, original, op);
     check_zero(mb, op);
     check_negative(mb, op);
-    if reg!(get addr_mode, mb) == AddressingMode::AbsX {
-        adj_cycles!(mb, 1);
-    }
 });
 op_fn!(op_inc, mb, {
-    let op = (Wrapping(read(mb)) + Wrapping(1)).0;
-    adj_cycles!(mb, 1);
-    write(mb, op);
+    let original = read(mb);
+    let op = (Wrapping(original) + Wrapping(1)).0;
+    write_rmw(mb, original, op);
     check_zero(mb, op);
     check_negative(mb, op);
-    if reg!(get addr_mode, mb) == AddressingMode::AbsX {
-        adj_cycles!(mb, 1);
-    }
 });
 op_fn!(op_lsr, mb, {
     // I'm doing a bit of a trick here
@@ -665,7 +840,8 @@ op_fn!(op_lsr, mb, {
     // difference between (u16 << 7) and (u8 >> 1). But by casting
     // to u16 and doing it 'backwards', we preserve the lopped off
     // bit so that we can use it to set the carry bit
-    let data = u16::from(read(mb)) << 7;
+    let original = read(mb);
+    let data = u16::from(original) << 7;
     // we want the last bit for the carry -----v
     mb.cpu_mut()
         .state
@@ -677,27 +853,17 @@ op_fn!(op_lsr, mb, {
     check_negative(mb, data);
     // Finally, since this _could_ go to the accumulator, we need to
     // check for that addressing mode
-    match reg!(get addr_mode, mb) {
-        AddressingMode::ZP => {
-            write(mb, data);
-        }
-        AddressingMode::Accum => reg!(set acc, mb, data),
-        _ => write(mb, data),
-    };
-    // cycle count correction
-    match reg!(get addr_mode, mb) {
-        AddressingMode::Abs => adj_cycles!(mb, 1),
-        AddressingMode::AbsX => adj_cycles!(mb, 2),
-        AddressingMode::ZP => adj_cycles!(mb, 1),
-        AddressingMode::ZPX => adj_cycles!(mb, 1),
-        _ => {}
+    match mb.cpu().state.addr_mode {
+        AddressingMode::Accum => mb.cpu_mut().state.acc = data,
+        _ => write_rmw(mb, original, data),
     };
 });
 op_fn!(op_ror, mb, {
     // See my notes on the LSR instruction, I do a similar trick
     // here (for similar reasons)
-    let data = u16::from(read(mb)) << 7
-        | if reg!(get status, mb).contains(Status::CARRY) {
+    let original = read(mb);
+    let data = u16::from(original) << 7
+        | if mb.cpu().state.status.contains(Status::CARRY) {
             0x80_00
         } else {
             0x0
@@ -710,22 +876,15 @@ op_fn!(op_ror, mb, {
     check_zero(mb, data);
     check_negative(mb, data);
     // Even the caveat on addressing is the same
-    match reg!(get addr_mode, mb) {
-        AddressingMode::Accum => reg!(set acc, mb, data),
-        _ => write(mb, data),
-    };
-    // cycle count correction
-    match reg!(get addr_mode, mb) {
-        AddressingMode::Abs => adj_cycles!(mb, 1),
-        AddressingMode::AbsX => adj_cycles!(mb, 2),
-        AddressingMode::ZP => adj_cycles!(mb, 1),
-        AddressingMode::ZPX => adj_cycles!(mb, 1),
-        _ => {}
+    match mb.cpu().state.addr_mode {
+        AddressingMode::Accum => mb.cpu_mut().state.acc = data,
+        _ => write_rmw(mb, original, data),
     };
 });
 op_fn!(op_rol, mb, {
-    let data = (u16::from(read(mb)) << 1)
-        | if reg!(get status, mb).contains(Status::CARRY) {
+    let original = read(mb);
+    let data = (u16::from(original) << 1)
+        | if mb.cpu().state.status.contains(Status::CARRY) {
             0x01
         } else {
             0x00
@@ -737,17 +896,9 @@ op_fn!(op_rol, mb, {
     let data: u8 = (data & 0xFF) as u8;
     check_zero(mb, data);
     check_negative(mb, data);
-    match reg!(get addr_mode, mb) {
-        AddressingMode::Accum => reg!(set acc, mb, data),
-        _ => write(mb, data),
-    };
-    // cycle count correction
-    match reg!(get addr_mode, mb) {
-        AddressingMode::Abs => adj_cycles!(mb, 1),
-        AddressingMode::AbsX => adj_cycles!(mb, 2),
-        AddressingMode::ZP => adj_cycles!(mb, 1),
-        AddressingMode::ZPX => adj_cycles!(mb, 1),
-        _ => {}
+    match mb.cpu().state.addr_mode {
+        AddressingMode::Accum => mb.cpu_mut().state.acc = data,
+        _ => write_rmw(mb, original, data),
     };
 });
 //endregion
@@ -766,161 +917,461 @@ op_fn!(op_sed, mb, set_flag(mb, Status::DECIMAL));
 //region Jumps
 // JMP JSR RTI RTS
 op_fn!(op_jmp, mb, {
-    let addr_mode = reg!(get addr_mode, mb);
-    if addr_mode != AddressingMode::Abs && addr_mode != AddressingMode::AbsInd {
-        adj_cycles!(mb, 1);
-    }
-    reg!(set pc, mb, reg!(get addr, mb));
+    mb.cpu_mut().state.pc = mb.cpu().state.addr;
 });
 op_fn!(op_jsr, mb, {
-    if reg!(get addr_mode, mb) != AddressingMode::Abs {
-        adj_cycles!(mb, 1);
-    }
-    let addr_bytes = (reg!(get pc, mb) - 1).to_le_bytes();
-    push_stack(mb, addr_bytes[1]);
-    push_stack(mb, addr_bytes[0]);
-    reg!(set pc, mb, reg!(get addr, mb));
-    adj_cycles!(mb, 1);
+    push_stack16(mb, mb.cpu().state.pc.wrapping_sub(1));
+    mb.cpu_mut().state.pc = mb.cpu().state.addr;
 });
 op_fn!(op_rti, mb, {
     let flags = pop_stack(mb);
-    reg!(set status, mb, Status::from_bits_truncate(flags) | Status::UNUSED);
-    let fst = pop_stack(mb);
-    let snd = pop_stack(mb);
-    reg!(set pc, mb, bytes_to_addr!(fst, snd));
-    adj_cycles!(mb, 1);
+    mb.cpu_mut().state.status = Status::from_bits_truncate(flags) | Status::UNUSED;
+    mb.cpu_mut().state.pc = pop_stack16(mb);
 });
 op_fn!(op_rts, mb, {
-    let fst = pop_stack(mb);
-    let snd = pop_stack(mb);
-    reg!(set pc, mb, bytes_to_addr!(fst, snd).wrapping_add(1));
-    adj_cycles!(mb, 2);
+    mb.cpu_mut().state.pc = pop_stack16(mb).wrapping_add(1);
 });
 //endregion
 
 //region Loads
 op_fn!(op_lda, mb, {
-    reg!(set acc, mb, read(mb));
-    check_zero(mb, reg!(get acc, mb));
-    check_negative(mb, reg!(get acc, mb));
+    mb.cpu_mut().state.acc = read(mb);
+    check_zero(mb, mb.cpu().state.acc);
+    check_negative(mb, mb.cpu().state.acc);
 });
 op_fn!(op_ldx, mb, {
-    reg!(set x, mb, read(mb));
-    check_zero(mb, reg!(get x, mb));
-    check_negative(mb, reg!(get x, mb));
-
-    // cycle count correction
-    match reg!(get addr_mode, mb) {
-        AddressingMode::ZPX => adj_cycles!(mb, 1),
-        AddressingMode::ZPY => adj_cycles!(mb, 1),
-        _ => {}
-    };
+    mb.cpu_mut().state.x = read(mb);
+    check_zero(mb, mb.cpu().state.x);
+    check_negative(mb, mb.cpu().state.x);
 });
 op_fn!(op_ldy, mb, {
-    reg!(set y, mb, read(mb));
-    check_zero(mb, reg!(get y, mb));
-    check_negative(mb, reg!(get y, mb));
+    mb.cpu_mut().state.y = read(mb);
+    check_zero(mb, mb.cpu().state.y);
+    check_negative(mb, mb.cpu().state.y);
 });
 //endregion
-op_fn!(op_nop, _mb, {
-    // no operation
+op_fn!(op_nop, mb, {
+    // The unofficial NOPs with an operand still read it, like a LDA would,
+    // which can set off a register's read side effects
+    if mb.cpu().state.addr_mode != AddressingMode::Impl {
+        read(mb);
+    }
 });
 
 //region Register instructions
 op_fn!(op_tax, mb, {
-    reg!(set x, mb, reg!(get acc, mb));
-    check_zero(mb, reg!(get x, mb));
-    check_negative(mb, reg!(get x, mb));
+    mb.cpu_mut().state.x = mb.cpu().state.acc;
+    check_zero(mb, mb.cpu().state.x);
+    check_negative(mb, mb.cpu().state.x);
 });
 op_fn!(op_txa, mb, {
-    reg!(set acc, mb, reg!(get x, mb));
-    check_zero(mb, reg!(get acc, mb));
-    check_negative(mb, reg!(get acc, mb));
+    mb.cpu_mut().state.acc = mb.cpu().state.x;
+    check_zero(mb, mb.cpu().state.acc);
+    check_negative(mb, mb.cpu().state.acc);
 });
 op_fn!(op_tay, mb, {
-    reg!(set y, mb, reg!(get acc, mb));
-    check_zero(mb, reg!(get y, mb));
-    check_negative(mb, reg!(get y, mb));
+    mb.cpu_mut().state.y = mb.cpu().state.acc;
+    check_zero(mb, mb.cpu().state.y);
+    check_negative(mb, mb.cpu().state.y);
 });
 op_fn!(op_tya, mb, {
-    reg!(set acc, mb, reg!(get y, mb));
-    check_zero(mb, reg!(get acc, mb));
-    check_negative(mb, reg!(get acc, mb));
+    mb.cpu_mut().state.acc = mb.cpu().state.y;
+    check_zero(mb, mb.cpu().state.acc);
+    check_negative(mb, mb.cpu().state.acc);
 });
 op_fn!(op_inx, mb, {
-    reg!(set x, mb, (Wrapping(reg!(get x, mb)) + Wrapping(1)).0);
-    check_zero(mb, reg!(get x, mb));
-    check_negative(mb, reg!(get x, mb));
+    mb.cpu_mut().state.x = (Wrapping(mb.cpu().state.x) + Wrapping(1)).0;
+    check_zero(mb, mb.cpu().state.x);
+    check_negative(mb, mb.cpu().state.x);
 });
 op_fn!(op_dex, mb, {
-    reg!(set x, mb, (Wrapping(reg!(get x, mb)) - Wrapping(1)).0);
-    check_zero(mb, reg!(get x, mb));
-    check_negative(mb, reg!(get x, mb));
+    mb.cpu_mut().state.x = (Wrapping(mb.cpu().state.x) - Wrapping(1)).0;
+    check_zero(mb, mb.cpu().state.x);
+    check_negative(mb, mb.cpu().state.x);
 });
 op_fn!(op_iny, mb, {
-    reg!(set y, mb, (Wrapping(reg!(get y, mb)) + Wrapping(1)).0);
-    check_zero(mb, reg!(get y, mb));
-    check_negative(mb, reg!(get y, mb));
+    mb.cpu_mut().state.y = (Wrapping(mb.cpu().state.y) + Wrapping(1)).0;
+    check_zero(mb, mb.cpu().state.y);
+    check_negative(mb, mb.cpu().state.y);
 });
 op_fn!(op_dey, mb, {
-    reg!(set y, mb, (Wrapping(reg!(get y, mb)) - Wrapping(1)).0);
-    check_zero(mb, reg!(get y, mb));
-    check_negative(mb, reg!(get y, mb));
+    mb.cpu_mut().state.y = (Wrapping(mb.cpu().state.y) - Wrapping(1)).0;
+    check_zero(mb, mb.cpu().state.y);
+    check_negative(mb, mb.cpu().state.y);
 });
 //endregion
 
 //region Storage instruction
 op_fn!(op_sta, mb, {
-    write(mb, reg!(get acc, mb));
-    if mb.cpu().oops_cycle {
-        // undo the oops cycle since this instruction doesn't suffer from it
-        adj_cycles!(mb, -1i32);
-    }
-    // Cycle count corrections
-    match reg!(get addr_mode, mb) {
-        AddressingMode::IndY => adj_cycles!(mb, 1),
-        AddressingMode::AbsX => adj_cycles!(mb, 1),
-        AddressingMode::AbsY => adj_cycles!(mb, 1),
-        _ => {}
-    };
+    write(mb, mb.cpu().state.acc);
 });
 op_fn!(op_stx, mb, {
-    write(mb, reg!(get x, mb));
-
-    // cycle count correction
-    match reg!(get addr_mode, mb) {
-        AddressingMode::ZPY => adj_cycles!(mb, 1),
-        _ => {}
-    };
+    write(mb, mb.cpu().state.x);
 });
 op_fn!(op_sty, mb, {
-    write(mb, reg!(get y, mb));
+    write(mb, mb.cpu().state.y);
 });
 //endregion
 
 //region Stack instructions
 op_fn!(op_txs, mb, {
-    reg!(set stack, mb, reg!(get x, mb));
+    mb.cpu_mut().state.stack = mb.cpu().state.x;
 });
 op_fn!(op_tsx, mb, {
-    reg!(set x, mb, reg!(get stack, mb));
-    check_zero(mb, reg!(get x, mb));
-    check_negative(mb, reg!(get x, mb));
+    mb.cpu_mut().state.x = mb.cpu().state.stack;
+    check_zero(mb, mb.cpu().state.x);
+    check_negative(mb, mb.cpu().state.x);
 });
 op_fn!(op_pha, mb, {
-    push_stack(mb, reg!(get acc, mb));
+    push_stack(mb, mb.cpu().state.acc);
 });
 op_fn!(op_pla, mb, {
-    reg!(set acc, mb, pop_stack(mb));
-    check_zero(mb, reg!(get acc, mb));
-    check_negative(mb, reg!(get acc, mb));
-    adj_cycles!(mb, 1);
+    mb.cpu_mut().state.acc = pop_stack(mb);
+    check_zero(mb, mb.cpu().state.acc);
+    check_negative(mb, mb.cpu().state.acc);
 });
 op_fn!(op_php, mb, {
-    push_stack(mb, reg!(get status, mb).bits() | 0x30)
+    push_stack(mb, mb.cpu().state.status.bits() | 0x30)
 });
 op_fn!(op_plp, mb, {
-    reg!(set status, mb, Status::from_bits_truncate((pop_stack(mb) & 0xEF) | 0x20));
-    adj_cycles!(mb, 1);
+    mb.cpu_mut().state.status = Status::from_bits_truncate((pop_stack(mb) & 0xEF) | 0x20);
 });
 //endregion
+
+#[cfg(test)]
+mod tests {
+    use super::super::AccuracyMode;
+    use super::super::TestHarnessMotherboard;
+    use super::*;
+    use alloc::{vec, vec::Vec};
+
+    /// A `TestHarnessMotherboard` that logs every read and write
+    struct BusLog {
+        inner: TestHarnessMotherboard,
+        reads: Vec<u16>,
+        writes: Vec<(u16, u8)>,
+        accuracy: AccuracyMode,
+    }
+
+    impl BusLog {
+        fn with_program(program: &[u8]) -> BusLog {
+            BusLog {
+                inner: TestHarnessMotherboard::with_program(0x0400, program),
+                reads: Vec::new(),
+                writes: Vec::new(),
+                accuracy: AccuracyMode::Strict,
+            }
+        }
+    }
+
+    impl Motherboard for BusLog {
+        fn read(&mut self, addr: u16) -> u8 {
+            self.reads.push(addr);
+            self.inner.read(addr)
+        }
+
+        fn peek(&self, addr: u16) -> Option<u8> {
+            self.inner.peek(addr)
+        }
+
+        fn write(&mut self, addr: u16, data: u8) {
+            self.writes.push((addr, data));
+            self.inner.write(addr, data);
+        }
+
+        fn accuracy(&self) -> AccuracyMode {
+            self.accuracy
+        }
+    }
+
+    impl WithCpu for BusLog {
+        fn cpu(&self) -> &Cpu6502 {
+            &self.inner.cpu
+        }
+
+        fn cpu_mut(&mut self) -> &mut Cpu6502 {
+            &mut self.inner.cpu
+        }
+    }
+
+    #[test]
+    fn rmw_instructions_write_the_original_value_first() {
+        // (opcode, result) for each instruction, on $41 at $10 with carry clear
+        let cases = [
+            (0x06, 0x82), // ASL $10
+            (0x46, 0x20), // LSR $10
+            (0x26, 0x82), // ROL $10
+            (0x66, 0x20), // ROR $10
+            (0xE6, 0x42), // INC $10
+            (0xC6, 0x40), // DEC $10
+        ];
+        for (opcode, result) in cases {
+            let mut mb = BusLog::with_program(&[opcode, 0x10]);
+            mb.inner.load(0x0010, &[0x41]);
+            mb.cpu_mut().state.status.remove(Status::CARRY);
+            exec(&mut mb);
+            while !tick(&mut mb) {}
+            assert_eq!(
+                mb.writes,
+                vec![(0x0010, 0x41), (0x0010, result)],
+                "opcode {:02X}",
+                opcode
+            );
+        }
+    }
+
+    #[test]
+    fn rmw_on_the_accumulator_doesnt_write() {
+        // ASL A
+        let mut mb = BusLog::with_program(&[0x0A]);
+        exec(&mut mb);
+        while !tick(&mut mb) {}
+        assert!(mb.writes.is_empty());
+    }
+
+    /// Run one instruction, and return the reads it made outside of the
+    /// program and the zero page
+    fn data_reads(mb: &mut BusLog) -> Vec<u16> {
+        mb.reads.clear();
+        exec(mb);
+        while !tick(mb) {}
+        mb.reads
+            .iter()
+            .copied()
+            .filter(|&addr| addr >= 0x1000)
+            .collect()
+    }
+
+    #[test]
+    fn indexed_reads_only_dummy_read_across_pages() {
+        // LDA $20F0,X; LDA $20F0,X
+        let mut mb = BusLog::with_program(&[0xBD, 0xF0, 0x20, 0xBD, 0xF0, 0x20]);
+        mb.cpu_mut().state.x = 0x01;
+        assert_eq!(data_reads(&mut mb), vec![0x20F1]);
+        mb.cpu_mut().state.x = 0x20;
+        assert_eq!(data_reads(&mut mb), vec![0x2010, 0x2110]);
+    }
+
+    #[test]
+    fn fast_mode_skips_dummy_accesses() {
+        // LDA $20F0,X; INC $10
+        let mut mb = BusLog::with_program(&[0xBD, 0xF0, 0x20, 0xE6, 0x10]);
+        mb.accuracy = AccuracyMode::Fast;
+        mb.inner.load(0x0010, &[0x41]);
+        mb.cpu_mut().state.x = 0x20;
+        assert_eq!(data_reads(&mut mb), vec![0x2110]);
+        let cycles = mb.cpu().state.tot_cycles;
+        exec(&mut mb);
+        while !tick(&mut mb) {}
+        assert_eq!(mb.writes, vec![(0x0010, 0x42)]);
+        // the cycles are still spent
+        assert_eq!(mb.cpu().state.tot_cycles - cycles, 5);
+    }
+
+    #[test]
+    fn indirect_indexed_reads_dummy_read_across_pages() {
+        // LDA ($10),Y
+        let mut mb = BusLog::with_program(&[0xB1, 0x10]);
+        mb.inner.load(0x0010, &[0xF0, 0x20]);
+        mb.cpu_mut().state.y = 0x20;
+        assert_eq!(data_reads(&mut mb), vec![0x2010, 0x2110]);
+    }
+
+    #[test]
+    fn disassembles_with_the_opcode_tables_mnemonics() {
+        // LAX $10; LDA #$42
+        let mut mb = TestHarnessMotherboard::with_program(0x0400, &[0xA7, 0x10, 0xA9, 0x42]);
+        while !tick(&mut mb) {}
+        assert!(debug(&mut mb).starts_with("0400  A7 10     LAX $10 = 00"));
+        while !tick(&mut mb) {}
+        assert!(debug(&mut mb).starts_with("0402  A9 42     LDA #$42"));
+    }
+
+    #[test]
+    fn unofficial_nops_read_their_operand() {
+        // NOP $2002; NOP $20F0,X; NOP #$12; NOP
+        let mut mb = BusLog::with_program(&[0x0C, 0x02, 0x20, 0x1C, 0xF0, 0x20, 0x80, 0x12, 0xEA]);
+        mb.cpu_mut().state.x = 0x20;
+        assert_eq!(data_reads(&mut mb), vec![0x2002]);
+        // across a page, this dummy reads like any other indexed read
+        assert_eq!(data_reads(&mut mb), vec![0x2010, 0x2110]);
+        assert_eq!(data_reads(&mut mb), vec![]);
+        assert_eq!(data_reads(&mut mb), vec![]);
+    }
+
+    /// Run one instruction, and return how many cycles it took
+    fn step_cycles(mb: &mut TestHarnessMotherboard) -> u64 {
+        let start = mb.cpu.state.tot_cycles;
+        mb.step();
+        mb.cpu.state.tot_cycles - start
+    }
+
+    #[test]
+    fn takes_as_many_cycles_as_the_opcode_table_says() {
+        let mut wrong = vec![];
+        for opcode in 0..=0xFFu8 {
+            let expected = match opcodes::decode(opcode) {
+                // branches depend on the flags
+                Some(op) if op.addr_mode != AddressingMode::Rel => op,
+                _ => continue,
+            };
+            // operands of $0000 don't cross any pages
+            let mut mb = TestHarnessMotherboard::with_program(0x0400, &[opcode, 0x00, 0x00]);
+            while !tick(&mut mb) {}
+            let cycles = step_cycles(&mut mb);
+            if cycles != u64::from(expected.cycles) {
+                wrong.push((opcode, expected.mnemonic, cycles));
+            }
+        }
+        assert_eq!(wrong, vec![]);
+    }
+
+    #[test]
+    fn interrupts_take_as_long_as_brk() {
+        // NOP; NOP
+        let mut mb = TestHarnessMotherboard::with_program(0x0400, &[0xEA, 0xEA]);
+        mb.cpu.override_vector(Vector::Nmi, Some(0x0401));
+        while !tick(&mut mb) {}
+        trigger_nmi(&mut mb);
+        // the NMI, then the second NOP
+        assert_eq!(step_cycles(&mut mb), 7 + 2);
+        assert_eq!(mb.cpu.state.pc, 0x0402);
+    }
+
+    #[test]
+    fn adds_the_extra_cycles_the_table_leaves_out() {
+        // STA $20F0,X; BNE +0; BEQ +0
+        let program = [0x9D, 0xF0, 0x20, 0xD0, 0x00, 0xF0, 0x00];
+        let mut mb = TestHarnessMotherboard::with_program(0x0400, &program);
+        mb.cpu.state.x = 0x20;
+        mb.cpu.state.status.remove(Status::ZERO);
+        while !tick(&mut mb) {}
+        // stores already count the oops cycle, across a page or not
+        assert_eq!(step_cycles(&mut mb), 5);
+        // a taken branch takes one more than one that isn't
+        assert_eq!(step_cycles(&mut mb), 3);
+        assert_eq!(step_cycles(&mut mb), 2);
+    }
+
+    #[test]
+    fn indexing_wraps_around_the_end_of_memory() {
+        // LDA $FFF0,X; LDA $FFFF,Y; LDA ($E0),Y
+        let program = [0xBD, 0xF0, 0xFF, 0xB9, 0xFF, 0xFF, 0xB1, 0xE0];
+        let mut mb = TestHarnessMotherboard::with_program(0x0400, &program);
+        mb.load(0x0000, &[0x42, 0x43]);
+        mb.load(0x0010, &[0x44]);
+        mb.load(0x00E0, &[0xF0, 0xFF]);
+        mb.cpu.state.x = 0x11;
+        mb.cpu.state.y = 0x01;
+        // finish the reset first
+        while !tick(&mut mb) {}
+        // each of these carries out of $FFxx, which takes the oops cycle
+        assert_eq!(step_cycles(&mut mb), 5);
+        assert_eq!(mb.cpu.state.acc, 0x43, "LDA $FFF0,X should read $0001");
+        assert_eq!(step_cycles(&mut mb), 5);
+        assert_eq!(mb.cpu.state.acc, 0x42, "LDA $FFFF,Y should read $0000");
+        mb.cpu.state.y = 0x20;
+        assert_eq!(step_cycles(&mut mb), 6);
+        assert_eq!(mb.cpu.state.acc, 0x44, "LDA ($E0),Y should read $0010");
+    }
+
+    #[test]
+    fn indexed_stores_always_dummy_read() {
+        // STA $2007,X
+        let mut mb = BusLog::with_program(&[0x9D, 0x07, 0x20]);
+        mb.cpu_mut().state.x = 0x00;
+        assert_eq!(data_reads(&mut mb), vec![0x2007]);
+        assert_eq!(mb.writes, vec![(0x2007, mb.cpu().state.acc)]);
+    }
+
+    #[test]
+    fn indexed_rmw_reads_twice() {
+        // INC $2000,X
+        let mut mb = BusLog::with_program(&[0xFE, 0x00, 0x20]);
+        mb.cpu_mut().state.x = 0x07;
+        assert_eq!(data_reads(&mut mb), vec![0x2007, 0x2007]);
+    }
+
+    #[test]
+    fn indirect_jumps_wrap_within_the_page() {
+        // JMP ($02FF)
+        let mut mb = TestHarnessMotherboard::with_program(0x0400, &[0x6C, 0xFF, 0x02]);
+        mb.load(0x0200, &[0x12]);
+        mb.load(0x02FF, &[0x34, 0x56]);
+        mb.step();
+        assert_eq!(mb.cpu.state.pc, 0x1234);
+    }
+
+    #[test]
+    fn indirect_jumps_read_both_bytes_in_the_page() {
+        // JMP ($0280)
+        let mut mb = TestHarnessMotherboard::with_program(0x0400, &[0x6C, 0x80, 0x02]);
+        mb.load(0x0280, &[0x34, 0x56]);
+        mb.step();
+        assert_eq!(mb.cpu.state.pc, 0x5634);
+    }
+
+    #[test]
+    fn pushes_words_high_byte_first() {
+        let mut mb = TestHarnessMotherboard::new();
+        mb.cpu.state.stack = 0xFD;
+        push_stack16(&mut mb, 0xABCD);
+        assert_eq!(&mb.ram[0x01FC..=0x01FD], &[0xCD, 0xAB]);
+        assert_eq!(mb.cpu.state.stack, 0xFB);
+        assert_eq!(pop_stack16(&mut mb), 0xABCD);
+        assert_eq!(mb.cpu.state.stack, 0xFD);
+    }
+
+    #[test]
+    fn pushes_wrap_around_page_1() {
+        let mut mb = TestHarnessMotherboard::new();
+        mb.cpu.state.stack = 0x00;
+        push_stack16(&mut mb, 0xABCD);
+        assert_eq!(mb.ram[0x0100], 0xAB);
+        assert_eq!(mb.ram[0x01FF], 0xCD);
+        // nothing should leak into the zero page or page 2
+        assert_eq!(mb.ram[0x00FF], 0x00);
+        assert_eq!(mb.ram[0x0200], 0x00);
+        assert_eq!(mb.cpu.state.stack, 0xFE);
+        assert_eq!(pop_stack16(&mut mb), 0xABCD);
+        assert_eq!(mb.cpu.state.stack, 0x00);
+    }
+
+    #[test]
+    fn pops_wrap_around_page_1() {
+        let mut mb = TestHarnessMotherboard::new();
+        mb.load(0x0100, &[0x34, 0x12]);
+        mb.cpu.state.stack = 0xFF;
+        assert_eq!(pop_stack16(&mut mb), 0x1234);
+        assert_eq!(mb.cpu.state.stack, 0x01);
+    }
+
+    #[test]
+    fn subroutines_return_across_the_stack_wrap() {
+        // JSR $0300, with an RTS waiting there
+        let mut mb = TestHarnessMotherboard::with_program(0x0400, &[0x20, 0x00, 0x03]);
+        mb.load(0x0300, &[0x60]);
+        mb.cpu.state.stack = 0x00;
+        mb.step();
+        assert_eq!(mb.cpu.state.pc, 0x0300);
+        assert_eq!(mb.cpu.state.stack, 0xFE);
+        mb.step();
+        assert_eq!(mb.cpu.state.pc, 0x0403);
+        assert_eq!(mb.cpu.state.stack, 0x00);
+    }
+
+    #[test]
+    fn interrupts_return_across_the_stack_wrap() {
+        // BRK, with an RTI waiting at the IRQ vector
+        let mut mb = TestHarnessMotherboard::with_program(0x0400, &[0x00, 0xEA, 0xEA]);
+        mb.load(0xFFFE, &[0x00, 0x03]);
+        mb.load(0x0300, &[0x40]);
+        mb.cpu.state.stack = 0x01;
+        mb.step();
+        assert_eq!(mb.cpu.state.pc, 0x0300);
+        assert_eq!(mb.cpu.state.stack, 0xFE);
+        mb.step();
+        assert_eq!(mb.cpu.state.stack, 0x01);
+        assert!((0x0401..=0x0402).contains(&mb.cpu.state.pc));
+    }
+}
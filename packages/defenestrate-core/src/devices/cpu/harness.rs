@@ -0,0 +1,160 @@
+//! A motherboard for testing the CPU on its own
+//!
+//! `TestHarnessMotherboard` maps 64k of RAM across the whole address space,
+//! with nothing else on the bus, so that unit tests can run a few opcodes
+//! without building a whole NES around a fake cartridge.
+
+use alloc::{vec, vec::Vec};
+
+use super::{exec, reset, tick, Cpu6502, Motherboard, WithCpu};
+
+/// A 6502 with 64k of flat RAM, and nothing else
+pub struct TestHarnessMotherboard {
+    pub cpu: Cpu6502,
+    pub ram: Vec<u8>,
+}
+
+impl TestHarnessMotherboard {
+    /// Create a harness with all of RAM set to 0
+    pub fn new() -> TestHarnessMotherboard {
+        TestHarnessMotherboard {
+            cpu: Cpu6502::new(),
+            ram: vec![0u8; 0x10000],
+        }
+    }
+
+    /// Create a harness with `program` loaded at `origin`, and reset into it
+    pub fn with_program(origin: u16, program: &[u8]) -> TestHarnessMotherboard {
+        let mut mb = TestHarnessMotherboard::new();
+        mb.load(origin, program);
+        mb.load(0xFFFC, &origin.to_le_bytes());
+        reset(&mut mb);
+        mb
+    }
+
+    /// Copy `data` into RAM starting at `addr`
+    ///
+    /// # Panics
+    ///
+    /// This panics if `data` runs off the end of memory.
+    pub fn load(&mut self, addr: u16, data: &[u8]) {
+        let start = addr as usize;
+        self.ram[start..start + data.len()].copy_from_slice(data);
+    }
+
+    /// Run one instruction, along with any interrupt before it
+    pub fn step(&mut self) {
+        exec(self);
+        while !tick(self) {}
+    }
+
+    /// Run `count` instructions
+    pub fn run(&mut self, count: usize) {
+        for _ in 0..count {
+            self.step();
+        }
+    }
+}
+
+impl Default for TestHarnessMotherboard {
+    fn default() -> TestHarnessMotherboard {
+        TestHarnessMotherboard::new()
+    }
+}
+
+impl Motherboard for TestHarnessMotherboard {
+    fn read(&mut self, addr: u16) -> u8 {
+        self.ram[addr as usize]
+    }
+
+    fn peek(&self, addr: u16) -> Option<u8> {
+        Some(self.ram[addr as usize])
+    }
+
+    fn write(&mut self, addr: u16, data: u8) {
+        self.ram[addr as usize] = data;
+    }
+}
+
+impl WithCpu for TestHarnessMotherboard {
+    fn cpu(&self) -> &Cpu6502 {
+        &self.cpu
+    }
+
+    fn cpu_mut(&mut self) -> &mut Cpu6502 {
+        &mut self.cpu
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::structs::Status;
+    use super::super::{trigger_irq, trigger_nmi, Vector};
+    use super::*;
+
+    #[test]
+    fn resets_into_the_program() {
+        // LDA #$42; STA $10
+        let mut mb = TestHarnessMotherboard::with_program(0x8000, &[0xA9, 0x42, 0x85, 0x10]);
+        assert_eq!(mb.cpu.state.pc, 0x8000);
+        mb.run(2);
+        assert_eq!(mb.ram[0x10], 0x42);
+        assert_eq!(mb.cpu.state.pc, 0x8004);
+    }
+
+    #[test]
+    fn jsr_wraps_around_the_end_of_memory() {
+        // JSR $1234, with its last byte at $FFFF
+        let mut mb = TestHarnessMotherboard::new();
+        mb.load(0xFFFD, &[0x20, 0x34, 0x12]);
+        mb.cpu.force_pc(0xFFFD);
+        let stack = mb.cpu.state.stack;
+        mb.step();
+        assert_eq!(mb.cpu.state.pc, 0x1234);
+        let return_addr = 0x0100 + stack.wrapping_sub(1) as usize;
+        assert_eq!(&mb.ram[return_addr..return_addr + 2], &[0xFF, 0xFF]);
+    }
+
+    #[test]
+    fn overrides_the_reset_vector() {
+        let mut mb = TestHarnessMotherboard::new();
+        mb.load(0xFFFC, &[0x00, 0x80]);
+        mb.cpu.override_vector(Vector::Reset, Some(0x1234));
+        reset(&mut mb);
+        assert_eq!(mb.cpu.state.pc, 0x1234);
+        mb.cpu.override_vector(Vector::Reset, None);
+        reset(&mut mb);
+        assert_eq!(mb.cpu.state.pc, 0x8000);
+    }
+
+    #[test]
+    fn overrides_interrupt_vectors() {
+        // CLI; NOP; NOP
+        let mut mb = TestHarnessMotherboard::with_program(0x0400, &[0x58, 0xEA, 0xEA]);
+        mb.cpu.override_vector(Vector::Nmi, Some(0x2000));
+        mb.cpu.override_vector(Vector::Irq, Some(0x3000));
+        mb.load(0x2000, &[0xEA]);
+        mb.load(0x3000, &[0xEA]);
+        mb.step();
+        trigger_nmi(&mut mb);
+        mb.step();
+        // the NOP at the handler ran, after the interrupt
+        assert_eq!(mb.cpu.state.pc, 0x2001);
+        // the NMI set I, so pretend its handler returned
+        mb.cpu.force_pc(0x0401);
+        mb.cpu.state.status.remove(Status::IRQ_DISABLE);
+        trigger_irq(&mut mb);
+        mb.step();
+        assert_eq!(mb.cpu.state.pc, 0x3001);
+    }
+
+    #[test]
+    fn overridden_vectors_take_as_long_as_reads() {
+        let mut read = TestHarnessMotherboard::new();
+        let mut forced = TestHarnessMotherboard::new();
+        forced.cpu.override_vector(Vector::Reset, Some(0x0000));
+        reset(&mut read);
+        reset(&mut forced);
+        assert_eq!(read.cpu.cycles, forced.cpu.cycles);
+    }
+}
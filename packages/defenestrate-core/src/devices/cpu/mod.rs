@@ -1,5 +1,9 @@
 mod cpu;
+mod harness;
+pub(crate) mod opcodes;
 pub mod structs;
-pub mod utils;
+pub(crate) mod utils;
 
 pub use self::cpu::*;
+pub use self::harness::TestHarnessMotherboard;
+pub use super::bus::{AccuracyMode, Motherboard};
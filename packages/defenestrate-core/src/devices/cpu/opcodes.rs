@@ -0,0 +1,455 @@
+//! The 6502's opcode table
+//!
+//! Everything the CPU knows about an opcode comes from the one table at the
+//! bottom of this file: the addressing mode and instruction it decodes to, the
+//! mnemonic to show for it, and how many cycles it takes. The table is checked
+//! at compile time, so an opcode can't be defined twice.
+//!
+//! Illegal opcodes are decoded with their addressing mode, so they read,
+//! advance the PC, and take as long as the real thing, but they run as NOPs.
+//! Opcodes that aren't in the table at all (the ones that jam a real 6502) are
+//! treated as 2-cycle, 1-byte NOPs.
+//!
+//! cf. https://wiki.nesdev.com/w/index.php/CPU_unofficial_opcodes
+
+use super::structs::{AddressingMode, Instruction};
+
+/// What an opcode decodes to
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub struct Opcode {
+    pub addr_mode: AddressingMode,
+    pub instr: Instruction,
+    /// The mnemonic to disassemble this as
+    ///
+    /// For illegal opcodes, this is the unofficial name of what the CPU really
+    /// does, rather than the NOP it runs as here.
+    pub mnemonic: &'static str,
+    /// How many cycles this takes on hardware, not counting the oops cycle for
+    /// crossing a page, or the extra cycles for taking a branch
+    pub cycles: u8,
+    /// Whether this is an illegal opcode, and only runs as a NOP
+    pub illegal: bool,
+}
+
+impl Opcode {
+    /// Whether indexing across a page costs this an extra cycle
+    ///
+    /// Only reads pay for it, since they can skip fixing up the high byte
+    /// when there's no carry. Stores and read-modify-writes always spend that
+    /// cycle, and it's already in their (longer) cycle counts.
+    pub fn has_oops_cycle(&self) -> bool {
+        matches!(
+            (self.addr_mode, self.cycles),
+            (AddressingMode::AbsX | AddressingMode::AbsY, 4) | (AddressingMode::IndY, 5)
+        )
+    }
+}
+
+/// What the opcodes that jam a real 6502 run as
+const JAM: Opcode = Opcode {
+    addr_mode: AddressingMode::Impl,
+    instr: Instruction::NOP,
+    mnemonic: "STP",
+    cycles: 2,
+    illegal: true,
+};
+
+/// Decode an opcode, if it's one the CPU knows about
+#[inline]
+pub fn decode(opcode: u8) -> Option<Opcode> {
+    OPCODES[opcode as usize]
+}
+
+/// The mnemonic to disassemble an opcode as
+///
+/// Illegal opcodes get the name of what they do on hardware, even though
+/// they run as NOPs here.
+pub fn mnemonic(opcode: u8) -> &'static str {
+    decode(opcode).unwrap_or(JAM).mnemonic
+}
+
+/// Decode an opcode to run
+///
+/// Illegal and unmapped opcodes come out as NOPs, and get logged.
+#[inline]
+pub fn decode_instruction(instr: u8) -> Opcode {
+    match decode(instr) {
+        Some(opcode) => {
+            if opcode.illegal {
+                log::debug!("Invalid opcode: {:02X} ({})", instr, opcode.mnemonic);
+            }
+            opcode
+        }
+        None => {
+            log::debug!("Unsupported opcode used: {:02X}", instr);
+            JAM
+        }
+    }
+}
+
+/// Build the opcode table from lines of `opcode => INSTR Mode cycles`
+///
+/// Illegal opcodes add `illegal "MNEMONIC"` to the end of the line.
+macro_rules! define_opcodes {
+    (@mnemonic $instr: ident) => {
+        stringify!($instr)
+    };
+    (@mnemonic $instr: ident $illegal: literal) => {
+        $illegal
+    };
+    (@illegal) => {
+        false
+    };
+    (@illegal $illegal: literal) => {
+        true
+    };
+    ($($opcode: literal => $instr: ident $mode: ident $cycles: literal $(illegal $illegal: literal)?,)*) => {
+        /// Every opcode the CPU knows about, indexed by the opcode
+        pub const OPCODES: [Option<Opcode>; 256] = {
+            let mut table = [None; 256];
+            $(
+                assert!(
+                    table[$opcode].is_none(),
+                    concat!("Opcode ", stringify!($opcode), " is defined twice")
+                );
+                table[$opcode] = Some(Opcode {
+                    addr_mode: AddressingMode::$mode,
+                    instr: Instruction::$instr,
+                    mnemonic: define_opcodes!(@mnemonic $instr $($illegal)?),
+                    cycles: $cycles,
+                    illegal: define_opcodes!(@illegal $($illegal)?),
+                });
+            )*
+            table
+        };
+    };
+}
+
+#[rustfmt::skip]
+define_opcodes! {
+    // 0x0_
+    0x00 => BRK Impl 7,
+    0x01 => ORA IndX 6,
+    0x03 => NOP IndX 8 illegal "SLO",
+    0x04 => NOP ZP 3,
+    0x05 => ORA ZP 3,
+    0x06 => ASL ZP 5,
+    0x07 => NOP ZP 5 illegal "SLO",
+    0x08 => PHP Impl 3,
+    0x09 => ORA Imm 2,
+    0x0A => ASL Accum 2,
+    0x0B => NOP Imm 2 illegal "ANC",
+    0x0C => NOP Abs 4,
+    0x0D => ORA Abs 4,
+    0x0E => ASL Abs 6,
+    0x0F => NOP Abs 6 illegal "SLO",
+
+    // 0x1_
+    0x10 => BPL Rel 2,
+    0x11 => ORA IndY 5,
+    0x13 => NOP IndY 8 illegal "SLO",
+    0x14 => NOP ZPX 4,
+    0x15 => ORA ZPX 4,
+    0x16 => ASL ZPX 6,
+    0x17 => NOP ZPX 6 illegal "SLO",
+    0x18 => CLC Impl 2,
+    0x19 => ORA AbsY 4,
+    0x1A => NOP Impl 2, // unofficial dup
+    0x1B => NOP AbsY 7 illegal "SLO",
+    0x1C => NOP AbsX 4,
+    0x1D => ORA AbsX 4,
+    0x1E => ASL AbsX 7,
+    0x1F => NOP AbsX 7 illegal "SLO",
+
+    // 0x2_
+    0x20 => JSR Abs 6,
+    0x21 => AND IndX 6,
+    0x23 => NOP IndX 8 illegal "RLA",
+    0x24 => BIT ZP 3,
+    0x25 => AND ZP 3,
+    0x26 => ROL ZP 5,
+    0x27 => NOP ZP 5 illegal "RLA",
+    0x28 => PLP Impl 4,
+    0x29 => AND Imm 2,
+    0x2A => ROL Accum 2,
+    0x2B => NOP Imm 2 illegal "ANC",
+    0x2C => BIT Abs 4,
+    0x2D => AND Abs 4,
+    0x2E => ROL Abs 6,
+    0x2F => NOP Abs 6 illegal "RLA",
+
+    // 0x3_
+    0x30 => BMI Rel 2,
+    0x31 => AND IndY 5,
+    0x33 => NOP IndY 8 illegal "RLA",
+    0x34 => NOP ZPX 4,
+    0x35 => AND ZPX 4,
+    0x36 => ROL ZPX 6,
+    0x37 => NOP ZPX 6 illegal "RLA",
+    0x38 => SEC Impl 2,
+    0x39 => AND AbsY 4,
+    0x3A => NOP Impl 2, // unofficial dup
+    0x3B => NOP AbsY 7 illegal "RLA",
+    0x3C => NOP AbsX 4,
+    0x3D => AND AbsX 4,
+    0x3E => ROL AbsX 7,
+    0x3F => NOP AbsX 7 illegal "RLA",
+
+    // 0x4_
+    0x40 => RTI Impl 6,
+    0x41 => EOR IndX 6,
+    0x43 => NOP IndX 8 illegal "SRE",
+    0x44 => NOP ZP 3,
+    0x45 => EOR ZP 3,
+    0x46 => LSR ZP 5,
+    0x47 => NOP ZP 5 illegal "SRE",
+    0x48 => PHA Impl 3,
+    0x49 => EOR Imm 2,
+    0x4A => LSR Accum 2,
+    0x4B => NOP Imm 2 illegal "ALR",
+    0x4C => JMP Abs 3,
+    0x4D => EOR Abs 4,
+    0x4E => LSR Abs 6,
+    0x4F => NOP Abs 6 illegal "SRE",
+
+    // 0x5_
+    0x50 => BVC Rel 2,
+    0x51 => EOR IndY 5,
+    0x53 => NOP IndY 8 illegal "SRE",
+    0x54 => NOP ZPX 4,
+    0x55 => EOR ZPX 4,
+    0x56 => LSR ZPX 6,
+    0x57 => NOP ZPX 6 illegal "SRE",
+    0x58 => CLI Impl 2,
+    0x59 => EOR AbsY 4,
+    0x5A => NOP Impl 2, // unofficial dup
+    0x5B => NOP AbsY 7 illegal "SRE",
+    0x5C => NOP AbsX 4,
+    0x5D => EOR AbsX 4,
+    0x5E => LSR AbsX 7,
+    0x5F => NOP AbsX 7 illegal "SRE",
+
+    // 0x6_
+    0x60 => RTS Impl 6,
+    0x61 => ADC IndX 6,
+    0x63 => NOP IndX 8 illegal "RRA",
+    0x64 => NOP ZP 3,
+    0x65 => ADC ZP 3,
+    0x66 => ROR ZP 5,
+    0x67 => NOP ZP 5 illegal "RRA",
+    0x68 => PLA Impl 4,
+    0x69 => ADC Imm 2,
+    0x6A => ROR Accum 2,
+    0x6B => NOP Imm 2 illegal "ARR",
+    0x6C => JMP AbsInd 5,
+    0x6D => ADC Abs 4,
+    0x6E => ROR Abs 6,
+    0x6F => NOP Abs 6 illegal "RRA",
+
+    // 0x7_
+    0x70 => BVS Rel 2,
+    0x71 => ADC IndY 5,
+    0x73 => NOP IndY 8 illegal "RRA",
+    0x74 => NOP ZPX 4,
+    0x75 => ADC ZPX 4,
+    0x76 => ROR ZPX 6,
+    0x77 => NOP ZPX 6 illegal "RRA",
+    0x78 => SEI Impl 2,
+    0x79 => ADC AbsY 4,
+    0x7A => NOP Impl 2, // unofficial dup
+    0x7B => NOP AbsY 7 illegal "RRA",
+    0x7C => NOP AbsX 4,
+    0x7D => ADC AbsX 4,
+    0x7E => ROR AbsX 7,
+    0x7F => NOP AbsX 7 illegal "RRA",
+
+    // 0x8_
+    0x80 => NOP Imm 2,
+    0x81 => STA IndX 6,
+    0x82 => NOP Imm 2,
+    0x83 => NOP IndX 6 illegal "SAX",
+    0x84 => STY ZP 3,
+    0x85 => STA ZP 3,
+    0x86 => STX ZP 3,
+    0x87 => NOP ZP 3 illegal "SAX",
+    0x88 => DEY Impl 2,
+    0x89 => NOP Imm 2,
+    0x8A => TXA Impl 2,
+    0x8B => NOP Imm 2 illegal "XAA",
+    0x8C => STY Abs 4,
+    0x8D => STA Abs 4,
+    0x8E => STX Abs 4,
+    0x8F => NOP Abs 4 illegal "SAX",
+
+    // 0x9_
+    0x90 => BCC Rel 2,
+    0x91 => STA IndY 6,
+    0x93 => NOP IndY 6 illegal "AHX",
+    0x94 => STY ZPX 4,
+    0x95 => STA ZPX 4,
+    0x96 => STX ZPY 4,
+    0x97 => NOP ZPY 4 illegal "SAX",
+    0x98 => TYA Impl 2,
+    0x99 => STA AbsY 5,
+    0x9A => TXS Impl 2,
+    0x9B => NOP AbsY 5 illegal "TAS",
+    0x9C => NOP AbsX 5 illegal "SHY",
+    0x9D => STA AbsX 5,
+    0x9E => NOP AbsY 5 illegal "SHX",
+    0x9F => NOP AbsY 5 illegal "AHX",
+
+    // 0xA_
+    0xA0 => LDY Imm 2,
+    0xA1 => LDA IndX 6,
+    0xA2 => LDX Imm 2,
+    0xA3 => NOP IndX 6 illegal "LAX",
+    0xA4 => LDY ZP 3,
+    0xA5 => LDA ZP 3,
+    0xA6 => LDX ZP 3,
+    0xA7 => NOP ZP 3 illegal "LAX",
+    0xA8 => TAY Impl 2,
+    0xA9 => LDA Imm 2,
+    0xAA => TAX Impl 2,
+    0xAB => NOP Imm 2 illegal "LAX",
+    0xAC => LDY Abs 4,
+    0xAD => LDA Abs 4,
+    0xAE => LDX Abs 4,
+    0xAF => NOP Abs 4 illegal "LAX",
+
+    // 0xB_
+    0xB0 => BCS Rel 2,
+    0xB1 => LDA IndY 5,
+    0xB3 => NOP IndY 5 illegal "LAX",
+    0xB4 => LDY ZPX 4,
+    0xB5 => LDA ZPX 4,
+    0xB6 => LDX ZPY 4,
+    0xB7 => NOP ZPY 4 illegal "LAX",
+    0xB8 => CLV Impl 2,
+    0xB9 => LDA AbsY 4,
+    0xBA => TSX Impl 2,
+    0xBB => NOP AbsY 4 illegal "LAS",
+    0xBC => LDY AbsX 4,
+    0xBD => LDA AbsX 4,
+    0xBE => LDX AbsY 4,
+    0xBF => NOP AbsY 4 illegal "LAX",
+
+    // 0xC_
+    0xC0 => CPY Imm 2,
+    0xC1 => CMP IndX 6,
+    0xC2 => NOP Imm 2,
+    0xC3 => NOP IndX 8 illegal "DCP",
+    0xC4 => CPY ZP 3,
+    0xC5 => CMP ZP 3,
+    0xC6 => DEC ZP 5,
+    0xC7 => NOP ZP 5 illegal "DCP",
+    0xC8 => INY Impl 2,
+    0xC9 => CMP Imm 2,
+    0xCA => DEX Impl 2,
+    0xCB => NOP Imm 2 illegal "AXS",
+    0xCC => CPY Abs 4,
+    0xCD => CMP Abs 4,
+    0xCE => DEC Abs 6,
+    0xCF => NOP Abs 6 illegal "DCP",
+
+    // 0xD_
+    0xD0 => BNE Rel 2,
+    0xD1 => CMP IndY 5,
+    0xD3 => NOP IndY 8 illegal "DCP",
+    0xD4 => NOP ZPX 4,
+    0xD5 => CMP ZPX 4,
+    0xD6 => DEC ZPX 6,
+    0xD7 => NOP ZPX 6 illegal "DCP",
+    0xD8 => CLD Impl 2,
+    0xD9 => CMP AbsY 4,
+    0xDA => NOP Impl 2, // unofficial dup
+    0xDB => NOP AbsY 7 illegal "DCP",
+    0xDC => NOP AbsX 4,
+    0xDD => CMP AbsX 4,
+    0xDE => DEC AbsX 7,
+    0xDF => NOP AbsX 7 illegal "DCP",
+
+    // 0xE_
+    0xE0 => CPX Imm 2,
+    0xE1 => SBC IndX 6,
+    0xE2 => NOP Imm 2,
+    0xE3 => NOP IndX 8 illegal "ISC",
+    0xE4 => CPX ZP 3,
+    0xE5 => SBC ZP 3,
+    0xE6 => INC ZP 5,
+    0xE7 => NOP ZP 5 illegal "ISC",
+    0xE8 => INX Impl 2,
+    0xE9 => SBC Imm 2,
+    0xEA => NOP Impl 2,
+    0xEB => SBC Imm 2,
+    0xEC => CPX Abs 4,
+    0xED => SBC Abs 4,
+    0xEE => INC Abs 6,
+    0xEF => NOP Abs 6 illegal "ISC",
+
+    // 0xF_
+    0xF0 => BEQ Rel 2,
+    0xF1 => SBC IndY 5,
+    0xF3 => NOP IndY 8 illegal "ISC",
+    0xF4 => NOP ZPX 4,
+    0xF5 => SBC ZPX 4,
+    0xF6 => INC ZPX 6,
+    0xF7 => NOP ZPX 6 illegal "ISC",
+    0xF8 => SED Impl 2,
+    0xF9 => SBC AbsY 4,
+    0xFA => NOP Impl 2, // unofficial dup
+    0xFB => NOP AbsY 7 illegal "ISC",
+    0xFC => NOP AbsX 4,
+    0xFD => SBC AbsX 4,
+    0xFE => INC AbsX 7,
+    0xFF => NOP AbsX 7 illegal "ISC",
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_instruction_correctly() {
+        let res = decode_instruction(0xEA);
+        assert_eq!(res.addr_mode, AddressingMode::Impl);
+        assert_eq!(res.instr, Instruction::NOP);
+    }
+
+    #[test]
+    fn decodes_illegal_opcode_correctly() {
+        let opcode = decode_instruction(0xFB);
+        assert_eq!(opcode.addr_mode, AddressingMode::AbsY);
+        assert_eq!(opcode.instr, Instruction::NOP);
+        assert!(opcode.illegal);
+        assert_eq!(opcode.mnemonic, "ISC");
+        assert_eq!(opcode.cycles, 7);
+    }
+
+    #[test]
+    fn decodes_unmapped_opcode() {
+        let res = decode_instruction(0xF2);
+        assert_eq!(res.addr_mode, AddressingMode::Impl);
+        assert_eq!(res.instr, Instruction::NOP);
+        assert_eq!(res.cycles, 2);
+        assert_eq!(decode(0xF2), None);
+        assert_eq!(mnemonic(0xF2), "STP");
+    }
+
+    #[test]
+    fn maps_everything_but_the_jams() {
+        let jams = [
+            0x02, 0x12, 0x22, 0x32, 0x42, 0x52, 0x62, 0x72, 0x92, 0xB2, 0xD2, 0xF2,
+        ];
+        assert!((0..256).filter(|&op| OPCODES[op].is_none()).eq(jams));
+        assert_eq!(decode(0xA9).unwrap().mnemonic, "LDA");
+    }
+
+    #[test]
+    fn only_indexed_reads_have_an_oops_cycle() {
+        let oops = |opcode| decode(opcode).unwrap().has_oops_cycle();
+        // LDA $nnnn,X; LDA ($nn),Y; LAX $nnnn,Y; NOP $nnnn,X
+        assert!(oops(0xBD) && oops(0xB1) && oops(0xBF) && oops(0x1C));
+        // STA $nnnn,X; STA ($nn),Y; INC $nnnn,X; LDA $nn,X
+        assert!(!oops(0x9D) && !oops(0x91) && !oops(0xFE) && !oops(0xB5));
+    }
+}
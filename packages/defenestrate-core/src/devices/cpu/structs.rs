@@ -4,6 +4,7 @@
 /// like debug formatters and, if taken at the end of a simulation cycle,
 /// serialization.
 #[derive(Debug, Eq, PartialEq, Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct CpuState {
     /// The Accumulator register
     pub acc: u8,
@@ -53,9 +54,9 @@ pub struct CpuState {
     ///
     /// # Note
     ///
-    /// This is allowed to overflow, as it's only used for debugging and test
-    /// comparison. It is not a part of core emulation.
-    pub tot_cycles: u32,
+    /// This is only used for debugging and test comparison. It is not a part
+    /// of core emulation.
+    pub tot_cycles: u64,
 
     /// The resolved address of the instruction
     pub addr: u16,
@@ -69,6 +70,7 @@ pub struct CpuState {
 
 // The addressing mode for the CPU
 #[derive(Debug, Eq, PartialEq, Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum AddressingMode {
     /// Zero-Page
     ZP,
@@ -108,6 +110,7 @@ pub enum AddressingMode {
 ///
 /// *depends on BCD flag, not currently supported
 #[derive(Debug, Eq, PartialEq, Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Instruction {
     /// ADd with Carry*
     ADC,
@@ -173,9 +176,9 @@ pub enum Instruction {
     ///
     /// The 6502 had a serious bug with indirect absolute indexing and the
     /// JMP instruction. If the operand crosses a page boundary, the 6502 will
-    /// 'forget' the carry and instead use the 00 byte on that page.
-    ///
-    /// TODO: Implement that bug
+    /// 'forget' the carry and instead use the 00 byte on that page. So
+    /// `JMP ($02FF)` reads the low byte from $02FF, but the high byte from
+    /// $0200 instead of $0300.
     JMP,
     /// Jump to SubRoutine
     JSR,
@@ -272,6 +275,9 @@ bitflags! {
     }
 }
 
+#[cfg(feature = "serde")]
+crate::serde_utils::serde_bitflags!(Status, u8);
+
 pub const POWERON_CPU_STATE: CpuState = CpuState {
     acc: 0,
     x: 0,
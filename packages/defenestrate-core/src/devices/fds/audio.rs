@@ -0,0 +1,391 @@
+//! The FDS's expansion audio: one wavetable channel, with a frequency modulator
+//!
+//! The channel plays back 64 6-bit samples from wave RAM ($4040-$407F), at a
+//! rate set by a 12-bit frequency. A second unit, the modulator, walks through
+//! a table of 3-bit steps and bends that frequency up and down, for vibrato and
+//! other effects. Both have a volume envelope, clocked off of a shared master
+//! speed.
+//!
+//! cf. https://wiki.nesdev.com/w/index.php/FDS_audio
+
+/// The master volume multipliers, from $4089, out of 36
+const MASTER_VOLUMES: [u32; 4] = [36, 24, 17, 14];
+
+/// How each modulator table entry changes the mod counter
+///
+/// `None` resets the counter to 0.
+const MOD_STEPS: [Option<i8>; 8] = [
+    Some(0),
+    Some(1),
+    Some(2),
+    Some(4),
+    None,
+    Some(-4),
+    Some(-2),
+    Some(-1),
+];
+
+/// The largest value `FdsAudio::output` can take
+const MAX_OUTPUT: f32 = 63.0;
+
+/// A volume (or modulation depth) envelope, shared by the wave and modulator
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+struct Envelope {
+    /// How many master envelope ticks pass between each step, minus 1
+    speed: u8,
+    /// The current volume (or depth), from 0 to 32 when the envelope is on
+    gain: u8,
+    /// Whether the envelope counts up instead of down
+    increase: bool,
+    /// Whether the envelope is off, and `gain` is fixed at `speed`
+    disabled: bool,
+    /// CPU cycles until the next step
+    timer: u32,
+}
+
+impl Envelope {
+    fn new() -> Envelope {
+        Envelope {
+            speed: 0,
+            gain: 0,
+            increase: false,
+            disabled: true,
+            timer: 0,
+        }
+    }
+
+    /// Handle a write to $4080 or $4084
+    fn write(&mut self, value: u8, master_speed: u8) {
+        self.speed = value & 0x3F;
+        self.increase = value & 0x40 != 0;
+        self.disabled = value & 0x80 != 0;
+        if self.disabled {
+            self.gain = self.speed;
+        }
+        self.reset_timer(master_speed);
+    }
+
+    fn reset_timer(&mut self, master_speed: u8) {
+        self.timer = 8 * (self.speed as u32 + 1) * master_speed as u32;
+    }
+
+    /// Clock the envelope once, returning whether the gain changed
+    fn tick(&mut self, master_speed: u8) -> bool {
+        if self.disabled || master_speed == 0 {
+            return false;
+        }
+        self.timer = self.timer.saturating_sub(1);
+        if self.timer > 0 {
+            return false;
+        }
+        self.reset_timer(master_speed);
+        if self.increase && self.gain < 32 {
+            self.gain += 1;
+        } else if !self.increase && self.gain > 0 {
+            self.gain -= 1;
+        }
+        true
+    }
+}
+
+/// The FDS's wavetable channel and modulator
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct FdsAudio {
+    /// The 64 samples of wave RAM, each 6 bits
+    #[cfg_attr(feature = "serde", serde(with = "crate::serde_utils::byte_array"))]
+    wave_table: [u8; 64],
+    /// Whether wave RAM can be written, which also holds the wave in place
+    wave_write_enabled: bool,
+    /// Whether the wave is stopped and reset to its first sample
+    wave_halted: bool,
+    /// The 12-bit wave frequency
+    wave_freq: u16,
+    /// The wave's phase, which steps to the next sample when it overflows
+    wave_accumulator: u16,
+    /// Which sample of the wave is playing
+    wave_position: u8,
+    volume: Envelope,
+    /// Whether the volume and mod envelopes are both paused
+    envelopes_disabled: bool,
+    /// The master volume, as an index into `MASTER_VOLUMES`
+    master_volume: u8,
+    /// How fast both envelopes run, from $408A
+    master_envelope_speed: u8,
+
+    /// The 64 steps of the mod table, each 3 bits
+    #[cfg_attr(feature = "serde", serde(with = "crate::serde_utils::byte_array"))]
+    mod_table: [u8; 64],
+    /// Which step of the mod table is next
+    mod_position: u8,
+    /// Whether the modulator is stopped, which also allows writes to its table
+    mod_halted: bool,
+    /// The 12-bit modulator frequency
+    mod_freq: u16,
+    /// The modulator's phase, which steps through the table when it overflows
+    mod_accumulator: u16,
+    /// The 7-bit signed mod counter, which is how far the pitch is bent
+    mod_counter: i8,
+    mod_depth: Envelope,
+    /// How much the modulator adds to the wave frequency right now
+    mod_output: i32,
+
+    /// The last sample the channel output, from 0 to 63
+    output: u8,
+}
+
+impl FdsAudio {
+    pub(super) fn new() -> FdsAudio {
+        FdsAudio {
+            wave_table: [0u8; 64],
+            wave_write_enabled: false,
+            wave_halted: true,
+            wave_freq: 0,
+            wave_accumulator: 0,
+            wave_position: 0,
+            volume: Envelope::new(),
+            envelopes_disabled: false,
+            master_volume: 0,
+            master_envelope_speed: 0xE8,
+            mod_table: [0u8; 64],
+            mod_position: 0,
+            mod_halted: true,
+            mod_freq: 0,
+            mod_accumulator: 0,
+            mod_counter: 0,
+            mod_depth: Envelope::new(),
+            mod_output: 0,
+            output: 0,
+        }
+    }
+
+    /// Handle a read from $4040-$4097, given as a CPU address
+    ///
+    /// Only wave RAM and the two gain registers can be read. The top 2 bits
+    /// are open bus.
+    pub(super) fn read(&self, addr: u16) -> Option<u8> {
+        match addr {
+            0x4040..=0x407F => Some(self.wave_table[(addr & 0x3F) as usize]),
+            0x4090 => Some(self.volume.gain),
+            0x4092 => Some(self.mod_depth.gain),
+            _ => None,
+        }
+    }
+
+    /// Handle a write to $4040-$408A, given as a CPU address
+    pub(super) fn write(&mut self, addr: u16, value: u8) {
+        match addr {
+            0x4040..=0x407F if self.wave_write_enabled => {
+                self.wave_table[(addr & 0x3F) as usize] = value & 0x3F;
+            }
+            0x4080 => self.volume.write(value, self.master_envelope_speed),
+            0x4082 => self.wave_freq = (self.wave_freq & 0x0F00) | value as u16,
+            0x4083 => {
+                self.wave_freq = (self.wave_freq & 0x00FF) | ((value as u16 & 0x0F) << 8);
+                self.envelopes_disabled = value & 0x40 != 0;
+                self.wave_halted = value & 0x80 != 0;
+                if self.wave_halted {
+                    self.wave_accumulator = 0;
+                    self.wave_position = 0;
+                }
+                if self.envelopes_disabled {
+                    self.volume.reset_timer(self.master_envelope_speed);
+                    self.mod_depth.reset_timer(self.master_envelope_speed);
+                }
+            }
+            0x4084 => {
+                self.mod_depth.write(value, self.master_envelope_speed);
+                self.update_mod_output();
+            }
+            0x4085 => {
+                self.set_mod_counter(value & 0x7F);
+                self.update_mod_output();
+            }
+            0x4086 => self.mod_freq = (self.mod_freq & 0x0F00) | value as u16,
+            0x4087 => {
+                self.mod_freq = (self.mod_freq & 0x00FF) | ((value as u16 & 0x0F) << 8);
+                self.mod_halted = value & 0x80 != 0;
+                if self.mod_halted {
+                    self.mod_accumulator = 0;
+                }
+            }
+            // the mod table can only be written while the modulator is halted,
+            // and each write fills two steps
+            0x4088 if self.mod_halted => {
+                let position = self.mod_position as usize;
+                self.mod_table[position] = value & 0x07;
+                self.mod_table[(position + 1) & 0x3F] = value & 0x07;
+                self.mod_position = ((position + 2) & 0x3F) as u8;
+            }
+            0x4089 => {
+                self.master_volume = value & 0x03;
+                self.wave_write_enabled = value & 0x80 != 0;
+            }
+            0x408A => self.master_envelope_speed = value,
+            _ => {}
+        }
+    }
+
+    /// Set the mod counter from a 7-bit two's complement value
+    fn set_mod_counter(&mut self, value: u8) {
+        // sign-extend from bit 6
+        self.mod_counter = ((value << 1) as i8) >> 1;
+    }
+
+    /// Work out how much the modulator bends the wave frequency
+    ///
+    /// This is the integer math the hardware does, rounding and all. cf.
+    /// https://wiki.nesdev.com/w/index.php/FDS_audio#Frequency_calculation
+    fn update_mod_output(&mut self) {
+        let mut temp = self.mod_counter as i32 * self.mod_depth.gain as i32;
+        let remainder = temp & 0x0F;
+        temp >>= 4;
+        if remainder > 0 && temp & 0x80 == 0 {
+            temp += if self.mod_counter < 0 { -1 } else { 2 };
+        }
+        if temp >= 192 {
+            temp -= 256;
+        } else if temp < -64 {
+            temp += 256;
+        }
+        temp *= self.wave_freq as i32;
+        let remainder = temp & 0x3F;
+        temp >>= 6;
+        if remainder >= 32 {
+            temp += 1;
+        }
+        self.mod_output = temp;
+    }
+
+    /// Clock the channel once per CPU cycle
+    pub(super) fn clock(&mut self) {
+        if !self.wave_halted && !self.envelopes_disabled {
+            self.volume.tick(self.master_envelope_speed);
+            if self.mod_depth.tick(self.master_envelope_speed) {
+                self.update_mod_output();
+            }
+        }
+        if !self.mod_halted && self.mod_freq > 0 {
+            let (accumulator, overflowed) = self.mod_accumulator.overflowing_add(self.mod_freq);
+            self.mod_accumulator = accumulator;
+            if overflowed {
+                let step = self.mod_table[self.mod_position as usize];
+                let counter = match MOD_STEPS[step as usize] {
+                    Some(offset) => (self.mod_counter + offset) as u8,
+                    None => 0,
+                };
+                self.set_mod_counter(counter & 0x7F);
+                self.mod_position = (self.mod_position + 1) & 0x3F;
+                self.update_mod_output();
+            }
+        }
+        let level = self.volume.gain.min(32) as u32 * MASTER_VOLUMES[self.master_volume as usize];
+        self.output = ((self.wave_table[self.wave_position as usize] as u32 * level) / 1152) as u8;
+        if self.wave_halted || self.wave_write_enabled {
+            return;
+        }
+        let freq = self.wave_freq as i32 + self.mod_output;
+        if freq > 0 {
+            let (accumulator, overflowed) = self.wave_accumulator.overflowing_add(freq as u16);
+            self.wave_accumulator = accumulator;
+            if overflowed {
+                self.wave_position = (self.wave_position + 1) & 0x3F;
+            }
+        }
+    }
+
+    /// The channel's current output, from 0.0 to 1.0
+    pub fn sample(&self) -> f32 {
+        self.output as f32 / MAX_OUTPUT
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Fill wave RAM with a square wave, at full volume
+    fn square_wave() -> FdsAudio {
+        let mut audio = FdsAudio::new();
+        audio.write(0x4089, 0x80);
+        for i in 0..64 {
+            audio.write(0x4040 + i, if i < 32 { 0x3F } else { 0x00 });
+        }
+        audio.write(0x4089, 0x00);
+        // envelope off, with the gain fixed at 32
+        audio.write(0x4080, 0x80 | 0x20);
+        audio
+    }
+
+    #[test]
+    fn only_writes_wave_ram_when_enabled() {
+        let mut audio = FdsAudio::new();
+        audio.write(0x4040, 0x12);
+        assert_eq!(audio.read(0x4040), Some(0x00));
+        audio.write(0x4089, 0x80);
+        audio.write(0x4040, 0xFF);
+        assert_eq!(audio.read(0x4040), Some(0x3F));
+    }
+
+    #[test]
+    fn plays_the_wave_table() {
+        let mut audio = square_wave();
+        // one step through the wave every 32 cycles
+        audio.write(0x4082, 0x00);
+        audio.write(0x4083, 0x08);
+        audio.clock();
+        assert_eq!(audio.sample(), 1.0);
+        for _ in 0..32 * 32 {
+            audio.clock();
+        }
+        assert_eq!(audio.sample(), 0.0);
+    }
+
+    #[test]
+    fn halting_resets_the_wave() {
+        let mut audio = square_wave();
+        audio.write(0x4083, 0x08);
+        for _ in 0..40 * 32 {
+            audio.clock();
+        }
+        assert_eq!(audio.sample(), 0.0);
+        audio.write(0x4083, 0x80);
+        audio.clock();
+        assert_eq!(audio.sample(), 1.0);
+    }
+
+    #[test]
+    fn runs_the_volume_envelope() {
+        let mut audio = FdsAudio::new();
+        audio.write(0x408A, 0x01);
+        audio.write(0x4083, 0x00);
+        // increasing, one step every 8 cycles
+        audio.write(0x4080, 0x40);
+        for _ in 0..8 * 4 {
+            audio.clock();
+        }
+        assert_eq!(audio.read(0x4090), Some(4));
+    }
+
+    #[test]
+    fn modulates_the_frequency() {
+        let mut audio = FdsAudio::new();
+        audio.write(0x4087, 0x80);
+        for _ in 0..32 {
+            // +1 every step
+            audio.write(0x4088, 0x01);
+        }
+        audio.write(0x4082, 0x00);
+        audio.write(0x4083, 0x01);
+        audio.write(0x4084, 0x80 | 0x20);
+        audio.write(0x4086, 0x00);
+        audio.write(0x4087, 0x08);
+        assert_eq!(audio.mod_output, 0);
+        for _ in 0..32 {
+            audio.clock();
+        }
+        assert_eq!(audio.mod_counter, 1);
+        assert!(audio.mod_output > 0);
+    }
+}
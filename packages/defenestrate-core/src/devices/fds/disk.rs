@@ -0,0 +1,195 @@
+//! Loading .fds disk images
+//!
+//! An .fds file is a list of disk sides, each 65500 bytes, with an optional
+//! 16-byte header in front. The sides hold just the data of each block on the
+//! disk, but the real disk also has a gap before each block, a start mark to
+//! end the gap, and a CRC after it. The BIOS expects to see all of that as it
+//! reads, so it's put back when the image is loaded.
+//!
+//! cf. https://wiki.nesdev.com/w/index.php/FDS_disk_format
+//! cf. https://wiki.nesdev.com/w/index.php/FDS_file_format
+
+use alloc::{vec, vec::Vec};
+
+use crate::error::{Error, Result};
+
+/// The optional .fds header's magic number, "FDS" followed by an MS-DOS EOF
+const FDS_MAGIC: [u8; 4] = [0x46, 0x44, 0x53, 0x1A];
+
+/// The length of the optional .fds header
+const FDS_HEADER_SIZE: usize = 16;
+
+/// The length of each side in an .fds file
+pub const SIDE_SIZE: usize = 65500;
+
+/// The length of each side once the gaps and CRCs are put back
+///
+/// This is a little more than the real disks hold, so that a full side always
+/// fits.
+const RAW_SIDE_SIZE: usize = 68000;
+
+/// The gap before the first block, in bytes (28300 bits)
+const LEADING_GAP: usize = 28300 / 8;
+
+/// The gap after each block, in bytes (976 bits)
+const BLOCK_GAP: usize = 976 / 8;
+
+/// The byte that ends a gap, right before a block
+const GAP_END_MARK: u8 = 0x80;
+
+/// The CRC written after each block
+///
+/// The BIOS never checks this (only the drive does, and it's emulated as never
+/// finding an error), so it doesn't need to be right.
+const FAKE_CRC: [u8; 2] = [0x4D, 0x62];
+
+/// The start of the disk info block, which every side starts with
+const DISK_INFO_MAGIC: &[u8] = b"\x01*NINTENDO-HVC*";
+
+/// A disk image, with one or more sides
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct FdsDisk {
+    /// Each side, as the drive sees it (with gaps and CRCs)
+    sides: Vec<Vec<u8>>,
+}
+
+impl FdsDisk {
+    /// Load a disk from an .fds file, with or without the header
+    pub fn from_fds(buf: &[u8]) -> Result<FdsDisk> {
+        let data = if buf.starts_with(&FDS_MAGIC) {
+            &buf[FDS_HEADER_SIZE.min(buf.len())..]
+        } else {
+            buf
+        };
+        if data.is_empty() || data.len() % SIDE_SIZE != 0 {
+            return Err(Error::InvalidDiskImage);
+        }
+        let sides = data
+            .chunks_exact(SIDE_SIZE)
+            .map(|side| {
+                if side.starts_with(DISK_INFO_MAGIC) {
+                    Ok(add_gaps(side))
+                } else {
+                    Err(Error::InvalidDiskImage)
+                }
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(FdsDisk { sides })
+    }
+
+    /// The number of sides on this disk
+    ///
+    /// Games on more than one disk usually come as a single image, with the
+    /// sides of each disk one after the other.
+    pub fn side_count(&self) -> usize {
+        self.sides.len()
+    }
+
+    /// Get a side, as the drive sees it (with gaps and CRCs)
+    pub fn side(&self, side: usize) -> &[u8] {
+        &self.sides[side]
+    }
+
+    pub(super) fn side_mut(&mut self, side: usize) -> &mut [u8] {
+        &mut self.sides[side]
+    }
+}
+
+/// Put the gaps, start marks, and CRCs back into a side from an .fds file
+fn add_gaps(side: &[u8]) -> Vec<u8> {
+    let mut raw = vec![0u8; LEADING_GAP];
+    let mut pos = 0;
+    let mut file_size = 0;
+    while pos < side.len() {
+        let block_len = match side[pos] {
+            // disk info
+            1 => 56,
+            // file count
+            2 => 2,
+            // file header, which says how big the next file is
+            3 => {
+                if let Some(size) = side.get(pos + 13..pos + 15) {
+                    file_size = size[0] as usize | (size[1] as usize) << 8;
+                }
+                16
+            }
+            // file data
+            4 => 1 + file_size,
+            // anything else is the unused space at the end of the side
+            _ => break,
+        };
+        let end = (pos + block_len).min(side.len());
+        raw.push(GAP_END_MARK);
+        raw.extend_from_slice(&side[pos..end]);
+        raw.extend_from_slice(&FAKE_CRC);
+        raw.resize(raw.len() + BLOCK_GAP, 0);
+        pos = end;
+    }
+    raw.resize(raw.len().max(RAW_SIDE_SIZE), 0);
+    raw
+}
+
+#[cfg(test)]
+pub(super) mod tests {
+    use super::*;
+
+    /// Build an .fds side with one file of `file_size` bytes, each of which is
+    /// `fill`
+    pub fn make_side(file_size: usize, fill: u8) -> Vec<u8> {
+        let mut side = DISK_INFO_MAGIC.to_vec();
+        side.resize(56, 0);
+        side.extend([2, 1]);
+        let mut header = vec![3u8; 16];
+        header[13] = file_size as u8;
+        header[14] = (file_size >> 8) as u8;
+        side.extend(header);
+        side.push(4);
+        side.extend(vec![fill; file_size]);
+        side.resize(SIDE_SIZE, 0);
+        side
+    }
+
+    #[test]
+    fn loads_images_with_and_without_a_header() {
+        let mut headerless = make_side(0x10, 0xAA);
+        headerless.extend(make_side(0x20, 0xBB));
+        let mut headered = FDS_MAGIC.to_vec();
+        headered.push(2);
+        headered.resize(FDS_HEADER_SIZE, 0);
+        headered.extend(&headerless);
+        let disk = FdsDisk::from_fds(&headerless).unwrap();
+        assert_eq!(disk.side_count(), 2);
+        assert!(FdsDisk::from_fds(&headered).unwrap() == disk);
+    }
+
+    #[test]
+    fn rejects_bad_images() {
+        assert!(matches!(
+            FdsDisk::from_fds(&[0u8; 100]),
+            Err(Error::InvalidDiskImage)
+        ));
+        assert!(matches!(
+            FdsDisk::from_fds(&vec![0u8; SIDE_SIZE]),
+            Err(Error::InvalidDiskImage)
+        ));
+    }
+
+    #[test]
+    fn puts_back_gaps_and_crcs() {
+        let disk = FdsDisk::from_fds(&make_side(3, 0xAA)).unwrap();
+        let side = disk.side(0);
+        assert_eq!(side.len(), RAW_SIDE_SIZE);
+        assert!(side[..LEADING_GAP].iter().all(|&byte| byte == 0));
+        assert_eq!(side[LEADING_GAP], GAP_END_MARK);
+        assert_eq!(&side[LEADING_GAP + 1..LEADING_GAP + 16], DISK_INFO_MAGIC);
+        // the file count block comes after the disk info's CRC and gap
+        let file_count = LEADING_GAP + 1 + 56 + 2 + BLOCK_GAP;
+        assert_eq!(&side[file_count..file_count + 3], &[GAP_END_MARK, 2, 1]);
+        let file_data = file_count + 3 + 2 + BLOCK_GAP + 1 + 16 + 2 + BLOCK_GAP;
+        assert_eq!(
+            &side[file_data..file_data + 7],
+            &[GAP_END_MARK, 4, 0xAA, 0xAA, 0xAA, FAKE_CRC[0], FAKE_CRC[1]]
+        );
+    }
+}
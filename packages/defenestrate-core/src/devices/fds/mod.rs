@@ -0,0 +1,558 @@
+//! The Famicom Disk System
+//!
+//! The FDS is a disk drive that plugs into the Famicom's cartridge slot through
+//! a RAM adapter. The adapter has 32k of PRG RAM at $6000-$DFFF, the 8k BIOS
+//! ROM at $E000-$FFFF, and 8k of CHR RAM, along with the registers for the
+//! drive, a CPU cycle timer, and an expansion audio channel at $4020-$409F.
+//! Games are loaded off of the disk into RAM by the BIOS.
+//!
+//! From the console's point of view this is just another cartridge, so
+//! `FdsAdapter` is an `ICartridge`. The disk drive is emulated a byte at a time:
+//! while the motor is on, the head moves one byte every 150 CPU cycles, and
+//! raises an IRQ for the BIOS to read or write it.
+//!
+//! The BIOS isn't included, and has to be supplied along with the disk.
+//!
+//! cf. https://wiki.nesdev.com/w/index.php/Family_Computer_Disk_System
+
+use alloc::{boxed::Box, vec, vec::Vec};
+
+mod audio;
+mod disk;
+
+pub use self::audio::FdsAudio;
+pub use self::disk::FdsDisk;
+
+use crate::devices::bus::BusPeekResult;
+use crate::devices::cartridge::{
+    hardwired_nametable_addr, CartridgeState, ICartridge, NametableArrangement, PrgRegion,
+};
+use crate::error::{Error, Result};
+
+/// Where the adapter's RAM starts on the CPU bus
+const PRG_RAM_START_ADDR: u16 = 0x6000;
+
+const BIOS_SIZE: usize = 0x2000;
+const PRG_RAM_SIZE: usize = 0x8000;
+const CHR_RAM_SIZE: usize = 0x2000;
+
+/// CPU cycles between each byte the head passes over
+const BYTE_TRANSFER_CYCLES: u32 = 150;
+
+/// CPU cycles for the head to go back to the start of the disk
+const HEAD_RETURN_CYCLES: u32 = 50000;
+
+/// The state of the disk drive, and the registers that control it
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+struct DiskDrive {
+    /// Which side of the disk is in the drive, if any
+    side: Option<usize>,
+    /// Where the head is on the current side
+    position: usize,
+    /// CPU cycles until the head gets to the next byte
+    delay: u32,
+    motor_on: bool,
+    /// Whether the drive is holding the head at the start of the disk
+    reset_transfer: bool,
+    /// Whether the drive is reading, instead of writing
+    read_mode: bool,
+    /// Whether the drive is sending the CRC after a block
+    crc_control: bool,
+    /// Whether the BIOS is ready for data (i.e., past the gap before a block)
+    disk_ready: bool,
+    /// Whether to raise an IRQ after each byte
+    irq_enabled: bool,
+    /// Whether the head has reached the end of the disk
+    end_of_head: bool,
+    /// Whether the head is moving over the disk
+    scanning: bool,
+    /// Whether the gap before the current block is over
+    gap_ended: bool,
+    /// Whether a byte has been read or written since the BIOS last checked
+    transfer_complete: bool,
+    read_data: u8,
+    write_data: u8,
+    irq: bool,
+}
+
+impl DiskDrive {
+    fn new(side: Option<usize>) -> DiskDrive {
+        DiskDrive {
+            side,
+            position: 0,
+            delay: 0,
+            motor_on: false,
+            reset_transfer: false,
+            read_mode: true,
+            crc_control: false,
+            disk_ready: false,
+            irq_enabled: false,
+            end_of_head: true,
+            scanning: false,
+            gap_ended: false,
+            transfer_complete: false,
+            read_data: 0,
+            write_data: 0,
+            irq: false,
+        }
+    }
+
+    /// Move the head along the disk, once per CPU cycle
+    fn clock(&mut self, disk: &mut FdsDisk) {
+        let side = match self.side {
+            Some(side) if self.motor_on => side,
+            _ => {
+                self.end_of_head = true;
+                self.scanning = false;
+                return;
+            }
+        };
+        if self.reset_transfer && !self.scanning {
+            return;
+        }
+        if self.end_of_head {
+            self.delay = HEAD_RETURN_CYCLES;
+            self.end_of_head = false;
+            self.position = 0;
+            self.gap_ended = false;
+            return;
+        }
+        if self.delay > 0 {
+            self.delay -= 1;
+            return;
+        }
+        self.scanning = true;
+        let data = disk.side_mut(side);
+        if self.read_mode {
+            let byte = data[self.position];
+            let mut needs_irq = self.irq_enabled;
+            if !self.disk_ready {
+                self.gap_ended = false;
+            } else if byte != 0 && !self.gap_ended {
+                // this is the start mark, which the BIOS doesn't see
+                self.gap_ended = true;
+                needs_irq = false;
+            }
+            if self.gap_ended {
+                self.transfer_complete = true;
+                self.read_data = byte;
+                self.irq |= needs_irq;
+            }
+        } else {
+            let mut byte = 0;
+            if !self.crc_control {
+                self.transfer_complete = true;
+                byte = self.write_data;
+                self.irq |= self.irq_enabled;
+            }
+            // CRCs are never checked, so they're just left blank
+            data[self.position] = if self.disk_ready { byte } else { 0 };
+            self.gap_ended = false;
+        }
+        self.position += 1;
+        if self.position >= data.len() {
+            self.motor_on = false;
+            self.end_of_head = true;
+        } else {
+            self.delay = BYTE_TRANSFER_CYCLES;
+        }
+    }
+}
+
+/// The RAM adapter, with a disk drive and (maybe) a disk attached
+#[derive(Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct FdsAdapter {
+    bios: Vec<u8>,
+    prg_ram: Vec<u8>,
+    chr_ram: Vec<u8>,
+    nametable: Vec<u8>,
+    arrangement: NametableArrangement,
+    disk: FdsDisk,
+    drive: DiskDrive,
+    audio: FdsAudio,
+    /// Whether the disk registers ($4024-$4026, $4030-$4033) are enabled
+    disk_regs_enabled: bool,
+    /// Whether the audio registers ($4040-$4092) are enabled
+    sound_regs_enabled: bool,
+    timer_reload: u16,
+    timer_counter: u16,
+    timer_enabled: bool,
+    timer_repeat: bool,
+    timer_irq: bool,
+}
+
+impl FdsAdapter {
+    /// Attach a disk to the RAM adapter, with the first side inserted
+    ///
+    /// `bios` is the 8k Disk System BIOS ROM (often called disksys.rom).
+    pub fn new(bios: &[u8], disk: FdsDisk) -> Result<FdsAdapter> {
+        if bios.len() != BIOS_SIZE {
+            return Err(Error::InvalidBios { len: bios.len() });
+        }
+        Ok(FdsAdapter {
+            bios: bios.to_vec(),
+            prg_ram: vec![0u8; PRG_RAM_SIZE],
+            chr_ram: vec![0u8; CHR_RAM_SIZE],
+            nametable: vec![0u8; 0x800],
+            arrangement: NametableArrangement::Vertical,
+            disk,
+            drive: DiskDrive::new(Some(0)),
+            audio: FdsAudio::new(),
+            disk_regs_enabled: false,
+            sound_regs_enabled: false,
+            timer_reload: 0,
+            timer_counter: 0,
+            timer_enabled: false,
+            timer_repeat: false,
+            timer_irq: false,
+        })
+    }
+
+    pub fn disk(&self) -> &FdsDisk {
+        &self.disk
+    }
+
+    /// Which side of the disk is in the drive, if any
+    pub fn inserted_side(&self) -> Option<usize> {
+        self.drive.side
+    }
+
+    /// Put a side of the disk in the drive, or take the disk out with `None`
+    ///
+    /// To flip or change disks, take the disk out first and give the game a
+    /// second or so to notice, the same as on hardware. Most games won't see
+    /// the new side otherwise.
+    ///
+    /// Fails with `Error::NoSuchDiskSide`, leaving the drive as it was, if the
+    /// disk doesn't have that side.
+    pub fn insert_side(&mut self, side: Option<usize>) -> Result<()> {
+        if let Some(side) = side {
+            let side_count = self.disk.side_count();
+            if side >= side_count {
+                return Err(Error::NoSuchDiskSide { side, side_count });
+            }
+        }
+        self.drive.side = side;
+        self.drive.end_of_head = true;
+        self.drive.scanning = false;
+        Ok(())
+    }
+
+    pub fn audio(&self) -> &FdsAudio {
+        &self.audio
+    }
+
+    /// Handle a read from a register in $4020-$409F, given as a CPU address
+    fn read_register(&mut self, addr: u16, last_bus_value: u8) -> u8 {
+        match addr {
+            0x4030 if self.disk_regs_enabled => {
+                let mut value = last_bus_value & 0x2C;
+                if self.timer_irq {
+                    value |= 0x01;
+                }
+                if self.drive.transfer_complete {
+                    value |= 0x02;
+                }
+                if self.drive.end_of_head {
+                    value |= 0x40;
+                }
+                self.drive.transfer_complete = false;
+                self.drive.irq = false;
+                self.timer_irq = false;
+                value
+            }
+            0x4031 if self.disk_regs_enabled => {
+                self.drive.transfer_complete = false;
+                self.drive.irq = false;
+                self.drive.read_data
+            }
+            0x4032 if self.disk_regs_enabled => {
+                let mut value = last_bus_value & 0xF8;
+                if self.drive.side.is_none() {
+                    // no disk, so it's also not ready or writable
+                    value |= 0x07;
+                } else if !self.drive.scanning {
+                    value |= 0x02;
+                }
+                value
+            }
+            // the battery is good
+            0x4033 if self.disk_regs_enabled => 0x80,
+            0x4040..=0x409F if self.sound_regs_enabled => match self.audio.read(addr) {
+                Some(value) => (last_bus_value & 0xC0) | value,
+                None => last_bus_value,
+            },
+            _ => last_bus_value,
+        }
+    }
+
+    /// Handle a write to a register in $4020-$409F, given as a CPU address
+    fn write_register(&mut self, addr: u16, value: u8) {
+        match addr {
+            0x4020 => self.timer_reload = (self.timer_reload & 0xFF00) | value as u16,
+            0x4021 => self.timer_reload = (self.timer_reload & 0x00FF) | (value as u16) << 8,
+            0x4022 => {
+                self.timer_repeat = value & 0x01 != 0;
+                self.timer_enabled = value & 0x02 != 0 && self.disk_regs_enabled;
+                if self.timer_enabled {
+                    self.timer_counter = self.timer_reload;
+                } else {
+                    self.timer_irq = false;
+                }
+            }
+            0x4023 => {
+                self.disk_regs_enabled = value & 0x01 != 0;
+                self.sound_regs_enabled = value & 0x02 != 0;
+                if !self.disk_regs_enabled {
+                    self.timer_enabled = false;
+                    self.timer_irq = false;
+                    self.drive.irq = false;
+                }
+            }
+            0x4024 if self.disk_regs_enabled => {
+                self.drive.write_data = value;
+                self.drive.transfer_complete = false;
+                self.drive.irq = false;
+            }
+            0x4025 if self.disk_regs_enabled => {
+                let drive = &mut self.drive;
+                drive.motor_on = value & 0x01 != 0;
+                drive.reset_transfer = value & 0x02 != 0;
+                drive.read_mode = value & 0x04 != 0;
+                drive.crc_control = value & 0x10 != 0;
+                drive.disk_ready = value & 0x40 != 0;
+                drive.irq_enabled = value & 0x80 != 0;
+                drive.irq = false;
+                self.arrangement = if value & 0x08 != 0 {
+                    NametableArrangement::Horizontal
+                } else {
+                    NametableArrangement::Vertical
+                };
+            }
+            0x4040..=0x409F if self.sound_regs_enabled => self.audio.write(addr, value),
+            _ => {}
+        }
+    }
+}
+
+impl ICartridge for FdsAdapter {
+    fn read_chr(&mut self, addr: u16, last_bus_value: u8) -> u8 {
+        self.peek_chr(addr).unwrap(last_bus_value)
+    }
+
+    fn peek_chr(&self, addr: u16) -> BusPeekResult {
+        if addr < 0x2000 {
+            return BusPeekResult::Result(self.chr_ram[addr as usize]);
+        }
+        let nt_addr = hardwired_nametable_addr(addr, self.arrangement);
+        BusPeekResult::Result(self.nametable[nt_addr])
+    }
+
+    fn write_chr(&mut self, addr: u16, value: u8) {
+        if addr < 0x2000 {
+            self.chr_ram[addr as usize] = value;
+            return;
+        }
+        let nt_addr = hardwired_nametable_addr(addr, self.arrangement);
+        self.nametable[nt_addr] = value;
+    }
+
+    fn read_prg(&mut self, region: PrgRegion, last_bus_value: u8) -> u8 {
+        if let PrgRegion::Expansion(_) = region {
+            return self.read_register(region.cpu_addr(), last_bus_value);
+        }
+        self.peek_prg(region).unwrap(last_bus_value)
+    }
+
+    fn peek_prg(&self, region: PrgRegion) -> BusPeekResult {
+        match region {
+            // $4020-$409F
+            PrgRegion::Expansion(0x0000..=0x007F) => BusPeekResult::MutableRead,
+            PrgRegion::Expansion(_) => BusPeekResult::Unmapped,
+            // the RAM carries on from $6000 through $DFFF
+            PrgRegion::Ram(_) | PrgRegion::Rom(0x0000..=0x5FFF) => BusPeekResult::Result(
+                self.prg_ram[(region.cpu_addr() - PRG_RAM_START_ADDR) as usize],
+            ),
+            PrgRegion::Rom(addr) => BusPeekResult::Result(self.bios[(addr - 0x6000) as usize]),
+        }
+    }
+
+    fn write_prg(&mut self, region: PrgRegion, value: u8) {
+        match region {
+            PrgRegion::Expansion(0x0000..=0x007F) => self.write_register(region.cpu_addr(), value),
+            PrgRegion::Ram(_) | PrgRegion::Rom(0x0000..=0x5FFF) => {
+                self.prg_ram[(region.cpu_addr() - PRG_RAM_START_ADDR) as usize] = value
+            }
+            _ => {}
+        }
+    }
+
+    fn dump_chr(&self) -> &[u8] {
+        &self.chr_ram
+    }
+
+    fn dump_nametables(&self) -> &[u8] {
+        &self.nametable
+    }
+
+    fn debug_state(&self) -> CartridgeState {
+        CartridgeState::FDS(Box::new(self.clone()))
+    }
+
+    fn clock_cpu(&mut self) {
+        if self.timer_enabled {
+            if self.timer_counter == 0 {
+                self.timer_irq = true;
+                self.timer_counter = self.timer_reload;
+                if !self.timer_repeat {
+                    self.timer_enabled = false;
+                }
+            } else {
+                self.timer_counter -= 1;
+            }
+        }
+        self.drive.clock(&mut self.disk);
+        self.audio.clock();
+    }
+
+    fn irq_pending(&self) -> bool {
+        self.timer_irq || self.drive.irq
+    }
+
+    fn audio_sample(&self) -> f32 {
+        self.audio.sample()
+    }
+
+    fn fds_mut(&mut self) -> Option<&mut FdsAdapter> {
+        Some(self)
+    }
+
+    fn power_cycle(&mut self) {
+        // the disk keeps whatever was written to it, and stays in the drive
+        self.prg_ram.fill(0);
+        self.chr_ram.fill(0);
+        self.nametable.fill(0);
+        self.arrangement = NametableArrangement::Vertical;
+        self.drive = DiskDrive::new(self.drive.side);
+        self.audio = FdsAudio::new();
+        self.disk_regs_enabled = false;
+        self.sound_regs_enabled = false;
+        self.timer_reload = 0;
+        self.timer_counter = 0;
+        self.timer_enabled = false;
+        self.timer_repeat = false;
+        self.timer_irq = false;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::disk::tests::make_side;
+    use super::*;
+
+    fn make_adapter() -> FdsAdapter {
+        let disk = FdsDisk::from_fds(&make_side(0x10, 0xAA)).unwrap();
+        let mut adapter = FdsAdapter::new(&[0u8; BIOS_SIZE], disk).unwrap();
+        adapter.write_prg(PrgRegion::from_cpu_addr(0x4023), 0x03);
+        adapter
+    }
+
+    fn read(adapter: &mut FdsAdapter, addr: u16) -> u8 {
+        adapter.read_prg(PrgRegion::from_cpu_addr(addr), 0x00)
+    }
+
+    fn write(adapter: &mut FdsAdapter, addr: u16, value: u8) {
+        adapter.write_prg(PrgRegion::from_cpu_addr(addr), value);
+    }
+
+    #[test]
+    fn rejects_bad_bios_sizes() {
+        let disk = FdsDisk::from_fds(&make_side(0, 0)).unwrap();
+        assert!(matches!(
+            FdsAdapter::new(&[0u8; 0x1000], disk),
+            Err(Error::InvalidBios { len: 0x1000 })
+        ));
+    }
+
+    #[test]
+    fn maps_ram_and_bios() {
+        let disk = FdsDisk::from_fds(&make_side(0, 0)).unwrap();
+        let mut bios = vec![0u8; BIOS_SIZE];
+        bios[0x1FFC] = 0x24;
+        let mut adapter = FdsAdapter::new(&bios, disk).unwrap();
+        write(&mut adapter, 0x6000, 0x12);
+        write(&mut adapter, 0xDFFF, 0x34);
+        write(&mut adapter, 0xFFFC, 0x56);
+        assert_eq!(read(&mut adapter, 0x6000), 0x12);
+        assert_eq!(read(&mut adapter, 0xDFFF), 0x34);
+        assert_eq!(read(&mut adapter, 0xFFFC), 0x24);
+    }
+
+    #[test]
+    fn runs_the_timer_irq() {
+        let mut adapter = make_adapter();
+        write(&mut adapter, 0x4020, 0x10);
+        write(&mut adapter, 0x4021, 0x00);
+        write(&mut adapter, 0x4022, 0x02);
+        for _ in 0..0x10 {
+            adapter.clock_cpu();
+        }
+        assert!(!adapter.irq_pending());
+        adapter.clock_cpu();
+        assert!(adapter.irq_pending());
+        assert_eq!(read(&mut adapter, 0x4030) & 0x01, 0x01);
+        assert!(!adapter.irq_pending());
+        // without repeat, it only fires once
+        for _ in 0..0x100 {
+            adapter.clock_cpu();
+        }
+        assert!(!adapter.irq_pending());
+    }
+
+    #[test]
+    fn ignores_disk_registers_until_enabled() {
+        let mut adapter = make_adapter();
+        write(&mut adapter, 0x4023, 0x00);
+        write(&mut adapter, 0x4025, 0xC5);
+        assert!(!adapter.drive.motor_on);
+        assert_eq!(read(&mut adapter, 0x4032), 0x00);
+    }
+
+    #[test]
+    fn reports_the_disk_as_missing() {
+        let mut adapter = make_adapter();
+        assert_eq!(read(&mut adapter, 0x4032) & 0x01, 0x00);
+        adapter.insert_side(None).unwrap();
+        assert_eq!(read(&mut adapter, 0x4032) & 0x07, 0x07);
+    }
+
+    #[test]
+    fn reads_blocks_off_of_the_disk() {
+        let mut adapter = make_adapter();
+        // motor on, read mode, ready for data, with IRQs
+        write(&mut adapter, 0x4025, 0xC5);
+        let mut bytes = Vec::new();
+        while bytes.len() < 15 {
+            adapter.clock_cpu();
+            if adapter.irq_pending() {
+                bytes.push(read(&mut adapter, 0x4031));
+            }
+        }
+        assert_eq!(&bytes[..], b"\x01*NINTENDO-HVC*");
+        assert_eq!(read(&mut adapter, 0x4032) & 0x02, 0x00);
+    }
+
+    #[test]
+    fn writes_to_the_disk() {
+        let mut adapter = make_adapter();
+        // motor on, write mode
+        write(&mut adapter, 0x4025, 0xC1);
+        write(&mut adapter, 0x4024, 0x5A);
+        while !adapter.irq_pending() {
+            adapter.clock_cpu();
+        }
+        assert_eq!(adapter.disk().side(0)[0], 0x5A);
+    }
+}
@@ -0,0 +1,180 @@
+//! Callbacks that embedders can attach to a running `Nes`
+//!
+//! Hooks are kept in plain `Vec`s, and the emulator only looks at a list when
+//! it isn't empty, so an emulator with no hooks registered pays for little more
+//! than a length check per event.
+
+use alloc::{boxed::Box, vec::Vec};
+use core::mem;
+use core::ops::{Bound, RangeBounds};
+
+use super::cpu::structs::CpuState;
+use super::nes::Nes;
+use super::ppu::PpuDebugView;
+
+// hooks are `Send` so that a `Nes` with hooks attached can still move between
+// threads
+pub type FrameHook = Box<dyn FnMut(&mut Nes) + Send>;
+pub type WriteHook = Box<dyn FnMut(u16, u8) + Send>;
+pub type ExecHook = Box<dyn FnMut(&CpuState) + Send>;
+pub type ScanlineHook = Box<dyn FnMut(u16, &PpuDebugView) + Send>;
+
+/// A handle to a registered hook, for removing it later
+#[derive(Debug, Eq, PartialEq, Hash, Copy, Clone)]
+pub struct HookId(u64);
+
+#[derive(Default)]
+pub(crate) struct Hooks {
+    next_id: u64,
+    pub frame: Vec<(HookId, FrameHook)>,
+    /// Write hooks, with the half-open address range each one watches
+    pub write: Vec<(HookId, u32, u32, WriteHook)>,
+    /// Exec hooks, with the address of the instruction each one watches
+    pub exec: Vec<(HookId, u16, ExecHook)>,
+    pub scanline: Vec<(HookId, ScanlineHook)>,
+    /// Whether the frame hooks are currently running (and so not in `frame`)
+    running_frame_hooks: bool,
+    /// Frame hooks removed while the frame hooks were running
+    removed_frame_hooks: Vec<HookId>,
+}
+
+impl Hooks {
+    fn next_id(&mut self) -> HookId {
+        let id = HookId(self.next_id);
+        self.next_id += 1;
+        id
+    }
+
+    pub fn add_frame(&mut self, hook: FrameHook) -> HookId {
+        let id = self.next_id();
+        self.frame.push((id, hook));
+        id
+    }
+
+    pub fn add_write<R: RangeBounds<u16>>(&mut self, range: R, hook: WriteHook) -> HookId {
+        // widened so that `..=0xFFFF` has somewhere to end
+        let start = match range.start_bound() {
+            Bound::Included(&addr) => addr as u32,
+            Bound::Excluded(&addr) => addr as u32 + 1,
+            Bound::Unbounded => 0x0000,
+        };
+        let end = match range.end_bound() {
+            Bound::Included(&addr) => addr as u32 + 1,
+            Bound::Excluded(&addr) => addr as u32,
+            Bound::Unbounded => 0x10000,
+        };
+        let id = self.next_id();
+        self.write.push((id, start, end, hook));
+        id
+    }
+
+    pub fn add_exec(&mut self, addr: u16, hook: ExecHook) -> HookId {
+        let id = self.next_id();
+        self.exec.push((id, addr, hook));
+        id
+    }
+
+    pub fn add_scanline(&mut self, hook: ScanlineHook) -> HookId {
+        let id = self.next_id();
+        self.scanline.push((id, hook));
+        id
+    }
+
+    /// Remove a hook, returning whether it was registered
+    pub fn remove(&mut self, id: HookId) -> bool {
+        let before = self.len();
+        self.frame.retain(|(hook_id, _)| *hook_id != id);
+        self.write.retain(|(hook_id, ..)| *hook_id != id);
+        self.exec.retain(|(hook_id, ..)| *hook_id != id);
+        self.scanline.retain(|(hook_id, _)| *hook_id != id);
+        if self.len() != before {
+            return true;
+        }
+        // The frame hooks are taken out of this struct while they run, so
+        // that they can borrow the Nes. If one of them is being removed from
+        // inside a frame hook, remember it for when they're put back.
+        if self.running_frame_hooks && !self.removed_frame_hooks.contains(&id) {
+            self.removed_frame_hooks.push(id);
+            return true;
+        }
+        false
+    }
+
+    fn len(&self) -> usize {
+        self.frame.len() + self.write.len() + self.exec.len() + self.scanline.len()
+    }
+
+    pub fn run_write(&mut self, addr: u16, data: u8) {
+        for (_, start, end, hook) in self.write.iter_mut() {
+            if (*start..*end).contains(&(addr as u32)) {
+                hook(addr, data);
+            }
+        }
+    }
+
+    pub fn run_exec(&mut self, state: &CpuState) {
+        for (_, addr, hook) in self.exec.iter_mut() {
+            if *addr == state.pc {
+                hook(state);
+            }
+        }
+    }
+
+    pub fn run_scanline(&mut self, view: &PpuDebugView) {
+        for (_, hook) in self.scanline.iter_mut() {
+            hook(view.scanline as u16, view);
+        }
+    }
+}
+
+/// Run every frame hook against the NES
+///
+/// This is a free function since the hooks need to be moved out of the `Nes`
+/// while they borrow it.
+pub(crate) fn run_frame_hooks(nes: &mut Nes) {
+    let hooks = nes.hooks_mut();
+    let mut running = mem::take(&mut hooks.frame);
+    hooks.running_frame_hooks = true;
+    for (_, hook) in running.iter_mut() {
+        hook(nes);
+    }
+    let hooks = nes.hooks_mut();
+    hooks.running_frame_hooks = false;
+    // hooks registered by a frame hook go after the ones that were running
+    running.append(&mut hooks.frame);
+    let removed = mem::take(&mut hooks.removed_frame_hooks);
+    running.retain(|(id, _)| !removed.contains(id));
+    hooks.frame = running;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn write_hooks_match_their_range() {
+        use std::sync::{Arc, Mutex};
+
+        let mut hooks = Hooks::default();
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let log = seen.clone();
+        hooks.add_write(
+            0x0300..0x0400,
+            Box::new(move |addr, data| log.lock().unwrap().push((addr, data))),
+        );
+        hooks.run_write(0x02FF, 1);
+        hooks.run_write(0x0300, 2);
+        hooks.run_write(0x03FF, 3);
+        hooks.run_write(0x0400, 4);
+        assert_eq!(*seen.lock().unwrap(), vec![(0x0300, 2), (0x03FF, 3)]);
+    }
+
+    #[test]
+    fn removes_hooks() {
+        let mut hooks = Hooks::default();
+        let id = hooks.add_exec(0xC000, Box::new(|_| {}));
+        assert!(hooks.remove(id));
+        assert!(hooks.exec.is_empty());
+        assert!(!hooks.remove(HookId(42)));
+    }
+}
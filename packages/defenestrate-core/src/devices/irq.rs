@@ -0,0 +1,87 @@
+//! The CPU's IRQ line, shared between every device that can pull it
+//!
+//! /IRQ is open-collector: any device on the bus can hold it low, and it only
+//! goes high again once every one of them lets go. So instead of each device
+//! triggering IRQs itself, each one asserts or releases its own source on the
+//! line, and the CPU just checks whether any source is still holding it.
+//!
+//! cf. https://wiki.nesdev.com/w/index.php/IRQ
+
+bitflags! {
+    /// The devices that can hold the IRQ line
+    #[derive(Default)]
+    pub struct IrqSource: u8 {
+        /// The cartridge board, like a mapper's counter or the FDS adapter
+        const MAPPER = 0x01;
+        /// The APU frame counter
+        const APU_FRAME = 0x02;
+        /// The APU's delta modulation channel, at the end of a sample
+        const APU_DMC = 0x04;
+    }
+}
+
+/// The wired-OR of every IRQ source
+#[derive(Debug, Default, Eq, PartialEq, Copy, Clone)]
+pub struct IrqLine {
+    sources: IrqSource,
+}
+
+impl IrqLine {
+    pub fn new() -> IrqLine {
+        IrqLine::default()
+    }
+
+    /// Hold the line for `source`, until it's released
+    pub fn assert(&mut self, source: IrqSource) {
+        self.sources |= source;
+    }
+
+    /// Let go of the line for `source`, leaving the other sources alone
+    pub fn release(&mut self, source: IrqSource) {
+        self.sources &= !source;
+    }
+
+    /// Assert or release `source`, for devices that report a level
+    pub fn set(&mut self, source: IrqSource, asserted: bool) {
+        self.sources.set(source, asserted);
+    }
+
+    /// Whether any source is holding the line
+    pub fn is_asserted(&self) -> bool {
+        !self.sources.is_empty()
+    }
+
+    /// Which sources are holding the line
+    pub fn sources(&self) -> IrqSource {
+        self.sources
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stays_asserted_until_every_source_releases() {
+        let mut line = IrqLine::new();
+        assert!(!line.is_asserted());
+        line.assert(IrqSource::MAPPER);
+        line.assert(IrqSource::APU_FRAME);
+        line.release(IrqSource::MAPPER);
+        assert!(line.is_asserted());
+        assert_eq!(line.sources(), IrqSource::APU_FRAME);
+        line.set(IrqSource::APU_FRAME, false);
+        assert!(!line.is_asserted());
+    }
+
+    #[test]
+    fn sources_assert_independently() {
+        let mut line = IrqLine::new();
+        line.set(IrqSource::APU_DMC, true);
+        line.set(IrqSource::APU_DMC, true);
+        line.release(IrqSource::MAPPER);
+        assert_eq!(line.sources(), IrqSource::APU_DMC);
+        line.release(IrqSource::APU_DMC);
+        assert_eq!(line.sources(), IrqSource::empty());
+    }
+}
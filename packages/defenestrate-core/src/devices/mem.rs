@@ -1,7 +1,25 @@
 //! Module for memory devices, such as RAM and ROM
 
+use alloc::{vec, vec::Vec};
+
 use super::bus::{BusDevice, BusPeekResult};
 
+/// A trait for the console's internal RAM
+///
+/// `Ram` is the one a `Nes` comes with. A replacement, like RAM that logs its
+/// accesses for `Nes::from_parts`, has to keep its contents where `dump` and
+/// `load` see them, since power-on, save states, and snapshots go through
+/// those rather than the bus.
+pub trait IRam: BusDevice + Send {
+    /// Get the contents of this RAM
+    fn dump(&self) -> &[u8];
+
+    /// Copy `data` into this RAM, starting at `offset`
+    ///
+    /// Panics if `data` runs past the end of the RAM.
+    fn load(&mut self, offset: usize, data: &[u8]);
+}
+
 pub struct Ram {
     buf: Vec<u8>,
     len: usize,
@@ -25,6 +43,16 @@ impl BusDevice for Ram {
     }
 }
 
+impl IRam for Ram {
+    fn dump(&self) -> &[u8] {
+        &self.buf
+    }
+
+    fn load(&mut self, offset: usize, data: &[u8]) {
+        self.buf[offset..offset + data.len()].copy_from_slice(data);
+    }
+}
+
 impl Ram {
     pub fn new(size: usize) -> Ram {
         Ram {
@@ -39,4 +67,98 @@ impl Ram {
             buf: Vec::from(buf),
         }
     }
+
+    /// Create a new RAM, filled according to a power-on pattern
+    pub fn new_with_pattern(size: usize, pattern: &RamPattern) -> Ram {
+        let mut ram = Ram::new(size);
+        pattern.fill(&mut ram.buf);
+        ram
+    }
+}
+
+/// The contents of RAM when the console is first powered on
+///
+/// Real consoles power on with semi-random RAM, and a handful of games (as
+/// well as TAS verification) depend on a particular pattern.
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum RamPattern {
+    /// Every byte is $00
+    AllZero,
+    /// Every byte is $FF
+    AllFF,
+    /// 256-byte pages alternate between $00 and $FF, starting with $00
+    AlternatingPages,
+    /// Pseudo-random bytes, which are the same every time for a given seed
+    Random(u64),
+}
+
+impl RamPattern {
+    /// Fill a buffer with this pattern
+    pub fn fill(&self, buf: &mut [u8]) {
+        match self {
+            RamPattern::AllZero => buf.fill(0x00),
+            RamPattern::AllFF => buf.fill(0xFF),
+            RamPattern::AlternatingPages => {
+                for (i, byte) in buf.iter_mut().enumerate() {
+                    *byte = if (i >> 8) & 1 == 0 { 0x00 } else { 0xFF };
+                }
+            }
+            RamPattern::Random(seed) => {
+                let mut rng = SeededRng::new(*seed);
+                for byte in buf.iter_mut() {
+                    *byte = rng.next_u8();
+                }
+            }
+        }
+    }
+}
+
+/// A small, deterministic PRNG for power-on state (SplitMix64)
+///
+/// This is _not_ suitable for anything that needs real randomness, it only
+/// exists so that "random" power-on state is reproducible from a seed.
+pub(crate) struct SeededRng {
+    state: u64,
+}
+
+impl SeededRng {
+    pub fn new(seed: u64) -> SeededRng {
+        SeededRng { state: seed }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    pub fn next_u8(&mut self) -> u8 {
+        (self.next_u64() >> 56) as u8
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fills_alternating_pages() {
+        let ram = Ram::new_with_pattern(2048, &RamPattern::AlternatingPages);
+        assert_eq!(ram.peek(0x00FF), BusPeekResult::Result(0x00));
+        assert_eq!(ram.peek(0x0100), BusPeekResult::Result(0xFF));
+        assert_eq!(ram.peek(0x0200), BusPeekResult::Result(0x00));
+        assert_eq!(ram.peek(0x07FF), BusPeekResult::Result(0xFF));
+    }
+
+    #[test]
+    fn random_pattern_is_reproducible() {
+        let left = Ram::new_with_pattern(2048, &RamPattern::Random(1234));
+        let right = Ram::new_with_pattern(2048, &RamPattern::Random(1234));
+        let other = Ram::new_with_pattern(2048, &RamPattern::Random(4321));
+        assert_eq!(left.buf, right.buf, "Same seed gave different RAM");
+        assert_ne!(left.buf, other.buf, "Different seeds gave the same RAM");
+    }
 }
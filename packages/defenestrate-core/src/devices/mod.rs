@@ -1,6 +1,38 @@
+#[cfg(feature = "console")]
+mod apu;
+#[cfg(feature = "console")]
+mod audio;
 mod bus;
+#[cfg(feature = "console")]
 mod cartridge;
+#[cfg(feature = "console")]
+mod clock;
+#[cfg(feature = "console")]
+mod controller;
 pub mod cpu;
+#[cfg(feature = "console")]
+mod fds;
+#[cfg(feature = "console")]
+mod hooks;
+#[cfg(feature = "console")]
+mod irq;
+#[cfg(feature = "console")]
 mod mem;
+#[cfg(feature = "console")]
 pub mod nes;
+#[cfg(feature = "console")]
+pub(crate) mod playback;
+#[cfg(feature = "console")]
 mod ppu;
+#[cfg(feature = "console")]
+mod probe;
+#[cfg(feature = "profiler")]
+pub mod profiler;
+#[cfg(feature = "console")]
+mod symbols;
+#[cfg(feature = "console")]
+mod trace;
+#[cfg(all(feature = "std", feature = "console"))]
+mod trace_log;
+#[cfg(feature = "console")]
+mod watch;
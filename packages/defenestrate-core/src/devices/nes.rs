@@ -1,10 +1,482 @@
-use crate::bytes_to_addr;
+use alloc::{boxed::Box, format, string::String, vec, vec::Vec};
+use core::ops::RangeBounds;
+use core::time::Duration;
 
-use super::bus::{cpu_memory_map, BusDevice, BusPeekResult, Motherboard};
-use super::cartridge::{from_rom, ICartridge, WithCartridge};
-use super::cpu::{self, WithCpu};
-use super::mem::Ram;
+use crate::checksum::crc32;
+use crate::error::Result;
+
+use crate::recorder::{Recorder, Sink};
+use crate::telemetry::Telemetry;
+use crate::video::{self, FrameInfo, FRAME_HEIGHT, FRAME_WIDTH, NTSC_PIXEL_ASPECT_RATIO};
+
+use super::audio::AudioOutput;
+use super::bus::{cpu_memory_map, Motherboard};
+use super::cartridge::{from_rom, rom_info};
+use super::controller::{Controller, ControllerPorts};
+use super::cpu::{
+    self,
+    structs::{CpuState, Instruction},
+    utils::bytes_to_addr,
+    WithCpu,
+};
+use super::hooks::{self, Hooks};
+use super::mem::SeededRng;
+use super::playback::Playback;
 use super::ppu;
+use super::probe::Probes;
+#[cfg(feature = "profiler")]
+use super::profiler::{AccessCounts, MemoryProfile};
+use super::trace::Tracer;
+#[cfg(feature = "std")]
+use super::trace_log;
+use super::watch::Watches;
+
+pub use super::apu::{Apu, ApuStatus, Channel};
+pub use super::audio::DEFAULT_SAMPLE_RATE;
+pub use super::bus::{AccuracyMode, BusDevice, BusPeekResult};
+pub use super::cartridge::{
+    BandaiFCGCartridge, CartridgeState, ConsoleType, FME7Cartridge, ICartridge, LatchBoard,
+    LatchCartridge, NROMCartridge, Namco163Audio, Namco163Cartridge, NametableArrangement,
+    PrgRegion, RomInfo, Sunsoft5B,
+};
+pub use super::clock::MasterClock;
+pub use super::controller::{Buttons, ExpansionDevice};
+pub use super::fds::{FdsAdapter, FdsAudio, FdsDisk};
+pub use super::hooks::HookId;
+pub use super::irq::{IrqLine, IrqSource};
+pub use super::mem::{IRam, Ram, RamPattern};
+pub use super::playback::{MAX_SPEED, MIN_SPEED};
+pub use super::ppu::{
+    DirtyRect, IndexedFrame, LayerMask, Palette, Ppu2C02, PpuDebugView, PpuState, TileEntry,
+    FRAME_SIZE, INDEXED_FRAME_SIZE, NAMETABLE_SIZE,
+};
+pub use super::probe::{Comparator, Probe, ProbeHit, ProbeId};
+pub use super::symbols::Symbols;
+pub use super::trace::{AccessKind, BusAccess, BusTrace, TraceDevice};
+#[cfg(feature = "std")]
+pub use super::trace_log::{TraceFormat, TraceLogger};
+pub use super::watch::{Watch, WatchId, WatchOperand};
+
+/// The size of the console's internal RAM, mirrored across $0000-$1FFF
+const INTERNAL_RAM_SIZE: usize = 0x800;
+
+/// Seed for `PowerOnConfig::cpu_randomize` when RAM isn't randomized as well
+const DEFAULT_CPU_SEED: u64 = 0x6502;
+
+/// How long the PPU ignores writes after power-on, in master (PPU) cycles
+///
+/// This is 29658 CPU cycles, cf. https://wiki.nesdev.com/w/index.php/PPU_power_up_state
+const PPU_WARMUP_CYCLES: u64 = 29658 * 3;
+
+/// The number of scanlines in a frame, including vblank and the pre-render line
+const SCANLINES_PER_FRAME: i16 = 262;
+
+/// The number of dots (PPU cycles) in a scanline
+const DOTS_PER_SCANLINE: u16 = 341;
+
+/// The scanline that vblank starts on
+const VBLANK_START_SCANLINE: i16 = 241;
+
+/// How long an NTSC frame lasts, with the PPU at a quarter of the 236.25/11MHz
+/// master clock
+const NTSC_FRAME_DURATION: Duration = Duration::from_nanos(16_639_263);
+
+/// Which console is being emulated
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Console {
+    /// The NES, as sold outside of Japan
+    Nes,
+    /// The Famicom, whose second controller has a microphone in place of
+    /// Select and Start
+    Famicom,
+}
+
+/// Configuration for the state of the console when it's first powered on
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct PowerOnConfig {
+    /// The console variant to emulate
+    pub console: Console,
+    /// The pattern to fill the 2k of internal RAM with
+    pub ram_pattern: RamPattern,
+    /// Whether to randomize the A, X, and Y registers
+    ///
+    /// These are seeded from `ram_pattern` if it's `RamPattern::Random`, and
+    /// from a fixed seed otherwise, so that power-on is always reproducible.
+    pub cpu_randomize: bool,
+    /// Whether the PPU ignores writes to PPUCTRL, PPUMASK, PPUSCROLL, and
+    /// PPUADDR for the first ~29658 CPU cycles, like it does on hardware
+    ///
+    /// Some homebrew doesn't wait for the PPU to warm up, and only works with
+    /// this disabled.
+    pub ppu_warmup: bool,
+}
+
+impl Default for PowerOnConfig {
+    fn default() -> PowerOnConfig {
+        PowerOnConfig {
+            console: Console::Nes,
+            ram_pattern: RamPattern::AllZero,
+            cpu_randomize: false,
+            ppu_warmup: true,
+        }
+    }
+}
+
+/// The TV standard being emulated
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Region {
+    /// 60Hz, with 262 scanlines per frame. This is the only region emulated
+    /// so far.
+    Ntsc,
+}
+
+impl Region {
+    /// How much wider than tall each pixel is on a TV of this standard
+    pub fn pixel_aspect_ratio(self) -> f32 {
+        match self {
+            Region::Ntsc => NTSC_PIXEL_ASPECT_RATIO,
+        }
+    }
+
+    /// How long a frame lasts on a console of this standard
+    ///
+    /// An NTSC frame is 89341.5 PPU cycles on average, since the pre-render
+    /// line is a dot short on every other frame, which is about 60.0988 frames
+    /// per second.
+    pub fn frame_duration(self) -> Duration {
+        match self {
+            Region::Ntsc => NTSC_FRAME_DURATION,
+        }
+    }
+
+    /// How long `ppu_cycles` PPU cycles last on a console of this standard
+    pub fn duration_of(self, ppu_cycles: u64) -> Duration {
+        match self {
+            // the PPU runs at a quarter of 236.25/11MHz, which is 945/176 PPU
+            // cycles per microsecond
+            Region::Ntsc => Duration::from_nanos((u128::from(ppu_cycles) * 176_000 / 945) as u64),
+        }
+    }
+}
+
+/// Everything about the machine being emulated, in one place
+///
+/// Build one up from `NesConfig::ntsc()`, like
+/// `NesConfig::ntsc().with_console(Console::Famicom).with_overscan(8)`, and
+/// pass it to `Nes::new_with_config`. `Nes::config` gives back the
+/// configuration a `Nes` is running with, including changes made since it was
+/// created, and it's captured in every `DebugSnapshot`.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct NesConfig {
+    pub region: Region,
+    /// The console and its state at power-on
+    pub power_on: PowerOnConfig,
+    /// How many pixels a frontend should crop from each edge of the frame,
+    /// like the overscan on a TV
+    ///
+    /// The core always renders the full 256x240 frame, so this is only a hint.
+    pub overscan: u8,
+    /// The colors to turn the PPU's output into RGB with
+    pub palette: Palette,
+    /// Whether to render the background a scanline at a time when that gives
+    /// the same result, see `Nes::set_batch_rendering`
+    pub batch_rendering: bool,
+    /// How much of the hardware's edge-case behavior to emulate, see
+    /// `AccuracyMode`
+    pub accuracy: AccuracyMode,
+    /// The sample rate for `Nes::take_audio_samples`, in Hz, or 0 to turn
+    /// audio output off
+    pub sample_rate: u32,
+}
+
+impl NesConfig {
+    /// An NTSC NES with the default power-on state and built-in palette
+    pub fn ntsc() -> NesConfig {
+        NesConfig {
+            region: Region::Ntsc,
+            power_on: PowerOnConfig::default(),
+            overscan: 0,
+            palette: Palette::default(),
+            batch_rendering: true,
+            accuracy: AccuracyMode::default(),
+            sample_rate: DEFAULT_SAMPLE_RATE,
+        }
+    }
+
+    pub fn with_console(mut self, console: Console) -> NesConfig {
+        self.power_on.console = console;
+        self
+    }
+
+    pub fn with_power_on(mut self, power_on: PowerOnConfig) -> NesConfig {
+        self.power_on = power_on;
+        self
+    }
+
+    pub fn with_ram_pattern(mut self, pattern: RamPattern) -> NesConfig {
+        self.power_on.ram_pattern = pattern;
+        self
+    }
+
+    pub fn with_overscan(mut self, overscan: u8) -> NesConfig {
+        self.overscan = overscan;
+        self
+    }
+
+    pub fn with_palette(mut self, palette: Palette) -> NesConfig {
+        self.palette = palette;
+        self
+    }
+
+    pub fn with_batch_rendering(mut self, enabled: bool) -> NesConfig {
+        self.batch_rendering = enabled;
+        self
+    }
+
+    pub fn with_accuracy(mut self, accuracy: AccuracyMode) -> NesConfig {
+        self.accuracy = accuracy;
+        self
+    }
+
+    pub fn with_sample_rate(mut self, sample_rate: u32) -> NesConfig {
+        self.sample_rate = sample_rate;
+        self
+    }
+}
+
+impl Default for NesConfig {
+    fn default() -> NesConfig {
+        NesConfig::ntsc()
+    }
+}
+
+/// Sets up a `Nes` in a known state, for tests and tools
+///
+/// Besides the cartridge and configuration, this can load an image into
+/// internal RAM and start the CPU somewhere other than the reset vector. These
+/// apply once the console has powered on, so a reset or power cycle afterwards
+/// goes back to the cartridge's reset vector and the usual RAM pattern.
+///
+/// Without a cartridge, the console gets a blank NROM board with every vector
+/// pointing at $0000, so a short program can go straight into RAM:
+///
+/// ```text
+/// let nes = NesBuilder::new()
+///     .with_ram_image(&[0xA9, 0x42, 0x85, 0x10]) // LDA #$42; STA $10
+///     .build();
+/// ```
+pub struct NesBuilder {
+    cart: Option<Box<dyn ICartridge>>,
+    config: NesConfig,
+    ram_image: Option<Vec<u8>>,
+    pc: Option<u16>,
+}
+
+impl NesBuilder {
+    pub fn new() -> NesBuilder {
+        NesBuilder {
+            cart: None,
+            config: NesConfig::default(),
+            ram_image: None,
+            pc: None,
+        }
+    }
+
+    pub fn with_cart(mut self, cart: Box<dyn ICartridge>) -> NesBuilder {
+        self.cart = Some(cart);
+        self
+    }
+
+    /// Plug in the cartridge for an iNES ROM
+    pub fn with_rom(self, buf: &[u8]) -> Result<NesBuilder> {
+        Ok(self.with_cart(from_rom(buf)?))
+    }
+
+    pub fn with_config(mut self, config: NesConfig) -> NesBuilder {
+        self.config = config;
+        self
+    }
+
+    /// Copy `image` into internal RAM, starting at $0000
+    ///
+    /// Panics if `image` is bigger than the console's 2k of RAM.
+    pub fn with_ram_image(mut self, image: &[u8]) -> NesBuilder {
+        assert!(
+            image.len() <= INTERNAL_RAM_SIZE,
+            "A RAM image can be at most 2k, not {} bytes",
+            image.len()
+        );
+        self.ram_image = Some(Vec::from(image));
+        self
+    }
+
+    /// Start the CPU at `pc` instead of the reset vector
+    pub fn with_pc(mut self, pc: u16) -> NesBuilder {
+        self.pc = Some(pc);
+        self
+    }
+
+    pub fn build(self) -> Nes {
+        let cart = self.cart.unwrap_or_else(|| {
+            Box::new(NROMCartridge::from_parts(
+                vec![0u8; 0x8000],
+                vec![0u8; 0x2000],
+                NametableArrangement::Horizontal,
+            ))
+        });
+        let mut nes = Nes::new_with_config(cart, self.config);
+        if let Some(image) = self.ram_image {
+            nes.ram.load(0, &image);
+        }
+        if let Some(pc) = self.pc {
+            nes.cpu.force_pc(pc);
+        }
+        nes
+    }
+}
+
+impl Default for NesBuilder {
+    fn default() -> NesBuilder {
+        NesBuilder::new()
+    }
+}
+
+/// A `Nes` taken apart, from `Nes::into_parts`
+///
+/// The CPU, PPU, RAM and cartridge can be swapped out or wrapped before
+/// putting the console back together with `Nes::from_parts`. The rest of the
+/// console (the APU, controllers, clock, hooks and so on) is kept as it was,
+/// so a round trip with nothing changed picks up exactly where it left off.
+///
+/// ```text
+/// let mut parts = nes.into_parts();
+/// parts.ram = Box::new(LoggingRam::new(parts.ram));
+/// let nes = Nes::from_parts(parts);
+/// ```
+pub struct NesParts {
+    pub cpu: cpu::Cpu6502,
+    pub ppu: Ppu2C02,
+    /// The 2k of internal RAM, which has to stay 2k
+    pub ram: Box<dyn IRam>,
+    pub cart: Box<dyn ICartridge>,
+    board: Board,
+}
+
+/// Everything in a `Nes` besides the devices in `NesParts`
+struct Board {
+    apu: Apu,
+    audio: AudioOutput,
+    controllers: ControllerPorts,
+    last_bus_value: u8,
+    clock: MasterClock,
+    frame_count: u64,
+    is_cpu_idle: bool,
+    irq: IrqLine,
+    config: PowerOnConfig,
+    overscan: u8,
+    hooks: Hooks,
+    probes: Probes,
+    watches: Watches,
+    watch_hit: Option<WatchId>,
+    symbols: Symbols,
+    call_depth: usize,
+    playback: Playback,
+    recorder: Option<Recorder>,
+    tracer: Tracer,
+    #[cfg(feature = "std")]
+    trace_logger: Option<TraceLogger>,
+    telemetry: Telemetry,
+    #[cfg(feature = "profiler")]
+    cpu_profile: Option<AccessCounts>,
+}
+
+/// The state of the console's hardware, from `Nes::save_state`
+///
+/// This includes the `NesConfig` the console was running with, and a copy of
+/// the cartridge, so loading a state made in another game switches back to
+/// that game. Debugging aids, like hooks, probes, and any recording, aren't
+/// part of it.
+#[derive(Clone)]
+pub struct SaveState {
+    config: NesConfig,
+    cpu: cpu::Cpu6502,
+    ppu: Box<PpuState>,
+    palette: Vec<u8>,
+    ram: Vec<u8>,
+    apu: Apu,
+    controllers: [Controller; 2],
+    cart: CartridgeState,
+    clock: MasterClock,
+    frame_count: u64,
+    is_cpu_idle: bool,
+    irq: IrqLine,
+    last_bus_value: u8,
+}
+
+/// A copy of the state of the whole console, from `Nes::debug_snapshot`
+///
+/// With the `serde` feature enabled this can be serialized, to check against
+/// golden snapshots or to diff between emulator versions.
+#[derive(Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct DebugSnapshot {
+    pub cpu: CpuState,
+    pub ppu: Box<PpuState>,
+    /// The PPU's 32 bytes of palette RAM
+    pub palette: Vec<u8>,
+    /// The 2k of internal RAM
+    pub ram: Vec<u8>,
+    pub apu: Apu,
+    pub cart: CartridgeState,
+    /// The number of master (PPU) cycles since power-on
+    pub cycles: u64,
+    pub last_bus_value: u8,
+    /// The configuration the console was running with
+    pub config: NesConfig,
+}
+
+/// The PPU's memory as the PPU sees it, from `Nes::dump_debug_data`
+#[derive(Clone, PartialEq)]
+pub struct DebugData {
+    /// The four logical nametables at $2000-$2FFF, with mirroring applied
+    pub nametables: Box<[u8; 4 * NAMETABLE_SIZE]>,
+    /// The PPU's 32 bytes of palette RAM
+    pub palette: [u8; 32],
+    /// The pattern tables at $0000-$1FFF, with the current CHR banks
+    pub chr: Box<[u8; 0x2000]>,
+}
+
+impl DebugData {
+    /// Get one of the four logical nametables
+    ///
+    /// Panics if `idx` isn't 0-3.
+    pub fn nametable(&self, idx: usize) -> &[u8; NAMETABLE_SIZE] {
+        let start = idx * NAMETABLE_SIZE;
+        self.nametables[start..start + NAMETABLE_SIZE]
+            .try_into()
+            .expect("Nametable index out of range")
+    }
+}
+
+/// Where `Nes::run_until` should stop
+///
+/// PPU positions are the dot the PPU is about to draw, so stopping at a dot
+/// means everything before it has happened and nothing after it has.
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum Breakpoint {
+    /// The start of the next vblank, right after the vblank flag is set
+    NextVblankStart,
+    /// The start of the next time the PPU reaches this scanline (0-261)
+    NextScanline(i16),
+    /// After this many more CPU instructions have finished
+    CpuInstructionCount(u64),
+    /// The next time the PPU reaches this dot (0-340) on this scanline (0-261)
+    PpuDot { scanline: i16, dot: u16 },
+}
 
 /// A struct representing the NES as a whole unit
 pub struct Nes {
@@ -13,83 +485,448 @@ pub struct Nes {
     /// The NES PPU
     ppu: ppu::Ppu2C02,
     /// The 2k RAM installed on the NES
-    ram: Ram,
+    ram: Box<dyn IRam>,
+    /// The APU's sound channels, registers, and counters
+    apu: Apu,
+    /// The samples made from the APU's output, waiting to be taken
+    audio: AudioOutput,
+    /// The controller ports, and whatever is in the expansion port
+    controllers: ControllerPorts,
     /// The last value on the main address bus
     last_bus_value: u8,
-    /// A tracking var for the number of cycles executed
+    /// The time since power-on
     ///
     /// This is used for things like DMA synchronization and PPU/CPU clock timing
-    cycles: usize,
+    clock: MasterClock,
+    /// The number of frames the PPU has finished since power-on
+    frame_count: u64,
     /// Whether the CPU is ready to execute a new instruction
     is_cpu_idle: bool,
+    /// The CPU's IRQ input, held by whichever devices want an interrupt
+    irq: IrqLine,
     /// The cartridge containing the game to be played
     cart: Box<dyn ICartridge>,
+    /// The power-on configuration, kept around for power cycling
+    config: PowerOnConfig,
+    /// The overscan hint from `NesConfig`
+    overscan: u8,
+    /// Callbacks registered by the embedder
+    hooks: Hooks,
+    /// Conditions for `run_until_probe` to stop at
+    probes: Probes,
+    /// Conditions for `run_until_watch` and `run_for` to stop at
+    watches: Watches,
+    /// The watch that last paused `run_for`, if it hasn't been taken
+    watch_hit: Option<WatchId>,
+    /// Labels for the game's code and data, for debugging
+    symbols: Symbols,
+    /// The subroutine depth for `dbg_step_cpu_annotated`
+    call_depth: usize,
+    /// The speed and pause state for `run_for`
+    playback: Playback,
+    /// The recording in progress, if there is one
+    recorder: Option<Recorder>,
+    /// The bus trace in progress or waiting to be taken, if there is one
+    tracer: Tracer,
+    /// The instruction log, if there is one
+    #[cfg(feature = "std")]
+    trace_logger: Option<TraceLogger>,
+    /// Frame pacing statistics, mostly filled in by the runner
+    telemetry: Telemetry,
+    /// Access counts for the CPU bus, if profiling is enabled
+    #[cfg(feature = "profiler")]
+    cpu_profile: Option<AccessCounts>,
 }
 
 impl Motherboard for Nes {
     fn read(&mut self, addr: u16) -> u8 {
+        let global_addr = addr;
         let (device, addr) = cpu_memory_map::match_addr(addr);
         let res = match device {
-            cpu_memory_map::Device::Cartridge => self.cart.read_prg(addr, self.last_bus_value),
+            cpu_memory_map::Device::Cartridge(region) => {
+                self.cart.read_prg(region, self.last_bus_value)
+            }
             cpu_memory_map::Device::RAM => self.ram.read(addr, self.last_bus_value),
             cpu_memory_map::Device::PPUControl => ppu::control_port_read(self, addr),
+            cpu_memory_map::Device::ApuIo => self.read_apu_io(addr),
+            cpu_memory_map::Device::Controllers => self.controllers.read(addr, self.last_bus_value),
             cpu_memory_map::Device::Unmapped => self.last_bus_value,
         };
-        self.last_bus_value = res;
+        self.tracer
+            .record(self.clock, global_addr, res, AccessKind::Read, &device);
+        #[cfg(feature = "profiler")]
+        if let Some(profile) = self.cpu_profile.as_mut() {
+            profile.record_read(global_addr);
+        }
+        // APUSTATUS is read inside the CPU, so it never reaches the data bus
+        if (device, addr) != (cpu_memory_map::Device::ApuIo, cpu_memory_map::APU_STATUS) {
+            self.last_bus_value = res;
+        }
         res
     }
 
     fn peek(&self, addr: u16) -> Option<u8> {
         let (device, addr) = cpu_memory_map::match_addr(addr);
         match device {
-            cpu_memory_map::Device::Cartridge => self.cart.peek_prg(addr),
+            cpu_memory_map::Device::Cartridge(region) => self.cart.peek_prg(region),
             cpu_memory_map::Device::RAM => self.ram.peek(addr),
             cpu_memory_map::Device::PPUControl => BusPeekResult::MutableRead,
+            // reading APUSTATUS acknowledges the frame IRQ
+            cpu_memory_map::Device::ApuIo if addr == cpu_memory_map::APU_STATUS => {
+                BusPeekResult::MutableRead
+            }
+            cpu_memory_map::Device::ApuIo => BusPeekResult::Unmapped,
+            cpu_memory_map::Device::Controllers => self.controllers.peek(addr),
             cpu_memory_map::Device::Unmapped => BusPeekResult::Unmapped,
         }
         .to_optional()
     }
 
+    fn ppu_position(&self) -> Option<(u16, u16)> {
+        Some((self.current_scanline(), self.current_dot()))
+    }
+
     fn write(&mut self, addr: u16, data: u8) {
+        #[cfg(feature = "profiler")]
+        if let Some(profile) = self.cpu_profile.as_mut() {
+            profile.record_write(addr);
+        }
+        if !self.hooks.write.is_empty() {
+            self.hooks.run_write(addr, data);
+        }
+        let global_addr = addr;
         let (device, addr) = cpu_memory_map::match_addr(addr);
+        self.tracer
+            .record(self.clock, global_addr, data, AccessKind::Write, &device);
         match device {
-            cpu_memory_map::Device::Cartridge => self.cart.write_prg(addr, data),
+            cpu_memory_map::Device::Cartridge(region) => {
+                // any of these could be a bank switch, which the batch
+                // renderer has to see before it takes effect
+                ppu::flush_batch_renderer(self);
+                self.cart.write_prg(region, data)
+            }
             cpu_memory_map::Device::RAM => self.ram.write(addr, data),
             cpu_memory_map::Device::PPUControl => ppu::control_port_write(self, addr, data),
+            cpu_memory_map::Device::ApuIo => self.apu.write(addr, data),
+            cpu_memory_map::Device::Controllers => {
+                // $4017 is the APU's frame counter when written
+                if addr == 1 {
+                    self.apu.write(0x17, data);
+                }
+                self.controllers.write(addr, data);
+            }
             cpu_memory_map::Device::Unmapped => {}
         };
         self.last_bus_value = data;
     }
+
+    fn accuracy(&self) -> AccuracyMode {
+        self.ppu.accuracy()
+    }
 }
 
 impl Nes {
-    pub fn new(cart: Box<dyn ICartridge>) -> Nes {
-        let cpu = cpu::Cpu6502::new();
-        let ppu = ppu::Ppu2C02::new();
-        let ram = Ram::new(2048);
+    pub fn new(cart: Box<dyn ICartridge>, config: PowerOnConfig) -> Nes {
+        Nes::new_with_config(cart, NesConfig::ntsc().with_power_on(config))
+    }
+
+    /// Create a new `Nes` that turns PPU colors into RGB with `palette`
+    ///
+    /// Use `Palette::from_pal` to load a palette from a .pal file.
+    pub fn new_with_palette(
+        cart: Box<dyn ICartridge>,
+        config: PowerOnConfig,
+        palette: Palette,
+    ) -> Nes {
+        Nes::new_with_config(
+            cart,
+            NesConfig::ntsc()
+                .with_power_on(config)
+                .with_palette(palette),
+        )
+    }
+
+    /// Create a new `Nes` for the machine described by `config`
+    pub fn new_with_config(cart: Box<dyn ICartridge>, config: NesConfig) -> Nes {
         let mut nes = Nes {
-            cpu,
-            ppu,
-            ram,
+            cpu: cpu::Cpu6502::new(),
+            ppu: ppu::Ppu2C02::new(),
+            ram: Box::new(Ram::new(INTERNAL_RAM_SIZE)),
+            apu: Apu::new(),
+            audio: AudioOutput::new(config.sample_rate),
+            controllers: ControllerPorts::new(config.power_on.console),
             last_bus_value: 0x00,
-            cycles: 0,
+            clock: MasterClock::new(),
+            frame_count: 0,
             is_cpu_idle: true,
+            irq: IrqLine::new(),
             cart,
+            config: config.power_on,
+            overscan: config.overscan,
+            hooks: Hooks::default(),
+            probes: Probes::default(),
+            watches: Watches::default(),
+            watch_hit: None,
+            symbols: Symbols::new(),
+            call_depth: 0,
+            playback: Playback::default(),
+            recorder: None,
+            tracer: Tracer::Off,
+            #[cfg(feature = "std")]
+            trace_logger: None,
+            telemetry: Telemetry::new(),
+            #[cfg(feature = "profiler")]
+            cpu_profile: None,
         };
-        let fst = nes.read(0xFFFC);
-        let snd = nes.read(0xFFFD);
-        let addr = bytes_to_addr!(fst, snd);
-        nes.cpu_mut().state.pc = addr;
+        nes.apply_config(config);
+        nes.power_on();
         return nes;
     }
 
-    pub fn new_from_buf(buf: &[u8]) -> Nes {
-        let cart = from_rom(&buf);
-        Nes::new(Box::new(cart))
+    /// Switch to the settings in `config`, without resetting anything
+    fn apply_config(&mut self, config: NesConfig) {
+        self.config = config.power_on;
+        self.overscan = config.overscan;
+        self.ppu.set_output_palette(config.palette);
+        self.ppu.set_batch_rendering(config.batch_rendering);
+        self.ppu.set_accuracy(config.accuracy);
+        self.set_sample_rate(config.sample_rate);
+    }
+
+    /// Swap the cartridge for one built from it, like a wrapper that logs or
+    /// counts its accesses
+    ///
+    /// Nothing is reset, so the new cartridge picks up mid-frame, and the rest
+    /// of the console carries on exactly where it left off.
+    ///
+    /// ```text
+    /// let nes = nes.with_cart(|cart| Box::new(LoggingCart::new(cart)));
+    /// ```
+    ///
+    /// A wrapper has to forward every `ICartridge` method to the cartridge it
+    /// wraps, including the ones with default bodies. See `ICartridge`.
+    pub fn with_cart<F>(mut self, wrap: F) -> Nes
+    where
+        F: FnOnce(Box<dyn ICartridge>) -> Box<dyn ICartridge>,
+    {
+        self.cart = wrap(self.cart);
+        self
+    }
+
+    /// Take the console apart, to swap out or instrument its devices
+    ///
+    /// See `NesParts`.
+    pub fn into_parts(self) -> NesParts {
+        let Nes {
+            cpu,
+            ppu,
+            ram,
+            apu,
+            audio,
+            controllers,
+            last_bus_value,
+            clock,
+            frame_count,
+            is_cpu_idle,
+            irq,
+            cart,
+            config,
+            overscan,
+            hooks,
+            probes,
+            watches,
+            watch_hit,
+            symbols,
+            call_depth,
+            playback,
+            recorder,
+            tracer,
+            #[cfg(feature = "std")]
+            trace_logger,
+            telemetry,
+            #[cfg(feature = "profiler")]
+            cpu_profile,
+        } = self;
+        NesParts {
+            cpu,
+            ppu,
+            ram,
+            cart,
+            board: Board {
+                apu,
+                audio,
+                controllers,
+                last_bus_value,
+                clock,
+                frame_count,
+                is_cpu_idle,
+                irq,
+                config,
+                overscan,
+                hooks,
+                probes,
+                watches,
+                watch_hit,
+                symbols,
+                call_depth,
+                playback,
+                recorder,
+                tracer,
+                #[cfg(feature = "std")]
+                trace_logger,
+                telemetry,
+                #[cfg(feature = "profiler")]
+                cpu_profile,
+            },
+        }
+    }
+
+    /// Put a console taken apart by `into_parts` back together
+    ///
+    /// Nothing is reset, so replacement devices pick up mid-frame, in
+    /// whatever state they're in. Call `reset` or `power_cycle` afterwards
+    /// for a clean start.
+    ///
+    /// Panics if `parts.ram` isn't 2k, since the console mirrors it across
+    /// $0000-$1FFF.
+    pub fn from_parts(parts: NesParts) -> Nes {
+        assert_eq!(
+            parts.ram.dump().len(),
+            INTERNAL_RAM_SIZE,
+            "Internal RAM has to be 2k"
+        );
+        let NesParts {
+            cpu,
+            ppu,
+            ram,
+            cart,
+            board,
+        } = parts;
+        Nes {
+            cpu,
+            ppu,
+            ram,
+            apu: board.apu,
+            audio: board.audio,
+            controllers: board.controllers,
+            last_bus_value: board.last_bus_value,
+            clock: board.clock,
+            frame_count: board.frame_count,
+            is_cpu_idle: board.is_cpu_idle,
+            irq: board.irq,
+            cart,
+            config: board.config,
+            overscan: board.overscan,
+            hooks: board.hooks,
+            probes: board.probes,
+            watches: board.watches,
+            watch_hit: board.watch_hit,
+            symbols: board.symbols,
+            call_depth: board.call_depth,
+            playback: board.playback,
+            recorder: board.recorder,
+            tracer: board.tracer,
+            #[cfg(feature = "std")]
+            trace_logger: board.trace_logger,
+            telemetry: board.telemetry,
+            #[cfg(feature = "profiler")]
+            cpu_profile: board.cpu_profile,
+        }
+    }
+
+    /// The configuration this `Nes` is running with
+    ///
+    /// This includes any changes since it was created, like a new palette.
+    pub fn config(&self) -> NesConfig {
+        NesConfig {
+            region: Region::Ntsc,
+            power_on: self.config,
+            overscan: self.overscan,
+            palette: self.ppu.output_palette().clone(),
+            batch_rendering: self.ppu.is_batch_rendering(),
+            accuracy: self.ppu.accuracy(),
+            sample_rate: self.audio.sample_rate(),
+        }
+    }
+
+    /// Put every device into its power-on state and jump to the reset vector
+    fn power_on(&mut self) {
+        let config = self.config;
+        self.cpu = cpu::Cpu6502::new();
+        if config.cpu_randomize {
+            let mut rng = SeededRng::new(match config.ram_pattern {
+                // keep the register stream distinct from the RAM stream
+                RamPattern::Random(seed) => !seed,
+                _ => DEFAULT_CPU_SEED,
+            });
+            self.cpu.state.acc = rng.next_u8();
+            self.cpu.state.x = rng.next_u8();
+            self.cpu.state.y = rng.next_u8();
+        }
+        self.ppu.power_on();
+        self.ppu.set_warming_up(config.ppu_warmup);
+        let mut image = [0u8; INTERNAL_RAM_SIZE];
+        config.ram_pattern.fill(&mut image);
+        self.ram.load(0, &image);
+        self.apu.power_on();
+        self.controllers.power_on(config.console);
+        self.cart.power_cycle();
+        self.last_bus_value = 0x00;
+        self.clock = MasterClock::new();
+        self.frame_count = 0;
+        self.call_depth = 0;
+        self.is_cpu_idle = true;
+        self.irq = IrqLine::new();
+        let fst = self.read(0xFFFC);
+        let snd = self.read(0xFFFD);
+        let addr = bytes_to_addr!(fst, snd);
+        self.cpu_mut().state.pc = addr;
+    }
+
+    /// Create a new `Nes` from an iNES ROM, with the default power-on state
+    pub fn new_from_buf(buf: &[u8]) -> Result<Nes> {
+        Nes::new_from_buf_with_config(buf, NesConfig::default())
+    }
+
+    /// Read what an iNES ROM's header says about it, without loading it
+    ///
+    /// This is handy for telling the player why a ROM won't load, say because
+    /// of its mapper or because it's for the Vs. System.
+    pub fn rom_info(buf: &[u8]) -> Result<RomInfo> {
+        rom_info(buf)
+    }
+
+    /// Create a new `Nes` from an iNES ROM, for the machine described by
+    /// `config`
+    pub fn new_from_buf_with_config(buf: &[u8], config: NesConfig) -> Result<Nes> {
+        let cart = from_rom(&buf)?;
+        Ok(Nes::new_with_config(cart, config))
+    }
+
+    /// Apply an IPS or BPS patch to an iNES ROM, and create a new `Nes` from
+    /// the result
+    pub fn new_from_buf_with_patch(rom: &[u8], patch: &[u8]) -> Result<Nes> {
+        let patched = crate::patch::apply_patch(rom, patch)?;
+        Nes::new_from_buf(&patched)
+    }
+
+    /// Create a new `Nes` with the Famicom Disk System plugged in
+    ///
+    /// `bios` is the FDS BIOS ROM, and `disk` is an .fds disk image. The first
+    /// side of the disk starts out in the drive. The console is a
+    /// `Console::Famicom`, since that's the only one the FDS plugs into.
+    pub fn new_from_fds(bios: &[u8], disk: &[u8]) -> Result<Nes> {
+        let disk = FdsDisk::from_fds(disk)?;
+        let adapter = FdsAdapter::new(bios, disk)?;
+        Ok(Nes::new_with_config(
+            Box::new(adapter),
+            NesConfig::ntsc().with_console(Console::Famicom),
+        ))
     }
 
-    #[cfg(not(target = "wasm32"))]
-    pub fn new_from_file(path: &str) -> std::io::Result<Nes> {
+    /// Load an iNES ROM from disk and create a new `Nes` from it
+    #[cfg(feature = "std")]
+    pub fn new_from_file(path: &str) -> Result<Nes> {
         use std::fs::File;
         use std::io::prelude::*;
         use std::path::Path;
@@ -101,33 +938,106 @@ impl Nes {
 
         file.read_to_end(&mut buf)?;
 
-        Ok(Nes::new_from_buf(&buf))
+        Nes::new_from_buf(&buf)
     }
 
     /// Advance the emulator 1 PPU cycle at a time, executing CPU instructions
     /// when appropriate (3 cycles in NTSC mode)
     pub fn tick(&mut self) {
-        self.cycles += 1;
+        self.step();
+    }
+
+    /// Advance the emulator by 1 PPU cycle, returning whether the CPU started
+    /// a new instruction
+    ///
+    /// Devices are clocked in a fixed order, so that each one sees the others
+    /// the way it would on hardware:
+    ///
+    /// 1. The PPU, every cycle. Mappers see its pattern fetches through
+    ///    `read_chr`. Background fetches land on their own dots for boards
+    ///    where `ICartridge::watches_ppu_a12` is true, since the batch
+    ///    renderer stays off for them. Sprite fetches for the next line all
+    ///    happen at dot 258, whatever the accuracy mode, so an A12 counter
+    ///    like the MMC3's sees one edge there rather than spread over dots
+    ///    257-320.
+    /// 2. The CPU, every third cycle. It samples the IRQ line as the last
+    ///    cycle left it, before running.
+    /// 3. The APU, which then updates its sources on the IRQ line. A DMC
+    ///    sample fetch it asks for is read from the bus right away, without
+    ///    stalling the CPU.
+    /// 4. The mapper's CPU clock, for boards that count M2 cycles, which then
+    ///    updates its source on the IRQ line.
+    /// 5. The audio output, which takes the APU's mix with the cartridge's
+    ///    expansion audio added in, and hands any sample it makes to the
+    ///    recording.
+    fn step(&mut self) -> bool {
+        self.clock.tick();
+        if self.ppu.is_warming_up() && self.clock.ppu_cycles() >= PPU_WARMUP_CYCLES {
+            self.ppu.set_warming_up(false);
+        }
         ppu::clock(self);
+        if self.ppu.is_frame_ready() {
+            if let Some(recorder) = self.recorder.as_mut() {
+                recorder.record_frame(self.ppu.get_buffer());
+            }
+            if !self.hooks.frame.is_empty() {
+                hooks::run_frame_hooks(self);
+            }
+            self.tracer.end_frame();
+            self.controllers.clock_frame();
+            self.frame_count += 1;
+            self.telemetry.record_emulated_frame();
+        }
+        if !self.hooks.scanline.is_empty() && self.ppu.state().pixel_cycle == 0 {
+            self.hooks.run_scanline(&self.ppu.debug_state());
+        }
         if self.ppu.is_vblank() {
             cpu::trigger_nmi(self);
             self.ppu.ack_vblank();
         }
-        if self.cycles % 3 != 0 {
-            return; // no CPU ticks required
+        if !self.clock.is_cpu_cycle() {
+            return false; // no CPU ticks required
         }
         // TODO: Tick the gamepad and OAM DMA controllers
         // TODO: test here for oam_dma inactive
-        if self.is_cpu_idle {
-            cpu::exec(self);
+        if self.irq.is_asserted() {
+            // the IRQ line is level-triggered, so this keeps firing until the
+            // handler gets every source to release it
+            cpu::trigger_irq(self);
+        }
+        let started = self.is_cpu_idle;
+        if started {
+            self.run_exec_hooks();
+            self.exec_instruction();
+            self.profile_exec();
         }
         self.is_cpu_idle = cpu::tick(self);
+        self.apu.clock();
+        if let Some(addr) = self.apu.take_dmc_fetch() {
+            let value = self.read(addr);
+            self.apu.fill_dmc_buffer(value);
+        }
+        self.irq.set(IrqSource::APU_FRAME, self.apu.frame_irq());
+        self.irq.set(IrqSource::APU_DMC, self.apu.dmc_irq());
+        self.cart.clock_cpu();
+        self.irq.set(IrqSource::MAPPER, self.cart.irq_pending());
+        if self.audio.is_enabled() {
+            let level = self.apu.output(self.cart.audio_sample());
+            if let (Some(sample), Some(recorder)) = (self.audio.push(level), &mut self.recorder) {
+                recorder.record_sample(sample);
+            }
+        }
+        started
     }
 
+    /// Run the emulator until the PPU finishes the next frame, and return it
     pub fn tick_frame(&mut self) -> &[u8] {
         let mut cycles_watchdog = 0;
         // if we exceed this limit, something is wrong in the frame ready path
         const MAX_CYCLES: i32 = 1_000_000;
+        // the frame ready flag stays up until the next tick, so always tick at
+        // least once to avoid returning the same frame twice
+        self.tick();
         while !self.ppu.is_frame_ready() {
             self.tick();
             cycles_watchdog += 1;
@@ -138,63 +1048,1143 @@ impl Nes {
         return self.ppu.get_buffer();
     }
 
-    /// Run the CPU for one full instruction
+    /// Run the next frame, and return it along with the parts of it that
+    /// changed since the last one
     ///
-    /// This does not accurately advance other parts of the emu, and is only for
-    /// debugging and testing
-    pub fn dbg_step_cpu(&mut self) -> String {
-        let status = cpu::debug(self);
-        // spin until the CPU is done ticking
-        while !cpu::tick(self) {}
-        status
+    /// See `set_dirty_tracking`. Without it, the whole frame is always dirty.
+    pub fn tick_frame_with_dirty_rects(&mut self) -> (&[u8], Vec<DirtyRect>) {
+        self.tick_frame();
+        (self.ppu.get_buffer(), self.ppu.dirty_rects())
     }
 
-    /// Trigger a hardware reset
+    /// Hold `p1` and `p2` on the controllers, then run the next frame
     ///
-    /// This is _not_ the same as stopping the emulator and reloading a ROM!
-    /// There was a physical reset button on the NES that would reset some state
-    /// and force the CPU to go back to the reset vector, but memory would be
-    /// left alone (among other things).
-    pub fn reset(&mut self) {
-        cpu::reset(self);
+    /// The buttons stay held for the whole frame (and after it, until they're
+    /// set again), so the same inputs always give the same frames. This is
+    /// what replays should use.
+    pub fn tick_frame_with_input(&mut self, p1: Buttons, p2: Buttons) -> &[u8] {
+        self.set_buttons(0, p1);
+        self.set_buttons(1, p2);
+        self.tick_frame()
     }
 
-    /// Dump nametables, palette RAM, and CHR ROM to buffers
-    pub fn dump_debug_data(&self) -> (&[u8], &[u8], &[u8]) {
-        return (
-            self.cart.dump_nametables(),
-            self.ppu.dump_palettes(),
-            self.cart.dump_chr(),
-        );
+    /// Run however many frames are due after `elapsed` of real time, and
+    /// return the most recent frame
+    ///
+    /// This is for frontends that are called back once per display refresh,
+    /// which isn't always 60Hz. Frames come due at the console's frame rate,
+    /// scaled by `speed`, and nothing runs while paused. If the host falls
+    /// far behind, some of the lost time is skipped rather than made up.
+    ///
+    /// With watches registered, this also checks them after every
+    /// instruction. The first one to hold pauses the emulator right there,
+    /// partway through the frame, and can be found with `take_watch_hit`.
+    pub fn run_for(&mut self, elapsed: Duration) -> &[u8] {
+        let frame_duration = Region::Ntsc.frame_duration();
+        for _ in 0..self.playback.frames_due(elapsed, frame_duration) {
+            if self.watches.is_empty() {
+                self.tick_frame();
+            } else if let Some(id) = self.tick_frame_watching() {
+                self.dump_trace_on_break();
+                self.watch_hit = Some(id);
+                self.pause();
+                break;
+            }
+        }
+        self.ppu.get_buffer()
     }
-}
 
-impl cpu::WithCpu for Nes {
-    fn cpu(&self) -> &cpu::Cpu6502 {
-        &self.cpu
+    /// Run to the end of the frame like `tick_frame`, unless a watch holds
+    /// after one of the instructions on the way
+    fn tick_frame_watching(&mut self) -> Option<WatchId> {
+        loop {
+            let was_busy = !self.is_cpu_idle;
+            self.step();
+            if was_busy && self.is_cpu_idle {
+                if let Some(id) = self.check_watches() {
+                    return Some(id);
+                }
+            }
+            if self.ppu.is_frame_ready() {
+                return None;
+            }
+        }
     }
 
-    fn cpu_mut(&mut self) -> &mut cpu::Cpu6502 {
-        &mut self.cpu
+    /// How fast `run_for` runs, as a multiple of real time
+    pub fn speed(&self) -> f32 {
+        self.playback.speed()
     }
-}
 
-impl WithCartridge for Nes {
-    fn cart(&self) -> &Box<dyn ICartridge> {
-        &self.cart
+    /// Make `run_for` run faster or slower than real time
+    ///
+    /// `speed` is clamped to between `MIN_SPEED` and `MAX_SPEED`. Runners that
+    /// pace themselves with a `Throttle` should pass it the same speed.
+    pub fn set_speed(&mut self, speed: f32) {
+        self.playback.set_speed(speed);
     }
 
-    fn cart_mut(&mut self) -> &mut Box<dyn ICartridge> {
-        &mut self.cart
+    /// Stop `run_for` from running any frames, until `resume`
+    pub fn pause(&mut self) {
+        self.playback.set_paused(true);
     }
-}
 
-impl ppu::WithPpu for Nes {
-    fn ppu(&self) -> &ppu::Ppu2C02 {
-        &self.ppu
+    pub fn resume(&mut self) {
+        self.playback.set_paused(false);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.playback.is_paused()
+    }
+
+    /// Pause, then run exactly one frame and return it
+    pub fn frame_advance(&mut self) -> &[u8] {
+        self.pause();
+        self.tick_frame()
+    }
+
+    /// How much time has gone by on the console since power-on
+    pub fn emulated_time(&self) -> Duration {
+        Region::Ntsc.duration_of(self.clock.ppu_cycles())
+    }
+
+    /// Run the emulator until it reaches `breakpoint`
+    ///
+    /// This always runs for at least one PPU cycle, so running to the same
+    /// breakpoint again will stop at its next occurrence. The exception is
+    /// `CpuInstructionCount(0)`, which only finishes the current instruction.
+    /// After `CpuInstructionCount`, the CPU is always between instructions.
+    ///
+    /// This panics if a PPU position is off the end of a scanline or frame,
+    /// since it would never be reached.
+    pub fn run_until(&mut self, breakpoint: Breakpoint) {
+        match breakpoint {
+            Breakpoint::NextVblankStart => self.run_until_dot(VBLANK_START_SCANLINE, 1),
+            Breakpoint::NextScanline(scanline) => self.run_until_dot(scanline, 0),
+            Breakpoint::PpuDot { scanline, dot } => self.run_until_dot(scanline, dot),
+            Breakpoint::CpuInstructionCount(count) => {
+                let mut remaining = count;
+                while remaining > 0 {
+                    if self.step() {
+                        remaining -= 1;
+                    }
+                }
+                while !self.is_cpu_idle {
+                    self.step();
+                }
+            }
+        }
+    }
+
+    /// Run the emulator until one of the registered probes fires, or
+    /// `timeout_cycles` CPU cycles have passed
+    ///
+    /// Probes are checked before running anything, and then between CPU
+    /// instructions, so a probe that already holds fires right away. When
+    /// more than one holds, the one added first wins. Returns `None` on a
+    /// timeout, or if there are no probes.
+    pub fn run_until_probe(&mut self, timeout_cycles: u64) -> Option<ProbeHit> {
+        let deadline = self.clock.cpu_cycles() + timeout_cycles;
+        loop {
+            if self.is_cpu_idle {
+                if let Some(hit) = self.probes.check(|addr| self.peek(addr)) {
+                    self.dump_trace_on_break();
+                    return Some(hit);
+                }
+            }
+            if self.clock.cpu_cycles() >= deadline {
+                return None;
+            }
+            self.step();
+        }
+    }
+
+    /// Run the emulator until one of the registered watches holds after an
+    /// instruction, or `timeout_cycles` CPU cycles have passed
+    ///
+    /// Unlike probes, watches are only checked once an instruction finishes,
+    /// so calling this again always runs at least one more instruction. When
+    /// more than one holds, the one added first wins. Returns `None` on a
+    /// timeout, or if there are no watches.
+    pub fn run_until_watch(&mut self, timeout_cycles: u64) -> Option<WatchId> {
+        let deadline = self.clock.cpu_cycles() + timeout_cycles;
+        while self.clock.cpu_cycles() < deadline {
+            let was_busy = !self.is_cpu_idle;
+            self.step();
+            if was_busy && self.is_cpu_idle {
+                if let Some(id) = self.check_watches() {
+                    self.dump_trace_on_break();
+                    return Some(id);
+                }
+            }
+        }
+        None
+    }
+
+    fn check_watches(&self) -> Option<WatchId> {
+        let cpu = &self.cpu.state;
+        self.watches.check(|operand| match operand {
+            WatchOperand::Acc => Some(u16::from(cpu.acc)),
+            WatchOperand::X => Some(u16::from(cpu.x)),
+            WatchOperand::Y => Some(u16::from(cpu.y)),
+            WatchOperand::Stack => Some(u16::from(cpu.stack)),
+            WatchOperand::Pc => Some(cpu.pc),
+            WatchOperand::Status => Some(u16::from(cpu.status.bits())),
+            WatchOperand::Scanline => Some(self.current_scanline()),
+            WatchOperand::Dot => Some(self.current_dot()),
+            WatchOperand::Memory(addr) => self.peek(addr).map(u16::from),
+        })
+    }
+
+    fn run_until_dot(&mut self, scanline: i16, dot: u16) {
+        assert!(
+            (0..SCANLINES_PER_FRAME).contains(&scanline) && dot < DOTS_PER_SCANLINE,
+            "The PPU never reaches dot {} of scanline {}",
+            dot,
+            scanline
+        );
+        // a frame is as long as it takes to get anywhere, but allow for two
+        // in case the dot is skipped on the first pass
+        let max_cycles = 2 * (SCANLINES_PER_FRAME as usize) * (DOTS_PER_SCANLINE as usize);
+        for _ in 0..max_cycles {
+            self.step();
+            let state = self.ppu.state();
+            if state.scanline == scanline && state.pixel_cycle == dot {
+                return;
+            }
+        }
+        panic!("Simulation error: Expected PPU to have reached the breakpoint by now.");
+    }
+
+    /// Start sending every completed frame to `sink`
+    ///
+    /// If a recording is already in progress, it's stopped first and the
+    /// result of stopping it is returned.
+    pub fn start_recording(&mut self, sink: Box<dyn Sink>) -> Option<Result<u64>> {
+        let previous = self.stop_recording();
+        self.recorder = Some(Recorder::new(sink));
+        previous
+    }
+
+    /// Stop recording and finish up the sink
+    ///
+    /// This returns the number of frames recorded, or the first error the sink
+    /// ran into (after which it stopped receiving frames). If nothing was
+    /// being recorded, this returns `None`.
+    pub fn stop_recording(&mut self) -> Option<Result<u64>> {
+        self.recorder.take().map(Recorder::finish)
+    }
+
+    /// Whether a recording is in progress
+    pub fn is_recording(&self) -> bool {
+        self.recorder.is_some()
+    }
+
+    /// Record every access on the CPU bus until the end of the current frame
+    ///
+    /// Called between `tick_frame`s, this traces exactly the next frame. Any
+    /// trace that finished but wasn't taken yet is thrown away.
+    pub fn trace_next_frame(&mut self) {
+        self.tracer = Tracer::Recording {
+            start_cycle: self.clock,
+            trace: BusTrace::default(),
+        };
+    }
+
+    /// Take the bus trace from `trace_next_frame`, once its frame is over
+    ///
+    /// This returns `None` while the frame is still running, and after the
+    /// trace has been taken.
+    pub fn take_bus_trace(&mut self) -> Option<BusTrace> {
+        self.tracer.take()
+    }
+
+    /// Log every instruction the CPU runs from now on to `logger`
+    ///
+    /// Any logger already set is replaced, and returned. Only instructions
+    /// run by the console itself (`tick`, `tick_frame`, `run_until`, and so
+    /// on) are logged, not `dbg_step_cpu`, which returns its line instead.
+    #[cfg(feature = "std")]
+    pub fn set_trace_logger(&mut self, logger: TraceLogger) -> Option<TraceLogger> {
+        self.trace_logger.replace(logger)
+    }
+
+    /// Stop logging instructions, and hand back the logger
+    ///
+    /// Call `TraceLogger::finish` on it to flush it and check for errors.
+    #[cfg(feature = "std")]
+    pub fn take_trace_logger(&mut self) -> Option<TraceLogger> {
+        self.trace_logger.take()
+    }
+
+    #[cfg(feature = "std")]
+    pub fn trace_logger(&self) -> Option<&TraceLogger> {
+        self.trace_logger.as_ref()
+    }
+
+    /// Dump the trace logger's ring buffer, or flush it if it's streaming
+    ///
+    /// This happens on its own when a watch or probe stops the emulator, and
+    /// when a panic drops the `Nes`. See `TraceLogger::dump`.
+    #[cfg(feature = "std")]
+    pub fn dump_trace_log(&mut self) {
+        if let Some(logger) = self.trace_logger.as_mut() {
+            logger.dump();
+        }
+    }
+
+    /// Trade the most recent frame for `frame`, without copying either
+    ///
+    /// This is for frontends that want to keep a frame around (say, to
+    /// present it later) while the next one renders. Pass the same buffer
+    /// back in each time, and the two buffers just change hands. An empty
+    /// `Box::default()` is fine for the first call, and gets replaced with a
+    /// new buffer of `FRAME_SIZE` bytes.
+    ///
+    /// Until the next frame is done, `frame_hash` and `screenshot_png` see
+    /// the old contents of `frame` as the most recent frame.
+    pub fn swap_buffers(&mut self, frame: &mut Box<[u8]>) {
+        self.ppu.swap_buffers(frame);
+    }
+
+    /// Frame pacing statistics, for a performance HUD
+    pub fn telemetry(&self) -> &Telemetry {
+        &self.telemetry
+    }
+
+    /// Get the frame pacing statistics for updating, which the runner should
+    /// do once per presented frame
+    pub fn telemetry_mut(&mut self) -> &mut Telemetry {
+        &mut self.telemetry
+    }
+
+    /// Hash the most recent frame, for regression tests
+    ///
+    /// See `Ppu2C02::frame_hash` for the details of the hash.
+    pub fn frame_hash(&self) -> u64 {
+        self.ppu.frame_hash()
+    }
+
+    /// Checksum the parts of the console two copies running in lockstep should
+    /// agree on, to notice when they've desynced
+    ///
+    /// This covers the time since power-on, the CPU and PPU registers, RAM,
+    /// OAM, palette RAM, the nametables and pattern tables as the PPU sees
+    /// them, and the cartridge's save RAM. Mapper registers only show up
+    /// through what they switch in, but a desync reaches RAM soon enough. The
+    /// checksum is only meant for comparing against the same version of this
+    /// crate.
+    pub fn state_checksum(&self) -> u32 {
+        let cpu = &self.cpu.state;
+        let ppu = self.ppu.state();
+        let debug_data = self.dump_debug_data();
+        let mut state = Vec::with_capacity(0x4000);
+        state.extend_from_slice(&self.clock.ppu_cycles().to_le_bytes());
+        state.extend_from_slice(&[cpu.acc, cpu.x, cpu.y, cpu.stack, cpu.status.bits()]);
+        state.extend_from_slice(&cpu.pc.to_le_bytes());
+        state.extend_from_slice(&ppu.v.to_le_bytes());
+        state.extend_from_slice(&ppu.t.to_le_bytes());
+        state.extend_from_slice(&[ppu.x, ppu.w as u8, ppu.control, ppu.mask, ppu.status]);
+        state.extend_from_slice(self.ram.dump());
+        state.extend_from_slice(&ppu.oam);
+        state.extend_from_slice(&debug_data.palette);
+        state.extend_from_slice(&debug_data.nametables[..]);
+        state.extend_from_slice(&debug_data.chr[..]);
+        if let Some(save_data) = self.cart.save_data() {
+            state.extend_from_slice(save_data);
+        }
+        crc32(&state)
+    }
+
+    /// The size of the frames from `tick_frame`, and the shape of their pixels
+    pub fn frame_info(&self) -> FrameInfo {
+        FrameInfo {
+            width: FRAME_WIDTH,
+            height: FRAME_HEIGHT,
+            pixel_aspect_ratio: Region::Ntsc.pixel_aspect_ratio(),
+        }
+    }
+
+    /// Upscale the most recent frame by `scale`, see `video::scale_frame`
+    pub fn scale_frame(&self, scale: usize) -> Vec<u8> {
+        video::scale_frame(FRAME_WIDTH, FRAME_HEIGHT, self.ppu.get_buffer(), scale)
+    }
+
+    /// Encode the most recent frame as a PNG
+    #[cfg(feature = "png")]
+    pub fn screenshot_png(&self) -> Vec<u8> {
+        video::encode_png(FRAME_WIDTH, FRAME_HEIGHT, self.ppu.get_buffer())
+    }
+
+    /// Run the CPU for one full instruction
+    ///
+    /// This does not accurately advance other parts of the emu, and is only for
+    /// debugging and testing
+    pub fn dbg_step_cpu(&mut self) -> String {
+        self.run_exec_hooks();
+        let status = cpu::debug(self);
+        self.profile_exec();
+        // spin until the CPU is done ticking
+        while !cpu::tick(self) {}
+        status
+    }
+
+    /// Run the CPU for one full instruction, like `dbg_step_cpu`, and note
+    /// where it went
+    ///
+    /// The line is the same as from `dbg_step_cpu`, followed by the
+    /// subroutine depth and, for branches, jumps, calls, and returns, where
+    /// the CPU ended up. Branches also say whether they were taken, so that
+    /// control flow can be followed from the log. For example:
+    ///
+    /// ```text
+    /// C72A  D0 03     BNE $C72F  ...  CYC:30  ; depth 1, taken -> $C72F
+    /// ```
+    ///
+    /// The depth goes up with JSR, BRK, and interrupts, and down with RTS and
+    /// RTI, counting from the first annotated step or the last reset. It never
+    /// goes below 0, so returning from a subroutine entered before then
+    /// doesn't throw it off.
+    ///
+    /// With symbols set, where the CPU went is also labeled, and an
+    /// instruction at a labeled address gets the label on a line of its own
+    /// first, like in an assembly listing:
+    ///
+    /// ```text
+    /// main_loop:
+    /// C72A  D0 03     BNE $C72F  ...  CYC:30  ; depth 1, taken -> $C72F (skip)
+    /// ```
+    pub fn dbg_step_cpu_annotated(&mut self) -> String {
+        // the interrupt runs before the instruction, which is in the handler
+        if self.cpu.interrupt_pending {
+            self.call_depth += 1;
+        }
+        // the line shows where the CPU was, even if an interrupt moves it
+        let label = self.symbols.label(self.cpu.state.pc).map(String::from);
+        let line = self.dbg_step_cpu();
+        let state = &self.cpu.state;
+        let depth = self.call_depth;
+        match state.instr {
+            Instruction::JSR | Instruction::BRK => self.call_depth += 1,
+            Instruction::RTS | Instruction::RTI => {
+                self.call_depth = self.call_depth.saturating_sub(1)
+            }
+            _ => {}
+        }
+        let line = match cpu::utils::describe_control_flow(state) {
+            Some(note) => match self.symbols.label(state.pc) {
+                Some(target) => format!("{}  ; depth {}, {} ({})", line, depth, note, target),
+                None => format!("{}  ; depth {}, {}", line, depth, note),
+            },
+            None => format!("{}  ; depth {}", line, depth),
+        };
+        match label {
+            Some(label) => format!("{}:\n{}", label, line),
+            None => line,
+        }
+    }
+
+    /// Trigger a hardware reset
+    ///
+    /// This is _not_ the same as stopping the emulator and reloading a ROM!
+    /// There was a physical reset button on the NES that would reset some state
+    /// and force the CPU to go back to the reset vector, but memory would be
+    /// left alone (among other things).
+    ///
+    /// The PPU clears its control registers and ignores writes to them until
+    /// the end of the next vblank, and the mapper gets a chance to handle the
+    /// reset however its board would. To start over from scratch, use
+    /// `power_cycle` instead.
+    pub fn reset(&mut self) {
+        self.ppu.reset();
+        self.apu.reset();
+        self.cart.reset();
+        self.is_cpu_idle = true;
+        self.call_depth = 0;
+        cpu::reset(self);
+    }
+
+    /// Turn the console off and back on again
+    ///
+    /// Unlike `reset`, this puts the CPU, PPU, RAM, and mapper back into their
+    /// power-on state, using the same `PowerOnConfig` as when this `Nes` was
+    /// created.
+    pub fn power_cycle(&mut self) {
+        self.power_on();
+    }
+
+    /// Plug in a different cartridge, and power cycle the console
+    ///
+    /// Everything set up on this `Nes` carries over to the new game: the
+    /// configuration, palette, hooks, turbo settings, and any recording in
+    /// progress. A bus trace in progress is dropped, since it would span two
+    /// games, and so are the symbols, since they're for the old game's code.
+    /// The old cartridge is handed back, say for saving its battery
+    /// RAM.
+    pub fn swap_cartridge(&mut self, cart: Box<dyn ICartridge>) -> Box<dyn ICartridge> {
+        let old = core::mem::replace(&mut self.cart, cart);
+        self.tracer = Tracer::Off;
+        self.symbols = Symbols::new();
+        self.power_on();
+        old
+    }
+
+    /// Load an iNES ROM in place of the current cartridge
+    ///
+    /// If the ROM can't be loaded, the current game keeps running untouched.
+    /// See `swap_cartridge` for what carries over.
+    pub fn load_rom(&mut self, buf: &[u8]) -> Result<()> {
+        let cart = from_rom(buf)?;
+        self.swap_cartridge(cart);
+        Ok(())
+    }
+
+    /// The cartridge's battery-backed RAM or EEPROM, if it has any
+    ///
+    /// Frontends should save this when the game is closed, and restore it
+    /// with `load_save_data` the next time it's loaded.
+    pub fn save_data(&self) -> Option<&[u8]> {
+        self.cart.save_data()
+    }
+
+    /// Restore the cartridge's battery-backed memory from `save_data`
+    ///
+    /// Data of the wrong size for the cartridge is ignored.
+    pub fn load_save_data(&mut self, data: &[u8]) {
+        self.cart.load_save_data(data);
+    }
+
+    /// Set the buttons held on the controller in `port` (0 or 1)
+    ///
+    /// The game only sees the change the next time it latches the
+    /// controllers.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `port` isn't 0 or 1.
+    ///
+    /// On a Famicom, Select and Start are ignored for the second controller.
+    pub fn set_buttons(&mut self, port: usize, buttons: Buttons) {
+        self.controllers.set_buttons(port, buttons);
+    }
+
+    /// Get the Famicom Disk System, if it's plugged in
+    ///
+    /// Use this to change which side of the disk is in the drive.
+    pub fn fds_mut(&mut self) -> Option<&mut FdsAdapter> {
+        self.cart.fds_mut()
+    }
+
+    /// Get the buttons held on the controller in `port` (0 or 1)
+    pub fn buttons(&self, port: usize) -> Buttons {
+        self.controllers.ports[port].buttons()
+    }
+
+    /// Get the buttons the game saw on the controller in `port` (0 or 1) the
+    /// last time it latched the controllers
+    ///
+    /// This is what an input display should show. Unlike `buttons`, it
+    /// includes turbo, and it only changes when the game reads the
+    /// controllers, so it's the input the game actually acted on.
+    pub fn last_latched_input(&self, port: usize) -> Buttons {
+        self.controllers.ports[port].latched()
+    }
+
+    /// Put `buttons` on the controller in `port` (0 or 1) on turbo
+    ///
+    /// While a turbo button is held, the game sees it pressed for
+    /// `frames_per_toggle` frames, then released for as many, and so on.
+    /// Pass `Buttons::empty()` to turn turbo off.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `port` isn't 0 or 1.
+    pub fn set_turbo(&mut self, port: usize, buttons: Buttons, frames_per_toggle: u8) {
+        self.controllers.ports[port].set_turbo(buttons, frames_per_toggle);
+    }
+
+    /// Get the buttons on turbo on the controller in `port` (0 or 1)
+    pub fn turbo(&self, port: usize) -> Buttons {
+        self.controllers.ports[port].turbo()
+    }
+
+    /// Set whether someone is speaking into the Famicom's microphone
+    ///
+    /// This does nothing unless the console is a `Console::Famicom`.
+    pub fn set_microphone(&mut self, active: bool) {
+        self.controllers.set_microphone(active);
+    }
+
+    /// Plug a device into the expansion port, returning the one that was
+    /// there before (if any)
+    pub fn attach_expansion(
+        &mut self,
+        device: Box<dyn ExpansionDevice>,
+    ) -> Option<Box<dyn ExpansionDevice>> {
+        self.controllers.expansion.replace(device)
+    }
+
+    /// Unplug the device in the expansion port, if there is one
+    pub fn detach_expansion(&mut self) -> Option<Box<dyn ExpansionDevice>> {
+        self.controllers.expansion.take()
+    }
+
+    /// Call `hook` every time the PPU finishes a frame
+    ///
+    /// The hook gets the whole `Nes`, so it can read memory, pull the frame
+    /// buffer for a HUD, or poke RAM for a trainer. It runs between PPU
+    /// cycles, right after the last dot of the frame.
+    pub fn on_frame<F: FnMut(&mut Nes) + Send + 'static>(&mut self, hook: F) -> HookId {
+        self.hooks.add_frame(Box::new(hook))
+    }
+
+    /// Call `hook` with the address and value of every CPU write in `range`
+    ///
+    /// Addresses are as the CPU wrote them, so a hook on $0000-$07FF won't
+    /// see writes to the RAM mirrors.
+    pub fn on_write<R, F>(&mut self, range: R, hook: F) -> HookId
+    where
+        R: RangeBounds<u16>,
+        F: FnMut(u16, u8) + Send + 'static,
+    {
+        self.hooks.add_write(range, Box::new(hook))
+    }
+
+    /// Call `hook` whenever the CPU is about to execute the instruction at
+    /// `addr`
+    ///
+    /// Any pending interrupt has already been taken by the time the hook runs,
+    /// so the registers are exactly what the instruction will see.
+    pub fn on_exec<F: FnMut(&CpuState) + Send + 'static>(&mut self, addr: u16, hook: F) -> HookId {
+        self.hooks.add_exec(addr, Box::new(hook))
+    }
+
+    /// Call `hook` at the start of every scanline, with the scanline (0-261)
+    /// and the PPU's registers
+    ///
+    /// The hook runs before the PPU draws anything on the line, so the
+    /// registers are what the line starts out with. Nothing is worked out for
+    /// this unless a scanline hook is registered.
+    pub fn on_scanline<F>(&mut self, hook: F) -> HookId
+    where
+        F: FnMut(u16, &PpuDebugView) + Send + 'static,
+    {
+        self.hooks.add_scanline(Box::new(hook))
+    }
+
+    /// Unregister a hook, returning whether it was registered
+    pub fn remove_hook(&mut self, id: HookId) -> bool {
+        self.hooks.remove(id)
+    }
+
+    /// Register a condition for `run_until_probe` to stop at
+    pub fn add_probe(&mut self, probe: Probe) -> ProbeId {
+        self.probes.add(probe)
+    }
+
+    /// Unregister a probe, returning whether it was registered
+    pub fn remove_probe(&mut self, id: ProbeId) -> bool {
+        self.probes.remove(id)
+    }
+
+    /// Unregister every probe
+    pub fn clear_probes(&mut self) {
+        self.probes.clear();
+    }
+
+    /// Register a condition for `run_until_watch` and `run_for` to stop at
+    ///
+    /// Use `Watch::parse` to make one from an expression like
+    /// `a == 0xFF && pc in 0x8000..0x9000`.
+    pub fn add_watch(&mut self, watch: Watch) -> WatchId {
+        self.watches.add(watch)
+    }
+
+    /// Unregister a watch, returning whether it was registered
+    pub fn remove_watch(&mut self, id: WatchId) -> bool {
+        self.watches.remove(id)
+    }
+
+    /// Unregister every watch
+    pub fn clear_watches(&mut self) {
+        self.watches.clear();
+    }
+
+    /// Take the watch that last paused `run_for`, if any
+    pub fn take_watch_hit(&mut self) -> Option<WatchId> {
+        self.watch_hit.take()
+    }
+
+    /// Register a watch that holds when the CPU is about to run the code at
+    /// `label`, like a breakpoint
+    ///
+    /// This returns `None` if `label` isn't in the symbols.
+    pub fn break_at(&mut self, label: &str) -> Option<WatchId> {
+        let addr = self.symbols.address(label)?;
+        Some(self.add_watch(WatchOperand::Pc.equals(addr)))
+    }
+
+    /// Use `symbols` to label the game's code in `dbg_step_cpu_annotated`,
+    /// and for `break_at`
+    ///
+    /// These replace any symbols already set. To use more than one file, like
+    /// FCEUX's one per bank, combine them with `Symbols::extend` first.
+    pub fn set_symbols(&mut self, symbols: Symbols) {
+        self.symbols = symbols;
+    }
+
+    /// The labels for the game's code and data, which are empty unless set
+    /// with `set_symbols`
+    ///
+    /// Pass these to `Watch::parse_with_symbols` to use labels in watches.
+    pub fn symbols(&self) -> &Symbols {
+        &self.symbols
+    }
+
+    /// Read from the APU and I/O registers at `addr`, other than the
+    /// controller ports
+    ///
+    /// Everything here is write-only apart from APUSTATUS, so reads are open
+    /// bus. APUSTATUS drives every bit but 5.
+    fn read_apu_io(&mut self, addr: u16) -> u8 {
+        if addr == cpu_memory_map::APU_STATUS {
+            (self.last_bus_value & 0x20) | self.apu.read_status()
+        } else {
+            self.last_bus_value
+        }
+    }
+
+    pub(crate) fn hooks_mut(&mut self) -> &mut Hooks {
+        &mut self.hooks
+    }
+
+    /// Run the next instruction, logging it if there's a trace logger
+    fn exec_instruction(&mut self) {
+        #[cfg(feature = "std")]
+        if let Some(format) = self.trace_logger.as_ref().map(TraceLogger::format) {
+            // log the instruction that actually runs, not the one an
+            // interrupt is about to put off
+            cpu::run_interrupt(self);
+            let line = match format {
+                TraceFormat::Nestest => cpu::debug(self),
+                TraceFormat::Structured => {
+                    let before = self.cpu.state;
+                    let (scanline, dot) = (self.current_scanline(), self.current_dot());
+                    cpu::exec(self);
+                    trace_log::structured_line(&before, &self.cpu.state, scanline, dot)
+                }
+            };
+            if let Some(logger) = self.trace_logger.as_mut() {
+                logger.log(line);
+            }
+            return;
+        }
+        cpu::exec(self);
+    }
+
+    /// Dump the trace log, when a watch or probe stops the emulator
+    fn dump_trace_on_break(&mut self) {
+        #[cfg(feature = "std")]
+        self.dump_trace_log();
+    }
+
+    fn run_exec_hooks(&mut self) {
+        if self.hooks.exec.is_empty() {
+            return;
+        }
+        cpu::run_interrupt(self);
+        self.hooks.run_exec(&self.cpu.state);
+    }
+
+    /// Start or stop counting reads, writes, and executes per address
+    ///
+    /// Turning profiling on starts from a clean slate, and turning it off
+    /// frees the counters.
+    #[cfg(feature = "profiler")]
+    pub fn set_profiling(&mut self, enabled: bool) {
+        self.cpu_profile = if enabled {
+            Some(AccessCounts::new(0x10000))
+        } else {
+            None
+        };
+        self.ppu.set_chr_profiling(enabled);
+    }
+
+    /// The access counts recorded since profiling was enabled, if it is
+    #[cfg(feature = "profiler")]
+    pub fn memory_profile(&self) -> Option<MemoryProfile> {
+        Some(MemoryProfile {
+            cpu: self.cpu_profile.as_ref()?,
+            chr: self.ppu.chr_profile()?,
+        })
+    }
+
+    /// Zero the access counts without turning profiling off
+    #[cfg(feature = "profiler")]
+    pub fn clear_memory_profile(&mut self) {
+        if let Some(profile) = self.cpu_profile.as_mut() {
+            profile.clear();
+        }
+        if let Some(profile) = self.ppu.chr_profile_mut() {
+            profile.clear();
+        }
+    }
+
+    /// Count the instruction the CPU just started, if profiling is enabled
+    #[inline(always)]
+    fn profile_exec(&mut self) {
+        #[cfg(feature = "profiler")]
+        if let Some(profile) = self.cpu_profile.as_mut() {
+            profile.record_exec(self.cpu.instruction_addr);
+        }
+    }
+
+    /// Enable or disable rendering the background a scanline at a time
+    ///
+    /// This is on by default, and falls back to the per-dot pipeline by itself
+    /// on lines that touch the scroll or rendering registers mid-line.
+    pub fn set_batch_rendering(&mut self, enabled: bool) {
+        self.ppu.set_batch_rendering(enabled);
+    }
+
+    /// Choose how much of the hardware's edge-case behavior to emulate
+    ///
+    /// This is `AccuracyMode::Balanced` by default. It takes effect right
+    /// away, even mid-frame.
+    pub fn set_accuracy(&mut self, accuracy: AccuracyMode) {
+        self.ppu.set_accuracy(accuracy);
+    }
+
+    pub fn accuracy(&self) -> AccuracyMode {
+        self.ppu.accuracy()
+    }
+
+    /// Change the sample rate for `take_audio_samples`, in Hz
+    ///
+    /// 0 turns audio output off, which saves mixing a sample every CPU cycle.
+    /// Samples waiting at the old rate are dropped.
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        if sample_rate != self.audio.sample_rate() {
+            self.audio.set_sample_rate(sample_rate);
+        }
+    }
+
+    /// Mute or unmute one of the sound channels, see `Apu::set_channel_enabled`
+    ///
+    /// Every channel is on to start with, and this sticks through power
+    /// cycles, swapping cartridges, and loading states.
+    pub fn set_channel_enabled(&mut self, channel: Channel, enabled: bool) {
+        self.apu.set_channel_enabled(channel, enabled);
+    }
+
+    /// Take the audio samples made since the last call, as mono samples at
+    /// the sample rate in `NesConfig`
+    ///
+    /// These come out at a steady rate as the console runs, about 735 a
+    /// frame at 44.1kHz. A frontend should take them at least once a second:
+    /// past that, the oldest are dropped.
+    pub fn take_audio_samples(&mut self) -> Vec<f32> {
+        self.audio.take_samples()
+    }
+
+    /// Replace the built-in colors with a 64-color palette, as RGB triplets
+    ///
+    /// The emphasis variants are generated from these. This takes effect from
+    /// the next pixel drawn.
+    pub fn load_palette(&mut self, colors: &[u8; 192]) {
+        self.ppu.set_output_palette(Palette::generate(colors));
+    }
+
+    /// Replace the palette used to turn PPU colors into RGB
+    pub fn set_palette(&mut self, palette: Palette) {
+        self.ppu.set_output_palette(palette);
+    }
+
+    /// Go back to the built-in palette
+    pub fn reset_palette(&mut self) {
+        self.ppu.set_output_palette(Palette::default());
+    }
+
+    /// The palette used to turn PPU colors into RGB
+    pub fn output_palette(&self) -> &Palette {
+        self.ppu.output_palette()
+    }
+
+    /// Hide the background or sprites from the frame buffer, for debugging
+    ///
+    /// This doesn't touch PPUMASK, so the game can't tell. Everything is drawn
+    /// by default.
+    pub fn set_layer_mask(&mut self, layer_mask: LayerMask) {
+        self.ppu.set_layer_mask(layer_mask);
+    }
+
+    /// The layers being drawn, see `set_layer_mask`
+    pub fn layer_mask(&self) -> LayerMask {
+        self.ppu.layer_mask()
+    }
+
+    /// Start or stop drawing the background and sprites into frames of their
+    /// own, alongside the RGB frames
+    ///
+    /// This is for tracking down which layer a graphics bug is in, see
+    /// `render_background_frame` and `render_sprite_frame`. It's off by
+    /// default, since it composites every pixel three times. The first frame
+    /// after turning it on is only partly drawn.
+    pub fn set_layer_frames(&mut self, enabled: bool) {
+        self.ppu.set_layer_frames(enabled);
+    }
+
+    /// The last frame with the sprites left transparent, if layer frames are
+    /// on
+    ///
+    /// This is the same emulated frame as `tick_frame` last returned.
+    pub fn render_background_frame(&self) -> Option<&[u8]> {
+        self.ppu.background_frame()
+    }
+
+    /// The last frame with the background left transparent, if layer frames
+    /// are on
+    ///
+    /// Sprites are drawn in front of the backdrop whatever their priority.
+    /// Like `render_background_frame`, this is the same emulated frame as
+    /// `tick_frame` last returned.
+    pub fn render_sprite_frame(&self) -> Option<&[u8]> {
+        self.ppu.sprite_frame()
+    }
+
+    /// Start or stop tracking which 8x8 blocks of the frame change
+    ///
+    /// This is for frontends that can upload just part of a frame, like to a
+    /// canvas or a texture. It's off by default, since it costs a compare for
+    /// every pixel.
+    pub fn set_dirty_tracking(&mut self, enabled: bool) {
+        self.ppu.set_dirty_tracking(enabled);
+    }
+
+    /// The parts of the last frame that changed from the one before it
+    ///
+    /// Each rect is in pixels, and lines up with 8x8 blocks. Without dirty
+    /// tracking, this is always the whole frame.
+    pub fn dirty_rects(&self) -> Vec<DirtyRect> {
+        self.ppu.dirty_rects()
+    }
+
+    /// Start or stop drawing frames as PPU color numbers, alongside the RGB
+    /// frames
+    ///
+    /// This is for frontends that turn colors into RGB themselves, like in a
+    /// shader, see `IndexedFrame`. It's off by default. The first frame after
+    /// turning it on is only partly drawn.
+    pub fn set_indexed_output(&mut self, enabled: bool) {
+        self.ppu.set_indexed_output(enabled);
+    }
+
+    /// The last frame, as PPU color numbers, if indexed output is on
+    pub fn indexed_frame(&self) -> Option<IndexedFrame<'_>> {
+        self.ppu.indexed_frame()
+    }
+
+    /// Run the next frame, and return it as PPU color numbers
+    ///
+    /// Returns `None` without running anything if indexed output is off.
+    pub fn tick_frame_indexed(&mut self) -> Option<IndexedFrame<'_>> {
+        self.ppu.indexed_frame()?;
+        self.tick_frame();
+        self.ppu.indexed_frame()
+    }
+
+    /// Get a typed view of the PPU's scroll, timing, and shift registers
+    pub fn ppu_debug_state(&self) -> PpuDebugView {
+        self.ppu.debug_state()
+    }
+
+    /// The APU's registers and counters, for debugging
+    pub fn apu(&self) -> &Apu {
+        &self.apu
+    }
+
+    /// The time since power-on, in PPU cycles with CPU and APU views
+    pub fn clock(&self) -> MasterClock {
+        self.clock
+    }
+
+    /// The scanline the PPU is on, from 0 to 261, where 261 is the pre-render
+    /// line
+    pub fn current_scanline(&self) -> u16 {
+        self.ppu.state().scanline as u16
+    }
+
+    /// The dot the PPU is on in the current scanline, from 0 to 340
+    pub fn current_dot(&self) -> u16 {
+        self.ppu.state().pixel_cycle
+    }
+
+    /// The number of frames the PPU has finished since power-on
+    pub fn frame_count(&self) -> u64 {
+        self.frame_count
+    }
+
+    /// An id for the most recent frame, which goes up by one with each new
+    /// frame
+    ///
+    /// A frontend polling from another thread can use this to tell whether
+    /// the frame in front of it is new, or whether it skipped any. Unlike
+    /// `frame_count`, this keeps counting across resets and power cycles. A
+    /// power cycle blacks out the screen, which counts as a new frame. The id
+    /// belongs to the frame itself, so it stays the same across
+    /// `swap_buffers`.
+    pub fn frame_id(&self) -> u64 {
+        self.ppu.frame_id()
+    }
+
+    /// The number of CPU cycles since power-on
+    pub fn cpu_cycles(&self) -> u64 {
+        self.clock.cpu_cycles()
+    }
+
+    /// Which devices are holding the CPU's IRQ line
+    pub fn irq_line(&self) -> IrqLine {
+        self.irq
+    }
+
+    /// Copy out the state of the console, to go back to with `load_state`
+    pub fn save_state(&self) -> SaveState {
+        SaveState {
+            config: self.config(),
+            cpu: self.cpu.clone(),
+            ppu: Box::new(self.ppu.state().clone()),
+            palette: self.ppu.dump_palettes().to_vec(),
+            ram: self.ram.dump().to_vec(),
+            apu: self.apu.clone(),
+            controllers: self.controllers.ports,
+            cart: self.cart.debug_state(),
+            clock: self.clock,
+            frame_count: self.frame_count,
+            is_cpu_idle: self.is_cpu_idle,
+            irq: self.irq,
+            last_bus_value: self.last_bus_value,
+        }
+    }
+
+    /// Put the console back the way it was when `state` was saved
+    ///
+    /// This includes the configuration, so the console runs with the same
+    /// accuracy, palette, and power-on settings it was saved with. Debugging
+    /// aids are left as they are, apart from a bus trace in progress, which is
+    /// dropped. The cartridge is replaced with the copy in `state`, so a
+    /// cartridge wrapped with `with_cart` is unwrapped.
+    pub fn load_state(&mut self, state: &SaveState) {
+        let state = state.clone();
+        self.apply_config(state.config);
+        self.cpu = state.cpu;
+        self.ppu.restore(*state.ppu, &state.palette);
+        self.ram.load(0, &state.ram);
+        self.apu.restore(state.apu);
+        self.controllers.ports = state.controllers;
+        self.cart = state.cart.into_cartridge();
+        self.clock = state.clock;
+        self.frame_count = state.frame_count;
+        self.is_cpu_idle = state.is_cpu_idle;
+        self.irq = state.irq;
+        self.last_bus_value = state.last_bus_value;
+        self.call_depth = 0;
+        self.tracer = Tracer::Off;
+    }
+
+    /// Copy out the state of every part of the console
+    pub fn debug_snapshot(&self) -> DebugSnapshot {
+        DebugSnapshot {
+            cpu: self.cpu.state,
+            ppu: Box::new(self.ppu.state().clone()),
+            palette: self.ppu.dump_palettes().to_vec(),
+            ram: self.ram.dump().to_vec(),
+            apu: self.apu.clone(),
+            cart: self.cart.debug_state(),
+            cycles: self.clock.ppu_cycles(),
+            last_bus_value: self.last_bus_value,
+            config: self.config(),
+        }
+    }
+
+    /// Copy out the nametables, palette RAM, and pattern tables
+    ///
+    /// Memory is read through the cartridge the same way the PPU reads it, so
+    /// the nametables come out in logical order and the pattern tables hold
+    /// whatever CHR banks are switched in.
+    pub fn dump_debug_data(&self) -> DebugData {
+        let mut nametables = Box::new([0u8; 4 * NAMETABLE_SIZE]);
+        for (addr, byte) in (0x2000..).zip(nametables.iter_mut()) {
+            *byte = self.cart.peek_chr(addr).unwrap(0);
+        }
+        let mut chr = Box::new([0u8; 0x2000]);
+        for (addr, byte) in (0x0000..).zip(chr.iter_mut()) {
+            *byte = self.cart.peek_chr(addr).unwrap(0);
+        }
+        let mut palette = [0u8; 32];
+        palette.copy_from_slice(self.ppu.dump_palettes());
+        DebugData {
+            nametables,
+            palette,
+            chr,
+        }
+    }
+
+    /// Decode one of the four logical nametables ($2000, $2400, $2800, $2C00)
+    ///
+    /// Tiles come out row-major, with pattern addresses in the background
+    /// pattern table PPUCTRL currently selects. Panics if `idx` isn't 0-3.
+    pub fn decode_nametable(&self, idx: usize) -> Vec<TileEntry> {
+        let control = self.ppu.state().control;
+        let pattern_base = ((control & ppu::PpuControlFlags::BG_TILE_SELECT.bits()) as u16) << 8;
+        ppu::decode_nametable(self.dump_debug_data().nametable(idx), pattern_base)
+    }
+
+    /// Decode the attribute table of one of the four logical nametables
+    ///
+    /// This is one palette per 16x16 pixel area, 16 columns by 15 rows. Panics
+    /// if `idx` isn't 0-3.
+    pub fn decode_attributes(&self, idx: usize) -> Vec<u8> {
+        ppu::decode_attributes(self.dump_debug_data().nametable(idx))
+    }
+}
+
+impl cpu::WithCpu for Nes {
+    fn cpu(&self) -> &cpu::Cpu6502 {
+        &self.cpu
+    }
+
+    fn cpu_mut(&mut self) -> &mut cpu::Cpu6502 {
+        &mut self.cpu
+    }
+}
+
+impl ppu::WithPpuBus for Nes {
+    fn ppu_and_cart_mut(&mut self) -> (&mut ppu::Ppu2C02, &mut dyn ICartridge) {
+        (&mut self.ppu, &mut *self.cart)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finishes_warming_up_after_missing_the_exact_cycle() {
+        let mut nes = NesBuilder::new().build();
+        // a clock that skipped over the end of the warm-up period, with a PPU
+        // that still thinks it's warming up
+        nes.clock = MasterClock::from_ppu_cycles(PPU_WARMUP_CYCLES + 30);
+        nes.ppu.set_warming_up(true);
+        nes.tick();
+        assert!(!nes.ppu.is_warming_up());
     }
 
-    fn ppu_mut(&mut self) -> &mut ppu::Ppu2C02 {
-        &mut self.ppu
+    #[test]
+    fn keeps_the_layer_mask_through_a_power_cycle() {
+        let mut nes = NesBuilder::new().build();
+        assert_eq!(nes.layer_mask(), LayerMask::all());
+        nes.set_layer_mask(LayerMask::SPRITES);
+        nes.power_cycle();
+        assert_eq!(nes.layer_mask(), LayerMask::SPRITES);
     }
 }
@@ -0,0 +1,135 @@
+//! Pausing, frame advance, and running faster or slower than real time
+//!
+//! Frontends that get a callback per display refresh, like a browser's
+//! `requestAnimationFrame`, can't just run one frame per callback, since
+//! displays don't all refresh at 60Hz. Instead, they pass `Nes::run_for` how
+//! much time went by, and the `Nes` runs however many frames are due at the
+//! current speed. Pausing and frame advance live here too, so every frontend
+//! handles them the same way.
+
+use core::time::Duration;
+
+/// The slowest the emulator can be set to run, as a multiple of real time
+pub const MIN_SPEED: f32 = 0.25;
+
+/// The fastest the emulator can be set to run, as a multiple of real time
+pub const MAX_SPEED: f32 = 4.0;
+
+/// How many frames of emulated time can pile up before the rest is dropped
+///
+/// If the host stalls, running all the frames it missed at once would look
+/// like fast-forward, and could take long enough to stall it again.
+const MAX_BACKLOG_FRAMES: u32 = 4;
+
+pub(crate) struct Playback {
+    speed: f32,
+    paused: bool,
+    /// Emulated time that's due, but not yet a whole frame
+    backlog: Duration,
+}
+
+impl Default for Playback {
+    fn default() -> Playback {
+        Playback {
+            speed: 1.0,
+            paused: false,
+            backlog: Duration::from_secs(0),
+        }
+    }
+}
+
+impl Playback {
+    pub fn speed(&self) -> f32 {
+        self.speed
+    }
+
+    /// Set the speed, clamped to `MIN_SPEED..=MAX_SPEED`
+    ///
+    /// NaN is ignored.
+    pub fn set_speed(&mut self, speed: f32) {
+        if !speed.is_nan() {
+            self.speed = speed.clamp(MIN_SPEED, MAX_SPEED);
+        }
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    pub fn set_paused(&mut self, paused: bool) {
+        self.paused = paused;
+        // coming back from a pause shouldn't run the frames it skipped
+        self.backlog = Duration::from_secs(0);
+    }
+
+    /// How many frames to run for `elapsed` of real time going by
+    pub fn frames_due(&mut self, elapsed: Duration, frame_duration: Duration) -> u32 {
+        if self.paused {
+            return 0;
+        }
+        self.backlog += scale(elapsed, self.speed);
+        let max_backlog = frame_duration * MAX_BACKLOG_FRAMES;
+        if self.backlog > max_backlog {
+            self.backlog = max_backlog;
+        }
+        let mut frames = 0;
+        while self.backlog >= frame_duration {
+            self.backlog -= frame_duration;
+            frames += 1;
+        }
+        frames
+    }
+}
+
+/// Multiply a duration by `factor`, to the nearest nanosecond
+///
+/// `Duration::mul_f32` goes through floating point seconds, which doesn't
+/// even give back the same duration for a factor of 1.
+pub(crate) fn scale(duration: Duration, factor: f32) -> Duration {
+    // there's no `f64::round` without std, but this is never negative
+    Duration::from_nanos((duration.as_nanos() as f64 * f64::from(factor) + 0.5) as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FRAME: Duration = Duration::from_millis(10);
+
+    #[test]
+    fn runs_frames_as_they_come_due() {
+        let mut playback = Playback::default();
+        assert_eq!(playback.frames_due(Duration::from_millis(4), FRAME), 0);
+        assert_eq!(playback.frames_due(Duration::from_millis(7), FRAME), 1);
+        assert_eq!(playback.frames_due(Duration::from_millis(19), FRAME), 2);
+        // a long stall only catches up a few frames
+        assert_eq!(playback.frames_due(Duration::from_secs(1), FRAME), 4);
+    }
+
+    #[test]
+    fn scales_time_by_the_speed() {
+        let mut playback = Playback::default();
+        playback.set_speed(0.5);
+        assert_eq!(playback.frames_due(FRAME, FRAME), 0);
+        assert_eq!(playback.frames_due(FRAME, FRAME), 1);
+        playback.set_speed(2.0);
+        assert_eq!(playback.frames_due(FRAME, FRAME), 2);
+        playback.set_speed(100.0);
+        assert_eq!(playback.speed(), MAX_SPEED);
+        playback.set_speed(0.0);
+        assert_eq!(playback.speed(), MIN_SPEED);
+        playback.set_speed(f32::NAN);
+        assert_eq!(playback.speed(), MIN_SPEED);
+    }
+
+    #[test]
+    fn runs_nothing_while_paused() {
+        let mut playback = Playback::default();
+        playback.frames_due(Duration::from_millis(9), FRAME);
+        playback.set_paused(true);
+        assert_eq!(playback.frames_due(Duration::from_secs(1), FRAME), 0);
+        // and the time from before the pause is forgotten
+        playback.set_paused(false);
+        assert_eq!(playback.frames_due(Duration::from_millis(2), FRAME), 0);
+    }
+}
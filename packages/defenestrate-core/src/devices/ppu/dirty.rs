@@ -0,0 +1,226 @@
+//! Tracking which parts of the frame changed since the last one
+//!
+//! Frontends that upload frames to a canvas or a texture can use this to only
+//! upload what changed, which for games with mostly static screens is a small
+//! fraction of each frame. The frame is split into 8x8 blocks, one per
+//! background tile when there's no fine scroll.
+
+use alloc::{boxed::Box, vec, vec::Vec};
+
+use crate::video::{FRAME_HEIGHT, FRAME_WIDTH};
+
+/// The size of each block, in pixels
+const BLOCK_SIZE: usize = 8;
+
+/// The number of block rows in a frame
+const BLOCK_ROWS: usize = FRAME_HEIGHT / BLOCK_SIZE;
+
+/// A color that no pixel can have, so that every block looks changed
+const NO_COLOR: u16 = 0xFFFF;
+
+/// A rectangle of the frame that changed, in pixels
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct DirtyRect {
+    pub x: u16,
+    pub y: u16,
+    pub width: u16,
+    pub height: u16,
+}
+
+impl DirtyRect {
+    /// The whole frame
+    pub const FULL_FRAME: DirtyRect = DirtyRect {
+        x: 0,
+        y: 0,
+        width: FRAME_WIDTH as u16,
+        height: FRAME_HEIGHT as u16,
+    };
+}
+
+/// Compares each pixel against the last frame, and remembers which blocks
+/// differ
+pub struct DirtyTracker {
+    /// The color of each pixel in the last frame, with emphasis bits above
+    /// the color index
+    colors: Box<[u16]>,
+    /// The changed blocks in the frame being drawn, one bit per column
+    drawing: [u32; BLOCK_ROWS],
+    /// The changed blocks in the last finished frame
+    finished: [u32; BLOCK_ROWS],
+}
+
+impl DirtyTracker {
+    pub fn new() -> DirtyTracker {
+        DirtyTracker {
+            colors: vec![NO_COLOR; FRAME_WIDTH * FRAME_HEIGHT].into_boxed_slice(),
+            drawing: [0; BLOCK_ROWS],
+            // nothing has been drawn yet, so the whole frame is out of date
+            finished: [u32::MAX; BLOCK_ROWS],
+        }
+    }
+
+    /// Record the color of a pixel in the frame being drawn
+    pub fn draw(&mut self, x: usize, y: usize, color: u16) {
+        let last = &mut self.colors[y * FRAME_WIDTH + x];
+        if *last != color {
+            *last = color;
+            self.drawing[y / BLOCK_SIZE] |= 1 << (x / BLOCK_SIZE);
+        }
+    }
+
+    pub fn finish_frame(&mut self) {
+        self.finished = self.drawing;
+        self.drawing = [0; BLOCK_ROWS];
+    }
+
+    /// Treat every pixel of the next frame as changed, like after the RGB
+    /// palette changes
+    pub fn invalidate(&mut self) {
+        self.colors.fill(NO_COLOR);
+    }
+
+    /// The parts of the last finished frame that changed from the one before
+    ///
+    /// Each run of changed blocks in a row becomes a rectangle, and runs that
+    /// line up across consecutive rows are merged.
+    pub fn dirty_rects(&self) -> Vec<DirtyRect> {
+        let mut rects = Vec::new();
+        // rects that reach the bottom of the last row, so they can still grow
+        let mut open: Vec<DirtyRect> = Vec::new();
+        for (row, &blocks) in self.finished.iter().enumerate() {
+            let mut next_open = Vec::new();
+            let mut blocks = blocks as u64;
+            while blocks != 0 {
+                let start = blocks.trailing_zeros();
+                let len = (blocks >> start).trailing_ones();
+                blocks &= !(((1 << len) - 1) << start);
+                let (x, width) = (
+                    (start as usize * BLOCK_SIZE) as u16,
+                    (len as usize * BLOCK_SIZE) as u16,
+                );
+                let rect = match open
+                    .iter()
+                    .position(|rect| rect.x == x && rect.width == width)
+                {
+                    Some(i) => {
+                        let rect = open.swap_remove(i);
+                        DirtyRect {
+                            height: rect.height + BLOCK_SIZE as u16,
+                            ..rect
+                        }
+                    }
+                    None => DirtyRect {
+                        x,
+                        y: (row * BLOCK_SIZE) as u16,
+                        width,
+                        height: BLOCK_SIZE as u16,
+                    },
+                };
+                next_open.push(rect);
+            }
+            rects.append(&mut open);
+            open = next_open;
+        }
+        rects.append(&mut open);
+        rects.sort_by_key(|rect| (rect.y, rect.x));
+        rects
+    }
+}
+
+impl Default for DirtyTracker {
+    fn default() -> DirtyTracker {
+        DirtyTracker::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Draw a whole frame of `color`, except for the pixels in `changes`
+    fn draw_frame(tracker: &mut DirtyTracker, color: u16, changes: &[(usize, usize, u16)]) {
+        for y in 0..FRAME_HEIGHT {
+            for x in 0..FRAME_WIDTH {
+                let color = changes
+                    .iter()
+                    .find(|&&(cx, cy, _)| (cx, cy) == (x, y))
+                    .map_or(color, |&(_, _, c)| c);
+                tracker.draw(x, y, color);
+            }
+        }
+        tracker.finish_frame();
+    }
+
+    #[test]
+    fn starts_with_the_whole_frame_dirty() {
+        let tracker = DirtyTracker::new();
+        assert_eq!(tracker.dirty_rects(), vec![DirtyRect::FULL_FRAME]);
+        let mut tracker = DirtyTracker::new();
+        draw_frame(&mut tracker, 0x0F, &[]);
+        assert_eq!(tracker.dirty_rects(), vec![DirtyRect::FULL_FRAME]);
+    }
+
+    #[test]
+    fn unchanged_frames_have_no_dirty_rects() {
+        let mut tracker = DirtyTracker::new();
+        draw_frame(&mut tracker, 0x0F, &[]);
+        draw_frame(&mut tracker, 0x0F, &[]);
+        assert_eq!(tracker.dirty_rects(), vec![]);
+    }
+
+    #[test]
+    fn merges_changed_blocks_into_rects() {
+        let mut tracker = DirtyTracker::new();
+        draw_frame(&mut tracker, 0x0F, &[]);
+        draw_frame(
+            &mut tracker,
+            0x0F,
+            &[
+                // a 2x2 block square, and a lone block beside it
+                (16, 8, 0x30),
+                (31, 15, 0x30),
+                (16, 16, 0x30),
+                (24, 23, 0x30),
+                (48, 8, 0x30),
+                // the far corner
+                (255, 239, 0x30),
+            ],
+        );
+        assert_eq!(
+            tracker.dirty_rects(),
+            vec![
+                DirtyRect {
+                    x: 16,
+                    y: 8,
+                    width: 16,
+                    height: 16
+                },
+                DirtyRect {
+                    x: 48,
+                    y: 8,
+                    width: 8,
+                    height: 8
+                },
+                DirtyRect {
+                    x: 248,
+                    y: 232,
+                    width: 8,
+                    height: 8
+                },
+            ]
+        );
+        // changing back counts as a change too
+        draw_frame(&mut tracker, 0x0F, &[]);
+        assert_eq!(tracker.dirty_rects().len(), 3);
+    }
+
+    #[test]
+    fn invalidating_dirties_the_next_frame() {
+        let mut tracker = DirtyTracker::new();
+        draw_frame(&mut tracker, 0x0F, &[]);
+        tracker.invalidate();
+        draw_frame(&mut tracker, 0x0F, &[]);
+        assert_eq!(tracker.dirty_rects(), vec![DirtyRect::FULL_FRAME]);
+    }
+}
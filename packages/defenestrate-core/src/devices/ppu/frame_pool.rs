@@ -0,0 +1,142 @@
+//! Double-buffered frame storage
+//!
+//! The PPU draws into a back buffer, and when a frame is done it becomes the
+//! front buffer while the old front buffer is reused for the next frame. So
+//! the last finished frame stays put while the next one renders, and nothing
+//! is allocated from one frame to the next.
+//!
+//! Each finished frame also gets an id, one more than the last, so a consumer
+//! polling from elsewhere can tell a new frame from one it has already seen,
+//! and notice any it missed.
+
+use alloc::{boxed::Box, vec};
+
+/// The size of one frame, as 256x240 RGB pixels
+pub const FRAME_SIZE: usize = 256 * 240 * 3;
+
+/// A pair of frame buffers, one being drawn and one finished
+pub struct FramePool {
+    /// The last finished frame
+    front: Box<[u8]>,
+    /// The frame being drawn
+    back: Box<[u8]>,
+    /// The id of the front buffer
+    front_id: u64,
+}
+
+impl FramePool {
+    pub fn new() -> FramePool {
+        FramePool {
+            front: new_frame(),
+            back: new_frame(),
+            front_id: 0,
+        }
+    }
+
+    /// The last finished frame
+    pub fn front(&self) -> &[u8] {
+        &self.front
+    }
+
+    /// The id of the last finished frame
+    ///
+    /// This goes up by one for each frame, and never goes back down, even
+    /// when the buffers are cleared.
+    pub fn front_id(&self) -> u64 {
+        self.front_id
+    }
+
+    /// The frame being drawn
+    pub fn back_mut(&mut self) -> &mut [u8] {
+        &mut self.back
+    }
+
+    /// Finish the frame being drawn, and start the next one in the old front
+    /// buffer
+    pub fn finish_frame(&mut self) {
+        core::mem::swap(&mut self.front, &mut self.back);
+        self.front_id += 1;
+    }
+
+    /// Trade the last finished frame for `frame`
+    ///
+    /// This lets a frontend keep a frame for as long as it likes without
+    /// copying it, and hand the buffer back on the next swap. Until the next
+    /// frame is done, the front buffer is whatever `frame` held. If `frame`
+    /// isn't the size of a frame (like an empty `Box::default()`), a new
+    /// buffer is allocated in its place, so the first swap can start from
+    /// nothing.
+    pub fn swap_buffers(&mut self, frame: &mut Box<[u8]>) {
+        core::mem::swap(&mut self.front, frame);
+        if self.front.len() != FRAME_SIZE {
+            self.front = new_frame();
+        }
+    }
+
+    /// Black out both buffers
+    ///
+    /// The blank front buffer counts as a new frame, so it gets a new id.
+    pub fn clear(&mut self) {
+        self.front.fill(0);
+        self.back.fill(0);
+        self.front_id += 1;
+    }
+}
+
+impl Default for FramePool {
+    fn default() -> FramePool {
+        FramePool::new()
+    }
+}
+
+fn new_frame() -> Box<[u8]> {
+    vec![0u8; FRAME_SIZE].into_boxed_slice()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finished_frames_move_to_the_front() {
+        let mut pool = FramePool::new();
+        pool.back_mut()[0] = 0xAA;
+        assert_eq!(pool.front()[0], 0x00);
+        pool.finish_frame();
+        assert_eq!(pool.front()[0], 0xAA);
+        pool.back_mut()[0] = 0xBB;
+        assert_eq!(pool.front()[0], 0xAA);
+    }
+
+    #[test]
+    fn numbers_each_finished_frame() {
+        let mut pool = FramePool::new();
+        assert_eq!(pool.front_id(), 0);
+        pool.finish_frame();
+        pool.finish_frame();
+        assert_eq!(pool.front_id(), 2);
+        pool.clear();
+        assert_eq!(pool.front_id(), 3);
+        let mut held: Box<[u8]> = Box::default();
+        pool.swap_buffers(&mut held);
+        assert_eq!(pool.front_id(), 3);
+    }
+
+    #[test]
+    fn swaps_reuse_the_callers_buffer() {
+        let mut pool = FramePool::new();
+        pool.back_mut()[0] = 0xAA;
+        pool.finish_frame();
+        let mut held: Box<[u8]> = Box::default();
+        pool.swap_buffers(&mut held);
+        assert_eq!(held.len(), FRAME_SIZE);
+        assert_eq!(held[0], 0xAA);
+        assert_eq!(pool.front().len(), FRAME_SIZE);
+
+        held[0] = 0xCC;
+        let ptr = held.as_ptr();
+        pool.swap_buffers(&mut held);
+        assert_eq!(pool.front().as_ptr(), ptr);
+        assert_eq!(pool.front()[0], 0xCC);
+    }
+}
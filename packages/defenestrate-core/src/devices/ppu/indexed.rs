@@ -0,0 +1,142 @@
+//! Frames as the PPU's own color numbers, rather than RGB
+//!
+//! Frontends that draw with shaders can do the palette lookup themselves, and
+//! apply emphasis or an NTSC filter in the same pass. Frames like this are a
+//! byte per pixel instead of three, which also makes them cheaper to copy out
+//! of the wasm build.
+//!
+//! Each frame is one buffer, laid out as:
+//!
+//! - 256x240 pixels, a byte each, holding the 6-bit color the PPU put out
+//!   (after grayscale). These index the 64 colors of a `Palette`.
+//! - 240 bytes, one per line, holding the emphasis bits of PPUMASK (shifted
+//!   down to bits 0-2) as of the first pixel of the line. Emphasis changed
+//!   partway through a line only shows up on the next one.
+//! - The 32 bytes of palette RAM, as they were when the frame finished.
+//!
+//! So a pixel's RGB is `palette.rgb(emphasis[y], pixels[y * 256 + x])`, or in
+//! a shader, entry `(emphasis << 6) | color` of `Palette::as_bytes`.
+
+use alloc::{boxed::Box, vec, vec::Vec};
+
+use super::palette::Palette;
+use crate::video::{FRAME_HEIGHT, FRAME_WIDTH};
+
+const PIXELS_SIZE: usize = FRAME_WIDTH * FRAME_HEIGHT;
+const PALETTE_RAM_SIZE: usize = 32;
+
+/// The size of one indexed frame, with its emphasis and palette RAM
+pub const INDEXED_FRAME_SIZE: usize = PIXELS_SIZE + FRAME_HEIGHT + PALETTE_RAM_SIZE;
+
+/// A finished frame of PPU color numbers, laid out as described in the module
+/// docs
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub struct IndexedFrame<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> IndexedFrame<'a> {
+    /// The color of each pixel, in rows of 256
+    pub fn pixels(&self) -> &'a [u8] {
+        &self.buf[..PIXELS_SIZE]
+    }
+
+    /// The emphasis bits for each line
+    pub fn emphasis(&self) -> &'a [u8] {
+        &self.buf[PIXELS_SIZE..PIXELS_SIZE + FRAME_HEIGHT]
+    }
+
+    /// Palette RAM at the end of the frame
+    pub fn palette_ram(&self) -> &'a [u8] {
+        &self.buf[PIXELS_SIZE + FRAME_HEIGHT..]
+    }
+
+    /// The whole frame as one buffer, to hand to a frontend
+    pub fn as_bytes(&self) -> &'a [u8] {
+        self.buf
+    }
+
+    /// Look every pixel up in `palette`, giving the same 8-bit RGB frame the
+    /// PPU draws
+    pub fn to_rgb(&self, palette: &Palette) -> Vec<u8> {
+        let mut rgb = Vec::with_capacity(PIXELS_SIZE * 3);
+        for (row, &emphasis) in self.pixels().chunks(FRAME_WIDTH).zip(self.emphasis()) {
+            for &color in row {
+                rgb.extend_from_slice(palette.rgb(emphasis, color));
+            }
+        }
+        rgb
+    }
+}
+
+/// A pair of indexed frame buffers, one being drawn and one finished, like
+/// `FramePool`
+pub struct IndexedFrames {
+    front: Box<[u8]>,
+    back: Box<[u8]>,
+}
+
+impl IndexedFrames {
+    pub fn new() -> IndexedFrames {
+        IndexedFrames {
+            front: vec![0u8; INDEXED_FRAME_SIZE].into_boxed_slice(),
+            back: vec![0u8; INDEXED_FRAME_SIZE].into_boxed_slice(),
+        }
+    }
+
+    /// Record a pixel in the frame being drawn
+    pub fn draw(&mut self, x: usize, y: usize, color: u8, emphasis: u8) {
+        self.back[y * FRAME_WIDTH + x] = color;
+        if x == 0 {
+            self.back[PIXELS_SIZE + y] = emphasis;
+        }
+    }
+
+    /// Finish the frame being drawn, with `palette_ram` as it is now
+    pub fn finish_frame(&mut self, palette_ram: &[u8]) {
+        self.back[PIXELS_SIZE + FRAME_HEIGHT..].copy_from_slice(palette_ram);
+        core::mem::swap(&mut self.front, &mut self.back);
+    }
+
+    /// The last finished frame
+    pub fn front(&self) -> IndexedFrame<'_> {
+        IndexedFrame { buf: &self.front }
+    }
+
+    /// Black out both buffers
+    pub fn clear(&mut self) {
+        self.front.fill(0);
+        self.back.fill(0);
+    }
+}
+
+impl Default for IndexedFrames {
+    fn default() -> IndexedFrames {
+        IndexedFrames::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lays_out_pixels_emphasis_and_palette() {
+        let mut frames = IndexedFrames::new();
+        frames.draw(0, 1, 0x16, 0x01);
+        frames.draw(1, 1, 0x2A, 0x05);
+        let palette_ram: Vec<u8> = (0..32).collect();
+        frames.finish_frame(&palette_ram);
+        let frame = frames.front();
+        assert_eq!(frame.as_bytes().len(), INDEXED_FRAME_SIZE);
+        assert_eq!(frame.pixels()[256..258], [0x16, 0x2A]);
+        // only the first pixel sets the line's emphasis
+        assert_eq!(frame.emphasis()[1], 0x01);
+        assert_eq!(frame.palette_ram(), &palette_ram[..]);
+
+        let palette = Palette::default();
+        let rgb = frame.to_rgb(&palette);
+        assert_eq!(rgb[256 * 3..257 * 3], *palette.rgb(0x01, 0x16));
+        assert_eq!(rgb[257 * 3..258 * 3], *palette.rgb(0x01, 0x2A));
+    }
+}
@@ -0,0 +1,65 @@
+//! The background and sprites, each drawn into a frame of its own
+//!
+//! When something looks wrong, the first question is which half of the PPU
+//! drew it. With layer frames on, the PPU composites every pixel twice more,
+//! once with the sprites transparent and once with the background
+//! transparent, so all three frames come from the same run of the game.
+//! `LayerMask` can hide a layer too, but only from the one frame, so telling
+//! the layers apart that way takes a second run that has to stay in sync.
+//!
+//! Both frames are 256x240 RGB, like the main frame. Wherever the layer is
+//! transparent, they show the backdrop color. Sprites are drawn whatever
+//! their priority, since there's no background for them to go behind.
+
+use super::frame_pool::FramePool;
+
+/// The background-only and sprite-only frames, each double-buffered like
+/// the main frame
+pub struct LayerFrames {
+    background: FramePool,
+    sprites: FramePool,
+}
+
+impl LayerFrames {
+    pub fn new() -> LayerFrames {
+        LayerFrames {
+            background: FramePool::new(),
+            sprites: FramePool::new(),
+        }
+    }
+
+    /// Record a pixel of each layer in the frames being drawn
+    pub fn draw(&mut self, x: usize, y: usize, background: &[u8], sprites: &[u8]) {
+        let idx = (y * 256 + x) * 3;
+        self.background.back_mut()[idx..idx + 3].copy_from_slice(background);
+        self.sprites.back_mut()[idx..idx + 3].copy_from_slice(sprites);
+    }
+
+    /// Finish the frames being drawn
+    pub fn finish_frame(&mut self) {
+        self.background.finish_frame();
+        self.sprites.finish_frame();
+    }
+
+    /// The last finished frame of just the background
+    pub fn background(&self) -> &[u8] {
+        self.background.front()
+    }
+
+    /// The last finished frame of just the sprites
+    pub fn sprites(&self) -> &[u8] {
+        self.sprites.front()
+    }
+
+    /// Black out every buffer
+    pub fn clear(&mut self) {
+        self.background.clear();
+        self.sprites.clear();
+    }
+}
+
+impl Default for LayerFrames {
+    fn default() -> LayerFrames {
+        LayerFrames::new()
+    }
+}
@@ -1,5 +1,19 @@
+mod dirty;
+mod frame_pool;
+mod indexed;
+mod layer_frames;
+mod nametable;
+mod palette;
 mod ppu;
+#[cfg(test)]
+mod scene;
 mod structs;
-mod utils;
 
+pub use dirty::DirtyRect;
+pub use frame_pool::FRAME_SIZE;
+pub use indexed::{IndexedFrame, INDEXED_FRAME_SIZE};
+pub use nametable::{decode_attributes, decode_nametable, TileEntry, NAMETABLE_SIZE};
+pub use palette::Palette;
 pub use ppu::*;
+pub(crate) use structs::PpuControlFlags;
+pub use structs::{LayerMask, PpuDebugView, PpuState};
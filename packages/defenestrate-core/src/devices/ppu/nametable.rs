@@ -0,0 +1,119 @@
+//! Decoding nametables for debug views
+//!
+//! A nametable is 960 tile indices (32 columns by 30 rows) followed by a
+//! 64-byte attribute table. Each attribute byte covers a 32x32 pixel block,
+//! with 2 bits of palette for each 16x16 quadrant, so the last row of
+//! attribute bytes only half-covers the screen.
+//!
+//! cf. https://wiki.nesdev.com/w/index.php/PPU_nametables
+//! cf. https://wiki.nesdev.com/w/index.php/PPU_attribute_tables
+
+use alloc::vec::Vec;
+
+/// The size of a nametable, including its attribute table
+pub const NAMETABLE_SIZE: usize = 0x400;
+
+/// The number of tile columns in a nametable
+pub const NAMETABLE_WIDTH: usize = 32;
+
+/// The number of tile rows in a nametable
+pub const NAMETABLE_HEIGHT: usize = 30;
+
+/// The offset of the attribute table within a nametable
+const ATTRIBUTE_OFFSET: usize = NAMETABLE_WIDTH * NAMETABLE_HEIGHT;
+
+/// One background tile from a nametable
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct TileEntry {
+    /// The tile index, as written in the nametable
+    pub tile: u8,
+    /// The address of the tile's first byte in the pattern tables
+    pub pattern_addr: u16,
+    /// Which of the 4 background palettes the tile is drawn with
+    pub palette: u8,
+}
+
+/// Decode a nametable into its tiles, in row-major order
+///
+/// `pattern_base` is the background pattern table ($0000 or $1000), as set by
+/// PPUCTRL.
+pub fn decode_nametable(table: &[u8; NAMETABLE_SIZE], pattern_base: u16) -> Vec<TileEntry> {
+    let attributes = decode_attributes(table);
+    (0..ATTRIBUTE_OFFSET)
+        .map(|i| {
+            let (x, y) = (i % NAMETABLE_WIDTH, i / NAMETABLE_WIDTH);
+            let tile = table[i];
+            TileEntry {
+                tile,
+                pattern_addr: pattern_base | ((tile as u16) << 4),
+                palette: attributes[(y / 2) * (NAMETABLE_WIDTH / 2) + x / 2],
+            }
+        })
+        .collect()
+}
+
+/// Decode a nametable's attribute table into one palette per 16x16 area
+///
+/// The result is 16 columns by 15 rows, in row-major order.
+pub fn decode_attributes(table: &[u8; NAMETABLE_SIZE]) -> Vec<u8> {
+    let (width, height) = (NAMETABLE_WIDTH / 2, NAMETABLE_HEIGHT / 2);
+    (0..width * height)
+        .map(|i| {
+            let (x, y) = (i % width, i / width);
+            let byte = table[ATTRIBUTE_OFFSET + (y / 2) * 8 + x / 2];
+            let shift = ((y & 1) << 2) | ((x & 1) << 1);
+            (byte >> shift) & 0x03
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn table_with_attributes(attributes: &[(usize, u8)]) -> [u8; NAMETABLE_SIZE] {
+        let mut table = [0u8; NAMETABLE_SIZE];
+        for (i, byte) in table.iter_mut().take(ATTRIBUTE_OFFSET).enumerate() {
+            *byte = i as u8;
+        }
+        for &(offset, value) in attributes {
+            table[ATTRIBUTE_OFFSET + offset] = value;
+        }
+        table
+    }
+
+    #[test]
+    fn splits_attribute_bytes_into_quadrants() {
+        // top left, top right, bottom left, bottom right = 0, 1, 2, 3
+        let table = table_with_attributes(&[(0, 0b11_10_01_00), (63, 0b11_10_01_00)]);
+        let attributes = decode_attributes(&table);
+        assert_eq!(attributes.len(), 16 * 15);
+        assert_eq!(&attributes[0..2], &[0, 1]);
+        assert_eq!(&attributes[16..18], &[2, 3]);
+        assert_eq!(attributes[2], 0);
+        // the last attribute row only has its top half on screen
+        assert_eq!(&attributes[14 * 16 + 14..], &[0, 1]);
+    }
+
+    #[test]
+    fn decodes_tiles_row_major() {
+        let table = table_with_attributes(&[(9, 0b11_10_01_00)]);
+        let tiles = decode_nametable(&table, 0x1000);
+        assert_eq!(tiles.len(), 960);
+        assert_eq!(
+            tiles[33],
+            TileEntry {
+                tile: 33,
+                pattern_addr: 0x1210,
+                palette: 0,
+            }
+        );
+        // attribute byte 9 covers tile columns 4-7 of rows 4-7
+        let at = |x: usize, y: usize| tiles[y * 32 + x].palette;
+        assert_eq!([at(4, 4), at(5, 5), at(6, 4), at(7, 5)], [0, 0, 1, 1]);
+        assert_eq!([at(4, 6), at(5, 7), at(6, 6), at(7, 7)], [2, 2, 3, 3]);
+        assert_eq!(at(8, 4), 0);
+        assert_eq!(tiles[959].pattern_addr, 0x1BF0);
+    }
+}
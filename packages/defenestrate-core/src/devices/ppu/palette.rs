@@ -0,0 +1,133 @@
+//! Turning PPU color indices into RGB
+//!
+//! The PPU outputs one of 64 colors, and the 3 emphasis bits in PPUMASK dim
+//! the channels that aren't emphasized. That makes 512 possible outputs, which
+//! are all worked out ahead of time into a lookup table indexed by
+//! `(emphasis << 6) | color`.
+//!
+//! cf. https://wiki.nesdev.com/w/index.php/PPU_palettes
+
+use alloc::{vec, vec::Vec};
+
+use super::structs::PALLETE_TABLE;
+use crate::error::{Error, Result};
+
+/// The number of colors the PPU can output, without emphasis
+const BASE_COLORS: usize = 64;
+
+/// The number of colors in the lookup table, with every emphasis combination
+const LUT_COLORS: usize = BASE_COLORS * 8;
+
+/// How much the emphasis bits dim the channels that aren't emphasized
+///
+/// This is the ratio of the attenuated and unattenuated signal levels, measured
+/// from an NTSC PPU.
+const EMPHASIS_ATTENUATION: f32 = 0.816_328;
+
+/// A lookup table from PPU colors (with emphasis) to RGB
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Palette {
+    /// 512 RGB triplets, indexed by `(emphasis << 6) | color`
+    lut: Vec<u8>,
+}
+
+impl Palette {
+    /// Build a palette from 64 RGB colors, generating the emphasis variants
+    pub fn generate(colors: &[u8; BASE_COLORS * 3]) -> Palette {
+        let mut lut = vec![0u8; LUT_COLORS * 3];
+        for emphasis in 0..8 {
+            for color in 0..BASE_COLORS {
+                let src = &colors[color * 3..color * 3 + 3];
+                let idx = ((emphasis << 6) | color) * 3;
+                for channel in 0..3 {
+                    // emphasis bits are red, green, blue from low to high, and
+                    // each one dims the other two channels
+                    let dimmed = emphasis & !(1 << channel) != 0;
+                    lut[idx + channel] = if dimmed {
+                        (src[channel] as f32 * EMPHASIS_ATTENUATION + 0.5) as u8
+                    } else {
+                        src[channel]
+                    };
+                }
+            }
+        }
+        Palette { lut }
+    }
+
+    /// Load a palette from the contents of a .pal file
+    ///
+    /// A .pal file is either 64 RGB colors (192 bytes), in which case the
+    /// emphasis variants are generated, or all 512 colors (1536 bytes) in
+    /// lookup table order.
+    pub fn from_pal(buf: &[u8]) -> Result<Palette> {
+        match buf.len() {
+            192 => {
+                let mut colors = [0u8; BASE_COLORS * 3];
+                colors.copy_from_slice(buf);
+                Ok(Palette::generate(&colors))
+            }
+            1536 => Ok(Palette { lut: buf.to_vec() }),
+            len => Err(Error::InvalidPalette { len }),
+        }
+    }
+
+    /// Get the RGB value for a color, with the emphasis bits from PPUMASK
+    ///
+    /// `emphasis` is the top 3 bits of PPUMASK shifted down, and only the low 6
+    /// bits of `color` are used.
+    pub fn rgb(&self, emphasis: u8, color: u8) -> &[u8] {
+        let idx = ((((emphasis & 0x07) as usize) << 6) | (color & 0x3F) as usize) * 3;
+        &self.lut[idx..idx + 3]
+    }
+
+    /// The whole lookup table, as 512 RGB triplets
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.lut
+    }
+}
+
+impl Default for Palette {
+    /// The built-in palette, from `PALLETE_TABLE`
+    fn default() -> Palette {
+        Palette::generate(&PALLETE_TABLE)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn leaves_unemphasized_colors_alone() {
+        let palette = Palette::default();
+        assert_eq!(palette.as_bytes()[..192], PALLETE_TABLE[..]);
+        assert_eq!(palette.rgb(0, 0x20), &PALLETE_TABLE[0x60..0x63]);
+    }
+
+    #[test]
+    fn dims_the_other_channels() {
+        let mut colors = [0u8; 192];
+        colors[0x30 * 3..0x30 * 3 + 3].copy_from_slice(&[200, 200, 200]);
+        let palette = Palette::generate(&colors);
+        // red
+        assert_eq!(palette.rgb(0b001, 0x30), &[200, 163, 163]);
+        // green and blue
+        assert_eq!(palette.rgb(0b110, 0x30), &[163, 163, 163]);
+        // all three
+        assert_eq!(palette.rgb(0b111, 0x30), &[163, 163, 163]);
+        assert_eq!(palette.rgb(0b100, 0x30), &[163, 163, 200]);
+    }
+
+    #[test]
+    fn loads_pal_files() {
+        let generated = Palette::from_pal(&PALLETE_TABLE).unwrap();
+        assert!(generated == Palette::default());
+        let full = Palette::from_pal(Palette::default().as_bytes()).unwrap();
+        assert!(full == Palette::default());
+        assert!(matches!(
+            Palette::from_pal(&[0u8; 191]),
+            Err(Error::InvalidPalette { len: 191 })
+        ));
+    }
+}
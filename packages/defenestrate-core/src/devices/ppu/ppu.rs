@@ -1,11 +1,18 @@
+use alloc::{boxed::Box, vec, vec::Vec};
+
+use super::dirty::{DirtyRect, DirtyTracker};
+use super::frame_pool::FramePool;
+use super::indexed::{IndexedFrame, IndexedFrames};
+use super::layer_frames::LayerFrames;
+use super::palette::Palette;
 use super::structs::{
-    PpuAddressPart, PpuControlFlags, PpuControlPorts, PpuMaskFlags, PpuOamAttributes,
-    PpuOamByteOffsets, PpuState, PpuStatusFlags, PALLETE_TABLE, PPU_POWERON_STATE,
+    BgPipelineSnapshot, LayerMask, PpuAddressPart, PpuControlFlags, PpuControlPorts, PpuDebugView,
+    PpuMaskFlags, PpuOamAttributes, PpuOamByteOffsets, PpuState, PpuStatusFlags, PPU_POWERON_STATE,
 };
-use super::utils;
-use crate::devices::bus::{ppu_memory_map, BusDevice, BusPeekResult};
-use crate::devices::cartridge::{self, WithCartridge};
-use crate::state;
+use crate::devices::bus::{ppu_memory_map, AccuracyMode, BusDevice, BusPeekResult};
+use crate::devices::cartridge::ICartridge;
+#[cfg(feature = "profiler")]
+use crate::devices::profiler::AccessCounts;
 
 const PPU_NAMETABLE_START_ADDR: u16 = 0x2000;
 const PPU_NAMETABLE_END_ADDR: u16 = 0x3EFF;
@@ -13,6 +20,9 @@ const PPU_NAMETABLE_MASK: u16 = 0x0FFF;
 const PPU_PALETTE_START_ADDR: u16 = 0x3F00;
 const PPU_PALETTE_END_ADDR: u16 = 0x3FFF;
 const PPU_PALETTE_MASK: u16 = 0x001F;
+/// v is 15 bits wide, but only the low 14 make it onto the PPU bus
+const PPU_VRAM_ADDR_MASK: u16 = 0x7FFF;
+const PPU_BUS_ADDR_MASK: u16 = 0x3FFF;
 //  _____________________________________
 // / I am 0x3-CO, you probably didn't    \
 // \ recognize me because of the red arm /
@@ -33,31 +43,188 @@ const PPU_PALETTE_MASK: u16 = 0x001F;
 //       | | |
 //      /_]_[_\
 const ATTR_TABLE_OFFSET: u16 = 0x3C0;
+/// Secondary OAM holds 4 bytes for each of the 8 sprites on a scanline
+const SECONDARY_OAM_SIZE: usize = 32;
+/** How many frames a bit of the PPU's open bus holds up without being driven,
+ * about 600ms
+ *
+ * cf. https://wiki.nesdev.com/w/index.php/Open_bus_behavior#PPU_open_bus
+ */
+const OPEN_BUS_DECAY_FRAMES: u8 = 36;
+/// The scanline the VBlank flag is set on
+const VBLANK_SCANLINE: i16 = 241;
+/// How many dots after the VBlank flag is set a PPUSTATUS read can still
+/// cancel the NMI
+///
+/// cf. https://wiki.nesdev.com/w/index.php/PPU_frame_timing#VBL_Flag_Timing
+const VBLANK_NMI_DELAY: u16 = 2;
 
-/// A trait for a device that owns a PPU, such as the NES Motherboard
-pub trait WithPpu {
-    /// Get an immutable reference to the PPU
-    fn ppu(&self) -> &Ppu2C02;
-    /// Get a mutable reference to the PPU
-    fn ppu_mut(&mut self) -> &mut Ppu2C02;
+/// A trait for a device that owns a PPU and the cartridge on its bus, such as
+/// the NES Motherboard
+///
+/// Most PPU bus accesses go to the cartridge, so the render loop needs both at
+/// once. Borrowing them together lets it work on the PPU's fields directly,
+/// rather than borrowing the console again for every one.
+pub trait WithPpuBus {
+    /// Get mutable references to the PPU and the cartridge at the same time
+    fn ppu_and_cart_mut(&mut self) -> (&mut Ppu2C02, &mut dyn ICartridge);
 }
 
 pub struct Ppu2C02 {
     /** The internal palette memory */
     palette: PpuPaletteRam,
+    /** The RGB values for each color the PPU can output */
+    output_palette: Palette,
+    /** Which layers to draw, regardless of PPUMASK */
+    layer_mask: LayerMask,
     state: PpuState,
+    /** The frame being drawn, and the last one finished */
+    frames: FramePool,
+    /** Whether to render the background a scanline at a time when possible */
+    batch_rendering: bool,
+    /** Which of the expensive edge cases to emulate */
+    accuracy: AccuracyMode,
+    /** Which parts of the frame changed, if dirty tracking is enabled */
+    dirty: Option<DirtyTracker>,
+    /** The frame as PPU color numbers, if indexed output is enabled */
+    indexed: Option<IndexedFrames>,
+    /** The background and sprites on their own, if layer frames are enabled */
+    layer_frames: Option<LayerFrames>,
+    /** Access counts for the pattern tables, if profiling is enabled */
+    #[cfg(feature = "profiler")]
+    chr_profile: Option<AccessCounts>,
 }
 
 impl Ppu2C02 {
     pub fn new() -> Ppu2C02 {
         let palette = PpuPaletteRam::new();
         let state = PPU_POWERON_STATE;
-        Ppu2C02 { palette, state }
+        Ppu2C02 {
+            palette,
+            output_palette: Palette::default(),
+            layer_mask: LayerMask::all(),
+            state,
+            frames: FramePool::new(),
+            batch_rendering: true,
+            accuracy: AccuracyMode::default(),
+            dirty: None,
+            indexed: None,
+            layer_frames: None,
+            #[cfg(feature = "profiler")]
+            chr_profile: None,
+        }
+    }
+
+    /** Start or stop counting accesses to the pattern tables */
+    #[cfg(feature = "profiler")]
+    pub fn set_chr_profiling(&mut self, enabled: bool) {
+        self.chr_profile = if enabled {
+            Some(AccessCounts::new(0x2000))
+        } else {
+            None
+        };
+    }
+
+    /** The pattern table access counts, if profiling is enabled */
+    #[cfg(feature = "profiler")]
+    pub fn chr_profile(&self) -> Option<&AccessCounts> {
+        self.chr_profile.as_ref()
+    }
+
+    #[cfg(feature = "profiler")]
+    pub fn chr_profile_mut(&mut self) -> Option<&mut AccessCounts> {
+        self.chr_profile.as_mut()
+    }
+
+    /** Return the PPU to its power-on state, keeping host-side settings */
+    pub fn power_on(&mut self) {
+        self.palette = PpuPaletteRam::new();
+        self.state = PPU_POWERON_STATE;
+        self.frames.clear();
+        if let Some(dirty) = self.dirty.as_mut() {
+            dirty.invalidate();
+        }
+        if let Some(indexed) = self.indexed.as_mut() {
+            indexed.clear();
+        }
+        if let Some(layer_frames) = self.layer_frames.as_mut() {
+            layer_frames.clear();
+        }
+    }
+
+    /** Put back a state and palette RAM copied out for a save state */
+    pub(crate) fn restore(&mut self, state: PpuState, palette: &[u8]) {
+        self.state = state;
+        self.palette.palette_buffer.copy_from_slice(palette);
+        if let Some(dirty) = self.dirty.as_mut() {
+            dirty.invalidate();
+        }
+    }
+
+    /** Whether the PPU is still ignoring writes after power-on */
+    pub fn is_warming_up(&self) -> bool {
+        self.state.warming_up
+    }
+
+    /** Begin or end the power-on warm-up period.
+     *
+     * The motherboard owns the cycle counter this is measured against, so it
+     * is responsible for ending the warm-up once enough cycles have passed.
+     */
+    pub fn set_warming_up(&mut self, warming_up: bool) {
+        self.state.warming_up = warming_up;
+    }
+
+    /** Handle the console's reset button.
+     *
+     * This clears PPUCTRL, PPUMASK, the scroll, the write latch, and the
+     * PPUDATA read buffer, then ignores writes to the control registers until
+     * the end of the next vblank. VRAM, OAM, and palette RAM are untouched.
+     */
+    pub fn reset(&mut self) {
+        self.state.control = 0;
+        self.state.mask = 0;
+        self.state.t = 0;
+        self.state.x = 0;
+        self.state.w = false;
+        self.state.ppudata_buffer = 0;
+        self.state.in_reset = true;
+    }
+
+    /** Enable or disable the scanline batch renderer.
+     *
+     * The batch renderer falls back to the per-dot pipeline by itself when a
+     * line is modified mid-render, so this is only useful for testing and
+     * for comparing the two paths.
+     */
+    pub fn set_batch_rendering(&mut self, enabled: bool) {
+        self.batch_rendering = enabled;
+    }
+
+    /** Whether the scanline batch renderer is enabled */
+    pub fn is_batch_rendering(&self) -> bool {
+        self.batch_rendering
+    }
+
+    /** Choose which of the expensive edge cases to emulate */
+    pub fn set_accuracy(&mut self, accuracy: AccuracyMode) {
+        self.accuracy = accuracy;
+    }
+
+    pub fn accuracy(&self) -> AccuracyMode {
+        self.accuracy
     }
 
-    /** Whether a VBlank NMI has occured. This should be plumbed to the CPU. */
+    /** Whether a VBlank NMI has occured. This should be plumbed to the CPU.
+     *
+     * The NMI isn't raised until VBLANK_NMI_DELAY dots after the VBlank flag
+     * is set, since a PPUSTATUS read in that window still cancels it.
+     */
     pub fn is_vblank(&self) -> bool {
-        self.state.vblank_nmi_ready
+        let state = &self.state;
+        let in_race_window =
+            state.scanline == VBLANK_SCANLINE && state.pixel_cycle <= VBLANK_NMI_DELAY;
+        state.vblank_nmi_ready && !in_race_window
     }
 
     /** Acknowledge the vblank NMI, so that the PPU stops asserting it */
@@ -70,9 +237,75 @@ impl Ppu2C02 {
         self.state.frame_ready
     }
 
-    /** Retrieve a slice of the current frame */
+    /** Hash the current frame, for cheaply checking it against a known-good one
+     *
+     * This is a 64-bit FNV-1a hash of the RGB frame buffer, which is stable
+     * across platforms and versions of this crate.
+     */
+    pub fn frame_hash(&self) -> u64 {
+        const FNV_OFFSET_BASIS: u64 = 0xCBF2_9CE4_8422_2325;
+        const FNV_PRIME: u64 = 0x0000_0100_0000_01B3;
+        self.frames
+            .front()
+            .iter()
+            .fold(FNV_OFFSET_BASIS, |hash, &byte| {
+                (hash ^ byte as u64).wrapping_mul(FNV_PRIME)
+            })
+    }
+
+    /** Get the internal state of the PPU, for debugging */
+    pub fn state(&self) -> &PpuState {
+        &self.state
+    }
+
+    /** Get a typed view of the registers a PPU debugger would want to show */
+    pub fn debug_state(&self) -> PpuDebugView {
+        let state = &self.state;
+        let status = PpuStatusFlags::from_bits_truncate(state.status);
+        PpuDebugView {
+            v: state.v,
+            t: state.t,
+            fine_x: state.x,
+            write_latch: state.w,
+            scanline: state.scanline,
+            dot: state.pixel_cycle,
+            control: state.control,
+            mask: state.mask,
+            status: state.status,
+            sprite_0_hit: status.contains(PpuStatusFlags::SPRITE_0_HIT),
+            sprite_overflow: status.contains(PpuStatusFlags::SPRITE_OVERFLOW),
+            nmi_pending: state.vblank_nmi_ready,
+            bg_tile_hi_shift_reg: state.bg_tile_hi_shift_reg,
+            bg_tile_lo_shift_reg: state.bg_tile_lo_shift_reg,
+            bg_attr_hi_shift_reg: state.bg_attr_hi_shift_reg,
+            bg_attr_lo_shift_reg: state.bg_attr_lo_shift_reg,
+            sprite_tile_hi_shift_regs: state.sprite_tile_hi_shift_regs,
+            sprite_tile_lo_shift_regs: state.sprite_tile_lo_shift_regs,
+        }
+    }
+
+    /** Retrieve a slice of the last finished frame
+     *
+     * This doesn't change while the next frame is drawn.
+     */
     pub fn get_buffer(&self) -> &[u8] {
-        &self.state.frame_data
+        self.frames.front()
+    }
+
+    /** The id of the frame `get_buffer` returns
+     *
+     * See `FramePool::front_id`.
+     */
+    pub fn frame_id(&self) -> u64 {
+        self.frames.front_id()
+    }
+
+    /** Trade the last finished frame for a buffer owned by the caller
+     *
+     * See `FramePool::swap_buffers` for the details.
+     */
+    pub fn swap_buffers(&mut self, frame: &mut Box<[u8]>) {
+        self.frames.swap_buffers(frame);
     }
 
     /** Write a byte to the OAM
@@ -87,12 +320,93 @@ impl Ppu2C02 {
         &self.palette.palette_buffer
     }
 
-    /** Returns true if rendering is enabled and the PPU is in the visible region */
+    /** Replace the RGB values used for each color, starting from the next pixel */
+    pub fn set_output_palette(&mut self, palette: Palette) {
+        self.output_palette = palette;
+        if let Some(dirty) = self.dirty.as_mut() {
+            dirty.invalidate();
+        }
+    }
+
+    /** Start or stop tracking which parts of each frame changed.
+     *
+     * This costs a compare for every pixel, so it's off by default. The first
+     * frame after turning it on is entirely dirty.
+     */
+    pub fn set_dirty_tracking(&mut self, enabled: bool) {
+        self.dirty = if enabled {
+            Some(DirtyTracker::new())
+        } else {
+            None
+        };
+    }
+
+    /** The parts of the last finished frame that differ from the frame before.
+     *
+     * Without dirty tracking, this is always the whole frame.
+     */
+    pub fn dirty_rects(&self) -> Vec<DirtyRect> {
+        match &self.dirty {
+            Some(dirty) => dirty.dirty_rects(),
+            None => vec![DirtyRect::FULL_FRAME],
+        }
+    }
+
+    /** Start or stop drawing frames as PPU color numbers, as well as RGB */
+    pub fn set_indexed_output(&mut self, enabled: bool) {
+        self.indexed = if enabled {
+            Some(self.indexed.take().unwrap_or_default())
+        } else {
+            None
+        };
+    }
+
+    /** The last finished frame as PPU color numbers, if indexed output is on */
+    pub fn indexed_frame(&self) -> Option<IndexedFrame<'_>> {
+        self.indexed.as_ref().map(IndexedFrames::front)
+    }
+
+    /** Start or stop drawing the background and sprites into frames of
+     * their own, as well as the composited frame
+     */
+    pub fn set_layer_frames(&mut self, enabled: bool) {
+        self.layer_frames = if enabled {
+            Some(self.layer_frames.take().unwrap_or_default())
+        } else {
+            None
+        };
+    }
+
+    /** The last finished frame of just the background, if layer frames are on */
+    pub fn background_frame(&self) -> Option<&[u8]> {
+        self.layer_frames.as_ref().map(LayerFrames::background)
+    }
+
+    /** The last finished frame of just the sprites, if layer frames are on */
+    pub fn sprite_frame(&self) -> Option<&[u8]> {
+        self.layer_frames.as_ref().map(LayerFrames::sprites)
+    }
+
+    pub fn output_palette(&self) -> &Palette {
+        &self.output_palette
+    }
+
+    /** Hide layers from the frame buffer, without changing what the game sees.
+     *
+     * This is for debugging graphics. Hidden layers are still fetched and
+     * still trigger sprite 0 hits, they just aren't drawn.
+     */
+    pub fn set_layer_mask(&mut self, layer_mask: LayerMask) {
+        self.layer_mask = layer_mask;
+    }
+
+    pub fn layer_mask(&self) -> LayerMask {
+        self.layer_mask
+    }
+
+    /** Returns true if rendering is enabled and the PPU is on a visible or pre-render line */
     fn is_rendering(&self) -> bool {
-        return (self.state.mask & (PpuMaskFlags::BG_ENABLE | PpuMaskFlags::SPRITE_ENABLE).bits())
-            > 0
-            && self.state.scanline > -1
-            && self.state.scanline < 240;
+        self.is_rendering_enabled() && (self.state.scanline < 240 || self.state.scanline == 261)
     }
 }
 
@@ -100,362 +414,631 @@ impl Ppu2C02 {
  *
  * Addresses should be given in CPU Bus addresses (eg, $PPUCTRL)
  */
-pub fn control_port_read<T: WithPpu + WithCartridge>(mb: &mut T, port_addr: u16) -> u8 {
-    match port_addr + 0x2000 {
-        PpuControlPorts::PPUSTATUS => {
-            let status = state!(get status, mb)
-                | (PpuStatusFlags::STATUS_IGNORED.bits() & state!(get last_control_port_value, mb));
-            state!(set status, mb, state!(get status, mb) &
-                0xFF & !(PpuStatusFlags::VBLANK | PpuStatusFlags::STATUS_IGNORED).bits());
-            state!(set w, mb, false);
-            state!(set vblank_nmi_ready, mb, false);
-            state!(set last_control_port_value, mb, status);
-            return status;
-        }
-        PpuControlPorts::OAMDATA => {
-            // TODO: OAMDATA reads, like OAMADDR writes, also corrupt OAM
-            return state!(get oam, mb)[state!(get oam_addr, mb) as usize];
-        }
-        PpuControlPorts::PPUDATA => {
-            // For most addresses, we need to buffer the response in internal
-            // memory, since the logic for PPUDATA reads isn't actually
-            // combinatorial and requires some plumbing (except for palette
-            // memory, which is spe
-            let addr = mb.ppu().state.v;
-
-            if !mb.ppu().is_rendering() {
-                if (0xFF
-                    & (state!(get control, mb) & PpuControlFlags::VRAM_INCREMENT_SELECT.bits()))
-                    != 0
-                {
-                    state!(set v, mb, 0x7FFF & (state!(get v, mb) + 32));
-                } else {
-                    state!(set v, mb, 0x7FFF & (state!(get v, mb) + 1));
-                }
-            } else {
-                eprintln!(" [INFO] Read from PPUDATA during render");
-                // Since we're writing during rendering, the PPU will
-                // increment both the coarse X and fine Y due to how the
-                // PPU is wired
-                inc_coarse_x(mb);
-                inc_fine_y(mb);
-            }
-            if port_addr >= 0x3F00 {
-                // This is palette memory, don't buffer...
-                //
-                // ......ish...
-                //
-                // According to Nesdev, the PPU actually _will_ populate the
-                // buffer with whatever's in the nametable, mirrored though
-                // 0x3F00. So let's do that after setting data, just in case
-                // anything needs that...
-                let data = read(mb, addr);
-                let buffer = read(mb, addr & 0x0FFF);
-                state!(set ppudata_buffer, mb, buffer);
-                state!(set last_control_port_value, mb, data);
-                return data;
-            }
-            let buffer = read(mb, addr);
-            let data = state!(get ppudata_buffer, mb);
-            state!(set ppudata_buffer, mb, buffer);
-            state!(set last_control_port_value, mb, data);
-            return data;
-        }
-        _ => mb.ppu().state.last_control_port_value,
-    }
+pub fn control_port_read<T: WithPpuBus>(mb: &mut T, port_addr: u16) -> u8 {
+    let (ppu, cart) = mb.ppu_and_cart_mut();
+    ppu.control_port_read(cart, port_addr)
 }
 
 /** Write data to a control port on the PPU.
  *
  * Addresses should be given in CPU Bus addresses (eg, $PPUCTRL)
  */
-pub fn control_port_write<T: WithPpu + WithCartridge>(mb: &mut T, port_addr: u16, data: u8) {
-    mb.ppu_mut().state.last_control_port_value = data;
-    match port_addr + 0x2000 {
-        // TODO: pre-boot cycle check
-        // TODO: simulate immediate NMI hardware bug
-        // TODO: Bit 0 race condition
-        // TODO: Complain loudly when BG_COLOR_SELECT is set
-        // The exact writes to T and V come from NESDEV documentation on
-        // how the internal PPU registers work:
-        // https://wiki.nesdev.com/w/index.php/PPU_scrolling
-        PpuControlPorts::PPUCTRL => {
-            let ppu = mb.ppu_mut();
-            state!(set control, mb, data);
-            state!(and t, mb,                 0x7FFF & !(PpuAddressPart::NAMETABLE_X | PpuAddressPart::NAMETABLE_Y).bits());
-            state!(or t, mb, ((data & PpuControlFlags::NAMETABLE_BASE_SELECT.bits()) as u16) << 10);
-            return;
-        }
-        PpuControlPorts::PPUMASK => {
-            let ppu = mb.ppu_mut();
-            state!(set mask, mb, data);
-            return;
+pub fn control_port_write<T: WithPpuBus>(mb: &mut T, port_addr: u16, data: u8) {
+    let (ppu, cart) = mb.ppu_and_cart_mut();
+    ppu.control_port_write(cart, port_addr, data);
+}
+
+/** Hand the rest of the scanline to the per-dot pipeline, if the batch
+ * renderer already drew it
+ *
+ * The batch renderer fetches a whole scanline from the cartridge up front, so
+ * this has to be called before anything changes what the cartridge returns
+ * mid-line, like a CHR or nametable bank switch.
+ */
+pub fn flush_batch_renderer<T: WithPpuBus>(mb: &mut T) {
+    let (ppu, cart) = mb.ppu_and_cart_mut();
+    ppu.flush_scanline_cache(cart);
+}
+
+/** Clock the PPU, rendering to the internal framebuffer and modifying state as appropriate */
+pub fn clock<T: WithPpuBus>(mb: &mut T) {
+    let (ppu, cart) = mb.ppu_and_cart_mut();
+    ppu.clock(cart);
+}
+
+impl Ppu2C02 {
+    /** Put `data` on the PPU's data bus latch, which is what reads of
+     * write-only registers see
+     *
+     * The bits in `driven` are refreshed, and the rest are left to decay.
+     */
+    fn refresh_io_latch(&mut self, data: u8, driven: u8) {
+        let state = &mut self.state;
+        state.last_control_port_value = data;
+        for (bit, age) in state.io_latch_age.iter_mut().enumerate() {
+            if driven & (1 << bit) != 0 {
+                *age = 0;
+            }
         }
-        PpuControlPorts::OAMADDR => {
-            // TODO: OAMADDR writes corrupt the OAM in particular ways, which
-            // I might need to implement
-            let ppu = mb.ppu_mut();
-            state!(set oam_addr, mb, data);
-            return;
+    }
+
+    /** Age the bits on the data bus latch by a frame, letting any that
+     * haven't been driven for long enough fade to 0
+     */
+    fn decay_io_latch(&mut self) {
+        let state = &mut self.state;
+        for (bit, age) in state.io_latch_age.iter_mut().enumerate() {
+            if *age >= OPEN_BUS_DECAY_FRAMES {
+                state.last_control_port_value &= !(1 << bit);
+            } else {
+                *age += 1;
+            }
         }
-        PpuControlPorts::OAMDATA => {
-            // TODO: OAMDATA writes, like OAMADDR writes, also corrupt OAM
-            let ppu = mb.ppu_mut();
-            let oam_addr = state!(get oam_addr, mb) as usize;
-            state!(set_arr oam, oam_addr, mb, data);
-            return;
+    }
+
+    fn control_port_read(&mut self, cart: &mut dyn ICartridge, port_addr: u16) -> u8 {
+        match port_addr + 0x2000 {
+            PpuControlPorts::PPUSTATUS => {
+                let state = &mut self.state;
+                if state.scanline == VBLANK_SCANLINE && state.pixel_cycle == 0 {
+                    // The flag is set on the very next dot. Reading now races
+                    // it, so the read sees it clear and it never gets set.
+                    state.vblank_suppressed = true;
+                }
+                let status = state.status
+                    | (PpuStatusFlags::STATUS_IGNORED.bits() & state.last_control_port_value);
+                state.status &= !(PpuStatusFlags::VBLANK | PpuStatusFlags::STATUS_IGNORED).bits();
+                state.w = false;
+                state.vblank_nmi_ready = false;
+                // only the top 3 bits are driven, the rest is open bus
+                self.refresh_io_latch(status, 0xE0);
+                status
+            }
+            PpuControlPorts::OAMDATA => {
+                // While secondary OAM is being cleared, the PPU forces OAM
+                // reads to $FF internally, and OAMDATA sees that
+                let state = &self.state;
+                let data = if self.is_rendering()
+                    && state.scanline < 240
+                    && (1..=64).contains(&state.pixel_cycle)
+                {
+                    0xFF
+                } else {
+                    state.oam[state.oam_addr as usize]
+                };
+                self.refresh_io_latch(data, 0xFF);
+                data
+            }
+            PpuControlPorts::PPUDATA => {
+                // For most addresses, we need to buffer the response in internal
+                // memory, since the logic for PPUDATA reads isn't actually
+                // combinatorial and requires some plumbing (except for palette
+                // memory, which is spe
+                self.flush_scanline_cache(cart);
+                let addr = self.state.v & PPU_BUS_ADDR_MASK;
+                self.increment_vram_addr();
+                if addr >= PPU_PALETTE_START_ADDR {
+                    // Palette reads skip the buffer, but the PPU still fills
+                    // it from the nametable mirrored underneath palette RAM.
+                    // Palette RAM is only 6 bits wide, so the top 2 bits are
+                    // whatever was left on the data bus latch.
+                    let color = self.read(cart, addr) & self.grayscale_mask();
+                    self.state.ppudata_buffer =
+                        self.read(cart, PPU_NAMETABLE_START_ADDR | (addr & PPU_NAMETABLE_MASK));
+                    let data = color | (self.state.last_control_port_value & 0xC0);
+                    self.refresh_io_latch(data, 0x3F);
+                    return data;
+                }
+                let data = self.state.ppudata_buffer;
+                self.state.ppudata_buffer = self.read(cart, addr);
+                self.refresh_io_latch(data, 0xFF);
+                data
+            }
+            _ => self.state.last_control_port_value,
         }
-        PpuControlPorts::PPUSCROLL => {
-            let ppu = mb.ppu_mut();
-            if !state!(get w, mb) {
-                state!(set x, mb, data & 0x07);
-                state!(and t, mb, 0xFFFF & !PpuAddressPart::COARSE_X.bits());
-                state!(or t, mb, ((data as u16) >> 3) & PpuAddressPart::COARSE_X.bits());
-                state!(set w, mb, true);
-            } else {
-                state!(and t, mb,                     0xFFFF & (!(PpuAddressPart::FINE_Y | PpuAddressPart::COARSE_Y).bits()));
-                state!(or t, mb, ((0x07 & (data as u16)) << 12) | (((data as u16) & 0xF8) << 2));
-                state!(set w, mb, false);
+    }
+
+    fn control_port_write(&mut self, cart: &mut dyn ICartridge, port_addr: u16, data: u8) {
+        self.refresh_io_latch(data, 0xFF);
+        match port_addr + 0x2000 {
+            PpuControlPorts::PPUCTRL
+            | PpuControlPorts::PPUMASK
+            | PpuControlPorts::PPUSCROLL
+            | PpuControlPorts::PPUADDR
+                if self.state.in_reset || self.state.warming_up =>
+            {
+                // the PPU ignores these until it's done resetting or warming up
+                return;
             }
-            return;
+            _ => {}
         }
-        PpuControlPorts::PPUADDR => {
-            let ppu = mb.ppu_mut();
-            if !state!(get w, mb) {
-                state!(and t, mb, 0x00FF);
-                state!(or t, mb, ((data as u16) & 0x3F) << 8);
-                state!(set w, mb, true);
-            } else {
-                state!(and t, mb, 0xFF00);
-                state!(or t, mb, data as u16);
-                state!(set v, mb, state!(get t, mb));
-                state!(set w, mb, false);
+        match port_addr + 0x2000 {
+            PpuControlPorts::PPUCTRL
+            | PpuControlPorts::PPUMASK
+            | PpuControlPorts::PPUSCROLL
+            | PpuControlPorts::PPUADDR
+            | PpuControlPorts::PPUDATA => {
+                // these can change the background mid-line, so the batch
+                // renderer needs to hand off to the per-dot pipeline
+                self.flush_scanline_cache(cart);
             }
-            return;
+            _ => {}
         }
-        PpuControlPorts::PPUDATA => {
-            write(mb, mb.ppu().state.v, data);
-            let ppu = mb.ppu_mut();
-            if !ppu.is_rendering() {
-                if (state!(get control, mb) & PpuControlFlags::VRAM_INCREMENT_SELECT.bits()) > 0 {
-                    state!(set v, mb, 0x7FFF & (state!(get v, mb) + 32));
+        let rendering = self.is_rendering();
+        let was_enabled = self.is_rendering_enabled();
+        let state = &mut self.state;
+        match port_addr + 0x2000 {
+            // TODO: simulate immediate NMI hardware bug
+            // TODO: Bit 0 race condition
+            // TODO: Complain loudly when BG_COLOR_SELECT is set
+            // The exact writes to T and V come from NESDEV documentation on
+            // how the internal PPU registers work:
+            // https://wiki.nesdev.com/w/index.php/PPU_scrolling
+            PpuControlPorts::PPUCTRL => {
+                state.control = data;
+                state.t &=
+                    0x7FFF & !(PpuAddressPart::NAMETABLE_X | PpuAddressPart::NAMETABLE_Y).bits();
+                state.t |= ((data & PpuControlFlags::NAMETABLE_BASE_SELECT.bits()) as u16) << 10;
+            }
+            PpuControlPorts::PPUMASK => {
+                state.mask = data;
+            }
+            PpuControlPorts::OAMADDR => {
+                // TODO: OAMADDR writes corrupt the OAM in particular ways, which
+                // I might need to implement
+                state.oam_addr = data;
+            }
+            PpuControlPorts::OAMDATA if rendering => {
+                // OAM is busy with sprite evaluation, so the write is dropped,
+                // but OAMADDR still gets a glitchy increment that only bumps
+                // the sprite index (the high 6 bits)
+                log::trace!(
+                    "OAMDATA write during rendering, at OAMADDR = {:02X}",
+                    state.oam_addr
+                );
+                state.oam_addr = state.oam_addr.wrapping_add(4);
+            }
+            PpuControlPorts::OAMDATA => {
+                state.oam[state.oam_addr as usize] = data;
+                state.oam_addr = state.oam_addr.wrapping_add(1);
+            }
+            PpuControlPorts::PPUSCROLL => {
+                if !state.w {
+                    state.x = data & 0x07;
+                    state.t &= !PpuAddressPart::COARSE_X.bits();
+                    state.t |= ((data as u16) >> 3) & PpuAddressPart::COARSE_X.bits();
+                    state.w = true;
                 } else {
-                    state!(set v, mb, 0x7FFF & (state!(get v, mb) + 1));
+                    state.t &= !(PpuAddressPart::FINE_Y | PpuAddressPart::COARSE_Y).bits();
+                    state.t |= ((0x07 & (data as u16)) << 12) | (((data as u16) & 0xF8) << 2);
+                    state.w = false;
+                }
+            }
+            PpuControlPorts::PPUADDR => {
+                if !state.w {
+                    state.t &= 0x00FF;
+                    state.t |= ((data as u16) & 0x3F) << 8;
+                    state.w = true;
+                } else {
+                    state.t &= 0xFF00;
+                    state.t |= data as u16;
+                    state.v = state.t;
+                    state.w = false;
                 }
-            } else {
-                eprintln!(" [INFO] Write to PPUDATA during render");
-                // Since we're writing during rendering, the PPU will
-                // increment both the coarse X and fine Y due to how the
-                // PPU is wired
-                inc_coarse_x(mb);
-                inc_fine_y(mb);
             }
+            PpuControlPorts::PPUDATA => {
+                self.write(cart, self.state.v & PPU_BUS_ADDR_MASK, data);
+                self.increment_vram_addr();
+            }
+            // PPUSTATUS is read-only, so the write only fills the data bus
+            // latch (done above)
+            PpuControlPorts::PPUSTATUS => {}
+            _ => unreachable!("Invalid PPU control port: ${:04X}", port_addr),
+        };
+        if port_addr + 0x2000 == PpuControlPorts::PPUMASK && self.accuracy.emulates_oam_glitches() {
+            self.toggle_rendering_glitches(was_enabled);
+        }
+    }
+
+    /**
+     * Corrupt OAM the way switching rendering off or on mid-line does
+     *
+     * Switching rendering off while the PPU is clearing secondary OAM or
+     * fetching sprites leaves OAM's row address wherever it was, and the row
+     * it points at gets overwritten with row 0 when rendering starts again.
+     * Which row that is depends on the dot rendering stopped on. Like Mesen,
+     * this flags the row when rendering stops, and copies over it once
+     * rendering is back.
+     *
+     * cf. https://wiki.nesdev.com/w/index.php/PPU_registers#OAMADDR
+     */
+    fn toggle_rendering_glitches(&mut self, was_enabled: bool) {
+        let enabled = self.is_rendering_enabled();
+        let scanline = self.state.scanline;
+        if enabled == was_enabled || !(scanline < 240 || scanline == 261) {
             return;
         }
-        _ => unreachable!("Invalid PPU control port: ${:04X}", port_addr),
-    };
-}
+        if enabled {
+            self.corrupt_oam_rows();
+            return;
+        }
+        let dot = self.state.pixel_cycle;
+        let row = match dot {
+            0..=63 => dot >> 1,
+            256..=319 => ((dot - 256) >> 3) * 4 + ((dot - 256) & 0x07).min(3),
+            _ => return,
+        };
+        self.state.oam_corrupt_rows |= 1 << row;
+    }
 
-/// Read from the PPU bus
-fn read<T: WithPpu + WithCartridge>(mb: &mut T, addr: u16) -> u8 {
-    let (device, addr) = ppu_memory_map::match_addr(addr);
-    let last_bus_value = mb.ppu().state.last_bus_value;
-    let response = match device {
-        ppu_memory_map::Device::CartridgeOrNametable => {
-            mb.cart_mut().read_chr(addr, last_bus_value)
+    /** Copy OAM row 0 over each row flagged by `toggle_rendering_glitches` */
+    fn corrupt_oam_rows(&mut self) {
+        let state = &mut self.state;
+        // row 0 would only be copied onto itself
+        for row in 1..32 {
+            if state.oam_corrupt_rows & (1 << row) != 0 {
+                state.oam.copy_within(0..8, row * 8);
+            }
         }
-        ppu_memory_map::Device::PaletteRAM => mb.ppu_mut().palette.read(addr, last_bus_value),
-        _ => last_bus_value,
-    };
-    mb.ppu_mut().state.last_bus_value = response;
-    return response;
-}
+        state.oam_corrupt_rows = 0;
+    }
 
-fn write<T: WithPpu + WithCartridge>(mb: &mut T, addr: u16, data: u8) {
-    let (device, addr) = ppu_memory_map::match_addr(addr);
-    mb.ppu_mut().state.last_bus_value = data;
-    match device {
-        ppu_memory_map::Device::CartridgeOrNametable => mb.cart_mut().write_chr(addr, data),
-        ppu_memory_map::Device::PaletteRAM => mb.ppu_mut().palette.write(addr, data),
-        _ => {}
+    /**
+     * Apply OAM's glitches for rendering starting on the pre-render line
+     *
+     * Besides the rows flagged by `toggle_rendering_glitches`, if OAMADDR is
+     * 8 or more, the 8 bytes at `OAMADDR & 0xF8` are copied over the first 8
+     * bytes of OAM.
+     *
+     * cf. https://wiki.nesdev.com/w/index.php/PPU_registers#OAMADDR
+     */
+    fn start_rendering_glitches(&mut self) {
+        self.corrupt_oam_rows();
+        let state = &mut self.state;
+        if state.oam_addr >= 8 {
+            let row = (state.oam_addr & 0xF8) as usize;
+            state.oam.copy_within(row..row + 8, 0);
+        }
     }
-}
 
-/** Clock the PPU, rendering to the internal framebuffer and modifying state as appropriate */
-pub fn clock<T: WithPpu + WithCartridge>(mb: &mut T) {
-    if mb.ppu().state.scanline < 240 || mb.ppu().state.scanline == 261 {
-        //#region Background evaluation
-        if (mb.ppu().state.pixel_cycle >= 1 && mb.ppu().state.pixel_cycle < 258)
-            || (mb.ppu().state.pixel_cycle > 320 && mb.ppu().state.pixel_cycle < 337)
-        {
-            update_shift_regs(mb);
-            let CHR_BANK =
-                ((mb.ppu().state.control & PpuControlFlags::BG_TILE_SELECT.bits()) as u16) << 8;
-            match (mb.ppu().state.pixel_cycle - 1) % 8 {
-                0 => {
-                    transfer_registers(mb);
-                    mb.ppu_mut().state.temp_nt_byte =
-                        read(mb, PPU_NAMETABLE_START_ADDR | (mb.ppu().state.v & 0x0FFF));
+    /**
+     * The mask applied to colors read out of palette RAM
+     *
+     * In grayscale mode, the PPU drops the low 4 bits of each color, which
+     * leaves only the gray column of the system palette.
+     */
+    fn grayscale_mask(&self) -> u8 {
+        if self.state.mask & PpuMaskFlags::USE_GRAYSCALE.bits() != 0 {
+            0x30
+        } else {
+            0x3F
+        }
+    }
+
+    /// Read from the PPU bus
+    fn read(&mut self, cart: &mut dyn ICartridge, addr: u16) -> u8 {
+        #[cfg(feature = "profiler")]
+        if let Some(profile) = self.chr_profile.as_mut() {
+            profile.record_read(addr);
+        }
+        let (device, addr) = ppu_memory_map::match_addr(addr);
+        let last_bus_value = self.state.last_bus_value;
+        let response = match device {
+            ppu_memory_map::Device::CartridgeOrNametable => cart.read_chr(addr, last_bus_value),
+            ppu_memory_map::Device::PaletteRAM => self.palette.read(addr, last_bus_value),
+            _ => last_bus_value,
+        };
+        self.state.last_bus_value = response;
+        response
+    }
+
+    fn write(&mut self, cart: &mut dyn ICartridge, addr: u16, data: u8) {
+        #[cfg(feature = "profiler")]
+        if let Some(profile) = self.chr_profile.as_mut() {
+            profile.record_write(addr);
+        }
+        let (device, addr) = ppu_memory_map::match_addr(addr);
+        self.state.last_bus_value = data;
+        match device {
+            ppu_memory_map::Device::CartridgeOrNametable => cart.write_chr(addr, data),
+            ppu_memory_map::Device::PaletteRAM => self.palette.write(addr, data),
+            _ => {}
+        }
+    }
+
+    fn clock(&mut self, cart: &mut dyn ICartridge) {
+        let dot = self.state.pixel_cycle;
+        if self.state.scanline < 240 || self.state.scanline == 261 {
+            //#region Background evaluation
+            if dot == 1 && self.state.scanline < 240 {
+                self.cache_scanline(cart);
+            }
+            if self.state.bg_line_cached && (1..=256).contains(&dot) {
+                // The batch renderer already fetched this line, so only the sprite
+                // shifters need to move until the pipeline catches up on dot 256
+                self.update_sprite_shift_regs();
+                if dot == 256 {
+                    self.commit_scanline_cache();
                 }
-                2 => {
-                    // self.state addressing comes from NESDEV:
-                    // https://wiki.nesdev.com/w/index.php/PPU_scrolling#Tile_and_attribute_fetching
-                    mb.ppu_mut().state.temp_at_byte = read(
-                        mb,
-                        PPU_NAMETABLE_START_ADDR
-                            | ATTR_TABLE_OFFSET
-                            | (mb.ppu().state.v & 0x0C00)
-                            | ((mb.ppu().state.v >> 4) & 0x38)
-                            | ((mb.ppu().state.v >> 2) & 0x07),
-                    );
-                    if (((mb.ppu().state.v & PpuAddressPart::COARSE_Y.bits()) >> 5) & 0x02) > 0 {
-                        mb.ppu_mut().state.temp_at_byte >>= 4;
-                    }
-                    if ((mb.ppu().state.v & PpuAddressPart::COARSE_X.bits()) & 0x02) > 0 {
-                        mb.ppu_mut().state.temp_at_byte >>= 2;
-                    }
-                    mb.ppu_mut().state.temp_at_byte &= 3;
+            } else if (1..258).contains(&dot) || (321..337).contains(&dot) {
+                self.update_sprite_shift_regs();
+                self.bg_pipeline_step(cart, dot);
+            }
+            if dot == 337 || dot == 339 {
+                // make a dummy read of the nametable bit
+                // this is important, since some mappers like MMC3 use it to
+                // clock a scanline counter
+                self.read(cart, PPU_NAMETABLE_START_ADDR | (self.state.v & 0x0FFF));
+            }
+            //#endregion
+
+            //#region Sprite evaluation
+            if self.is_rendering_enabled() {
+                if dot == 0 && self.state.scanline == 261 && self.accuracy.emulates_oam_glitches() {
+                    self.start_rendering_glitches();
                 }
-                4 => {
-                    mb.ppu_mut().state.temp_bg_lo_byte = read(
-                        mb,
-                        CHR_BANK
-                            | ((mb.ppu().state.temp_nt_byte as u16) << 4)
-                            | ((mb.ppu().state.v & PpuAddressPart::FINE_Y.bits()) >> 12),
-                    );
+                match dot {
+                    // the pre-render line doesn't look for sprites, so
+                    // scanline 0 never has any
+                    1..=256 if self.state.scanline == 261 => {}
+                    1..=256 if self.accuracy.evaluates_sprites_per_dot() => {
+                        self.sprite_eval_step(dot)
+                    }
+                    256 => self.evaluate_sprites(),
+                    // I'm still cheating on the fetches, which really happen
+                    // one sprite at a time over dots 257-320
+                    258 => self.fetch_sprites(cart),
+                    _ => {}
                 }
-                6 => {
-                    mb.ppu_mut().state.temp_bg_hi_byte = read(
-                        mb,
-                        CHR_BANK
-                            | ((mb.ppu().state.temp_nt_byte as u16) << 4)
-                            | ((mb.ppu().state.v & PpuAddressPart::FINE_Y.bits()) >> 12)
-                            | 8,
-                    );
+                if (257..=320).contains(&dot) {
+                    self.state.oam_addr = 0;
                 }
-                7 => {
-                    inc_coarse_x(mb);
+            }
+            //#endregion
+
+            //#region Address increments
+            if dot == 256 {
+                self.inc_fine_y();
+            }
+            if dot == 257 {
+                self.transfer_x_addr();
+            }
+            // this is the pre-render scanline, it has some special handling
+            if self.state.scanline == 261 {
+                if dot == 1 {
+                    self.state.in_reset = false;
+                    self.state.status &= !(PpuStatusFlags::SPRITE_0_HIT
+                        | PpuStatusFlags::SPRITE_OVERFLOW
+                        | PpuStatusFlags::VBLANK)
+                        .bits();
                 }
-                _ => {
-                    // no-op- we're waiting on a read or doing something else
+                if dot >= 280 || dot < 305 {
+                    self.transfer_y_addr();
                 }
             }
+            //#endregion
         }
-        if state!(get pixel_cycle, mb) == 337 || state!(get pixel_cycle, mb) == 339 {
-            // make a dummy read of the nametable bit
-            // self.state is important, since some mappers like MMC3 use self.state to
-            // clock a scanline counter
-            read(mb, PPU_NAMETABLE_START_ADDR | (state!(get v, mb) & 0x0FFF));
+        let state = &mut self.state;
+        // check if we need to set the vblank flag
+        let nmi_enabled = (state.control & PpuControlFlags::VBLANK_NMI_ENABLE.bits()) > 0;
+        if state.scanline == VBLANK_SCANLINE && dot == 0 {
+            if !state.vblank_suppressed {
+                state.vblank_nmi_ready = nmi_enabled;
+                state.status |= PpuStatusFlags::VBLANK.bits();
+            }
+            state.vblank_suppressed = false;
+        }
+        // this is a true render scanline
+        if state.scanline < 240 && (1..=256).contains(&dot) {
+            // dot 0 is idle, and each of dots 1-256 outputs one pixel
+            self.render_pixel(cart);
         }
-        //#endregion
 
-        //#region Sprite evaluation
-        // I'm cheating here, technically the sprite evaluation is pipelined
-        // just like the background, but I'm gonna implement that later
-        if state!(get pixel_cycle, mb) == 258 {
-            // clear the secondary OAM
-            state!(set secondary_oam, mb, [0xFFu8; 64]);
-            let mut n_sprites = 0;
-            let mut byte_addr = 0;
-            for sprite in (state!(get oam_addr, mb) / 4)..64 {
-                let diff =
-                    state!(get scanline, mb) - (state!(get oam, mb)[(sprite * 4) as usize] as i16);
-                let diff_cmp =
-                    if state!(get control, mb) & PpuControlFlags::SPRITE_MODE_SELECT.bits() > 0 {
-                        16
-                    } else {
-                        8
-                    };
-                if diff >= 0 && diff < (diff_cmp) {
-                    // self.state sprite is visible
-                    n_sprites += 1;
-                    if n_sprites == 8 {
-                        // TODO: Sprite Overflow bug
-                        // for now self.state is an incorrectly correct setup
-                        state!(or status, mb, PpuStatusFlags::SPRITE_OVERFLOW.bits());
-                        break;
-                    }
-                    for i in 0u8..4u8 {
-                        mb.ppu_mut().state.secondary_oam[((n_sprites - 1) * 4 + i) as usize] =
-                            state!(get oam, mb)[(sprite * 4 + i) as usize];
-                    }
-                }
+        let state = &mut self.state;
+        state.pixel_cycle += 1;
+        if state.pixel_cycle > 340 {
+            state.pixel_cycle = 0;
+            state.scanline += 1;
+        }
+
+        state.frame_ready = false;
+
+        if state.scanline > 261 {
+            // The "0" scanline is special, and rendering should handle it differently
+            state.scanline = 0;
+            state.frame_ready = true;
+            if self.accuracy.decays_open_bus() {
+                self.decay_io_latch();
+            }
+            self.frames.finish_frame();
+            if let Some(dirty) = self.dirty.as_mut() {
+                dirty.finish_frame();
             }
-            // prepare the shifters for rendering
-            for i in 0..n_sprites {
-                let tile_addr = (((state!(get control, mb) & PpuControlFlags::SPRITE_TILE_SELECT.bits()) as u16) << 9)
-                            // +1 = tile id
-                        | ((state!(get secondary_oam, mb)[(i * 4 + 1) as usize] as u16) << 4)
-                        | ((state!(get scanline, mb) as u16) - (state!(get secondary_oam, mb)[(i * 4) as usize] as u16));
-                state!(set_arr sprite_tile_lo_shift_regs, i, mb, read(mb, tile_addr));
-                state!(set_arr sprite_tile_hi_shift_regs, i, mb, read(mb, tile_addr + 8));
+            if let Some(indexed) = self.indexed.as_mut() {
+                indexed.finish_frame(&self.palette.palette_buffer);
+            }
+            if let Some(layer_frames) = self.layer_frames.as_mut() {
+                layer_frames.finish_frame();
             }
         }
-        //#endregion
+    }
 
-        //#region Address increments
-        if state!(get pixel_cycle, mb) == 256 {
-            inc_fine_y(mb);
+    /**
+     * Run one dot of sprite evaluation for the next scanline
+     *
+     * Dots 1-64 clear secondary OAM to $FF, and dots 65-256 search OAM for
+     * sprites on this scanline, starting from OAMADDR. Odd dots read OAM, and
+     * even dots act on what was read.
+     *
+     * cf. https://wiki.nesdev.com/w/index.php/PPU_sprite_evaluation
+     */
+    fn sprite_eval_step(&mut self, dot: u16) {
+        let state = &mut self.state;
+        if dot <= 64 {
+            if dot & 0x01 == 0 {
+                state.secondary_oam[(dot / 2 - 1) as usize] = 0xFF;
+            }
+            return;
         }
-        if state!(get pixel_cycle, mb) == 257 {
-            transfer_x_addr(mb);
+        if dot == 65 {
+            // A misaligned OAMADDR starts evaluation mid-sprite, so every
+            // "sprite" after that is off by a few bytes. Only Strict mode
+            // keeps that, since it's rare and looks like a bug.
+            // cf. https://wiki.nesdev.com/w/index.php/PPU_sprite_evaluation
+            if !self.accuracy.emulates_oam_glitches() {
+                state.oam_addr &= !0x03;
+            }
+            state.secondary_oam_addr = 0;
+            state.sprite_eval_done = false;
+            state.sprite_zero_in_range = false;
         }
-        // self.state is the pre-render scanline, it has some special handling
-        if state!(get scanline, mb) == 261 {
-            if state!(get pixel_cycle, mb) == 1 {
-                state!(and status, mb, 0xFF
-                    & !(PpuStatusFlags::SPRITE_0_HIT
-                        | PpuStatusFlags::SPRITE_OVERFLOW
-                        | PpuStatusFlags::VBLANK)
-                        .bits());
+        if dot & 0x01 == 1 {
+            state.temp_oam_byte = state.oam[state.oam_addr as usize];
+            return;
+        }
+        if state.sprite_eval_done {
+            return;
+        }
+        let data = state.temp_oam_byte;
+        let in_range = sprite_row(state, data).is_some();
+        let (next_addr, carry) = if (state.secondary_oam_addr as usize) < SECONDARY_OAM_SIZE {
+            state.secondary_oam[state.secondary_oam_addr as usize] = data;
+            if state.secondary_oam_addr & 0x03 != 0 || in_range {
+                // copy the rest of this sprite
+                if dot == 66 {
+                    state.sprite_zero_in_range = true;
+                }
+                state.secondary_oam_addr += 1;
+                state.oam_addr.overflowing_add(1)
+            } else {
+                state.oam_addr.overflowing_add(4)
+            }
+        } else if in_range {
+            // TODO: the PPU keeps reading the rest of this sprite, but nothing
+            // can see that yet
+            state.status |= PpuStatusFlags::SPRITE_OVERFLOW.bits();
+            (state.oam_addr, true)
+        } else {
+            // The sprite overflow bug: once secondary OAM is full, the PPU
+            // moves to the next sprite _and_ the next byte in each sprite, so
+            // it checks X positions and tiles as if they were Y positions
+            let sprite = (state.oam_addr & !0x03).overflowing_add(4);
+            (sprite.0 | (state.oam_addr.wrapping_add(1) & 0x03), sprite.1)
+        };
+        state.oam_addr = next_addr;
+        // every sprite has been evaluated once n wraps around
+        state.sprite_eval_done = carry;
+    }
+
+    /**
+     * Find all of the next scanline's sprites at once, for when per-dot
+     * evaluation is too slow
+     *
+     * This finds the same sprites `sprite_eval_step` would, and leaves
+     * secondary OAM the same way, but sets the overflow flag only when there
+     * really is a ninth sprite on the line.
+     */
+    fn evaluate_sprites(&mut self) {
+        let state = &mut self.state;
+        state.secondary_oam[..SECONDARY_OAM_SIZE].fill(0xFF);
+        state.secondary_oam_addr = 0;
+        state.sprite_zero_in_range = false;
+        // like per-dot evaluation, this starts from OAMADDR and stops at the
+        // end of OAM
+        let start = (state.oam_addr & !0x03) as usize;
+        for (idx, sprite) in state.oam[start..].chunks_exact(4).enumerate() {
+            if sprite_row(state, sprite[0]).is_none() {
+                continue;
             }
-            if state!(get pixel_cycle, mb) >= 280 || state!(get pixel_cycle, mb) < 305 {
-                transfer_y_addr(mb);
+            let slot = state.secondary_oam_addr as usize;
+            if slot >= SECONDARY_OAM_SIZE {
+                state.status |= PpuStatusFlags::SPRITE_OVERFLOW.bits();
+                break;
+            }
+            state.secondary_oam[slot..slot + 4].copy_from_slice(sprite);
+            state.secondary_oam_addr += 4;
+            if idx == 0 {
+                state.sprite_zero_in_range = true;
             }
         }
-        //#endregion
+        state.sprite_eval_done = true;
     }
-    // check if we need to set the vblank flag
-    let nmi_enabled = (state!(get control, mb) & PpuControlFlags::VBLANK_NMI_ENABLE.bits()) > 0;
-    if state!(get scanline, mb) == 241 && state!(get pixel_cycle, mb) == 0 {
-        state!(set vblank_nmi_ready, mb, nmi_enabled);
-        if (nmi_enabled) {
-            panic!("panik")
+
+    /**
+     * Load the sprites found by evaluation into the sprite shifters
+     *
+     * These are drawn on the next scanline, which is why a sprite shows up a
+     * line below its Y position. The pre-render line doesn't evaluate
+     * sprites, so nothing is ever drawn on scanline 0.
+     */
+    fn fetch_sprites(&mut self, cart: &mut dyn ICartridge) {
+        let n_sprites = if self.state.scanline == 261 {
+            0
         } else {
-        } // kalm
-        state!(or status, mb, PpuStatusFlags::VBLANK.bits());
-    }
-    // self.state is a true render scanline
-    if state!(get scanline, mb) < 240
-        && state!(get pixel_cycle, mb) > 3
-        && state!(get scanline, mb) < 257
-    {
-        // interestingly enough, pixel output doesn't begin until cycle _4_.
-        // self.state comes from NESDEV:
-        // https://wiki.nesdev.com/w/index.php/NTSC_video
+            (self.state.secondary_oam_addr as usize) / 4
+        };
+        self.state.sprite_zero_on_line = self.state.sprite_zero_in_range && n_sprites > 0;
+        for i in 0..8 {
+            let state = &self.state;
+            let mut sprite = [0u8; 4];
+            sprite.copy_from_slice(&state.secondary_oam[i * 4..i * 4 + 4]);
+            // a slot that's still $FF from the clear, or that holds a sprite
+            // that isn't on this line, is empty
+            let row = match sprite_row(state, sprite[PpuOamByteOffsets::Y_POS.bits() as usize]) {
+                Some(row) if i < n_sprites => row,
+                _ => {
+                    // empty slots are transparent
+                    self.state.sprite_x_counters[i] = 0xFF;
+                    self.state.sprite_attrs[i] = 0xFF;
+                    self.state.sprite_tile_lo_shift_regs[i] = 0;
+                    self.state.sprite_tile_hi_shift_regs[i] = 0;
+                    continue;
+                }
+            };
+            let tile_addr = (((state.control & PpuControlFlags::SPRITE_TILE_SELECT.bits()) as u16) << 9)
+                        // +1 = tile id
+                        | ((sprite[PpuOamByteOffsets::TILE.bits() as usize] as u16) << 4)
+                        | row;
+            self.state.sprite_x_counters[i] = sprite[PpuOamByteOffsets::X_POS.bits() as usize];
+            self.state.sprite_attrs[i] = sprite[PpuOamByteOffsets::ATTR.bits() as usize];
+            self.state.sprite_tile_lo_shift_regs[i] = self.read(cart, tile_addr);
+            self.state.sprite_tile_hi_shift_regs[i] = self.read(cart, tile_addr + 8);
+        }
+    }
+
+    /** Draw the pixel for the current dot into the frame buffer */
+    fn render_pixel(&mut self, cart: &mut dyn ICartridge) {
+        let layer_mask = self.layer_mask;
+        let state = &mut self.state;
+        let bg_enabled = (state.mask & PpuMaskFlags::BG_ENABLE.bits()) > 0;
+        let sprites_enabled = (state.mask & PpuMaskFlags::SPRITE_ENABLE.bits()) > 0;
         //#region Background rendering
         let mut bg_pixel = 0x00;
         let mut bg_palette = 0x00;
 
-        if (state!(get mask, mb) & PpuMaskFlags::BG_ENABLE.bits()) > 0 {
-            let bit_mux = 0x8000 >> state!(get x, mb);
-            let pattern_hi = if (state!(get bg_tile_hi_shift_reg, mb) & bit_mux) > 0 {
+        if bg_enabled && state.bg_line_cached {
+            let cached = state.bg_line_cache[(state.pixel_cycle - 1) as usize];
+            bg_pixel = cached & 0x03;
+            bg_palette = cached >> 2;
+        } else if bg_enabled {
+            let bit_mux = 0x8000 >> state.x;
+            let pattern_hi = if (state.bg_tile_hi_shift_reg & bit_mux) > 0 {
                 1
             } else {
                 0
             };
-            let pattern_lo = if (state!(get bg_tile_lo_shift_reg, mb) & bit_mux) > 0 {
+            let pattern_lo = if (state.bg_tile_lo_shift_reg & bit_mux) > 0 {
                 1
             } else {
                 0
             };
             bg_pixel = (pattern_hi << 1) | pattern_lo;
-            let palette_hi = if ((state!(get bg_attr_hi_shift_reg, mb) as u16) & bit_mux) > 0 {
+            let palette_hi = if (state.bg_attr_hi_shift_reg & bit_mux) > 0 {
                 1
             } else {
                 0
             };
-            let palette_lo = if ((state!(get bg_attr_lo_shift_reg, mb) as u16) & bit_mux) > 0 {
+            let palette_lo = if (state.bg_attr_lo_shift_reg & bit_mux) > 0 {
                 1
             } else {
                 0
@@ -470,33 +1053,58 @@ pub fn clock<T: WithPpu + WithCartridge>(mb: &mut T) {
         let mut sprite_priority = false;
         let mut is_sprite0_rendered = false;
 
-        if (state!(get mask, mb) & PpuMaskFlags::SPRITE_ENABLE.bits()) > 0 {
+        if sprites_enabled {
+            // The first sprite (in OAM order) with an opaque pixel here wins,
+            // and only after that does its priority bit get a say. So a sprite
+            // behind the background still hides any later sprites under it,
+            // which games use to mask sprites with the background.
             for i in 0..8 {
-                // self.state sprite is active, use the shifters
-                if state!(get secondary_oam, mb)[(i * 4 + PpuOamByteOffsets::X_POS.bits()) as usize]
-                    == 0
-                {
-                    if i == 0 {
-                        is_sprite0_rendered = true;
-                    }
-                    let pattern_hi = state!(get sprite_tile_hi_shift_regs, mb)[i as usize] & 0x80;
-                    let pattern_lo = state!(get sprite_tile_lo_shift_regs, mb)[i as usize] & 0x80;
-                    sprite_pixel = (pattern_hi << 1) | pattern_lo;
-                    let attr = state!(get secondary_oam, mb)
-                        [(i * 4 + PpuOamByteOffsets::ATTR.bits()) as usize];
-                    // add 0x04 since the sprites use the last 4 palettes
-                    sprite_palette = (attr & PpuOamAttributes::PALLETE.bits()) + 0x04;
-                    sprite_priority = attr & PpuOamAttributes::BACKGROUND_PRIORITY.bits() > 0;
-                    if sprite_pixel != 0 {
-                        // we're done, a non-transparent sprite pixel has been selected
-                        break;
-                    }
+                // only active sprites are shifting out pixels
+                if state.sprite_x_counters[i] != 0 {
+                    continue;
                 }
+                let pattern_hi = (state.sprite_tile_hi_shift_regs[i] & 0x80) >> 6;
+                let pattern_lo = (state.sprite_tile_lo_shift_regs[i] & 0x80) >> 7;
+                if pattern_hi | pattern_lo == 0 {
+                    continue;
+                }
+                is_sprite0_rendered = i == 0 && state.sprite_zero_on_line;
+                sprite_pixel = pattern_hi | pattern_lo;
+                let attr = state.sprite_attrs[i];
+                // add 0x04 since the sprites use the last 4 palettes
+                sprite_palette = (attr & PpuOamAttributes::PALLETE.bits()) + 0x04;
+                sprite_priority = attr & PpuOamAttributes::BACKGROUND_PRIORITY.bits() > 0;
+                break;
             }
         }
         //#endregion
 
         //#region Compositing
+        // test for sprite0 hits before hiding any layers, since the layer mask
+        // is only for the host and the game shouldn't be able to tell
+        if is_sprite0_rendered
+            && sprite_pixel != 0
+            && bg_pixel != 0
+            && bg_enabled
+            && sprites_enabled
+        {
+            state.status |= PpuStatusFlags::SPRITE_0_HIT.bits();
+        }
+        // the layer frames are for the host too, and show what the mask hides
+        let layer_colors = if self.layer_frames.is_some() {
+            Some((
+                self.palette.color_of(bg_palette, bg_pixel),
+                self.palette.color_of(sprite_palette, sprite_pixel),
+            ))
+        } else {
+            None
+        };
+        if !layer_mask.contains(LayerMask::BACKGROUND) {
+            bg_pixel = 0;
+        }
+        if !layer_mask.contains(LayerMask::SPRITES) {
+            sprite_pixel = 0;
+        }
         let mut pixel = bg_pixel;
         let mut palette = bg_palette;
         if sprite_pixel != 0 {
@@ -504,167 +1112,430 @@ pub fn clock<T: WithPpu + WithCartridge>(mb: &mut T) {
                 // use the sprite
                 pixel = sprite_pixel;
                 palette = sprite_palette;
-            } else {
+            } else if !sprite_priority {
                 // we need to sort out priority
-                if !sprite_priority {
-                    pixel = sprite_pixel;
-                    palette = sprite_palette;
-                }
-                // then test for sprite0 hits
-                if is_sprite0_rendered {
-                    if (state!(get mask, mb) & PpuMaskFlags::BG_ENABLE.bits() > 0)
-                        && (state!(get mask, mb) & PpuMaskFlags::SPRITE_ENABLE.bits() > 0)
-                    {
-                        state!(or status, mb, PpuStatusFlags::SPRITE_0_HIT.bits());
-                    }
-                }
+                pixel = sprite_pixel;
+                palette = sprite_palette;
             }
         }
-        let color = read(
-            mb,
+        let color = self.read(
+            cart,
             PPU_PALETTE_START_ADDR
                 | (if pixel == 0x00 {
                     0u16
                 } else {
                     ((palette as u16) << 2) | (pixel as u16)
                 }),
-        ) as u16;
-        let idx = (state!(get scanline, mb) as u16) * 256 + state!(get pixel_cycle, mb);
-        for i in 0..3 {
-            state!(set_arr frame_data, idx * 3 + i, mb, PALLETE_TABLE[(color * 3 + i) as usize]);
+        );
+        let state = &mut self.state;
+        let emphasis = state.mask >> 5;
+        let (x, y) = ((state.pixel_cycle - 1) as usize, state.scanline as usize);
+        let idx = (y * 256 + x) * 3;
+        self.frames.back_mut()[idx..idx + 3]
+            .copy_from_slice(self.output_palette.rgb(emphasis, color));
+        if let Some(dirty) = self.dirty.as_mut() {
+            dirty.draw(
+                x,
+                y,
+                (((emphasis & 0x07) as u16) << 6) | (color & 0x3F) as u16,
+            );
         }
-    //#endregion
-    } else if state!(get pixel_cycle, mb) < 4 {
-        let idx = (state!(get scanline, mb) as u16) * 256 + state!(get pixel_cycle, mb);
-        let color = read(mb, PPU_PALETTE_START_ADDR) as u16;
-        for i in 0..3 {
-            // fill with black for now
-            // technically self.state should actually be the background color
-            state!(set_arr frame_data, (idx * 3 + i) as usize, mb, PALLETE_TABLE[(color * 3 + i) as usize]);
+        if let Some(indexed) = self.indexed.as_mut() {
+            indexed.draw(x, y, color & 0x3F, emphasis & 0x07);
+        }
+        if let (Some(layer_frames), Some((bg_color, sprite_color))) =
+            (self.layer_frames.as_mut(), layer_colors)
+        {
+            layer_frames.draw(
+                x,
+                y,
+                self.output_palette.rgb(emphasis, bg_color),
+                self.output_palette.rgb(emphasis, sprite_color),
+            );
         }
+        //#endregion
     }
-    state!(add pixel_cycle, mb, 1);
 
-    if state!(get pixel_cycle, mb) > 340 {
-        state!(set pixel_cycle, mb, 0);
-        state!(add scanline, mb, 1);
+    /** Whether either the background or sprites are enabled */
+    fn is_rendering_enabled(&self) -> bool {
+        (self.state.mask & (PpuMaskFlags::BG_ENABLE | PpuMaskFlags::SPRITE_ENABLE).bits()) != 0
     }
 
-    state!(set frame_ready, mb, false);
-
-    if state!(get scanline, mb) > 261 {
-        // The "0" scanline is special, and rendering should handle it differently
-        state!(set scanline, mb, 0);
-        state!(set frame_ready, mb, true);
+    /**
+     * Advance v after a PPUDATA read or write
+     *
+     * Outside of rendering this adds 1 or 32, depending on PPUCTRL, and wraps
+     * from $7FFF back to $0000.
+     */
+    fn increment_vram_addr(&mut self) {
+        if self.is_rendering() {
+            log::trace!(
+                "PPUDATA access during rendering, at v = {:04X}",
+                self.state.v
+            );
+            // The PPU increments both the coarse X and fine Y during
+            // rendering, due to how it's wired
+            self.inc_coarse_x();
+            self.inc_fine_y();
+            return;
+        }
+        let increment = if (self.state.control & PpuControlFlags::VRAM_INCREMENT_SELECT.bits()) != 0
+        {
+            32
+        } else {
+            1
+        };
+        self.state.v = self.state.v.wrapping_add(increment) & PPU_VRAM_ADDR_MASK;
     }
-}
 
-/** Increment the coarse X register */
-fn inc_coarse_x<T: WithPpu>(mb: &mut T) {
-    if (state!(get mask, mb) & (PpuMaskFlags::BG_ENABLE | PpuMaskFlags::SPRITE_ENABLE).bits()) == 0
-    {
-        return;
+    /** Increment the coarse X register */
+    fn inc_coarse_x(&mut self) {
+        if !self.is_rendering_enabled() {
+            return;
+        }
+        self.state.v = coarse_x_increment(self.state.v);
     }
-    if (state!(get v, mb) & PpuAddressPart::COARSE_X.bits()) == 31 {
-        // clear the coarse X and invert the X nametable
-        state!(and v, mb, 0xFFFF & !PpuAddressPart::COARSE_X.bits());
-        state!(xor v, mb, PpuAddressPart::NAMETABLE_X.bits());
-    } else {
-        // increment coarse X directly
-        state!(add v, mb, 1);
+
+    /** Increment the fine Y register */
+    fn inc_fine_y(&mut self) {
+        if !self.is_rendering_enabled() {
+            return;
+        }
+        let state = &mut self.state;
+        if (state.v & PpuAddressPart::FINE_Y.bits()) != 0x7000 {
+            // if the fine Y is less than 7, we can increment it directly
+            state.v += 0x1000;
+        } else {
+            // clear fine Y and attempt to increment coarse Y
+            state.v &= !PpuAddressPart::FINE_Y.bits();
+            let mut new_y = (state.v & PpuAddressPart::COARSE_Y.bits()) >> 5;
+            if new_y == 29 {
+                // flip nametables
+                new_y = 0;
+                state.v ^= PpuAddressPart::NAMETABLE_Y.bits();
+            } else if new_y == 31 {
+                // a weird quirk of the PPU is that it allows setting coarse Y
+                // out-of-bounds. When the coarse Y increments to 31 (where it
+                // would overflow), the PPU doesn't switch the nametable. This
+                // is, in effect, a "negative" scroll value of sorts.
+                new_y = 0;
+            } else {
+                new_y += 1;
+            }
+            state.v &= !PpuAddressPart::COARSE_Y.bits();
+            state.v |= new_y << 5;
+        }
     }
-}
 
-/** Increment the fine Y register */
-fn inc_fine_y<T: WithPpu>(mb: &mut T) {
-    if (state!(get mask, mb) & (PpuMaskFlags::BG_ENABLE | PpuMaskFlags::SPRITE_ENABLE).bits()) == 0
-    {
-        return;
+    fn transfer_registers(&mut self) {
+        let state = &mut self.state;
+        state.bg_tile_lo_shift_reg =
+            (state.bg_tile_lo_shift_reg & 0xFF00) | (state.temp_bg_lo_byte as u16);
+        state.bg_tile_hi_shift_reg =
+            (state.bg_tile_hi_shift_reg & 0xFF00) | (state.temp_bg_hi_byte as u16);
+        state.bg_attr_latch = state.temp_at_byte;
+        state.bg_attr_lo_shift_reg =
+            (state.bg_attr_lo_shift_reg & 0xFF00) | (0xFF * ((state.bg_attr_latch & 0x01) as u16));
+        state.bg_attr_hi_shift_reg = (state.bg_attr_hi_shift_reg & 0xFF00)
+            | (0xFF * (((state.bg_attr_latch & 0x02) >> 1) as u16));
     }
-    if (state!(get v, mb) & PpuAddressPart::FINE_Y.bits()) != 0x7000 {
-        // if the fine Y is less than 7, we can increment it directly
-        state!(add v, mb, 0x1000);
-    } else {
-        // clear fine Y and attempt to increment coarse Y
-        state!(and v, mb, 0xFFFF & !PpuAddressPart::FINE_Y.bits());
-        let mut new_y = (state!(get v, mb) & PpuAddressPart::COARSE_Y.bits()) >> 5;
-        if new_y == 29 {
-            // flip nametables
-            new_y = 0;
-            state!(xor v, mb, PpuAddressPart::NAMETABLE_Y.bits());
-        } else if new_y == 31 {
-            // a weird quirk of the PPU is that it allows setting coarse Y
-            // out-of-bounds. When the coarse Y increments to 31 (where it
-            // would overflow), the PPU doesn't switch the nametable. This
-            // is, in effect, a "negative" scroll value of sorts.
-            new_y = 0;
-        } else {
-            new_y += 1;
+
+    fn update_bg_shift_regs(&mut self) {
+        let state = &mut self.state;
+        if state.mask & PpuMaskFlags::BG_ENABLE.bits() > 0 {
+            state.bg_tile_hi_shift_reg <<= 1;
+            state.bg_tile_lo_shift_reg <<= 1;
+            state.bg_attr_lo_shift_reg <<= 1;
+            state.bg_attr_hi_shift_reg <<= 1;
         }
-        state!(and v, mb, 0xFFFF & !PpuAddressPart::COARSE_Y.bits());
-        state!(or v, mb, new_y << 5);
     }
-}
 
-fn transfer_registers<T: WithPpu>(mb: &mut T) {
-    let ppu = mb.ppu_mut();
-    state!(set bg_tile_lo_shift_reg, mb,         (state!(get bg_tile_lo_shift_reg, mb) & 0xFF00) | (state!(get temp_bg_lo_byte, mb) as u16));
-    state!(set bg_tile_hi_shift_reg, mb,         (state!(get bg_tile_hi_shift_reg, mb) & 0xFF00) | (state!(get temp_bg_hi_byte, mb) as u16));
-    state!(set bg_attr_latch, mb, state!(get temp_at_byte, mb));
-    state!(and bg_attr_lo_shift_reg, mb, 0x0);
-    state!(or bg_attr_lo_shift_reg, mb, 0xFF * (state!(get bg_attr_latch, mb) & 0x01));
-    state!(and bg_attr_hi_shift_reg, mb, 0x0);
-    state!(or bg_attr_hi_shift_reg, mb, 0xFF * ((state!(get bg_attr_latch, mb) & 0x02) >> 1));
-}
+    /**
+     * Count down the sprite X counters, and shift the sprites that are active
+     *
+     * This runs before the pixel for this dot is drawn, so it catches up on the
+     * pixel from the last dot. That way a sprite at X shows up starting at X,
+     * and every one of its columns gets drawn.
+     */
+    fn update_sprite_shift_regs(&mut self) {
+        let state = &mut self.state;
+        if (state.mask & PpuMaskFlags::SPRITE_ENABLE.bits() > 0)
+            && (2..258).contains(&state.pixel_cycle)
+        {
+            for i in 0..8 {
+                if state.sprite_x_counters[i] > 0 {
+                    state.sprite_x_counters[i] -= 1;
+                } else {
+                    state.sprite_tile_hi_shift_regs[i] <<= 1;
+                    state.sprite_tile_lo_shift_regs[i] <<= 1;
+                }
+            }
+        }
+    }
 
-fn update_shift_regs<T: WithPpu>(mb: &mut T) {
-    if state!(get mask, mb) & PpuMaskFlags::BG_ENABLE.bits() > 0 {
-        state!(set bg_tile_hi_shift_reg, mb, 0xFFFF & state!(get bg_tile_hi_shift_reg, mb) << 1);
-        state!(set bg_tile_lo_shift_reg, mb, 0xFFFF & state!(get bg_tile_lo_shift_reg, mb) << 1);
-        state!(set bg_attr_lo_shift_reg, mb, 0xFF & state!(get bg_attr_lo_shift_reg, mb) << 1);
-        state!(set bg_attr_hi_shift_reg, mb, 0xFF & state!(get bg_attr_hi_shift_reg, mb) << 1);
-    }
-    if (state!(get mask, mb) & PpuMaskFlags::SPRITE_ENABLE.bits() > 0)
-        && state!(get pixel_cycle, mb) >= 1
-        && state!(get pixel_cycle, mb) < 258
-    {
-        for i in 0..8 {
-            let idx = i * 4 + PpuOamByteOffsets::X_POS.bits() as usize;
-            if state!(get secondary_oam, mb)[idx] > 0 {
-                state!(set_arr secondary_oam, idx, mb, state!(get secondary_oam, mb)[idx].wrapping_sub(1));
-            } else {
-                state!(shl_arr sprite_tile_hi_shift_regs, i, mb, 1);
-                state!(shl_arr sprite_tile_lo_shift_regs, i, mb, 1);
+    /** Run one dot of the background pipeline: shift, then fetch on the 8-dot schedule */
+    fn bg_pipeline_step(&mut self, cart: &mut dyn ICartridge, dot: u16) {
+        self.update_bg_shift_regs();
+        let v = self.state.v;
+        match (dot - 1) % 8 {
+            0 => {
+                self.transfer_registers();
+                self.state.temp_nt_byte = self.fetch_nametable_byte(cart, v);
+            }
+            2 => {
+                self.state.temp_at_byte = self.fetch_attribute_bits(cart, v);
             }
+            4 => {
+                self.state.temp_bg_lo_byte =
+                    self.fetch_pattern_byte(cart, v, self.state.temp_nt_byte, 0);
+            }
+            6 => {
+                self.state.temp_bg_hi_byte =
+                    self.fetch_pattern_byte(cart, v, self.state.temp_nt_byte, 8);
+            }
+            7 => {
+                self.inc_coarse_x();
+            }
+            _ => {
+                // no-op- we're waiting on a read or doing something else
+            }
+        }
+    }
+
+    /** Fetch the tile index that `v` points to */
+    fn fetch_nametable_byte(&mut self, cart: &mut dyn ICartridge, v: u16) -> u8 {
+        self.read(cart, PPU_NAMETABLE_START_ADDR | (v & 0x0FFF))
+    }
+
+    /** Fetch the 2-bit palette index of the tile that `v` points to */
+    fn fetch_attribute_bits(&mut self, cart: &mut dyn ICartridge, v: u16) -> u8 {
+        // this addressing comes from NESDEV:
+        // https://wiki.nesdev.com/w/index.php/PPU_scrolling#Tile_and_attribute_fetching
+        let at_byte = self.read(
+            cart,
+            PPU_NAMETABLE_START_ADDR
+                | ATTR_TABLE_OFFSET
+                | (v & 0x0C00)
+                | ((v >> 4) & 0x38)
+                | ((v >> 2) & 0x07),
+        );
+        attribute_bits(v, at_byte)
+    }
+
+    /** Fetch one bitplane (0 for low, 8 for high) of a background tile row */
+    fn fetch_pattern_byte(
+        &mut self,
+        cart: &mut dyn ICartridge,
+        v: u16,
+        tile: u8,
+        plane: u16,
+    ) -> u8 {
+        let chr_bank = ((self.state.control & PpuControlFlags::BG_TILE_SELECT.bits()) as u16) << 8;
+        self.read(
+            cart,
+            chr_bank | ((tile as u16) << 4) | ((v & PpuAddressPart::FINE_Y.bits()) >> 12) | plane,
+        )
+    }
+
+    /** Fetch and decode the background of a whole scanline at once.
+     *
+     * This runs on dot 1 of a visible scanline, and if the line can be batched
+     * it fills `bg_line_cache` and works out what the pipeline will look like
+     * after dot 256. The per-dot pipeline is then skipped for dots 1-256, unless
+     * a register write lands mid-line and `flush_scanline_cache` is called.
+     */
+    fn cache_scanline(&mut self, cart: &mut dyn ICartridge) {
+        if !self.batch_rendering
+            || cart.watches_ppu_a12()
+            || self.state.mask & PpuMaskFlags::BG_ENABLE.bits() == 0
+        {
+            return;
+        }
+        let start = BgPipelineSnapshot::take(&self.state);
+        // Each tile is (pattern lo, pattern hi, attribute lo, attribute hi), with
+        // the attribute bits spread across the byte like the shift registers
+        // hold them. Tile 0 is already in the shift registers and tile 1 is in
+        // the fetch latches, both prefetched at the end of the last scanline.
+        let mut tiles = [(0u8, 0u8, 0u8, 0u8); 34];
+        tiles[0] = (
+            (start.bg_tile_lo_shift_reg << 1 >> 8) as u8,
+            (start.bg_tile_hi_shift_reg << 1 >> 8) as u8,
+            (start.bg_attr_lo_shift_reg << 1 >> 8) as u8,
+            (start.bg_attr_hi_shift_reg << 1 >> 8) as u8,
+        );
+        tiles[1] = (
+            start.temp_bg_lo_byte,
+            start.temp_bg_hi_byte,
+            0xFF * (start.temp_at_byte & 0x01),
+            0xFF * ((start.temp_at_byte & 0x02) >> 1),
+        );
+        let mut v = start.v;
+        let mut last_fetch = (0u8, 0u8, 0u8, 0u8);
+        for tile in tiles.iter_mut().skip(2) {
+            let nt = self.fetch_nametable_byte(cart, v);
+            let at = self.fetch_attribute_bits(cart, v);
+            let lo = self.fetch_pattern_byte(cart, v, nt, 0);
+            let hi = self.fetch_pattern_byte(cart, v, nt, 8);
+            *tile = (lo, hi, 0xFF * (at & 0x01), 0xFF * ((at & 0x02) >> 1));
+            last_fetch = (nt, at, lo, hi);
+            v = coarse_x_increment(v);
+        }
+
+        let state = &mut self.state;
+        let fine_x = state.x as usize;
+        for (x, pixel) in state.bg_line_cache.iter_mut().enumerate() {
+            let (lo, hi, attr_lo, attr_hi) = tiles[(x + fine_x) / 8];
+            let bit = 7 - ((x + fine_x) % 8);
+            *pixel = (((attr_hi >> bit) & 1) << 3)
+                | (((attr_lo >> bit) & 1) << 2)
+                | (((hi >> bit) & 1) << 1)
+                | ((lo >> bit) & 1);
+        }
+
+        // Tiles 31 and 32 were the last to be transferred into the shift
+        // registers (on dot 249), which have shifted 7 more times since. Tile 33
+        // is left in the fetch latches.
+        let (nt, at, lo, hi) = last_fetch;
+        let shifted = |left: u8, right: u8| (((left as u16) << 8) | (right as u16)) << 7;
+        state.bg_line_end = BgPipelineSnapshot {
+            v,
+            bg_tile_lo_shift_reg: shifted(tiles[31].0, tiles[32].0),
+            bg_tile_hi_shift_reg: shifted(tiles[31].1, tiles[32].1),
+            bg_attr_lo_shift_reg: shifted(tiles[31].2, tiles[32].2),
+            bg_attr_hi_shift_reg: shifted(tiles[31].3, tiles[32].3),
+            bg_attr_latch: (tiles[32].2 & 0x01) | (tiles[32].3 & 0x02),
+            temp_nt_byte: nt,
+            temp_at_byte: at,
+            temp_bg_lo_byte: lo,
+            temp_bg_hi_byte: hi,
+        };
+        state.bg_line_start = start;
+        state.bg_line_cached = true;
+    }
+
+    /** Bring the background pipeline to where it would be after dot 256 */
+    fn commit_scanline_cache(&mut self) {
+        let end = self.state.bg_line_end;
+        end.restore(&mut self.state);
+        self.state.bg_line_cached = false;
+    }
+
+    /** Fall back to the per-dot background pipeline for the rest of the scanline.
+     *
+     * The pipeline is rewound to dot 1 and the dots that were already drawn from
+     * the cache are replayed, so that whatever register access caused the
+     * fallback sees the same state it would have without the batch renderer.
+     */
+    fn flush_scanline_cache(&mut self, cart: &mut dyn ICartridge) {
+        if !self.state.bg_line_cached {
+            return;
+        }
+        let start = self.state.bg_line_start;
+        start.restore(&mut self.state);
+        self.state.bg_line_cached = false;
+        for dot in 1..self.state.pixel_cycle {
+            self.bg_pipeline_step(cart, dot);
+        }
+    }
+
+    fn transfer_x_addr(&mut self) {
+        if !self.is_rendering_enabled() {
+            return;
         }
+        let x_addr_part = (PpuAddressPart::COARSE_X | PpuAddressPart::NAMETABLE_X).bits();
+        self.state.v &= !x_addr_part;
+        self.state.v |= self.state.t & x_addr_part;
+    }
+
+    fn transfer_y_addr(&mut self) {
+        if !self.is_rendering_enabled() {
+            return;
+        }
+        let y_addr_part =
+            (PpuAddressPart::FINE_Y | PpuAddressPart::NAMETABLE_Y | PpuAddressPart::COARSE_Y)
+                .bits();
+        self.state.v &= !y_addr_part;
+        self.state.v |= self.state.t & y_addr_part;
+    }
+}
+
+/**
+ * The row of a sprite at OAM Y position `y` that's on this scanline, or None
+ * if the sprite isn't on it
+ *
+ * Rows are counted down from the top of the sprite, and go up to 7, or 15 in
+ * 8x16 sprite mode. Since sprites are drawn a line late, this is the row
+ * that ends up on the next scanline.
+ */
+fn sprite_row(state: &PpuState, y: u8) -> Option<u16> {
+    let sprite_height = if state.control & PpuControlFlags::SPRITE_MODE_SELECT.bits() > 0 {
+        16
+    } else {
+        8
+    };
+    let row = state.scanline - y as i16;
+    if (0..sprite_height).contains(&row) {
+        Some(row as u16)
+    } else {
+        None
     }
 }
 
-fn transfer_x_addr<T: WithPpu>(mb: &mut T) {
-    let ppu = mb.ppu_mut();
-    if (state!(get mask, mb) & (PpuMaskFlags::BG_ENABLE | PpuMaskFlags::SPRITE_ENABLE).bits()) == 0
-    {
-        return;
+/** Return the VRAM address with coarse X moved to the next tile */
+fn coarse_x_increment(v: u16) -> u16 {
+    if (v & PpuAddressPart::COARSE_X.bits()) == 31 {
+        // clear the coarse X and invert the X nametable
+        (v & !PpuAddressPart::COARSE_X.bits()) ^ PpuAddressPart::NAMETABLE_X.bits()
+    } else {
+        // increment coarse X directly
+        v + 1
     }
-    let X_ADDR_PART = PpuAddressPart::COARSE_X | PpuAddressPart::NAMETABLE_X;
-    state!(and v, mb, 0xFFFF & !X_ADDR_PART.bits());
-    state!(or v, mb, state!(get t, mb) & X_ADDR_PART.bits());
 }
 
-fn transfer_y_addr<T: WithPpu>(mb: &mut T) {
-    let ppu = mb.ppu_mut();
-    if (state!(get mask, mb) & (PpuMaskFlags::BG_ENABLE | PpuMaskFlags::SPRITE_ENABLE).bits()) == 0
-    {
-        return;
+/**
+ * Pick the 2-bit palette index for the tile `v` points to out of its attribute
+ * byte
+ *
+ * Each attribute byte covers a 4x4 block of tiles, split into 2x2 quadrants
+ * packed as (bottom right, bottom left, top right, top left) from the high bits
+ * down. Bit 1 of coarse Y picks the bottom half and bit 1 of coarse X picks the
+ * right half, so the quadrant is just those two bits.
+ *
+ * cf. https://wiki.nesdev.com/w/index.php/PPU_attribute_tables
+ */
+fn attribute_bits(v: u16, at_byte: u8) -> u8 {
+    let mut at_byte = at_byte;
+    if (((v & PpuAddressPart::COARSE_Y.bits()) >> 5) & 0x02) > 0 {
+        at_byte >>= 4;
     }
-    let Y_ADDR_PART =
-        PpuAddressPart::FINE_Y | PpuAddressPart::NAMETABLE_Y | PpuAddressPart::COARSE_Y;
-    state!(and v, mb, 0xFFFF & !Y_ADDR_PART.bits());
-    state!(or v, mb, state!(get t, mb) & Y_ADDR_PART.bits());
+    if ((v & PpuAddressPart::COARSE_X.bits()) & 0x02) > 0 {
+        at_byte >>= 2;
+    }
+    at_byte & 3
 }
 
+/**
+ * The contents of palette RAM at power-on
+ *
+ * Palette RAM isn't cleared at power-on, but in practice it comes up with
+ * this pattern (as read back by blargg's power_up_palette test). Note that
+ * the sprite entries at $3F10/$14/$18/$1C agree with the background entries
+ * they mirror.
+ *
+ * cf. https://wiki.nesdev.com/w/index.php/PPU_power_up_state
+ */
+const PALETTE_POWERON_STATE: [u8; 32] = [
+    0x09, 0x01, 0x00, 0x01, 0x00, 0x02, 0x02, 0x0D, 0x08, 0x10, 0x08, 0x24, 0x00, 0x00, 0x04, 0x2C,
+    0x09, 0x01, 0x34, 0x03, 0x00, 0x04, 0x00, 0x14, 0x08, 0x3A, 0x00, 0x02, 0x00, 0x20, 0x2C, 0x08,
+];
+
 /**
  * A helper for handling some of the odd PPU palette mirrors
+ *
+ * The first entry of each sprite palette ($3F10/$14/$18/$1C) is a mirror of
+ * the first entry of the matching background palette ($3F00/$04/$08/$0C).
+ * Only $3F00 is ever drawn as a backdrop, but the other background entries
+ * are still ordinary, readable and writable memory.
  */
 struct PpuPaletteRam {
     palette_buffer: [u8; 32],
@@ -673,9 +1544,29 @@ struct PpuPaletteRam {
 impl PpuPaletteRam {
     fn new() -> PpuPaletteRam {
         PpuPaletteRam {
-            palette_buffer: [0u8; 32],
+            palette_buffer: PALETTE_POWERON_STATE,
+        }
+    }
+
+    /** Get the address that a palette address mirrors, if it's a mirror */
+    fn mirror_of(addr: u16) -> u16 {
+        match addr {
+            0x10 | 0x14 | 0x18 | 0x1C => addr & 0x0F,
+            _ => addr,
         }
     }
+
+    /** The color a pixel of `palette` is drawn in, where pixel 0 is the
+     * backdrop, without going through the PPU bus
+     */
+    fn color_of(&self, palette: u8, pixel: u8) -> u8 {
+        let addr = if pixel == 0 {
+            0
+        } else {
+            ((palette as u16) << 2) | (pixel as u16)
+        };
+        self.palette_buffer[PpuPaletteRam::mirror_of(addr) as usize]
+    }
 }
 
 impl BusDevice for PpuPaletteRam {
@@ -683,25 +1574,1120 @@ impl BusDevice for PpuPaletteRam {
         self.peek(addr).unwrap(last_bus_value)
     }
     fn peek(&self, addr: u16) -> BusPeekResult {
-        let read_addr = match addr {
-            0x10 => 0x00,
-            0x14 => 0x04,
-            0x18 => 0x08,
-            0x1C => 0x0C,
-            _ => addr,
-        };
+        let read_addr = PpuPaletteRam::mirror_of(addr);
         return BusPeekResult::Result(self.palette_buffer[read_addr as usize]);
     }
 
     fn write(&mut self, addr: u16, data: u8) {
-        // these sprite palette locations are actually mirrors into the bg colors
-        let read_addr = match addr {
-            0x10 => 0x00,
-            0x14 => 0x04,
-            0x18 => 0x08,
-            0x1C => 0x0C,
-            _ => addr,
+        // these sprite palette locations are actually mirrors into the bg
+        // colors, but keep both copies up to date so debug dumps agree
+        let write_addr = PpuPaletteRam::mirror_of(addr);
+        self.palette_buffer[write_addr as usize] = data;
+        if write_addr & 0x03 == 0 {
+            self.palette_buffer[(write_addr | 0x10) as usize] = data;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::scene::TestBus;
+    use super::*;
+    use crate::devices::cartridge::{from_rom, CartridgeState, PrgRegion};
+
+    /// Build an NROM test setup with busy CHR, nametable, and palette data
+    fn make_bus(batch_rendering: bool) -> TestBus {
+        make_bus_with_mapper(0, 1, batch_rendering)
+    }
+
+    /// Build a test setup like `make_bus`, on another board with `chr_banks`
+    /// 8k CHR banks
+    fn make_bus_with_mapper(mapper: u8, chr_banks: u8, batch_rendering: bool) -> TestBus {
+        let mut rom = vec![0x4E, 0x45, 0x53, 0x1A, 1, chr_banks, mapper << 4];
+        rom.extend(vec![0u8; 9]);
+        // all 1s, so that bank switches don't lose any bits to bus conflicts
+        rom.extend(vec![0xFFu8; 0x4000]);
+        // with a different pattern in each bank
+        let chr = (0..0x2000 * chr_banks as usize)
+            .map(|i: usize| (i.wrapping_mul(37) ^ (i >> 4) ^ (i >> 13).wrapping_mul(0x5A)) as u8);
+        rom.extend(chr);
+        let mut bus = TestBus {
+            ppu: Ppu2C02::new(),
+            cart: from_rom(&rom).unwrap(),
+        };
+        bus.ppu.set_batch_rendering(batch_rendering);
+        for addr in 0x2000u16..0x2800 {
+            bus.ppu
+                .write(&mut *bus.cart, addr, (addr.wrapping_mul(7) >> 1) as u8);
+        }
+        for addr in 0x3F00u16..0x3F20 {
+            bus.ppu
+                .write(&mut *bus.cart, addr, (addr as u8).wrapping_mul(3) & 0x3F);
+        }
+        // park every sprite offscreen
+        for addr in 0..=255u8 {
+            bus.ppu.write_oam(addr, 0xFF);
+        }
+        control_port_write(&mut bus, 0x0001, PpuMaskFlags::BG_ENABLE.bits());
+        control_port_write(&mut bus, 0x0005, 0x13);
+        control_port_write(&mut bus, 0x0005, 0x2A);
+        bus
+    }
+
+    /// Clock the PPU through one frame, calling `on_dot` before each dot
+    fn run_frame<F: FnMut(&mut TestBus)>(bus: &mut TestBus, mut on_dot: F) {
+        loop {
+            on_dot(bus);
+            clock(bus);
+            if bus.ppu.is_frame_ready() {
+                return;
+            }
+        }
+    }
+
+    #[test]
+    fn attribute_bits_pick_the_quadrant_for_every_tile() {
+        // top left, top right, bottom left, bottom right = 0, 1, 2, 3
+        let at_byte = 0b11_10_01_00;
+        for coarse_y in 0..32u16 {
+            for coarse_x in 0..32u16 {
+                let quadrant = (((coarse_y >> 1) & 1) << 1 | ((coarse_x >> 1) & 1)) as u8;
+                // the nametable and fine Y bits shouldn't matter
+                for high_bits in &[0x0000u16, 0x0C00, 0x7000, 0x7C00] {
+                    let v = high_bits | (coarse_y << 5) | coarse_x;
+                    assert_eq!(
+                        attribute_bits(v, at_byte),
+                        quadrant,
+                        "Wrong quadrant for tile ({}, {}) with v={:04X}",
+                        coarse_x,
+                        coarse_y,
+                        v
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn attribute_bits_extract_every_palette_from_every_quadrant() {
+        // one tile from each quadrant: (0, 0), (2, 0), (0, 2), (2, 2)
+        let tiles = [0x0000u16, 0x0002, 0x0040, 0x0042];
+        for at_byte in 0..=255u8 {
+            for (quadrant, &v) in tiles.iter().enumerate() {
+                assert_eq!(
+                    attribute_bits(v, at_byte),
+                    (at_byte >> (quadrant * 2)) & 0x03,
+                    "Wrong palette for quadrant {} of {:02X}",
+                    quadrant,
+                    at_byte
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn batch_renderer_matches_per_dot_pipeline() {
+        let mut accurate = make_bus(false);
+        let mut batched = make_bus(true);
+        let mut used_cache = false;
+        for _ in 0..2 {
+            run_frame(&mut accurate, |_| {});
+            run_frame(&mut batched, |bus| {
+                used_cache |= bus.ppu.state.bg_line_cached;
+            });
+        }
+        assert!(used_cache, "Batch renderer was never used");
+        assert_eq!(
+            accurate.ppu.state.v, batched.ppu.state.v,
+            "VRAM address mismatch"
+        );
+        assert!(
+            accurate.ppu.get_buffer() == batched.ppu.get_buffer(),
+            "Frame mismatch"
+        );
+    }
+
+    #[test]
+    fn indexed_frames_match_the_rgb_frames() {
+        let mut bus = make_bus(true);
+        bus.ppu.set_indexed_output(true);
+        // emphasize red, from the middle of the frame
+        run_frame(&mut bus, |bus| {
+            if bus.ppu.state.scanline == 120 && bus.ppu.state.pixel_cycle == 0 {
+                let mask = bus.ppu.state.mask | PpuMaskFlags::COLOR_EMPHASIS_RED.bits();
+                control_port_write(bus, 0x0001, mask);
+            }
+        });
+        let frame = bus.ppu.indexed_frame().expect("Indexed output is on");
+        assert_eq!(frame.emphasis()[119], 0x00);
+        assert_eq!(frame.emphasis()[120], 0x01);
+        assert_eq!(frame.palette_ram(), bus.ppu.dump_palettes());
+        assert!(
+            frame.to_rgb(bus.ppu.output_palette()) == bus.ppu.get_buffer(),
+            "Frame mismatch"
+        );
+        bus.ppu.set_indexed_output(false);
+        assert!(bus.ppu.indexed_frame().is_none());
+    }
+
+    /// A cartridge that says it watches PPU A12, and otherwise passes
+    /// everything through
+    struct A12Watcher(Box<dyn ICartridge>);
+
+    impl ICartridge for A12Watcher {
+        fn read_chr(&mut self, addr: u16, last_bus_value: u8) -> u8 {
+            self.0.read_chr(addr, last_bus_value)
+        }
+        fn peek_chr(&self, addr: u16) -> BusPeekResult {
+            self.0.peek_chr(addr)
+        }
+        fn write_chr(&mut self, addr: u16, value: u8) {
+            self.0.write_chr(addr, value)
+        }
+        fn read_prg(&mut self, region: PrgRegion, last_bus_value: u8) -> u8 {
+            self.0.read_prg(region, last_bus_value)
+        }
+        fn peek_prg(&self, region: PrgRegion) -> BusPeekResult {
+            self.0.peek_prg(region)
+        }
+        fn write_prg(&mut self, region: PrgRegion, value: u8) {
+            self.0.write_prg(region, value)
+        }
+        fn dump_chr(&self) -> &[u8] {
+            self.0.dump_chr()
+        }
+        fn dump_nametables(&self) -> &[u8] {
+            self.0.dump_nametables()
+        }
+        fn debug_state(&self) -> CartridgeState {
+            self.0.debug_state()
+        }
+        fn watches_ppu_a12(&self) -> bool {
+            true
+        }
+    }
+
+    #[test]
+    fn batch_renderer_stays_off_for_boards_watching_a12() {
+        let mut accurate = make_bus(false);
+        let mut watched = make_bus(true);
+        watched.cart = Box::new(A12Watcher(watched.cart));
+        for _ in 0..2 {
+            run_frame(&mut accurate, |_| {});
+            run_frame(&mut watched, |bus| {
+                assert!(!bus.ppu.state.bg_line_cached, "Batch renderer was used");
+            });
+        }
+        assert!(
+            accurate.ppu.get_buffer() == watched.ppu.get_buffer(),
+            "Frame mismatch"
+        );
+    }
+
+    #[test]
+    fn batch_renderer_falls_back_on_mid_line_writes() {
+        let mut accurate = make_bus(false);
+        let mut batched = make_bus(true);
+        run_frame(&mut accurate, |_| {});
+        run_frame(&mut batched, |_| {});
+        let split = |bus: &mut TestBus| {
+            if bus.ppu.state.scanline == 100 && bus.ppu.state.pixel_cycle == 131 {
+                control_port_write(bus, 0x0000, PpuControlFlags::BG_TILE_SELECT.bits());
+            }
+            if bus.ppu.state.scanline == 150 && bus.ppu.state.pixel_cycle == 77 {
+                control_port_write(bus, 0x0005, 0x45);
+                control_port_write(bus, 0x0005, 0x10);
+            }
+        };
+        run_frame(&mut accurate, split);
+        run_frame(&mut batched, |bus| {
+            split(bus);
+            if bus.ppu.state.scanline == 150 && bus.ppu.state.pixel_cycle == 77 {
+                assert!(!bus.ppu.state.bg_line_cached, "Cache was not flushed");
+            }
+        });
+        assert_eq!(
+            accurate.ppu.state.v, batched.ppu.state.v,
+            "VRAM address mismatch"
+        );
+        assert!(
+            accurate.ppu.get_buffer() == batched.ppu.get_buffer(),
+            "Frame mismatch"
+        );
+    }
+
+    #[test]
+    fn batch_renderer_falls_back_on_mid_line_bank_switches() {
+        // Color Dreams, which switches all of CHR with one register
+        let mut accurate = make_bus_with_mapper(11, 2, false);
+        let mut batched = make_bus_with_mapper(11, 2, true);
+        let mut unswitched = make_bus_with_mapper(11, 2, false);
+        run_frame(&mut accurate, |_| {});
+        run_frame(&mut batched, |_| {});
+        run_frame(&mut unswitched, |_| {});
+        // this is the order `Nes` does it in for writes to the cartridge
+        let switch = |bus: &mut TestBus| {
+            if bus.ppu.state.pixel_cycle == 131 && (100..140).contains(&bus.ppu.state.scanline) {
+                let bank = (bus.ppu.state.scanline as u8 & 1) << 4;
+                flush_batch_renderer(bus);
+                bus.cart.write_prg(PrgRegion::Rom(0), bank);
+            }
+        };
+        run_frame(&mut accurate, switch);
+        run_frame(&mut batched, |bus| {
+            switch(bus);
+            if bus.ppu.state.scanline == 100 && bus.ppu.state.pixel_cycle == 131 {
+                assert!(!bus.ppu.state.bg_line_cached, "Cache was not flushed");
+            }
+        });
+        run_frame(&mut unswitched, |_| {});
+        assert!(
+            accurate.ppu.get_buffer() != unswitched.ppu.get_buffer(),
+            "Bank switches didn't change the frame"
+        );
+        assert!(
+            accurate.ppu.get_buffer() == batched.ppu.get_buffer(),
+            "Frame mismatch"
+        );
+    }
+
+    #[test]
+    fn layer_mask_hides_the_background() {
+        let mut shown = make_bus(false);
+        let mut hidden = make_bus(false);
+        hidden.ppu.set_layer_mask(LayerMask::SPRITES);
+        for _ in 0..2 {
+            run_frame(&mut shown, |_| {});
+            run_frame(&mut hidden, |_| {});
+        }
+        let backdrop = hidden.ppu.palette.palette_buffer[0];
+        let rgb = hidden.ppu.output_palette().rgb(0, backdrop).to_vec();
+        let is_backdrop = |bus: &TestBus| {
+            bus.ppu
+                .get_buffer()
+                .chunks_exact(3)
+                .all(|pixel| pixel == &rgb[..])
+        };
+        assert!(!is_backdrop(&shown));
+        assert!(is_backdrop(&hidden));
+    }
+
+    #[test]
+    fn ignores_control_writes_after_reset_until_vblank_ends() {
+        let mut bus = make_bus(false);
+        bus.ppu.reset();
+        assert_eq!(bus.ppu.state.mask, 0, "PPUMASK was not cleared");
+        control_port_write(&mut bus, 0x0000, PpuControlFlags::BG_TILE_SELECT.bits());
+        assert_eq!(bus.ppu.state.control, 0, "PPUCTRL write was not ignored");
+        run_frame(&mut bus, |_| {});
+        control_port_write(&mut bus, 0x0000, PpuControlFlags::BG_TILE_SELECT.bits());
+        assert_eq!(
+            bus.ppu.state.control,
+            PpuControlFlags::BG_TILE_SELECT.bits(),
+            "PPUCTRL write was ignored after vblank"
+        );
+    }
+
+    #[test]
+    fn ignores_control_writes_while_warming_up() {
+        let mut bus = make_bus(false);
+        bus.ppu.set_warming_up(true);
+        control_port_write(&mut bus, 0x0001, 0);
+        assert_eq!(
+            bus.ppu.state.mask,
+            PpuMaskFlags::BG_ENABLE.bits(),
+            "PPUMASK write was not ignored"
+        );
+        bus.ppu.set_warming_up(false);
+        control_port_write(&mut bus, 0x0001, 0);
+        assert_eq!(
+            bus.ppu.state.mask, 0,
+            "PPUMASK write was ignored after warm-up"
+        );
+    }
+
+    #[test]
+    fn palette_powers_on_with_known_values() {
+        let mut palette = PpuPaletteRam::new();
+        assert_eq!(palette.read(0x00, 0), 0x09);
+        assert_eq!(palette.read(0x0B, 0), 0x24);
+        assert_eq!(palette.read(0x12, 0), 0x34);
+        assert_eq!(palette.read(0x1F, 0), 0x08);
+    }
+
+    #[test]
+    fn sprite_backdrop_entries_mirror_the_background() {
+        for &(sprite, bg) in &[(0x10, 0x00), (0x14, 0x04), (0x18, 0x08), (0x1C, 0x0C)] {
+            let mut palette = PpuPaletteRam::new();
+            palette.write(sprite, 0x2A);
+            assert_eq!(palette.read(bg, 0), 0x2A, "${:02X} -> ${:02X}", sprite, bg);
+            palette.write(bg, 0x15);
+            assert_eq!(
+                palette.read(sprite, 0),
+                0x15,
+                "${:02X} -> ${:02X}",
+                bg,
+                sprite
+            );
+            assert_eq!(palette.palette_buffer[sprite as usize], 0x15);
+        }
+    }
+
+    #[test]
+    fn unused_background_entries_are_plain_memory() {
+        let mut palette = PpuPaletteRam::new();
+        palette.write(0x00, 0x0F);
+        palette.write(0x04, 0x11);
+        palette.write(0x08, 0x22);
+        palette.write(0x0C, 0x33);
+        assert_eq!(palette.read(0x00, 0), 0x0F);
+        assert_eq!(palette.read(0x04, 0), 0x11);
+        assert_eq!(palette.read(0x08, 0), 0x22);
+        assert_eq!(palette.read(0x0C, 0), 0x33);
+        // the other sprite entries aren't mirrors
+        palette.write(0x11, 0x3F);
+        assert_eq!(palette.read(0x01, 0), 0x01, "Expected power-on value");
+    }
+
+    /// Clock the PPU until it's about to run `dot` of `scanline`
+    fn run_to(bus: &mut TestBus, scanline: i16, dot: u16) {
+        while bus.ppu.state.scanline != scanline || bus.ppu.state.pixel_cycle != dot {
+            clock(bus);
+        }
+    }
+
+    /// Put sprite `n` at `y`, leaving the rest of it where it was
+    fn place_sprite(bus: &mut TestBus, n: u8, y: u8) {
+        bus.ppu.write_oam(n * 4, y);
+    }
+
+    #[test]
+    fn evaluates_sprites_into_secondary_oam() {
+        let mut bus = make_bus(false);
+        place_sprite(&mut bus, 2, 10);
+        place_sprite(&mut bus, 5, 6);
+        bus.ppu.write_oam(5 * 4 + 3, 0x42);
+        run_to(&mut bus, 10, 257);
+        assert_eq!(bus.ppu.state.secondary_oam_addr, 8);
+        assert_eq!(bus.ppu.state.secondary_oam[0..4], [10, 0xFF, 0xFF, 0xFF]);
+        assert_eq!(bus.ppu.state.secondary_oam[4..8], [6, 0xFF, 0xFF, 0x42]);
+        assert!(bus.ppu.state.secondary_oam[8..32]
+            .iter()
+            .all(|&b| b == 0xFF));
+        assert!(!bus.ppu.state.sprite_zero_in_range);
+        assert_eq!(bus.ppu.state.oam_addr, 0, "OAMADDR was not reset");
+    }
+
+    #[test]
+    fn starts_evaluation_at_oamaddr() {
+        let mut bus = make_bus(false);
+        place_sprite(&mut bus, 0, 10);
+        place_sprite(&mut bus, 1, 10);
+        place_sprite(&mut bus, 2, 10);
+        run_to(&mut bus, 10, 0);
+        control_port_write(&mut bus, 0x0003, 0x08);
+        run_to(&mut bus, 10, 257);
+        // sprites 0 and 1 were skipped, and sprite 2 stands in for sprite 0
+        assert_eq!(bus.ppu.state.secondary_oam_addr, 4);
+        assert!(bus.ppu.state.sprite_zero_in_range);
+        // the PPU resets OAMADDR itself, so the next line sees sprite 0 again
+        run_to(&mut bus, 11, 257);
+        assert_eq!(bus.ppu.state.secondary_oam_addr, 12);
+    }
+
+    /// Run sprite evaluation for line 10 from a misaligned OAMADDR
+    fn evaluate_from_oamaddr_5(accuracy: AccuracyMode) -> TestBus {
+        let mut bus = make_bus(false);
+        bus.ppu.set_accuracy(accuracy);
+        // bytes 5 and 9 are in range when read as Y positions
+        for (addr, value) in [(5, 10), (6, 0x11), (7, 0x22), (8, 0x33), (9, 10)] {
+            bus.ppu.write_oam(addr, value);
+        }
+        run_to(&mut bus, 10, 0);
+        control_port_write(&mut bus, 0x0003, 0x05);
+        run_to(&mut bus, 10, 257);
+        bus
+    }
+
+    #[test]
+    fn starts_evaluation_mid_sprite_in_strict_mode() {
+        let bus = evaluate_from_oamaddr_5(AccuracyMode::Strict);
+        assert_eq!(bus.ppu.state.secondary_oam_addr, 8);
+        assert_eq!(bus.ppu.state.secondary_oam[0..4], [10, 0x11, 0x22, 0x33]);
+        assert_eq!(bus.ppu.state.secondary_oam[4..8], [10, 0xFF, 0xFF, 0xFF]);
+    }
+
+    #[test]
+    fn aligns_oamaddr_outside_strict_mode() {
+        let bus = evaluate_from_oamaddr_5(AccuracyMode::Balanced);
+        // evaluation starts at sprite 1, whose Y is 0xFF
+        assert_eq!(bus.ppu.state.secondary_oam_addr, 0);
+    }
+
+    #[test]
+    fn copies_oamaddr_row_when_rendering_starts() {
+        let mut bus = make_bus(false);
+        bus.ppu.set_accuracy(AccuracyMode::Strict);
+        for addr in 0x20..0x28 {
+            bus.ppu.write_oam(addr, addr);
+        }
+        run_to(&mut bus, 250, 0);
+        control_port_write(&mut bus, 0x0003, 0x23);
+        run_to(&mut bus, 261, 1);
+        assert_eq!(
+            bus.ppu.state.oam[0..8],
+            [0x20, 0x21, 0x22, 0x23, 0x24, 0x25, 0x26, 0x27]
+        );
+    }
+
+    #[test]
+    fn corrupts_oam_when_rendering_stops_mid_line() {
+        let mut bus = make_bus(false);
+        bus.ppu.set_accuracy(AccuracyMode::Strict);
+        for addr in 0..8 {
+            bus.ppu.write_oam(addr, addr + 1);
+        }
+        let enable = PpuMaskFlags::BG_ENABLE.bits();
+        // stopping on dot 10 leaves row 5 selected, and dot 270 row 7
+        run_to(&mut bus, 20, 10);
+        control_port_write(&mut bus, 0x0001, 0);
+        run_to(&mut bus, 20, 100);
+        control_port_write(&mut bus, 0x0001, enable);
+        run_to(&mut bus, 21, 270);
+        control_port_write(&mut bus, 0x0001, 0);
+        assert_eq!(bus.ppu.state.oam[40..48], [1, 2, 3, 4, 5, 6, 7, 8]);
+        assert_eq!(bus.ppu.state.oam[56..64], [0xFF; 8]);
+        run_to(&mut bus, 22, 0);
+        control_port_write(&mut bus, 0x0001, enable);
+        assert_eq!(bus.ppu.state.oam[56..64], [1, 2, 3, 4, 5, 6, 7, 8]);
+        assert_eq!(bus.ppu.state.oam[48..56], [0xFF; 8]);
+    }
+
+    #[test]
+    fn sets_sprite_overflow_on_a_ninth_sprite() {
+        let mut bus = make_bus(false);
+        bus.ppu.state.status = 0;
+        for n in 0..9 {
+            place_sprite(&mut bus, n, 30);
+        }
+        run_to(&mut bus, 29, 257);
+        assert_eq!(
+            bus.ppu.state.status & PpuStatusFlags::SPRITE_OVERFLOW.bits(),
+            0
+        );
+        run_to(&mut bus, 30, 257);
+        assert_eq!(bus.ppu.state.secondary_oam_addr, 32);
+        assert_ne!(
+            bus.ppu.state.status & PpuStatusFlags::SPRITE_OVERFLOW.bits(),
+            0
+        );
+    }
+
+    #[test]
+    fn parked_sprites_stay_off_the_pre_render_line() {
+        for accuracy in [AccuracyMode::Balanced, AccuracyMode::Fast] {
+            let mut bus = make_bus(false);
+            bus.ppu.set_accuracy(accuracy);
+            bus.ppu.state.status = 0;
+            // every sprite is at Y = $FF, which would be in range of line 261
+            run_to(&mut bus, 261, 300);
+            assert_eq!(bus.ppu.state.secondary_oam_addr, 0, "{:?}", accuracy);
+            assert_eq!(
+                bus.ppu.state.status & PpuStatusFlags::SPRITE_OVERFLOW.bits(),
+                0,
+                "{:?}",
+                accuracy
+            );
+        }
+    }
+
+    #[test]
+    fn emulates_the_sprite_overflow_bug() {
+        let mut bus = make_bus(false);
+        bus.ppu.state.status = 0;
+        for n in 0..8 {
+            place_sprite(&mut bus, n, 30);
+        }
+        place_sprite(&mut bus, 8, 0);
+        // after sprite 8, the PPU checks the tile index of sprite 9 as a Y
+        bus.ppu.write_oam(9 * 4 + 1, 30);
+        run_to(&mut bus, 30, 257);
+        assert_ne!(
+            bus.ppu.state.status & PpuStatusFlags::SPRITE_OVERFLOW.bits(),
+            0,
+            "False positive was missed"
+        );
+
+        let mut bus = make_bus(false);
+        bus.ppu.state.status = 0;
+        for n in 0..8 {
+            place_sprite(&mut bus, n, 30);
+        }
+        place_sprite(&mut bus, 8, 0);
+        place_sprite(&mut bus, 9, 30);
+        run_to(&mut bus, 30, 257);
+        assert_eq!(
+            bus.ppu.state.status & PpuStatusFlags::SPRITE_OVERFLOW.bits(),
+            0,
+            "False negative was missed"
+        );
+    }
+
+    #[test]
+    fn fast_evaluation_finds_the_same_sprites() {
+        let mut bus = make_bus(false);
+        bus.ppu.set_accuracy(AccuracyMode::Fast);
+        place_sprite(&mut bus, 0, 8);
+        place_sprite(&mut bus, 2, 10);
+        place_sprite(&mut bus, 5, 6);
+        bus.ppu.write_oam(5 * 4 + 3, 0x42);
+        run_to(&mut bus, 10, 257);
+        assert_eq!(bus.ppu.state.secondary_oam_addr, 12);
+        assert_eq!(bus.ppu.state.secondary_oam[4..8], [10, 0xFF, 0xFF, 0xFF]);
+        assert_eq!(bus.ppu.state.secondary_oam[8..12], [6, 0xFF, 0xFF, 0x42]);
+        assert!(bus.ppu.state.secondary_oam[12..32]
+            .iter()
+            .all(|&b| b == 0xFF));
+        assert!(bus.ppu.state.sprite_zero_in_range);
+    }
+
+    #[test]
+    fn fast_evaluation_skips_the_sprite_overflow_bug() {
+        let mut bus = make_bus(false);
+        bus.ppu.set_accuracy(AccuracyMode::Fast);
+        bus.ppu.state.status = 0;
+        for n in 0..8 {
+            place_sprite(&mut bus, n, 30);
+        }
+        place_sprite(&mut bus, 8, 0);
+        bus.ppu.write_oam(9 * 4 + 1, 30);
+        run_to(&mut bus, 30, 257);
+        assert_eq!(
+            bus.ppu.state.status & PpuStatusFlags::SPRITE_OVERFLOW.bits(),
+            0,
+            "Only 8 sprites are on this line"
+        );
+        place_sprite(&mut bus, 9, 31);
+        run_to(&mut bus, 31, 257);
+        assert_ne!(
+            bus.ppu.state.status & PpuStatusFlags::SPRITE_OVERFLOW.bits(),
+            0,
+            "The ninth sprite was missed"
+        );
+    }
+
+    /// Clock the PPU through `frames` whole frames
+    fn run_frames(bus: &mut TestBus, frames: u8) {
+        for _ in 0..frames {
+            clock(bus);
+            run_to(bus, 0, 0);
+        }
+    }
+
+    #[test]
+    fn open_bus_decays_in_strict_mode() {
+        let mut bus = make_bus(false);
+        bus.ppu.set_accuracy(AccuracyMode::Strict);
+        control_port_write(&mut bus, 0x0002, 0xFF);
+        run_frames(&mut bus, 20);
+        // a status read refreshes the top 3 bits, but not the rest
+        bus.ppu.state.status = 0xE0;
+        control_port_read(&mut bus, 0x0002);
+        assert_eq!(bus.ppu.state.last_control_port_value, 0xFF);
+        run_frames(&mut bus, 20);
+        assert_eq!(bus.ppu.state.last_control_port_value, 0xE0);
+        run_frames(&mut bus, 20);
+        assert_eq!(bus.ppu.state.last_control_port_value, 0x00);
+    }
+
+    #[test]
+    fn open_bus_holds_outside_strict_mode() {
+        let mut bus = make_bus(false);
+        control_port_write(&mut bus, 0x0002, 0xA5);
+        run_frames(&mut bus, OPEN_BUS_DECAY_FRAMES + 2);
+        assert_eq!(bus.ppu.state.last_control_port_value, 0xA5);
+    }
+
+    #[test]
+    fn ppustatus_writes_only_fill_the_latch() {
+        let mut bus = make_bus(false);
+        let status = bus.ppu.state.status;
+        control_port_write(&mut bus, 0x0002, 0x5A);
+        assert_eq!(bus.ppu.state.status, status);
+        assert_eq!(bus.ppu.state.last_control_port_value, 0x5A);
+    }
+
+    #[test]
+    fn oamdata_writes_increment_oamaddr() {
+        let mut bus = make_bus(false);
+        control_port_write(&mut bus, 0x0001, 0);
+        control_port_write(&mut bus, 0x0003, 0x10);
+        control_port_write(&mut bus, 0x0004, 0xAA);
+        control_port_write(&mut bus, 0x0004, 0xBB);
+        assert_eq!(&bus.ppu.state.oam[0x10..0x12], &[0xAA, 0xBB]);
+        assert_eq!(bus.ppu.state.oam_addr, 0x12);
+    }
+
+    #[test]
+    fn oamdata_writes_during_rendering_only_bump_the_sprite_index() {
+        let mut bus = make_bus(false);
+        for line in &[261, 10] {
+            run_to(&mut bus, *line, 100);
+            let oam = bus.ppu.state.oam;
+            bus.ppu.state.oam_addr = 0x21;
+            control_port_write(&mut bus, 0x0004, 0x55);
+            assert_eq!(
+                &bus.ppu.state.oam[..],
+                &oam[..],
+                "OAM changed on line {}",
+                line
+            );
+            assert_eq!(bus.ppu.state.oam_addr, 0x25);
+        }
+        // the increment wraps, like the real one
+        bus.ppu.state.oam_addr = 0xFE;
+        control_port_write(&mut bus, 0x0004, 0x55);
+        assert_eq!(bus.ppu.state.oam_addr, 0x02);
+    }
+
+    #[test]
+    fn oamdata_reads_ff_while_secondary_oam_is_cleared() {
+        let mut bus = make_bus(false);
+        bus.ppu.write_oam(0, 0x42);
+        run_to(&mut bus, 10, 30);
+        bus.ppu.state.oam_addr = 0;
+        assert_eq!(control_port_read(&mut bus, 0x0004), 0xFF);
+        run_to(&mut bus, 241, 1);
+        bus.ppu.state.oam_addr = 0;
+        assert_eq!(control_port_read(&mut bus, 0x0004), 0x42);
+    }
+
+    /// Point v at `addr` with two PPUADDR writes
+    fn set_vram_addr(bus: &mut TestBus, addr: u16) {
+        control_port_write(bus, 0x0006, (addr >> 8) as u8);
+        control_port_write(bus, 0x0006, addr as u8);
+    }
+
+    #[test]
+    fn ppudata_increments_by_1_or_32() {
+        let mut bus = make_bus(false);
+        control_port_write(&mut bus, 0x0001, 0);
+        set_vram_addr(&mut bus, 0x2000);
+        control_port_write(&mut bus, 0x0007, 0x11);
+        assert_eq!(bus.ppu.state.v, 0x2001);
+        control_port_read(&mut bus, 0x0007);
+        assert_eq!(bus.ppu.state.v, 0x2002);
+        control_port_write(
+            &mut bus,
+            0x0000,
+            PpuControlFlags::VRAM_INCREMENT_SELECT.bits(),
+        );
+        control_port_write(&mut bus, 0x0007, 0x22);
+        assert_eq!(bus.ppu.state.v, 0x2022);
+        control_port_read(&mut bus, 0x0007);
+        assert_eq!(bus.ppu.state.v, 0x2042);
+    }
+
+    #[test]
+    fn ppudata_increments_into_palette_space() {
+        let mut bus = make_bus(false);
+        control_port_write(&mut bus, 0x0001, 0);
+        set_vram_addr(&mut bus, 0x3EFF);
+        control_port_write(&mut bus, 0x0007, 0x05);
+        control_port_write(&mut bus, 0x0007, 0x2C);
+        assert_eq!(bus.ppu.state.v, 0x3F01);
+        assert_eq!(bus.ppu.palette.read(0x00, 0), 0x2C);
+        // $3EFF mirrors $2EFF
+        assert_eq!(bus.ppu.read(&mut *bus.cart, 0x2EFF), 0x05);
+    }
+
+    #[test]
+    fn ppudata_reads_palette_ram_directly() {
+        let mut bus = make_bus(false);
+        control_port_write(&mut bus, 0x0001, 0);
+        bus.ppu.write(&mut *bus.cart, 0x3F05, 0x2C);
+        bus.ppu.write(&mut *bus.cart, 0x2F05, 0x99);
+        set_vram_addr(&mut bus, 0x3F05);
+        // the latch's top bits came from the PPUADDR write of $05
+        assert_eq!(control_port_read(&mut bus, 0x0007), 0x2C);
+        // ...and the buffer gets the nametable byte under the palette
+        assert_eq!(bus.ppu.state.ppudata_buffer, 0x99);
+        set_vram_addr(&mut bus, 0x2000);
+        assert_eq!(control_port_read(&mut bus, 0x0007), 0x99);
+    }
+
+    #[test]
+    fn ppudata_palette_reads_follow_grayscale() {
+        let mut bus = make_bus(false);
+        control_port_write(&mut bus, 0x0001, PpuMaskFlags::USE_GRAYSCALE.bits());
+        bus.ppu.write(&mut *bus.cart, 0x3F1D, 0x2C);
+        set_vram_addr(&mut bus, 0x3F1D);
+        assert_eq!(control_port_read(&mut bus, 0x0007), 0x20);
+        // $3F1D mirrors $2F1D in the buffer, and isn't grayscaled
+        let nametable = bus.ppu.read(&mut *bus.cart, 0x2F1D);
+        assert_eq!(bus.ppu.state.ppudata_buffer, nametable);
+        // the top 2 bits come from the data bus latch
+        control_port_write(&mut bus, 0x0001, 0);
+        set_vram_addr(&mut bus, 0x3F1D);
+        control_port_write(&mut bus, 0x0002, 0xC0);
+        assert_eq!(control_port_read(&mut bus, 0x0007), 0xEC);
+    }
+
+    #[test]
+    fn ppudata_wraps_past_the_top_of_the_bus() {
+        let mut bus = make_bus(false);
+        control_port_write(&mut bus, 0x0001, 0);
+        set_vram_addr(&mut bus, 0x3FFF);
+        control_port_read(&mut bus, 0x0007);
+        assert_eq!(bus.ppu.state.v, 0x4000);
+        control_port_write(
+            &mut bus,
+            0x0000,
+            PpuControlFlags::VRAM_INCREMENT_SELECT.bits(),
+        );
+        set_vram_addr(&mut bus, 0x3FE5);
+        control_port_read(&mut bus, 0x0007);
+        assert_eq!(bus.ppu.state.v, 0x4005);
+        // $4005 is $0005 on the 14-bit bus, so the next read buffers CHR
+        control_port_read(&mut bus, 0x0007);
+        let chr = bus.ppu.read(&mut *bus.cart, 0x0005);
+        assert_eq!(control_port_read(&mut bus, 0x0007), chr);
+        bus.ppu.state.v = 0x7FF0;
+        control_port_read(&mut bus, 0x0007);
+        assert_eq!(bus.ppu.state.v, 0x0010);
+    }
+
+    /// The `(v, t, x, w)` registers
+    fn loopy_registers(bus: &TestBus) -> (u16, u16, u8, bool) {
+        let state = &bus.ppu.state;
+        (state.v, state.t, state.x, state.w)
+    }
+
+    #[test]
+    fn scroll_writes_follow_the_nesdev_example() {
+        // cf. https://wiki.nesdev.com/w/index.php/PPU_scrolling#Summary
+        let mut bus = make_bus(false);
+        control_port_write(&mut bus, 0x0001, 0);
+        bus.ppu.state.t = 0x7FFF;
+        bus.ppu.state.v = 0x0000;
+        control_port_write(&mut bus, 0x0000, 0x00);
+        assert_eq!(loopy_registers(&bus), (0x0000, 0x73FF, 0x03, false));
+        control_port_read(&mut bus, 0x0002);
+        assert!(!bus.ppu.state.w);
+        control_port_write(&mut bus, 0x0005, 0x7D);
+        assert_eq!(loopy_registers(&bus), (0x0000, 0x73EF, 0x05, true));
+        control_port_write(&mut bus, 0x0005, 0x5E);
+        assert_eq!(loopy_registers(&bus), (0x0000, 0x616F, 0x05, false));
+        control_port_write(&mut bus, 0x0006, 0x3D);
+        assert_eq!(loopy_registers(&bus), (0x0000, 0x3D6F, 0x05, true));
+        control_port_write(&mut bus, 0x0006, 0xF0);
+        assert_eq!(loopy_registers(&bus), (0x3DF0, 0x3DF0, 0x05, false));
+    }
+
+    #[test]
+    fn scroll_and_address_writes_share_the_write_toggle() {
+        let mut bus = make_bus(false);
+        control_port_write(&mut bus, 0x0001, 0);
+        // the first $2005 write leaves the toggle set, so the next $2006
+        // write is taken as the low byte and copied straight into v
+        control_port_write(&mut bus, 0x0005, 0xFF);
+        control_port_write(&mut bus, 0x0006, 0x42);
+        assert_eq!(loopy_registers(&bus), (0x2042, 0x2042, 0x07, false));
+        // and the other way around, a $2005 write after a lone $2006 write
+        // is taken as the Y scroll
+        control_port_write(&mut bus, 0x0006, 0x21);
+        control_port_write(&mut bus, 0x0005, 0xFF);
+        assert_eq!(loopy_registers(&bus).1, 0x73E2);
+        assert!(!bus.ppu.state.w);
+        // reading PPUSTATUS starts over with the first write
+        control_port_write(&mut bus, 0x0006, 0x3F);
+        control_port_read(&mut bus, 0x0002);
+        control_port_write(&mut bus, 0x0006, 0x20);
+        assert_eq!(loopy_registers(&bus).1 & 0x7F00, 0x2000);
+        assert!(bus.ppu.state.w);
+    }
+
+    #[test]
+    fn split_scroll_writes_set_v_mid_frame() {
+        // cf. https://wiki.nesdev.com/w/index.php/PPU_scrolling#Split_X.2FY_scroll
+        let mut bus = make_bus(false);
+        let mut checks = 0;
+        run_frame(&mut bus, |_| {});
+        run_frame(&mut bus, |bus| {
+            if bus.ppu.state.scanline == 100 && bus.ppu.state.pixel_cycle == 300 {
+                checks += 1;
+                // nametable 1, X = 0x9B, Y = 0x64
+                control_port_write(bus, 0x0006, 0x04);
+                control_port_write(bus, 0x0005, 0x64);
+                control_port_write(bus, 0x0005, 0x9B);
+                assert_eq!(loopy_registers(bus).1, 0x4593, "t mismatch");
+                control_port_write(bus, 0x0006, 0x93);
+                // the first $2006 write clears the top bit of fine Y, but
+                // the Y scroll write after it sets it again
+                assert_eq!(loopy_registers(bus), (0x4593, 0x4593, 0x03, false));
+            }
+            if bus.ppu.state.scanline == 101 && bus.ppu.state.pixel_cycle == 1 {
+                // the first two tiles of the next line were fetched
+                checks += 1;
+                assert_eq!(bus.ppu.state.v, 0x4595);
+            }
+            if bus.ppu.state.scanline == 101 && bus.ppu.state.pixel_cycle == 258 {
+                // fine Y moved down a line, and X was reset from t
+                checks += 1;
+                assert_eq!(bus.ppu.state.v, 0x5593);
+            }
+        });
+        assert_eq!(checks, 3);
+    }
+
+    #[test]
+    fn debug_state_tracks_scroll_writes() {
+        let mut bus = make_bus(false);
+        let view = bus.ppu.debug_state();
+        assert_eq!(view.fine_x, 0x13 & 0x07);
+        assert!(!view.write_latch);
+        control_port_write(&mut bus, 0x0005, 0x7D);
+        let view = bus.ppu.debug_state();
+        assert_eq!(view.fine_x, 0x05);
+        assert_eq!(view.t & 0x001F, 0x7D >> 3, "Coarse X mismatch");
+        assert!(view.write_latch);
+        run_frame(&mut bus, |_| {});
+        let view = bus.ppu.debug_state();
+        assert_eq!(
+            (view.scanline, view.dot),
+            (bus.ppu.state.scanline, bus.ppu.state.pixel_cycle)
+        );
+        assert_eq!(view.mask, PpuMaskFlags::BG_ENABLE.bits());
+    }
+
+    /// Read PPUSTATUS right before `dot` of `scanline` with NMIs enabled, and
+    /// return what it read and whether an NMI was raised around VBlank
+    fn race_vblank(scanline: i16, dot: u16) -> (u8, bool) {
+        let mut bus = make_bus(false);
+        // clear the VBlank flag from power-on
+        control_port_read(&mut bus, 0x0002);
+        control_port_write(&mut bus, 0x0000, PpuControlFlags::VBLANK_NMI_ENABLE.bits());
+        let mut raised = false;
+        while bus.ppu.state.scanline != scanline || bus.ppu.state.pixel_cycle != dot {
+            clock(&mut bus);
+            raised |= bus.ppu.is_vblank();
+        }
+        let status = control_port_read(&mut bus, 0x0002);
+        for _ in 0..20 {
+            clock(&mut bus);
+            raised |= bus.ppu.is_vblank();
+        }
+        (status & PpuStatusFlags::VBLANK.bits(), raised)
+    }
+
+    #[test]
+    fn reading_status_early_misses_vblank() {
+        assert_eq!(race_vblank(240, 340), (0, true), "Read 2 dots early");
+        assert_eq!(
+            race_vblank(VBLANK_SCANLINE, 0),
+            (0, false),
+            "Read 1 dot early"
+        );
+    }
+
+    #[test]
+    fn reading_status_at_vblank_cancels_the_nmi() {
+        assert_eq!(race_vblank(VBLANK_SCANLINE, 1), (0x80, false));
+        assert_eq!(race_vblank(VBLANK_SCANLINE, 2), (0x80, false));
+        assert_eq!(race_vblank(VBLANK_SCANLINE, 3), (0x80, true));
+    }
+
+    #[test]
+    fn raises_the_nmi_every_frame_when_enabled() {
+        let mut bus = make_bus(false);
+        control_port_write(&mut bus, 0x0000, PpuControlFlags::VBLANK_NMI_ENABLE.bits());
+        for _ in 0..2 {
+            run_to(&mut bus, VBLANK_SCANLINE, 20);
+            assert!(bus.ppu.is_vblank());
+            bus.ppu.ack_vblank();
+            run_frame(&mut bus, |_| {});
+        }
+    }
+
+    #[test]
+    fn suppressed_vblank_stays_clear() {
+        let mut bus = make_bus(false);
+        run_to(&mut bus, VBLANK_SCANLINE, 0);
+        control_port_read(&mut bus, 0x0002);
+        run_to(&mut bus, VBLANK_SCANLINE, 100);
+        assert_eq!(control_port_read(&mut bus, 0x0002) & 0x80, 0);
+        // the next frame isn't affected
+        run_frame(&mut bus, |_| {});
+        run_to(&mut bus, VBLANK_SCANLINE, 100);
+        assert_eq!(control_port_read(&mut bus, 0x0002) & 0x80, 0x80);
+    }
+
+    /// A tile that's solid color 1
+    const SOLID_TILE: [u8; 16] = [
+        0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0, 0, 0, 0, 0, 0, 0, 0,
+    ];
+
+    /// Build an NROM test setup for compositing sprites
+    ///
+    /// Tile 0 is transparent, and `tiles` go in the pattern table after it.
+    /// The left half of the nametable is tile 1, and the right half is tile 0.
+    /// Sprite palette `n` draws color 1 as $2n, and the background draws it
+    /// as $01.
+    fn make_sprite_bus(tiles: &[[u8; 16]]) -> TestBus {
+        let mut rom = vec![0x4E, 0x45, 0x53, 0x1A, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        rom.extend(vec![0u8; 0x4000]);
+        let mut chr = vec![0u8; 0x2000];
+        for (i, tile) in tiles.iter().enumerate() {
+            chr[(i + 1) * 16..(i + 2) * 16].copy_from_slice(tile);
+        }
+        rom.extend(chr);
+        let mut bus = TestBus {
+            ppu: Ppu2C02::new(),
+            cart: from_rom(&rom).unwrap(),
         };
-        self.palette_buffer[read_addr as usize] = data;
+        for addr in 0x2000u16..0x23C0 {
+            let tile = if addr & 0x1F < 16 { 1 } else { 0 };
+            bus.ppu.write(&mut *bus.cart, addr, tile);
+        }
+        bus.ppu.write(&mut *bus.cart, 0x3F00, 0x0F);
+        bus.ppu.write(&mut *bus.cart, 0x3F01, 0x01);
+        for palette in 0..4u16 {
+            bus.ppu
+                .write(&mut *bus.cart, 0x3F11 + palette * 4, 0x20 + palette as u8);
+        }
+        for addr in 0..=255u8 {
+            bus.ppu.write_oam(addr, 0xFF);
+        }
+        let mask = PpuMaskFlags::BG_ENABLE | PpuMaskFlags::SPRITE_ENABLE;
+        control_port_write(&mut bus, 0x0001, mask.bits());
+        control_port_write(&mut bus, 0x0005, 0x00);
+        control_port_write(&mut bus, 0x0005, 0x00);
+        bus
+    }
+
+    /// Put sprite `n` at (`x`, `y`), with the given tile and attributes
+    fn put_sprite(bus: &mut TestBus, n: u8, x: u8, y: u8, tile: u8, attr: u8) {
+        for (offset, &byte) in [y, tile, attr, x].iter().enumerate() {
+            bus.ppu.write_oam(n * 4 + offset as u8, byte);
+        }
+    }
+
+    /// The palette color drawn at (`x`, `y`) in the last finished frame
+    fn color_at(bus: &TestBus, x: usize, y: usize) -> &[u8] {
+        let idx = (y * 256 + x) * 3;
+        &bus.ppu.get_buffer()[idx..idx + 3]
+    }
+
+    #[test]
+    fn layer_frames_split_the_same_frame() {
+        let mut bus = make_sprite_bus(&[SOLID_TILE]);
+        bus.ppu.set_layer_frames(true);
+        // one sprite behind the background, and one in front of the backdrop
+        put_sprite(&mut bus, 0, 8, 100, 1, 0x20);
+        put_sprite(&mut bus, 1, 200, 100, 1, 0x01);
+        run_frame(&mut bus, |_| {});
+        run_frame(&mut bus, |_| {});
+        let layer_at = |frame: &[u8], x: usize, y: usize| frame[(y * 256 + x) * 3..][..3].to_vec();
+        let palette = bus.ppu.output_palette();
+        let background = bus.ppu.background_frame().expect("Layer frames are on");
+        let sprites = bus.ppu.sprite_frame().expect("Layer frames are on");
+        for &(x, y, composite, bg, sprite) in &[
+            (10, 104, 0x01, 0x01, 0x20),
+            (202, 104, 0x21, 0x0F, 0x21),
+            (10, 50, 0x01, 0x01, 0x0F),
+        ] {
+            assert_eq!(color_at(&bus, x, y), palette.rgb(0, composite));
+            assert_eq!(layer_at(background, x, y), palette.rgb(0, bg));
+            assert_eq!(layer_at(sprites, x, y), palette.rgb(0, sprite));
+        }
+        bus.ppu.set_layer_frames(false);
+        assert!(bus.ppu.background_frame().is_none());
+        assert!(bus.ppu.sprite_frame().is_none());
+    }
+
+    #[test]
+    fn draws_sprites_a_line_below_their_y() {
+        let mut bus = make_sprite_bus(&[SOLID_TILE]);
+        put_sprite(&mut bus, 0, 200, 0, 1, 0);
+        put_sprite(&mut bus, 1, 220, 238, 1, 0);
+        // these would only be on scanlines 0 and 240
+        put_sprite(&mut bus, 2, 240, 0xFF, 1, 0);
+        put_sprite(&mut bus, 3, 240, 239, 1, 0);
+        run_frame(&mut bus, |_| {});
+        run_frame(&mut bus, |_| {});
+        let palette = bus.ppu.output_palette();
+        let (backdrop, sprite) = (palette.rgb(0, 0x0F), palette.rgb(0, 0x20));
+        // the top edge
+        assert_eq!(color_at(&bus, 200, 0), backdrop);
+        assert_eq!(color_at(&bus, 200, 1), sprite);
+        assert_eq!(color_at(&bus, 200, 8), sprite);
+        assert_eq!(color_at(&bus, 200, 9), backdrop);
+        assert_eq!(color_at(&bus, 240, 0), backdrop);
+        // the bottom edge, with only the top row of the sprite showing
+        assert_eq!(color_at(&bus, 220, 238), backdrop);
+        assert_eq!(color_at(&bus, 220, 239), sprite);
+        assert_eq!(color_at(&bus, 240, 239), backdrop);
+    }
+
+    #[test]
+    fn draws_every_sprite_color() {
+        // columns of colors 0, 1, 2, and 3, two pixels each
+        let mut columns = [0x33; 16];
+        columns[8..16].copy_from_slice(&[0x0F; 8]);
+        let mut bus = make_sprite_bus(&[SOLID_TILE, columns]);
+        for (i, &color) in [0x21u8, 0x22, 0x23].iter().enumerate() {
+            bus.ppu.write(&mut *bus.cart, 0x3F11 + i as u16, color);
+        }
+        put_sprite(&mut bus, 0, 200, 100, 2, 0);
+        run_frame(&mut bus, |_| {});
+        run_frame(&mut bus, |_| {});
+        let palette = bus.ppu.output_palette();
+        for (x, &color) in [0x0Fu8, 0x0F, 0x21, 0x21, 0x22, 0x22, 0x23, 0x23]
+            .iter()
+            .enumerate()
+        {
+            assert_eq!(
+                color_at(&bus, 200 + x, 104),
+                palette.rgb(0, color),
+                "x = {}",
+                x
+            );
+        }
+    }
+
+    #[test]
+    fn behind_background_sprites_mask_later_sprites() {
+        let mut bus = make_sprite_bus(&[SOLID_TILE]);
+        let behind = PpuOamAttributes::BACKGROUND_PRIORITY.bits();
+        // a behind-background sprite over the background, and over the backdrop
+        put_sprite(&mut bus, 0, 64, 100, 1, behind);
+        put_sprite(&mut bus, 1, 192, 100, 1, behind);
+        // front sprites under both of them
+        put_sprite(&mut bus, 2, 64, 100, 1, 0x01);
+        put_sprite(&mut bus, 3, 192, 100, 1, 0x01);
+        // and a front sprite on its own, for comparison
+        put_sprite(&mut bus, 4, 32, 100, 1, 0x01);
+        run_frame(&mut bus, |_| {});
+        run_frame(&mut bus, |_| {});
+        let palette = bus.ppu.output_palette();
+        assert_eq!(color_at(&bus, 36, 104), palette.rgb(0, 0x21));
+        // sprite 0 wins, and then loses to the background
+        assert_eq!(color_at(&bus, 68, 104), palette.rgb(0, 0x01));
+        // with no background in the way, sprite 1 shows through
+        assert_eq!(color_at(&bus, 196, 104), palette.rgb(0, 0x20));
+    }
+
+    #[test]
+    fn transparent_sprite_0_pixels_dont_hit() {
+        let mut bus = make_sprite_bus(&[SOLID_TILE]);
+        put_sprite(&mut bus, 0, 64, 100, 0, 0);
+        put_sprite(&mut bus, 1, 64, 100, 1, 0);
+        run_frame(&mut bus, |_| {});
+        run_to(&mut bus, 200, 0);
+        assert!(!bus.ppu.debug_state().sprite_0_hit);
+        put_sprite(&mut bus, 0, 64, 100, 1, 0);
+        run_frame(&mut bus, |_| {});
+        run_to(&mut bus, 200, 0);
+        assert!(bus.ppu.debug_state().sprite_0_hit);
     }
 }
@@ -0,0 +1,344 @@
+//! Tiny synthetic PPU setups, for golden-image tests
+//!
+//! A `Scene` builds up CHR, nametables, palettes, and OAM in memory, loads
+//! them into an NROM board the way a game would (through PPUADDR and
+//! PPUDATA), and runs the PPU. `assert_pixels` then checks part of the frame
+//! against a small picture drawn in text, so PPU tests don't need any ROM
+//! files.
+
+use alloc::{boxed::Box, format, string::String, vec, vec::Vec};
+
+use super::ppu::{clock, control_port_write, Ppu2C02, WithPpuBus};
+use super::structs::PpuMaskFlags;
+use crate::devices::cartridge::{from_rom, ICartridge};
+
+/// A PPU and a cartridge for it to draw from
+pub struct TestBus {
+    pub ppu: Ppu2C02,
+    pub cart: Box<dyn ICartridge>,
+}
+
+impl WithPpuBus for TestBus {
+    fn ppu_and_cart_mut(&mut self) -> (&mut Ppu2C02, &mut dyn ICartridge) {
+        (&mut self.ppu, &mut *self.cart)
+    }
+}
+
+/// A PPU setup, built up one piece at a time
+///
+/// Everything starts out blank: transparent tiles, empty nametables, a black
+/// palette, and every sprite parked offscreen. The background is enabled
+/// (including the leftmost 8 pixels) with no scroll, and sprites are off.
+pub struct Scene {
+    chr: Vec<u8>,
+    vertical_mirroring: bool,
+    /// PPU bus writes to make, in order
+    writes: Vec<(u16, u8)>,
+    oam: [u8; 256],
+    control: u8,
+    mask: u8,
+    scroll: (u8, u8),
+}
+
+impl Scene {
+    pub fn new() -> Scene {
+        Scene {
+            chr: vec![0; 0x2000],
+            vertical_mirroring: false,
+            writes: Vec::new(),
+            oam: [0xFF; 256],
+            control: 0,
+            mask: (PpuMaskFlags::BG_ENABLE | PpuMaskFlags::BG_LEFT_ENABLE).bits(),
+            scroll: (0, 0),
+        }
+    }
+
+    /// Draw a tile into the pattern table at `addr`, from 8 rows of 8 color
+    /// indices ('0' to '3')
+    pub fn tile(mut self, addr: u16, rows: [&str; 8]) -> Scene {
+        for (y, row) in rows.iter().enumerate() {
+            let (mut lo, mut hi) = (0u8, 0u8);
+            for (x, pixel) in row.bytes().enumerate() {
+                let color = pixel - b'0';
+                assert!(color < 4 && x < 8, "Bad tile row {:?}", row);
+                lo |= (color & 0x01) << (7 - x);
+                hi |= (color >> 1) << (7 - x);
+            }
+            self.chr[addr as usize + y] = lo;
+            self.chr[addr as usize + y + 8] = hi;
+        }
+        self
+    }
+
+    /// Write bytes to PPU memory starting at `addr`, like nametables,
+    /// attribute tables, or palettes
+    pub fn write(mut self, addr: u16, data: &[u8]) -> Scene {
+        for (offset, &byte) in data.iter().enumerate() {
+            self.writes.push((addr + offset as u16, byte));
+        }
+        self
+    }
+
+    /// Fill `len` bytes of PPU memory starting at `addr` with `byte`
+    pub fn fill(self, addr: u16, len: usize, byte: u8) -> Scene {
+        self.write(addr, &vec![byte; len])
+    }
+
+    /// Mirror the nametables vertically, instead of horizontally
+    pub fn vertical_mirroring(mut self) -> Scene {
+        self.vertical_mirroring = true;
+        self
+    }
+
+    /// Put sprite `n` at (`x`, `y`) in OAM
+    pub fn sprite(mut self, n: u8, x: u8, y: u8, tile: u8, attr: u8) -> Scene {
+        let offset = n as usize * 4;
+        self.oam[offset..offset + 4].copy_from_slice(&[y, tile, attr, x]);
+        self
+    }
+
+    pub fn control(mut self, control: u8) -> Scene {
+        self.control = control;
+        self
+    }
+
+    pub fn mask(mut self, mask: u8) -> Scene {
+        self.mask = mask;
+        self
+    }
+
+    pub fn scroll(mut self, x: u8, y: u8) -> Scene {
+        self.scroll = (x, y);
+        self
+    }
+
+    /// Load the scene into a fresh PPU, and render frames until one has been
+    /// drawn entirely with it
+    pub fn render(&self) -> TestBus {
+        let mut rom = vec![0x4E, 0x45, 0x53, 0x1A, 1, 1, self.vertical_mirroring as u8];
+        rom.resize(16 + 0x4000, 0);
+        rom.extend_from_slice(&self.chr);
+        let mut bus = TestBus {
+            ppu: Ppu2C02::new(),
+            cart: from_rom(&rom).expect("Could not build the test cartridge"),
+        };
+        for &(addr, data) in &self.writes {
+            control_port_write(&mut bus, 0x0006, (addr >> 8) as u8);
+            control_port_write(&mut bus, 0x0006, addr as u8);
+            control_port_write(&mut bus, 0x0007, data);
+        }
+        for (addr, &data) in self.oam.iter().enumerate() {
+            bus.ppu.write_oam(addr as u8, data);
+        }
+        control_port_write(&mut bus, 0x0000, self.control);
+        control_port_write(&mut bus, 0x0005, self.scroll.0);
+        control_port_write(&mut bus, 0x0005, self.scroll.1);
+        control_port_write(&mut bus, 0x0001, self.mask);
+        // the first frame starts with v wherever the writes left it, and the
+        // scroll only takes hold on the pre-render line
+        for _ in 0..2 {
+            loop {
+                clock(&mut bus);
+                if bus.ppu.is_frame_ready() {
+                    break;
+                }
+            }
+        }
+        bus
+    }
+}
+
+/// Check a block of the last frame, with its top left corner at (`x`, `y`)
+///
+/// Each character of `rows` is one pixel, and `colors` says which PPU color
+/// (without emphasis) each character stands for.
+pub fn assert_pixels(bus: &TestBus, x: usize, y: usize, rows: &[&str], colors: &[(char, u8)]) {
+    let frame = bus.ppu.get_buffer();
+    let palette = bus.ppu.output_palette();
+    let mut mismatches: Vec<String> = Vec::new();
+    for (dy, row) in rows.iter().enumerate() {
+        for (dx, pixel) in row.chars().enumerate() {
+            let color = colors
+                .iter()
+                .find(|&&(c, _)| c == pixel)
+                .unwrap_or_else(|| panic!("No color for {:?}", pixel))
+                .1;
+            let idx = ((y + dy) * 256 + x + dx) * 3;
+            let actual = &frame[idx..idx + 3];
+            if actual != palette.rgb(0, color) {
+                let actual_color = (0..64u8).find(|&c| palette.rgb(0, c) == actual);
+                mismatches.push(format!(
+                    "({}, {}): expected ${:02X}, got {:?}",
+                    x + dx,
+                    y + dy,
+                    color,
+                    actual_color
+                ));
+            }
+        }
+    }
+    assert!(
+        mismatches.is_empty(),
+        "{} pixels differ:\n{}",
+        mismatches.len(),
+        mismatches.join("\n")
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::structs::PpuControlFlags;
+    use super::*;
+
+    /// A tile with a different color in each 4x4 quadrant
+    const QUADRANTS: [&str; 8] = [
+        "00001111", "00001111", "00001111", "00001111", "22223333", "22223333", "22223333",
+        "22223333",
+    ];
+
+    /// A solid tile of color 1
+    const SOLID: [&str; 8] = [
+        "11111111", "11111111", "11111111", "11111111", "11111111", "11111111", "11111111",
+        "11111111",
+    ];
+
+    /// Backdrop, then colors 1-3 of background palette 0
+    const BG_COLORS: [(char, u8); 4] = [('.', 0x0F), ('a', 0x16), ('b', 0x2A), ('c', 0x12)];
+
+    fn quadrant_scene() -> Scene {
+        Scene::new()
+            .tile(0x0010, QUADRANTS)
+            .fill(0x2000, 960, 1)
+            .write(0x3F00, &[0x0F, 0x16, 0x2A, 0x12])
+    }
+
+    #[test]
+    fn draws_tiles_from_the_pattern_table() {
+        let bus = quadrant_scene().render();
+        let rows = [
+            "....aaaa....aaaa",
+            "....aaaa....aaaa",
+            "....aaaa....aaaa",
+            "....aaaa....aaaa",
+            "bbbbccccbbbbcccc",
+            "bbbbccccbbbbcccc",
+            "bbbbccccbbbbcccc",
+            "bbbbccccbbbbcccc",
+        ];
+        assert_pixels(&bus, 0, 0, &rows, &BG_COLORS);
+        assert_pixels(&bus, 240, 232, &rows, &BG_COLORS);
+    }
+
+    #[test]
+    fn uses_the_pattern_table_ppuctrl_selects() {
+        let scene = Scene::new()
+            .tile(0x1010, QUADRANTS)
+            .fill(0x2000, 960, 1)
+            .write(0x3F00, &[0x0F, 0x16, 0x2A, 0x12]);
+        let rows = ["....aaaa", "bbbbcccc"];
+        let bus = scene
+            .control(PpuControlFlags::BG_TILE_SELECT.bits())
+            .render();
+        assert_pixels(&bus, 0, 3, &rows, &BG_COLORS);
+        let bus = Scene::new()
+            .tile(0x1010, QUADRANTS)
+            .fill(0x2000, 960, 1)
+            .write(0x3F00, &[0x0F, 0x16, 0x2A, 0x12])
+            .render();
+        assert_pixels(&bus, 0, 3, &["........", "........"], &BG_COLORS);
+    }
+
+    #[test]
+    fn scrolls_by_fine_and_coarse_amounts() {
+        let bus = quadrant_scene().scroll(11, 2).render();
+        // 3 pixels into the second column of tiles, 2 rows down
+        let rows = [
+            ".aaaa....aaaa...",
+            ".aaaa....aaaa...",
+            "bccccbbbbccccbbb",
+            "bccccbbbbccccbbb",
+            "bccccbbbbccccbbb",
+            "bccccbbbbccccbbb",
+            ".aaaa....aaaa...",
+        ];
+        assert_pixels(&bus, 0, 0, &rows, &BG_COLORS);
+    }
+
+    #[test]
+    fn attributes_pick_a_palette_per_quadrant() {
+        let bus = Scene::new()
+            .tile(0x0010, SOLID)
+            .fill(0x2000, 960, 1)
+            // top left, top right, bottom left, bottom right = 0, 1, 2, 3
+            .write(0x23C0, &[0b11_10_01_00])
+            .write(
+                0x3F00,
+                &[0x0F, 0x16, 0, 0, 0, 0x2A, 0, 0, 0, 0x12, 0, 0, 0, 0x30],
+            )
+            .render();
+        let colors = [('0', 0x16), ('1', 0x2A), ('2', 0x12), ('3', 0x30)];
+        let top = "0000000000000000111111111111111100000000";
+        let bottom = "2222222222222222333333333333333300000000";
+        assert_pixels(&bus, 0, 0, &[top, top], &colors);
+        assert_pixels(&bus, 0, 15, &[top, bottom], &colors);
+        assert_pixels(&bus, 0, 31, &[bottom, "0000"], &colors);
+    }
+
+    /// A scene with a different tile at the right edge of the first nametable
+    /// and the left edge of the second, scrolled to straddle them
+    fn straddle_scene() -> Scene {
+        Scene::new()
+            .tile(0x0010, SOLID)
+            .tile(
+                0x0020,
+                [
+                    "22222222", "22222222", "22222222", "22222222", "22222222", "22222222",
+                    "22222222", "22222222",
+                ],
+            )
+            .write(0x201F, &[1])
+            .write(0x2400, &[2])
+            .write(0x3F00, &[0x0F, 0x16, 0x2A])
+            .scroll(252, 0)
+    }
+
+    #[test]
+    fn vertical_mirroring_puts_nametables_side_by_side() {
+        let bus = straddle_scene().vertical_mirroring().render();
+        assert_pixels(&bus, 0, 0, &["aaaabbbbbbbb...."], &BG_COLORS);
+    }
+
+    #[test]
+    fn horizontal_mirroring_repeats_nametables_across() {
+        // $2400 mirrors $2000, so the tile at $2400 lands on top of $2000's
+        // first tile, and the scroll wraps around to it
+        let bus = straddle_scene().render();
+        assert_pixels(&bus, 0, 0, &["aaaabbbbbbbb...."], &BG_COLORS);
+        let bus = straddle_scene().write(0x2000, &[0]).render();
+        assert_pixels(&bus, 0, 0, &["aaaa............"], &BG_COLORS);
+    }
+
+    #[test]
+    fn draws_sprites_over_the_background() {
+        let mask = PpuMaskFlags::BG_ENABLE
+            | PpuMaskFlags::BG_LEFT_ENABLE
+            | PpuMaskFlags::SPRITE_ENABLE
+            | PpuMaskFlags::SPRITE_LEFT_ENABLE;
+        let bus = quadrant_scene()
+            .tile(0x0020, SOLID)
+            .write(0x3F11, &[0x30])
+            .sprite(0, 2, 20, 2, 0)
+            .mask(mask.bits())
+            .render();
+        let colors = [
+            BG_COLORS[0],
+            BG_COLORS[1],
+            BG_COLORS[2],
+            BG_COLORS[3],
+            ('s', 0x30),
+        ];
+        // sprites are drawn a line below their Y coordinate
+        let rows = ["bbbbccccbbbbcccc", "bbssssssssbbcccc", "bbssssssssbbcccc"];
+        assert_pixels(&bus, 0, 20, &rows, &colors);
+    }
+}
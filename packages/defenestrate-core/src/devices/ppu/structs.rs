@@ -1,3 +1,5 @@
+#[derive(Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct PpuState {
     //#region Loopy registers
     // These registers represent internal registers that handle numerous
@@ -19,13 +21,19 @@ pub struct PpuState {
     // Sprites get their own shift registers and counters
     pub bg_tile_hi_shift_reg: u16,
     pub bg_tile_lo_shift_reg: u16,
-    pub bg_attr_hi_shift_reg: u8,
-    pub bg_attr_lo_shift_reg: u8,
+    pub bg_attr_hi_shift_reg: u16,
+    pub bg_attr_lo_shift_reg: u16,
     /** The 2-bit attribute for the next tile to render, which feeds the shift registers */
     pub bg_attr_latch: u8,
     // The 8 tile shift registers for the 8 sprites
     pub sprite_tile_hi_shift_regs: [u8; 8],
     pub sprite_tile_lo_shift_regs: [u8; 8],
+    /** The X position counters for the 8 sprites, which count down to 0 before each is drawn */
+    pub sprite_x_counters: [u8; 8],
+    /** The attribute bytes of the 8 sprites */
+    pub sprite_attrs: [u8; 8],
+    /** Whether the first sprite on this scanline is sprite 0, for sprite 0 hits */
+    pub sprite_zero_on_line: bool,
     //#endregion
 
     //#region Byte buffers
@@ -56,10 +64,21 @@ pub struct PpuState {
     pub oam_addr: u8,
     /** The secondary OAM address, used for sprite evaluation */
     pub secondary_oam_addr: u8,
+    /** Whether sprite evaluation has finished with OAM for this scanline */
+    pub sprite_eval_done: bool,
+    /** Whether the first sprite evaluated on this scanline was in range */
+    pub sprite_zero_in_range: bool,
+    /**
+     * The rows of OAM (8 bytes each, one bit per row) to overwrite with row 0
+     * when rendering starts again, after being switched off mid-line
+     */
+    pub oam_corrupt_rows: u32,
     /** The  */
     /** The internal OAM memory */
+    #[cfg_attr(feature = "serde", serde(with = "crate::serde_utils::byte_array"))]
     pub oam: [u8; 256],
     /** The secondary OAM used for sprite evaluation */
+    #[cfg_attr(feature = "serde", serde(with = "crate::serde_utils::byte_array"))]
     pub secondary_oam: [u8; 64],
     /** The pixel currently being output by the PPU. */
     pub pixel_cycle: u16,
@@ -67,10 +86,13 @@ pub struct PpuState {
     pub scanline: i16,
     /** Whether the PPU has completed a frame */
     pub frame_ready: bool,
-    /** The internal framebuffer containing the rendered image, in u8 RGB */
-    pub frame_data: [u8; 184_320], // 240 * 256 * 3
     /** Whether a VBlank interrupt has occured */
     pub vblank_nmi_ready: bool,
+    /**
+     * Whether PPUSTATUS was read right before the VBlank flag would be set,
+     * which keeps it (and the NMI) from being set this frame
+     */
+    pub vblank_suppressed: bool,
     /**
      * Buffer containing the value of the address given in PPUADDR.
      *
@@ -89,11 +111,125 @@ pub struct PpuState {
     pub ppudata_buffer: u8,
     /** The last value put on a PPU control port */
     pub last_control_port_value: u8,
+    /** How many frames it's been since each bit of `last_control_port_value`
+     * was last driven, for open bus decay */
+    pub io_latch_age: [u8; 8],
     /** The last value put on the internal PPU bus */
     pub last_bus_value: u8,
+    /** Whether the PPU is coming out of a reset, and ignoring register writes */
+    pub in_reset: bool,
+    /** Whether the PPU has just been powered on, and is ignoring register writes */
+    pub warming_up: bool,
     //#endregion
+
+    //#region Scanline batch renderer
+    // When nothing touches the scroll or rendering registers mid-line, the
+    // whole background for a scanline is fetched at once on dot 1 and the
+    // per-dot pipeline is skipped until dot 256.
+    /** The background pixels (palette << 2 | pattern) of the current scanline */
+    #[cfg_attr(feature = "serde", serde(with = "crate::serde_utils::byte_array"))]
+    pub bg_line_cache: [u8; 256],
+    /** Whether `bg_line_cache` is being used for the current scanline */
+    pub bg_line_cached: bool,
+    /** The background pipeline as it was on dot 1, for falling back mid-line */
+    pub bg_line_start: BgPipelineSnapshot,
+    /** The background pipeline as it will be after dot 256 */
+    pub bg_line_end: BgPipelineSnapshot,
+    //#endregion
+}
+
+/// A typed view of the PPU's internal registers, from `Ppu2C02::debug_state`
+///
+/// This is meant for debugger panels (like a scroll or timing viewer), so it
+/// leaves out the bulk memory in `PpuState`, and is cheap to copy every frame.
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct PpuDebugView {
+    /// The current VRAM address (Loopy's `v`)
+    pub v: u16,
+    /// The temporary VRAM address (Loopy's `t`)
+    pub t: u16,
+    /// The fine X scroll
+    pub fine_x: u8,
+    /// The shared PPUSCROLL/PPUADDR write latch (Loopy's `w`)
+    pub write_latch: bool,
+    pub scanline: i16,
+    pub dot: u16,
+    pub control: u8,
+    pub mask: u8,
+    pub status: u8,
+    /// Whether sprite 0 has hit the background this frame
+    pub sprite_0_hit: bool,
+    pub sprite_overflow: bool,
+    /// Whether the PPU is asserting an NMI that the CPU hasn't taken yet
+    pub nmi_pending: bool,
+    pub bg_tile_hi_shift_reg: u16,
+    pub bg_tile_lo_shift_reg: u16,
+    pub bg_attr_hi_shift_reg: u16,
+    pub bg_attr_lo_shift_reg: u16,
+    pub sprite_tile_hi_shift_regs: [u8; 8],
+    pub sprite_tile_lo_shift_regs: [u8; 8],
+}
+
+/// A copy of the registers involved in background fetching and shifting
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct BgPipelineSnapshot {
+    pub v: u16,
+    pub bg_tile_hi_shift_reg: u16,
+    pub bg_tile_lo_shift_reg: u16,
+    pub bg_attr_hi_shift_reg: u16,
+    pub bg_attr_lo_shift_reg: u16,
+    pub bg_attr_latch: u8,
+    pub temp_nt_byte: u8,
+    pub temp_at_byte: u8,
+    pub temp_bg_lo_byte: u8,
+    pub temp_bg_hi_byte: u8,
 }
 
+impl BgPipelineSnapshot {
+    pub fn take(state: &PpuState) -> BgPipelineSnapshot {
+        BgPipelineSnapshot {
+            v: state.v,
+            bg_tile_hi_shift_reg: state.bg_tile_hi_shift_reg,
+            bg_tile_lo_shift_reg: state.bg_tile_lo_shift_reg,
+            bg_attr_hi_shift_reg: state.bg_attr_hi_shift_reg,
+            bg_attr_lo_shift_reg: state.bg_attr_lo_shift_reg,
+            bg_attr_latch: state.bg_attr_latch,
+            temp_nt_byte: state.temp_nt_byte,
+            temp_at_byte: state.temp_at_byte,
+            temp_bg_lo_byte: state.temp_bg_lo_byte,
+            temp_bg_hi_byte: state.temp_bg_hi_byte,
+        }
+    }
+
+    pub fn restore(&self, state: &mut PpuState) {
+        state.v = self.v;
+        state.bg_tile_hi_shift_reg = self.bg_tile_hi_shift_reg;
+        state.bg_tile_lo_shift_reg = self.bg_tile_lo_shift_reg;
+        state.bg_attr_hi_shift_reg = self.bg_attr_hi_shift_reg;
+        state.bg_attr_lo_shift_reg = self.bg_attr_lo_shift_reg;
+        state.bg_attr_latch = self.bg_attr_latch;
+        state.temp_nt_byte = self.temp_nt_byte;
+        state.temp_at_byte = self.temp_at_byte;
+        state.temp_bg_lo_byte = self.temp_bg_lo_byte;
+        state.temp_bg_hi_byte = self.temp_bg_hi_byte;
+    }
+}
+
+const BG_PIPELINE_POWERON_STATE: BgPipelineSnapshot = BgPipelineSnapshot {
+    v: 0,
+    bg_tile_hi_shift_reg: 0,
+    bg_tile_lo_shift_reg: 0,
+    bg_attr_hi_shift_reg: 0,
+    bg_attr_lo_shift_reg: 0,
+    bg_attr_latch: 0,
+    temp_nt_byte: 0,
+    temp_at_byte: 0,
+    temp_bg_lo_byte: 0,
+    temp_bg_hi_byte: 0,
+};
+
 pub const PPU_POWERON_STATE: PpuState = PpuState {
     v: 0,
     t: 0,
@@ -101,6 +237,9 @@ pub const PPU_POWERON_STATE: PpuState = PpuState {
     w: false,
     oam_addr: 0,
     secondary_oam_addr: 0,
+    sprite_eval_done: false,
+    sprite_zero_in_range: false,
+    oam_corrupt_rows: 0,
     bg_tile_hi_shift_reg: 0,
     bg_tile_lo_shift_reg: 0,
     bg_attr_hi_shift_reg: 0,
@@ -108,6 +247,9 @@ pub const PPU_POWERON_STATE: PpuState = PpuState {
     bg_attr_latch: 0,
     sprite_tile_hi_shift_regs: [0u8; 8],
     sprite_tile_lo_shift_regs: [0u8; 8],
+    sprite_x_counters: [0xFFu8; 8],
+    sprite_attrs: [0xFFu8; 8],
+    sprite_zero_on_line: false,
     ppudata_buffer: 0,
     temp_nt_byte: 0,
     temp_bg_hi_byte: 0,
@@ -123,10 +265,17 @@ pub const PPU_POWERON_STATE: PpuState = PpuState {
     pixel_cycle: 0,
     scanline: 0,
     frame_ready: false,
-    frame_data: [0u8; 184_320],
     vblank_nmi_ready: false,
+    vblank_suppressed: false,
     last_control_port_value: 0,
+    io_latch_age: [0u8; 8],
     last_bus_value: 0,
+    in_reset: false,
+    warming_up: false,
+    bg_line_cache: [0u8; 256],
+    bg_line_cached: false,
+    bg_line_start: BG_PIPELINE_POWERON_STATE,
+    bg_line_end: BG_PIPELINE_POWERON_STATE,
 };
 
 bitflags! {
@@ -136,7 +285,7 @@ bitflags! {
         const COARSE_Y = 0x03E0;
         const NAMETABLE_X = 0x0400;
         const NAMETABLE_Y = 0x0800;
-        const FINE_Y = 0x700;
+        const FINE_Y = 0x7000;
     }
 }
 
@@ -183,6 +332,17 @@ bitflags! {
     }
 }
 
+bitflags! {
+    /// Which layers the PPU draws into the frame buffer, see
+    /// `Ppu2C02::set_layer_mask`
+    ///
+    /// This is a host-side override for debugging, on top of PPUMASK.
+    pub struct LayerMask: u8 {
+        const BACKGROUND = 0x01;
+        const SPRITES = 0x02;
+    }
+}
+
 bitflags! {
     /// Bitmasks for the PPU status register ($PPUSTATUS)
     pub struct PpuStatusFlags: u8 {
@@ -211,8 +371,6 @@ pub mod PpuControlPorts {
     pub const PPUADDR: u16 = 0x2006;
     /// Read-write port for interfacing with the PPU bus
     pub const PPUDATA: u16 = 0x2007;
-    /// Address for setting up OAM
-    pub const OAMDMA: u16 = 0x4014;
 }
 
 bitflags! {
@@ -0,0 +1,197 @@
+//! Conditions on memory that a running `Nes` can stop at
+//!
+//! Test ROMs usually report their progress through memory rather than the
+//! screen. blargg's tests, for instance, write $80 to $6000 while they run and
+//! the result code once they finish. Probes let integration tests wait for
+//! that sort of thing without stepping the emulator by hand.
+//!
+//! Probes read memory the same way the debugger does, without side effects, so
+//! a probe on an address that can't be read that way (like the PPU ports) never
+//! fires.
+//!
+//! Watches (see the `watch` module) are kept and checked the same way, in a
+//! `ProbeList` of their own.
+
+use alloc::vec::Vec;
+
+/// How a probe compares the value in memory against its own
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum Comparator {
+    Equal,
+    NotEqual,
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+}
+
+impl Comparator {
+    /// Whether `actual` compares to `expected` this way
+    pub fn compare<T: PartialOrd>(self, actual: T, expected: T) -> bool {
+        match self {
+            Comparator::Equal => actual == expected,
+            Comparator::NotEqual => actual != expected,
+            Comparator::Less => actual < expected,
+            Comparator::LessOrEqual => actual <= expected,
+            Comparator::Greater => actual > expected,
+            Comparator::GreaterOrEqual => actual >= expected,
+        }
+    }
+}
+
+/// A condition on one byte of the CPU's address space
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub struct Probe {
+    pub addr: u16,
+    pub comparator: Comparator,
+    pub value: u8,
+}
+
+impl Probe {
+    pub fn new(addr: u16, comparator: Comparator, value: u8) -> Probe {
+        Probe {
+            addr,
+            comparator,
+            value,
+        }
+    }
+
+    /// Whether the probe fires when its address holds `actual`
+    pub fn is_met(&self, actual: u8) -> bool {
+        self.comparator.compare(actual, self.value)
+    }
+}
+
+/// A handle to a registered probe, for removing it later
+#[derive(Debug, Eq, PartialEq, Hash, Copy, Clone)]
+pub struct ProbeId(u64);
+
+/// A handle to something in a `ProbeList`
+pub(crate) trait ProbeHandle: Copy + Eq {
+    fn from_raw(id: u64) -> Self;
+}
+
+impl ProbeHandle for ProbeId {
+    fn from_raw(id: u64) -> ProbeId {
+        ProbeId(id)
+    }
+}
+
+/// A probe that fired, and the value that set it off
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub struct ProbeHit {
+    pub id: ProbeId,
+    pub probe: Probe,
+    pub value: u8,
+}
+
+/// Conditions registered on a `Nes`, in the order they were added, with
+/// handles to remove them by
+pub(crate) struct ProbeList<I, T> {
+    next_id: u64,
+    entries: Vec<(I, T)>,
+}
+
+impl<I, T> Default for ProbeList<I, T> {
+    fn default() -> ProbeList<I, T> {
+        ProbeList {
+            next_id: 0,
+            entries: Vec::new(),
+        }
+    }
+}
+
+impl<I: ProbeHandle, T> ProbeList<I, T> {
+    pub fn add(&mut self, condition: T) -> I {
+        let id = I::from_raw(self.next_id);
+        self.next_id += 1;
+        self.entries.push((id, condition));
+        id
+    }
+
+    /// Remove a condition, returning whether it was registered
+    pub fn remove(&mut self, id: I) -> bool {
+        let before = self.entries.len();
+        self.entries.retain(|(entry_id, _)| *entry_id != id);
+        self.entries.len() != before
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// The first result `f` gives, trying the conditions in the order they
+    /// were added
+    pub fn find_map<R, F: FnMut(I, &T) -> Option<R>>(&self, mut f: F) -> Option<R> {
+        self.entries
+            .iter()
+            .find_map(|(id, condition)| f(*id, condition))
+    }
+}
+
+pub(crate) type Probes = ProbeList<ProbeId, Probe>;
+
+impl Probes {
+    /// The first probe, in the order they were added, whose condition holds
+    ///
+    /// `peek` reads memory without side effects, returning `None` where that
+    /// isn't possible.
+    pub fn check<F: Fn(u16) -> Option<u8>>(&self, peek: F) -> Option<ProbeHit> {
+        self.find_map(|id, &probe| {
+            let value = peek(probe.addr)?;
+            if probe.is_met(value) {
+                Some(ProbeHit { id, probe, value })
+            } else {
+                None
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn comparators_compare_unsigned() {
+        let probe = |comparator| Probe::new(0x6000, comparator, 0x80);
+        assert!(probe(Comparator::Less).is_met(0x00));
+        assert!(!probe(Comparator::Less).is_met(0xFF));
+        assert!(probe(Comparator::LessOrEqual).is_met(0x80));
+        assert!(probe(Comparator::Greater).is_met(0x81));
+        assert!(!probe(Comparator::GreaterOrEqual).is_met(0x7F));
+        assert!(probe(Comparator::Equal).is_met(0x80));
+        assert!(probe(Comparator::NotEqual).is_met(0x7F));
+    }
+
+    #[test]
+    fn checks_probes_in_the_order_they_were_added() {
+        let mut probes = Probes::default();
+        let memory = |addr: u16| match addr {
+            0x0010 => Some(0x01),
+            0x0020 => Some(0x02),
+            _ => None,
+        };
+        assert_eq!(probes.check(memory), None);
+        let first = probes.add(Probe::new(0x0020, Comparator::Equal, 0x02));
+        let second = probes.add(Probe::new(0x0010, Comparator::Equal, 0x01));
+        assert_eq!(probes.check(memory).map(|hit| hit.id), Some(first));
+        assert!(probes.remove(first));
+        assert!(!probes.remove(first));
+        let hit = probes.check(memory).unwrap();
+        assert_eq!((hit.id, hit.value), (second, 0x01));
+    }
+
+    #[test]
+    fn unreadable_addresses_never_fire() {
+        let mut probes = Probes::default();
+        probes.add(Probe::new(0x2002, Comparator::NotEqual, 0x00));
+        assert_eq!(probes.check(|_| None), None);
+        probes.clear();
+        assert_eq!(probes.check(|_| Some(0xFF)), None);
+    }
+}
@@ -0,0 +1,142 @@
+//! Memory access profiling, for coverage maps and heatmaps
+//!
+//! This module only exists with the `profiler` feature enabled, so that the
+//! bookkeeping compiles out entirely otherwise. Even with the feature on,
+//! nothing is recorded (or allocated) until profiling is turned on with
+//! `Nes::set_profiling`.
+
+use alloc::{vec, vec::Vec};
+
+/// Per-address access counters for one address space
+///
+/// Counters saturate instead of wrapping, so a hot loop left running for a
+/// long time won't make an address look cold.
+#[derive(Debug, Clone)]
+pub struct AccessCounts {
+    reads: Vec<u32>,
+    writes: Vec<u32>,
+    execs: Vec<u32>,
+}
+
+/// Access counts summed over a range of addresses
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub struct AccessBucket {
+    /// The first address in this bucket
+    pub start: usize,
+    /// The number of addresses in this bucket
+    pub len: usize,
+    pub reads: u64,
+    pub writes: u64,
+    pub execs: u64,
+}
+
+impl AccessCounts {
+    pub fn new(size: usize) -> AccessCounts {
+        AccessCounts {
+            reads: vec![0u32; size],
+            writes: vec![0u32; size],
+            execs: vec![0u32; size],
+        }
+    }
+
+    /// The number of addresses tracked
+    pub fn len(&self) -> usize {
+        self.reads.len()
+    }
+
+    pub fn reads(&self) -> &[u32] {
+        &self.reads
+    }
+
+    pub fn writes(&self) -> &[u32] {
+        &self.writes
+    }
+
+    /// Opcode fetches per address. This is always empty for the PPU bus.
+    pub fn execs(&self) -> &[u32] {
+        &self.execs
+    }
+
+    pub fn record_read(&mut self, addr: u16) {
+        Self::bump(&mut self.reads, addr);
+    }
+
+    pub fn record_write(&mut self, addr: u16) {
+        Self::bump(&mut self.writes, addr);
+    }
+
+    pub fn record_exec(&mut self, addr: u16) {
+        Self::bump(&mut self.execs, addr);
+    }
+
+    /// Zero every counter
+    pub fn clear(&mut self) {
+        self.reads.fill(0);
+        self.writes.fill(0);
+        self.execs.fill(0);
+    }
+
+    /// Sum the counters into buckets of `bucket_size` addresses each
+    ///
+    /// A bucket size of 256 gives one bucket per page, which is a good size
+    /// for a heatmap of the CPU address space.
+    pub fn buckets(&self, bucket_size: usize) -> Vec<AccessBucket> {
+        assert!(bucket_size > 0, "Bucket size must be nonzero");
+        (0..self.len())
+            .step_by(bucket_size)
+            .map(|start| {
+                let end = (start + bucket_size).min(self.len());
+                let sum = |counts: &[u32]| counts[start..end].iter().map(|&n| n as u64).sum();
+                AccessBucket {
+                    start,
+                    len: end - start,
+                    reads: sum(&self.reads),
+                    writes: sum(&self.writes),
+                    execs: sum(&self.execs),
+                }
+            })
+            .collect()
+    }
+
+    fn bump(counts: &mut [u32], addr: u16) {
+        if let Some(count) = counts.get_mut(addr as usize) {
+            *count = count.saturating_add(1);
+        }
+    }
+}
+
+/// A view of the access counts for the CPU bus and the CHR space of the PPU bus
+#[derive(Debug, Copy, Clone)]
+pub struct MemoryProfile<'a> {
+    /// Accesses to the CPU bus, by CPU address ($0000-$FFFF)
+    pub cpu: &'a AccessCounts,
+    /// Accesses to the pattern tables, by PPU address ($0000-$1FFF)
+    pub chr: &'a AccessCounts,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sums_buckets() {
+        let mut counts = AccessCounts::new(0x1000);
+        counts.record_read(0x0000);
+        counts.record_read(0x00FF);
+        counts.record_write(0x0100);
+        counts.record_exec(0x0FFF);
+        let buckets = counts.buckets(0x100);
+        assert_eq!(buckets.len(), 16);
+        assert_eq!(buckets[0].reads, 2);
+        assert_eq!(buckets[1].writes, 1);
+        assert_eq!(buckets[15].execs, 1);
+        assert_eq!(buckets[15].start, 0x0F00);
+    }
+
+    #[test]
+    fn ignores_out_of_range_addresses() {
+        let mut counts = AccessCounts::new(0x2000);
+        counts.record_read(0x2000);
+        assert_eq!(counts.buckets(0x2000)[0].reads, 0);
+    }
+}
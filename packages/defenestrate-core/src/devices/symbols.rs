@@ -0,0 +1,251 @@
+//! Names for addresses in the CPU's address space, from debug symbol files
+//!
+//! Assemblers like ca65 and asm6 can export the labels in a program, so that
+//! a debugger can say `JSR wait_vblank` instead of `JSR $C0A3`. Two of the
+//! formats emulators read are supported:
+//!
+//! - FCEUX's `.nl` files, with one label per line, like `$C000#reset#` (with
+//!   an optional comment after the second `#`). The addresses are CPU
+//!   addresses. FCEUX splits them into a file per bank (`game.nes.0.nl`,
+//!   `game.nes.ram.nl`, ...), which can be loaded one by one and combined with
+//!   `Symbols::extend`.
+//! - Mesen's `.mlb` files, with lines like `P:1C000:reset` (again, with an
+//!   optional comment after another `:`). The letter says which memory the
+//!   address is in, like `P` for PRG ROM or `R` for the console's RAM, and
+//!   the address is an offset into it. Mesen 2's longer names for them, like
+//!   `NesPrgRom`, work too.
+//!
+//! Labels for arrays (`$0200/100#oam#` or `R:0200-02FF:oam`) only name their
+//! first address.
+//!
+//! Symbols are looked up by CPU address, but Mesen labels PRG ROM by where it
+//! is in the ROM, which is only a CPU address once the mapper banks it in.
+//! Those labels are placed as if the ROM were banked like NROM, so ROMs of 32k
+//! or less are labeled everywhere they appear. For bigger ROMs, only the last
+//! 16k is labeled, at $C000, since that's the bank many mappers leave there
+//! for the vectors. Labels in the other banks are skipped.
+
+use alloc::collections::BTreeMap;
+use alloc::string::{String, ToString};
+use alloc::vec;
+use alloc::vec::Vec;
+
+use crate::error::{Error, Result};
+
+/// Where PRG ROM starts in the CPU's address space
+const PRG_ROM_START: u16 = 0x8000;
+/// Where the fixed bank goes, for ROMs too big to map all at once
+const FIXED_BANK_START: u16 = 0xC000;
+const FIXED_BANK_SIZE: usize = 0x4000;
+/// Where save and work RAM go, on the boards that have them
+const PRG_RAM_START: u16 = 0x6000;
+const PRG_RAM_SIZE: usize = 0x2000;
+const RAM_SIZE: usize = 0x0800;
+
+/// A set of labels for addresses in the CPU's address space
+#[derive(Debug, Default, Eq, PartialEq, Clone)]
+pub struct Symbols {
+    labels: BTreeMap<u16, String>,
+}
+
+impl Symbols {
+    pub fn new() -> Symbols {
+        Symbols::default()
+    }
+
+    /// Read the labels from an FCEUX `.nl` file
+    ///
+    /// Blank lines are skipped. Anything else that isn't a label is an error,
+    /// naming the line (counting from 1) it's on.
+    pub fn from_nl(text: &str) -> Result<Symbols> {
+        let mut symbols = Symbols::new();
+        for (idx, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let error = || Error::InvalidSymbolFile { line: idx + 1 };
+            let mut fields = line.splitn(3, '#');
+            let addr = fields.next().unwrap_or_default();
+            let label = fields.next().ok_or_else(error)?;
+            let addr = addr.strip_prefix('$').ok_or_else(error)?;
+            // arrays have their size after a slash
+            let addr = addr.split('/').next().unwrap_or_default();
+            let addr = u16::from_str_radix(addr, 16).map_err(|_| error())?;
+            if !label.is_empty() {
+                symbols.insert(addr, label);
+            }
+        }
+        Ok(symbols)
+    }
+
+    /// Read the labels from a Mesen `.mlb` file, for a ROM with `prg_size`
+    /// bytes of PRG ROM
+    ///
+    /// Blank lines, comments without a label, and labels for memory the CPU
+    /// can't see (like CHR ROM) are skipped. Anything else that isn't a label
+    /// is an error, naming the line (counting from 1) it's on.
+    pub fn from_mlb(text: &str, prg_size: usize) -> Result<Symbols> {
+        let mut symbols = Symbols::new();
+        for (idx, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let error = || Error::InvalidSymbolFile { line: idx + 1 };
+            let mut fields = line.splitn(4, ':');
+            let kind = fields.next().unwrap_or_default();
+            let addr = fields.next().ok_or_else(error)?;
+            let label = fields.next().ok_or_else(error)?;
+            // arrays are a range of addresses
+            let addr = addr.split('-').next().unwrap_or_default();
+            let offset = usize::from_str_radix(addr, 16).map_err(|_| error())?;
+            if label.is_empty() {
+                continue;
+            }
+            match kind {
+                "P" | "NesPrgRom" => {
+                    for addr in prg_rom_addresses(offset, prg_size) {
+                        symbols.insert(addr, label);
+                    }
+                }
+                "R" | "NesInternalRam" if offset < RAM_SIZE => symbols.insert(offset as u16, label),
+                "S" | "W" | "NesSaveRam" | "NesWorkRam" if offset < PRG_RAM_SIZE => {
+                    symbols.insert(PRG_RAM_START + offset as u16, label)
+                }
+                "G" | "NesMemory" if offset <= 0xFFFF => symbols.insert(offset as u16, label),
+                _ => {}
+            }
+        }
+        Ok(symbols)
+    }
+
+    /// Name `addr` `label`, replacing any label it already had
+    pub fn insert(&mut self, addr: u16, label: &str) {
+        self.labels.insert(addr, label.to_string());
+    }
+
+    /// Add the labels from `other`, which win over these ones for addresses
+    /// both have labels for
+    pub fn extend(&mut self, other: Symbols) {
+        self.labels.extend(other.labels);
+    }
+
+    /// The label for `addr`, if it has one
+    pub fn label(&self, addr: u16) -> Option<&str> {
+        self.labels.get(&addr).map(String::as_str)
+    }
+
+    /// The lowest address named `label`, if there is one
+    pub fn address(&self, label: &str) -> Option<u16> {
+        self.labels
+            .iter()
+            .find(|(_, name)| *name == label)
+            .map(|(addr, _)| *addr)
+    }
+
+    /// Every address with a label, in order
+    pub fn iter(&self) -> impl Iterator<Item = (u16, &str)> {
+        self.labels
+            .iter()
+            .map(|(addr, label)| (*addr, label.as_str()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.labels.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.labels.is_empty()
+    }
+}
+
+/// The CPU addresses an offset into PRG ROM shows up at, assuming it's
+/// banked like NROM
+fn prg_rom_addresses(offset: usize, prg_size: usize) -> Vec<u16> {
+    if prg_size > 0x8000 {
+        // only the last bank has somewhere to go
+        return match offset.checked_sub(prg_size - FIXED_BANK_SIZE) {
+            Some(offset) if offset < FIXED_BANK_SIZE => vec![FIXED_BANK_START + offset as u16],
+            _ => Vec::new(),
+        };
+    }
+    if offset >= prg_size {
+        return Vec::new();
+    }
+    // smaller ROMs are mirrored to fill the 32k
+    (offset..0x8000)
+        .step_by(prg_size)
+        .map(|addr| PRG_ROM_START + addr as u16)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_fceux_labels() {
+        let symbols = Symbols::from_nl(
+            "$C000#reset#Where it all begins\n\
+             \n\
+             $0200/100#oam#\n\
+             $C0A3#wait_vblank#spins on #$2002\n\
+             $C0B0##no label, just a comment\n",
+        )
+        .expect("Could not read symbols");
+        assert_eq!(symbols.len(), 3);
+        assert_eq!(symbols.label(0xC000), Some("reset"));
+        assert_eq!(symbols.label(0x0200), Some("oam"));
+        assert_eq!(symbols.address("wait_vblank"), Some(0xC0A3));
+        assert_eq!(symbols.label(0xC0B0), None);
+    }
+
+    #[test]
+    fn reads_mesen_labels() {
+        let symbols = Symbols::from_mlb(
+            "P:0000:reset:Where it all begins\n\
+             R:0010-0011:pointer\n\
+             S:0100:save_slot\n\
+             G:2002:PPUSTATUS\n\
+             N:0000:tiles\n\
+             P:0010::just a comment\n\
+             NesPrgRom:3FFA:vectors\n",
+            0x4000,
+        )
+        .expect("Could not read symbols");
+        // 16k of PRG ROM is mirrored at $8000 and $C000
+        assert_eq!(symbols.label(0x8000), Some("reset"));
+        assert_eq!(symbols.label(0xC000), Some("reset"));
+        assert_eq!(symbols.label(0xFFFA), Some("vectors"));
+        assert_eq!(symbols.label(0x0010), Some("pointer"));
+        assert_eq!(symbols.label(0x6100), Some("save_slot"));
+        assert_eq!(symbols.label(0x2002), Some("PPUSTATUS"));
+        assert_eq!(symbols.len(), 7);
+    }
+
+    #[test]
+    fn only_labels_the_last_bank_of_big_roms() {
+        let symbols = Symbols::from_mlb("P:0100:bank_0\nP:1C010:fixed\n", 0x20000)
+            .expect("Could not read symbols");
+        assert_eq!(symbols.address("bank_0"), None);
+        assert_eq!(symbols.address("fixed"), Some(0xC010));
+        assert_eq!(symbols.len(), 1);
+    }
+
+    #[test]
+    fn reports_the_bad_line() {
+        let err = Symbols::from_nl("$C000#reset#\nC010#main#\n").unwrap_err();
+        assert!(matches!(err, Error::InvalidSymbolFile { line: 2 }));
+        let err = Symbols::from_mlb("P:0000:reset\n\nP:zzzz:main\n", 0x8000).unwrap_err();
+        assert!(matches!(err, Error::InvalidSymbolFile { line: 3 }));
+    }
+
+    #[test]
+    fn later_labels_win() {
+        let mut symbols = Symbols::from_nl("$C000#reset#\n$C010#main#\n").unwrap();
+        symbols.extend(Symbols::from_nl("$C010#start#\n").unwrap());
+        assert_eq!(symbols.label(0xC000), Some("reset"));
+        assert_eq!(symbols.label(0xC010), Some("start"));
+    }
+}
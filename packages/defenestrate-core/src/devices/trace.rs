@@ -0,0 +1,220 @@
+//! Frame-long traces of CPU bus activity, for logic-analyzer style debugging
+//!
+//! Tracing is off until `Nes::trace_next_frame` arms it. From then until the
+//! end of the frame, every read and write on the CPU bus is appended to a
+//! `BusTrace`, which `Nes::take_bus_trace` hands back once the frame is done.
+
+use alloc::vec::Vec;
+
+use super::bus::cpu_memory_map::Device;
+use super::cartridge::PrgRegion;
+use super::clock::{MasterClock, CPU_DIVIDER};
+
+/// The size of each access in `BusTrace::to_bytes`
+pub const PACKED_ACCESS_SIZE: usize = 8;
+
+/// Whether an access was a read or a write
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum AccessKind {
+    Read,
+    Write,
+}
+
+/// The device on the CPU bus that an access went to
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum TraceDevice {
+    Cartridge = 0,
+    Ram = 1,
+    /// The PPU's registers, $2000-$3FFF
+    PpuPorts = 2,
+    /// The controller ports, $4016-$4017
+    Controllers = 3,
+    /// Nothing answered, so the value is open bus
+    Unmapped = 4,
+    /// The cartridge's PRG RAM, $6000-$7FFF
+    PrgRam = 5,
+    /// The rest of the APU and I/O registers, $4000-$401F
+    ApuIo = 6,
+}
+
+impl From<&Device> for TraceDevice {
+    fn from(device: &Device) -> TraceDevice {
+        match device {
+            Device::Cartridge(PrgRegion::Ram(_)) => TraceDevice::PrgRam,
+            Device::Cartridge(_) => TraceDevice::Cartridge,
+            Device::RAM => TraceDevice::Ram,
+            Device::PPUControl => TraceDevice::PpuPorts,
+            Device::ApuIo => TraceDevice::ApuIo,
+            Device::Controllers => TraceDevice::Controllers,
+            Device::Unmapped => TraceDevice::Unmapped,
+        }
+    }
+}
+
+/// One read or write on the CPU bus
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub struct BusAccess {
+    /// The CPU cycle this happened on, counting from the start of the trace
+    pub cycle: u32,
+    /// The address on the bus, before mirroring
+    pub addr: u16,
+    /// The value read or written
+    pub value: u8,
+    pub kind: AccessKind,
+    pub device: TraceDevice,
+}
+
+/// Every bus access made during one frame, in order
+#[derive(Debug, Clone, Default)]
+pub struct BusTrace {
+    accesses: Vec<BusAccess>,
+}
+
+impl BusTrace {
+    pub fn accesses(&self) -> &[BusAccess] {
+        &self.accesses
+    }
+
+    pub fn len(&self) -> usize {
+        self.accesses.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.accesses.is_empty()
+    }
+
+    /// Pack the trace into 8 bytes per access, for handing to the web UI
+    ///
+    /// Each access is the cycle as a little-endian u32, the address as a
+    /// little-endian u16, the value, and then a flags byte. The low 7 bits of
+    /// the flags are the `TraceDevice`, and the top bit is set for writes.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(self.accesses.len() * PACKED_ACCESS_SIZE);
+        for access in &self.accesses {
+            buf.extend_from_slice(&access.cycle.to_le_bytes());
+            buf.extend_from_slice(&access.addr.to_le_bytes());
+            buf.push(access.value);
+            let write_flag = match access.kind {
+                AccessKind::Read => 0x00,
+                AccessKind::Write => 0x80,
+            };
+            buf.push(access.device as u8 | write_flag);
+        }
+        buf
+    }
+}
+
+/// Where the `Nes` is in tracing a frame
+#[derive(Debug, Clone)]
+pub(crate) enum Tracer {
+    Off,
+    /// Tracing is on, and started at the given time
+    Recording {
+        start_cycle: MasterClock,
+        trace: BusTrace,
+    },
+    /// The frame is over, and the trace is waiting to be taken
+    Done(BusTrace),
+}
+
+impl Tracer {
+    #[inline(always)]
+    pub fn record(
+        &mut self,
+        now: MasterClock,
+        addr: u16,
+        value: u8,
+        kind: AccessKind,
+        device: &Device,
+    ) {
+        if let Tracer::Recording { start_cycle, trace } = self {
+            trace.accesses.push(BusAccess {
+                cycle: (now.since(*start_cycle) / CPU_DIVIDER) as u32,
+                addr,
+                value,
+                kind,
+                device: device.into(),
+            });
+        }
+    }
+
+    /// Finish the trace in progress, if there is one
+    pub fn end_frame(&mut self) {
+        if let Tracer::Recording { trace, .. } = self {
+            let trace = core::mem::take(trace);
+            *self = Tracer::Done(trace);
+        }
+    }
+
+    /// Take the finished trace, if there is one
+    pub fn take(&mut self) -> Option<BusTrace> {
+        match core::mem::replace(self, Tracer::Off) {
+            Tracer::Done(trace) => Some(trace),
+            other => {
+                *self = other;
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_records_until_the_frame_ends() {
+        let mut tracer = Tracer::Recording {
+            start_cycle: MasterClock::from_ppu_cycles(30),
+            trace: BusTrace::default(),
+        };
+        tracer.record(
+            MasterClock::from_ppu_cycles(36),
+            0x2002,
+            0x80,
+            AccessKind::Read,
+            &Device::PPUControl,
+        );
+        assert!(tracer.take().is_none());
+        tracer.end_frame();
+        tracer.record(
+            MasterClock::from_ppu_cycles(39),
+            0x0000,
+            0x12,
+            AccessKind::Write,
+            &Device::RAM,
+        );
+        let trace = tracer.take().unwrap();
+        assert_eq!(
+            trace.accesses(),
+            &[BusAccess {
+                cycle: 2,
+                addr: 0x2002,
+                value: 0x80,
+                kind: AccessKind::Read,
+                device: TraceDevice::PpuPorts,
+            }]
+        );
+        assert!(tracer.take().is_none());
+    }
+
+    #[test]
+    fn packs_accesses_into_8_bytes() {
+        let mut tracer = Tracer::Recording {
+            start_cycle: MasterClock::new(),
+            trace: BusTrace::default(),
+        };
+        tracer.record(
+            MasterClock::from_ppu_cycles(0x30000),
+            0x4016,
+            0x01,
+            AccessKind::Write,
+            &Device::Controllers,
+        );
+        tracer.end_frame();
+        assert_eq!(
+            tracer.take().unwrap().to_bytes(),
+            &[0x00, 0x00, 0x01, 0x00, 0x16, 0x40, 0x01, 0x83]
+        );
+    }
+}
@@ -0,0 +1,310 @@
+//! Logging every instruction the CPU runs, with the `std` feature enabled
+//!
+//! `Nes::dbg_step_cpu` is fine for a few hundred instructions, but mapper and
+//! PPU bugs tend to show up millions of instructions in. A `TraceLogger`
+//! handed to `Nes::set_trace_logger` logs every instruction as the console
+//! runs normally, either streaming them to a writer (like a file) or keeping
+//! only the last few in a ring buffer.
+//!
+//! A ring buffer costs no I/O, and is dumped when something goes wrong: when
+//! a watch or probe stops the emulator, or when the emulator panics. The
+//! panic dump happens as the `Nes` is dropped, so code that catches panics
+//! and keeps the `Nes` around should call `Nes::dump_trace_log` itself. It
+//! also needs the panic to unwind: nothing is dumped with `panic = "abort"`,
+//! or when a panic reaches the C API, which catches it and never drops the
+//! `Nes` behind the handle.
+
+use std::collections::VecDeque;
+use std::fmt::Write as _;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::thread;
+
+use super::cpu::opcodes;
+use super::cpu::structs::{AddressingMode, CpuState};
+
+/// How each instruction is written in a trace
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum TraceFormat {
+    /// The same lines as `Nes::dbg_step_cpu`, which line up with the
+    /// nestest gold log and other emulators' traces
+    Nestest,
+    /// One JSON object per line, for scripts to pick apart:
+    ///
+    /// ```text
+    /// {"pc": 49152, "bytes": [76, 245, 197], "instr": "JMP", "mode": "Abs", "addr": 50677,
+    ///  "a": 0, "x": 0, "y": 0, "p": 36, "sp": 253, "scanline": 0, "dot": 21, "cycles": 7}
+    /// ```
+    ///
+    /// (all on one line). The registers, PPU position, and cycle count are
+    /// from just before the instruction ran, and `addr` is the address its
+    /// operand resolved to.
+    Structured,
+}
+
+/// Where a `TraceLogger` puts its lines
+enum Output {
+    Stream(Box<dyn Write + Send>),
+    Ring {
+        lines: VecDeque<String>,
+        capacity: usize,
+        /// Where `dump` writes the buffered lines
+        dump_to: Box<dyn Write + Send>,
+    },
+}
+
+/// A log of the instructions the CPU runs
+///
+/// See the module docs for how it's used.
+pub struct TraceLogger {
+    format: TraceFormat,
+    output: Output,
+    /// How many instructions have been logged, including any that fell out of
+    /// the ring buffer
+    logged: u64,
+    /// The first error from the writer, after which nothing else is written
+    error: Option<io::Error>,
+}
+
+impl TraceLogger {
+    /// Write every instruction to `out`, as it runs
+    ///
+    /// Buffered writers are a good idea, since this writes a line at a time.
+    pub fn streaming(out: Box<dyn Write + Send>, format: TraceFormat) -> TraceLogger {
+        TraceLogger {
+            format,
+            output: Output::Stream(out),
+            logged: 0,
+            error: None,
+        }
+    }
+
+    /// Write every instruction to a new file at `path`, replacing anything
+    /// already there
+    pub fn to_file<P: AsRef<Path>>(path: P, format: TraceFormat) -> io::Result<TraceLogger> {
+        let file = File::create(path)?;
+        Ok(TraceLogger::streaming(
+            Box::new(BufWriter::new(file)),
+            format,
+        ))
+    }
+
+    /// Keep the last `capacity` instructions, and write them to stderr when
+    /// dumped
+    ///
+    /// The buffer is dumped on a panic only if the panic unwinds far enough
+    /// to drop the logger. Builds with `panic = "abort"` lose it, and so do
+    /// hosts of the C API, since panics stop at the `extern "C"` boundary
+    /// with the console still alive. Write errors during that dump are
+    /// ignored, and the writer must not panic itself, since a second panic
+    /// while unwinding aborts the process.
+    ///
+    /// Panics if `capacity` is 0.
+    pub fn ring_buffer(capacity: usize, format: TraceFormat) -> TraceLogger {
+        assert!(capacity > 0, "A trace ring buffer can't be empty");
+        TraceLogger {
+            format,
+            output: Output::Ring {
+                lines: VecDeque::with_capacity(capacity),
+                capacity,
+                dump_to: Box::new(io::stderr()),
+            },
+            logged: 0,
+            error: None,
+        }
+    }
+
+    /// Dump the ring buffer to `out` instead of stderr
+    ///
+    /// This does nothing to a streaming logger, which always writes to the
+    /// same place.
+    pub fn dump_to(mut self, out: Box<dyn Write + Send>) -> TraceLogger {
+        if let Output::Ring { dump_to, .. } = &mut self.output {
+            *dump_to = out;
+        }
+        self
+    }
+
+    pub fn format(&self) -> TraceFormat {
+        self.format
+    }
+
+    /// How many instructions have been logged so far
+    pub fn logged(&self) -> u64 {
+        self.logged
+    }
+
+    /// The instructions in the ring buffer, oldest first
+    ///
+    /// This is always empty for a streaming logger.
+    pub fn recent(&self) -> impl Iterator<Item = &str> {
+        let lines = match &self.output {
+            Output::Ring { lines, .. } => Some(lines.iter().map(String::as_str)),
+            Output::Stream(_) => None,
+        };
+        lines.into_iter().flatten()
+    }
+
+    pub(crate) fn log(&mut self, line: String) {
+        self.logged += 1;
+        match &mut self.output {
+            Output::Stream(out) => {
+                if self.error.is_none() {
+                    if let Err(err) = writeln!(out, "{}", line) {
+                        self.error = Some(err);
+                    }
+                }
+            }
+            Output::Ring {
+                lines, capacity, ..
+            } => {
+                if lines.len() == *capacity {
+                    lines.pop_front();
+                }
+                lines.push_back(line);
+            }
+        }
+    }
+
+    /// Write out the ring buffer and empty it, or flush a streaming logger
+    ///
+    /// Errors are kept for `finish`, like errors from logging.
+    pub fn dump(&mut self) {
+        if self.error.is_some() {
+            return;
+        }
+        if let Err(err) = self.write_out() {
+            self.error = Some(err);
+        }
+    }
+
+    fn write_out(&mut self) -> io::Result<()> {
+        match &mut self.output {
+            Output::Stream(out) => out.flush(),
+            Output::Ring { lines, dump_to, .. } => lines
+                .drain(..)
+                .try_for_each(|line| writeln!(dump_to, "{}", line))
+                .and_then(|_| dump_to.flush()),
+        }
+    }
+
+    /// Stop logging, and flush a streaming logger
+    ///
+    /// This returns the number of instructions logged, or the first error the
+    /// writer ran into. Anything left in a ring buffer is dropped.
+    pub fn finish(mut self) -> io::Result<u64> {
+        if let Output::Stream(out) = &mut self.output {
+            if self.error.is_none() {
+                if let Err(err) = out.flush() {
+                    self.error = Some(err);
+                }
+            }
+        }
+        match self.error.take() {
+            Some(err) => Err(err),
+            None => Ok(self.logged),
+        }
+    }
+}
+
+impl Drop for TraceLogger {
+    fn drop(&mut self) {
+        // nobody is left to see an error, so it's dropped on the floor
+        if thread::panicking() && self.error.is_none() {
+            let _ = self.write_out();
+        }
+    }
+}
+
+/// The length of an instruction, opcode and all, by its addressing mode
+fn instruction_len(mode: AddressingMode) -> usize {
+    match mode {
+        AddressingMode::Abs
+        | AddressingMode::AbsX
+        | AddressingMode::AbsY
+        | AddressingMode::AbsInd => 3,
+        AddressingMode::Accum | AddressingMode::Impl => 1,
+        _ => 2,
+    }
+}
+
+/// Write one instruction as a `TraceFormat::Structured` line
+///
+/// `before` is the CPU as the instruction started, after any interrupt, and
+/// `after` is the CPU once it ran, which still has the instruction decoded.
+pub(crate) fn structured_line(
+    before: &CpuState,
+    after: &CpuState,
+    scanline: u16,
+    dot: u16,
+) -> String {
+    let bytes = after.instruction.to_le_bytes();
+    let bytes = &bytes[..instruction_len(after.addr_mode)];
+    let mut line = String::with_capacity(192);
+    let _ = write!(line, "{{\"pc\": {}, \"bytes\": {:?}, ", before.pc, bytes);
+    let _ = write!(
+        line,
+        "\"instr\": \"{}\", \"mode\": \"{:?}\", \"addr\": {}, ",
+        opcodes::mnemonic(bytes[0]),
+        after.addr_mode,
+        after.addr
+    );
+    let _ = write!(
+        line,
+        "\"a\": {}, \"x\": {}, \"y\": {}, \"p\": {}, \"sp\": {}, ",
+        before.acc,
+        before.x,
+        before.y,
+        before.status.bits(),
+        before.stack
+    );
+    let _ = write!(
+        line,
+        "\"scanline\": {}, \"dot\": {}, \"cycles\": {}}}",
+        scanline, dot, before.tot_cycles
+    );
+    line
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_the_last_lines_in_the_ring_buffer() {
+        let mut logger =
+            TraceLogger::ring_buffer(2, TraceFormat::Nestest).dump_to(Box::new(io::sink()));
+        for line in &["one", "two", "three"] {
+            logger.log(line.to_string());
+        }
+        assert_eq!(logger.recent().collect::<Vec<_>>(), vec!["two", "three"]);
+        assert_eq!(logger.logged(), 3);
+        logger.dump();
+        assert_eq!(logger.recent().count(), 0, "Dump didn't empty the buffer");
+        assert_eq!(logger.finish().unwrap(), 3);
+    }
+
+    /// A writer that always fails
+    struct Broken;
+
+    impl Write for Broken {
+        fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+            Err(io::Error::other("broken"))
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn reports_the_first_write_error() {
+        let mut logger = TraceLogger::streaming(Box::new(Broken), TraceFormat::Nestest);
+        logger.log("one".to_string());
+        logger.log("two".to_string());
+        assert_eq!(logger.logged(), 2);
+        let err = logger.finish().expect_err("Expected a write error");
+        assert_eq!(err.to_string(), "broken");
+    }
+}
@@ -0,0 +1,450 @@
+//! Conditions on the CPU's registers and memory, checked after every
+//! instruction
+//!
+//! Where a probe only looks at one byte of memory, a watch can combine
+//! conditions on the registers, the PPU's position, and memory, like "the
+//! accumulator is $FF while running code in $8000-$8FFF". Watches can be
+//! built up in code, or parsed from a small expression language so that a
+//! debugger UI can take them as text:
+//!
+//! ```text
+//! a == 0xFF && pc in 0x8000..0x9000
+//! [$0300] != 0 || !(scanline < 240)
+//! ```
+//!
+//! Operands are the registers `a`, `x`, `y`, `sp`, `pc`, and `p`, the PPU's
+//! `scanline` and `dot`, and bytes of memory as `[addr]`. Each is compared to
+//! a number with `==`, `!=`, `<`, `<=`, `>`, or `>=`, or checked against a
+//! half-open range with `in lo..hi`. Numbers are decimal, hex with a `0x` or
+//! `$` prefix, or labels from a symbol file with `parse_with_symbols`.
+//! Comparisons combine with `&&`, `||`, `!`, and parentheses, and `&&` binds
+//! tighter than `||`.
+//!
+//! Memory is read the same way probes read it, without side effects, so a
+//! comparison on an address that can't be read that way (like the PPU ports)
+//! never holds. Any probe can be turned into a watch on its byte of memory.
+
+use alloc::boxed::Box;
+use core::ops::{Not, Range};
+
+use super::probe::{Comparator, Probe, ProbeHandle, ProbeList};
+use super::symbols::Symbols;
+use crate::error::{Error, Result};
+
+/// Something a watch can look at
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum WatchOperand {
+    Acc,
+    X,
+    Y,
+    Stack,
+    Pc,
+    /// The status register, as it would be pushed by PHP
+    Status,
+    /// The PPU's scanline, from 0 to 261
+    Scanline,
+    /// The PPU's dot in the current scanline, from 0 to 340
+    Dot,
+    /// A byte in the CPU's address space
+    Memory(u16),
+}
+
+impl WatchOperand {
+    /// A watch that holds when this compares to `value` this way
+    pub fn compare(self, comparator: Comparator, value: u16) -> Watch {
+        Watch::Compare {
+            operand: self,
+            comparator,
+            value,
+        }
+    }
+
+    /// A watch that holds when this is `value`
+    pub fn equals(self, value: u16) -> Watch {
+        self.compare(Comparator::Equal, value)
+    }
+
+    /// A watch that holds when this is in `range`
+    pub fn in_range(self, range: Range<u16>) -> Watch {
+        Watch::InRange {
+            operand: self,
+            range,
+        }
+    }
+}
+
+/// A condition on the state of the console
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub enum Watch {
+    Compare {
+        operand: WatchOperand,
+        comparator: Comparator,
+        value: u16,
+    },
+    InRange {
+        operand: WatchOperand,
+        range: Range<u16>,
+    },
+    And(Box<Watch>, Box<Watch>),
+    Or(Box<Watch>, Box<Watch>),
+    Not(Box<Watch>),
+}
+
+impl Watch {
+    /// Parse a watch from the expression language described in the module
+    /// docs
+    ///
+    /// Anything that isn't a valid expression is an `InvalidWatch` error,
+    /// with the byte offset of where parsing stopped making sense.
+    pub fn parse(expr: &str) -> Result<Watch> {
+        Watch::parse_with_symbols(expr, &Symbols::new())
+    }
+
+    /// Parse a watch like `parse`, where numbers can also be labels from
+    /// `symbols`, like `pc == nmi_handler` or `[player_x] > 0x80`
+    pub fn parse_with_symbols(expr: &str, symbols: &Symbols) -> Result<Watch> {
+        let mut parser = Parser {
+            expr,
+            pos: 0,
+            symbols,
+        };
+        let watch = parser.parse_or()?;
+        parser.skip_whitespace();
+        if parser.pos != expr.len() {
+            return Err(parser.error());
+        }
+        Ok(watch)
+    }
+
+    /// A watch that holds when both this and `other` do
+    pub fn and(self, other: Watch) -> Watch {
+        Watch::And(Box::new(self), Box::new(other))
+    }
+
+    /// A watch that holds when either this or `other` does
+    pub fn or(self, other: Watch) -> Watch {
+        Watch::Or(Box::new(self), Box::new(other))
+    }
+
+    /// Whether this holds, given a way to read its operands
+    ///
+    /// `read` returns `None` for operands that can't be read, which makes any
+    /// comparison on them false.
+    pub fn is_met<F: Fn(WatchOperand) -> Option<u16>>(&self, read: &F) -> bool {
+        match self {
+            Watch::Compare {
+                operand,
+                comparator,
+                value,
+            } => matches!(read(*operand), Some(actual) if comparator.compare(actual, *value)),
+            Watch::InRange { operand, range } => {
+                matches!(read(*operand), Some(actual) if range.contains(&actual))
+            }
+            Watch::And(left, right) => left.is_met(read) && right.is_met(read),
+            Watch::Or(left, right) => left.is_met(read) || right.is_met(read),
+            Watch::Not(watch) => !watch.is_met(read),
+        }
+    }
+}
+
+impl From<Probe> for Watch {
+    fn from(probe: Probe) -> Watch {
+        WatchOperand::Memory(probe.addr).compare(probe.comparator, u16::from(probe.value))
+    }
+}
+
+impl Not for Watch {
+    type Output = Watch;
+
+    fn not(self) -> Watch {
+        Watch::Not(Box::new(self))
+    }
+}
+
+/// A recursive descent parser for watch expressions
+struct Parser<'a> {
+    expr: &'a str,
+    /// The byte offset of the next unparsed character
+    pos: usize,
+    /// Labels that can stand in for numbers
+    symbols: &'a Symbols,
+}
+
+impl<'a> Parser<'a> {
+    fn error(&self) -> Error {
+        Error::InvalidWatch { offset: self.pos }
+    }
+
+    fn rest(&self) -> &'a str {
+        &self.expr[self.pos..]
+    }
+
+    fn skip_whitespace(&mut self) {
+        let rest = self.rest();
+        self.pos += rest.len() - rest.trim_start().len();
+    }
+
+    /// Consume `token` if it's next, returning whether it was
+    fn eat(&mut self, token: &str) -> bool {
+        self.skip_whitespace();
+        if self.rest().starts_with(token) {
+            self.pos += token.len();
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Consume the identifier or number that's next
+    fn word(&mut self) -> &'a str {
+        self.skip_whitespace();
+        let rest = self.rest();
+        let len = rest
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == '$'))
+            .unwrap_or(rest.len());
+        self.pos += len;
+        &rest[..len]
+    }
+
+    fn parse_or(&mut self) -> Result<Watch> {
+        let mut watch = self.parse_and()?;
+        while self.eat("||") {
+            watch = watch.or(self.parse_and()?);
+        }
+        Ok(watch)
+    }
+
+    fn parse_and(&mut self) -> Result<Watch> {
+        let mut watch = self.parse_unary()?;
+        while self.eat("&&") {
+            watch = watch.and(self.parse_unary()?);
+        }
+        Ok(watch)
+    }
+
+    fn parse_unary(&mut self) -> Result<Watch> {
+        // `!=` only ever comes after an operand, so this can't be one
+        if self.eat("!") {
+            return Ok(!self.parse_unary()?);
+        }
+        if self.eat("(") {
+            let watch = self.parse_or()?;
+            if !self.eat(")") {
+                return Err(self.error());
+            }
+            return Ok(watch);
+        }
+        self.parse_comparison()
+    }
+
+    fn parse_comparison(&mut self) -> Result<Watch> {
+        let operand = self.parse_operand()?;
+        // the two-character comparators have to be tried first
+        let comparators = [
+            ("==", Comparator::Equal),
+            ("!=", Comparator::NotEqual),
+            ("<=", Comparator::LessOrEqual),
+            (">=", Comparator::GreaterOrEqual),
+            ("<", Comparator::Less),
+            (">", Comparator::Greater),
+        ];
+        for (token, comparator) in comparators {
+            if self.eat(token) {
+                return Ok(operand.compare(comparator, self.parse_number()?));
+            }
+        }
+        let start = self.pos;
+        if self.word() == "in" {
+            let lo = self.parse_number()?;
+            if !self.eat("..") {
+                return Err(self.error());
+            }
+            let hi = self.parse_number()?;
+            return Ok(operand.in_range(lo..hi));
+        }
+        self.pos = start;
+        self.skip_whitespace();
+        Err(self.error())
+    }
+
+    fn parse_operand(&mut self) -> Result<WatchOperand> {
+        if self.eat("[") {
+            let addr = self.parse_number()?;
+            if !self.eat("]") {
+                return Err(self.error());
+            }
+            return Ok(WatchOperand::Memory(addr));
+        }
+        self.skip_whitespace();
+        let start = self.pos;
+        let operand = match self.word() {
+            "a" | "acc" => WatchOperand::Acc,
+            "x" => WatchOperand::X,
+            "y" => WatchOperand::Y,
+            "sp" => WatchOperand::Stack,
+            "pc" => WatchOperand::Pc,
+            "p" => WatchOperand::Status,
+            "scanline" => WatchOperand::Scanline,
+            "dot" => WatchOperand::Dot,
+            _ => {
+                self.pos = start;
+                return Err(self.error());
+            }
+        };
+        Ok(operand)
+    }
+
+    fn parse_number(&mut self) -> Result<u16> {
+        self.skip_whitespace();
+        let start = self.pos;
+        let word = self.word();
+        let parsed = if let Some(hex) = word.strip_prefix("0x").or(word.strip_prefix('$')) {
+            u16::from_str_radix(hex, 16)
+        } else {
+            word.parse()
+        };
+        parsed
+            .ok()
+            .or_else(|| self.symbols.address(word))
+            .ok_or_else(|| {
+                self.pos = start;
+                self.error()
+            })
+    }
+}
+
+/// A handle to a registered watch, for removing it later
+#[derive(Debug, Eq, PartialEq, Hash, Copy, Clone)]
+pub struct WatchId(u64);
+
+impl ProbeHandle for WatchId {
+    fn from_raw(id: u64) -> WatchId {
+        WatchId(id)
+    }
+}
+
+pub(crate) type Watches = ProbeList<WatchId, Watch>;
+
+impl Watches {
+    /// The first watch, in the order they were added, that holds
+    pub fn check<F: Fn(WatchOperand) -> Option<u16>>(&self, read: F) -> Option<WatchId> {
+        self.find_map(|id, watch| if watch.is_met(&read) { Some(id) } else { None })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Registers and memory for watches to look at
+    fn read(operand: WatchOperand) -> Option<u16> {
+        match operand {
+            WatchOperand::Acc => Some(0xFF),
+            WatchOperand::X => Some(0x10),
+            WatchOperand::Pc => Some(0x8123),
+            WatchOperand::Scanline => Some(241),
+            WatchOperand::Memory(0x0300) => Some(0x01),
+            WatchOperand::Memory(_) => None,
+            _ => Some(0),
+        }
+    }
+
+    fn holds(expr: &str) -> bool {
+        Watch::parse(expr)
+            .unwrap_or_else(|err| panic!("Could not parse {:?}: {}", expr, err))
+            .is_met(&read)
+    }
+
+    #[test]
+    fn parses_into_the_same_watch_as_the_builder() {
+        assert_eq!(
+            Watch::parse("acc == 0xFF && pc in 0x8000..0x9000").unwrap(),
+            WatchOperand::Acc
+                .equals(0xFF)
+                .and(WatchOperand::Pc.in_range(0x8000..0x9000))
+        );
+        assert_eq!(
+            Watch::parse("!([$300] >= 2) || x < 16").unwrap(),
+            (!WatchOperand::Memory(0x0300).compare(Comparator::GreaterOrEqual, 2))
+                .or(WatchOperand::X.compare(Comparator::Less, 16))
+        );
+    }
+
+    #[test]
+    fn evaluates_comparisons() {
+        assert!(holds("a == 255"));
+        assert!(holds("a != 0"));
+        assert!(holds("x <= $10"));
+        assert!(!holds("x < $10"));
+        assert!(holds("pc in 0x8000..0x9000"));
+        assert!(!holds("pc in 0x8124..0x9000"));
+        assert!(holds("scanline >= 241"));
+        // unreadable memory never compares as anything
+        assert!(!holds("[$2002] == 0"));
+        assert!(!holds("[$2002] != 0"));
+    }
+
+    #[test]
+    fn and_binds_tighter_than_or() {
+        assert!(holds("a == 0 && x == 0 || pc == 0x8123"));
+        assert!(!holds("a == 0 && (x == 0 || pc == 0x8123)"));
+        assert!(holds("!(a == 0) && !!(x == 16)"));
+    }
+
+    #[test]
+    fn points_at_what_it_could_not_parse() {
+        let offset = |expr| match Watch::parse(expr) {
+            Err(Error::InvalidWatch { offset }) => Some(offset),
+            _ => None,
+        };
+        assert_eq!(offset("pc == "), Some(6));
+        assert_eq!(offset("foo == 1"), Some(0));
+        assert_eq!(offset("a == 1 &&"), Some(9));
+        assert_eq!(offset("a == 0x1FFFF"), Some(5));
+        assert_eq!(offset("(a == 1"), Some(7));
+        assert_eq!(offset("a 1"), Some(2));
+        assert_eq!(offset("a == 1 x"), Some(7));
+    }
+
+    #[test]
+    fn takes_labels_for_numbers() {
+        let mut symbols = Symbols::new();
+        symbols.insert(0x8123, "main_loop");
+        symbols.insert(0x0010, "counter");
+        assert_eq!(
+            Watch::parse_with_symbols("pc == main_loop && [counter] > 0", &symbols).unwrap(),
+            WatchOperand::Pc
+                .equals(0x8123)
+                .and(WatchOperand::Memory(0x0010).compare(Comparator::Greater, 0))
+        );
+        assert!(matches!(
+            Watch::parse_with_symbols("pc == nmi", &symbols),
+            Err(Error::InvalidWatch { offset: 6 })
+        ));
+    }
+
+    #[test]
+    fn probes_make_watches_on_their_byte() {
+        let probe = Probe::new(0x0300, Comparator::Less, 0x02);
+        assert_eq!(
+            Watch::from(probe),
+            WatchOperand::Memory(0x0300).compare(Comparator::Less, 2)
+        );
+        assert!(Watch::from(probe).is_met(&read));
+        assert!(!Watch::from(Probe::new(0x2002, Comparator::NotEqual, 0)).is_met(&read));
+    }
+
+    #[test]
+    fn checks_watches_in_the_order_they_were_added() {
+        let mut watches = Watches::default();
+        assert_eq!(watches.check(read), None);
+        let first = watches.add(WatchOperand::Acc.equals(0xFF));
+        let second = watches.add(WatchOperand::X.equals(0x10));
+        assert_eq!(watches.check(read), Some(first));
+        assert!(watches.remove(first));
+        assert!(!watches.remove(first));
+        assert_eq!(watches.check(read), Some(second));
+        watches.clear();
+        assert!(watches.is_empty());
+    }
+}
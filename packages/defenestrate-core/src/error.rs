@@ -0,0 +1,153 @@
+//! The error type for everything in this crate that can fail
+
+use core::fmt;
+
+/// Things that can go wrong when loading or patching a ROM, building a `Nes`,
+/// or parsing a watch expression or symbol file
+#[derive(Debug)]
+pub enum Error {
+    /// The ROM doesn't start with the iNES magic number (`NES\x1A`)
+    InvalidHeader,
+    /// The ROM is shorter than its header says it should be
+    TruncatedRom { expected: usize, actual: usize },
+    /// The ROM uses a mapper that hasn't been implemented yet
+    ///
+    /// `board` is the name of the board(s) most commonly behind that mapper
+    /// number, if it's a well-known one. `prg_size` and `chr_size` are from the
+    /// header, in 16k and 8k chunks respectively.
+    UnsupportedMapper {
+        mapper: u8,
+        board: Option<&'static str>,
+        prg_size: usize,
+        chr_size: usize,
+    },
+    /// The ROM's board can't hold as much PRG or CHR ROM as the header says it
+    /// has, like an NROM ROM with 48k of PRG
+    ///
+    /// `prg_size` and `chr_size` are in 16k and 8k chunks, like for
+    /// `UnsupportedMapper`.
+    InvalidRomSize {
+        mapper: u8,
+        prg_size: usize,
+        chr_size: usize,
+    },
+    /// The ROM was made for an arcade board or clone console that isn't
+    /// emulated, like the Vs. System
+    UnsupportedConsole { console: &'static str },
+    /// The patch isn't a valid IPS or BPS file, or it's corrupt
+    InvalidPatch,
+    /// The patch is for a different ROM (the source CRC32s don't match)
+    PatchSourceMismatch { expected: u32, actual: u32 },
+    /// The disk image isn't a valid .fds file
+    InvalidDiskImage,
+    /// The disk in the FDS doesn't have the side that was asked for
+    NoSuchDiskSide { side: usize, side_count: usize },
+    /// The FDS BIOS isn't 8k
+    InvalidBios { len: usize },
+    /// A .pal file isn't 64 or 512 colors long
+    InvalidPalette { len: usize },
+    /// A watch expression couldn't be parsed, starting at the byte `offset`
+    InvalidWatch { offset: usize },
+    /// A symbol file couldn't be parsed, starting at `line` (counting from 1)
+    InvalidSymbolFile { line: usize },
+    /// An image isn't a PNG, or is one that `video::decode_png` can't read
+    InvalidPng,
+    /// An image isn't the size of a frame
+    ImageSizeMismatch { width: usize, height: usize },
+    /// The ROM couldn't be read from disk
+    #[cfg(feature = "std")]
+    Io(std::io::Error),
+}
+
+pub type Result<T> = core::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::InvalidHeader => write!(f, "not an iNES ROM (bad magic number)"),
+            Error::TruncatedRom { expected, actual } => write!(
+                f,
+                "ROM is truncated: expected {} bytes, found {}",
+                expected, actual
+            ),
+            Error::UnsupportedMapper {
+                mapper,
+                board,
+                prg_size,
+                chr_size,
+            } => {
+                write!(f, "mapper {}", mapper)?;
+                if let Some(board) = board {
+                    write!(f, " ({})", board)?;
+                }
+                write!(
+                    f,
+                    " is not supported ({}k PRG ROM, {}k CHR ROM)",
+                    prg_size * 16,
+                    chr_size * 8
+                )
+            }
+            Error::InvalidRomSize {
+                mapper,
+                prg_size,
+                chr_size,
+            } => write!(
+                f,
+                "mapper {} can't have {}k PRG ROM and {}k CHR ROM",
+                mapper,
+                prg_size * 16,
+                chr_size * 8
+            ),
+            Error::UnsupportedConsole { console } => {
+                write!(f, "{} ROMs are not supported", console)
+            }
+            Error::InvalidPatch => write!(f, "not a valid IPS or BPS patch"),
+            Error::PatchSourceMismatch { expected, actual } => write!(
+                f,
+                "patch is for a different ROM: expected CRC32 {:08X}, found {:08X}",
+                expected, actual
+            ),
+            Error::InvalidDiskImage => write!(f, "not a valid FDS disk image"),
+            Error::NoSuchDiskSide { side, side_count } => {
+                write!(f, "disk has no side {} (it has {} sides)", side, side_count)
+            }
+            Error::InvalidBios { len } => {
+                write!(f, "FDS BIOS must be 8192 bytes, found {}", len)
+            }
+            Error::InvalidPalette { len } => {
+                write!(f, "palette must be 192 or 1536 bytes, found {}", len)
+            }
+            Error::InvalidWatch { offset } => {
+                write!(f, "invalid watch expression at offset {}", offset)
+            }
+            Error::InvalidSymbolFile { line } => {
+                write!(f, "invalid symbol file at line {}", line)
+            }
+            Error::InvalidPng => write!(f, "not a PNG, or not an 8-bit, non-interlaced one"),
+            Error::ImageSizeMismatch { width, height } => write!(
+                f,
+                "image must be 256x240 to compare with a frame, found {}x{}",
+                width, height
+            ),
+            #[cfg(feature = "std")]
+            Error::Io(err) => write!(f, "could not read ROM: {}", err),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl From<std::io::Error> for Error {
+    fn from(err: std::io::Error) -> Error {
+        Error::Io(err)
+    }
+}
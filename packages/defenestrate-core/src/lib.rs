@@ -1,8 +1,54 @@
+//! The emulation core for deFeNEStrate
+//!
+//! With the default `std` feature disabled, this crate only needs `alloc`, and
+//! loading ROMs from disk is left to the embedder.
+//!
+//! Most embedders only need what's in `prelude`. The public modules are the
+//! rest of the API: `devices::nes` for the console, `devices::cpu` for the
+//! 6502 on its own, and the modules for optional extras like `netplay`,
+//! `recorder`, `video`, `soak`, and `tools`. Anything not reachable from
+//! those is an implementation detail.
+//!
+//! Diagnostics go through the `log` crate, so they cost next to nothing
+//! unless the embedder installs a logger. Things that happen every frame or
+//! more often, like interrupts, are logged at the `trace` level.
+
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
+
 #[macro_use]
 extern crate bitflags;
 
+extern crate alloc;
+
+// The cdylib still needs a panic handler and an allocator without `std`. On
+// targets that have std they come from linking it, and naming it `_` keeps
+// it out of scope, so nothing here can use it by accident.
+#[cfg(not(any(feature = "std", test, target_os = "none")))]
+extern crate std as _;
+
 #[cfg(target = "wasm32")]
 extern crate wasm_bindgen;
 
 pub mod bindings;
+mod checksum;
 pub mod devices;
+pub mod error;
+#[cfg(feature = "console")]
+pub mod netplay;
+pub mod patch;
+pub mod prelude;
+#[cfg(feature = "console")]
+pub mod recorder;
+#[cfg(feature = "serde")]
+mod serde_utils;
+#[cfg(all(feature = "std", feature = "console"))]
+pub mod soak;
+#[cfg(feature = "console")]
+pub mod telemetry;
+#[cfg(all(feature = "std", feature = "console"))]
+pub mod throttle;
+#[cfg(all(feature = "std", feature = "png", feature = "console"))]
+pub mod tools;
+pub mod video;
+
+pub use error::{Error, Result};
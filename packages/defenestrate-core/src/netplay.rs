@@ -0,0 +1,309 @@
+//! Groundwork for netplay, by deterministic lockstep
+//!
+//! In lockstep, every player runs their own copy of the console, and only the
+//! inputs go over the network. The emulator is deterministic, so as long as
+//! every copy runs each frame with the same inputs, they all stay in sync. A
+//! `Lockstep` holds the inputs for each port by frame, and only runs a frame
+//! once it has all of them.
+//!
+//! Every so often, each side also sends a checksum of its console's state
+//! (see `Nes::state_checksum`), so that if the copies do drift apart (like
+//! from loading different ROMs, or a bug) it's noticed right away rather than
+//! when the players do.
+//!
+//! Getting `Message`s to the other side is up to the frontend. They're small
+//! and fixed-size, so they fit in a datagram each. Rollback, where a frame
+//! runs on a guessed input and is redone when the real one arrives, needs save
+//! states, which the core doesn't have yet.
+
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+
+use crate::devices::nes::{Buttons, Nes};
+
+/// The size of a `Message` from `Message::to_bytes`
+pub const MESSAGE_SIZE: usize = 13;
+
+const INPUT_TAG: u8 = 0x01;
+const CHECKSUM_TAG: u8 = 0x02;
+
+/// Something one side of a lockstep session tells the other
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum Message {
+    /// The buttons held on `port` for frame number `frame`
+    Input {
+        frame: u64,
+        port: u8,
+        buttons: Buttons,
+    },
+    /// The sender's `Nes::state_checksum` after running `frame` frames
+    Checksum { frame: u64, checksum: u32 },
+}
+
+impl Message {
+    /// Pack this message for sending
+    ///
+    /// Messages are a tag byte, then the frame as a little-endian u64, then
+    /// four bytes that are the port and buttons for inputs, or the checksum as
+    /// a little-endian u32.
+    pub fn to_bytes(&self) -> [u8; MESSAGE_SIZE] {
+        let (tag, frame, payload) = match *self {
+            Message::Input {
+                frame,
+                port,
+                buttons,
+            } => (INPUT_TAG, frame, [port, buttons.bits(), 0, 0]),
+            Message::Checksum { frame, checksum } => (CHECKSUM_TAG, frame, checksum.to_le_bytes()),
+        };
+        let mut buf = [0u8; MESSAGE_SIZE];
+        buf[0] = tag;
+        buf[1..9].copy_from_slice(&frame.to_le_bytes());
+        buf[9..].copy_from_slice(&payload);
+        buf
+    }
+
+    /// Unpack a message from `to_bytes`
+    ///
+    /// This returns `None` if `buf` is the wrong size or isn't a message.
+    pub fn from_bytes(buf: &[u8]) -> Option<Message> {
+        if buf.len() != MESSAGE_SIZE {
+            return None;
+        }
+        let mut frame = [0u8; 8];
+        frame.copy_from_slice(&buf[1..9]);
+        let frame = u64::from_le_bytes(frame);
+        match buf[0] {
+            INPUT_TAG if buf[9] < 2 => Some(Message::Input {
+                frame,
+                port: buf[9],
+                buttons: Buttons::from_bits_truncate(buf[10]),
+            }),
+            CHECKSUM_TAG => Some(Message::Checksum {
+                frame,
+                checksum: u32::from_le_bytes([buf[9], buf[10], buf[11], buf[12]]),
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// One side of a lockstep session between two players
+///
+/// Each side controls one controller port. Local inputs go in with
+/// `add_local_input`, which returns the message to send the other side, and
+/// whatever the other side sends goes to `receive`. `advance` then runs frames
+/// as their inputs come in.
+///
+/// The first local input is for frame 0, and each one after is for the frame
+/// after that. Adding a few inputs before the first `advance` delays the
+/// local player by that many frames, which gives the other side's inputs time
+/// to arrive without stalling.
+pub struct Lockstep {
+    local_port: usize,
+    /// The next frame to run
+    frame: u64,
+    /// The frame the next local input is for
+    next_local_frame: u64,
+    /// Inputs for frames that haven't run yet, by port
+    inputs: BTreeMap<u64, [Option<Buttons>; 2]>,
+    /// How many frames go by between checksums, or 0 for none
+    checksum_interval: u64,
+    local_checksums: BTreeMap<u64, u32>,
+    remote_checksums: BTreeMap<u64, u32>,
+    /// The first frame the checksums didn't match after
+    desync: Option<u64>,
+    /// Messages to send that `advance` came up with
+    outgoing: Vec<Message>,
+}
+
+impl Lockstep {
+    /// Start a session where this side controls `local_port`, checksumming
+    /// the console every `checksum_interval` frames
+    ///
+    /// A `checksum_interval` of 0 turns checksums off.
+    pub fn new(local_port: usize, checksum_interval: u64) -> Lockstep {
+        assert!(
+            local_port < 2,
+            "Controller port {} out of range",
+            local_port
+        );
+        Lockstep {
+            local_port,
+            frame: 0,
+            next_local_frame: 0,
+            inputs: BTreeMap::new(),
+            checksum_interval,
+            local_checksums: BTreeMap::new(),
+            remote_checksums: BTreeMap::new(),
+            desync: None,
+            outgoing: Vec::new(),
+        }
+    }
+
+    /// The next frame `advance` will run, which is also how many it has run
+    pub fn frame(&self) -> u64 {
+        self.frame
+    }
+
+    /// Queue the local player's buttons for their next frame, and get the
+    /// message telling the other side
+    pub fn add_local_input(&mut self, buttons: Buttons) -> Message {
+        let frame = self.next_local_frame;
+        self.next_local_frame += 1;
+        self.set_input(frame, self.local_port, buttons);
+        Message::Input {
+            frame,
+            port: self.local_port as u8,
+            buttons,
+        }
+    }
+
+    /// Take in a message from the other side
+    ///
+    /// Inputs for frames that already ran, and for the local port, are
+    /// ignored, so messages can be sent more than once.
+    pub fn receive(&mut self, message: Message) {
+        match message {
+            Message::Input {
+                frame,
+                port,
+                buttons,
+            } => {
+                let port = port as usize;
+                if port < 2 && port != self.local_port && frame >= self.frame {
+                    self.set_input(frame, port, buttons);
+                }
+            }
+            Message::Checksum { frame, checksum } => {
+                self.remote_checksums.insert(frame, checksum);
+                self.compare_checksums();
+            }
+        }
+    }
+
+    /// Whether the inputs for the next frame are all in
+    pub fn is_ready(&self) -> bool {
+        matches!(self.inputs.get(&self.frame), Some(ports) if ports.iter().all(Option::is_some))
+    }
+
+    /// Run every frame whose inputs are all in, returning how many ran
+    ///
+    /// Any checksums to send the other side are queued up for
+    /// `take_outgoing`.
+    pub fn advance(&mut self, nes: &mut Nes) -> u32 {
+        let mut frames = 0;
+        while self.is_ready() {
+            let ports = self.inputs.remove(&self.frame).unwrap_or_default();
+            let [p1, p2] = ports.map(|buttons| buttons.unwrap_or_else(Buttons::empty));
+            nes.tick_frame_with_input(p1, p2);
+            self.frame += 1;
+            frames += 1;
+            // an interval of 0 has no remainder, and no checksums
+            if self.frame.checked_rem(self.checksum_interval) == Some(0) {
+                let checksum = nes.state_checksum();
+                self.local_checksums.insert(self.frame, checksum);
+                self.outgoing.push(Message::Checksum {
+                    frame: self.frame,
+                    checksum,
+                });
+                self.compare_checksums();
+            }
+        }
+        frames
+    }
+
+    /// Take the messages `advance` has queued up to send
+    pub fn take_outgoing(&mut self) -> Vec<Message> {
+        core::mem::take(&mut self.outgoing)
+    }
+
+    /// The first frame after which the two sides' checksums didn't match, if
+    /// they've ever desynced
+    ///
+    /// Once the consoles have desynced, they stay that way, so there's
+    /// nothing to do but stop the session (or start a new one from the same
+    /// state).
+    pub fn desync(&self) -> Option<u64> {
+        self.desync
+    }
+
+    fn set_input(&mut self, frame: u64, port: usize, buttons: Buttons) {
+        self.inputs.entry(frame).or_default()[port] = Some(buttons);
+    }
+
+    /// Check the checksums both sides have, and forget the ones that match
+    fn compare_checksums(&mut self) {
+        let frames: Vec<u64> = self
+            .remote_checksums
+            .keys()
+            .filter(|frame| self.local_checksums.contains_key(frame))
+            .copied()
+            .collect();
+        for frame in frames {
+            let local = self.local_checksums.remove(&frame);
+            let remote = self.remote_checksums.remove(&frame);
+            if local != remote {
+                self.desync = Some(self.desync.map_or(frame, |desync| desync.min(frame)));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn messages_survive_a_round_trip() {
+        for message in [
+            Message::Input {
+                frame: 0x0123_4567_89AB,
+                port: 1,
+                buttons: Buttons::A | Buttons::LEFT,
+            },
+            Message::Checksum {
+                frame: 60,
+                checksum: 0xDEAD_BEEF,
+            },
+        ] {
+            assert_eq!(Message::from_bytes(&message.to_bytes()), Some(message));
+        }
+    }
+
+    #[test]
+    fn rejects_bad_messages() {
+        let mut bytes = Message::Checksum {
+            frame: 1,
+            checksum: 2,
+        }
+        .to_bytes();
+        assert_eq!(Message::from_bytes(&bytes[..MESSAGE_SIZE - 1]), None);
+        bytes[0] = 0xFF;
+        assert_eq!(Message::from_bytes(&bytes), None);
+        // there are only two controller ports
+        bytes[0] = INPUT_TAG;
+        bytes[9] = 2;
+        assert_eq!(Message::from_bytes(&bytes), None);
+    }
+
+    #[test]
+    fn waits_for_both_inputs() {
+        let mut lockstep = Lockstep::new(0, 0);
+        assert!(!lockstep.is_ready());
+        lockstep.add_local_input(Buttons::START);
+        assert!(!lockstep.is_ready());
+        // inputs for the local port, or from the past, don't count
+        lockstep.receive(Message::Input {
+            frame: 0,
+            port: 0,
+            buttons: Buttons::empty(),
+        });
+        assert!(!lockstep.is_ready());
+        lockstep.receive(Message::Input {
+            frame: 0,
+            port: 1,
+            buttons: Buttons::empty(),
+        });
+        assert!(lockstep.is_ready());
+    }
+}
@@ -0,0 +1,330 @@
+//! Applying IPS and BPS patches to ROMs
+//!
+//! Patches apply to the whole ROM file, iNES header included, which is how
+//! ROM hacks are distributed. The format is detected from the patch's magic
+//! number.
+
+use alloc::vec::Vec;
+
+use crate::checksum::crc32;
+use crate::error::{Error, Result};
+
+const IPS_MAGIC: &[u8] = b"PATCH";
+const IPS_EOF: &[u8] = b"EOF";
+const BPS_MAGIC: &[u8] = b"BPS1";
+
+/// Apply an IPS or BPS patch to a ROM, returning the patched ROM
+pub fn apply_patch(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>> {
+    if patch.starts_with(IPS_MAGIC) {
+        apply_ips(rom, patch)
+    } else if patch.starts_with(BPS_MAGIC) {
+        apply_bps(rom, patch)
+    } else {
+        Err(Error::InvalidPatch)
+    }
+}
+
+/// A cursor over the bytes of a patch, where running off the end is an error
+struct PatchReader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> PatchReader<'a> {
+    fn new(buf: &'a [u8], pos: usize) -> PatchReader<'a> {
+        PatchReader { buf, pos }
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8]> {
+        let end = self.pos.checked_add(len).ok_or(Error::InvalidPatch)?;
+        let bytes = self.buf.get(self.pos..end).ok_or(Error::InvalidPatch)?;
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    /// Read a big-endian number, `len` bytes long
+    fn be(&mut self, len: usize) -> Result<usize> {
+        Ok(self
+            .bytes(len)?
+            .iter()
+            .fold(0, |acc, &byte| (acc << 8) | byte as usize))
+    }
+
+    /// Read one of BPS's variable-length numbers
+    fn varint(&mut self) -> Result<usize> {
+        let mut data: usize = 0;
+        let mut shift: usize = 1;
+        loop {
+            let byte = self.u8()?;
+            let part = ((byte & 0x7F) as usize)
+                .checked_mul(shift)
+                .ok_or(Error::InvalidPatch)?;
+            data = data.checked_add(part).ok_or(Error::InvalidPatch)?;
+            if byte & 0x80 != 0 {
+                return Ok(data);
+            }
+            shift = shift.checked_shl(7).ok_or(Error::InvalidPatch)?;
+            data = data.checked_add(shift).ok_or(Error::InvalidPatch)?;
+        }
+    }
+}
+
+/// Apply an International Patching System patch
+///
+/// IPS is a list of (offset, bytes) records, plus a run-length encoded form
+/// for filling with a single byte, and an optional size to truncate to at the
+/// end. Records past the end of the ROM make it bigger.
+fn apply_ips(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>> {
+    let mut out = rom.to_vec();
+    let mut reader = PatchReader::new(patch, IPS_MAGIC.len());
+    loop {
+        let tag = reader.bytes(3)?;
+        if tag == IPS_EOF {
+            break;
+        }
+        let offset = tag.iter().fold(0, |acc, &byte| (acc << 8) | byte as usize);
+        let len = reader.be(2)?;
+        if len == 0 {
+            let run = reader.be(2)?;
+            let value = reader.u8()?;
+            if out.len() < offset + run {
+                out.resize(offset + run, 0);
+            }
+            out[offset..offset + run].fill(value);
+        } else {
+            let data = reader.bytes(len)?;
+            if out.len() < offset + len {
+                out.resize(offset + len, 0);
+            }
+            out[offset..offset + len].copy_from_slice(data);
+        }
+    }
+    // some patches end with the size to truncate the ROM to
+    if let Ok(size) = reader.be(3) {
+        out.truncate(size);
+    }
+    Ok(out)
+}
+
+/// Apply a BPS (beat) patch
+///
+/// BPS patches carry CRCs of the source ROM, the patched ROM, and the patch
+/// itself, so unlike IPS it's an error to apply one to the wrong ROM.
+fn apply_bps(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>> {
+    // magic, 3 sizes of at least 1 byte each, and 3 CRCs
+    if patch.len() < BPS_MAGIC.len() + 3 + 12 {
+        return Err(Error::InvalidPatch);
+    }
+    let footer = patch.len() - 12;
+    let read_crc =
+        |at: usize| u32::from_le_bytes([patch[at], patch[at + 1], patch[at + 2], patch[at + 3]]);
+    let (source_crc, target_crc, patch_crc) =
+        (read_crc(footer), read_crc(footer + 4), read_crc(footer + 8));
+    if crc32(&patch[..footer + 8]) != patch_crc {
+        return Err(Error::InvalidPatch);
+    }
+    let actual_crc = crc32(rom);
+    if actual_crc != source_crc {
+        return Err(Error::PatchSourceMismatch {
+            expected: source_crc,
+            actual: actual_crc,
+        });
+    }
+
+    let mut reader = PatchReader::new(&patch[..footer], BPS_MAGIC.len());
+    let source_size = reader.varint()?;
+    let target_size = reader.varint()?;
+    let metadata_size = reader.varint()?;
+    reader.bytes(metadata_size)?;
+    if source_size != rom.len() {
+        return Err(Error::InvalidPatch);
+    }
+
+    let mut out = Vec::with_capacity(target_size);
+    let mut source_offset: usize = 0;
+    let mut target_offset: usize = 0;
+    while reader.pos < footer {
+        let data = reader.varint()?;
+        let len = (data >> 2) + 1;
+        if out.len() + len > target_size {
+            return Err(Error::InvalidPatch);
+        }
+        match data & 0x03 {
+            // SourceRead: copy from the same offset in the source
+            0 => {
+                let start = out.len();
+                out.extend_from_slice(rom.get(start..start + len).ok_or(Error::InvalidPatch)?);
+            }
+            // TargetRead: copy from the patch itself
+            1 => out.extend_from_slice(reader.bytes(len)?),
+            // SourceCopy: copy from anywhere in the source
+            2 => {
+                source_offset = relative_offset(source_offset, reader.varint()?)?;
+                let bytes = rom
+                    .get(source_offset..source_offset + len)
+                    .ok_or(Error::InvalidPatch)?;
+                out.extend_from_slice(bytes);
+                source_offset += len;
+            }
+            // TargetCopy: copy from earlier in the output, which may overlap
+            // what's being written (so this has to go a byte at a time)
+            _ => {
+                target_offset = relative_offset(target_offset, reader.varint()?)?;
+                for _ in 0..len {
+                    let byte = *out.get(target_offset).ok_or(Error::InvalidPatch)?;
+                    out.push(byte);
+                    target_offset += 1;
+                }
+            }
+        }
+    }
+    if out.len() != target_size || crc32(&out) != target_crc {
+        return Err(Error::InvalidPatch);
+    }
+    Ok(out)
+}
+
+/// Move a BPS copy offset, which is stored as a sign bit and a magnitude
+fn relative_offset(offset: usize, data: usize) -> Result<usize> {
+    let delta = data >> 1;
+    if data & 1 == 1 {
+        offset.checked_sub(delta)
+    } else {
+        offset.checked_add(delta)
+    }
+    .ok_or(Error::InvalidPatch)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode_varint(mut data: usize, out: &mut Vec<u8>) {
+        loop {
+            let x = (data & 0x7F) as u8;
+            data >>= 7;
+            if data == 0 {
+                out.push(0x80 | x);
+                return;
+            }
+            out.push(x);
+            data -= 1;
+        }
+    }
+
+    /// Finish a BPS patch by adding the CRCs
+    fn seal_bps(mut patch: Vec<u8>, source: &[u8], target: &[u8]) -> Vec<u8> {
+        patch.extend_from_slice(&crc32(source).to_le_bytes());
+        patch.extend_from_slice(&crc32(target).to_le_bytes());
+        let patch_crc = crc32(&patch);
+        patch.extend_from_slice(&patch_crc.to_le_bytes());
+        patch
+    }
+
+    #[test]
+    fn reads_varints() {
+        for &value in &[0usize, 1, 127, 128, 300, 16_511, 16_512, 1 << 20] {
+            let mut buf = Vec::new();
+            encode_varint(value, &mut buf);
+            assert_eq!(PatchReader::new(&buf, 0).varint().unwrap(), value);
+        }
+    }
+
+    #[test]
+    fn applies_ips_records() {
+        let rom = vec![0u8; 8];
+        let mut patch = IPS_MAGIC.to_vec();
+        // write 2 bytes at $000002
+        patch.extend_from_slice(&[0x00, 0x00, 0x02, 0x00, 0x02, 0xAA, 0xBB]);
+        // fill 4 bytes at $000006 with $CC, growing the ROM
+        patch.extend_from_slice(&[0x00, 0x00, 0x06, 0x00, 0x00, 0x00, 0x04, 0xCC]);
+        patch.extend_from_slice(IPS_EOF);
+        let out = apply_patch(&rom, &patch).unwrap();
+        assert_eq!(out, vec![0, 0, 0xAA, 0xBB, 0, 0, 0xCC, 0xCC, 0xCC, 0xCC]);
+    }
+
+    #[test]
+    fn truncates_with_ips() {
+        let rom = vec![1u8; 8];
+        let mut patch = IPS_MAGIC.to_vec();
+        patch.extend_from_slice(IPS_EOF);
+        patch.extend_from_slice(&[0x00, 0x00, 0x04]);
+        assert_eq!(apply_patch(&rom, &patch).unwrap(), vec![1u8; 4]);
+    }
+
+    #[test]
+    fn rejects_truncated_ips() {
+        let mut patch = IPS_MAGIC.to_vec();
+        patch.extend_from_slice(&[0x00, 0x00, 0x02, 0x00, 0x04, 0xAA]);
+        assert!(matches!(
+            apply_patch(&[0; 8], &patch),
+            Err(Error::InvalidPatch)
+        ));
+    }
+
+    /// A BPS patch that uses every kind of action
+    fn make_bps(source: &[u8], target: &[u8]) -> Vec<u8> {
+        let mut patch = BPS_MAGIC.to_vec();
+        encode_varint(source.len(), &mut patch);
+        encode_varint(target.len(), &mut patch);
+        let metadata = b"<test/>";
+        encode_varint(metadata.len(), &mut patch);
+        patch.extend_from_slice(metadata);
+        // SourceRead 2 bytes
+        encode_varint((2 - 1) << 2, &mut patch);
+        // TargetRead 2 bytes
+        encode_varint(((2 - 1) << 2) | 1, &mut patch);
+        patch.extend_from_slice(&[0xAA, 0xBB]);
+        // SourceCopy 2 bytes from offset 6
+        encode_varint(((2 - 1) << 2) | 2, &mut patch);
+        encode_varint(6 << 1, &mut patch);
+        // TargetCopy 3 bytes from offset 2, which overlaps the output
+        encode_varint(((3 - 1) << 2) | 3, &mut patch);
+        encode_varint(2 << 1, &mut patch);
+        seal_bps(patch, source, target)
+    }
+
+    #[test]
+    fn applies_bps_actions() {
+        let source = [0u8, 1, 2, 3, 4, 5, 6, 7];
+        let target = [0u8, 1, 0xAA, 0xBB, 6, 7, 0xAA, 0xBB, 6];
+        let patch = make_bps(&source, &target);
+        assert_eq!(apply_patch(&source, &patch).unwrap(), target.to_vec());
+    }
+
+    #[test]
+    fn rejects_bps_for_the_wrong_rom() {
+        let source = [0u8, 1, 2, 3, 4, 5, 6, 7];
+        let target = [0u8, 1, 0xAA, 0xBB, 6, 7, 0xAA, 0xBB, 6];
+        let patch = make_bps(&source, &target);
+        let other = [9u8; 8];
+        assert!(matches!(
+            apply_patch(&other, &patch),
+            Err(Error::PatchSourceMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn rejects_corrupt_bps() {
+        let source = [0u8, 1, 2, 3, 4, 5, 6, 7];
+        let target = [0u8, 1, 0xAA, 0xBB, 6, 7, 0xAA, 0xBB, 6];
+        let mut patch = make_bps(&source, &target);
+        patch[BPS_MAGIC.len() + 5] ^= 0xFF;
+        assert!(matches!(
+            apply_patch(&source, &patch),
+            Err(Error::InvalidPatch)
+        ));
+    }
+
+    #[test]
+    fn rejects_unknown_formats() {
+        assert!(matches!(
+            apply_patch(&[0; 8], b"UPS1"),
+            Err(Error::InvalidPatch)
+        ));
+    }
+}
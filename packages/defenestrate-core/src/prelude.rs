@@ -0,0 +1,17 @@
+//! The types most embedders need, for glob importing
+//!
+//! `use defenestrate_core::prelude::*;` brings in enough to load a game, run
+//! it frame by frame with input, and handle errors. Everything here is part
+//! of the stable embedding API, along with the public modules it comes from.
+//!
+//! `Result` is left out, so that it doesn't shadow the one in `std`. It's at
+//! `defenestrate_core::Result`.
+
+pub use crate::devices::cpu::{Cpu6502, Motherboard, WithCpu};
+#[cfg(feature = "console")]
+pub use crate::devices::nes::{
+    Breakpoint, Buttons, Console, DebugSnapshot, Nes, NesConfig, Palette, RamPattern, Region,
+    SaveState, FRAME_SIZE,
+};
+pub use crate::error::Error;
+pub use crate::video::FrameInfo;
@@ -0,0 +1,218 @@
+//! Recording gameplay footage
+//!
+//! A recording is a stream of frames, and the audio that goes with them,
+//! written to a `Sink`, which decides what container (if any) they end up in. With the `std` feature, this module
+//! provides sinks for raw RGB frames and for Y4M video, which most video tools
+//! (ffmpeg, mpv, etc.) can read directly.
+//!
+//! To record, hand a sink to `Nes::start_recording`, and call
+//! `Nes::stop_recording` when done.
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+
+use crate::error::{Error, Result};
+
+/// Somewhere to send recorded frames
+///
+/// Sinks have to be `Send`, since they're owned by the `Nes`.
+pub trait Sink: Send {
+    /// Write one frame, as 256x240 8-bit RGB
+    fn write_frame(&mut self, rgb: &[u8]) -> Result<()>;
+
+    /// Write the audio samples that go with the last frame
+    ///
+    /// This comes right after each `write_frame`, with the mono samples made
+    /// during that frame, at the sample rate in the console's `NesConfig`.
+    /// With audio turned off, it's never called.
+    fn write_audio(&mut self, _samples: &[f32]) -> Result<()> {
+        Ok(())
+    }
+
+    /// Flush anything buffered, once the recording is over
+    fn finish(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// A recording in progress
+pub(crate) struct Recorder {
+    sink: Box<dyn Sink>,
+    frames: u64,
+    /// The samples made since the last frame
+    audio: Vec<f32>,
+    /// The first error from the sink, after which nothing else is written
+    error: Option<Error>,
+}
+
+impl Recorder {
+    pub fn new(sink: Box<dyn Sink>) -> Recorder {
+        Recorder {
+            sink,
+            frames: 0,
+            audio: Vec::new(),
+            error: None,
+        }
+    }
+
+    pub fn record_frame(&mut self, rgb: &[u8]) {
+        if self.error.is_some() {
+            return;
+        }
+        let mut result = self.sink.write_frame(rgb);
+        if result.is_ok() && !self.audio.is_empty() {
+            result = self.sink.write_audio(&self.audio);
+            self.audio.clear();
+        }
+        match result {
+            Ok(()) => self.frames += 1,
+            Err(err) => self.error = Some(err),
+        }
+    }
+
+    /// Hold on to an audio sample, until the frame it's part of is finished
+    pub fn record_sample(&mut self, sample: f32) {
+        if self.error.is_none() {
+            self.audio.push(sample);
+        }
+    }
+
+    /// End the recording, returning the number of frames written
+    pub fn finish(mut self) -> Result<u64> {
+        if let Some(err) = self.error {
+            return Err(err);
+        }
+        self.sink.finish()?;
+        Ok(self.frames)
+    }
+}
+
+#[cfg(feature = "std")]
+pub use self::writers::{RawWriter, Y4mWriter};
+
+#[cfg(feature = "std")]
+mod writers {
+    use std::io::Write;
+
+    use super::Sink;
+    use crate::error::Result;
+
+    /// The NTSC frame rate, as a fraction
+    ///
+    /// The NES runs at 1.789773MHz / 29780.5 CPU cycles per frame, which works
+    /// out to about 60.0988 frames per second.
+    const FRAME_RATE: (u32, u32) = (39_375_000, 655_171);
+
+    /// A sink that writes frames one after another, as raw 8-bit RGB
+    ///
+    /// There's no header, so whatever reads this needs to be told the frame
+    /// size (256x240) and pixel format (rgb24).
+    pub struct RawWriter<W: Write> {
+        out: W,
+    }
+
+    impl<W: Write> RawWriter<W> {
+        pub fn new(out: W) -> RawWriter<W> {
+            RawWriter { out }
+        }
+    }
+
+    impl<W: Write + Send> Sink for RawWriter<W> {
+        fn write_frame(&mut self, rgb: &[u8]) -> Result<()> {
+            self.out.write_all(rgb)?;
+            Ok(())
+        }
+
+        fn finish(&mut self) -> Result<()> {
+            self.out.flush()?;
+            Ok(())
+        }
+    }
+
+    /// A sink that writes a YUV4MPEG2 (Y4M) video stream
+    ///
+    /// Frames are converted to 4:4:4 YCbCr, using the BT.601 coefficients.
+    pub struct Y4mWriter<W: Write> {
+        out: W,
+        wrote_header: bool,
+        /// Scratch space for the Y, Cb, and Cr planes
+        planes: Vec<u8>,
+    }
+
+    impl<W: Write> Y4mWriter<W> {
+        pub fn new(out: W) -> Y4mWriter<W> {
+            Y4mWriter {
+                out,
+                wrote_header: false,
+                planes: Vec::new(),
+            }
+        }
+    }
+
+    /// Convert an RGB pixel to limited-range BT.601 YCbCr
+    pub(super) fn rgb_to_ycbcr(r: u8, g: u8, b: u8) -> (u8, u8, u8) {
+        let (r, g, b) = (r as i32, g as i32, b as i32);
+        let y = ((66 * r + 129 * g + 25 * b + 128) >> 8) + 16;
+        let cb = ((-38 * r - 74 * g + 112 * b + 128) >> 8) + 128;
+        let cr = ((112 * r - 94 * g - 18 * b + 128) >> 8) + 128;
+        (y as u8, cb as u8, cr as u8)
+    }
+
+    impl<W: Write + Send> Sink for Y4mWriter<W> {
+        fn write_frame(&mut self, rgb: &[u8]) -> Result<()> {
+            if !self.wrote_header {
+                writeln!(
+                    self.out,
+                    "YUV4MPEG2 W256 H240 F{}:{} Ip A1:1 C444",
+                    FRAME_RATE.0, FRAME_RATE.1
+                )?;
+                self.wrote_header = true;
+            }
+            let pixels = rgb.len() / 3;
+            self.planes.resize(pixels * 3, 0);
+            for (i, pixel) in rgb.chunks_exact(3).enumerate() {
+                let (y, cb, cr) = rgb_to_ycbcr(pixel[0], pixel[1], pixel[2]);
+                self.planes[i] = y;
+                self.planes[pixels + i] = cb;
+                self.planes[pixels * 2 + i] = cr;
+            }
+            self.out.write_all(b"FRAME\n")?;
+            self.out.write_all(&self.planes)?;
+            Ok(())
+        }
+
+        fn finish(&mut self) -> Result<()> {
+            self.out.flush()?;
+            Ok(())
+        }
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::writers::rgb_to_ycbcr;
+    use super::*;
+
+    #[test]
+    fn converts_black_and_white() {
+        assert_eq!(rgb_to_ycbcr(0, 0, 0), (16, 128, 128));
+        assert_eq!(rgb_to_ycbcr(255, 255, 255), (235, 128, 128));
+    }
+
+    #[test]
+    fn writes_y4m_frames() {
+        let mut out = Vec::new();
+        {
+            let mut writer = Y4mWriter::new(&mut out);
+            let frame = vec![0u8; 256 * 240 * 3];
+            writer.write_frame(&frame).unwrap();
+            writer.write_frame(&frame).unwrap();
+            writer.finish().unwrap();
+        }
+        let header = b"YUV4MPEG2 W256 H240 F39375000:655171 Ip A1:1 C444\n";
+        assert!(out.starts_with(header));
+        let frame_len = b"FRAME\n".len() + 256 * 240 * 3;
+        assert_eq!(out.len(), header.len() + frame_len * 2);
+        assert!(out[header.len() + frame_len..].starts_with(b"FRAME\n"));
+    }
+}
@@ -0,0 +1,89 @@
+//! Helpers for (de)serializing types that serde can't derive impls for
+//!
+//! This module only exists with the `serde` feature enabled.
+
+use core::fmt;
+
+use serde::de::{Deserializer, Error, SeqAccess, Visitor};
+use serde::ser::Serializer;
+
+/// Implement `Serialize` and `Deserialize` for a bitflags struct, as its bits
+///
+/// Unknown bits are dropped on the way back in, same as `from_bits_truncate`.
+macro_rules! serde_bitflags {
+    ($flags: ty, $bits: ty) => {
+        impl serde::Serialize for $flags {
+            fn serialize<S: serde::Serializer>(
+                &self,
+                serializer: S,
+            ) -> core::result::Result<S::Ok, S::Error> {
+                serde::Serialize::serialize(&self.bits(), serializer)
+            }
+        }
+
+        impl<'de> serde::Deserialize<'de> for $flags {
+            fn deserialize<D: serde::Deserializer<'de>>(
+                deserializer: D,
+            ) -> core::result::Result<Self, D::Error> {
+                let bits = <$bits as serde::Deserialize>::deserialize(deserializer)?;
+                Ok(<$flags>::from_bits_truncate(bits))
+            }
+        }
+    };
+}
+
+pub(crate) use serde_bitflags;
+
+/// (De)serialize a byte array of any length, for use with `#[serde(with)]`
+///
+/// Serde only implements its traits for arrays of up to 32 elements, and the
+/// PPU has a few that are much bigger than that.
+pub mod byte_array {
+    use super::*;
+
+    pub fn serialize<S: Serializer, const N: usize>(
+        array: &[u8; N],
+        serializer: S,
+    ) -> core::result::Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(array)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>, const N: usize>(
+        deserializer: D,
+    ) -> Result<[u8; N], D::Error> {
+        deserializer.deserialize_bytes(ByteArrayVisitor::<N>)
+    }
+
+    struct ByteArrayVisitor<const N: usize>;
+
+    impl<'de, const N: usize> Visitor<'de> for ByteArrayVisitor<N> {
+        type Value = [u8; N];
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "an array of {} bytes", N)
+        }
+
+        fn visit_bytes<E: Error>(self, bytes: &[u8]) -> Result<[u8; N], E> {
+            let mut array = [0u8; N];
+            if bytes.len() != N {
+                return Err(E::invalid_length(bytes.len(), &self));
+            }
+            array.copy_from_slice(bytes);
+            Ok(array)
+        }
+
+        // self-describing formats like JSON write bytes out as a sequence
+        fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<[u8; N], A::Error> {
+            let mut array = [0u8; N];
+            for (i, byte) in array.iter_mut().enumerate() {
+                *byte = seq
+                    .next_element()?
+                    .ok_or_else(|| A::Error::invalid_length(i, &self))?;
+            }
+            if seq.next_element::<u8>()?.is_some() {
+                return Err(A::Error::invalid_length(N + 1, &self));
+            }
+            Ok(array)
+        }
+    }
+}
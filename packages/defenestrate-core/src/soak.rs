@@ -0,0 +1,288 @@
+//! Running ROMs for a long time to shake out panics, with the `std` feature
+//! enabled
+//!
+//! Parts of the core still `panic!` on states they don't expect, like a write
+//! to a PPU port that can't exist. Most of those only turn up after a game
+//! has been running for a while, so `soak` runs a console for thousands of
+//! frames, and catches any panic along with the frame it happened on and
+//! where the CPU and PPU were. `report_json` writes up a batch of runs, for
+//! CI or a bug report.
+//!
+//! Runs are deterministic: `soak_rom` starts every ROM from the same power-on
+//! state, with no buttons pressed, so a panic found here happens again on the
+//! same frame every time.
+
+use std::any::Any;
+use std::fmt::Write;
+use std::panic::{self, AssertUnwindSafe};
+
+use crate::devices::cpu::structs::CpuState;
+use crate::devices::nes::{Nes, NesConfig, PpuDebugView};
+use crate::error;
+
+/// Where the console was when it panicked
+#[derive(Debug, Clone, PartialEq)]
+pub struct SoakPanic {
+    pub message: String,
+    /// The frame being run, counted from 1 like `Nes::frame_count`
+    pub frame: u64,
+    /// The CPU, as the panic left it
+    pub cpu: CpuState,
+    /// The PPU, as the panic left it
+    pub ppu: PpuDebugView,
+}
+
+/// How a ROM fared in a soak run
+#[derive(Debug, Clone, PartialEq)]
+pub enum SoakOutcome {
+    /// The ROM couldn't be loaded, so nothing ran
+    LoadFailed(String),
+    /// Loading the ROM panicked, with this message, so nothing ran
+    LoadPanicked(String),
+    Panicked(Box<SoakPanic>),
+    /// Every frame ran, and the last one had this `Nes::frame_hash`
+    Finished {
+        frame_hash: u64,
+    },
+}
+
+/// One ROM's soak run, for `report_json`
+#[derive(Debug, Clone, PartialEq)]
+pub struct SoakResult {
+    /// What to call the ROM in the report, like its file name
+    pub name: String,
+    /// How many frames the ROM was meant to run for
+    pub frames: u64,
+    pub outcome: SoakOutcome,
+}
+
+impl SoakResult {
+    /// Whether the core panicked, either while loading the ROM or running it
+    pub fn panicked(&self) -> bool {
+        matches!(
+            self.outcome,
+            SoakOutcome::Panicked(_) | SoakOutcome::LoadPanicked(_)
+        )
+    }
+}
+
+/// Run `frames` more frames, stopping at the first panic
+///
+/// Returns the hash of the last frame if nothing panicked. After a panic,
+/// `nes` is left as the panic found it, which may be partway through an
+/// instruction, so it's only good for inspecting. Its trace log, if it has
+/// one, is dumped.
+pub fn soak(nes: &mut Nes, frames: u64) -> Result<u64, SoakPanic> {
+    for _ in 0..frames {
+        let frame = nes.frame_count() + 1;
+        let ran = panic::catch_unwind(AssertUnwindSafe(|| {
+            nes.tick_frame();
+        }));
+        if let Err(payload) = ran {
+            // the console outlives the panic, so it won't dump on its own
+            nes.dump_trace_log();
+            return Err(SoakPanic {
+                message: panic_message(payload.as_ref()),
+                frame,
+                cpu: nes.debug_snapshot().cpu,
+                ppu: nes.ppu_debug_state(),
+            });
+        }
+    }
+    Ok(nes.frame_hash())
+}
+
+/// The message a panic was raised with
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(msg) = payload.downcast_ref::<&str>() {
+        msg.to_string()
+    } else if let Some(msg) = payload.downcast_ref::<String>() {
+        msg.clone()
+    } else {
+        "(non-string panic payload)".to_string()
+    }
+}
+
+/// Load an iNES ROM with the default config, and `soak` it from power-on
+///
+/// The loader gets the same treatment as the frames, so a ROM that panics
+/// the core as it's loaded is reported as `SoakOutcome::LoadPanicked`.
+pub fn soak_rom(name: &str, rom: &[u8], frames: u64) -> SoakResult {
+    soak_loaded(name, frames, || {
+        Nes::new_from_buf_with_config(rom, NesConfig::default())
+    })
+}
+
+/// `soak` whatever `load` gives back, catching any panic while it loads
+fn soak_loaded<F: FnOnce() -> error::Result<Nes>>(name: &str, frames: u64, load: F) -> SoakResult {
+    let outcome = match panic::catch_unwind(AssertUnwindSafe(load)) {
+        Err(payload) => SoakOutcome::LoadPanicked(panic_message(payload.as_ref())),
+        Ok(Err(err)) => SoakOutcome::LoadFailed(err.to_string()),
+        Ok(Ok(mut nes)) => match soak(&mut nes, frames) {
+            Ok(frame_hash) => SoakOutcome::Finished { frame_hash },
+            Err(panicked) => SoakOutcome::Panicked(Box::new(panicked)),
+        },
+    };
+    SoakResult {
+        name: name.to_string(),
+        frames,
+        outcome,
+    }
+}
+
+/// Write up a batch of soak runs as JSON
+///
+/// ```text
+/// {
+///   "total": 2,
+///   "panicked": 1,
+///   "roms": [
+///     {"name": "a.nes", "frames": 10000, "outcome": "finished", "frame_hash": "0123456789abcdef"},
+///     {"name": "b.nes", "frames": 10000, "outcome": "panicked", "panic": {
+///       "message": "...", "frame": 1234,
+///       "cpu": {"pc": 49152, "a": 0, "x": 0, "y": 0, "sp": 253, "status": 36, "cycles": 7},
+///       "ppu": {"scanline": 0, "dot": 0, "control": 0, "mask": 0, "status": 0, "v": 0, "t": 0, "fine_x": 0}
+///     }}
+///   ]
+/// }
+/// ```
+///
+/// Load failures have `"outcome": "load_failed"` and an `"error"`, and panics
+/// while loading have `"outcome": "load_panicked"` and a `"message"`. Frame
+/// hashes are hex strings, since JSON numbers can't hold all 64 bits.
+pub fn report_json(results: &[SoakResult]) -> String {
+    let panicked = results.iter().filter(|result| result.panicked()).count();
+    let mut out = String::new();
+    out.push_str("{\n");
+    let _ = writeln!(out, "  \"total\": {},", results.len());
+    let _ = writeln!(out, "  \"panicked\": {},", panicked);
+    out.push_str("  \"roms\": [");
+    for (idx, result) in results.iter().enumerate() {
+        out.push_str(if idx == 0 { "\n    {" } else { ",\n    {" });
+        let _ = write!(
+            out,
+            "\"name\": {}, \"frames\": {}, ",
+            json_string(&result.name),
+            result.frames
+        );
+        match &result.outcome {
+            SoakOutcome::LoadFailed(err) => {
+                let _ = write!(
+                    out,
+                    "\"outcome\": \"load_failed\", \"error\": {}",
+                    json_string(err)
+                );
+            }
+            SoakOutcome::LoadPanicked(message) => {
+                let _ = write!(
+                    out,
+                    "\"outcome\": \"load_panicked\", \"message\": {}",
+                    json_string(message)
+                );
+            }
+            SoakOutcome::Finished { frame_hash } => {
+                let _ = write!(
+                    out,
+                    "\"outcome\": \"finished\", \"frame_hash\": \"{:016x}\"",
+                    frame_hash
+                );
+            }
+            SoakOutcome::Panicked(panicked) => {
+                let (cpu, ppu) = (&panicked.cpu, &panicked.ppu);
+                let _ = write!(
+                    out,
+                    "\"outcome\": \"panicked\", \"panic\": {{\"message\": {}, \"frame\": {}, \
+                     \"cpu\": {{\"pc\": {}, \"a\": {}, \"x\": {}, \"y\": {}, \"sp\": {}, \
+                     \"status\": {}, \"cycles\": {}}}, \
+                     \"ppu\": {{\"scanline\": {}, \"dot\": {}, \"control\": {}, \"mask\": {}, \
+                     \"status\": {}, \"v\": {}, \"t\": {}, \"fine_x\": {}}}}}",
+                    json_string(&panicked.message),
+                    panicked.frame,
+                    cpu.pc,
+                    cpu.acc,
+                    cpu.x,
+                    cpu.y,
+                    cpu.stack,
+                    cpu.status.bits(),
+                    cpu.tot_cycles,
+                    ppu.scanline,
+                    ppu.dot,
+                    ppu.control,
+                    ppu.mask,
+                    ppu.status,
+                    ppu.v,
+                    ppu.t,
+                    ppu.fine_x
+                );
+            }
+        }
+        out.push('}');
+    }
+    out.push_str(if results.is_empty() { "]\n" } else { "\n  ]\n" });
+    out.push_str("}\n");
+    out
+}
+
+/// Quote and escape a string for JSON
+fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escapes_json_strings() {
+        assert_eq!(json_string("plain"), "\"plain\"");
+        assert_eq!(
+            json_string("a \"quote\"\\\n\u{1}"),
+            "\"a \\\"quote\\\"\\\\\\n\\u0001\""
+        );
+    }
+
+    #[test]
+    fn reports_load_failures() {
+        let result = soak_rom("junk.nes", b"not a ROM", 10);
+        assert!(matches!(result.outcome, SoakOutcome::LoadFailed(_)));
+        let report = report_json(&[result]);
+        assert!(report.contains("\"total\": 1,"));
+        assert!(report.contains("\"panicked\": 0,"));
+        assert!(
+            report.contains("\"name\": \"junk.nes\", \"frames\": 10, \"outcome\": \"load_failed\"")
+        );
+        assert_eq!(
+            report_json(&[]),
+            "{\n  \"total\": 0,\n  \"panicked\": 0,\n  \"roms\": []\n}\n"
+        );
+    }
+
+    #[test]
+    fn catches_panics_while_loading() {
+        let result = soak_loaded("cursed.nes", 10, || panic!("Cursed header"));
+        assert_eq!(
+            result.outcome,
+            SoakOutcome::LoadPanicked("Cursed header".to_string())
+        );
+        assert!(result.panicked());
+        let report = report_json(&[result]);
+        assert!(report.contains("\"panicked\": 1,"));
+        assert!(report.contains("\"outcome\": \"load_panicked\", \"message\": \"Cursed header\""));
+    }
+}
@@ -0,0 +1,237 @@
+//! Frame pacing statistics, for performance HUDs and diagnosing stutter
+//!
+//! The core has no clock of its own, so the runner (whatever calls
+//! `Nes::tick_frame`) reports the host side: when each frame was presented,
+//! how long emulating it took, and how full its audio buffer is. The `Nes`
+//! counts emulated frames itself. Everything is kept over a rolling window of
+//! the last couple of seconds, apart from the dropped frame count, which is a
+//! running total.
+//!
+//! To use it, call `Nes::telemetry_mut().record_host_frame(..)` once per
+//! presented frame, and read the results from `Nes::telemetry()`.
+
+use alloc::{collections::VecDeque, vec::Vec};
+
+/// How many host frames the statistics are worked out over
+const WINDOW: usize = 120;
+
+/// How long an NTSC frame lasts, in milliseconds
+const NTSC_FRAME_MS: f64 = 1000.0 / 60.0988;
+
+/// How late a host frame has to be, in NTSC frames, to count as dropping one
+///
+/// This leaves some slack for timer jitter, so a frame that's a millisecond
+/// late isn't counted.
+const DROP_THRESHOLD: f64 = 1.5;
+
+/// One presented frame, as reported by the runner
+#[derive(Debug, Copy, Clone)]
+struct HostFrame {
+    /// When the frame was presented, in milliseconds from any fixed point
+    timestamp_ms: f64,
+    /// How long the runner spent emulating this frame, in milliseconds
+    frame_time_ms: f64,
+    /// How many frames the PPU finished since the last host frame
+    emulated_frames: u32,
+}
+
+/// Frame pacing statistics, from `Nes::telemetry`
+#[derive(Debug, Clone)]
+pub struct Telemetry {
+    frames: VecDeque<HostFrame>,
+    /// Emulated frames since the last host frame was recorded
+    pending_emulated_frames: u32,
+    audio_buffer_fill: Option<f32>,
+    dropped_frames: u64,
+}
+
+impl Telemetry {
+    pub fn new() -> Telemetry {
+        Telemetry {
+            frames: VecDeque::with_capacity(WINDOW),
+            pending_emulated_frames: 0,
+            audio_buffer_fill: None,
+            dropped_frames: 0,
+        }
+    }
+
+    /// Record a frame presented by the host
+    ///
+    /// `timestamp_ms` is when it was presented, from any clock that only
+    /// goes forward (like `performance.now()`), and `frame_time_ms` is how
+    /// long it took to emulate.
+    pub fn record_host_frame(&mut self, timestamp_ms: f64, frame_time_ms: f64) {
+        if let Some(last) = self.frames.back() {
+            let late_by = (timestamp_ms - last.timestamp_ms) / NTSC_FRAME_MS;
+            if late_by >= DROP_THRESHOLD {
+                // round to the nearest frame, less the one that was on time
+                self.dropped_frames += (late_by + 0.5) as u64 - 1;
+            }
+        }
+        if self.frames.len() == WINDOW {
+            self.frames.pop_front();
+        }
+        self.frames.push_back(HostFrame {
+            timestamp_ms,
+            frame_time_ms,
+            emulated_frames: self.pending_emulated_frames,
+        });
+        self.pending_emulated_frames = 0;
+    }
+
+    /// Record how full the host's audio buffer is, from 0 (empty) to 1 (full)
+    pub fn set_audio_buffer_fill(&mut self, fill: f32) {
+        self.audio_buffer_fill = Some(fill);
+    }
+
+    /// Count a frame finished by the PPU
+    pub(crate) fn record_emulated_frame(&mut self) {
+        self.pending_emulated_frames += 1;
+    }
+
+    /// The number of frames the PPU finished per second of host time
+    ///
+    /// This is `None` until at least two host frames have been recorded.
+    pub fn emulated_fps(&self) -> Option<f64> {
+        let emulated: u32 = self
+            .frames
+            .iter()
+            .skip(1)
+            .map(|frame| frame.emulated_frames)
+            .sum();
+        Some(emulated as f64 * 1000.0 / self.window_ms()?)
+    }
+
+    /// The number of frames the host presented per second
+    ///
+    /// This is `None` until at least two host frames have been recorded.
+    pub fn host_fps(&self) -> Option<f64> {
+        let window_ms = self.window_ms()?;
+        Some((self.frames.len() - 1) as f64 * 1000.0 / window_ms)
+    }
+
+    /// The last audio buffer fill level the host reported, if there was one
+    pub fn audio_buffer_fill(&self) -> Option<f32> {
+        self.audio_buffer_fill
+    }
+
+    /// The `percentile`th (0-100) emulation time per host frame, in
+    /// milliseconds
+    ///
+    /// This is `None` until a host frame has been recorded.
+    pub fn frame_time_percentile(&self, percentile: u8) -> Option<f64> {
+        if self.frames.is_empty() {
+            return None;
+        }
+        let mut times: Vec<f64> = self
+            .frames
+            .iter()
+            .map(|frame| frame.frame_time_ms)
+            .collect();
+        times.sort_by(|a, b| a.partial_cmp(b).unwrap_or(core::cmp::Ordering::Equal));
+        // nearest rank
+        let percentile = percentile.min(100) as usize;
+        let rank = (percentile * (times.len() - 1) + 50) / 100;
+        Some(times[rank])
+    }
+
+    /// The number of frames the host has dropped in total
+    ///
+    /// A frame counts as dropped when the host goes more than one and a half
+    /// NTSC frames without presenting one.
+    pub fn dropped_frames(&self) -> u64 {
+        self.dropped_frames
+    }
+
+    /// Forget everything recorded so far
+    pub fn clear(&mut self) {
+        *self = Telemetry::new();
+    }
+
+    /// The host time covered by the window, if it covers any
+    fn window_ms(&self) -> Option<f64> {
+        let span = self.frames.back()?.timestamp_ms - self.frames.front()?.timestamp_ms;
+        if span > 0.0 {
+            Some(span)
+        } else {
+            None
+        }
+    }
+}
+
+impl Default for Telemetry {
+    fn default() -> Telemetry {
+        Telemetry::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Record `count` host frames, `interval` ms apart, each with one
+    /// emulated frame that took `frame_time` ms
+    fn run(telemetry: &mut Telemetry, start: f64, count: usize, interval: f64, frame_time: f64) {
+        for i in 0..count {
+            telemetry.record_emulated_frame();
+            telemetry.record_host_frame(start + i as f64 * interval, frame_time);
+        }
+    }
+
+    #[test]
+    fn needs_two_frames_for_rates() {
+        let mut telemetry = Telemetry::new();
+        assert_eq!(telemetry.host_fps(), None);
+        assert_eq!(telemetry.frame_time_percentile(50), None);
+        telemetry.record_host_frame(0.0, 1.0);
+        assert_eq!(telemetry.host_fps(), None);
+        assert_eq!(telemetry.emulated_fps(), None);
+        assert_eq!(telemetry.frame_time_percentile(50), Some(1.0));
+    }
+
+    #[test]
+    fn measures_frame_rates() {
+        let mut telemetry = Telemetry::new();
+        run(&mut telemetry, 0.0, 11, 20.0, 1.0);
+        assert_eq!(telemetry.host_fps(), Some(50.0));
+        // two emulated frames per host frame, like a 120Hz emulator on 60Hz
+        for i in 0..10 {
+            telemetry.record_emulated_frame();
+            telemetry.record_emulated_frame();
+            telemetry.record_host_frame(200.0 + (i + 1) as f64 * 20.0, 1.0);
+        }
+        assert_eq!(telemetry.emulated_fps(), Some(75.0));
+    }
+
+    #[test]
+    fn finds_frame_time_percentiles() {
+        let mut telemetry = Telemetry::new();
+        for i in 0..101 {
+            telemetry.record_host_frame(i as f64 * 16.0, (100 - i) as f64);
+        }
+        assert_eq!(telemetry.frame_time_percentile(0), Some(0.0));
+        assert_eq!(telemetry.frame_time_percentile(50), Some(50.0));
+        assert_eq!(telemetry.frame_time_percentile(99), Some(99.0));
+        assert_eq!(telemetry.frame_time_percentile(100), Some(100.0));
+    }
+
+    #[test]
+    fn only_keeps_a_window_of_frames() {
+        let mut telemetry = Telemetry::new();
+        run(&mut telemetry, 0.0, WINDOW, 16.0, 50.0);
+        run(&mut telemetry, WINDOW as f64 * 16.0, WINDOW, 16.0, 1.0);
+        assert_eq!(telemetry.frame_time_percentile(100), Some(1.0));
+    }
+
+    #[test]
+    fn counts_dropped_frames() {
+        let mut telemetry = Telemetry::new();
+        run(&mut telemetry, 0.0, 10, NTSC_FRAME_MS, 1.0);
+        // a little jitter isn't a drop
+        telemetry.record_host_frame(10.0 * NTSC_FRAME_MS + 2.0, 1.0);
+        assert_eq!(telemetry.dropped_frames(), 0);
+        // but a gap of 3 frames is 2 dropped ones
+        telemetry.record_host_frame(13.0 * NTSC_FRAME_MS + 2.0, 1.0);
+        assert_eq!(telemetry.dropped_frames(), 2);
+    }
+}
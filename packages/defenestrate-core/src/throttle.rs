@@ -0,0 +1,189 @@
+//! Pacing a headless runner to real time
+//!
+//! The core never sleeps on its own, since browsers and game loops already
+//! have their own timing. Runners that just loop on `Nes::tick_frame`, like a
+//! CLI or a test harness, can call `Throttle::wait` after each frame to play
+//! back at the console's speed instead of as fast as the host can go.
+
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::devices::nes::{Region, MAX_SPEED, MIN_SPEED};
+use crate::devices::playback::scale;
+
+/// How far behind a throttle can fall, in frames, before it stops catching up
+///
+/// After a long stall, like the process being suspended, running flat out to
+/// make up the lost time would look like fast-forward, so the throttle starts
+/// counting from the late frame instead.
+const MAX_LAG_FRAMES: u32 = 4;
+
+/// Sleeps between frames to hold a runner to the console's frame rate
+///
+/// Frames are scheduled against a fixed start time rather than the end of the
+/// last sleep, so oversleeping on one frame is made up on the next ones and
+/// the rate doesn't drift.
+#[derive(Debug, Clone)]
+pub struct Throttle {
+    frame_duration: Duration,
+    /// How fast to run, as a multiple of real time
+    speed: f32,
+    uncapped: bool,
+    /// When the next frame is due, if `wait` has been called since the last
+    /// reset
+    next_frame: Option<Instant>,
+}
+
+impl Throttle {
+    /// A throttle that paces frames at `region`'s frame rate
+    pub fn new(region: Region) -> Throttle {
+        Throttle::with_frame_duration(region.frame_duration())
+    }
+
+    /// A throttle that paces frames `frame_duration` apart
+    pub fn with_frame_duration(frame_duration: Duration) -> Throttle {
+        Throttle {
+            frame_duration,
+            speed: 1.0,
+            uncapped: false,
+            next_frame: None,
+        }
+    }
+
+    pub fn frame_duration(&self) -> Duration {
+        self.frame_duration
+    }
+
+    /// Run faster or slower than real time, like `Nes::set_speed`
+    ///
+    /// `speed` is clamped the same way, and NaN is ignored.
+    pub fn set_speed(&mut self, speed: f32) {
+        if !speed.is_nan() {
+            self.speed = speed.clamp(MIN_SPEED, MAX_SPEED);
+            self.reset();
+        }
+    }
+
+    pub fn speed(&self) -> f32 {
+        self.speed
+    }
+
+    /// Stop waiting between frames, or start again
+    ///
+    /// Going back to capped starts the count over, so the runner doesn't
+    /// sleep to make up for the frames it ran ahead.
+    pub fn set_uncapped(&mut self, uncapped: bool) {
+        if self.uncapped && !uncapped {
+            self.reset();
+        }
+        self.uncapped = uncapped;
+    }
+
+    pub fn is_uncapped(&self) -> bool {
+        self.uncapped
+    }
+
+    /// Sleep until the next frame is due
+    ///
+    /// Call this once per frame, after running it. The first call after
+    /// creating or resetting the throttle doesn't sleep, and starts the count.
+    pub fn wait(&mut self) {
+        if let Some(delay) = self.delay(Instant::now()) {
+            thread::sleep(delay);
+        }
+    }
+
+    /// Forget the schedule, like after pausing
+    pub fn reset(&mut self) {
+        self.next_frame = None;
+    }
+
+    /// How long to sleep at `now` for the next frame, scheduling the one
+    /// after it
+    fn delay(&mut self, now: Instant) -> Option<Duration> {
+        if self.uncapped {
+            return None;
+        }
+        let frame_duration = scale(self.frame_duration, 1.0 / self.speed);
+        let due = match self.next_frame {
+            Some(due) if now.saturating_duration_since(due) <= frame_duration * MAX_LAG_FRAMES => {
+                due
+            }
+            _ => now,
+        };
+        self.next_frame = Some(due + frame_duration);
+        due.checked_duration_since(now)
+            .filter(|delay| *delay > Duration::from_secs(0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FRAME: Duration = Duration::from_millis(10);
+
+    #[test]
+    fn sleeps_for_the_rest_of_each_frame() {
+        let mut throttle = Throttle::with_frame_duration(FRAME);
+        let start = Instant::now();
+        assert_eq!(throttle.delay(start), None);
+        assert_eq!(
+            throttle.delay(start + Duration::from_millis(4)),
+            Some(Duration::from_millis(6))
+        );
+        // oversleeping on one frame comes out of the next
+        assert_eq!(
+            throttle.delay(start + Duration::from_millis(12)),
+            Some(Duration::from_millis(8))
+        );
+        assert_eq!(throttle.delay(start + Duration::from_millis(35)), None);
+        assert_eq!(
+            throttle.delay(start + Duration::from_millis(36)),
+            Some(Duration::from_millis(4))
+        );
+    }
+
+    #[test]
+    fn gives_up_catching_up_after_a_stall() {
+        let mut throttle = Throttle::with_frame_duration(FRAME);
+        let start = Instant::now();
+        throttle.delay(start);
+        assert_eq!(throttle.delay(start + Duration::from_secs(1)), None);
+        assert_eq!(
+            throttle.delay(start + Duration::from_millis(1002)),
+            Some(Duration::from_millis(8))
+        );
+    }
+
+    #[test]
+    fn scales_frames_by_the_speed() {
+        let mut throttle = Throttle::with_frame_duration(FRAME);
+        throttle.set_speed(0.5);
+        let start = Instant::now();
+        throttle.delay(start);
+        assert_eq!(throttle.delay(start), Some(Duration::from_millis(20)));
+        throttle.set_speed(16.0);
+        assert_eq!(throttle.speed(), MAX_SPEED);
+        throttle.delay(start);
+        assert_eq!(throttle.delay(start), Some(Duration::from_micros(2500)));
+    }
+
+    #[test]
+    fn uncapped_never_sleeps() {
+        let mut throttle = Throttle::new(Region::Ntsc);
+        assert_eq!(throttle.frame_duration(), Region::Ntsc.frame_duration());
+        let start = Instant::now();
+        throttle.delay(start);
+        throttle.set_uncapped(true);
+        assert!(throttle.is_uncapped());
+        for i in 0..10 {
+            assert_eq!(throttle.delay(start + Duration::from_micros(i)), None);
+        }
+        // and the schedule starts over once it's capped again
+        throttle.set_uncapped(false);
+        let now = start + Duration::from_millis(1);
+        assert_eq!(throttle.delay(now), None);
+        assert_eq!(throttle.delay(now), Some(throttle.frame_duration()));
+    }
+}
@@ -0,0 +1,125 @@
+//! Tools for working on the emulator itself, with the `std` and `png`
+//! features enabled
+//!
+//! `compare_frame` renders one frame of a ROM and diffs it against a reference
+//! screenshot, say from another emulator or a capture of real hardware. The
+//! result counts the pixels that differ, and draws them over a faded copy of
+//! the reference, so it's easy to see exactly what a PPU change altered.
+
+use std::fs;
+
+use crate::devices::nes::{Nes, NesConfig, FRAME_SIZE};
+use crate::error::{Error, Result};
+use crate::video::{self, FRAME_HEIGHT, FRAME_WIDTH};
+
+/// The color pixels that differ are drawn in, in a diff image
+const DIFF_COLOR: [u8; 3] = [0xFF, 0x00, 0x00];
+
+/// How two frames differ
+#[derive(Debug, Clone, PartialEq)]
+pub struct FrameDiff {
+    differing_pixels: usize,
+    image: Vec<u8>,
+}
+
+impl FrameDiff {
+    /// How many pixels differ at all, by any amount in any channel
+    pub fn differing_pixels(&self) -> usize {
+        self.differing_pixels
+    }
+
+    /// Whether the frames are identical
+    pub fn is_match(&self) -> bool {
+        self.differing_pixels == 0
+    }
+
+    /// The diff as a 256x240 RGB image
+    ///
+    /// Pixels that differ are bright red, and the rest are the reference
+    /// frame in dim grayscale, so the differences stand out wherever they are.
+    pub fn image(&self) -> &[u8] {
+        &self.image
+    }
+
+    /// The diff image, encoded as a PNG
+    pub fn to_png(&self) -> Vec<u8> {
+        video::encode_png(FRAME_WIDTH, FRAME_HEIGHT, &self.image)
+    }
+}
+
+/// Run an iNES ROM from power-on, and return frame number `frame`
+///
+/// Frames are counted from 1, like `Nes::frame_count`, so frame 1 is the
+/// first one the PPU finishes. No buttons are pressed along the way.
+///
+/// # Panics
+///
+/// Panics if `frame` is 0.
+pub fn render_frame(rom: &[u8], config: NesConfig, frame: u64) -> Result<Vec<u8>> {
+    assert!(frame > 0, "Frames are counted from 1");
+    let mut nes = Nes::new_from_buf_with_config(rom, config)?;
+    while nes.frame_count() < frame - 1 {
+        nes.tick_frame();
+    }
+    Ok(nes.tick_frame().to_vec())
+}
+
+/// Diff a frame against a reference frame, both as 256x240 RGB
+///
+/// # Panics
+///
+/// Panics if either frame isn't `FRAME_SIZE` bytes long.
+pub fn diff_frames(frame: &[u8], reference: &[u8]) -> FrameDiff {
+    assert_eq!(frame.len(), FRAME_SIZE, "Frame size mismatch");
+    assert_eq!(reference.len(), FRAME_SIZE, "Reference size mismatch");
+    let mut differing_pixels = 0;
+    let mut image = Vec::with_capacity(FRAME_SIZE);
+    for (pixel, expected) in frame.chunks(3).zip(reference.chunks(3)) {
+        if pixel == expected {
+            // a quarter-brightness luma, cf. ITU-R BT.601
+            let luma =
+                (expected[0] as u32 * 77 + expected[1] as u32 * 150 + expected[2] as u32 * 29)
+                    >> 10;
+            image.extend_from_slice(&[luma as u8; 3]);
+        } else {
+            differing_pixels += 1;
+            image.extend_from_slice(&DIFF_COLOR);
+        }
+    }
+    FrameDiff {
+        differing_pixels,
+        image,
+    }
+}
+
+/// Render frame number `frame` of an iNES ROM (see `render_frame`), and diff
+/// it against a reference PNG
+///
+/// The reference has to be a 256x240 PNG that `video::decode_png` can read.
+/// Screenshots cropped for overscan, or scaled up, won't line up with the
+/// frame, and fail with `Error::ImageSizeMismatch`.
+pub fn compare_frame(
+    rom: &[u8],
+    config: NesConfig,
+    frame: u64,
+    reference_png: &[u8],
+) -> Result<FrameDiff> {
+    let (width, height, reference) = video::decode_png(reference_png)?;
+    if (width, height) != (FRAME_WIDTH, FRAME_HEIGHT) {
+        return Err(Error::ImageSizeMismatch { width, height });
+    }
+    let rendered = render_frame(rom, config, frame)?;
+    Ok(diff_frames(&rendered, &reference))
+}
+
+/// Like `compare_frame`, but with the ROM and reference read from disk
+pub fn compare_frame_files(
+    rom_path: &str,
+    config: NesConfig,
+    frame: u64,
+    reference_path: &str,
+) -> Result<FrameDiff> {
+    let rom = fs::read(rom_path)?;
+    let reference = fs::read(reference_path)?;
+    compare_frame(&rom, config, frame, &reference)
+}
@@ -0,0 +1,299 @@
+//! Decompressing zlib streams, for reading PNGs with the `png` feature enabled
+//!
+//! The encoder in `png` gets away with storing everything uncompressed, but
+//! PNGs from anywhere else are compressed, so reading them takes a real
+//! inflater. This one is small and slow, in the style of zlib's `puff`, which
+//! is plenty for a few screenshots.
+//!
+//! cf. RFC 1950 (zlib) and RFC 1951 (deflate)
+
+use alloc::{vec, vec::Vec};
+
+use super::png::adler32;
+
+/// The longest a Huffman code can be, in bits
+const MAX_CODE_LEN: usize = 15;
+
+/// Base lengths for length codes 257-285
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+/// Extra bits for length codes 257-285
+const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+/// Base distances for distance codes 0-29
+const DIST_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+/// Extra bits for distance codes 0-29
+const DIST_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+/// The order code length code lengths come in, in a dynamic block header
+const CODE_LEN_ORDER: [usize; 19] = [
+    16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+];
+
+/// Reads a deflate stream a few bits at a time, least significant bit first
+struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    buf: u32,
+    count: u32,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8]) -> BitReader<'a> {
+        BitReader {
+            data,
+            pos: 0,
+            buf: 0,
+            count: 0,
+        }
+    }
+
+    /// Read `n` bits (at most 16), or `None` at the end of the data
+    fn bits(&mut self, n: u32) -> Option<u32> {
+        while self.count < n {
+            let byte = *self.data.get(self.pos)?;
+            self.pos += 1;
+            self.buf |= (byte as u32) << self.count;
+            self.count += 8;
+        }
+        let value = self.buf & ((1 << n) - 1);
+        self.buf >>= n;
+        self.count -= n;
+        Some(value)
+    }
+
+    /// Skip to the next byte boundary, and read `len` whole bytes
+    fn bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        self.buf = 0;
+        self.count = 0;
+        let bytes = self.data.get(self.pos..self.pos + len)?;
+        self.pos += len;
+        Some(bytes)
+    }
+}
+
+/// A canonical Huffman code, as the number of codes of each length and the
+/// symbols in code order
+struct Huffman {
+    counts: [u16; MAX_CODE_LEN + 1],
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    /// Build the code for symbols with the given code lengths, where 0 means
+    /// a symbol isn't used
+    fn new(lengths: &[u8]) -> Huffman {
+        let mut counts = [0u16; MAX_CODE_LEN + 1];
+        for &len in lengths {
+            counts[len as usize] += 1;
+        }
+        counts[0] = 0;
+        let mut offsets = [0u16; MAX_CODE_LEN + 1];
+        for len in 1..MAX_CODE_LEN {
+            offsets[len + 1] = offsets[len] + counts[len];
+        }
+        let mut symbols = vec![0u16; lengths.len()];
+        for (symbol, &len) in lengths.iter().enumerate() {
+            if len != 0 {
+                symbols[offsets[len as usize] as usize] = symbol as u16;
+                offsets[len as usize] += 1;
+            }
+        }
+        Huffman { counts, symbols }
+    }
+
+    /// Read one symbol, a bit at a time
+    fn decode(&self, reader: &mut BitReader) -> Option<u16> {
+        // codes of each length follow on from the last code of the length
+        // before, so walk the lengths until the code falls in one's range
+        let mut code = 0i32;
+        let mut first = 0i32;
+        let mut index = 0i32;
+        for &count in &self.counts[1..] {
+            code |= reader.bits(1)? as i32;
+            let count = count as i32;
+            if code - count < first {
+                return self.symbols.get((index + code - first) as usize).copied();
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        None
+    }
+}
+
+/// The literal/length and distance codes of a fixed Huffman block
+fn fixed_codes() -> (Huffman, Huffman) {
+    let mut lengths = [0u8; 288];
+    lengths[..144].fill(8);
+    lengths[144..256].fill(9);
+    lengths[256..280].fill(7);
+    lengths[280..].fill(8);
+    (Huffman::new(&lengths), Huffman::new(&[5; 30]))
+}
+
+/// Read the codes at the start of a dynamic Huffman block
+fn dynamic_codes(reader: &mut BitReader) -> Option<(Huffman, Huffman)> {
+    let lit_count = reader.bits(5)? as usize + 257;
+    let dist_count = reader.bits(5)? as usize + 1;
+    let code_len_count = reader.bits(4)? as usize + 4;
+    let mut code_lens = [0u8; 19];
+    for &symbol in &CODE_LEN_ORDER[..code_len_count] {
+        code_lens[symbol] = reader.bits(3)? as u8;
+    }
+    let code_len_code = Huffman::new(&code_lens);
+
+    let mut lengths = Vec::with_capacity(lit_count + dist_count);
+    while lengths.len() < lit_count + dist_count {
+        let (len, repeat) = match code_len_code.decode(reader)? {
+            len @ 0..=15 => (len as u8, 1),
+            16 => (*lengths.last()?, 3 + reader.bits(2)?),
+            17 => (0, 3 + reader.bits(3)?),
+            18 => (0, 11 + reader.bits(7)?),
+            _ => return None,
+        };
+        lengths.extend((0..repeat).map(|_| len));
+    }
+    if lengths.len() != lit_count + dist_count {
+        return None;
+    }
+    let (lits, dists) = lengths.split_at(lit_count);
+    Some((Huffman::new(lits), Huffman::new(dists)))
+}
+
+/// Decode the body of a compressed block onto the end of `out`
+fn inflate_block(
+    reader: &mut BitReader,
+    out: &mut Vec<u8>,
+    lits: &Huffman,
+    dists: &Huffman,
+) -> Option<()> {
+    loop {
+        let symbol = lits.decode(reader)? as usize;
+        match symbol {
+            0..=255 => out.push(symbol as u8),
+            256 => return Some(()),
+            _ => {
+                let code = symbol - 257;
+                let len = *LENGTH_BASE.get(code)? as usize
+                    + reader.bits(LENGTH_EXTRA[code] as u32)? as usize;
+                let code = dists.decode(reader)? as usize;
+                let dist =
+                    *DIST_BASE.get(code)? as usize + reader.bits(DIST_EXTRA[code] as u32)? as usize;
+                let start = out.len().checked_sub(dist)?;
+                // the copy can overlap what it's writing, which is how runs
+                // get encoded
+                for i in start..start + len {
+                    out.push(out[i]);
+                }
+            }
+        }
+    }
+}
+
+/// Decompress a zlib stream, or return `None` if it's corrupt
+pub fn zlib_decompress(data: &[u8]) -> Option<Vec<u8>> {
+    let (&cmf, &flg) = (data.first()?, data.get(1)?);
+    // deflate, with no preset dictionary
+    if cmf & 0x0F != 8
+        || u16::from_be_bytes([cmf, flg]).checked_rem(31) != Some(0)
+        || flg & 0x20 != 0
+    {
+        return None;
+    }
+    let mut reader = BitReader::new(&data[2..]);
+    let mut out = Vec::new();
+    loop {
+        let is_final = reader.bits(1)? == 1;
+        match reader.bits(2)? {
+            0 => {
+                let header = reader.bytes(4)?;
+                let len = u16::from_le_bytes([header[0], header[1]]);
+                let nlen = u16::from_le_bytes([header[2], header[3]]);
+                if len != !nlen {
+                    return None;
+                }
+                out.extend_from_slice(reader.bytes(len as usize)?);
+            }
+            1 => {
+                let (lits, dists) = fixed_codes();
+                inflate_block(&mut reader, &mut out, &lits, &dists)?;
+            }
+            2 => {
+                let (lits, dists) = dynamic_codes(&mut reader)?;
+                inflate_block(&mut reader, &mut out, &lits, &dists)?;
+            }
+            _ => return None,
+        }
+        if is_final {
+            break;
+        }
+    }
+    let adler = reader.bytes(4)?;
+    if u32::from_be_bytes([adler[0], adler[1], adler[2], adler[3]]) != adler32(&out) {
+        return None;
+    }
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn inflates_fixed_huffman_blocks() {
+        // zlib.compress(b"Hello, Hello, Hello!", 9)
+        let data = [
+            0x78, 0xDA, 0xF3, 0x48, 0xCD, 0xC9, 0xC9, 0xD7, 0x51, 0xF0, 0x40, 0xA2, 0x14, 0x01,
+            0x46, 0x3E, 0x06, 0x96,
+        ];
+        assert_eq!(
+            zlib_decompress(&data).as_deref(),
+            Some(&b"Hello, Hello, Hello!"[..])
+        );
+    }
+
+    #[test]
+    fn inflates_dynamic_huffman_blocks() {
+        let text: &[u8] = b"Sphinx of black quartz, judge my vow! Pack my box with five dozen \
+            liquor jugs. How vexingly quick daft zebras jump; the five boxing wizards jump \
+            quickly.";
+        // zlib.compress(text, 9)
+        let data = [
+            0x78, 0xDA, 0x25, 0x8C, 0xCB, 0x0D, 0xC3, 0x20, 0x14, 0x04, 0x5B, 0xD9, 0xDC, 0x23,
+            0x37, 0x90, 0x06, 0x72, 0x8C, 0x94, 0x0A, 0x20, 0x80, 0x79, 0x09, 0x06, 0x1B, 0xF3,
+            0xAF, 0xDE, 0x2F, 0xE2, 0xB8, 0x9A, 0x9D, 0x79, 0xEF, 0x96, 0x7C, 0x43, 0x30, 0x90,
+            0x4E, 0x7C, 0x7E, 0x38, 0xB2, 0x88, 0x69, 0xDC, 0xF1, 0xCD, 0x6A, 0xD5, 0xD8, 0x3A,
+            0x4A, 0xA8, 0x37, 0xBC, 0xFE, 0x88, 0x87, 0x0C, 0x0D, 0x95, 0x92, 0x85, 0xA1, 0xA2,
+            0xA1, 0xC2, 0xD0, 0x1E, 0x8E, 0x8E, 0x1C, 0x22, 0x0B, 0xEB, 0xB9, 0xE0, 0x19, 0x2A,
+            0x8A, 0x6E, 0xE4, 0x57, 0xD7, 0xB9, 0x45, 0xAC, 0x29, 0x61, 0x12, 0x86, 0x96, 0x51,
+            0x9C, 0x7C, 0xDA, 0xF6, 0x07, 0x92, 0xD5, 0x33, 0xC0, 0x39, 0x7E, 0x72, 0x71, 0x88,
+            0xA8, 0x26, 0x9D, 0x92, 0xEB, 0xCB, 0x05, 0x9A, 0x16, 0x37, 0xC9,
+        ];
+        assert_eq!(zlib_decompress(&data).as_deref(), Some(text));
+    }
+
+    #[test]
+    fn rejects_corrupt_streams() {
+        let mut data = vec![
+            0x78, 0xDA, 0xF3, 0x48, 0xCD, 0xC9, 0xC9, 0xD7, 0x51, 0xF0, 0x40, 0xA2, 0x14, 0x01,
+            0x46, 0x3E, 0x06, 0x96,
+        ];
+        // a bad checksum
+        data[17] ^= 0x01;
+        assert_eq!(zlib_decompress(&data), None);
+        // and a truncated stream
+        assert_eq!(zlib_decompress(&data[..8]), None);
+        // and a bad header
+        assert_eq!(zlib_decompress(&[0x78, 0x00]), None);
+    }
+}
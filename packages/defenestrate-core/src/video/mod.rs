@@ -0,0 +1,117 @@
+//! Helpers for getting video out of the emulator
+//!
+//! Frames are 256x240 8-bit RGB, but NES pixels aren't square: an NTSC TV
+//! draws each one a bit wider than it is tall. `FrameInfo` describes that, so
+//! frontends can scale frames to the right shape, and `scale_frame` does a
+//! nearest-neighbor integer upscale for frontends that can't scale cheaply
+//! themselves.
+//!
+//! cf. https://wiki.nesdev.com/w/index.php/Overscan
+
+use alloc::vec::Vec;
+
+#[cfg(feature = "png")]
+mod inflate;
+#[cfg(feature = "png")]
+mod png;
+
+#[cfg(feature = "png")]
+pub use png::{decode_png, encode_png};
+
+/// The width of an NES frame, in pixels
+pub const FRAME_WIDTH: usize = 256;
+/// The height of an NES frame, in pixels
+pub const FRAME_HEIGHT: usize = 240;
+
+/// The pixel aspect ratio of NTSC video, 8:7
+///
+/// This is the ratio of the NTSC color subcarrier to the PPU's dot clock,
+/// which is how wide a pixel is compared to the spacing between scanlines.
+pub const NTSC_PIXEL_ASPECT_RATIO: f32 = 8.0 / 7.0;
+
+/// The size of a frame, and the shape of its pixels
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct FrameInfo {
+    /// The width of the frame, in pixels
+    pub width: usize,
+    /// The height of the frame, in pixels
+    pub height: usize,
+    /// How much wider than tall each pixel is on a TV
+    pub pixel_aspect_ratio: f32,
+}
+
+impl FrameInfo {
+    /// The width the frame should be shown at to look right when `height`
+    /// pixels tall, rounded to the nearest pixel
+    pub fn display_width(&self, height: usize) -> usize {
+        let width = self.width as f32 * self.pixel_aspect_ratio * height as f32;
+        (width / self.height as f32 + 0.5) as usize
+    }
+}
+
+/// Upscale an 8-bit RGB image by a whole number, with nearest-neighbor scaling
+///
+/// The result is `width * scale` by `height * scale` pixels. This doesn't
+/// correct the aspect ratio, since that can't be done with whole pixels
+/// without distorting some columns.
+///
+/// # Panics
+///
+/// Panics if `rgb` isn't exactly `width * height * 3` bytes long, or if
+/// `scale` is 0.
+pub fn scale_frame(width: usize, height: usize, rgb: &[u8], scale: usize) -> Vec<u8> {
+    assert_eq!(rgb.len(), width * height * 3, "Image size mismatch");
+    assert!(scale > 0, "Scale must be at least 1");
+    let row_len = width * scale * 3;
+    let mut out = Vec::with_capacity(rgb.len() * scale * scale);
+    for line in rgb.chunks(width * 3) {
+        let start = out.len();
+        for pixel in line.chunks(3) {
+            for _ in 0..scale {
+                out.extend_from_slice(pixel);
+            }
+        }
+        // the rest of this row's copies are the same as the first
+        for _ in 1..scale {
+            out.extend_from_within(start..start + row_len);
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scales_by_whole_pixels() {
+        let rgb = [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12];
+        assert_eq!(scale_frame(2, 2, &rgb, 1), rgb.to_vec());
+        let scaled = scale_frame(2, 2, &rgb, 3);
+        assert_eq!(scaled.len(), 6 * 6 * 3);
+        let pixel = |x: usize, y: usize| &scaled[(y * 6 + x) * 3..(y * 6 + x) * 3 + 3];
+        assert_eq!(pixel(0, 0), &[1, 2, 3]);
+        assert_eq!(pixel(2, 2), &[1, 2, 3]);
+        assert_eq!(pixel(3, 0), &[4, 5, 6]);
+        assert_eq!(pixel(5, 2), &[4, 5, 6]);
+        assert_eq!(pixel(0, 3), &[7, 8, 9]);
+        assert_eq!(pixel(5, 5), &[10, 11, 12]);
+    }
+
+    #[test]
+    fn corrects_the_display_width() {
+        let info = FrameInfo {
+            width: FRAME_WIDTH,
+            height: FRAME_HEIGHT,
+            pixel_aspect_ratio: NTSC_PIXEL_ASPECT_RATIO,
+        };
+        // the usual 8:7 width for a 240 line frame, and at 2x
+        assert_eq!(info.display_width(240), 293);
+        assert_eq!(info.display_width(480), 585);
+        let square = FrameInfo {
+            pixel_aspect_ratio: 1.0,
+            ..info
+        };
+        assert_eq!(square.display_width(720), 768);
+    }
+}
@@ -0,0 +1,372 @@
+//! Encoding and decoding frames as PNG, with the `png` feature enabled
+//!
+//! The encoder here doesn't compress anything. Screenshots come out at
+//! about 180k, which is fine for a debugging aid, and it means the core
+//! doesn't need a deflate implementation (or any dependencies at all) to
+//! write them.
+//!
+//! The decoder is for reference screenshots, which can come from anywhere,
+//! so it handles compressed images (see `inflate`) and every 8-bit color
+//! type. Interlaced images and other bit depths are rare enough for
+//! screenshots that they're rejected.
+
+use alloc::{vec, vec::Vec};
+
+use super::inflate::zlib_decompress;
+use crate::checksum::crc32;
+use crate::error::{Error, Result};
+
+const PNG_SIGNATURE: [u8; 8] = [0x89, 0x50, 0x4E, 0x47, 0x0D, 0x0A, 0x1A, 0x0A];
+
+/// The largest payload of a stored deflate block
+const MAX_STORED_BLOCK: usize = 0xFFFF;
+
+/// Encode an 8-bit RGB image as a PNG
+///
+/// # Panics
+///
+/// Panics if `rgb` isn't exactly `width * height * 3` bytes long.
+pub fn encode_png(width: usize, height: usize, rgb: &[u8]) -> Vec<u8> {
+    assert_eq!(rgb.len(), width * height * 3, "Image size mismatch");
+    let mut png = Vec::with_capacity(rgb.len() + rgb.len() / MAX_STORED_BLOCK * 5 + 128);
+    png.extend_from_slice(&PNG_SIGNATURE);
+
+    let mut ihdr = Vec::with_capacity(13);
+    ihdr.extend_from_slice(&(width as u32).to_be_bytes());
+    ihdr.extend_from_slice(&(height as u32).to_be_bytes());
+    // bit depth 8, color type 2 (RGB), default compression, filter, and no interlacing
+    ihdr.extend_from_slice(&[8, 2, 0, 0, 0]);
+    write_chunk(&mut png, b"IHDR", &ihdr);
+
+    // every scanline starts with its filter type, which is always 0 (None)
+    let mut raw = Vec::with_capacity(height * (width * 3 + 1));
+    for line in rgb.chunks(width * 3) {
+        raw.push(0);
+        raw.extend_from_slice(line);
+    }
+    write_chunk(&mut png, b"IDAT", &zlib_stored(&raw));
+    write_chunk(&mut png, b"IEND", &[]);
+    png
+}
+
+/// Wrap some data in a zlib stream, without compressing it
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len() + data.len() / MAX_STORED_BLOCK * 5 + 11);
+    // deflate with a 32k window, no preset dictionary, fastest compression
+    out.extend_from_slice(&[0x78, 0x01]);
+    let mut blocks = data.chunks(MAX_STORED_BLOCK).peekable();
+    if blocks.peek().is_none() {
+        // an empty stream still needs one (final) block
+        out.extend_from_slice(&[0x01, 0x00, 0x00, 0xFF, 0xFF]);
+    }
+    while let Some(block) = blocks.next() {
+        let is_final = blocks.peek().is_none();
+        let len = block.len() as u16;
+        out.push(if is_final { 0x01 } else { 0x00 });
+        out.extend_from_slice(&len.to_le_bytes());
+        out.extend_from_slice(&(!len).to_le_bytes());
+        out.extend_from_slice(block);
+    }
+    out.extend_from_slice(&adler32(data).to_be_bytes());
+    out
+}
+
+fn write_chunk(png: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let start = png.len();
+    png.extend_from_slice(kind);
+    png.extend_from_slice(data);
+    // the CRC covers the chunk type and data, but not the length
+    let crc = crc32(&png[start..]);
+    png.extend_from_slice(&crc.to_be_bytes());
+}
+
+/// Decode a PNG into 8-bit RGB, returning its width, height, and pixels
+///
+/// Transparency is dropped, rather than blended with anything. Fails with
+/// `Error::InvalidPng` if the PNG is corrupt, or isn't an 8-bit,
+/// non-interlaced image.
+pub fn decode_png(png: &[u8]) -> Result<(usize, usize, Vec<u8>)> {
+    if png.get(..8) != Some(&PNG_SIGNATURE[..]) {
+        return Err(Error::InvalidPng);
+    }
+    let mut header = None;
+    let mut palette: &[u8] = &[];
+    let mut compressed = Vec::new();
+    let mut pos = 8;
+    loop {
+        let (kind, data) = read_chunk(png, &mut pos).ok_or(Error::InvalidPng)?;
+        match kind {
+            b"IHDR" => header = Some(data),
+            b"PLTE" => palette = data,
+            b"IDAT" => compressed.extend_from_slice(data),
+            b"IEND" => break,
+            _ => {}
+        }
+    }
+    let header = header.filter(|header| header.len() == 13);
+    let header = header.ok_or(Error::InvalidPng)?;
+    let width = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+    let height = u32::from_be_bytes([header[4], header[5], header[6], header[7]]) as usize;
+    let (bit_depth, color_type, interlace) = (header[8], header[9], header[12]);
+    // bytes per pixel, for each color type
+    let bpp = match color_type {
+        0 | 3 => 1,
+        4 => 2,
+        2 => 3,
+        6 => 4,
+        _ => return Err(Error::InvalidPng),
+    };
+    if bit_depth != 8 || interlace != 0 || width == 0 || height == 0 {
+        return Err(Error::InvalidPng);
+    }
+
+    let mut raw = zlib_decompress(&compressed).ok_or(Error::InvalidPng)?;
+    // the header can claim any size, so these can overflow
+    let stride = width.checked_mul(bpp).ok_or(Error::InvalidPng)?;
+    let raw_len = (stride + 1).checked_mul(height).ok_or(Error::InvalidPng)?;
+    if raw.len() != raw_len {
+        return Err(Error::InvalidPng);
+    }
+    let mut pixels = vec![0u8; height * stride];
+    let mut prev = vec![0u8; stride];
+    for (line, out) in raw.chunks_mut(stride + 1).zip(pixels.chunks_mut(stride)) {
+        unfilter(line[0], &mut line[1..], &prev, bpp)?;
+        out.copy_from_slice(&line[1..]);
+        prev.copy_from_slice(out);
+    }
+
+    let mut rgb = Vec::with_capacity(width * height * 3);
+    for pixel in pixels.chunks(bpp) {
+        match color_type {
+            0 | 4 => rgb.extend_from_slice(&[pixel[0]; 3]),
+            3 => {
+                let index = pixel[0] as usize * 3;
+                let color = palette.get(index..index + 3).ok_or(Error::InvalidPng)?;
+                rgb.extend_from_slice(color);
+            }
+            _ => rgb.extend_from_slice(&pixel[..3]),
+        }
+    }
+    Ok((width, height, rgb))
+}
+
+/// Read the chunk at `pos`, checking its CRC, and move `pos` past it
+fn read_chunk<'a>(png: &'a [u8], pos: &mut usize) -> Option<(&'a [u8], &'a [u8])> {
+    let len = png.get(*pos..*pos + 4)?;
+    let len = u32::from_be_bytes([len[0], len[1], len[2], len[3]]) as usize;
+    // this can overflow on 32-bit targets
+    let end = pos.checked_add(8)?.checked_add(len)?;
+    let body = png.get(*pos + 4..end)?;
+    let crc = png.get(end..end.checked_add(4)?)?;
+    if u32::from_be_bytes([crc[0], crc[1], crc[2], crc[3]]) != crc32(body) {
+        return None;
+    }
+    *pos = end + 4;
+    Some(body.split_at(4))
+}
+
+/// Undo the filter on a line, in place, given the line above it
+///
+/// cf. https://www.w3.org/TR/png/#9Filter-types
+fn unfilter(filter: u8, line: &mut [u8], prev: &[u8], bpp: usize) -> Result<()> {
+    for i in 0..line.len() {
+        let left = if i >= bpp { line[i - bpp] } else { 0 };
+        let up = prev[i];
+        let up_left = if i >= bpp { prev[i - bpp] } else { 0 };
+        let predicted = match filter {
+            0 => 0,
+            1 => left,
+            2 => up,
+            3 => ((left as u16 + up as u16) / 2) as u8,
+            4 => paeth(left, up, up_left),
+            _ => return Err(Error::InvalidPng),
+        };
+        line[i] = line[i].wrapping_add(predicted);
+    }
+    Ok(())
+}
+
+/// Whichever of the pixels to the left, above, and up-left is closest to
+/// `left + up - up_left`
+fn paeth(left: u8, up: u8, up_left: u8) -> u8 {
+    let estimate = left as i16 + up as i16 - up_left as i16;
+    let left_dist = (estimate - left as i16).abs();
+    let up_dist = (estimate - up as i16).abs();
+    let up_left_dist = (estimate - up_left as i16).abs();
+    if left_dist <= up_dist && left_dist <= up_left_dist {
+        left
+    } else if up_dist <= up_left_dist {
+        up
+    } else {
+        up_left
+    }
+}
+
+pub(super) fn adler32(data: &[u8]) -> u32 {
+    const MOD_ADLER: u32 = 65521;
+    let (a, b) = data.iter().fold((1u32, 0u32), |(a, b), &byte| {
+        let a = (a + byte as u32) % MOD_ADLER;
+        (a, (b + a) % MOD_ADLER)
+    });
+    (b << 16) | a
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{FRAME_HEIGHT, FRAME_WIDTH};
+    use super::*;
+
+    /// Pull the chunks back out of a PNG, checking each CRC along the way
+    fn read_chunks(png: &[u8]) -> Vec<([u8; 4], Vec<u8>)> {
+        assert_eq!(&png[..8], &PNG_SIGNATURE, "Bad signature");
+        let mut chunks = Vec::new();
+        let mut pos = 8;
+        while pos < png.len() {
+            let len = u32::from_be_bytes([png[pos], png[pos + 1], png[pos + 2], png[pos + 3]]);
+            let body = &png[pos + 4..pos + 8 + len as usize];
+            let crc_pos = pos + 8 + len as usize;
+            let crc = u32::from_be_bytes([
+                png[crc_pos],
+                png[crc_pos + 1],
+                png[crc_pos + 2],
+                png[crc_pos + 3],
+            ]);
+            assert_eq!(crc, crc32(body), "CRC mismatch");
+            let mut kind = [0u8; 4];
+            kind.copy_from_slice(&body[..4]);
+            chunks.push((kind, body[4..].to_vec()));
+            pos = crc_pos + 4;
+        }
+        chunks
+    }
+
+    /// Undo `zlib_stored`
+    fn inflate_stored(zlib: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        let mut pos = 2;
+        loop {
+            let header = zlib[pos];
+            let len = u16::from_le_bytes([zlib[pos + 1], zlib[pos + 2]]) as usize;
+            let nlen = u16::from_le_bytes([zlib[pos + 3], zlib[pos + 4]]) as usize;
+            assert_eq!(len, !nlen & 0xFFFF, "Bad stored block length");
+            out.extend_from_slice(&zlib[pos + 5..pos + 5 + len]);
+            pos += 5 + len;
+            if header & 1 == 1 {
+                break;
+            }
+        }
+        let adler = u32::from_be_bytes([zlib[pos], zlib[pos + 1], zlib[pos + 2], zlib[pos + 3]]);
+        assert_eq!(adler, adler32(&out), "Adler-32 mismatch");
+        out
+    }
+
+    #[test]
+    fn adler32_matches_reference_value() {
+        assert_eq!(adler32(b"Wikipedia"), 0x11E6_0398);
+    }
+
+    #[test]
+    fn encodes_a_frame() {
+        let rgb: Vec<u8> = (0..FRAME_WIDTH * FRAME_HEIGHT * 3)
+            .map(|i| (i % 251) as u8)
+            .collect();
+        let png = encode_png(FRAME_WIDTH, FRAME_HEIGHT, &rgb);
+        let chunks = read_chunks(&png);
+        let kinds: Vec<&[u8; 4]> = chunks.iter().map(|(kind, _)| kind).collect();
+        assert_eq!(kinds, vec![b"IHDR", b"IDAT", b"IEND"]);
+        assert_eq!(&chunks[0].1[..8], &[0, 0, 1, 0, 0, 0, 0, 240]);
+        let raw = inflate_stored(&chunks[1].1);
+        assert_eq!(raw.len(), FRAME_HEIGHT * (FRAME_WIDTH * 3 + 1));
+        for (y, line) in raw.chunks(FRAME_WIDTH * 3 + 1).enumerate() {
+            assert_eq!(line[0], 0, "Unexpected filter type");
+            let start = y * FRAME_WIDTH * 3;
+            assert_eq!(&line[1..], &rgb[start..start + FRAME_WIDTH * 3]);
+        }
+    }
+
+    #[test]
+    fn decodes_what_it_encodes() {
+        let rgb: Vec<u8> = (0..FRAME_WIDTH * FRAME_HEIGHT * 3)
+            .map(|i| (i % 251) as u8)
+            .collect();
+        let (width, height, decoded) =
+            decode_png(&encode_png(FRAME_WIDTH, FRAME_HEIGHT, &rgb)).unwrap();
+        assert_eq!((width, height), (FRAME_WIDTH, FRAME_HEIGHT));
+        assert!(decoded == rgb, "Round trip changed the image");
+    }
+
+    #[test]
+    fn decodes_compressed_and_filtered_images() {
+        // a 4x3 RGB image, with its lines filtered as Sub, Paeth, and
+        // Average, compressed, and split over two IDAT chunks
+        let png = [
+            0x89, 0x50, 0x4E, 0x47, 0x0D, 0x0A, 0x1A, 0x0A, 0x00, 0x00, 0x00, 0x0D, 0x49, 0x48,
+            0x44, 0x52, 0x00, 0x00, 0x00, 0x04, 0x00, 0x00, 0x00, 0x03, 0x08, 0x02, 0x00, 0x00,
+            0x00, 0x3B, 0x96, 0x39, 0x91, 0x00, 0x00, 0x00, 0x05, 0x49, 0x44, 0x41, 0x54, 0x78,
+            0xDA, 0x63, 0xE4, 0x12, 0x4F, 0xFD, 0x99, 0xE3, 0x00, 0x00, 0x00, 0x27, 0x49, 0x44,
+            0x41, 0x54, 0x91, 0x83, 0x80, 0x26, 0x91, 0x65, 0x2C, 0xDF, 0xDF, 0x3D, 0xBD, 0x74,
+            0xE7, 0x84, 0x90, 0x51, 0x90, 0xC5, 0xBD, 0xFF, 0xCC, 0x9C, 0x1C, 0x1C, 0xED, 0x3D,
+            0x13, 0xFF, 0x7E, 0x7A, 0xCE, 0xCC, 0xDC, 0x0C, 0x00, 0xFF, 0x35, 0x0F, 0x3C, 0xDC,
+            0xA1, 0x97, 0x98, 0x00, 0x00, 0x00, 0x00, 0x49, 0x45, 0x4E, 0x44, 0xAE, 0x42, 0x60,
+            0x82,
+        ];
+        let (width, height, rgb) = decode_png(&png).unwrap();
+        assert_eq!((width, height), (4, 3));
+        assert_eq!(
+            rgb,
+            [
+                10, 20, 30, 40, 50, 60, 70, 80, 90, 200, 100, 0, //
+                1, 2, 3, 250, 240, 230, 12, 34, 56, 0, 0, 255, //
+                9, 9, 9, 8, 8, 8, 7, 7, 7, 6, 6, 6,
+            ]
+        );
+    }
+
+    #[test]
+    fn decodes_palette_images() {
+        // a 2x2 image with a red, green, and blue palette, and its second
+        // line filtered as Up
+        let png = [
+            0x89, 0x50, 0x4E, 0x47, 0x0D, 0x0A, 0x1A, 0x0A, 0x00, 0x00, 0x00, 0x0D, 0x49, 0x48,
+            0x44, 0x52, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, 0x02, 0x08, 0x03, 0x00, 0x00,
+            0x00, 0x45, 0x68, 0xFD, 0x16, 0x00, 0x00, 0x00, 0x09, 0x50, 0x4C, 0x54, 0x45, 0xFF,
+            0x00, 0x00, 0x00, 0xFF, 0x00, 0x00, 0x00, 0xFF, 0x2D, 0x4A, 0xCD, 0x8A, 0x00, 0x00,
+            0x00, 0x05, 0x49, 0x44, 0x41, 0x54, 0x78, 0xDA, 0x63, 0x60, 0x60, 0xAE, 0x19, 0xD4,
+            0xBC, 0x00, 0x00, 0x00, 0x09, 0x49, 0x44, 0x41, 0x54, 0x64, 0x62, 0xFA, 0x0F, 0x00,
+            0x01, 0x13, 0x01, 0x05, 0xCC, 0x84, 0xAE, 0xFD, 0x00, 0x00, 0x00, 0x00, 0x49, 0x45,
+            0x4E, 0x44, 0xAE, 0x42, 0x60, 0x82,
+        ];
+        let (_, _, rgb) = decode_png(&png).unwrap();
+        assert_eq!(rgb, [255, 0, 0, 0, 255, 0, 0, 0, 255, 255, 0, 0]);
+    }
+
+    #[test]
+    fn rejects_corrupt_pngs() {
+        let mut png = encode_png(2, 2, &[0u8; 12]);
+        assert!(decode_png(&png[..png.len() - 12]).is_err(), "Missing IEND");
+        // flip a bit in the IDAT chunk's data
+        png[45] ^= 0x01;
+        assert!(matches!(decode_png(&png), Err(Error::InvalidPng)));
+        assert!(matches!(decode_png(b"GIF89a"), Err(Error::InvalidPng)));
+    }
+
+    #[test]
+    fn rejects_sizes_that_overflow() {
+        let png = encode_png(2, 2, &[0u8; 12]);
+        for &(width, height) in &[(u32::MAX, u32::MAX), (0, 2), (2, 0)] {
+            let mut png = png.clone();
+            // the IHDR chunk's data starts at 16, and its CRC at 29
+            png[16..20].copy_from_slice(&width.to_be_bytes());
+            png[20..24].copy_from_slice(&height.to_be_bytes());
+            let crc = crc32(&png[12..29]);
+            png[29..33].copy_from_slice(&crc.to_be_bytes());
+            assert!(
+                matches!(decode_png(&png), Err(Error::InvalidPng)),
+                "Decoded a {}x{} PNG",
+                width,
+                height
+            );
+        }
+    }
+}
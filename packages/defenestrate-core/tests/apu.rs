@@ -0,0 +1,191 @@
+//! Runs small programs that poll APUSTATUS, to check that the length counters
+//! and frame counter behave the way music engines expect, and listens to what
+//! comes out of `take_audio_samples`
+
+extern crate defenestrate_core;
+
+mod util;
+
+use defenestrate_core::devices::nes::{
+    ApuStatus, Channel, Comparator, IrqSource, Nes, NesConfig, Probe, DEFAULT_SAMPLE_RATE,
+};
+use defenestrate_core::prelude::Motherboard;
+use util::roms;
+
+/// Play a note on pulse 1 for 2 half frames, then write $42 to $00 once
+/// APUSTATUS says it's over
+const WAIT_FOR_NOTE: &[u8] = &[
+    0x78, //             SEI
+    0xA9, 0x40, //       LDA #$40       ; no frame IRQs
+    0x8D, 0x17, 0x40, // STA $4017
+    0xA9, 0x01, //       LDA #$01       ; enable pulse 1
+    0x8D, 0x15, 0x40, // STA $4015
+    0xA9, 0x18, //       LDA #$18       ; a length of 2
+    0x8D, 0x03, 0x40, // STA $4003
+    0xAD, 0x15, 0x40, // LDA $4015      ; $8010
+    0x29, 0x01, //       AND #$01
+    0xD0, 0xF9, //       BNE $8010
+    0xA9, 0x42, //       LDA #$42
+    0x85, 0x00, //       STA $00
+    0x4C, 0x1B, 0x80, // JMP $801B
+];
+
+/// Spin forever with IRQs masked
+const SPIN: &[u8] = &[
+    0x78, //             SEI
+    0x4C, 0x01, 0x80, // JMP $8001
+];
+
+fn load(program: &[u8]) -> Nes {
+    Nes::new_from_buf(&roms::program_rom(program)).expect("Could not load test ROM")
+}
+
+#[test]
+fn length_counters_run_out_every_half_frame() {
+    let mut nes = load(WAIT_FOR_NOTE);
+    nes.add_probe(Probe::new(0x0000, Comparator::Equal, 0x42));
+    // the first half frame is a quarter of the way into the sequence, and the
+    // second is at the end of it
+    assert!(nes.run_until_probe(20_000).is_none());
+    assert_eq!(nes.apu().length_counters()[0], 1);
+    assert!(nes.run_until_probe(12_000).is_some());
+    assert_eq!(nes.apu().peek_status(), ApuStatus::empty());
+}
+
+#[test]
+fn frame_irq_holds_the_line_until_status_is_read() {
+    let mut nes = load(SPIN);
+    for _ in 0..2 {
+        nes.tick_frame();
+    }
+    assert_eq!(nes.irq_line().sources(), IrqSource::APU_FRAME);
+    assert_eq!(nes.read(0x4015) & 0x40, 0x40);
+    assert_eq!(nes.read(0x4015) & 0x40, 0x00);
+    // the line follows the flag on the next CPU cycle
+    for _ in 0..3 {
+        nes.tick();
+    }
+    assert!(!nes.irq_line().is_asserted());
+}
+
+#[test]
+fn inhibiting_the_frame_irq_clears_it() {
+    let mut nes = load(SPIN);
+    for _ in 0..2 {
+        nes.tick_frame();
+    }
+    assert!(nes.irq_line().is_asserted());
+    nes.write(0x4017, 0x40);
+    assert_eq!(nes.apu().peek_status(), ApuStatus::empty());
+    for _ in 0..4 {
+        nes.tick_frame();
+    }
+    assert!(!nes.irq_line().is_asserted());
+}
+
+#[test]
+fn reset_silences_every_channel() {
+    let mut nes = load(SPIN);
+    nes.write(0x4015, 0x0F);
+    nes.write(0x4003, 0x08);
+    nes.write(0x400F, 0x08);
+    assert_eq!(
+        nes.apu().peek_status(),
+        ApuStatus::PULSE_1 | ApuStatus::NOISE
+    );
+    nes.reset();
+    assert_eq!(nes.apu().peek_status(), ApuStatus::empty());
+}
+
+/// Hold an A440 on pulse 1: a 50% duty cycle at a constant volume of 15,
+/// with a period of 253, which is 1789773 / (16 * 254) = 440.4Hz
+fn play_a440(nes: &mut Nes) {
+    nes.write(0x4015, 0x01);
+    nes.write(0x4000, 0xBF);
+    nes.write(0x4002, 0xFD);
+    nes.write(0x4003, 0x00);
+}
+
+/// Run `frames` frames, returning the audio from the last `keep` of them
+fn listen(nes: &mut Nes, frames: usize, keep: usize) -> Vec<f32> {
+    for _ in 0..frames - keep {
+        nes.tick_frame();
+    }
+    nes.take_audio_samples();
+    for _ in 0..keep {
+        nes.tick_frame();
+    }
+    nes.take_audio_samples()
+}
+
+/// How many times the samples go from below 0 to 0 or above
+fn rising_edges(samples: &[f32]) -> usize {
+    samples
+        .windows(2)
+        .filter(|pair| pair[0] < 0.0 && pair[1] >= 0.0)
+        .count()
+}
+
+#[test]
+fn makes_a_frame_of_samples_each_frame() {
+    let mut nes = load(SPIN);
+    assert_eq!(nes.config().sample_rate, DEFAULT_SAMPLE_RATE);
+    nes.tick_frame();
+    nes.take_audio_samples();
+    for _ in 0..60 {
+        nes.tick_frame();
+    }
+    // 60 frames is a hair short of a second
+    let samples = nes.take_audio_samples().len();
+    assert!((44_000..44_100).contains(&samples), "Got {}", samples);
+    nes.set_sample_rate(0);
+    nes.tick_frame();
+    assert!(nes.take_audio_samples().is_empty());
+}
+
+#[test]
+fn plays_pulse_notes_at_their_pitch() {
+    let config = NesConfig::ntsc().with_sample_rate(48_000);
+    let mut nes = Nes::new_from_buf_with_config(&roms::program_rom(SPIN), config)
+        .expect("Could not load test ROM");
+    play_a440(&mut nes);
+    let samples = listen(&mut nes, 72, 60);
+    let edges = rising_edges(&samples);
+    assert!((438..=442).contains(&edges), "Got {} cycles", edges);
+    assert!(samples.iter().all(|sample| sample.abs() < 0.2));
+}
+
+#[test]
+fn dmc_samples_come_from_prg() {
+    // a 1 byte sample of $FF at $8040, which the 16k PRG mirrors at $C040
+    let mut program = SPIN.to_vec();
+    program.resize(0x40, 0x00);
+    program.push(0xFF);
+    let mut nes = load(&program);
+    nes.write(0x4011, 0x40);
+    nes.write(0x4010, 0x0F);
+    nes.write(0x4012, 0x01);
+    nes.write(0x4013, 0x00);
+    nes.write(0x4015, 0x10);
+    nes.tick_frame();
+    // 8 steps of 2 up, and then it holds
+    assert_eq!(nes.apu().dmc_level(), 0x50);
+}
+
+#[test]
+fn muted_channels_stay_muted_through_save_states() {
+    let mut nes = load(SPIN);
+    play_a440(&mut nes);
+    let state = nes.save_state();
+    nes.set_channel_enabled(Channel::Pulse1, false);
+    nes.load_state(&state);
+    assert!(!nes.apu().is_channel_enabled(Channel::Pulse1));
+    let samples = listen(&mut nes, 30, 10);
+    assert_eq!(rising_edges(&samples), 0);
+    assert!(samples.iter().all(|sample| sample.abs() < 0.01));
+    // the note is still playing underneath
+    assert!(nes.apu().peek_status().contains(ApuStatus::PULSE_1));
+    nes.set_channel_enabled(Channel::Pulse1, true);
+    let samples = listen(&mut nes, 12, 6);
+    assert!(rising_edges(&samples) > 40);
+}
@@ -0,0 +1,104 @@
+//! Checks that `Nes::run_until` stops exactly where it's asked to
+
+extern crate defenestrate_core;
+
+mod util;
+
+use defenestrate_core::devices::nes::{Breakpoint, Nes};
+use util::roms;
+
+/// Master cycles in a frame
+const FRAME_CYCLES: u64 = 341 * 262;
+
+/// Count up in X forever
+const COUNT_UP: &[u8] = &[
+    0xA2, 0x00, //       LDX #$00
+    0xE8, //             INX
+    0x4C, 0x02, 0x80, // JMP $8002
+];
+
+fn scroll_nes() -> Nes {
+    Nes::new_from_buf(&roms::scroll_rom()).expect("Could not load test ROM")
+}
+
+fn ppu_position(nes: &Nes) -> (i16, u16) {
+    let view = nes.ppu_debug_state();
+    (view.scanline, view.dot)
+}
+
+#[test]
+fn stops_at_the_start_of_vblank() {
+    let mut nes = scroll_nes();
+    nes.run_until(Breakpoint::NextVblankStart);
+    assert_eq!(ppu_position(&nes), (241, 1));
+    let view = nes.ppu_debug_state();
+    assert_eq!(view.status & 0x80, 0x80, "Vblank flag is not set");
+    let cycles = nes.debug_snapshot().cycles;
+    nes.run_until(Breakpoint::NextVblankStart);
+    assert_eq!(ppu_position(&nes), (241, 1));
+    assert_eq!(nes.debug_snapshot().cycles - cycles, FRAME_CYCLES);
+}
+
+#[test]
+fn stops_at_scanlines_and_dots() {
+    let mut nes = scroll_nes();
+    nes.run_until(Breakpoint::NextScanline(100));
+    assert_eq!(ppu_position(&nes), (100, 0));
+    nes.run_until(Breakpoint::PpuDot {
+        scanline: 100,
+        dot: 257,
+    });
+    assert_eq!(ppu_position(&nes), (100, 257));
+    // scanline 50 has already gone by, so this is in the next frame
+    let cycles = nes.debug_snapshot().cycles;
+    nes.run_until(Breakpoint::NextScanline(50));
+    assert_eq!(ppu_position(&nes), (50, 0));
+    assert_eq!(
+        nes.debug_snapshot().cycles - cycles,
+        FRAME_CYCLES - 50 * 341 - 257
+    );
+}
+
+#[test]
+fn counts_cpu_instructions() {
+    let mut nes = Nes::new_from_buf(&roms::program_rom(COUNT_UP)).expect("Could not load test ROM");
+    // LDX, then (INX, JMP) 5 times, then one more INX
+    nes.run_until(Breakpoint::CpuInstructionCount(12));
+    assert_eq!(nes.debug_snapshot().cpu.x, 6);
+    assert_eq!(nes.debug_snapshot().cpu.pc, 0x8003);
+    nes.run_until(Breakpoint::CpuInstructionCount(0));
+    assert_eq!(nes.debug_snapshot().cpu.pc, 0x8003);
+    nes.run_until(Breakpoint::CpuInstructionCount(2));
+    assert_eq!(nes.debug_snapshot().cpu.x, 7);
+}
+
+#[test]
+#[should_panic(expected = "never reaches")]
+fn rejects_dots_off_the_end_of_a_scanline() {
+    scroll_nes().run_until(Breakpoint::PpuDot {
+        scanline: 10,
+        dot: 341,
+    });
+}
+
+#[test]
+fn reports_the_position_and_frame_count() {
+    let mut nes = scroll_nes();
+    assert_eq!(nes.frame_count(), 0);
+    nes.run_until(Breakpoint::PpuDot {
+        scanline: 100,
+        dot: 50,
+    });
+    assert_eq!((nes.current_scanline(), nes.current_dot()), (100, 50));
+    assert_eq!(nes.cpu_cycles(), nes.clock().cpu_cycles());
+    nes.tick_frame();
+    nes.tick_frame();
+    assert_eq!(nes.frame_count(), 2);
+    // debug logs show where the PPU was when the instruction started
+    nes.run_until(Breakpoint::PpuDot {
+        scanline: 10,
+        dot: 7,
+    });
+    let log = nes.dbg_step_cpu();
+    assert!(log.contains("PPU:  7, 10"), "{}", log);
+}
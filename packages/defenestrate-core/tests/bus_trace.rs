@@ -0,0 +1,90 @@
+//! Checks that bus traces cover exactly one frame of CPU bus activity
+
+extern crate defenestrate_core;
+
+mod util;
+
+use defenestrate_core::devices::nes::{AccessKind, Breakpoint, Nes, TraceDevice};
+use util::roms;
+
+/// Copy PPUSTATUS into $00, forever
+const POLL_PPUSTATUS: &[u8] = &[
+    0xAD, 0x02, 0x20, // LDA $2002
+    0x85, 0x00, //       STA $00
+    0x4C, 0x00, 0x80, // JMP $8000
+];
+
+/// The number of CPU cycles in a frame, rounded up
+const CPU_CYCLES_PER_FRAME: u32 = 29781;
+
+#[test]
+fn traces_one_frame() {
+    let mut nes =
+        Nes::new_from_buf(&roms::program_rom(POLL_PPUSTATUS)).expect("Could not load test ROM");
+    nes.tick_frame();
+    assert!(nes.take_bus_trace().is_none());
+    nes.trace_next_frame();
+    nes.run_until(Breakpoint::NextScanline(100));
+    assert!(nes.take_bus_trace().is_none());
+    nes.tick_frame();
+    let trace = nes
+        .take_bus_trace()
+        .expect("The frame should have been traced");
+    assert!(nes.take_bus_trace().is_none());
+
+    let accesses = trace.accesses();
+    assert!(accesses
+        .windows(2)
+        .all(|pair| pair[0].cycle <= pair[1].cycle));
+    assert!(accesses.last().unwrap().cycle < CPU_CYCLES_PER_FRAME);
+    assert!(accesses.iter().any(|access| access.addr == 0x2002
+        && access.kind == AccessKind::Read
+        && access.device == TraceDevice::PpuPorts
+        && access.value & 0x80 != 0));
+    assert!(accesses.iter().any(|access| access.addr == 0x0000
+        && access.kind == AccessKind::Write
+        && access.device == TraceDevice::Ram));
+    assert!(accesses
+        .iter()
+        .filter(|access| access.addr >= 0x8000)
+        .all(|access| access.device == TraceDevice::Cartridge));
+    assert_eq!(trace.to_bytes().len(), trace.len() * 8);
+}
+
+#[test]
+fn tracing_is_off_by_default() {
+    let mut nes =
+        Nes::new_from_buf(&roms::program_rom(POLL_PPUSTATUS)).expect("Could not load test ROM");
+    nes.tick_frame();
+    nes.tick_frame();
+    assert!(nes.take_bus_trace().is_none());
+}
+
+#[test]
+fn traces_apu_io_and_prg_ram() {
+    let program = &[
+        0xAD, 0x00, 0x40, // LDA $4000
+        0x85, 0x00, //       STA $00
+        0x8D, 0x00, 0x60, // STA $6000
+        0x4C, 0x00, 0x80, // JMP $8000
+    ];
+    let mut nes = Nes::new_from_buf(&roms::program_rom(program)).expect("Could not load test ROM");
+    nes.trace_next_frame();
+    nes.tick_frame();
+    let trace = nes
+        .take_bus_trace()
+        .expect("The frame should have been traced");
+    // $4000 is write-only, so reading it gives back the last byte of the
+    // instruction
+    let read = trace
+        .accesses()
+        .iter()
+        .find(|access| access.addr == 0x4000)
+        .expect("$4000 should have been read");
+    assert_eq!((read.device, read.value), (TraceDevice::ApuIo, 0x40));
+    assert!(trace.accesses().iter().any(|access| access.addr == 0x6000
+        && access.kind == AccessKind::Write
+        && access.device == TraceDevice::PrgRam
+        && access.value == 0x40));
+    assert_eq!(nes.debug_snapshot().ram[0], 0x40);
+}
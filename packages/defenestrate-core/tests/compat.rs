@@ -0,0 +1,271 @@
+//! A ROM compatibility harness
+//!
+//! This runs every ROM in a directory for about 10 seconds of emulated time,
+//! several ROMs at a time, and writes a report of how each one did. Since ROMs
+//! can't be checked in, it only runs when `DEFENESTRATE_COMPAT_ROMS` is set to
+//! the directory to test:
+//!
+//! ```sh
+//! DEFENESTRATE_COMPAT_ROMS=~/roms cargo test --release --test compat -- --nocapture
+//! ```
+//!
+//! The report is a Markdown table, written to `DEFENESTRATE_COMPAT_REPORT`
+//! (or `target/compat-report.md` if that isn't set).
+//!
+//! Each ROM runs twice, and a ROM is "stable" if both runs produced the same
+//! frames. A ROM "boots" if it gets through the run without panicking and puts
+//! more than one distinct frame on screen.
+
+extern crate defenestrate_core;
+
+mod util;
+
+use std::any::Any;
+use std::env;
+use std::fs;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+
+use defenestrate_core::devices::nes::Nes;
+use util::roms;
+
+/// About 10 seconds, at 60.0988 frames per second
+const COMPAT_FRAMES: usize = 601;
+
+/// How often to sample the frame hash
+const SAMPLE_INTERVAL: usize = 60;
+
+/// How a single ROM fared
+#[derive(Debug)]
+struct RomReport {
+    path: PathBuf,
+    /// The mapper number from the iNES header, if there was a header
+    mapper: Option<u8>,
+    outcome: Outcome,
+}
+
+#[derive(Debug, PartialEq)]
+enum Outcome {
+    /// The ROM couldn't be loaded (bad header, unsupported mapper, etc.)
+    LoadFailed(String),
+    /// The emulator panicked while running the ROM
+    Panicked(String),
+    /// The ROM ran to completion
+    Ran {
+        /// Whether more than one distinct frame was drawn
+        booted: bool,
+        /// Whether both runs drew the same frames
+        stable: bool,
+        /// The hash of the last frame of the first run
+        final_hash: u64,
+    },
+}
+
+/// Pull the mapper number out of an iNES header
+fn mapper_number(rom: &[u8]) -> Option<u8> {
+    if rom.len() < 16 || &rom[0..4] != b"NES\x1A" {
+        return None;
+    }
+    Some((rom[6] >> 4) | (rom[7] & 0xF0))
+}
+
+fn panic_message(payload: Box<dyn Any + Send>) -> String {
+    if let Some(msg) = payload.downcast_ref::<&str>() {
+        msg.to_string()
+    } else if let Some(msg) = payload.downcast_ref::<String>() {
+        msg.clone()
+    } else {
+        "(non-string panic payload)".to_string()
+    }
+}
+
+/// Run a ROM for `frames` frames, sampling the frame hash as it goes
+fn sample_hashes(rom: &[u8], frames: usize) -> Result<Vec<u64>, String> {
+    let mut nes = Nes::new_from_buf(rom).map_err(|err| err.to_string())?;
+    let mut hashes = Vec::with_capacity(frames / SAMPLE_INTERVAL + 1);
+    for frame in 1..=frames {
+        nes.tick_frame();
+        if frame % SAMPLE_INTERVAL == 0 || frame == frames {
+            hashes.push(nes.frame_hash());
+        }
+    }
+    Ok(hashes)
+}
+
+fn run_rom(path: &Path, frames: usize) -> RomReport {
+    let rom = match fs::read(path) {
+        Ok(rom) => rom,
+        Err(err) => {
+            return RomReport {
+                path: path.to_path_buf(),
+                mapper: None,
+                outcome: Outcome::LoadFailed(err.to_string()),
+            }
+        }
+    };
+    let run = || panic::catch_unwind(AssertUnwindSafe(|| sample_hashes(&rom, frames)));
+    let outcome = match (run(), run()) {
+        (Ok(Err(err)), _) => Outcome::LoadFailed(err),
+        (Err(payload), _) | (_, Err(payload)) => Outcome::Panicked(panic_message(payload)),
+        (Ok(Ok(first)), Ok(second)) => {
+            let mut distinct = first.clone();
+            distinct.sort_unstable();
+            distinct.dedup();
+            Outcome::Ran {
+                booted: distinct.len() > 1,
+                stable: second.as_ref() == Ok(&first),
+                final_hash: *first.last().unwrap_or(&0),
+            }
+        }
+    };
+    RomReport {
+        path: path.to_path_buf(),
+        mapper: mapper_number(&rom),
+        outcome,
+    }
+}
+
+/// Run every ROM, spread across one thread per core
+///
+/// Each ROM gets its own `Nes`, built on the thread that runs it.
+fn run_all(paths: &[PathBuf], frames: usize) -> Vec<RomReport> {
+    let next = AtomicUsize::new(0);
+    let reports = Mutex::new(Vec::with_capacity(paths.len()));
+    let workers = thread::available_parallelism()
+        .map(|n| n.get())
+        .unwrap_or(1)
+        .min(paths.len());
+    thread::scope(|scope| {
+        for _ in 0..workers {
+            scope.spawn(|| loop {
+                let idx = next.fetch_add(1, Ordering::Relaxed);
+                let Some(path) = paths.get(idx) else {
+                    break;
+                };
+                let report = run_rom(path, frames);
+                reports.lock().unwrap().push(report);
+            });
+        }
+    });
+    let mut reports = reports.into_inner().unwrap();
+    reports.sort_by(|a, b| a.path.cmp(&b.path));
+    reports
+}
+
+fn find_roms(dir: &Path) -> Vec<PathBuf> {
+    let mut paths: Vec<PathBuf> = fs::read_dir(dir)
+        .unwrap_or_else(|err| panic!("Could not read ROM dir {:?}: {}", dir, err))
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| {
+            path.extension()
+                .is_some_and(|ext| ext.eq_ignore_ascii_case("nes"))
+        })
+        .collect();
+    paths.sort();
+    paths
+}
+
+fn yes_no(value: bool) -> &'static str {
+    if value {
+        "yes"
+    } else {
+        "no"
+    }
+}
+
+fn format_report(reports: &[RomReport]) -> String {
+    let mut out = String::from("| ROM | Mapper | Boots | Stable | Final frame | Notes |\n");
+    out.push_str("| --- | --- | --- | --- | --- | --- |\n");
+    for report in reports {
+        let name = report.path.file_name().map_or_else(
+            || report.path.display().to_string(),
+            |name| name.to_string_lossy().into_owned(),
+        );
+        let mapper = report
+            .mapper
+            .map_or("?".to_string(), |mapper| mapper.to_string());
+        let row = match &report.outcome {
+            Outcome::LoadFailed(err) => format!("no | - | - | load failed: {}", err),
+            Outcome::Panicked(msg) => format!("no | - | - | panicked: {}", msg),
+            Outcome::Ran {
+                booted,
+                stable,
+                final_hash,
+            } => format!(
+                "{} | {} | {:016x} |",
+                yes_no(*booted),
+                yes_no(*stable),
+                final_hash
+            ),
+        };
+        out.push_str(&format!(
+            "| {} | {} | {} |\n",
+            name,
+            mapper,
+            row.replace('\n', " ")
+        ));
+    }
+    let booted = reports
+        .iter()
+        .filter(|report| matches!(report.outcome, Outcome::Ran { booted: true, .. }))
+        .count();
+    out.push_str(&format!("\n{} of {} ROMs booted\n", booted, reports.len()));
+    out
+}
+
+#[test]
+fn rom_compatibility() {
+    let dir = match env::var_os("DEFENESTRATE_COMPAT_ROMS") {
+        Some(dir) => PathBuf::from(dir),
+        None => {
+            println!("DEFENESTRATE_COMPAT_ROMS is not set, skipping the compatibility run");
+            return;
+        }
+    };
+    let report_path = env::var_os("DEFENESTRATE_COMPAT_REPORT")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from("./target/compat-report.md"));
+    let reports = run_all(&find_roms(&dir), COMPAT_FRAMES);
+    let report = format_report(&reports);
+    if let Some(parent) = report_path.parent() {
+        fs::create_dir_all(parent).expect("Could not create report dir");
+    }
+    fs::write(&report_path, &report).expect("Could not write report");
+    println!("{}", report);
+    println!("Report written to {:?}", report_path);
+}
+
+#[test]
+fn harness_reports_each_rom() {
+    let dir = env::temp_dir().join(format!("defenestrate-compat-{}", std::process::id()));
+    fs::create_dir_all(&dir).expect("Could not create temp dir");
+    fs::write(dir.join("scroll.nes"), roms::scroll_rom()).unwrap();
+    fs::write(dir.join("garbage.nes"), b"not a ROM").unwrap();
+    fs::write(dir.join("readme.txt"), b"not a ROM either").unwrap();
+
+    let reports = run_all(&find_roms(&dir), 90);
+    fs::remove_dir_all(&dir).ok();
+
+    assert_eq!(reports.len(), 2, "Expected only the .nes files to run");
+    assert!(matches!(reports[0].outcome, Outcome::LoadFailed(_)));
+    assert_eq!(reports[0].mapper, None);
+    assert_eq!(reports[1].mapper, Some(0));
+    assert!(
+        matches!(
+            reports[1].outcome,
+            Outcome::Ran {
+                booted: true,
+                stable: true,
+                ..
+            }
+        ),
+        "Unexpected outcome {:?}",
+        reports[1].outcome
+    );
+    let report = format_report(&reports);
+    assert!(report.contains("| scroll.nes | 0 | yes | yes |"));
+    assert!(report.contains("1 of 2 ROMs booted"));
+}
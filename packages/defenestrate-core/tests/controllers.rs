@@ -0,0 +1,92 @@
+//! Checks that the CPU sees controller reads the way games expect
+
+extern crate defenestrate_core;
+
+mod util;
+
+use defenestrate_core::devices::nes::{Buttons, Nes};
+use util::roms;
+
+/// Latch the controllers, then read port 1 twice and port 2 once into $00-$02
+const READ_CONTROLLERS: &[u8] = &[
+    0xA9, 0x01, //       LDA #$01
+    0x8D, 0x16, 0x40, // STA $4016
+    0xA9, 0x00, //       LDA #$00
+    0x8D, 0x16, 0x40, // STA $4016
+    0xAD, 0x16, 0x40, // LDA $4016
+    0x85, 0x00, //       STA $00
+    0xAD, 0x16, 0x40, // LDA $4016
+    0x85, 0x01, //       STA $01
+    0xAD, 0x17, 0x40, // LDA $4017
+    0x85, 0x02, //       STA $02
+    0x4C, 0x19, 0x80, // JMP $8019
+];
+
+#[test]
+fn reads_include_open_bus() {
+    let mut nes =
+        Nes::new_from_buf(&roms::program_rom(READ_CONTROLLERS)).expect("Could not load test ROM");
+    nes.set_buttons(0, Buttons::A);
+    nes.set_buttons(1, Buttons::B);
+    for _ in 0..12 {
+        nes.dbg_step_cpu();
+    }
+    let ram = nes.debug_snapshot().ram;
+    // the top bits are left over from the high byte of $4016
+    assert_eq!(&ram[0..3], &[0x41, 0x40, 0x40]);
+}
+
+#[test]
+fn holds_input_for_the_whole_frame() {
+    let mut nes =
+        Nes::new_from_buf(&roms::program_rom(READ_CONTROLLERS)).expect("Could not load test ROM");
+    nes.tick_frame_with_input(Buttons::A, Buttons::B);
+    assert_eq!(&nes.debug_snapshot().ram[0..3], &[0x41, 0x40, 0x40]);
+    assert_eq!(nes.buttons(0), Buttons::A);
+    assert_eq!(nes.buttons(1), Buttons::B);
+}
+
+/// Latch the controllers and copy the A button into $00, forever
+const POLL_A: &[u8] = &[
+    0xA9, 0x01, //       LDA #$01
+    0x8D, 0x16, 0x40, // STA $4016
+    0xA9, 0x00, //       LDA #$00
+    0x8D, 0x16, 0x40, // STA $4016
+    0xAD, 0x16, 0x40, // LDA $4016
+    0x29, 0x01, //       AND #$01
+    0x85, 0x00, //       STA $00
+    0x4C, 0x00, 0x80, // JMP $8000
+];
+
+#[test]
+fn turbo_buttons_toggle_between_frames() {
+    let mut nes = Nes::new_from_buf(&roms::program_rom(POLL_A)).expect("Could not load test ROM");
+    nes.set_buttons(0, Buttons::A);
+    nes.set_turbo(0, Buttons::A, 2);
+    let seen: Vec<u8> = (0..8)
+        .map(|_| {
+            nes.tick_frame();
+            nes.debug_snapshot().ram[0]
+        })
+        .collect();
+    assert_eq!(seen, vec![1, 1, 0, 0, 1, 1, 0, 0]);
+    // the host still sees the button as held
+    assert_eq!(nes.buttons(0), Buttons::A);
+    assert_eq!(nes.turbo(0), Buttons::A);
+}
+
+#[test]
+fn reports_the_latched_input_with_turbo() {
+    let mut nes = Nes::new_from_buf(&roms::program_rom(POLL_A)).expect("Could not load test ROM");
+    nes.set_buttons(0, Buttons::A | Buttons::START);
+    nes.set_turbo(0, Buttons::A, 1);
+    let seen: Vec<Buttons> = (0..4)
+        .map(|_| {
+            nes.tick_frame();
+            nes.last_latched_input(0)
+        })
+        .collect();
+    let pressed = Buttons::A | Buttons::START;
+    assert_eq!(seen, vec![pressed, Buttons::START, pressed, Buttons::START]);
+    assert_eq!(nes.last_latched_input(1), Buttons::empty());
+}
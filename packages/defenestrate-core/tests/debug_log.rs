@@ -0,0 +1,68 @@
+//! Checks the control flow notes and labels in `Nes::dbg_step_cpu_annotated`
+
+extern crate defenestrate_core;
+
+mod util;
+
+use defenestrate_core::devices::nes::{Nes, Symbols};
+use util::roms;
+
+/// Call a subroutine with a branch in it, then loop forever
+const CALL_AND_BRANCH: &[u8] = &[
+    0xA2, 0x00, //       LDX #$00
+    0x20, 0x07, 0x80, // JSR $8007
+    0xD0, 0xFE, //       BNE $8005      ; $8005
+    0xE8, //             INX            ; $8007
+    0xF0, 0x01, //       BEQ $800B
+    0x60, //             RTS
+];
+
+#[test]
+fn notes_depth_and_where_control_went() {
+    let mut nes =
+        Nes::new_from_buf(&roms::program_rom(CALL_AND_BRANCH)).expect("Could not load test ROM");
+    let notes: Vec<String> = (0..7)
+        .map(|_| {
+            let line = nes.dbg_step_cpu_annotated();
+            let note = line.find("  ; ").expect("Line wasn't annotated");
+            line[note + 4..].to_string()
+        })
+        .collect();
+    assert_eq!(
+        notes,
+        vec![
+            "depth 0",
+            "depth 0, -> $8007",
+            "depth 1",
+            "depth 1, not taken",
+            "depth 1, -> $8005",
+            "depth 0, taken -> $8005",
+            "depth 0, taken -> $8005",
+        ]
+    );
+}
+
+#[test]
+fn keeps_the_plain_log_line_first() {
+    let rom = roms::program_rom(CALL_AND_BRANCH);
+    let mut plain = Nes::new_from_buf(&rom).expect("Could not load test ROM");
+    let mut annotated = Nes::new_from_buf(&rom).expect("Could not load test ROM");
+    for _ in 0..4 {
+        let line = plain.dbg_step_cpu();
+        assert!(annotated.dbg_step_cpu_annotated().starts_with(&line));
+    }
+}
+
+#[test]
+fn labels_code_from_symbols() {
+    let mut nes =
+        Nes::new_from_buf(&roms::program_rom(CALL_AND_BRANCH)).expect("Could not load test ROM");
+    let symbols = Symbols::from_nl("$8005#spin#\n$8007#increment#\n").expect("Bad symbols");
+    nes.set_symbols(symbols);
+    let lines: Vec<String> = (0..6).map(|_| nes.dbg_step_cpu_annotated()).collect();
+    assert!(lines[1].ends_with("; depth 0, -> $8007 (increment)"));
+    assert!(lines[2].starts_with("increment:\n8007  E8"));
+    assert!(lines[4].ends_with("; depth 1, -> $8005 (spin)"));
+    assert!(lines[5].starts_with("spin:\n8005  D0 FE"));
+    assert!(!lines[0].contains('\n'));
+}
@@ -0,0 +1,81 @@
+//! Checks that frames can be diffed against reference screenshots
+
+extern crate defenestrate_core;
+
+mod util;
+
+use defenestrate_core::devices::nes::{Nes, NesConfig};
+use defenestrate_core::tools::{compare_frame, diff_frames, render_frame};
+use defenestrate_core::video::{encode_png, FRAME_HEIGHT, FRAME_WIDTH};
+use defenestrate_core::Error;
+use util::roms;
+
+#[test]
+fn renders_the_requested_frame() {
+    let rom = roms::scroll_rom();
+    let mut nes = Nes::new_from_buf(&rom).expect("Could not load test ROM");
+    for _ in 0..4 {
+        nes.tick_frame();
+    }
+    let expected = nes.tick_frame().to_vec();
+    let frame = render_frame(&rom, NesConfig::default(), 5).expect("Could not load test ROM");
+    assert!(frame == expected, "Rendered the wrong frame");
+}
+
+#[test]
+fn matches_a_screenshot_of_the_same_frame() {
+    let rom = roms::scroll_rom();
+    let frame = render_frame(&rom, NesConfig::default(), 5).expect("Could not load test ROM");
+    let reference = encode_png(FRAME_WIDTH, FRAME_HEIGHT, &frame);
+    let diff =
+        compare_frame(&rom, NesConfig::default(), 5, &reference).expect("Could not compare frames");
+    assert!(diff.is_match());
+    assert_eq!(diff.to_png().len(), reference.len());
+}
+
+#[test]
+fn counts_and_marks_differing_pixels() {
+    let rom = roms::scroll_rom();
+    let frame = render_frame(&rom, NesConfig::default(), 5).expect("Could not load test ROM");
+    let mut reference = frame.clone();
+    // change one channel of one pixel, and all of another
+    reference[(10 * FRAME_WIDTH + 20) * 3 + 1] ^= 0x01;
+    reference[..3].copy_from_slice(&[0x12, 0x34, 0x56]);
+    if frame[..3] == reference[..3] {
+        reference[0] ^= 0x80;
+    }
+    let diff = diff_frames(&frame, &reference);
+    assert_eq!(diff.differing_pixels(), 2);
+    let pixel = |x: usize, y: usize| &diff.image()[(y * FRAME_WIDTH + x) * 3..][..3];
+    assert_eq!(pixel(0, 0), &[0xFF, 0x00, 0x00]);
+    assert_eq!(pixel(20, 10), &[0xFF, 0x00, 0x00]);
+    let gray = pixel(1, 0);
+    assert!(
+        gray[0] == gray[1] && gray[1] == gray[2],
+        "Matches aren't gray"
+    );
+    assert!(gray[0] < 0x40, "Matches aren't dimmed");
+}
+
+#[test]
+fn rejects_references_of_the_wrong_size() {
+    let rom = roms::scroll_rom();
+    let cropped = encode_png(
+        FRAME_WIDTH,
+        FRAME_HEIGHT - 16,
+        &vec![0u8; FRAME_WIDTH * 224 * 3],
+    );
+    let err = compare_frame(&rom, NesConfig::default(), 1, &cropped)
+        .expect_err("Expected a size mismatch");
+    assert!(matches!(
+        err,
+        Error::ImageSizeMismatch {
+            width: 256,
+            height: 224
+        }
+    ));
+    assert!(matches!(
+        compare_frame(&rom, NesConfig::default(), 1, b"not a png"),
+        Err(Error::InvalidPng)
+    ));
+}
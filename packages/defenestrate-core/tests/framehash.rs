@@ -0,0 +1,137 @@
+//! Video regression tests, which compare frame hashes against goldens
+//!
+//! See `util/framehash.rs` for how to update the goldens.
+
+extern crate defenestrate_core;
+
+mod util;
+
+use defenestrate_core::devices::nes::{DirtyRect, Nes, FRAME_SIZE, INDEXED_FRAME_SIZE};
+use util::{framehash, roms};
+
+#[test]
+fn scroll_rom_matches_golden() {
+    let mut nes = Nes::new_from_buf(&roms::scroll_rom()).expect("Could not load test ROM");
+    let hashes = framehash::hash_frames(&mut nes, 8);
+    framehash::assert_golden("scroll", &hashes);
+}
+
+#[test]
+fn frame_hashes_are_deterministic() {
+    let run = || {
+        let mut nes = Nes::new_from_buf(&roms::scroll_rom()).expect("Could not load test ROM");
+        framehash::hash_frames(&mut nes, 4)
+    };
+    assert_eq!(run(), run());
+}
+
+#[test]
+fn loaded_palettes_change_the_output() {
+    let mut nes = Nes::new_from_buf(&roms::scroll_rom()).expect("Could not load test ROM");
+    nes.load_palette(&[0u8; 192]);
+    assert!(nes.tick_frame().iter().all(|&byte| byte == 0));
+    nes.reset_palette();
+    nes.tick_frame();
+    assert!(nes.tick_frame().iter().any(|&byte| byte != 0));
+}
+
+#[test]
+fn swapped_out_frames_survive_the_next_frame() {
+    let mut nes = Nes::new_from_buf(&roms::scroll_rom()).expect("Could not load test ROM");
+    nes.tick_frame();
+    let first = nes.tick_frame().to_vec();
+    let mut held: Box<[u8]> = Box::default();
+    nes.swap_buffers(&mut held);
+    assert_eq!(&held[..], &first[..]);
+    let second = nes.tick_frame().to_vec();
+    assert_ne!(first, second);
+    assert_eq!(&held[..], &first[..]);
+    nes.swap_buffers(&mut held);
+    assert_eq!(held.len(), FRAME_SIZE);
+    assert_eq!(&held[..], &second[..]);
+}
+
+#[test]
+fn scaled_frames_match_the_frame() {
+    let mut nes = Nes::new_from_buf(&roms::scroll_rom()).expect("Could not load test ROM");
+    let info = nes.frame_info();
+    assert_eq!((info.width, info.height), (256, 240));
+    assert!((info.pixel_aspect_ratio - 8.0 / 7.0).abs() < 1e-6);
+    nes.tick_frame();
+    let frame = nes.tick_frame().to_vec();
+    let scaled = nes.scale_frame(2);
+    assert_eq!(scaled.len(), FRAME_SIZE * 4);
+    let row = info.width * 3;
+    for (y, line) in frame.chunks(row).enumerate() {
+        for copy in &[2 * y, 2 * y + 1] {
+            let scaled_line = &scaled[copy * row * 2..(copy + 1) * row * 2];
+            for (x, pixel) in line.chunks(3).enumerate() {
+                assert_eq!(&scaled_line[x * 6..x * 6 + 3], pixel);
+                assert_eq!(&scaled_line[x * 6 + 3..x * 6 + 6], pixel);
+            }
+        }
+    }
+}
+
+#[test]
+fn dirty_rects_cover_every_changed_pixel() {
+    let mut nes = Nes::new_from_buf(&roms::scroll_rom()).expect("Could not load test ROM");
+    nes.set_dirty_tracking(true);
+    let mut last = nes.tick_frame().to_vec();
+    assert_eq!(nes.dirty_rects(), vec![DirtyRect::FULL_FRAME]);
+    for _ in 0..8 {
+        let (frame, rects) = nes.tick_frame_with_dirty_rects();
+        let covered = |x: usize, y: usize| {
+            rects.iter().any(|rect| {
+                (rect.x as usize..(rect.x + rect.width) as usize).contains(&x)
+                    && (rect.y as usize..(rect.y + rect.height) as usize).contains(&y)
+            })
+        };
+        for (i, (old, new)) in last.chunks(3).zip(frame.chunks(3)).enumerate() {
+            let (x, y) = (i % 256, i / 256);
+            assert!(
+                old == new || covered(x, y),
+                "({}, {}) changed outside the rects",
+                x,
+                y
+            );
+        }
+        last = frame.to_vec();
+    }
+}
+
+#[test]
+fn static_frames_have_no_dirty_rects() {
+    // JMP $8000, forever
+    let rom = roms::program_rom(&[0x4C, 0x00, 0x80]);
+    let mut nes = Nes::new_from_buf(&rom).expect("Could not load test ROM");
+    assert_eq!(nes.dirty_rects(), vec![DirtyRect::FULL_FRAME]);
+    nes.set_dirty_tracking(true);
+    for _ in 0..4 {
+        nes.tick_frame();
+    }
+    let (_, rects) = nes.tick_frame_with_dirty_rects();
+    assert_eq!(rects, vec![]);
+    // a new palette changes every pixel's RGB value, even if the colors don't
+    nes.reset_palette();
+    let (_, rects) = nes.tick_frame_with_dirty_rects();
+    assert_eq!(rects, vec![DirtyRect::FULL_FRAME]);
+}
+
+#[test]
+fn indexed_frames_match_the_frame() {
+    let mut nes = Nes::new_from_buf(&roms::scroll_rom()).expect("Could not load test ROM");
+    assert!(nes.tick_frame_indexed().is_none());
+    nes.set_indexed_output(true);
+    let frame = nes.tick_frame_indexed().expect("Indexed output is on");
+    assert_eq!(frame.as_bytes().len(), INDEXED_FRAME_SIZE);
+    for _ in 0..4 {
+        let rgb = nes.tick_frame().to_vec();
+        let frame = nes.indexed_frame().expect("Indexed output is on");
+        assert!(frame.pixels().iter().all(|&color| color < 0x40));
+        assert!(
+            frame.to_rgb(nes.output_palette()) == rgb,
+            "Indexed frame doesn't match"
+        );
+    }
+}
@@ -0,0 +1,69 @@
+//! Checks that hooks registered on the `Nes` fire when they should, using
+//! NESTEST as a convenient source of CPU activity.
+
+extern crate defenestrate_core;
+
+mod util;
+
+use std::sync::{Arc, Mutex};
+
+use defenestrate_core::devices::nes::{Nes, NesBuilder};
+use util::provider::NESTEST_ROM_PATH;
+use util::roms;
+
+fn load_nestest() -> Nes {
+    let rom = std::fs::read(NESTEST_ROM_PATH).expect("Could not read NESTEST rom");
+    NesBuilder::new()
+        .with_rom(&rom)
+        .expect("Could not load NESTEST rom")
+        .with_pc(0xC000)
+        .build()
+}
+
+#[test]
+fn exec_hooks_see_the_pc_before_the_instruction() {
+    let mut nes = load_nestest();
+    let hits = Arc::new(Mutex::new(Vec::new()));
+    let log = hits.clone();
+    nes.on_exec(0xC000, move |state| log.lock().unwrap().push(state.pc));
+    for _ in 0..100 {
+        nes.dbg_step_cpu();
+    }
+    assert_eq!(*hits.lock().unwrap(), vec![0xC000]);
+}
+
+#[test]
+fn write_hooks_see_writes_in_range() {
+    let mut nes = load_nestest();
+    let writes = Arc::new(Mutex::new(0));
+    let count = writes.clone();
+    nes.on_write(0x0000..=0x07FF, move |addr, _| {
+        assert!(addr <= 0x07FF);
+        *count.lock().unwrap() += 1;
+    });
+    for _ in 0..1000 {
+        nes.dbg_step_cpu();
+    }
+    assert!(*writes.lock().unwrap() > 0);
+}
+
+#[test]
+fn scanline_hooks_fire_once_per_line() {
+    let mut nes = Nes::new_from_buf(&roms::scroll_rom()).expect("Could not load test ROM");
+    nes.tick_frame();
+    let lines = Arc::new(Mutex::new(Vec::new()));
+    let log = lines.clone();
+    let id = nes.on_scanline(move |scanline, view| {
+        assert_eq!(view.dot, 0);
+        assert_eq!(view.scanline as u16, scanline);
+        log.lock().unwrap().push(scanline);
+    });
+    nes.tick_frame();
+    let mut expected: Vec<u16> = (1..262).collect();
+    expected.push(0);
+    assert_eq!(*lines.lock().unwrap(), expected);
+
+    assert!(nes.remove_hook(id));
+    nes.tick_frame();
+    assert_eq!(lines.lock().unwrap().len(), 262);
+}
@@ -0,0 +1,63 @@
+//! Checks that the core keeps its logging out of the way while running
+
+extern crate defenestrate_core;
+
+mod util;
+
+use std::sync::Mutex;
+
+use defenestrate_core::devices::nes::{Nes, NesBuilder};
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use util::provider::NESTEST_ROM_PATH;
+use util::roms;
+
+/// A logger that keeps the level of every record
+struct Capture {
+    levels: Mutex<Vec<Level>>,
+}
+
+impl Log for Capture {
+    fn enabled(&self, _: &Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &Record) {
+        self.levels.lock().unwrap().push(record.level());
+    }
+
+    fn flush(&self) {}
+}
+
+static CAPTURE: Capture = Capture {
+    levels: Mutex::new(Vec::new()),
+};
+
+#[test]
+fn running_games_only_logs_at_debug_and_below() {
+    log::set_logger(&CAPTURE).expect("Could not install the logger");
+    log::set_max_level(LevelFilter::Trace);
+
+    let rom = std::fs::read(NESTEST_ROM_PATH).expect("Could not read NESTEST rom");
+    let mut nes = NesBuilder::new()
+        .with_rom(&rom)
+        .expect("Could not load NESTEST rom")
+        .with_pc(0xC000)
+        .build();
+    for _ in 0..8991 {
+        nes.dbg_step_cpu();
+    }
+    let mut nes = Nes::new_from_buf(&roms::scroll_rom()).expect("Could not load test ROM");
+    for _ in 0..4 {
+        nes.tick_frame();
+    }
+
+    let levels = CAPTURE.levels.lock().unwrap();
+    assert!(
+        levels.iter().all(|&level| level >= Level::Debug),
+        "Expected only debug and trace logs, got {:?}",
+        levels
+            .iter()
+            .filter(|&&level| level < Level::Debug)
+            .collect::<Vec<_>>()
+    );
+}
@@ -0,0 +1,394 @@
+//! Runs small programs on boards other than NROM, to check that they're wired
+//! up to the console correctly
+
+extern crate defenestrate_core;
+
+mod util;
+
+use defenestrate_core::devices::nes::{
+    CartridgeState, Console, IrqSource, NROMCartridge, NametableArrangement, Nes, NesConfig,
+    PowerOnConfig,
+};
+use defenestrate_core::Error;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use util::roms;
+
+/// Count 1000 CPU cycles with the FME-7's IRQ counter, and count IRQs at $00
+///
+/// This lives in the fixed bank at $E000.
+const FME7_IRQ_PROGRAM: &[u8] = &[
+    0x78, //             SEI
+    0xA9, 0x40, //       LDA #$40       ; no APU frame IRQs
+    0x8D, 0x17, 0x40, // STA $4017
+    0xA9, 0x0E, //       LDA #$0E       ; counter low byte
+    0x8D, 0x00, 0x80, // STA $8000
+    0xA9, 0xE8, //       LDA #$E8
+    0x8D, 0x00, 0xA0, // STA $A000
+    0xA9, 0x0F, //       LDA #$0F       ; counter high byte
+    0x8D, 0x00, 0x80, // STA $8000
+    0xA9, 0x03, //       LDA #$03
+    0x8D, 0x00, 0xA0, // STA $A000
+    0xA9, 0x0D, //       LDA #$0D       ; IRQ control
+    0x8D, 0x00, 0x80, // STA $8000
+    0xA9, 0x81, //       LDA #$81       ; count, and raise an IRQ
+    0x8D, 0x00, 0xA0, // STA $A000
+    0x58, //             CLI
+    0x4C, 0x25, 0xE0, // JMP $E025
+    0xE6, 0x00, //       INC $00        ; $E028: IRQ handler
+    0xA9, 0x00, //       LDA #$00       ; acknowledge, and stop counting
+    0x8D, 0x00, 0xA0, // STA $A000
+    0x40, //             RTI
+];
+
+/// After `roms::SETUP_PROGRAM`, flip the FME-7's first CHR bank back and forth
+/// as fast as possible, so that most switches land partway through a line
+const FME7_CHR_SWITCH_PROGRAM: &[u8] = &[
+    0xA9, 0x00, //       LDA #$00       ; $804E: select the first CHR bank
+    0x8D, 0x00, 0x80, // STA $8000
+    0xA9, 0x09, //       LDA #$09       ; $8053
+    0x8D, 0x00, 0xA0, // STA $A000
+    0xA9, 0x00, //       LDA #$00
+    0x8D, 0x00, 0xA0, // STA $A000
+    0x4C, 0x53, 0x80, // JMP $8053
+];
+
+/// Count 1000 CPU cycles with the Bandai FCG's IRQ counter, and count IRQs at
+/// $00
+///
+/// This lives in the fixed bank at $C000.
+const BANDAI_IRQ_PROGRAM: &[u8] = &[
+    0x78, //             SEI
+    0xA9, 0x40, //       LDA #$40       ; no APU frame IRQs
+    0x8D, 0x17, 0x40, // STA $4017
+    0xA9, 0xE8, //       LDA #$E8       ; counter low byte
+    0x8D, 0x0B, 0x80, // STA $800B
+    0xA9, 0x03, //       LDA #$03       ; counter high byte
+    0x8D, 0x0C, 0x80, // STA $800C
+    0xA9, 0x01, //       LDA #$01       ; raise an IRQ
+    0x8D, 0x0A, 0x80, // STA $800A
+    0x58, //             CLI
+    0x4C, 0x16, 0xC0, // JMP $C016
+    0xE6, 0x00, //       INC $00        ; $C019: IRQ handler
+    0xA9, 0x00, //       LDA #$00       ; acknowledge, and stop counting
+    0x8D, 0x0A, 0x80, // STA $800A
+    0x40, //             RTI
+];
+
+/// After `roms::SETUP_PROGRAM`, flip the Bandai FCG's first CHR bank and its
+/// mirroring back and forth as fast as possible
+const BANDAI_CHR_SWITCH_PROGRAM: &[u8] = &[
+    0xA9, 0x09, //       LDA #$09       ; $804E
+    0x8D, 0x00, 0x80, // STA $8000
+    0xA9, 0x03, //       LDA #$03       ; one screen, from the second nametable
+    0x8D, 0x09, 0x80, // STA $8009
+    0xA9, 0x00, //       LDA #$00
+    0x8D, 0x00, 0x80, // STA $8000
+    0x8D, 0x09, 0x80, // STA $8009
+    0x4C, 0x4E, 0x80, // JMP $804E
+];
+
+/// After `roms::SETUP_PROGRAM`, flip the Namco 163's first CHR bank and first
+/// nametable back and forth as fast as possible
+///
+/// The nametables start out in CHR ROM, so the setup program's nametable
+/// writes are lost, but there's still plenty on screen.
+const NAMCO163_CHR_SWITCH_PROGRAM: &[u8] = &[
+    0xA9, 0x09, //       LDA #$09       ; $804E
+    0x8D, 0x00, 0x80, // STA $8000
+    0xA9, 0xE0, //       LDA #$E0       ; the first nametable in CIRAM
+    0x8D, 0x00, 0xC0, // STA $C000
+    0xA9, 0x00, //       LDA #$00       ; back to CHR ROM
+    0x8D, 0x00, 0x80, // STA $8000
+    0x8D, 0x00, 0xC0, // STA $C000
+    0x4C, 0x4E, 0x80, // JMP $804E
+];
+
+/// Count 1000 CPU cycles with the FDS timer, and count IRQs at $00
+///
+/// This stands in for the BIOS, at $E000.
+const FDS_IRQ_PROGRAM: &[u8] = &[
+    0x78, //             SEI
+    0xA9, 0x40, //       LDA #$40       ; no APU frame IRQs
+    0x8D, 0x17, 0x40, // STA $4017
+    0xA9, 0x01, //       LDA #$01       ; enable the disk registers
+    0x8D, 0x23, 0x40, // STA $4023
+    0xA9, 0xE8, //       LDA #$E8       ; reload value
+    0x8D, 0x20, 0x40, // STA $4020
+    0xA9, 0x03, //       LDA #$03
+    0x8D, 0x21, 0x40, // STA $4021
+    0xA9, 0x02, //       LDA #$02       ; start the timer, without repeat
+    0x8D, 0x22, 0x40, // STA $4022
+    0x58, //             CLI
+    0x4C, 0x1B, 0xE0, // JMP $E01B
+    0xE6, 0x00, //       INC $00        ; $E01E: IRQ handler
+    0xAD, 0x30, 0x40, // LDA $4030      ; acknowledge
+    0x40, //             RTI
+];
+
+/// Build an 8k FDS BIOS that runs `program` from $E000
+fn fds_bios(program: &[u8]) -> Vec<u8> {
+    let mut bios = vec![0u8; 0x2000];
+    bios[..program.len()].copy_from_slice(program);
+    // reset vector
+    bios[0x1FFC] = 0x00;
+    bios[0x1FFD] = 0xE0;
+    // IRQ vector
+    bios[0x1FFE] = 0x1E;
+    bios[0x1FFF] = 0xE0;
+    bios
+}
+
+/// Build a headerless .fds image with `sides` blank sides
+fn fds_disk(sides: usize) -> Vec<u8> {
+    let mut side = b"\x01*NINTENDO-HVC*".to_vec();
+    side.resize(65500, 0);
+    side.repeat(sides)
+}
+
+/// Build a 32k PRG, 8k CHR FME-7 ROM that runs `program` from $E000
+fn fme7_rom(program: &[u8]) -> Vec<u8> {
+    let mut rom = vec![
+        0x4E, 0x45, 0x53, 0x1A, 2, 1, 0x50, 0x40, 0, 0, 0, 0, 0, 0, 0, 0,
+    ];
+    let mut prg = vec![0u8; 0x8000];
+    prg[0x6000..0x6000 + program.len()].copy_from_slice(program);
+    // reset vector
+    prg[0x7FFC] = 0x00;
+    prg[0x7FFD] = 0xE0;
+    // IRQ vector
+    prg[0x7FFE] = 0x28;
+    prg[0x7FFF] = 0xE0;
+    rom.extend(prg);
+    rom.extend(vec![0u8; 0x2000]);
+    rom
+}
+
+/// Build a 32k PRG, 16k CHR FME-7 ROM that runs `roms::setup_prg(program)`
+fn fme7_setup_rom(program: &[u8]) -> Vec<u8> {
+    let mut rom = vec![
+        0x4E, 0x45, 0x53, 0x1A, 2, 2, 0x50, 0x40, 0, 0, 0, 0, 0, 0, 0, 0,
+    ];
+    // the setup program runs from the first 8k bank, which every switchable
+    // slot starts out on, and its vectors end up in the last one
+    rom.extend(roms::setup_prg(program).repeat(2));
+    rom.extend(roms::busy_chr(2));
+    rom
+}
+
+/// Run `rom` with and without the batch renderer, and check that every frame
+/// comes out the same
+fn assert_batch_rendering_matches(rom: &[u8]) {
+    let config = NesConfig::ntsc();
+    let mut accurate =
+        Nes::new_from_buf_with_config(rom, config.clone().with_batch_rendering(false))
+            .expect("Could not load test ROM");
+    let mut batched = Nes::new_from_buf_with_config(rom, config.with_batch_rendering(true))
+        .expect("Could not load test ROM");
+    let mut hashes = Vec::new();
+    for frame in 0..6 {
+        accurate.tick_frame();
+        batched.tick_frame();
+        assert_eq!(
+            accurate.frame_hash(),
+            batched.frame_hash(),
+            "Frame {} mismatch",
+            frame
+        );
+        hashes.push(batched.frame_hash());
+    }
+    hashes.dedup();
+    assert!(hashes.len() > 1, "Nothing was drawn");
+}
+
+/// Build a 32k PRG, 8k CHR Bandai FCG ROM that runs `program` from $C000
+fn bandai_rom(program: &[u8]) -> Vec<u8> {
+    let mut rom = vec![
+        0x4E, 0x45, 0x53, 0x1A, 2, 1, 0x00, 0x10, 0, 0, 0, 0, 0, 0, 0, 0,
+    ];
+    let mut prg = vec![0u8; 0x8000];
+    prg[0x4000..0x4000 + program.len()].copy_from_slice(program);
+    // reset vector
+    prg[0x7FFC] = 0x00;
+    prg[0x7FFD] = 0xC0;
+    // IRQ vector
+    prg[0x7FFE] = 0x19;
+    prg[0x7FFF] = 0xC0;
+    rom.extend(prg);
+    rom.extend(vec![0u8; 0x2000]);
+    rom
+}
+
+#[test]
+fn loads_fme7_roms() {
+    let nes = Nes::new_from_buf(&fme7_rom(&[])).expect("Could not load test ROM");
+    assert!(matches!(nes.debug_snapshot().cart, CartridgeState::FME7(_)));
+}
+
+#[test]
+fn delivers_fme7_irqs() {
+    let mut nes = Nes::new_from_buf(&fme7_rom(FME7_IRQ_PROGRAM)).expect("Could not load test ROM");
+    nes.tick_frame();
+    nes.tick_frame();
+    let snapshot = nes.debug_snapshot();
+    assert_eq!(snapshot.ram[0x00], 1, "Expected exactly one IRQ");
+    assert_eq!(
+        snapshot.cpu.pc & 0xFFF0,
+        0xE020,
+        "CPU is not in the main loop"
+    );
+}
+
+#[test]
+fn renders_fme7_bank_switches_with_batching() {
+    assert_batch_rendering_matches(&fme7_setup_rom(FME7_CHR_SWITCH_PROGRAM));
+}
+
+#[test]
+fn delivers_bandai_irqs() {
+    let mut nes =
+        Nes::new_from_buf(&bandai_rom(BANDAI_IRQ_PROGRAM)).expect("Could not load test ROM");
+    assert!(matches!(
+        nes.debug_snapshot().cart,
+        CartridgeState::BandaiFCG(_)
+    ));
+    // the counter starts at 1000 and counts on the cycle it's written, so it
+    // wraps from 0 (which is when the IRQ fires) 1000 CPU cycles later
+    let started = Arc::new(AtomicBool::new(false));
+    let hook_started = started.clone();
+    nes.on_write(0x800A..=0x800A, move |_, _| {
+        hook_started.store(true, Ordering::Relaxed);
+    });
+    let mut ppu_cycles = 0;
+    while !nes.irq_line().sources().contains(IrqSource::MAPPER) {
+        if started.load(Ordering::Relaxed) {
+            ppu_cycles += 1;
+        }
+        nes.tick();
+    }
+    assert_eq!(ppu_cycles, 3 * 1000, "IRQ fired on the wrong cycle");
+    nes.tick_frame();
+    nes.tick_frame();
+    let snapshot = nes.debug_snapshot();
+    assert_eq!(snapshot.ram[0x00], 1, "Expected exactly one IRQ");
+    assert_eq!(
+        snapshot.cpu.pc & 0xFFF0,
+        0xC010,
+        "CPU is not in the main loop"
+    );
+}
+
+#[test]
+fn renders_bandai_bank_switches_with_batching() {
+    let mut rom = vec![
+        0x4E, 0x45, 0x53, 0x1A, 2, 2, 0x00, 0x10, 0, 0, 0, 0, 0, 0, 0, 0,
+    ];
+    // the switchable bank starts out on the first 16k, and the fixed bank is
+    // the same program
+    rom.extend(roms::setup_prg(BANDAI_CHR_SWITCH_PROGRAM).repeat(2));
+    rom.extend(roms::busy_chr(2));
+    assert_batch_rendering_matches(&rom);
+}
+
+#[test]
+fn renders_namco163_bank_switches_with_batching() {
+    let mut rom = vec![
+        0x4E, 0x45, 0x53, 0x1A, 2, 2, 0x30, 0x10, 0, 0, 0, 0, 0, 0, 0, 0,
+    ];
+    // like the FME-7, every switchable slot starts out on the first 8k
+    rom.extend(roms::setup_prg(NAMCO163_CHR_SWITCH_PROGRAM).repeat(2));
+    rom.extend(roms::busy_chr(2));
+    assert_batch_rendering_matches(&rom);
+}
+
+#[test]
+fn mappers_hold_the_irq_line_until_acknowledged() {
+    // the same program, with IRQs left disabled
+    let mut program = BANDAI_IRQ_PROGRAM.to_vec();
+    assert_eq!(program[21], 0x58);
+    program[21] = 0xEA; // NOP in place of CLI
+    let mut nes = Nes::new_from_buf(&bandai_rom(&program)).expect("Could not load test ROM");
+    assert!(!nes.irq_line().is_asserted());
+    nes.tick_frame();
+    nes.tick_frame();
+    assert_eq!(nes.irq_line().sources(), IrqSource::MAPPER);
+    assert_eq!(nes.debug_snapshot().ram[0x00], 0, "IRQ was not masked");
+
+    let mut nes =
+        Nes::new_from_buf(&bandai_rom(BANDAI_IRQ_PROGRAM)).expect("Could not load test ROM");
+    nes.tick_frame();
+    nes.tick_frame();
+    assert!(
+        !nes.irq_line().is_asserted(),
+        "The handler didn't release the line"
+    );
+}
+
+#[test]
+fn keeps_bandai_eeprom_saves() {
+    let mut nes = Nes::new_from_buf(&bandai_rom(&[])).expect("Could not load test ROM");
+    assert_eq!(nes.save_data(), Some(&[0xFF; 256][..]));
+    let save: Vec<u8> = (0..=255).collect();
+    nes.load_save_data(&save);
+    nes.power_cycle();
+    assert_eq!(nes.save_data(), Some(&save[..]));
+    // boards without a battery have nothing to save
+    let nes = Nes::new_from_buf(&fme7_rom(&[])).expect("Could not load test ROM");
+    assert_eq!(nes.save_data(), None);
+}
+
+#[test]
+fn delivers_fds_timer_irqs() {
+    let mut nes = Nes::new_from_fds(&fds_bios(FDS_IRQ_PROGRAM), &fds_disk(1))
+        .expect("Could not load test disk");
+    assert!(matches!(nes.debug_snapshot().cart, CartridgeState::FDS(_)));
+    assert_eq!(nes.config().power_on.console, Console::Famicom);
+    for _ in 0..10 {
+        nes.tick_frame();
+    }
+    assert_eq!(nes.debug_snapshot().ram[0], 1);
+}
+
+#[test]
+fn switches_disk_sides() {
+    let mut nes =
+        Nes::new_from_fds(&fds_bios(&[]), &fds_disk(2)).expect("Could not load test disk");
+    let fds = nes.fds_mut().expect("FDS should be plugged in");
+    assert_eq!(fds.disk().side_count(), 2);
+    assert_eq!(fds.inserted_side(), Some(0));
+    fds.insert_side(None).expect("Could not eject the disk");
+    assert_eq!(fds.inserted_side(), None);
+    fds.insert_side(Some(1)).expect("Could not insert side B");
+    assert_eq!(fds.inserted_side(), Some(1));
+    assert!(matches!(
+        fds.insert_side(Some(2)),
+        Err(Error::NoSuchDiskSide {
+            side: 2,
+            side_count: 2
+        })
+    ));
+    assert_eq!(fds.inserted_side(), Some(1), "A bad side changed the drive");
+}
+
+#[test]
+fn only_the_fds_has_a_disk_drive() {
+    let mut nes = Nes::new_from_buf(&fme7_rom(&[])).expect("Could not load test ROM");
+    assert!(nes.fds_mut().is_none());
+}
+
+#[test]
+fn runs_carts_built_from_parts() {
+    // LDA #$42; STA $00; loop: JMP loop
+    let program = [0xA9, 0x42, 0x85, 0x00, 0x4C, 0x04, 0x80];
+    let mut prg = vec![0u8; 0x4000];
+    prg[..program.len()].copy_from_slice(&program);
+    // reset vector
+    prg[0x3FFC] = 0x00;
+    prg[0x3FFD] = 0x80;
+    let cart = NROMCartridge::from_parts(prg, vec![0u8; 0x2000], NametableArrangement::Vertical);
+    let mut nes = Nes::new(Box::new(cart), PowerOnConfig::default());
+    nes.tick_frame();
+    assert_eq!(nes.debug_snapshot().ram[0x00], 0x42);
+    assert!(matches!(nes.debug_snapshot().cart, CartridgeState::NROM(_)));
+}
@@ -0,0 +1,113 @@
+//! Checks that a `Nes` can be taken apart and put back together, with or
+//! without swapping out its devices
+
+extern crate defenestrate_core;
+
+mod util;
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use defenestrate_core::devices::cpu::Motherboard;
+use defenestrate_core::devices::nes::{BusDevice, BusPeekResult, IRam, Nes, NesBuilder, Ram};
+use util::provider::NESTEST_ROM_PATH;
+
+fn load_nestest() -> Nes {
+    let rom = std::fs::read(NESTEST_ROM_PATH).expect("Could not read NESTEST rom");
+    NesBuilder::new()
+        .with_rom(&rom)
+        .expect("Could not load NESTEST rom")
+        .with_pc(0xC000)
+        .build()
+}
+
+/// RAM that counts the writes it passes through to another
+struct CountingRam {
+    inner: Box<dyn IRam>,
+    writes: Arc<AtomicUsize>,
+}
+
+impl BusDevice for CountingRam {
+    fn read(&mut self, addr: u16, last_bus_value: u8) -> u8 {
+        self.inner.read(addr, last_bus_value)
+    }
+    fn peek(&self, addr: u16) -> BusPeekResult {
+        self.inner.peek(addr)
+    }
+    fn write(&mut self, addr: u16, value: u8) {
+        self.writes.fetch_add(1, Ordering::Relaxed);
+        self.inner.write(addr, value)
+    }
+}
+
+impl IRam for CountingRam {
+    fn dump(&self) -> &[u8] {
+        self.inner.dump()
+    }
+    fn load(&mut self, offset: usize, data: &[u8]) {
+        self.inner.load(offset, data)
+    }
+}
+
+#[test]
+fn round_trip_picks_up_where_it_left_off() {
+    let mut nes = load_nestest();
+    let mut untouched = load_nestest();
+    for _ in 0..500 {
+        nes.dbg_step_cpu();
+        untouched.dbg_step_cpu();
+    }
+    let mut nes = Nes::from_parts(nes.into_parts());
+    for _ in 0..500 {
+        nes.dbg_step_cpu();
+        untouched.dbg_step_cpu();
+    }
+    assert!(nes.debug_snapshot() == untouched.debug_snapshot());
+}
+
+#[test]
+fn swapped_ram_is_used() {
+    let mut untouched = load_nestest();
+    let mut parts = load_nestest().into_parts();
+    let writes = Arc::new(AtomicUsize::new(0));
+    parts.ram = Box::new(CountingRam {
+        inner: parts.ram,
+        writes: writes.clone(),
+    });
+    let mut nes = Nes::from_parts(parts);
+    for _ in 0..500 {
+        nes.dbg_step_cpu();
+        untouched.dbg_step_cpu();
+    }
+    assert!(writes.load(Ordering::Relaxed) > 0, "RAM was never written");
+    assert!(nes.debug_snapshot() == untouched.debug_snapshot());
+}
+
+#[test]
+fn swapped_ram_survives_a_power_cycle() {
+    let mut parts = load_nestest().into_parts();
+    let mut image = vec![0u8; 0x800];
+    image[0x0123] = 0x42;
+    let writes = Arc::new(AtomicUsize::new(0));
+    parts.ram = Box::new(CountingRam {
+        inner: Box::new(Ram::new_from_buf(0x800, &image)),
+        writes: writes.clone(),
+    });
+    let mut nes = Nes::from_parts(parts);
+    assert_eq!(nes.peek(0x0123), Some(0x42));
+    // RAM is mirrored every 2k
+    assert_eq!(nes.peek(0x1923), Some(0x42));
+    nes.power_cycle();
+    // power-on refills the RAM that's there, rather than replacing it
+    assert_eq!(nes.peek(0x0123), Some(0x00));
+    nes.write(0x0123, 0x99);
+    assert_eq!(writes.load(Ordering::Relaxed), 1);
+}
+
+#[test]
+#[should_panic(expected = "Internal RAM has to be 2k")]
+fn rejects_ram_of_the_wrong_size() {
+    let mut parts = load_nestest().into_parts();
+    parts.ram = Box::new(Ram::new(0x400));
+    Nes::from_parts(parts);
+}
@@ -22,8 +22,7 @@ mod util;
 
 use util::{logparse, provider};
 
-use defenestrate_core::devices::cpu::WithCpu;
-use defenestrate_core::devices::nes::Nes;
+use defenestrate_core::devices::nes::NesBuilder;
 use provider::NESTEST_ROM_PATH;
 
 // If true, test Nestest to completion
@@ -31,12 +30,15 @@ const TEST_ILLEGAL_OPCODES: bool = false;
 
 #[test]
 fn nestest_exec() {
-    let mut nes = Nes::new_from_file(&NESTEST_ROM_PATH).expect("Could not read NESTEST rom");
+    let rom = std::fs::read(NESTEST_ROM_PATH).expect("Could not read NESTEST rom");
+    let mut nes = NesBuilder::new()
+        .with_rom(&rom)
+        .expect("Could not load NESTEST rom")
+        .with_pc(0xC000)
+        .build();
 
     let gold_log = provider::load_gold_standard_log();
 
-    nes.cpu_mut().state.pc = 0xC000;
-
     let mut line = 1;
 
     for gold_line in gold_log {
@@ -0,0 +1,70 @@
+//! Runs two consoles in lockstep, passing messages between them as bytes
+
+extern crate defenestrate_core;
+
+mod util;
+
+use defenestrate_core::devices::nes::{Buttons, Nes, NesConfig, RamPattern};
+use defenestrate_core::netplay::{Lockstep, Message};
+use util::roms;
+
+fn scroll_nes(ram_pattern: RamPattern) -> Nes {
+    let config = NesConfig::ntsc().with_ram_pattern(ram_pattern);
+    Nes::new_from_buf_with_config(&roms::scroll_rom(), config).expect("Could not load test ROM")
+}
+
+/// Send a message over the "network"
+fn send(message: Message, to: &mut Lockstep) {
+    to.receive(Message::from_bytes(&message.to_bytes()).expect("Message was garbled"));
+}
+
+/// Run both sides for `frames` frames, with the second side's inputs showing
+/// up a frame late
+fn run_session(left: &mut Nes, right: &mut Nes, frames: u64) -> (Lockstep, Lockstep) {
+    let mut left_side = Lockstep::new(0, 10);
+    let mut right_side = Lockstep::new(1, 10);
+    let mut in_flight = None;
+    for frame in 0..frames {
+        let message = left_side.add_local_input(Buttons::from_bits_truncate(frame as u8));
+        send(message, &mut right_side);
+        if let Some(message) = in_flight.take() {
+            send(message, &mut left_side);
+        }
+        in_flight = Some(right_side.add_local_input(Buttons::A));
+        left_side.advance(left);
+        right_side.advance(right);
+        for message in left_side.take_outgoing() {
+            send(message, &mut right_side);
+        }
+        for message in right_side.take_outgoing() {
+            send(message, &mut left_side);
+        }
+    }
+    if let Some(message) = in_flight {
+        send(message, &mut left_side);
+        left_side.advance(left);
+    }
+    (left_side, right_side)
+}
+
+#[test]
+fn stays_in_sync() {
+    let mut left = scroll_nes(RamPattern::AllZero);
+    let mut right = scroll_nes(RamPattern::AllZero);
+    let (left_side, right_side) = run_session(&mut left, &mut right, 60);
+    assert_eq!(left_side.frame(), 60);
+    assert_eq!(right_side.frame(), 60);
+    assert_eq!(left_side.desync(), None);
+    assert_eq!(right_side.desync(), None);
+    assert_eq!(left.state_checksum(), right.state_checksum());
+    assert_eq!(left.frame_hash(), right.frame_hash());
+}
+
+#[test]
+fn notices_a_desync() {
+    let mut left = scroll_nes(RamPattern::AllZero);
+    let mut right = scroll_nes(RamPattern::AllFF);
+    let (left_side, right_side) = run_session(&mut left, &mut right, 30);
+    assert_eq!(left_side.desync(), Some(10));
+    assert_eq!(right_side.desync(), Some(10));
+}
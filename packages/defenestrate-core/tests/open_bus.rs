@@ -0,0 +1,71 @@
+//! Checks what the CPU sees when it reads the write-only and unused registers
+//! at $4000-$401F, including when it runs code from them, like blargg's
+//! cpu_exec_space_apu test does, and what writes to the PPU's read-only
+//! PPUSTATUS do
+
+extern crate defenestrate_core;
+
+mod util;
+
+use defenestrate_core::devices::nes::{Comparator, Nes, Probe};
+use defenestrate_core::prelude::Motherboard;
+use util::roms;
+
+fn load(program: &[u8]) -> Nes {
+    Nes::new_from_buf(&roms::program_rom(program)).expect("Could not load test ROM")
+}
+
+#[test]
+fn write_only_registers_read_back_the_last_bus_value() {
+    let mut nes = load(&[0x4C, 0x00, 0x80]);
+    for &addr in &[0x4000, 0x4014, 0x4018, 0x401F] {
+        nes.write(0x0000, addr as u8);
+        assert_eq!(nes.read(addr), addr as u8, "${:04X} isn't open bus", addr);
+    }
+}
+
+#[test]
+fn apu_status_only_leaves_bit_5_open() {
+    let mut nes = load(&[0x4C, 0x00, 0x80]);
+    nes.write(0x0000, 0xFF);
+    assert_eq!(nes.read(0x4015), 0x20);
+    // and reading it doesn't change what's on the bus
+    assert_eq!(nes.read(0x4000), 0xFF);
+    nes.write(0x0000, 0xDF);
+    assert_eq!(nes.read(0x4015), 0x00);
+    assert_eq!(nes.peek(0x4015), None);
+    assert_eq!(nes.peek(0x4000), None);
+}
+
+#[test]
+fn runs_code_from_open_bus() {
+    let program = &[
+        0xA9, 0x80, //       LDA #$80
+        0x48, //             PHA
+        0xA9, 0x10, //       LDA #$10
+        0x48, //             PHA
+        0xA9, 0x00, //       LDA #$00
+        0x48, //             PHA
+        // the last byte on the bus is $40, so this runs an RTI, which returns
+        // to the address pushed above
+        0x4C, 0x18, 0x40, // JMP $4018
+        0xEA, 0xEA, 0xEA, 0xEA, // NOP...
+        0xA9, 0x42, //       LDA #$42
+        0x85, 0x00, //       STA $00
+        0x4C, 0x14, 0x80, // JMP $8014
+    ];
+    let mut nes = load(program);
+    nes.add_probe(Probe::new(0x0000, Comparator::Equal, 0x42));
+    assert!(nes.run_until_probe(1_000).is_some());
+}
+
+#[test]
+fn ppustatus_writes_only_fill_the_ppu_latch() {
+    let mut nes = load(&[0x4C, 0x00, 0x80]);
+    // $3FFA mirrors $2002
+    for &(addr, value) in &[(0x2002, 0x5A), (0x3FFA, 0x3C)] {
+        nes.write(addr, value);
+        // the write-only PPUCTRL reads back the PPU's latch
+        assert_eq!(nes.read(0x2000), value, "Write to ${:04X} was lost", addr);
+    }
+}
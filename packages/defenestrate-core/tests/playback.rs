@@ -0,0 +1,72 @@
+//! Checks pausing, frame advance, and speed control through `Nes::run_for`
+
+extern crate defenestrate_core;
+
+mod util;
+
+use std::time::Duration;
+
+use defenestrate_core::devices::nes::{Nes, Region};
+use util::roms;
+
+fn scroll_nes() -> Nes {
+    Nes::new_from_buf(&roms::scroll_rom()).expect("Could not load test ROM")
+}
+
+#[test]
+fn runs_frames_at_the_set_speed() {
+    let mut nes = scroll_nes();
+    let frame = Region::Ntsc.frame_duration();
+    nes.run_for(frame * 3);
+    assert_eq!(nes.frame_count(), 3);
+    // half speed takes two refreshes per frame
+    nes.set_speed(0.5);
+    nes.run_for(frame);
+    nes.run_for(frame);
+    assert_eq!(nes.frame_count(), 4);
+    nes.set_speed(2.0);
+    nes.run_for(frame);
+    assert_eq!(nes.frame_count(), 6);
+    // a 144Hz display runs a frame on most refreshes, but not all of them
+    nes.set_speed(1.0);
+    for _ in 0..144 {
+        nes.run_for(Duration::from_secs(1) / 144);
+    }
+    assert_eq!(nes.frame_count(), 66);
+}
+
+#[test]
+fn pauses_and_advances_a_frame_at_a_time() {
+    let mut nes = scroll_nes();
+    nes.pause();
+    assert!(nes.is_paused());
+    nes.run_for(Duration::from_secs(1));
+    assert_eq!(nes.frame_count(), 0);
+    nes.frame_advance();
+    nes.frame_advance();
+    assert_eq!(nes.frame_count(), 2);
+    assert!(nes.is_paused());
+    nes.resume();
+    nes.run_for(Region::Ntsc.frame_duration());
+    assert_eq!(nes.frame_count(), 3);
+}
+
+#[test]
+fn keeps_emulated_time() {
+    let mut nes = scroll_nes();
+    assert_eq!(nes.emulated_time(), Duration::from_secs(0));
+    for _ in 0..60 {
+        nes.tick_frame();
+    }
+    // 60 frames is a little under a second at 60.0988 frames per second
+    let time = nes.emulated_time();
+    assert!(
+        time > Duration::from_millis(995) && time < Duration::from_millis(1000),
+        "{:?}",
+        time
+    );
+    assert_eq!(
+        Region::Ntsc.duration_of(89342),
+        Region::Ntsc.frame_duration() + Duration::from_nanos(93)
+    );
+}
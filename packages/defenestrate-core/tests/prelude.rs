@@ -0,0 +1,24 @@
+//! Checks that the prelude is enough to embed the emulator
+
+extern crate defenestrate_core;
+
+mod util;
+
+use defenestrate_core::prelude::*;
+use util::roms;
+
+fn load(rom: &[u8]) -> Result<Nes, Error> {
+    Nes::new_from_buf_with_config(rom, NesConfig::ntsc().with_console(Console::Famicom))
+}
+
+#[test]
+fn runs_a_game_with_only_the_prelude() {
+    let mut nes = load(&roms::scroll_rom()).expect("Could not load test ROM");
+    let frame = nes.tick_frame_with_input(Buttons::START, Buttons::empty());
+    assert_eq!(frame.len(), FRAME_SIZE);
+    let info: FrameInfo = nes.frame_info();
+    assert_eq!(info.width * info.height * 3, FRAME_SIZE);
+    nes.run_until(Breakpoint::NextVblankStart);
+    assert!(nes.cpu().state.pc >= 0x8000);
+    assert!(matches!(load(&[]), Err(Error::TruncatedRom { .. })));
+}
@@ -0,0 +1,48 @@
+//! Checks that `run_until_probe` stops on the right conditions, using small
+//! programs that count in RAM
+
+extern crate defenestrate_core;
+
+mod util;
+
+use defenestrate_core::devices::nes::{Comparator, Nes, Probe};
+use util::roms;
+
+/// LDA #$00; STA $10; loop: INC $10; JMP loop
+const COUNT_UP: &[u8] = &[0xA9, 0x00, 0x85, 0x10, 0xE6, 0x10, 0x4C, 0x04, 0x80];
+
+fn load(program: &[u8]) -> Nes {
+    Nes::new_from_buf(&roms::program_rom(program)).expect("Could not load test ROM")
+}
+
+#[test]
+fn stops_once_memory_matches() {
+    let mut nes = load(COUNT_UP);
+    let id = nes.add_probe(Probe::new(0x0010, Comparator::Equal, 0x40));
+    let hit = nes.run_until_probe(100_000).expect("Probe never fired");
+    assert_eq!(hit.id, id);
+    assert_eq!(hit.value, 0x40);
+    assert_eq!(nes.debug_snapshot().ram[0x10], 0x40);
+    // the condition still holds, so running again stops straight away
+    assert_eq!(nes.run_until_probe(0), Some(hit));
+}
+
+#[test]
+fn probes_see_ram_through_its_mirrors() {
+    let mut nes = load(COUNT_UP);
+    nes.add_probe(Probe::new(0x0810, Comparator::GreaterOrEqual, 0x80));
+    let hit = nes.run_until_probe(100_000).expect("Probe never fired");
+    assert_eq!(hit.value, 0x80);
+}
+
+#[test]
+fn times_out_when_nothing_fires() {
+    let mut nes = load(COUNT_UP);
+    assert_eq!(nes.run_until_probe(1_000), None);
+    let start = nes.clock();
+    let id = nes.add_probe(Probe::new(0x0011, Comparator::NotEqual, 0x00));
+    assert_eq!(nes.run_until_probe(1_000), None);
+    assert!(nes.clock().since(start) >= 3 * 1_000);
+    assert!(nes.remove_probe(id));
+    assert!(!nes.remove_probe(id));
+}
@@ -0,0 +1,115 @@
+//! Checks that recordings get every frame the console renders
+
+extern crate defenestrate_core;
+
+mod util;
+
+use std::sync::{Arc, Mutex};
+
+use defenestrate_core::devices::nes::Nes;
+use defenestrate_core::recorder::Sink;
+use defenestrate_core::{Error, Result};
+use util::roms;
+
+/// A sink that keeps the hash of every frame it gets
+struct HashSink {
+    hashes: Arc<Mutex<Vec<u64>>>,
+    fail_after: Option<usize>,
+}
+
+impl Sink for HashSink {
+    fn write_frame(&mut self, rgb: &[u8]) -> Result<()> {
+        let mut hashes = self.hashes.lock().unwrap();
+        if Some(hashes.len()) == self.fail_after {
+            return Err(Error::Io(std::io::ErrorKind::WriteZero.into()));
+        }
+        hashes.push(
+            rgb.iter()
+                .fold(0u64, |hash, &byte| hash.rotate_left(5) ^ byte as u64),
+        );
+        Ok(())
+    }
+}
+
+/// A sink that keeps how many audio samples came with each frame
+struct AudioSink {
+    counts: Arc<Mutex<Vec<usize>>>,
+}
+
+impl Sink for AudioSink {
+    fn write_frame(&mut self, _rgb: &[u8]) -> Result<()> {
+        self.counts.lock().unwrap().push(0);
+        Ok(())
+    }
+
+    fn write_audio(&mut self, samples: &[f32]) -> Result<()> {
+        *self.counts.lock().unwrap().last_mut().unwrap() += samples.len();
+        Ok(())
+    }
+}
+
+fn load_scroll_rom() -> Nes {
+    Nes::new_from_buf(&roms::scroll_rom()).expect("Could not load test ROM")
+}
+
+#[test]
+fn records_every_frame() {
+    let mut nes = load_scroll_rom();
+    let hashes = Arc::new(Mutex::new(Vec::new()));
+    let sink = HashSink {
+        hashes: hashes.clone(),
+        fail_after: None,
+    };
+    assert!(nes.start_recording(Box::new(sink)).is_none());
+    let mut expected = Vec::new();
+    for _ in 0..4 {
+        let frame = nes.tick_frame();
+        expected.push(
+            frame
+                .iter()
+                .fold(0u64, |hash, &byte| hash.rotate_left(5) ^ byte as u64),
+        );
+    }
+    assert_eq!(nes.stop_recording().unwrap().unwrap(), 4);
+    assert!(!nes.is_recording());
+    assert_eq!(*hashes.lock().unwrap(), expected);
+    nes.tick_frame();
+    assert_eq!(hashes.lock().unwrap().len(), 4, "Recorded after stopping");
+}
+
+#[test]
+fn reports_sink_errors() {
+    let mut nes = load_scroll_rom();
+    let hashes = Arc::new(Mutex::new(Vec::new()));
+    let sink = HashSink {
+        hashes: hashes.clone(),
+        fail_after: Some(2),
+    };
+    nes.start_recording(Box::new(sink));
+    for _ in 0..4 {
+        nes.tick_frame();
+    }
+    assert!(matches!(nes.stop_recording(), Some(Err(Error::Io(_)))));
+    assert_eq!(hashes.lock().unwrap().len(), 2);
+}
+
+#[test]
+fn records_the_audio_for_each_frame() {
+    let mut nes = load_scroll_rom();
+    nes.tick_frame();
+    let counts = Arc::new(Mutex::new(Vec::new()));
+    nes.start_recording(Box::new(AudioSink {
+        counts: counts.clone(),
+    }));
+    for _ in 0..4 {
+        nes.tick_frame();
+    }
+    nes.set_sample_rate(0);
+    nes.tick_frame();
+    nes.stop_recording();
+    let counts = counts.lock().unwrap();
+    // 44100Hz is 733.8 samples per frame, and only 4 frames had audio
+    assert_eq!(counts.len(), 5);
+    assert!(counts[..4].iter().all(|count| (733..=734).contains(count)));
+    assert_eq!(counts[4], 0);
+}
@@ -0,0 +1,83 @@
+//! Checks that loading a save state puts the console back exactly where it was
+
+extern crate defenestrate_core;
+
+mod util;
+
+use defenestrate_core::devices::nes::{
+    AccuracyMode, CartridgeState, Nes, NesBuilder, NesConfig, RamPattern,
+};
+use util::provider::NESTEST_ROM_PATH;
+use util::roms;
+
+fn load_nestest() -> Nes {
+    let rom = std::fs::read(NESTEST_ROM_PATH).expect("Could not read NESTEST rom");
+    NesBuilder::new()
+        .with_rom(&rom)
+        .expect("Could not load NESTEST rom")
+        .with_pc(0xC000)
+        .build()
+}
+
+#[test]
+fn loading_a_state_replays_the_same_instructions() {
+    let mut nes = load_nestest();
+    for _ in 0..500 {
+        nes.dbg_step_cpu();
+    }
+    let state = nes.save_state();
+    let first: Vec<String> = (0..500).map(|_| nes.dbg_step_cpu()).collect();
+    let snapshot = nes.debug_snapshot();
+    nes.load_state(&state);
+    let second: Vec<String> = (0..500).map(|_| nes.dbg_step_cpu()).collect();
+    assert_eq!(first, second);
+    assert!(nes.debug_snapshot() == snapshot);
+}
+
+#[test]
+fn loading_a_state_mid_frame_draws_the_same_frames() {
+    let mut nes = Nes::new_from_buf(&roms::split_scroll_rom()).expect("Could not load test ROM");
+    nes.tick_frame();
+    for _ in 0..10_000 {
+        nes.tick();
+    }
+    let state = nes.save_state();
+    nes.tick_frame();
+    let hash = nes.frame_hash();
+    nes.load_state(&state);
+    nes.tick_frame();
+    assert_eq!(nes.frame_hash(), hash);
+}
+
+#[test]
+fn loading_a_state_switches_back_to_its_game() {
+    let mut nes = load_nestest();
+    let state = nes.save_state();
+    nes.load_rom(&roms::program_rom(&[0x4C, 0x00, 0x80]))
+        .expect("Could not load test ROM");
+    nes.load_state(&state);
+    assert!(matches!(nes.debug_snapshot().cart, CartridgeState::NROM(_)));
+    assert!(nes.debug_snapshot().cart == load_nestest().debug_snapshot().cart);
+    assert_eq!(nes.dbg_step_cpu(), load_nestest().dbg_step_cpu());
+}
+
+#[test]
+fn loading_a_state_brings_back_its_config() {
+    let rom = std::fs::read(NESTEST_ROM_PATH).expect("Could not read NESTEST rom");
+    let config = NesConfig::ntsc()
+        .with_ram_pattern(RamPattern::Random(7))
+        .with_overscan(8)
+        .with_batch_rendering(false)
+        .with_accuracy(AccuracyMode::Strict);
+    let state = NesBuilder::new()
+        .with_rom(&rom)
+        .expect("Could not load NESTEST rom")
+        .with_config(config.clone())
+        .build()
+        .save_state();
+    // a console set up differently, which should pick up the saved settings
+    let mut nes = load_nestest();
+    nes.set_accuracy(AccuracyMode::Fast);
+    nes.load_state(&state);
+    assert!(nes.config() == config);
+}
@@ -0,0 +1,56 @@
+//! Checks that mid-frame writes to the scroll registers split the screen the
+//! way games expect
+
+extern crate defenestrate_core;
+
+mod util;
+
+use defenestrate_core::devices::nes::Nes;
+use util::roms;
+
+/// The line that the ROM's split writes land on, partway through
+const SPLIT_LINE: usize = 106;
+
+/// The bytes in one row of a frame
+const ROW_SIZE: usize = 256 * 3;
+
+fn row(frame: &[u8], y: usize) -> &[u8] {
+    &frame[y * ROW_SIZE..(y + 1) * ROW_SIZE]
+}
+
+/// Whether `bottom` is `top` scrolled 128 pixels to the right
+///
+/// The ROM uses horizontal mirroring, so the scroll wraps around to the same
+/// nametable.
+fn is_scrolled_by_half(bottom: &[u8], top: &[u8]) -> bool {
+    let half = ROW_SIZE / 2;
+    bottom[..half] == top[half..] && bottom[half..] == top[..half]
+}
+
+#[test]
+fn split_scroll_restarts_the_nametable_mid_frame() {
+    let mut nes = Nes::new_from_buf(&roms::split_scroll_rom()).expect("Could not load test ROM");
+    for _ in 0..3 {
+        nes.tick_frame();
+    }
+    for _ in 0..4 {
+        let frame = nes.tick_frame().to_vec();
+        // the split lands mid-line, and the PPU moves to the next line of the
+        // nametable at the end of it, so the first full line after the split
+        // shows the second line of the nametable
+        for y in SPLIT_LINE + 1..2 * SPLIT_LINE {
+            let top = y - SPLIT_LINE;
+            assert!(
+                is_scrolled_by_half(row(&frame, y), row(&frame, top)),
+                "Line {} doesn't match line {} of the nametable",
+                y,
+                top
+            );
+        }
+        // but the split line itself only changes partway through
+        assert!(!is_scrolled_by_half(
+            row(&frame, SPLIT_LINE),
+            row(&frame, 0)
+        ));
+    }
+}
@@ -0,0 +1,180 @@
+//! Checks that debug snapshots capture the state of the console
+
+extern crate defenestrate_core;
+
+mod util;
+
+use defenestrate_core::devices::cpu::WithCpu;
+use defenestrate_core::devices::nes::{
+    CartridgeState, Console, NROMCartridge, NametableArrangement, Nes, NesBuilder, NesConfig,
+    Palette, RamPattern, Region,
+};
+use util::provider::NESTEST_ROM_PATH;
+use util::roms;
+
+fn run_nestest(steps: usize) -> Nes {
+    let rom = std::fs::read(NESTEST_ROM_PATH).expect("Could not read NESTEST rom");
+    let mut nes = NesBuilder::new()
+        .with_rom(&rom)
+        .expect("Could not load NESTEST rom")
+        .with_pc(0xC000)
+        .build();
+    for _ in 0..steps {
+        nes.dbg_step_cpu();
+    }
+    nes
+}
+
+#[test]
+fn snapshots_match_the_console() {
+    let nes = run_nestest(500);
+    let snapshot = nes.debug_snapshot();
+    assert_eq!(snapshot.cpu, nes.cpu().state);
+    assert_eq!(snapshot.ram.len(), 2048);
+    assert_eq!(snapshot.palette.len(), 32);
+    assert!(matches!(snapshot.cart, CartridgeState::NROM(_)));
+}
+
+#[test]
+fn snapshots_are_deterministic() {
+    let left = run_nestest(500).debug_snapshot();
+    let right = run_nestest(500).debug_snapshot();
+    assert!(left == right, "Snapshots of identical runs differ");
+    let later = run_nestest(501).debug_snapshot();
+    assert!(left != later, "Snapshots of different runs are equal");
+}
+
+#[test]
+fn runs_programs_from_a_ram_image() {
+    // LDA #$42; STA $10; JMP $0004
+    let program = [0xA9, 0x42, 0x85, 0x10, 0x4C, 0x04, 0x00];
+    let mut nes = NesBuilder::new().with_ram_image(&program).build();
+    assert_eq!(nes.debug_snapshot().cpu.pc, 0x0000);
+    assert_eq!(nes.debug_snapshot().ram[..program.len()], program);
+    for _ in 0..3 {
+        nes.dbg_step_cpu();
+    }
+    let snapshot = nes.debug_snapshot();
+    assert_eq!(snapshot.ram[0x10], 0x42);
+    assert_eq!(snapshot.cpu.pc, 0x0004);
+}
+
+#[test]
+fn builders_start_the_cpu_at_the_given_pc() {
+    let nes = NesBuilder::new()
+        .with_cart(Box::new(NROMCartridge::from_parts(
+            vec![0xEA; 0x4000],
+            vec![0u8; 0x2000],
+            NametableArrangement::Vertical,
+        )))
+        .with_config(NesConfig::ntsc().with_overscan(8))
+        .with_ram_image(&[0xEA; 0x800])
+        .with_pc(0x0200)
+        .build();
+    let snapshot = nes.debug_snapshot();
+    assert_eq!(snapshot.cpu.pc, 0x0200);
+    assert_eq!(snapshot.ram, vec![0xEA; 0x800]);
+    assert_eq!(snapshot.config.overscan, 8);
+}
+
+#[test]
+fn builds_configs() {
+    let config = NesConfig::ntsc()
+        .with_console(Console::Famicom)
+        .with_ram_pattern(RamPattern::Random(42))
+        .with_overscan(8)
+        .with_batch_rendering(false);
+    assert_eq!(config.region, Region::Ntsc);
+    assert_eq!(config.power_on.console, Console::Famicom);
+    assert_eq!(config.power_on.ram_pattern, RamPattern::Random(42));
+    assert_eq!(config.overscan, 8);
+    assert!(!config.batch_rendering);
+    assert!(config.palette == Palette::default());
+    assert_eq!(NesConfig::default(), NesConfig::ntsc());
+}
+
+#[test]
+fn snapshots_capture_the_config() {
+    let config = NesConfig::ntsc()
+        .with_console(Console::Famicom)
+        .with_overscan(8);
+    let mut nes = Nes::new_from_buf_with_config(&roms::scroll_rom(), config.clone())
+        .expect("Could not load test ROM");
+    assert_eq!(nes.config(), config);
+    assert_eq!(nes.debug_snapshot().config, config);
+    // changes after power-on show up too
+    nes.load_palette(&[0u8; 192]);
+    nes.set_batch_rendering(false);
+    let snapshot = nes.debug_snapshot();
+    assert!(snapshot.config.palette != Palette::default());
+    assert!(!snapshot.config.batch_rendering);
+}
+
+#[test]
+fn swapping_cartridges_boots_the_new_game() {
+    let config = NesConfig::ntsc().with_ram_pattern(RamPattern::Random(42));
+    let mut fresh = Nes::new_from_buf_with_config(&roms::scroll_rom(), config.clone())
+        .expect("Could not load test ROM");
+    let mut swapped = Nes::new_from_buf_with_config(&roms::split_scroll_rom(), config.clone())
+        .expect("Could not load test ROM");
+    for _ in 0..3 {
+        swapped.tick_frame();
+    }
+    swapped.load_palette(&[0x80; 192]);
+    fresh.load_palette(&[0x80; 192]);
+    swapped
+        .load_rom(&roms::scroll_rom())
+        .expect("Could not load test ROM");
+    for _ in 0..3 {
+        fresh.tick_frame();
+        swapped.tick_frame();
+    }
+    assert!(swapped.debug_snapshot() == fresh.debug_snapshot());
+    assert_eq!(swapped.frame_hash(), fresh.frame_hash());
+}
+
+#[test]
+fn bad_roms_leave_the_current_game_running() {
+    let mut nes = Nes::new_from_buf(&roms::scroll_rom()).expect("Could not load test ROM");
+    nes.tick_frame();
+    let before = nes.debug_snapshot();
+    assert!(nes.load_rom(&[0x4E, 0x45, 0x53]).is_err());
+    assert!(nes.debug_snapshot() == before);
+}
+
+#[test]
+fn debug_data_follows_mirroring() {
+    let mut nes = Nes::new_from_buf(&roms::scroll_rom()).expect("Could not load test ROM");
+    for _ in 0..3 {
+        nes.tick_frame();
+    }
+    let data = nes.dump_debug_data();
+    let filled: Vec<u8> = (0..0x400).map(|i| i as u8).collect();
+    // the test ROM is horizontally mirrored
+    assert_eq!(&data.nametable(0)[..], &filled[..]);
+    assert_eq!(&data.nametable(1)[..], &filled[..]);
+    assert!(data.nametable(2).iter().all(|&byte| byte == 0));
+    assert_eq!(&data.palette[..], &nes.debug_snapshot().palette[..]);
+}
+
+#[test]
+fn decodes_nametables() {
+    let mut nes = Nes::new_from_buf(&roms::scroll_rom()).expect("Could not load test ROM");
+    for _ in 0..3 {
+        nes.tick_frame();
+    }
+    let tiles = nes.decode_nametable(0);
+    assert_eq!(tiles.len(), 960);
+    for (i, entry) in tiles.iter().enumerate() {
+        assert_eq!(entry.tile, i as u8);
+        assert_eq!(entry.pattern_addr, (i as u16 & 0xFF) << 4);
+    }
+    // the test ROM fills the attribute table with $C0-$FF too, so the first
+    // attribute byte is %11000000
+    let attributes = nes.decode_attributes(0);
+    assert_eq!(attributes.len(), 16 * 15);
+    assert_eq!(&attributes[0..2], &[0, 0]);
+    assert_eq!(&attributes[16..18], &[0, 3]);
+    assert_eq!(tiles[2 * 32 + 2].palette, 3);
+    assert_eq!(tiles[3 * 32 + 1].palette, 0);
+}
@@ -0,0 +1,121 @@
+//! A soak test, which runs ROMs for thousands of frames each to flush out
+//! panics
+//!
+//! This takes a while, so it's ignored by default. With no ROMs given, it
+//! soaks the test ROMs in this repo. Other ROMs can be given as a list of
+//! paths in `DEFENESTRATE_SOAK_ROMS`, separated like `PATH`:
+//!
+//! ```sh
+//! DEFENESTRATE_SOAK_ROMS=~/roms/a.nes:~/roms/b.nes \
+//!     cargo test --release --test soak -- --ignored --nocapture
+//! ```
+//!
+//! Each ROM runs for `DEFENESTRATE_SOAK_FRAMES` frames (10,000 if that isn't
+//! set), and the JSON report from `soak::report_json` is written to
+//! `DEFENESTRATE_SOAK_REPORT` (or `target/soak-report.json`). The test fails
+//! if any ROM panicked.
+
+extern crate defenestrate_core;
+
+mod util;
+
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+use defenestrate_core::devices::nes::{Nes, NesBuilder};
+use defenestrate_core::soak::{report_json, soak, soak_rom, SoakOutcome, SoakResult};
+use util::provider::NESTEST_ROM_PATH;
+use util::roms;
+
+/// About 3 minutes, at 60.0988 frames per second
+const DEFAULT_SOAK_FRAMES: u64 = 10_000;
+
+/// The ROMs to soak, as (name, contents)
+fn soak_roms() -> Vec<(String, Vec<u8>)> {
+    let Some(paths) = env::var_os("DEFENESTRATE_SOAK_ROMS") else {
+        return vec![
+            (
+                "nestest.nes".to_string(),
+                fs::read(NESTEST_ROM_PATH).expect("Could not read NESTEST rom"),
+            ),
+            ("scroll.nes".to_string(), roms::scroll_rom()),
+            ("split_scroll.nes".to_string(), roms::split_scroll_rom()),
+        ];
+    };
+    env::split_paths(&paths)
+        .map(|path| {
+            let rom = fs::read(&path)
+                .unwrap_or_else(|err| panic!("Could not read {}: {}", path.display(), err));
+            (path.display().to_string(), rom)
+        })
+        .collect()
+}
+
+#[test]
+#[ignore]
+fn soak_roms_for_thousands_of_frames() {
+    let frames = env::var("DEFENESTRATE_SOAK_FRAMES")
+        .map(|frames| {
+            frames
+                .parse()
+                .expect("Could not parse DEFENESTRATE_SOAK_FRAMES")
+        })
+        .unwrap_or(DEFAULT_SOAK_FRAMES);
+    let report_path = env::var_os("DEFENESTRATE_SOAK_REPORT")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from("./target/soak-report.json"));
+    let results: Vec<SoakResult> = soak_roms()
+        .iter()
+        .map(|(name, rom)| soak_rom(name, rom, frames))
+        .collect();
+    let report = report_json(&results);
+    if let Some(parent) = report_path.parent() {
+        fs::create_dir_all(parent).expect("Could not create report dir");
+    }
+    fs::write(&report_path, &report).expect("Could not write report");
+    println!("{}", report);
+    println!("Report written to {:?}", report_path);
+    let panicked: Vec<&str> = results
+        .iter()
+        .filter(|result| result.panicked())
+        .map(|result| result.name.as_str())
+        .collect();
+    assert!(panicked.is_empty(), "Panicked: {:?}", panicked);
+}
+
+#[test]
+fn soak_catches_panics_with_the_frame_and_state() {
+    let mut nes: Nes = NesBuilder::new()
+        .with_rom(&roms::scroll_rom())
+        .expect("Could not load test ROM")
+        .build();
+    // frame hooks run just before the frame is counted
+    nes.on_frame(|nes| {
+        if nes.frame_count() + 1 == 5 {
+            panic!("Frame {} is cursed", nes.frame_count() + 1);
+        }
+    });
+    let panicked = soak(&mut nes, 100).expect_err("Expected a panic");
+    assert_eq!(panicked.message, "Frame 5 is cursed");
+    assert_eq!(panicked.frame, 5);
+    assert_eq!(panicked.ppu, nes.ppu_debug_state());
+
+    let result = SoakResult {
+        name: "cursed.nes".to_string(),
+        frames: 100,
+        outcome: SoakOutcome::Panicked(Box::new(panicked)),
+    };
+    let report = report_json(&[result]);
+    assert!(report.contains("\"panicked\": 1,"));
+    assert!(report.contains("\"outcome\": \"panicked\""));
+    assert!(report.contains("\"message\": \"Frame 5 is cursed\", \"frame\": 5,"));
+}
+
+#[test]
+fn soak_runs_are_deterministic() {
+    let first = soak_rom("scroll.nes", &roms::scroll_rom(), 120);
+    let second = soak_rom("scroll.nes", &roms::scroll_rom(), 120);
+    assert!(matches!(first.outcome, SoakOutcome::Finished { .. }));
+    assert_eq!(first, second);
+}
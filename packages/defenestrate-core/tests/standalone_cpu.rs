@@ -0,0 +1,115 @@
+//! Checks that the 6502 core runs on its own, against a bus that has nothing
+//! to do with the NES
+
+extern crate defenestrate_core;
+
+use defenestrate_core::devices::cpu::{self, structs, Cpu6502, Motherboard, WithCpu};
+
+/// A 6502 with 64k of RAM and nothing else
+struct FlatRam {
+    cpu: Cpu6502,
+    ram: Vec<u8>,
+}
+
+impl FlatRam {
+    fn new(program: &[u8]) -> FlatRam {
+        let mut ram = vec![0u8; 0x10000];
+        ram[0x0400..0x0400 + program.len()].copy_from_slice(program);
+        // reset vector
+        ram[0xFFFC] = 0x00;
+        ram[0xFFFD] = 0x04;
+        FlatRam {
+            cpu: Cpu6502::new(),
+            ram,
+        }
+    }
+}
+
+impl Motherboard for FlatRam {
+    fn read(&mut self, addr: u16) -> u8 {
+        self.ram[addr as usize]
+    }
+
+    fn peek(&self, addr: u16) -> Option<u8> {
+        Some(self.ram[addr as usize])
+    }
+
+    fn write(&mut self, addr: u16, data: u8) {
+        self.ram[addr as usize] = data;
+    }
+}
+
+impl WithCpu for FlatRam {
+    fn cpu(&self) -> &Cpu6502 {
+        &self.cpu
+    }
+
+    fn cpu_mut(&mut self) -> &mut Cpu6502 {
+        &mut self.cpu
+    }
+}
+
+/// Add up 1 through 10, store the sum at $0200, and spin
+const SUM_TO_TEN: &[u8] = &[
+    0xA9, 0x00, //       LDA #$00
+    0xA2, 0x0A, //       LDX #$0A
+    0x86, 0x00, //       STX $00        ; $0404
+    0x18, //             CLC
+    0x65, 0x00, //       ADC $00
+    0xCA, //             DEX
+    0xD0, 0xF8, //       BNE -8
+    0x8D, 0x00, 0x02, // STA $0200
+    0x4C, 0x0F, 0x04, // JMP $040F
+];
+
+/// Add $15 and $27 and subtract $01 from $00 with the D flag set, storing the
+/// results at $0200 and $0201
+const DECIMAL_MATH: &[u8] = &[
+    0xF8, //             SED
+    0x18, //             CLC
+    0xA9, 0x15, //       LDA #$15
+    0x69, 0x27, //       ADC #$27
+    0x8D, 0x00, 0x02, // STA $0200
+    0x38, //             SEC
+    0xA9, 0x00, //       LDA #$00
+    0xE9, 0x01, //       SBC #$01
+    0x8D, 0x01, 0x02, // STA $0201
+    0x4C, 0x11, 0x04, // JMP $0411
+];
+
+fn run(mb: &mut FlatRam, instructions: usize) {
+    for _ in 0..instructions {
+        cpu::exec(mb);
+        while !cpu::tick(mb) {}
+    }
+}
+
+#[test]
+fn runs_programs_on_any_motherboard() {
+    let mut mb = FlatRam::new(SUM_TO_TEN);
+    cpu::reset(&mut mb);
+    assert_eq!(mb.cpu().state.pc, 0x0400);
+    run(&mut mb, 100);
+    assert_eq!(mb.ram[0x0200], 55);
+    assert_eq!(mb.cpu().state.pc, 0x040F);
+}
+
+#[test]
+fn ignores_decimal_flag_by_default() {
+    let mut mb = FlatRam::new(DECIMAL_MATH);
+    cpu::reset(&mut mb);
+    run(&mut mb, 20);
+    assert_eq!(mb.ram[0x0200], 0x3C);
+    assert_eq!(mb.ram[0x0201], 0xFF);
+}
+
+#[test]
+fn does_decimal_math_when_enabled() {
+    let mut mb = FlatRam::new(DECIMAL_MATH);
+    mb.cpu_mut().enable_bcd(true);
+    cpu::reset(&mut mb);
+    run(&mut mb, 20);
+    assert_eq!(mb.ram[0x0200], 0x42);
+    assert_eq!(mb.ram[0x0201], 0x99);
+    assert!(!mb.cpu().state.status.contains(structs::Status::CARRY));
+}
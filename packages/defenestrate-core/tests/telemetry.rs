@@ -0,0 +1,26 @@
+//! Checks that the `Nes` counts its own frames for the pacing statistics
+
+extern crate defenestrate_core;
+
+mod util;
+
+use defenestrate_core::devices::nes::Nes;
+use util::roms;
+
+#[test]
+fn counts_emulated_frames_between_host_frames() {
+    let mut nes = Nes::new_from_buf(&roms::scroll_rom()).expect("Could not load test ROM");
+    nes.telemetry_mut().record_host_frame(0.0, 1.0);
+    // a runner that's fallen behind, catching up two frames at a time
+    for i in 1..=10 {
+        nes.tick_frame();
+        nes.tick_frame();
+        nes.telemetry_mut().record_host_frame(i as f64 * 40.0, 2.0);
+    }
+    let telemetry = nes.telemetry();
+    assert_eq!(telemetry.host_fps(), Some(25.0));
+    assert_eq!(telemetry.emulated_fps(), Some(50.0));
+    assert_eq!(telemetry.frame_time_percentile(50), Some(2.0));
+    // 40ms is a little over two NTSC frames, so one was dropped each time
+    assert_eq!(telemetry.dropped_frames(), 10);
+}
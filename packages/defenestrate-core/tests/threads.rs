@@ -0,0 +1,77 @@
+//! Checks that a `Nes` can be moved to, and shared between, threads
+
+extern crate defenestrate_core;
+
+mod util;
+
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use defenestrate_core::devices::nes::Nes;
+use defenestrate_core::recorder::Sink;
+use util::roms;
+
+/// Fails to compile if `T` isn't `Send`
+fn assert_send<T: Send>() {}
+
+#[test]
+fn core_types_are_send() {
+    assert_send::<Nes>();
+    assert_send::<Box<dyn Sink>>();
+    assert_send::<defenestrate_core::Error>();
+}
+
+#[test]
+fn runs_on_a_worker_thread() {
+    // a `Nes` is big enough (mostly frame buffers) that moving it around by
+    // value can overflow a thread's stack, so box it first
+    let mut nes =
+        Box::new(Nes::new_from_buf(&roms::scroll_rom()).expect("Could not load test ROM"));
+    let frames = Arc::new(Mutex::new(0));
+    let count = frames.clone();
+    nes.on_frame(move |_| *count.lock().unwrap() += 1);
+    let nes = thread::spawn(move || {
+        for _ in 0..4 {
+            nes.tick_frame();
+        }
+        nes
+    })
+    .join()
+    .expect("Worker thread panicked");
+    assert_eq!(*frames.lock().unwrap(), 4);
+
+    // and with external locking, it can be shared
+    let nes = Arc::new(Mutex::new(nes));
+    let worker = {
+        let nes = nes.clone();
+        thread::spawn(move || nes.lock().unwrap().frame_hash())
+    };
+    let hash = worker.join().expect("Worker thread panicked");
+    assert_eq!(hash, nes.lock().unwrap().frame_hash());
+}
+
+#[test]
+fn frame_ids_show_new_and_missed_frames() {
+    let nes = Arc::new(Mutex::new(Box::new(
+        Nes::new_from_buf(&roms::scroll_rom()).expect("Could not load test ROM"),
+    )));
+    let first = nes.lock().unwrap().frame_id();
+    let worker = {
+        let nes = nes.clone();
+        thread::spawn(move || {
+            for _ in 0..3 {
+                nes.lock().unwrap().tick_frame();
+            }
+        })
+    };
+    worker.join().expect("Worker thread panicked");
+    let mut nes = nes.lock().unwrap();
+    assert_eq!(nes.frame_id(), first + 3);
+
+    let mut held: Box<[u8]> = Box::default();
+    nes.swap_buffers(&mut held);
+    assert_eq!(nes.frame_id(), first + 3, "Swapping isn't a new frame");
+    nes.power_cycle();
+    assert_eq!(nes.frame_count(), 0);
+    assert_eq!(nes.frame_id(), first + 4, "Frame ids went backwards");
+}
@@ -0,0 +1,145 @@
+//! Checks that `TraceLogger` logs the instructions the console runs, and
+//! dumps its ring buffer when the emulator stops or panics
+
+extern crate defenestrate_core;
+
+mod util;
+
+use std::io::{self, Write};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Mutex};
+
+use defenestrate_core::devices::nes::{
+    Breakpoint, Nes, NesBuilder, TraceFormat, TraceLogger, WatchOperand,
+};
+use util::provider::{self, NESTEST_ROM_PATH};
+use util::{logparse, roms};
+
+/// LDA #$00; STA $10; loop: INC $10; JMP loop
+const COUNT_UP: &[u8] = &[0xA9, 0x00, 0x85, 0x10, 0xE6, 0x10, 0x4C, 0x04, 0x80];
+
+/// A writer that can still be read after it's handed to a logger
+#[derive(Clone, Default)]
+struct SharedBuf(Arc<Mutex<Vec<u8>>>);
+
+impl SharedBuf {
+    fn lines(&self) -> Vec<String> {
+        let buf = self.0.lock().unwrap();
+        String::from_utf8_lossy(&buf)
+            .lines()
+            .map(String::from)
+            .collect()
+    }
+}
+
+impl Write for SharedBuf {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn load_nestest() -> Nes {
+    let rom = std::fs::read(NESTEST_ROM_PATH).expect("Could not read NESTEST rom");
+    NesBuilder::new()
+        .with_rom(&rom)
+        .expect("Could not load NESTEST rom")
+        .with_pc(0xC000)
+        .build()
+}
+
+fn load(program: &[u8]) -> Nes {
+    Nes::new_from_buf(&roms::program_rom(program)).expect("Could not load test ROM")
+}
+
+#[test]
+fn streams_lines_matching_the_nestest_log() {
+    let out = SharedBuf::default();
+    let mut nes = load_nestest();
+    nes.set_trace_logger(TraceLogger::streaming(
+        Box::new(out.clone()),
+        TraceFormat::Nestest,
+    ));
+    nes.run_until(Breakpoint::CpuInstructionCount(200));
+    let logger = nes.take_trace_logger().expect("Logger went missing");
+    assert_eq!(logger.finish().unwrap(), 200);
+    let lines = out.lines();
+    assert_eq!(lines.len(), 200);
+    for (line, gold_line) in lines.iter().zip(provider::load_gold_standard_log()) {
+        logparse::assert_logs_eq(
+            &logparse::parse_line(line),
+            &logparse::parse_line(&gold_line),
+        );
+    }
+}
+
+#[test]
+fn writes_structured_lines() {
+    let out = SharedBuf::default();
+    let mut nes = load_nestest();
+    nes.set_trace_logger(TraceLogger::streaming(
+        Box::new(out.clone()),
+        TraceFormat::Structured,
+    ));
+    nes.run_until(Breakpoint::CpuInstructionCount(2));
+    let lines = out.lines();
+    assert_eq!(lines.len(), 2);
+    // C000  4C F5 C5  JMP $C5F5
+    assert!(
+        lines[0].starts_with(
+            "{\"pc\": 49152, \"bytes\": [76, 245, 197], \"instr\": \"JMP\", \"mode\": \"Abs\", \
+             \"addr\": 50677, \"a\": 0, \"x\": 0, \"y\": 0, \"p\": 36, \"sp\": 253, "
+        ),
+        "Unexpected line: {}",
+        lines[0]
+    );
+    assert!(
+        lines[0].ends_with("\"cycles\": 7}"),
+        "Unexpected line: {}",
+        lines[0]
+    );
+    // C5F5  A2 00     LDX #$00
+    assert!(lines[1].starts_with("{\"pc\": 50677, \"bytes\": [162, 0], \"instr\": \"LDX\""));
+}
+
+#[test]
+fn dumps_the_ring_buffer_when_a_watch_hits() {
+    let out = SharedBuf::default();
+    let mut nes = load(COUNT_UP);
+    nes.set_trace_logger(
+        TraceLogger::ring_buffer(3, TraceFormat::Nestest).dump_to(Box::new(out.clone())),
+    );
+    nes.add_watch(WatchOperand::Memory(0x10).equals(0x05));
+    assert!(nes.run_until_watch(100_000).is_some());
+    let lines = out.lines();
+    assert_eq!(lines.len(), 3);
+    // the INC that made it 5, after a JMP and the INC before it
+    assert!(lines[0].starts_with("8004  E6 10     INC $10 = 03"));
+    assert!(lines[1].starts_with("8006  4C 04 80  JMP $8004"));
+    assert!(lines[2].starts_with("8004  E6 10     INC $10 = 04"));
+    assert_eq!(nes.trace_logger().unwrap().recent().count(), 0);
+}
+
+#[test]
+fn dumps_the_ring_buffer_on_a_panic() {
+    let out = SharedBuf::default();
+    let dump = out.clone();
+    let result = panic::catch_unwind(AssertUnwindSafe(move || {
+        let mut nes = load(COUNT_UP);
+        nes.set_trace_logger(
+            TraceLogger::ring_buffer(2, TraceFormat::Nestest).dump_to(Box::new(dump)),
+        );
+        nes.on_exec(0x8006, |_| panic!("Reached the JMP"));
+        nes.tick_frame();
+    }));
+    assert!(result.is_err());
+    let lines = out.lines();
+    assert_eq!(lines.len(), 2);
+    // the hook runs before the JMP, so it never makes it into the log
+    assert!(lines[0].starts_with("8002  85 10     STA $10"));
+    assert!(lines[1].starts_with("8004  E6 10     INC $10"));
+}
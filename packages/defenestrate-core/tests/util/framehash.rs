@@ -0,0 +1,53 @@
+//! Helpers for checking frame hashes against stored goldens
+//!
+//! Goldens live in `tests/data/goldens`, one hex hash per line. To update them
+//! after an intentional change to rendering, run the tests with
+//! `BLESS_GOLDENS=1` set and review the diff.
+
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+use defenestrate_core::devices::nes::Nes;
+
+/// Run the NES for `frames` frames, and hash each one
+pub fn hash_frames(nes: &mut Nes, frames: usize) -> Vec<u64> {
+    (0..frames)
+        .map(|_| {
+            nes.tick_frame();
+            nes.frame_hash()
+        })
+        .collect()
+}
+
+fn golden_path(name: &str) -> PathBuf {
+    PathBuf::from(format!("./tests/data/goldens/{}.txt", name))
+}
+
+/// Compare a sequence of frame hashes against the golden called `name`
+pub fn assert_golden(name: &str, hashes: &[u64]) {
+    let path = golden_path(name);
+    if env::var_os("BLESS_GOLDENS").is_some() {
+        let text: String = hashes
+            .iter()
+            .map(|hash| format!("{:016x}\n", hash))
+            .collect();
+        fs::create_dir_all(path.parent().unwrap()).expect("Could not create goldens dir");
+        fs::write(&path, text).expect("Could not write golden");
+        return;
+    }
+    let text = fs::read_to_string(&path)
+        .unwrap_or_else(|_| panic!("Missing golden {:?}, run with BLESS_GOLDENS=1", path));
+    let golden: Vec<u64> = text
+        .lines()
+        .map(|line| u64::from_str_radix(line.trim(), 16).expect("Malformed golden"))
+        .collect();
+    assert_eq!(golden.len(), hashes.len(), "Frame count mismatch");
+    for (frame, (expected, actual)) in golden.iter().zip(hashes).enumerate() {
+        assert_eq!(
+            expected, actual,
+            "Frame {} differs from the golden ({:016x} != {:016x})",
+            frame, expected, actual
+        );
+    }
+}
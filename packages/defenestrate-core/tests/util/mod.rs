@@ -1,2 +1,4 @@
+pub mod framehash;
 pub mod logparse;
 pub mod provider;
+pub mod roms;
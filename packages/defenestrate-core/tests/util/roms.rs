@@ -0,0 +1,144 @@
+//! Tiny hand-assembled test ROMs, for tests that need something on screen
+
+/// The iNES header for a 16k PRG, 8k CHR, mapper 0 ROM
+const NROM_HEADER: [u8; 16] = [0x4E, 0x45, 0x53, 0x1A, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+
+/// Set up the PPU to draw every tile in the nametable, ending at $804E
+///
+/// The PRG is mirrored at $8000 and $C000, and the programs are assembled for
+/// $8000.
+const SETUP_PROGRAM: &[u8] = &[
+    0x78, //             SEI
+    0xD8, //             CLD
+    0xA2, 0xFF, //       LDX #$FF
+    0x9A, //             TXS
+    0x2C, 0x02, 0x20, // BIT $2002      ; clear the vblank flag from power-on
+    0x2C, 0x02, 0x20, // BIT $2002      ; wait for the PPU to warm up
+    0x10, 0xFB, //       BPL -5
+    0x2C, 0x02, 0x20, // BIT $2002
+    0x10, 0xFB, //       BPL -5
+    0xA9, 0x3F, //       LDA #$3F       ; load the palette
+    0x8D, 0x06, 0x20, // STA $2006
+    0xA9, 0x00, //       LDA #$00
+    0x8D, 0x06, 0x20, // STA $2006
+    0xA2, 0x00, //       LDX #$00
+    0xBD, 0x00, 0x81, // LDA $8100,X
+    0x8D, 0x07, 0x20, // STA $2007
+    0xE8, //             INX
+    0xE0, 0x20, //       CPX #$20
+    0xD0, 0xF5, //       BNE -11
+    0xA9, 0x20, //       LDA #$20       ; fill the first nametable
+    0x8D, 0x06, 0x20, // STA $2006
+    0xA9, 0x00, //       LDA #$00
+    0x8D, 0x06, 0x20, // STA $2006
+    0xA0, 0x04, //       LDY #$04
+    0xA2, 0x00, //       LDX #$00
+    0x8A, //             TXA
+    0x8D, 0x07, 0x20, // STA $2007
+    0xE8, //             INX
+    0xD0, 0xF9, //       BNE -7
+    0x88, //             DEY
+    0xD0, 0xF6, //       BNE -10
+    0xA9, 0x00, //       LDA #$00       ; reset the scroll
+    0x8D, 0x05, 0x20, // STA $2005
+    0x8D, 0x05, 0x20, // STA $2005
+    0xA9, 0x0A, //       LDA #$0A       ; show the background
+    0x8D, 0x01, 0x20, // STA $2001
+];
+
+/// After `SETUP_PROGRAM`, scroll right by one pixel per frame
+const SCROLL_PROGRAM: &[u8] = &[
+    0x2C, 0x02, 0x20, // BIT $2002      ; $804E: wait for vblank
+    0x10, 0xFB, //       BPL -5
+    0xE6, 0x00, //       INC $00        ; scroll one more pixel
+    0xA5, 0x00, //       LDA $00
+    0x8D, 0x05, 0x20, // STA $2005
+    0xA9, 0x00, //       LDA #$00
+    0x8D, 0x05, 0x20, // STA $2005
+    0x4C, 0x4E, 0x80, // JMP $804E
+];
+
+/// After `SETUP_PROGRAM`, split the screen partway down every frame using the
+/// $2006/$2005/$2005/$2006 trick, so that the bottom part starts over from
+/// the top of the nametable, 128 pixels to the right
+///
+/// cf. https://wiki.nesdev.com/w/index.php/PPU_scrolling#Split_X.2FY_scroll
+const SPLIT_SCROLL_PROGRAM: &[u8] = &[
+    0x2C, 0x02, 0x20, // BIT $2002      ; $804E: wait for vblank
+    0x10, 0xFB, //       BPL -5
+    0xA9, 0x00, //       LDA #$00       ; no scroll at the top
+    0x8D, 0x00, 0x20, // STA $2000
+    0x8D, 0x05, 0x20, // STA $2005
+    0x8D, 0x05, 0x20, // STA $2005
+    0xA2, 0x08, //       LDX #$08       ; wait until about halfway down
+    0xA0, 0x00, //       LDY #$00
+    0x88, //             DEY
+    0xD0, 0xFD, //       BNE -3
+    0xCA, //             DEX
+    0xD0, 0xF8, //       BNE -8
+    0xA9, 0x00, //       LDA #$00       ; nametable 0, << 2
+    0x8D, 0x06, 0x20, // STA $2006
+    0x8D, 0x05, 0x20, // STA $2005      ; Y = 0
+    0xA9, 0x80, //       LDA #$80       ; X = 128
+    0x8D, 0x05, 0x20, // STA $2005
+    0xA9, 0x10, //       LDA #$10       ; ((Y & $F8) << 2) | (X >> 3)
+    0x8D, 0x06, 0x20, // STA $2006
+    0x4C, 0x4E, 0x80, // JMP $804E
+];
+
+/// Build the scrolling test ROM from `SCROLL_PROGRAM`
+pub fn scroll_rom() -> Vec<u8> {
+    setup_rom(SCROLL_PROGRAM)
+}
+
+/// Build the split scroll test ROM from `SPLIT_SCROLL_PROGRAM`
+pub fn split_scroll_rom() -> Vec<u8> {
+    setup_rom(SPLIT_SCROLL_PROGRAM)
+}
+
+/// Build a ROM that runs `SETUP_PROGRAM` and then `program`, with a palette
+/// and busy CHR
+fn setup_rom(program: &[u8]) -> Vec<u8> {
+    let mut rom = NROM_HEADER.to_vec();
+    rom.extend(setup_prg(program));
+    rom.extend(busy_chr(1));
+    rom
+}
+
+/// Build 16k of PRG that runs `SETUP_PROGRAM` and then `program` from $8000
+///
+/// Other boards can use this too, as long as they map it to $8000 at power-on
+/// and map its last 8k (with the reset vector) to $E000.
+pub fn setup_prg(program: &[u8]) -> Vec<u8> {
+    let mut prg = vec![0u8; 0x4000];
+    prg[..SETUP_PROGRAM.len()].copy_from_slice(SETUP_PROGRAM);
+    prg[SETUP_PROGRAM.len()..SETUP_PROGRAM.len() + program.len()].copy_from_slice(program);
+    // palette, at $8100
+    for (i, byte) in prg[0x100..0x120].iter_mut().enumerate() {
+        *byte = (i as u8 * 7) & 0x3F;
+    }
+    // reset vector
+    prg[0x3FFC] = 0x00;
+    prg[0x3FFD] = 0x80;
+    prg
+}
+
+/// Build `banks` 8k banks of CHR, with a different pattern in each
+pub fn busy_chr(banks: usize) -> Vec<u8> {
+    (0..0x2000 * banks)
+        .map(|i| (i.wrapping_mul(37) ^ (i >> 4) ^ (i >> 13).wrapping_mul(0x5A)) as u8)
+        .collect()
+}
+
+/// Build an NROM ROM that runs `program` from $8000, with blank CHR
+pub fn program_rom(program: &[u8]) -> Vec<u8> {
+    let mut prg = vec![0u8; 0x4000];
+    prg[..program.len()].copy_from_slice(program);
+    // reset vector
+    prg[0x3FFC] = 0x00;
+    prg[0x3FFD] = 0x80;
+    let mut rom = NROM_HEADER.to_vec();
+    rom.extend(prg);
+    rom.extend(vec![0u8; 0x2000]);
+    rom
+}
@@ -0,0 +1,82 @@
+//! Checks that watches stop the emulator once their condition holds, using a
+//! small program that counts in RAM
+
+extern crate defenestrate_core;
+
+mod util;
+
+use std::time::Duration;
+
+use defenestrate_core::devices::nes::{Nes, Symbols, Watch, WatchOperand};
+use util::roms;
+
+/// LDA #$00; STA $10; loop: INC $10; JMP loop
+const COUNT_UP: &[u8] = &[0xA9, 0x00, 0x85, 0x10, 0xE6, 0x10, 0x4C, 0x04, 0x80];
+
+fn load(program: &[u8]) -> Nes {
+    Nes::new_from_buf(&roms::program_rom(program)).expect("Could not load test ROM")
+}
+
+#[test]
+fn stops_after_the_instruction_that_met_it() {
+    let mut nes = load(COUNT_UP);
+    let watch = Watch::parse("[$10] == 0x40 && pc == $8006").expect("Could not parse watch");
+    let id = nes.add_watch(watch);
+    assert_eq!(nes.run_until_watch(100_000), Some(id));
+    assert_eq!(nes.debug_snapshot().ram[0x10], 0x40);
+    assert_eq!(nes.debug_snapshot().cpu.pc, 0x8006);
+    // watches are checked after each instruction, so this one won't hold
+    // again until the counter wraps around
+    assert_eq!(nes.run_until_watch(1_000), None);
+}
+
+#[test]
+fn the_first_watch_added_wins() {
+    let mut nes = load(COUNT_UP);
+    // both of these hold once the LDA runs
+    let first = nes.add_watch(WatchOperand::Acc.equals(0x00));
+    nes.add_watch(WatchOperand::Pc.in_range(0x8000..0x9000));
+    assert_eq!(nes.run_until_watch(100_000), Some(first));
+    assert_eq!(nes.debug_snapshot().cpu.pc, 0x8002);
+}
+
+#[test]
+fn run_for_pauses_on_a_watch() {
+    let mut nes = load(COUNT_UP);
+    let id = nes.add_watch(WatchOperand::Memory(0x0010).equals(0x80));
+    nes.run_for(Duration::from_millis(100));
+    assert!(nes.is_paused());
+    assert_eq!(nes.take_watch_hit(), Some(id));
+    assert_eq!(nes.take_watch_hit(), None);
+    assert_eq!(nes.debug_snapshot().ram[0x10], 0x80);
+}
+
+#[test]
+fn removed_watches_do_nothing() {
+    let mut nes = load(COUNT_UP);
+    let id = nes.add_watch(!WatchOperand::Memory(0x0010).equals(0x00));
+    assert!(nes.remove_watch(id));
+    assert!(!nes.remove_watch(id));
+    assert_eq!(nes.run_until_watch(1_000), None);
+    nes.add_watch(WatchOperand::Acc.equals(0x00));
+    nes.clear_watches();
+    assert_eq!(nes.run_until_watch(1_000), None);
+}
+
+#[test]
+fn breaks_at_symbols() {
+    let mut nes = load(COUNT_UP);
+    assert_eq!(nes.break_at("jump_back"), None);
+    let symbols = Symbols::from_mlb("P:0006:jump_back\nR:0010:counter\n", 0x8000)
+        .expect("Could not read symbols");
+    nes.set_symbols(symbols);
+    let id = nes.break_at("jump_back").expect("Symbol wasn't loaded");
+    assert_eq!(nes.run_until_watch(100_000), Some(id));
+    assert_eq!(nes.debug_snapshot().cpu.pc, 0x8006);
+    nes.clear_watches();
+    let watch = Watch::parse_with_symbols("[counter] == 3 && pc == jump_back", nes.symbols())
+        .expect("Could not parse watch");
+    nes.add_watch(watch);
+    assert!(nes.run_until_watch(100_000).is_some());
+    assert_eq!(nes.debug_snapshot().ram[0x10], 3);
+}
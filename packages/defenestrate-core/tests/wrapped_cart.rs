@@ -0,0 +1,121 @@
+//! Checks that `Nes::with_cart` swaps in a wrapped cartridge without
+//! disturbing the rest of the console
+
+extern crate defenestrate_core;
+
+mod util;
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use defenestrate_core::devices::nes::{
+    BusPeekResult, CartridgeState, FdsAdapter, ICartridge, Nes, NesBuilder, PrgRegion,
+};
+use util::provider::NESTEST_ROM_PATH;
+
+fn load_nestest() -> Nes {
+    let rom = std::fs::read(NESTEST_ROM_PATH).expect("Could not read NESTEST rom");
+    NesBuilder::new()
+        .with_rom(&rom)
+        .expect("Could not load NESTEST rom")
+        .with_pc(0xC000)
+        .build()
+}
+
+/// A cartridge that counts the PRG reads it passes through to another
+struct CountingCart {
+    inner: Box<dyn ICartridge>,
+    prg_reads: Arc<AtomicUsize>,
+}
+
+impl CountingCart {
+    fn wrap(inner: Box<dyn ICartridge>, prg_reads: Arc<AtomicUsize>) -> Box<dyn ICartridge> {
+        Box::new(CountingCart { inner, prg_reads })
+    }
+}
+
+impl ICartridge for CountingCart {
+    fn read_chr(&mut self, addr: u16, last_bus_value: u8) -> u8 {
+        self.inner.read_chr(addr, last_bus_value)
+    }
+    fn peek_chr(&self, addr: u16) -> BusPeekResult {
+        self.inner.peek_chr(addr)
+    }
+    fn write_chr(&mut self, addr: u16, value: u8) {
+        self.inner.write_chr(addr, value)
+    }
+    fn read_prg(&mut self, region: PrgRegion, last_bus_value: u8) -> u8 {
+        self.prg_reads.fetch_add(1, Ordering::Relaxed);
+        self.inner.read_prg(region, last_bus_value)
+    }
+    fn peek_prg(&self, region: PrgRegion) -> BusPeekResult {
+        self.inner.peek_prg(region)
+    }
+    fn write_prg(&mut self, region: PrgRegion, value: u8) {
+        self.inner.write_prg(region, value)
+    }
+    fn dump_chr(&self) -> &[u8] {
+        self.inner.dump_chr()
+    }
+    fn dump_nametables(&self) -> &[u8] {
+        self.inner.dump_nametables()
+    }
+    fn debug_state(&self) -> CartridgeState {
+        self.inner.debug_state()
+    }
+    // everything with a default body has to be forwarded too
+    fn reset(&mut self) {
+        self.inner.reset()
+    }
+    fn clock_cpu(&mut self) {
+        self.inner.clock_cpu()
+    }
+    fn irq_pending(&self) -> bool {
+        self.inner.irq_pending()
+    }
+    fn watches_ppu_a12(&self) -> bool {
+        self.inner.watches_ppu_a12()
+    }
+    fn audio_sample(&self) -> f32 {
+        self.inner.audio_sample()
+    }
+    fn fds_mut(&mut self) -> Option<&mut FdsAdapter> {
+        self.inner.fds_mut()
+    }
+    fn power_cycle(&mut self) {
+        self.inner.power_cycle()
+    }
+    fn save_data(&self) -> Option<&[u8]> {
+        self.inner.save_data()
+    }
+    fn load_save_data(&mut self, data: &[u8]) {
+        self.inner.load_save_data(data)
+    }
+}
+
+#[test]
+fn wrapping_picks_up_where_it_left_off() {
+    let mut nes = load_nestest();
+    let mut untouched = load_nestest();
+    for _ in 0..500 {
+        nes.dbg_step_cpu();
+        untouched.dbg_step_cpu();
+    }
+    let prg_reads = Arc::new(AtomicUsize::new(0));
+    let mut nes = nes.with_cart(|cart| CountingCart::wrap(cart, prg_reads.clone()));
+    for _ in 0..500 {
+        nes.dbg_step_cpu();
+        untouched.dbg_step_cpu();
+    }
+    assert!(nes.debug_snapshot() == untouched.debug_snapshot());
+}
+
+#[test]
+fn wrapped_cart_is_used() {
+    let prg_reads = Arc::new(AtomicUsize::new(0));
+    let mut nes = load_nestest().with_cart(|cart| CountingCart::wrap(cart, prg_reads.clone()));
+    for _ in 0..100 {
+        nes.dbg_step_cpu();
+    }
+    assert!(prg_reads.load(Ordering::Relaxed) >= 100);
+}
@@ -0,0 +1,95 @@
+//! Playing the core's audio samples through `cpal`
+//!
+//! The core makes mono samples at whatever rate it's told, and `main` hands
+//! them over after every frame. They wait in a queue until the sound card's
+//! callback asks for them, which copies each one to every channel. If the
+//! queue runs dry, like while the emulator is paused or running slow, the
+//! callback fills in silence; if it backs up, like while running uncapped,
+//! the oldest samples are dropped, so the sound never falls behind the
+//! picture.
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+use cpal::{FromSample, Sample, SampleFormat, SizedSample, Stream, StreamConfig};
+
+/// How much audio can queue up before the oldest is dropped, in seconds
+const MAX_QUEUED_SECONDS: f32 = 0.1;
+
+type Queue = Arc<Mutex<VecDeque<f32>>>;
+
+pub struct AudioOut {
+    /// The stream the samples go out on, which stops when this is dropped
+    _stream: Stream,
+    queue: Queue,
+    sample_rate: u32,
+}
+
+impl AudioOut {
+    /// Start playing on the default output device, at its preferred rate
+    pub fn open() -> Result<AudioOut, String> {
+        let device = cpal::default_host()
+            .default_output_device()
+            .ok_or("No audio output device")?;
+        let supported = device
+            .default_output_config()
+            .map_err(|err| err.to_string())?;
+        let format = supported.sample_format();
+        let config: StreamConfig = supported.into();
+        let queue = Queue::default();
+        let stream = match format {
+            SampleFormat::F32 => build_stream::<f32>(&device, &config, queue.clone()),
+            SampleFormat::I16 => build_stream::<i16>(&device, &config, queue.clone()),
+            SampleFormat::U16 => build_stream::<u16>(&device, &config, queue.clone()),
+            other => return Err(format!("Unsupported sample format {}", other)),
+        }?;
+        stream.play().map_err(|err| err.to_string())?;
+        Ok(AudioOut {
+            _stream: stream,
+            queue,
+            sample_rate: config.sample_rate.0,
+        })
+    }
+
+    /// The rate the core should make samples at
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    /// Queue up samples from `Nes::take_audio_samples`
+    pub fn play(&self, samples: &[f32]) {
+        let max_len = (self.sample_rate as f32 * MAX_QUEUED_SECONDS) as usize;
+        let mut queue = self.queue.lock().unwrap();
+        queue.extend(samples);
+        if queue.len() > max_len {
+            let extra = queue.len() - max_len;
+            queue.drain(..extra);
+        }
+    }
+}
+
+fn build_stream<T>(
+    device: &cpal::Device,
+    config: &StreamConfig,
+    queue: Queue,
+) -> Result<Stream, String>
+where
+    T: SizedSample + FromSample<f32>,
+{
+    let channels = config.channels as usize;
+    device
+        .build_output_stream(
+            config,
+            move |data: &mut [T], _: &cpal::OutputCallbackInfo| {
+                let mut queue = queue.lock().unwrap();
+                for frame in data.chunks_mut(channels) {
+                    let sample = T::from_sample(queue.pop_front().unwrap_or(0.0));
+                    frame.fill(sample);
+                }
+            },
+            |err| eprintln!("Audio output error: {}", err),
+            None,
+        )
+        .map_err(|err| err.to_string())
+}
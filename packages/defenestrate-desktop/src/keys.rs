@@ -0,0 +1,198 @@
+//! Which keys are bound to which controller buttons
+//!
+//! The default layout is in the table in `main`. Any of it can be changed
+//! with the `DEFENESTRATE_KEYS` environment variable, which holds a comma
+//! separated list of `button=key` pairs, like `a=K,b=J,up=W,left=A,down=S,right=D`.
+//! The buttons are `up`, `down`, `left`, `right`, `a`, `b`, `start` and
+//! `select`, and the keys are named the way winit names them (`Return`,
+//! `RShift`, `Space`, `Key1`, `Numpad0`, and so on), ignoring case. Each
+//! button has one key, so binding a button moves it off its default key.
+//! Keys the frontend already uses, like Tab and P, can't be bound.
+
+use defenestrate_core::prelude::Buttons;
+use winit::event::VirtualKeyCode;
+
+/// The environment variable the bindings are read from
+pub const ENV_VAR: &str = "DEFENESTRATE_KEYS";
+
+/// Keys `main` handles itself, before looking at the bindings
+const RESERVED: [VirtualKeyCode; 9] = [
+    VirtualKeyCode::Escape,
+    VirtualKeyCode::Tab,
+    VirtualKeyCode::P,
+    VirtualKeyCode::Period,
+    VirtualKeyCode::Minus,
+    VirtualKeyCode::Equals,
+    VirtualKeyCode::F5,
+    VirtualKeyCode::F8,
+    VirtualKeyCode::F12,
+];
+
+pub struct KeyBindings {
+    keys: [(Buttons, VirtualKeyCode); 8],
+}
+
+impl Default for KeyBindings {
+    fn default() -> Self {
+        KeyBindings {
+            keys: [
+                (Buttons::UP, VirtualKeyCode::Up),
+                (Buttons::DOWN, VirtualKeyCode::Down),
+                (Buttons::LEFT, VirtualKeyCode::Left),
+                (Buttons::RIGHT, VirtualKeyCode::Right),
+                (Buttons::A, VirtualKeyCode::X),
+                (Buttons::B, VirtualKeyCode::Z),
+                (Buttons::START, VirtualKeyCode::Return),
+                (Buttons::SELECT, VirtualKeyCode::RShift),
+            ],
+        }
+    }
+}
+
+impl KeyBindings {
+    /// The bindings from `DEFENESTRATE_KEYS`, or the defaults if it isn't set
+    pub fn from_setting(setting: Option<&str>) -> Result<KeyBindings, String> {
+        match setting {
+            Some(spec) => KeyBindings::parse(spec),
+            None => Ok(KeyBindings::default()),
+        }
+    }
+
+    /// The default layout, with the changes in `spec` made to it
+    pub fn parse(spec: &str) -> Result<KeyBindings, String> {
+        let mut bindings = KeyBindings::default();
+        for pair in spec
+            .split(',')
+            .map(str::trim)
+            .filter(|pair| !pair.is_empty())
+        {
+            let (button, key) = pair
+                .split_once('=')
+                .ok_or_else(|| format!("Expected button=key, got {}", pair))?;
+            let button = button_named(button.trim())
+                .ok_or_else(|| format!("Not a controller button: {}", button))?;
+            let key = key_named(key.trim()).ok_or_else(|| format!("Not a key: {}", key))?;
+            if RESERVED.contains(&key) {
+                return Err(format!("{:?} is already used by the frontend", key));
+            }
+            for binding in bindings.keys.iter_mut() {
+                if binding.0 == button {
+                    binding.1 = key;
+                }
+            }
+        }
+        for (i, (_, key)) in bindings.keys.iter().enumerate() {
+            if bindings.keys[i + 1..].iter().any(|(_, other)| other == key) {
+                return Err(format!("{:?} is bound to more than one button", key));
+            }
+        }
+        Ok(bindings)
+    }
+
+    /// The controller button `key` is bound to, if any
+    pub fn button(&self, key: VirtualKeyCode) -> Option<Buttons> {
+        self.keys
+            .iter()
+            .find(|(_, bound)| *bound == key)
+            .map(|(button, _)| *button)
+    }
+}
+
+fn button_named(name: &str) -> Option<Buttons> {
+    match name.to_ascii_lowercase().as_str() {
+        "up" => Some(Buttons::UP),
+        "down" => Some(Buttons::DOWN),
+        "left" => Some(Buttons::LEFT),
+        "right" => Some(Buttons::RIGHT),
+        "a" => Some(Buttons::A),
+        "b" => Some(Buttons::B),
+        "start" => Some(Buttons::START),
+        "select" => Some(Buttons::SELECT),
+        _ => None,
+    }
+}
+
+/// Match a key name to the `VirtualKeyCode` variant of the same name
+macro_rules! key_names {
+    ($name:expr, $($key:ident),* $(,)?) => {
+        $(if $name.eq_ignore_ascii_case(stringify!($key)) {
+            return Some(VirtualKeyCode::$key);
+        })*
+    };
+}
+
+/// The key winit calls `name`, out of the ones it makes sense to play with
+#[rustfmt::skip]
+fn key_named(name: &str) -> Option<VirtualKeyCode> {
+    key_names!(
+        name, A, B, C, D, E, F, G, H, I, J, K, L, M, N, O, P, Q, R, S, T, U, V, W, X, Y, Z,
+        Key1, Key2, Key3, Key4, Key5, Key6, Key7, Key8, Key9, Key0, Numpad0, Numpad1,
+        Numpad2, Numpad3, Numpad4, Numpad5, Numpad6, Numpad7, Numpad8, Numpad9, Up, Down,
+        Left, Right, Return, Space, Back, Insert, Delete, Home, End, PageUp, PageDown,
+        LShift, RShift, LControl, RControl, LAlt, RAlt, Comma, Semicolon, Slash,
+        Backslash, Apostrophe, Grave, LBracket, RBracket, NumpadEnter, NumpadAdd,
+        NumpadSubtract, NumpadMultiply, NumpadDivide, NumpadDecimal,
+        // only so they're turned away as reserved, instead of as unknown
+        Escape, Tab, Period, Minus, Equals, F5, F8, F12,
+    );
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn falls_back_to_the_defaults() {
+        let bindings = KeyBindings::from_setting(None).unwrap();
+        assert_eq!(bindings.button(VirtualKeyCode::X), Some(Buttons::A));
+        assert_eq!(
+            bindings.button(VirtualKeyCode::RShift),
+            Some(Buttons::SELECT)
+        );
+        assert_eq!(bindings.button(VirtualKeyCode::W), None);
+        let empty = KeyBindings::from_setting(Some("")).unwrap();
+        assert_eq!(empty.button(VirtualKeyCode::Return), Some(Buttons::START));
+    }
+
+    #[test]
+    fn rebinds_only_the_buttons_named() {
+        let bindings = KeyBindings::parse(" a=k, B=j ,up=W,").unwrap();
+        assert_eq!(bindings.button(VirtualKeyCode::K), Some(Buttons::A));
+        assert_eq!(bindings.button(VirtualKeyCode::J), Some(Buttons::B));
+        assert_eq!(bindings.button(VirtualKeyCode::W), Some(Buttons::UP));
+        // the old keys are free again
+        assert_eq!(bindings.button(VirtualKeyCode::X), None);
+        assert_eq!(bindings.button(VirtualKeyCode::Up), None);
+        assert_eq!(bindings.button(VirtualKeyCode::Down), Some(Buttons::DOWN));
+    }
+
+    #[test]
+    fn swapping_two_buttons_is_allowed() {
+        let bindings = KeyBindings::parse("a=Z,b=X").unwrap();
+        assert_eq!(bindings.button(VirtualKeyCode::Z), Some(Buttons::A));
+        assert_eq!(bindings.button(VirtualKeyCode::X), Some(Buttons::B));
+    }
+
+    #[test]
+    fn rejects_bad_names() {
+        let err = |spec| KeyBindings::parse(spec).err().expect("Expected an error");
+        assert_eq!(err("a=Banana"), "Not a key: Banana");
+        assert_eq!(err("jump=Z"), "Not a controller button: jump");
+        assert_eq!(err("a"), "Expected button=key, got a");
+    }
+
+    #[test]
+    fn rejects_duplicate_and_reserved_keys() {
+        let err = |spec| KeyBindings::parse(spec).err().expect("Expected an error");
+        // B is still on Z
+        assert_eq!(err("a=Z"), "Z is bound to more than one button");
+        assert_eq!(err("up=W,down=W"), "W is bound to more than one button");
+        assert_eq!(err("start=Tab"), "Tab is already used by the frontend");
+        assert_eq!(
+            err("select=escape"),
+            "Escape is already used by the frontend"
+        );
+        assert_eq!(err("a=F12"), "F12 is already used by the frontend");
+    }
+}
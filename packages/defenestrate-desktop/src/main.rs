@@ -1,3 +1,268 @@
+//! A minimal native frontend for deFeNEStrate
+//!
+//! This only uses the core's public API, so it doubles as the reference for
+//! embedding the emulator: load a ROM, run it a frame at a time with the
+//! controller state, paced by `Throttle`, and keep the battery save between
+//! sessions. Frames are drawn with `pixels`, which scales them up by whole
+//! pixels. The audio samples from each frame go to the default output device
+//! through `cpal` (see `audio`); without one, games run silent.
+//!
+//! Usage: `defenestrate-desktop <rom.nes>`, or `defenestrate-desktop soak ...`
+//! to soak test ROMs without a window (see `soak`). Dropping another ROM on
+//! the window switches to it, after writing out the battery save of the game
+//! that was running. F5 saves the state of the console, and F8 puts it back;
+//! there's one save state slot, which is emptied when the window closes or
+//! another ROM is dropped on it.
+//!
+//! The keys for the controller buttons can be changed with the
+//! `DEFENESTRATE_KEYS` environment variable (see `keys`). The defaults are:
+//!
+//! | Key         | Does                    |
+//! |-------------|-------------------------|
+//! | Arrow keys  | D-pad                   |
+//! | X           | A                       |
+//! | Z           | B                       |
+//! | Enter       | Start                   |
+//! | Right Shift | Select                  |
+//! | Tab         | Run uncapped while held |
+//! | P           | Pause or resume         |
+//! | Period      | Advance one frame       |
+//! | Minus       | Halve the speed         |
+//! | Equals      | Double the speed        |
+//! | F5          | Save state              |
+//! | F8          | Load state              |
+//! | F12         | Save a PNG screenshot   |
+//! | Escape      | Quit                    |
+
+mod audio;
+mod keys;
+mod soak;
+
+use std::path::{Path, PathBuf};
+use std::{env, fs, process};
+
+use defenestrate_core::prelude::*;
+use defenestrate_core::throttle::Throttle;
+use defenestrate_core::video::{FRAME_HEIGHT, FRAME_WIDTH};
+use pixels::{Pixels, SurfaceTexture};
+use winit::dpi::LogicalSize;
+use winit::event::{ElementState, Event, KeyboardInput, VirtualKeyCode, WindowEvent};
+use winit::event_loop::{ControlFlow, EventLoop};
+use winit::window::WindowBuilder;
+
+use audio::AudioOut;
+use keys::KeyBindings;
+
+/// How many times bigger than the frame the window starts out
+const INITIAL_SCALE: u32 = 3;
+
+/// Copy an RGB frame from the core into an RGBA one for `pixels`
+fn copy_frame(rgb: &[u8], rgba: &mut [u8]) {
+    for (src, dst) in rgb.chunks_exact(3).zip(rgba.chunks_exact_mut(4)) {
+        dst[..3].copy_from_slice(src);
+        dst[3] = 0xFF;
+    }
+}
+
+/// Send the samples from the last frame to the sound card, if there is one
+fn play_audio(nes: &mut Nes, audio: &Option<AudioOut>) {
+    if let Some(audio) = audio {
+        audio.play(&nes.take_audio_samples());
+    }
+}
+
+/// Where the battery save for `rom` lives, next to the ROM itself
+fn save_path(rom: &Path) -> PathBuf {
+    rom.with_extension("sav")
+}
+
+/// The first free screenshot name next to `rom`, like `game-1.png`
+fn screenshot_path(rom: &Path) -> PathBuf {
+    let stem = rom.file_stem().unwrap_or_default().to_string_lossy();
+    (1..)
+        .map(|n| rom.with_file_name(format!("{}-{}.png", stem, n)))
+        .find(|path| !path.exists())
+        .expect("Ran out of screenshot names")
+}
+
+fn write_screenshot(nes: &Nes, rom: &Path) {
+    let path = screenshot_path(rom);
+    match fs::write(&path, nes.screenshot_png()) {
+        Ok(()) => println!("Saved {}", path.display()),
+        Err(err) => eprintln!("Could not write {}: {}", path.display(), err),
+    }
+}
+
+fn write_save(nes: &Nes, path: &Path) {
+    if let Some(data) = nes.save_data() {
+        if let Err(err) = fs::write(path, data) {
+            eprintln!("Could not write {}: {}", path.display(), err);
+        }
+    }
+}
+
+/// Pick up the battery save at `path`, if there is one
+fn read_save(nes: &mut Nes, path: &Path) {
+    if let Ok(data) = fs::read(path) {
+        nes.load_save_data(&data);
+    }
+}
+
 fn main() {
-    println!("Hello, world!");
+    let args: Vec<String> = env::args().collect();
+    if args.get(1).map(String::as_str) == Some("soak") {
+        process::exit(soak::run(&args[2..]));
+    }
+    let mut rom_path = match args.get(1) {
+        Some(path) => PathBuf::from(path),
+        None => {
+            eprintln!("Usage: defenestrate-desktop <rom.nes>");
+            process::exit(2);
+        }
+    };
+    let bindings = KeyBindings::from_setting(env::var(keys::ENV_VAR).ok().as_deref())
+        .unwrap_or_else(|err| {
+            eprintln!("Bad {}: {}", keys::ENV_VAR, err);
+            process::exit(2);
+        });
+    let mut nes = match fs::read(&rom_path)
+        .map_err(|err| err.to_string())
+        .and_then(|rom| Nes::new_from_buf(&rom).map_err(|err| err.to_string()))
+    {
+        Ok(nes) => nes,
+        Err(err) => {
+            eprintln!("Could not load {}: {}", rom_path.display(), err);
+            process::exit(1);
+        }
+    };
+    let mut save_file = save_path(&rom_path);
+    read_save(&mut nes, &save_file);
+    let audio = match AudioOut::open() {
+        Ok(audio) => {
+            nes.set_sample_rate(audio.sample_rate());
+            Some(audio)
+        }
+        Err(err) => {
+            eprintln!("Could not start audio, so there won't be any: {}", err);
+            nes.set_sample_rate(0);
+            None
+        }
+    };
+
+    let event_loop = EventLoop::new();
+    let window = WindowBuilder::new()
+        .with_title("deFeNEStrate")
+        .with_inner_size(LogicalSize::new(
+            FRAME_WIDTH as u32 * INITIAL_SCALE,
+            FRAME_HEIGHT as u32 * INITIAL_SCALE,
+        ))
+        .with_min_inner_size(LogicalSize::new(FRAME_WIDTH as u32, FRAME_HEIGHT as u32))
+        .build(&event_loop)
+        .expect("Could not open a window");
+    let mut pixels = {
+        let size = window.inner_size();
+        let surface = SurfaceTexture::new(size.width, size.height, &window);
+        Pixels::new(FRAME_WIDTH as u32, FRAME_HEIGHT as u32, surface)
+            .expect("Could not create a renderer")
+    };
+
+    let mut throttle = Throttle::new(nes.config().region);
+    let mut held = Buttons::empty();
+    let mut saved_state: Option<SaveState> = None;
+    event_loop.run(move |event, _, control_flow| match event {
+        Event::WindowEvent { event, .. } => match event {
+            WindowEvent::CloseRequested => *control_flow = ControlFlow::Exit,
+            WindowEvent::Resized(size) => {
+                if let Err(err) = pixels.resize_surface(size.width, size.height) {
+                    eprintln!("Could not resize the window: {}", err);
+                    *control_flow = ControlFlow::Exit;
+                }
+            }
+            WindowEvent::DroppedFile(path) => {
+                let loaded = fs::read(&path)
+                    .map_err(|err| err.to_string())
+                    .and_then(|rom| {
+                        write_save(&nes, &save_file);
+                        nes.load_rom(&rom).map_err(|err| err.to_string())
+                    });
+                match loaded {
+                    Ok(()) => {
+                        save_file = save_path(&path);
+                        read_save(&mut nes, &save_file);
+                        saved_state = None;
+                        rom_path = path;
+                    }
+                    Err(err) => eprintln!("Could not load {}: {}", path.display(), err),
+                }
+            }
+            WindowEvent::KeyboardInput {
+                input:
+                    KeyboardInput {
+                        virtual_keycode: Some(key),
+                        state,
+                        ..
+                    },
+                ..
+            } => {
+                let pressed = state == ElementState::Pressed;
+                match key {
+                    VirtualKeyCode::Escape => *control_flow = ControlFlow::Exit,
+                    VirtualKeyCode::Tab => throttle.set_uncapped(pressed),
+                    VirtualKeyCode::P if pressed => {
+                        if nes.is_paused() {
+                            nes.resume();
+                        } else {
+                            nes.pause();
+                        }
+                    }
+                    VirtualKeyCode::Period if pressed => {
+                        nes.pause();
+                        let frame = nes.tick_frame_with_input(held, Buttons::empty());
+                        copy_frame(frame, pixels.frame_mut());
+                        play_audio(&mut nes, &audio);
+                        window.request_redraw();
+                    }
+                    VirtualKeyCode::Minus | VirtualKeyCode::Equals if pressed => {
+                        let factor = if key == VirtualKeyCode::Minus {
+                            0.5
+                        } else {
+                            2.0
+                        };
+                        nes.set_speed(nes.speed() * factor);
+                        throttle.set_speed(nes.speed());
+                    }
+                    VirtualKeyCode::F5 if pressed => saved_state = Some(nes.save_state()),
+                    VirtualKeyCode::F8 if pressed => {
+                        if let Some(state) = &saved_state {
+                            nes.load_state(state);
+                        }
+                    }
+                    VirtualKeyCode::F12 if pressed => write_screenshot(&nes, &rom_path),
+                    _ => {
+                        if let Some(button) = bindings.button(key) {
+                            held.set(button, pressed);
+                        }
+                    }
+                }
+            }
+            _ => {}
+        },
+        Event::MainEventsCleared => {
+            if !nes.is_paused() {
+                let frame = nes.tick_frame_with_input(held, Buttons::empty());
+                copy_frame(frame, pixels.frame_mut());
+                play_audio(&mut nes, &audio);
+                window.request_redraw();
+            }
+            throttle.wait();
+        }
+        Event::RedrawRequested(_) => {
+            if let Err(err) = pixels.render() {
+                eprintln!("Could not draw the frame: {}", err);
+                *control_flow = ControlFlow::Exit;
+            }
+        }
+        Event::LoopDestroyed => write_save(&nes, &save_file),
+        _ => {}
+    });
 }
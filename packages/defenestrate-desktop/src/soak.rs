@@ -0,0 +1,111 @@
+//! The `soak` subcommand, which runs ROMs headless for thousands of frames
+//! to look for panics in the core
+//!
+//! Usage: `defenestrate-desktop soak [--frames N] [--report report.json] <rom.nes>...`
+//!
+//! The JSON report from `soak::report_json` goes to stdout, or to the
+//! `--report` file. The exit code is 1 if any ROM panicked. Panics are still
+//! printed to stderr as they happen, with where in the core they came from,
+//! which the report leaves out.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use defenestrate_core::soak::{report_json, soak_rom, SoakOutcome, SoakResult};
+
+const USAGE: &str =
+    "Usage: defenestrate-desktop soak [--frames N] [--report report.json] <rom.nes>...";
+
+/// About 3 minutes, at 60.0988 frames per second
+const DEFAULT_FRAMES: u64 = 10_000;
+
+struct SoakArgs {
+    frames: u64,
+    report: Option<PathBuf>,
+    roms: Vec<PathBuf>,
+}
+
+fn parse_args(args: &[String]) -> Result<SoakArgs, String> {
+    let mut parsed = SoakArgs {
+        frames: DEFAULT_FRAMES,
+        report: None,
+        roms: Vec::new(),
+    };
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--frames" => {
+                let frames = args.next().ok_or("--frames needs a number")?;
+                parsed.frames = frames
+                    .parse()
+                    .map_err(|_| format!("Not a number of frames: {}", frames))?;
+            }
+            "--report" => {
+                let path = args.next().ok_or("--report needs a path")?;
+                parsed.report = Some(PathBuf::from(path));
+            }
+            _ => parsed.roms.push(PathBuf::from(arg)),
+        }
+    }
+    if parsed.roms.is_empty() {
+        return Err("No ROMs to soak".to_string());
+    }
+    Ok(parsed)
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name().map_or_else(
+        || path.display().to_string(),
+        |name| name.to_string_lossy().into_owned(),
+    )
+}
+
+/// Run the subcommand with the arguments after `soak`, returning the exit code
+pub fn run(args: &[String]) -> i32 {
+    let args = match parse_args(args) {
+        Ok(args) => args,
+        Err(err) => {
+            eprintln!("{}\n{}", err, USAGE);
+            return 2;
+        }
+    };
+    let mut results = Vec::with_capacity(args.roms.len());
+    for path in &args.roms {
+        let name = file_name(path);
+        let result = match fs::read(path) {
+            Ok(rom) => soak_rom(&name, &rom, args.frames),
+            Err(err) => SoakResult {
+                name: name.clone(),
+                frames: args.frames,
+                outcome: SoakOutcome::LoadFailed(err.to_string()),
+            },
+        };
+        match &result.outcome {
+            SoakOutcome::LoadFailed(err) => eprintln!("{}: could not load: {}", name, err),
+            SoakOutcome::LoadPanicked(message) => {
+                eprintln!("{}: panicked while loading: {}", name, message)
+            }
+            SoakOutcome::Panicked(panicked) => eprintln!(
+                "{}: panicked on frame {}: {}",
+                name, panicked.frame, panicked.message
+            ),
+            SoakOutcome::Finished { .. } => eprintln!("{}: ok", name),
+        }
+        results.push(result);
+    }
+    let report = report_json(&results);
+    match &args.report {
+        Some(path) => {
+            if let Err(err) = fs::write(path, &report) {
+                eprintln!("Could not write {}: {}", path.display(), err);
+                return 1;
+            }
+        }
+        None => print!("{}", report),
+    }
+    if results.iter().any(|result| result.panicked()) {
+        1
+    } else {
+        0
+    }
+}
@@ -68,15 +68,10 @@ export class HTMLNesEmulatorElement extends HTMLElement {
             throw Error("Bad state: WASM not loaded")
         }
         if (this.emulator != null) {
-            try {
-                this.emulator.free();
-                this.emulator = void 0;
-            } catch (err) {
-                // complain and then just try to create a new one anyway
-                console.warn("Unexpected error when attempting to free stale emulator");
-                console.warn(err);
-                console.warn("This may result in memory leaks! Reload the page to recover.");
-            }
+            // swap the cartridge in place, so the settings carry over. If the
+            // ROM is bad, this throws and the current game keeps running.
+            this.emulator.load_rom(new Uint8Array(rom));
+            return;
         }
         try {
             this.emulator = new this.module.NesEmulator(new Uint8Array(rom));
@@ -87,6 +82,8 @@ export class HTMLNesEmulatorElement extends HTMLElement {
             throw new Error("Failed to instantiate emulator");
         }
         this.loading = LoadingState.READY;
+        this.emulator.set_dirty_tracking(true);
+        this.applyFrameInfo(this.emulator);
     }
 
     /** Run emulation for just a single frame. */
@@ -94,9 +91,7 @@ export class HTMLNesEmulatorElement extends HTMLElement {
         if (!this.isEmulatorReady(this.emulator)) {
             throw Error("Bad state: Emulator not loaded")
         }
-        const output = this.emulator.step_frame();
-        const frame = convertEmuBufferToImageData(output, 256, 240);
-        this.renderingContext!.putImageData(frame, 0, 0);
+        this.drawFrame(this.emulator.step_frame());
     }
 
     /**
@@ -109,9 +104,7 @@ export class HTMLNesEmulatorElement extends HTMLElement {
         this.isRunning = true;
         const tick = () => {
             if (!this.isRunning) return;
-            const output = this.emulator!.step_frame();
-            const frame = convertEmuBufferToImageData(output, 256, 240);
-            this.renderingContext!.putImageData(frame, 0, 0);
+            this.drawFrame(this.emulator!.step_frame());
             requestAnimationFrame(tick);
         }
         requestAnimationFrame(tick);
@@ -125,9 +118,13 @@ export class HTMLNesEmulatorElement extends HTMLElement {
     /** WebComponent hook, not part of public API */
     connectedCallback() {
         this.render();
+        this.addEventListener("dragover", this.onDragOver);
+        this.addEventListener("drop", this.onDrop);
     }
 
     disconnectedCallback() {
+        this.removeEventListener("dragover", this.onDragOver);
+        this.removeEventListener("drop", this.onDrop);
         if (this.emulator) {
             this.emulator.free();
             this.emulator = void 0;
@@ -135,6 +132,22 @@ export class HTMLNesEmulatorElement extends HTMLElement {
         this.loading = LoadingState.WASM_LOADED;
     }
 
+    private onDragOver = (event: DragEvent) => {
+        // this is what lets the element be a drop target at all
+        event.preventDefault();
+    }
+
+    /** Load a ROM file dropped onto the screen, once the WASM is loaded */
+    private onDrop = (event: DragEvent) => {
+        event.preventDefault();
+        const file = event.dataTransfer?.files[0];
+        if (!file || !this.isModuleReady(this.module)) return;
+        file.arrayBuffer().then(rom => this.loadRom(rom)).catch(error => {
+            console.error(`Failed to load ${file.name}:`);
+            console.error(error);
+        });
+    }
+
     private render() {
         this.innerHTML = `
             <canvas id="emu-screen" width="256" height="240"></canvas>
@@ -146,6 +159,24 @@ export class HTMLNesEmulatorElement extends HTMLElement {
         })!;
     }
 
+    /** Draw a frame, only uploading the parts that changed since the last one */
+    private drawFrame(output: Uint8Array) {
+        const frame = convertEmuBufferToImageData(output, 256, 240);
+        const rects = this.emulator!.dirty_rects();
+        for (let i = 0; i < rects.length; i += 4) {
+            this.renderingContext!.putImageData(
+                frame, 0, 0, rects[i], rects[i + 1], rects[i + 2], rects[i + 3]);
+        }
+    }
+
+    /** Stretch the canvas so NES pixels come out the shape they would on a TV */
+    private applyFrameInfo(emulator: NesEmulator) {
+        const info = emulator.frame_info();
+        const width = info.width * info.pixel_aspect_ratio;
+        this.canvas!.style.aspectRatio = `${width} / ${info.height}`;
+        info.free();
+    }
+
     private isModuleReady(mod?: IWasmModule): mod is IWasmModule {
         return this.loading >= LoadingState.WASM_LOADED;
     }
@@ -50,7 +50,8 @@ const config = {
     },
     plugins: [
         new WasmPackPlugin({
-            crateDirectory: path.resolve("../defenestrate-core")
+            crateDirectory: path.resolve("../defenestrate-core"),
+            extraArgs: "-- --features png,console-log"
         })
     ],
     experiments: {